@@ -12,6 +12,7 @@ use async_trait::async_trait;
 use blobstore::BlobCopier;
 use blobstore::Blobstore;
 use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreGetWithProvenance;
 use blobstore::BlobstoreIsPresent;
 use blobstore::GenericBlobstoreCopier;
 use context::CoreContext;
@@ -109,6 +110,13 @@ impl Blobstore for RepoBlobstore {
     ) -> Result<Option<BlobstoreGetData>> {
         self.0.0.get(ctx, key).await
     }
+    async fn get_with_provenance<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreGetWithProvenance> {
+        self.0.0.get_with_provenance(ctx, key).await
+    }
     async fn put<'a>(
         &'a self,
         ctx: &'a CoreContext,
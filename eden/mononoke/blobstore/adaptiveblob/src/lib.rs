@@ -0,0 +1,702 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! A blobstore limiting the number of concurrent operations on the blobstore
+//! it wraps, adjusting the limit to the latency the blobstore shows.
+//!
+//! The limit is adjusted with AIMD (additive increase, multiplicative
+//! decrease): once per `adjust_interval`, it is increased by one if the p95
+//! latency of the operations completed in the interval is within the target
+//! and the limit was reached, and multiplied by `decrease_factor` if the p95
+//! latency exceeds the target or too many operations failed. The limit thus
+//! settles around the highest concurrency the blobstore sustains.
+//!
+//! A fraction of the limit can be reserved for interactive operations, see
+//! `RequestClass`: the others only run within the rest of the limit.
+
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use blobstore::Blobstore;
+use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreIsPresent;
+use blobstore::BlobstorePutOps;
+use blobstore::OverwriteStatus;
+use blobstore::PutBehaviour;
+use context::CoreContext;
+use context::RequestClass;
+use mononoke_types::BlobstoreBytes;
+use tokio::sync::Semaphore;
+use tokio::sync::SemaphorePermit;
+use tokio::time::Instant;
+
+#[derive(Clone, Copy, Debug)]
+pub struct AdaptiveLimitOptions {
+    pub min_limit: usize,
+    pub max_limit: usize,
+    pub initial_limit: usize,
+    /// The p95 latency of the wrapped blobstore to stay within.
+    pub latency_target: Duration,
+    /// How often the limit is adjusted.
+    pub adjust_interval: Duration,
+    /// Operations to complete in an interval before the limit is adjusted,
+    /// unless enough of them failed.
+    pub min_samples: usize,
+    /// Factor the limit is multiplied by when the latency is over target.
+    pub decrease_factor: f64,
+    /// Failures in an interval to decrease the limit, whatever the latency.
+    pub error_burst: usize,
+    /// After this long without operations, the state is stale: the latency
+    /// observed so far is forgotten, and the limit is moved half way back to
+    /// `initial_limit` for each such period.
+    pub idle_timeout: Duration,
+    /// Fraction of the limit, rounded up, that only interactive operations
+    /// can use. Other operations can always use at least one permit.
+    pub interactive_reserve: f64,
+}
+
+impl Default for AdaptiveLimitOptions {
+    fn default() -> Self {
+        Self {
+            min_limit: 1,
+            max_limit: 256,
+            initial_limit: 16,
+            latency_target: Duration::from_millis(100),
+            adjust_interval: Duration::from_secs(1),
+            min_samples: 10,
+            decrease_factor: 0.75,
+            error_burst: 5,
+            idle_timeout: Duration::from_secs(60),
+            interactive_reserve: 0.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    limit: usize,
+    /// Permits to forget instead of returning to the semaphore once
+    /// released, because the limit was decreased while they were held.
+    debt: usize,
+    /// The same, for the permits of non-interactive operations.
+    unreserved_debt: usize,
+    in_flight: usize,
+    /// Most operations in flight during the interval.
+    peak_in_flight: usize,
+    interval_start: Instant,
+    last_activity: Instant,
+    /// Time spent in the wrapped blobstore by the operations completed in
+    /// the interval.
+    latencies: Vec<Duration>,
+    /// Time the same operations waited for a permit.
+    queue_waits: Vec<Duration>,
+    errors: usize,
+    recent_p95: Option<Duration>,
+    recent_queue_wait_p95: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct Limiter {
+    options: AdaptiveLimitOptions,
+    semaphore: Semaphore,
+    /// Permits for non-interactive operations, which also take one from
+    /// `semaphore`. There are `unreserved(limit)` of them.
+    unreserved: Semaphore,
+    state: Mutex<State>,
+}
+
+fn p95(samples: &mut [Duration]) -> Option<Duration> {
+    if samples.is_empty() {
+        return None;
+    }
+    samples.sort_unstable();
+    let index = (samples.len() * 95 + 99) / 100 - 1;
+    Some(samples[index])
+}
+
+impl Limiter {
+    fn new(options: AdaptiveLimitOptions) -> Self {
+        let min_limit = options.min_limit.max(1);
+        let max_limit = options.max_limit.max(min_limit);
+        let options = AdaptiveLimitOptions {
+            min_limit,
+            max_limit,
+            initial_limit: options.initial_limit.clamp(min_limit, max_limit),
+            ..options
+        };
+        let now = Instant::now();
+        let state = State {
+            limit: options.initial_limit,
+            debt: 0,
+            unreserved_debt: 0,
+            in_flight: 0,
+            peak_in_flight: 0,
+            interval_start: now,
+            last_activity: now,
+            latencies: Vec::new(),
+            queue_waits: Vec::new(),
+            errors: 0,
+            recent_p95: None,
+            recent_queue_wait_p95: None,
+        };
+        Self {
+            options,
+            semaphore: Semaphore::new(options.initial_limit),
+            unreserved: Semaphore::new(unreserved(&options, options.initial_limit)),
+            state: Mutex::new(state),
+        }
+    }
+
+    async fn acquire(&self, class: RequestClass) -> Permit<'_> {
+        let start = Instant::now();
+        self.decay_if_idle(start);
+        let reserve = self.options.interactive_reserve > 0.0;
+        let unreserved = if class != RequestClass::Interactive && reserve {
+            Some(
+                self.unreserved
+                    .acquire()
+                    .await
+                    .expect("the semaphore is never closed"),
+            )
+        } else {
+            None
+        };
+        let permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("the semaphore is never closed");
+        let mut state = self.state.lock().expect("lock poisoned");
+        state.in_flight += 1;
+        state.peak_in_flight = state.peak_in_flight.max(state.in_flight);
+        state.last_activity = Instant::now();
+        Permit {
+            limiter: self,
+            permit: Some(permit),
+            unreserved,
+            queue_wait: start.elapsed(),
+        }
+    }
+
+    fn decay_if_idle(&self, now: Instant) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        if state.in_flight > 0 {
+            return;
+        }
+        let idle = now.saturating_duration_since(state.last_activity);
+        let idle_timeout = self.options.idle_timeout;
+        if idle_timeout.is_zero() || idle < idle_timeout {
+            return;
+        }
+        let initial = self.options.initial_limit;
+        let mut limit = state.limit;
+        for _ in 0..(idle.as_nanos() / idle_timeout.as_nanos()).min(64) {
+            if limit == initial {
+                break;
+            }
+            // Half way, rounding towards `initial`.
+            limit = if limit > initial {
+                initial + (limit - initial) / 2
+            } else {
+                limit + (initial - limit + 1) / 2
+            };
+        }
+        self.set_limit(&mut state, limit);
+        self.start_interval(&mut state, now);
+        state.recent_p95 = None;
+        state.recent_queue_wait_p95 = None;
+        state.last_activity = now;
+    }
+
+    fn record(&self, latency: Duration, queue_wait: Duration, ok: bool) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        state.latencies.push(latency);
+        state.queue_waits.push(queue_wait);
+        if !ok {
+            state.errors += 1;
+        }
+        let now = Instant::now();
+        state.last_activity = now;
+        if now.saturating_duration_since(state.interval_start) < self.options.adjust_interval {
+            return;
+        }
+        let errors = state.errors >= self.options.error_burst;
+        if state.latencies.len() < self.options.min_samples && !errors {
+            return;
+        }
+
+        let p95_latency = p95(&mut state.latencies);
+        state.recent_p95 = p95_latency;
+        state.recent_queue_wait_p95 = p95(&mut state.queue_waits);
+        let over_target = p95_latency.map_or(false, |p95| p95 > self.options.latency_target);
+        let limit = if errors || over_target {
+            let decreased = (state.limit as f64 * self.options.decrease_factor) as usize;
+            decreased.min(state.limit - 1)
+        } else if state.peak_in_flight >= state.limit {
+            state.limit + 1
+        } else {
+            // The limit was not reached, so there is no telling whether
+            // more would be sustained.
+            state.limit
+        };
+        self.set_limit(&mut state, limit);
+        self.start_interval(&mut state, now);
+    }
+
+    fn start_interval(&self, state: &mut State, now: Instant) {
+        state.interval_start = now;
+        state.peak_in_flight = state.in_flight;
+        state.latencies.clear();
+        state.queue_waits.clear();
+        state.errors = 0;
+    }
+
+    fn set_limit(&self, state: &mut State, limit: usize) {
+        let limit = limit.clamp(self.options.min_limit, self.options.max_limit);
+        resize(&self.semaphore, &mut state.debt, state.limit, limit);
+        resize(
+            &self.unreserved,
+            &mut state.unreserved_debt,
+            unreserved(&self.options, state.limit),
+            unreserved(&self.options, limit),
+        );
+        state.limit = limit;
+    }
+
+    fn release(&self, permit: SemaphorePermit<'_>, unreserved: Option<SemaphorePermit<'_>>) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        state.in_flight -= 1;
+        if state.debt > 0 {
+            state.debt -= 1;
+            permit.forget();
+        }
+        if let Some(unreserved) = unreserved {
+            if state.unreserved_debt > 0 {
+                state.unreserved_debt -= 1;
+                unreserved.forget();
+            }
+        }
+    }
+}
+
+/// The permits of `limit` that non-interactive operations can use.
+fn unreserved(options: &AdaptiveLimitOptions, limit: usize) -> usize {
+    let reserve = options.interactive_reserve.clamp(0.0, 1.0);
+    let reserved = (limit as f64 * reserve).ceil() as usize;
+    limit - reserved.min(limit - 1)
+}
+
+/// Change the permits of `semaphore` from `from` to `to`. Those to remove
+/// that are held by operations in flight are added to `debt`, to be
+/// forgotten once released.
+fn resize(semaphore: &Semaphore, debt: &mut usize, from: usize, to: usize) {
+    if to > from {
+        let increase = to - from;
+        let repaid = increase.min(*debt);
+        *debt -= repaid;
+        semaphore.add_permits(increase - repaid);
+    } else {
+        let mut decrease = from - to;
+        while decrease > 0 {
+            match semaphore.try_acquire() {
+                Ok(permit) => permit.forget(),
+                Err(_) => break,
+            }
+            decrease -= 1;
+        }
+        // The rest are held by operations in flight.
+        *debt += decrease;
+    }
+}
+
+/// A permit to run an operation on the wrapped blobstore.
+struct Permit<'a> {
+    limiter: &'a Limiter,
+    permit: Option<SemaphorePermit<'a>>,
+    /// Held by non-interactive operations, with `permit`.
+    unreserved: Option<SemaphorePermit<'a>>,
+    queue_wait: Duration,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if let Some(permit) = self.permit.take() {
+            self.limiter.release(permit, self.unreserved.take());
+        }
+    }
+}
+
+/// The state of the limiter of an `AdaptiveLimitBlob`.
+#[derive(Clone, Debug)]
+pub struct AdaptiveLimitStats(Arc<Limiter>);
+
+impl AdaptiveLimitStats {
+    /// The current limit of concurrent operations.
+    pub fn limit(&self) -> usize {
+        self.0.state.lock().expect("lock poisoned").limit
+    }
+
+    /// Operations currently running on the wrapped blobstore.
+    pub fn in_flight(&self) -> usize {
+        self.0.state.lock().expect("lock poisoned").in_flight
+    }
+
+    /// p95 latency of the wrapped blobstore, over the last interval the
+    /// limit was adjusted for. `None` until the limit is adjusted again
+    /// after idling.
+    pub fn recent_p95(&self) -> Option<Duration> {
+        self.0.state.lock().expect("lock poisoned").recent_p95
+    }
+
+    /// p95 time operations waited to run, over the same interval as
+    /// `recent_p95`.
+    pub fn recent_queue_wait_p95(&self) -> Option<Duration> {
+        self.0
+            .state
+            .lock()
+            .expect("lock poisoned")
+            .recent_queue_wait_p95
+    }
+}
+
+/// A Blobstore limiting concurrent gets and puts to a limit adjusted to the
+/// latency of the blobstore it wraps. See the module documentation.
+#[derive(Clone, Debug)]
+pub struct AdaptiveLimitBlob<B> {
+    blobstore: B,
+    limiter: Arc<Limiter>,
+}
+
+impl<B: fmt::Display> fmt::Display for AdaptiveLimitBlob<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AdaptiveLimitBlob<{}>", &self.blobstore)
+    }
+}
+
+impl<B> AdaptiveLimitBlob<B> {
+    pub fn new(blobstore: B, options: AdaptiveLimitOptions) -> Self {
+        Self {
+            blobstore,
+            limiter: Arc::new(Limiter::new(options)),
+        }
+    }
+
+    pub fn as_inner(&self) -> &B {
+        &self.blobstore
+    }
+
+    pub fn stats(&self) -> AdaptiveLimitStats {
+        AdaptiveLimitStats(self.limiter.clone())
+    }
+
+    async fn limited<T>(
+        &self,
+        class: RequestClass,
+        op: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let permit = self.limiter.acquire(class).await;
+        let start = Instant::now();
+        let result = op.await;
+        self.limiter
+            .record(start.elapsed(), permit.queue_wait, result.is_ok());
+        result
+    }
+}
+
+#[async_trait]
+impl<B: Blobstore> Blobstore for AdaptiveLimitBlob<B> {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        self.limited(ctx.request_class(), self.blobstore.get(ctx, key))
+            .await
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        self.limited(ctx.request_class(), self.blobstore.put(ctx, key, value))
+            .await
+    }
+
+    async fn is_present<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreIsPresent> {
+        self.limited(ctx.request_class(), self.blobstore.is_present(ctx, key))
+            .await
+    }
+}
+
+#[async_trait]
+impl<B: BlobstorePutOps> BlobstorePutOps for AdaptiveLimitBlob<B> {
+    async fn put_explicit<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+    ) -> Result<OverwriteStatus> {
+        self.limited(
+            ctx.request_class(),
+            self.blobstore.put_explicit(ctx, key, value, put_behaviour),
+        )
+        .await
+    }
+
+    async fn put_with_status<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<OverwriteStatus> {
+        self.limited(
+            ctx.request_class(),
+            self.blobstore.put_with_status(ctx, key, value),
+        )
+        .await
+    }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        self.limited(
+            ctx.request_class(),
+            self.blobstore.put_with_ttl(ctx, key, value, ttl),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    use borrowed::borrowed;
+    use context::with_request_class;
+    use fbinit::FacebookInit;
+    use futures::future::join_all;
+    use memblob::Memblob;
+
+    use super::*;
+
+    /// A blobstore that is slow with more than `knee` concurrent operations.
+    #[derive(Debug, Default)]
+    struct KneeBlob {
+        inner: Memblob,
+        knee: AtomicUsize,
+        in_flight: AtomicUsize,
+    }
+
+    impl fmt::Display for KneeBlob {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "KneeBlob")
+        }
+    }
+
+    impl KneeBlob {
+        fn new(knee: usize) -> Self {
+            Self {
+                knee: AtomicUsize::new(knee),
+                ..Default::default()
+            }
+        }
+
+        async fn delay(&self) {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            let delay = if in_flight > self.knee.load(Ordering::SeqCst) {
+                Duration::from_millis(200)
+            } else {
+                Duration::from_millis(10)
+            };
+            tokio::time::sleep(delay).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    #[async_trait]
+    impl Blobstore for KneeBlob {
+        async fn get<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: &'a str,
+        ) -> Result<Option<BlobstoreGetData>> {
+            self.delay().await;
+            self.inner.get(ctx, key).await
+        }
+
+        async fn put<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: String,
+            value: BlobstoreBytes,
+        ) -> Result<()> {
+            self.delay().await;
+            self.inner.put(ctx, key, value).await
+        }
+    }
+
+    fn options() -> AdaptiveLimitOptions {
+        AdaptiveLimitOptions {
+            min_limit: 1,
+            max_limit: 64,
+            initial_limit: 1,
+            latency_target: Duration::from_millis(50),
+            adjust_interval: Duration::from_millis(100),
+            min_samples: 5,
+            idle_timeout: Duration::from_secs(10),
+            ..Default::default()
+        }
+    }
+
+    /// Run `workers` loops of gets on `blob` for `duration`.
+    async fn load<B: Blobstore>(
+        ctx: &CoreContext,
+        blob: &B,
+        workers: usize,
+        duration: Duration,
+    ) -> Result<()> {
+        let deadline = Instant::now() + duration;
+        join_all((0..workers).map(|_| async move {
+            while Instant::now() < deadline {
+                blob.get(ctx, "key").await?;
+            }
+            Ok(())
+        }))
+        .await
+        .into_iter()
+        .collect()
+    }
+
+    #[fbinit::test]
+    async fn test_converges(fb: FacebookInit) -> Result<()> {
+        tokio::time::pause();
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = AdaptiveLimitBlob::new(KneeBlob::new(8), options());
+        let stats = blob.stats();
+        assert_eq!(stats.limit(), 1);
+
+        load(ctx, &blob, 64, Duration::from_secs(30)).await?;
+        let limit = stats.limit();
+        assert!((3..=9).contains(&limit), "limit {}", limit);
+        assert_eq!(stats.in_flight(), 0);
+        assert!(stats.recent_p95().is_some());
+        // With 64 workers, most of the time is spent waiting for a permit.
+        assert!(stats.recent_queue_wait_p95().unwrap() > stats.recent_p95().unwrap());
+
+        // The blobstore gets faster, the limit grows back.
+        blob.as_inner().knee.store(32, Ordering::SeqCst);
+        load(ctx, &blob, 64, Duration::from_secs(30)).await?;
+        let limit = stats.limit();
+        assert!((16..=33).contains(&limit), "limit {}", limit);
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_errors_and_idle(fb: FacebookInit) -> Result<()> {
+        tokio::time::pause();
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = AdaptiveLimitBlob::new(
+            KneeBlob::new(64),
+            AdaptiveLimitOptions {
+                initial_limit: 4,
+                ..options()
+            },
+        );
+        let stats = blob.stats();
+        load(ctx, &blob, 32, Duration::from_secs(5)).await?;
+        // Grows one past the number of workers, and no further.
+        assert_eq!(stats.limit(), 33);
+        assert!(stats.recent_p95().is_some());
+
+        // After idling, the limit is moved back towards the initial limit,
+        // and the latency observed before is forgotten.
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        blob.get(ctx, "key").await?;
+        assert_eq!(stats.limit(), 7);
+        assert!(stats.recent_p95().is_none());
+        assert!(stats.recent_queue_wait_p95().is_none());
+
+        // Errors decrease the limit.
+        let errors = join_all((0..8).map(|_| {
+            blob.limited(RequestClass::Interactive, async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Err::<(), _>(anyhow::anyhow!("failed"))
+            })
+        }))
+        .await;
+        assert!(errors.iter().all(|r| r.is_err()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(
+            blob.limited(RequestClass::Interactive, async {
+                Err::<(), _>(anyhow::anyhow!("failed"))
+            })
+            .await
+            .is_err()
+        );
+        assert_eq!(stats.limit(), 5);
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_interactive_reserve(fb: FacebookInit) -> Result<()> {
+        tokio::time::pause();
+        let ctx = CoreContext::test_mock(fb);
+        let background = with_request_class(&ctx, RequestClass::Background);
+        borrowed!(ctx, background);
+        // Every operation takes 200ms.
+        let blob = AdaptiveLimitBlob::new(
+            KneeBlob::new(0),
+            AdaptiveLimitOptions {
+                min_limit: 4,
+                max_limit: 4,
+                initial_limit: 4,
+                interactive_reserve: 0.5,
+                ..options()
+            },
+        );
+
+        let start = Instant::now();
+        let backgrounds = async {
+            join_all((0..8).map(|_| blob.get(background, "key")))
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>>>()?;
+            anyhow::Ok(start.elapsed())
+        };
+        let interactive = async {
+            // Let the background gets queue first.
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            blob.get(ctx, "key").await?;
+            anyhow::Ok(start.elapsed())
+        };
+        let (backgrounds, interactive) = futures::join!(backgrounds, interactive);
+        // The background gets run two at a time, and the interactive one
+        // does not wait for them.
+        assert!(backgrounds? >= Duration::from_millis(800));
+        assert!(interactive? < Duration::from_millis(300));
+        Ok(())
+    }
+}
@@ -9,7 +9,9 @@ use std::time::Duration;
 
 use anyhow::Error;
 use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreGetManyData;
 use blobstore::BlobstoreIsPresent;
+use blobstore::BlobstorePutBatchResults;
 use blobstore::OverwriteStatus;
 use clap::ValueEnum;
 use context::PerfCounters;
@@ -31,13 +33,28 @@ pub const BLOBSTORE_TYPE: &str = "blobstore_type";
 pub const COMPLETION_TIME: &str = "completion_time";
 pub const ERROR: &str = "error";
 pub const KEY: &str = "key";
+/// Number of keys of a batched operation.
+pub const KEY_COUNT: &str = "key_count";
 pub const OPERATION: &str = "operation";
 pub const QUEUE: &str = "queue";
+/// The `RequestClass` of the context of the operation.
+pub const REQUEST_CLASS: &str = "request_class";
 pub const SESSION: &str = "session";
 pub const SIZE: &str = "size";
 pub const WRITE_ORDER: &str = "write_order";
 /// Was the blob found during the get/is_present operations?
 pub const BLOB_PRESENT: &str = "blob_present";
+/// Was the operation aborted because the request deadline passed?
+pub const TIMED_OUT: &str = "timed_out";
+/// Seconds until a blob put with `put_with_ttl` expires.
+pub const TTL_SECS: &str = "ttl_secs";
+/// Name and position of the store of a chain that served a get.
+pub const SERVED_BY: &str = "served_by";
+pub const SERVED_BY_INDEX: &str = "served_by_index";
+/// Stores of a chain tried before the one that served a get, and the time
+/// they took.
+pub const CHAIN_MISSES: &str = "chain_misses";
+pub const CHAIN_MISS_LATENCY_US: &str = "chain_miss_latency_us";
 
 const OVERWRITE_STATUS: &str = "overwrite_status";
 
@@ -57,7 +74,9 @@ const OVERWRITE_STATUS: &str = "overwrite_status";
 #[strum(serialize_all = "kebab_case")]
 pub enum OperationType {
     Get,
+    GetMany,
     Put,
+    PutBatch,
     ScrubGet,
     IsPresent,
     Link,
@@ -153,6 +172,55 @@ pub fn record_get_stats(
     scuba.log();
 }
 
+/// Record a `get_many` of `key_count` keys. `SIZE` is the total size of the
+/// blobs found, and `ERROR` the whole batch error, or the number of keys that
+/// failed and the first of their errors.
+pub fn record_get_many_stats(
+    scuba: &mut MononokeScubaSampleBuilder,
+    pc: &PerfCounters,
+    stats: FutureStats,
+    result: Result<&BlobstoreGetManyData, &Error>,
+    key_count: usize,
+    session: &str,
+    blobstore_id: Option<BlobstoreId>,
+    blobstore_type: impl ToString,
+) {
+    scuba
+        .add(OPERATION, OperationType::GetMany)
+        .add(KEY_COUNT, key_count)
+        .add(BLOBSTORE_TYPE, blobstore_type.to_string());
+    pc.insert_nonzero_perf_counters(scuba);
+    if let Some(blobstore_id) = blobstore_id {
+        scuba.add(BLOBSTORE_ID, blobstore_id);
+    }
+    add_completion_time(scuba, session, stats);
+
+    match result {
+        Ok(results) => {
+            let size: usize = results
+                .values()
+                .filter_map(|result| Some(result.as_ref().ok()?.as_ref()?.as_bytes().len()))
+                .sum();
+            scuba.add(SIZE, size);
+            let mut errors = results.values().filter_map(|result| result.as_ref().err());
+            if let Some(first) = errors.next() {
+                scuba.unsampled();
+                scuba.add(
+                    ERROR,
+                    format!("{} keys failed, first: {:#}", errors.count() + 1, first),
+                );
+            }
+        }
+        Err(error) => {
+            // Always log errors
+            scuba.unsampled();
+            scuba.add(ERROR, format!("{:#}", error));
+        }
+    }
+
+    scuba.log();
+}
+
 pub fn record_is_present_stats(
     scuba: &mut MononokeScubaSampleBuilder,
     pc: &PerfCounters,
@@ -235,6 +303,51 @@ pub fn record_put_stats(
     scuba.log();
 }
 
+/// Record a `put_batch` of `key_count` items of `size` bytes in total.
+/// `ERROR` is the whole batch error, or the number of items that failed and
+/// the first of their errors.
+pub fn record_put_batch_stats(
+    scuba: &mut MononokeScubaSampleBuilder,
+    pc: &PerfCounters,
+    stats: FutureStats,
+    result: Result<&BlobstorePutBatchResults, &Error>,
+    key_count: usize,
+    size: usize,
+    session: &str,
+    blobstore_id: Option<BlobstoreId>,
+    blobstore_type: impl ToString,
+) {
+    scuba
+        .add(OPERATION, OperationType::PutBatch)
+        .add(KEY_COUNT, key_count)
+        .add(SIZE, size)
+        .add(BLOBSTORE_TYPE, blobstore_type.to_string());
+    pc.insert_nonzero_perf_counters(scuba);
+    if let Some(blobstore_id) = blobstore_id {
+        scuba.add(BLOBSTORE_ID, blobstore_id);
+    }
+    add_completion_time(scuba, session, stats);
+
+    match result {
+        Ok(results) => {
+            let mut errors = results
+                .iter()
+                .filter_map(|(_, result)| result.as_ref().err());
+            if let Some(first) = errors.next() {
+                scuba.add(
+                    ERROR,
+                    format!("{} keys failed, first: {:#}", errors.count() + 1, first),
+                );
+            }
+        }
+        Err(error) => {
+            scuba.add(ERROR, format!("{:#}", error));
+        }
+    }
+
+    scuba.log();
+}
+
 pub fn record_queue_stats(
     scuba: &mut MononokeScubaSampleBuilder,
     pc: &PerfCounters,
@@ -0,0 +1,497 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! A blobstore over an ordered chain of named stores, the fastest first,
+//! like a local cache, a regional store and the origin.
+//!
+//! Gets try each store in order and return the first hit. Through
+//! `Blobstore::get_with_provenance`, they also tell which store served the
+//! value, and how long the stores tried before took to miss. When
+//! `populate_upward` is set, a hit is put into the earlier stores from a
+//! background task, at most `max_populate_concurrency` at once.
+
+use std::fmt;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use blobstore::Blobstore;
+use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreGetWithProvenance;
+use blobstore::BlobstoreIsPresent;
+use blobstore::BlobstorePutOps;
+use blobstore::OverwriteStatus;
+use blobstore::Provenance;
+use blobstore::PutBehaviour;
+use blobstore::StoreMiss;
+use blobstore::StoreRef;
+use context::CoreContext;
+use futures::future::try_join_all;
+use futures::Future;
+use mononoke_types::BlobstoreBytes;
+use slog::warn;
+use tokio::sync::Notify;
+use tokio::sync::Semaphore;
+
+/// Which stores puts go to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PutTargets {
+    /// The first store of the chain only.
+    First,
+    /// All the stores. Fails if any store fails.
+    All,
+}
+
+#[derive(Clone, Debug)]
+pub struct ChainedBlobOptions {
+    /// Put values found by gets into the stores before the one that had
+    /// them.
+    pub populate_upward: bool,
+    /// Maximum number of background puts of `populate_upward` running at
+    /// once. Must not be 0.
+    pub max_populate_concurrency: usize,
+    pub puts: PutTargets,
+}
+
+impl Default for ChainedBlobOptions {
+    fn default() -> Self {
+        Self {
+            populate_upward: false,
+            max_populate_concurrency: 10,
+            puts: PutTargets::All,
+        }
+    }
+}
+
+struct NamedStore<B> {
+    name: String,
+    store: B,
+}
+
+struct Populating {
+    permits: Semaphore,
+    queued: AtomicU64,
+    /// Notified when no population is queued.
+    idle: Notify,
+}
+
+impl Populating {
+    fn done(&self) {
+        if self.queued.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.idle.notify_waiters();
+        }
+    }
+}
+
+/// Reads from the first store of a chain that has a blob. See the crate
+/// documentation.
+pub struct ChainedBlob<B> {
+    stores: Arc<Vec<NamedStore<B>>>,
+    populate_upward: bool,
+    puts: PutTargets,
+    populating: Arc<Populating>,
+}
+
+impl<B: fmt::Display> fmt::Display for ChainedBlob<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ChainedBlob<")?;
+        for (i, named) in self.stores.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}: {}", named.name, named.store)?;
+        }
+        write!(f, ">")
+    }
+}
+
+impl<B: fmt::Debug> fmt::Debug for ChainedBlob<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stores: Vec<_> = self
+            .stores
+            .iter()
+            .map(|named| (&named.name, &named.store))
+            .collect();
+        f.debug_struct("ChainedBlob")
+            .field("stores", &stores)
+            .field("populate_upward", &self.populate_upward)
+            .field("puts", &self.puts)
+            .finish()
+    }
+}
+
+impl<B> ChainedBlob<B> {
+    /// `stores` are tried in order, by name.
+    pub fn new(stores: Vec<(String, B)>, options: ChainedBlobOptions) -> Result<Self> {
+        anyhow::ensure!(!stores.is_empty(), "ChainedBlob needs at least one store");
+        // No background put would ever run.
+        anyhow::ensure!(
+            options.max_populate_concurrency > 0,
+            "ChainedBlob needs a max_populate_concurrency of at least 1"
+        );
+        let stores = stores
+            .into_iter()
+            .map(|(name, store)| NamedStore { name, store })
+            .collect();
+        Ok(Self {
+            stores: Arc::new(stores),
+            populate_upward: options.populate_upward,
+            puts: options.puts,
+            populating: Arc::new(Populating {
+                permits: Semaphore::new(options.max_populate_concurrency),
+                queued: AtomicU64::new(0),
+                idle: Notify::new(),
+            }),
+        })
+    }
+
+    /// Wait until the background puts of `populate_upward` queued so far
+    /// finished.
+    pub async fn wait_populated(&self) {
+        loop {
+            let idle = self.populating.idle.notified();
+            if self.populating.queued.load(Ordering::Acquire) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    fn store_ref(&self, index: usize) -> StoreRef {
+        StoreRef {
+            index,
+            name: self.stores[index].name.clone(),
+        }
+    }
+
+    /// Run `write` on the stores puts go to.
+    async fn write<'a, T, Fut>(&'a self, write: impl Fn(&'a B) -> Fut) -> Result<T>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        match self.puts {
+            PutTargets::First => write(&self.stores[0].store).await,
+            PutTargets::All => {
+                let mut results =
+                    try_join_all(self.stores.iter().map(|named| write(&named.store))).await?;
+                Ok(results.swap_remove(0))
+            }
+        }
+    }
+}
+
+impl<B: Blobstore + 'static> ChainedBlob<B> {
+    /// Put `value` into the stores before `index` in the background. Must be
+    /// called from a Tokio runtime.
+    fn populate(&self, ctx: &CoreContext, key: &str, value: BlobstoreBytes, index: usize) {
+        self.populating.queued.fetch_add(1, Ordering::AcqRel);
+        let ctx = ctx.clone();
+        let key = key.to_string();
+        let stores = self.stores.clone();
+        let populating = self.populating.clone();
+        tokio::spawn(async move {
+            if let Ok(_permit) = populating.permits.acquire().await {
+                for named in &stores[..index] {
+                    if let Err(e) = named.store.put(&ctx, key.clone(), value.clone()).await {
+                        warn!(
+                            ctx.logger(),
+                            "Failed to populate key {} in {}: {:#}", key, named.name, e
+                        );
+                    }
+                }
+            }
+            populating.done();
+        });
+    }
+
+    /// Like `is_present`, but also tells which store answered.
+    pub async fn is_present_with_provenance<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<(BlobstoreIsPresent, Provenance)> {
+        let mut result = BlobstoreIsPresent::Absent;
+        let mut provenance = Provenance::default();
+        for (i, named) in self.stores.iter().enumerate() {
+            let start = Instant::now();
+            let present = named.store.is_present(ctx, key).await;
+            let failed = match present {
+                Ok(BlobstoreIsPresent::Present) => {
+                    provenance.served_by = Some(self.store_ref(i));
+                    return Ok((BlobstoreIsPresent::Present, provenance));
+                }
+                Ok(BlobstoreIsPresent::Absent) => false,
+                Ok(BlobstoreIsPresent::ProbablyNotPresent(e)) | Err(e) => {
+                    if let BlobstoreIsPresent::Absent = result {
+                        result = BlobstoreIsPresent::ProbablyNotPresent(e);
+                    }
+                    true
+                }
+            };
+            provenance.misses.push(StoreMiss {
+                store: self.store_ref(i),
+                latency: start.elapsed(),
+                failed,
+            });
+        }
+        Ok((result, provenance))
+    }
+}
+
+#[async_trait]
+impl<B: Blobstore + 'static> Blobstore for ChainedBlob<B> {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        Ok(self.get_with_provenance(ctx, key).await?.data)
+    }
+
+    async fn get_with_provenance<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreGetWithProvenance> {
+        let mut misses = Vec::new();
+        let mut first_err = None;
+        for (i, named) in self.stores.iter().enumerate() {
+            let start = Instant::now();
+            let result = named.store.get(ctx, key).await;
+            let failed = match result {
+                Ok(Some(value)) => {
+                    if self.populate_upward && i > 0 {
+                        self.populate(ctx, key, value.as_bytes().clone(), i);
+                    }
+                    return Ok(BlobstoreGetWithProvenance {
+                        data: Some(value),
+                        provenance: Some(Provenance {
+                            served_by: Some(self.store_ref(i)),
+                            misses,
+                        }),
+                    });
+                }
+                Ok(None) => false,
+                Err(e) => {
+                    first_err.get_or_insert(e);
+                    true
+                }
+            };
+            misses.push(StoreMiss {
+                store: self.store_ref(i),
+                latency: start.elapsed(),
+                failed,
+            });
+        }
+        // A store that failed may have the blob.
+        match first_err {
+            Some(e) => Err(e),
+            None => Ok(BlobstoreGetWithProvenance {
+                data: None,
+                provenance: Some(Provenance {
+                    served_by: None,
+                    misses,
+                }),
+            }),
+        }
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        self.write(|store| store.put(ctx, key.clone(), value.clone()))
+            .await
+    }
+
+    async fn is_present<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreIsPresent> {
+        Ok(self.is_present_with_provenance(ctx, key).await?.0)
+    }
+}
+
+#[async_trait]
+impl<B: BlobstorePutOps + 'static> BlobstorePutOps for ChainedBlob<B> {
+    async fn put_explicit<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+    ) -> Result<OverwriteStatus> {
+        self.write(|store| store.put_explicit(ctx, key.clone(), value.clone(), put_behaviour))
+            .await
+    }
+
+    async fn put_with_status<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<OverwriteStatus> {
+        self.write(|store| store.put_with_status(ctx, key.clone(), value.clone()))
+            .await
+    }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        self.write(|store| store.put_with_ttl(ctx, key.clone(), value.clone(), ttl))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use blobstore::CountedBlobstore;
+    use borrowed::borrowed;
+    use fbinit::FacebookInit;
+    use memblob::Memblob;
+
+    use super::*;
+
+    fn bytes(value: &[u8]) -> BlobstoreBytes {
+        BlobstoreBytes::from_bytes(value.to_vec())
+    }
+
+    fn chain(stores: &[Memblob], options: ChainedBlobOptions) -> Result<ChainedBlob<Memblob>> {
+        let names = ["local", "regional", "origin"];
+        ChainedBlob::new(
+            names
+                .iter()
+                .zip(stores)
+                .map(|(name, store)| (name.to_string(), store.clone()))
+                .collect(),
+            options,
+        )
+    }
+
+    fn served_by(result: &BlobstoreGetWithProvenance) -> Option<(usize, &str)> {
+        let served_by = result.provenance.as_ref()?.served_by.as_ref()?;
+        Some((served_by.index, served_by.name.as_str()))
+    }
+
+    fn missed(provenance: &Provenance) -> Vec<&str> {
+        provenance
+            .misses
+            .iter()
+            .map(|miss| miss.store.name.as_str())
+            .collect()
+    }
+
+    #[fbinit::test]
+    async fn test_provenance(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let stores = [Memblob::default(), Memblob::default(), Memblob::default()];
+        let blob = chain(&stores, Default::default())?;
+        stores[2]
+            .put(ctx, "key".to_string(), bytes(b"value"))
+            .await?;
+
+        let result = blob.get_with_provenance(ctx, "key").await?;
+        assert_eq!(served_by(&result), Some((2, "origin")));
+        // Wrappers pass it along.
+        let counted =
+            CountedBlobstore::new("chain".to_string(), chain(&stores, Default::default())?);
+        let counted_result = counted.get_with_provenance(ctx, "key").await?;
+        assert_eq!(served_by(&counted_result), Some((2, "origin")));
+        let provenance = result.provenance.as_ref().unwrap();
+        assert_eq!(missed(provenance), vec!["local", "regional"]);
+        assert!(provenance.misses.iter().all(|miss| !miss.failed));
+        assert_eq!(
+            result.data.unwrap().into_raw_bytes().as_ref(),
+            b"value".as_ref()
+        );
+
+        let (present, provenance) = blob.is_present_with_provenance(ctx, "key").await?;
+        assert!(matches!(present, BlobstoreIsPresent::Present));
+        assert_eq!(provenance.served_by.unwrap().name, "origin");
+
+        // Not populated.
+        assert!(stores[0].get(ctx, "key").await?.is_none());
+        let result = blob.get_with_provenance(ctx, "absent").await?;
+        assert!(result.data.is_none());
+        let provenance = result.provenance.unwrap();
+        assert_eq!(provenance.served_by, None);
+        assert_eq!(missed(&provenance), vec!["local", "regional", "origin"]);
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_populate_upward(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let stores = [Memblob::default(), Memblob::default(), Memblob::default()];
+        let options = ChainedBlobOptions {
+            populate_upward: true,
+            max_populate_concurrency: 0,
+            ..Default::default()
+        };
+        assert!(chain(&stores, options).is_err());
+        let blob = chain(
+            &stores,
+            ChainedBlobOptions {
+                populate_upward: true,
+                ..Default::default()
+            },
+        )?;
+        stores[2]
+            .put(ctx, "key".to_string(), bytes(b"value"))
+            .await?;
+
+        let result = blob.get_with_provenance(ctx, "key").await?;
+        assert_eq!(served_by(&result), Some((2, "origin")));
+        blob.wait_populated().await;
+        for store in &stores[..2] {
+            assert!(store.get(ctx, "key").await?.is_some());
+        }
+
+        let result = blob.get_with_provenance(ctx, "key").await?;
+        assert_eq!(served_by(&result), Some((0, "local")));
+        assert!(result.provenance.unwrap().misses.is_empty());
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_put_targets(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        for (puts, expected) in [(PutTargets::First, 1), (PutTargets::All, 3)] {
+            let stores = [Memblob::default(), Memblob::default(), Memblob::default()];
+            let blob = chain(
+                &stores,
+                ChainedBlobOptions {
+                    puts,
+                    ..Default::default()
+                },
+            )?;
+            blob.put(ctx, "key".to_string(), bytes(b"value")).await?;
+            let mut present = 0;
+            for store in &stores {
+                if store.get(ctx, "key").await?.is_some() {
+                    present += 1;
+                }
+            }
+            assert_eq!(present, expected, "{:?}", puts);
+        }
+        Ok(())
+    }
+}
@@ -6,6 +6,7 @@
  */
 
 use std::num::NonZeroU32;
+use std::time::Duration;
 
 use anyhow::Result;
 use async_trait::async_trait;
@@ -181,6 +182,20 @@ impl<T: BlobstorePutOps> BlobstorePutOps for ChaosBlobstore<T> {
     ) -> Result<OverwriteStatus> {
         self.put_impl(ctx, key, value, None).await
     }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        let should_error = thread_rng().gen::<f32>() > self.sample_threshold_write;
+        if should_error {
+            return Err(ErrorKind::InjectedChaosPut(key).into());
+        }
+        self.blobstore.put_with_ttl(ctx, key, value, ttl).await
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,763 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use async_trait::async_trait;
+use blobstore::Blobstore;
+use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreIsPresent;
+use blobstore::BlobstorePutOps;
+use blobstore::BlobstoreUnlinkOps;
+use blobstore::OverwriteStatus;
+use blobstore::PutBehaviour;
+use bytes::Bytes;
+use bytes::BytesMut;
+use context::CoreContext;
+use futures::stream;
+use futures::StreamExt;
+use futures::TryStreamExt;
+use mononoke_types::hash::Context as HashContext;
+use mononoke_types::BlobstoreBytes;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+
+/// Marks a value stored under the original key as a chunk manifest.
+const MANIFEST_MAGIC: &[u8] = b"\0chunkingblob-manifest-v1\0";
+
+const CHECKSUM_KEY: &[u8] = b"chunkingblob";
+
+#[derive(Debug, Error)]
+pub enum ErrorKind {
+    #[error("Chunk {chunk_key} of {key} is missing")]
+    MissingChunk { key: String, chunk_key: String },
+    #[error("Chunk {chunk_key} of {key} has size {actual}, expected {expected}")]
+    ChunkSizeMismatch {
+        key: String,
+        chunk_key: String,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("Checksum mismatch for chunked blob {key}: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        key: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+/// Stored under the original key of a chunked value.
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkManifest {
+    chunk_sizes: Vec<u64>,
+    checksum: String,
+}
+
+impl ChunkManifest {
+    fn encode(&self) -> Result<BlobstoreBytes> {
+        let mut encoded = MANIFEST_MAGIC.to_vec();
+        serde_json::to_writer(&mut encoded, self)?;
+        Ok(BlobstoreBytes::from_bytes(encoded))
+    }
+
+    /// Returns `None` if `bytes` is not a manifest.
+    fn decode(key: &str, bytes: &Bytes) -> Result<Option<Self>> {
+        match bytes.strip_prefix(MANIFEST_MAGIC) {
+            None => Ok(None),
+            Some(json) => {
+                let manifest = serde_json::from_slice(json)
+                    .with_context(|| format!("Invalid chunk manifest for {}", key))?;
+                Ok(Some(manifest))
+            }
+        }
+    }
+}
+
+fn checksum(bytes: &[u8]) -> String {
+    let mut context = HashContext::new(CHECKSUM_KEY);
+    context.update(bytes);
+    context.finish().to_hex().to_string()
+}
+
+/// A layer over an existing blobstore that splits values larger than
+/// `chunk_size` into several chunks stored under
+/// `<key>.chunk.<checksum>.<i>`, plus a manifest stored under the original
+/// key. As chunk keys depend on the value, overwriting a value does not
+/// modify the chunks of the previous one.
+///
+/// Values that fit in a single chunk are stored as-is, so existing data can
+/// be read through this layer unchanged.
+#[derive(Debug)]
+pub struct ChunkingBlob<B> {
+    inner: B,
+    chunk_size: usize,
+    concurrency: usize,
+    overwrite_cleanup: bool,
+}
+
+impl<B: std::fmt::Display> std::fmt::Display for ChunkingBlob<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ChunkingBlob<{}>", &self.inner)
+    }
+}
+
+impl<B> ChunkingBlob<B> {
+    pub fn new(inner: B, chunk_size: usize, concurrency: usize) -> Self {
+        assert!(chunk_size > 0, "chunk_size must be positive");
+        Self {
+            inner,
+            chunk_size,
+            concurrency: concurrency.max(1),
+            overwrite_cleanup: false,
+        }
+    }
+
+    /// Unlink the chunks of the values overwritten by puts. Each put that
+    /// may overwrite a value then first gets the value it replaces, which
+    /// adds the latency of a get. Otherwise, the chunks of overwritten
+    /// values are left behind, and never read.
+    pub fn with_overwrite_cleanup(mut self) -> Self {
+        self.overwrite_cleanup = true;
+        self
+    }
+
+    pub fn as_inner(&self) -> &B {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+
+    fn chunk_key(key: &str, checksum: &str, index: usize) -> String {
+        format!("{}.chunk.{}.{}", key, checksum, index)
+    }
+}
+
+impl<B: BlobstoreUnlinkOps> ChunkingBlob<B> {
+    async fn put_impl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: Option<PutBehaviour>,
+        ttl: Option<Duration>,
+    ) -> Result<OverwriteStatus> {
+        let bytes = value.into_bytes();
+        let check_old =
+            self.overwrite_cleanup && !matches!(put_behaviour, Some(PutBehaviour::IfAbsent));
+        // The manifest of the value this put may overwrite, whose chunks
+        // must not be left behind once it is replaced. A corrupt value can
+        // still be overwritten, leaving its chunks if it had any.
+        let old_manifest = if check_old {
+            self.stored_manifest(ctx, &key).await.unwrap_or(None)
+        } else {
+            None
+        };
+        // Values that look like a manifest are always chunked, so they are
+        // not mistaken for one on read.
+        if bytes.len() <= self.chunk_size && !bytes.starts_with(MANIFEST_MAGIC) {
+            let status = self
+                .put_inner(
+                    ctx,
+                    key.clone(),
+                    BlobstoreBytes::from_bytes(bytes),
+                    put_behaviour,
+                    ttl,
+                )
+                .await?;
+            if status != OverwriteStatus::Prevented {
+                if let Some(old_manifest) = &old_manifest {
+                    self.unlink_chunks(ctx, &key, old_manifest).await;
+                }
+            }
+            return Ok(status);
+        }
+
+        let chunks: Vec<Bytes> = (0..bytes.len())
+            .step_by(self.chunk_size)
+            .map(|start| bytes.slice(start..(start + self.chunk_size).min(bytes.len())))
+            .collect();
+        let manifest = ChunkManifest {
+            chunk_sizes: chunks.iter().map(|c| c.len() as u64).collect(),
+            checksum: checksum(&bytes),
+        };
+        // The chunks are those of the value already stored, whose cleanup
+        // would break it.
+        let same_as_old = old_manifest
+            .as_ref()
+            .map_or(false, |old| old.checksum == manifest.checksum);
+
+        let mut created = Vec::with_capacity(chunks.len());
+        let mut results = stream::iter(chunks.into_iter().enumerate())
+            .map(|(index, chunk)| {
+                let chunk_key = Self::chunk_key(&key, &manifest.checksum, index);
+                async move {
+                    let result = self
+                        .put_inner(
+                            ctx,
+                            chunk_key.clone(),
+                            BlobstoreBytes::from_bytes(chunk),
+                            put_behaviour,
+                            ttl,
+                        )
+                        .await;
+                    (chunk_key, result)
+                }
+            })
+            .buffer_unordered(self.concurrency);
+        let mut error = None;
+        while let Some((chunk_key, result)) = results.next().await {
+            match result {
+                // Chunks that may have been there already are those of the
+                // stored value, unless it was checked to be another one.
+                Ok(OverwriteStatus::New) => created.push(chunk_key),
+                Ok(OverwriteStatus::Prevented) => {}
+                Ok(_) if check_old && !same_as_old => created.push(chunk_key),
+                Ok(_) => {}
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        drop(results);
+
+        let result = match error {
+            Some(e) => Err(e),
+            None => {
+                self.put_inner(ctx, key.clone(), manifest.encode()?, put_behaviour, ttl)
+                    .await
+            }
+        };
+        match &result {
+            Ok(OverwriteStatus::Prevented) | Err(_) => {
+                // Best effort cleanup. Chunks without a manifest are never read.
+                for chunk_key in created {
+                    let _ = self.inner.unlink(ctx, &chunk_key).await;
+                }
+            }
+            Ok(_) => {
+                if let (Some(old_manifest), false) = (&old_manifest, same_as_old) {
+                    self.unlink_chunks(ctx, &key, old_manifest).await;
+                }
+            }
+        }
+        result.with_context(|| format!("Failed to put chunked blob {}", key))
+    }
+
+    /// The manifest of the value stored under `key`, if it is chunked.
+    async fn stored_manifest<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<ChunkManifest>> {
+        match self.inner.get(ctx, key).await? {
+            None => Ok(None),
+            Some(data) => ChunkManifest::decode(key, data.as_raw_bytes()),
+        }
+    }
+
+    /// Best effort cleanup of the chunks of `manifest`, once the value they
+    /// were part of was replaced.
+    async fn unlink_chunks<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+        manifest: &'a ChunkManifest,
+    ) {
+        for index in 0..manifest.chunk_sizes.len() {
+            let chunk_key = Self::chunk_key(key, &manifest.checksum, index);
+            let _ = self.inner.unlink(ctx, &chunk_key).await;
+        }
+    }
+
+    async fn put_inner<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: Option<PutBehaviour>,
+        ttl: Option<Duration>,
+    ) -> Result<OverwriteStatus> {
+        // Chunks expire along with their manifest.
+        match (put_behaviour, ttl) {
+            (_, Some(ttl)) => self.inner.put_with_ttl(ctx, key, value, ttl).await,
+            (Some(put_behaviour), None) => {
+                self.inner
+                    .put_explicit(ctx, key, value, put_behaviour)
+                    .await
+            }
+            (None, None) => self.inner.put_with_status(ctx, key, value).await,
+        }
+    }
+
+    async fn get_chunks<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+        manifest: ChunkManifest,
+    ) -> Result<Bytes> {
+        let total_size = manifest.chunk_sizes.iter().sum::<u64>() as usize;
+        let chunks: Vec<Bytes> = stream::iter(manifest.chunk_sizes.iter().enumerate())
+            .map(|(index, expected)| async move {
+                let chunk_key = Self::chunk_key(key, &manifest.checksum, index);
+                let chunk = self
+                    .inner
+                    .get(ctx, &chunk_key)
+                    .await?
+                    .ok_or_else(|| ErrorKind::MissingChunk {
+                        key: key.to_string(),
+                        chunk_key: chunk_key.clone(),
+                    })?
+                    .into_raw_bytes();
+                if chunk.len() as u64 != *expected {
+                    return Err(ErrorKind::ChunkSizeMismatch {
+                        key: key.to_string(),
+                        chunk_key,
+                        expected: *expected,
+                        actual: chunk.len() as u64,
+                    }
+                    .into());
+                }
+                Ok(chunk)
+            })
+            .buffered(self.concurrency)
+            .try_collect()
+            .await?;
+
+        let mut bytes = BytesMut::with_capacity(total_size);
+        for chunk in chunks {
+            bytes.extend_from_slice(&chunk);
+        }
+        let bytes = bytes.freeze();
+
+        let actual = checksum(&bytes);
+        if actual != manifest.checksum {
+            return Err(ErrorKind::ChecksumMismatch {
+                key: key.to_string(),
+                expected: manifest.checksum,
+                actual,
+            }
+            .into());
+        }
+        Ok(bytes)
+    }
+}
+
+#[async_trait]
+impl<B: BlobstoreUnlinkOps> Blobstore for ChunkingBlob<B> {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        let data = match self.inner.get(ctx, key).await? {
+            None => return Ok(None),
+            Some(data) => data,
+        };
+        match ChunkManifest::decode(key, data.as_raw_bytes())? {
+            None => Ok(Some(data)),
+            Some(manifest) => {
+                let bytes = self.get_chunks(ctx, key, manifest).await?;
+                Ok(Some(BlobstoreGetData::new(
+                    data.as_meta().clone(),
+                    BlobstoreBytes::from_bytes(bytes),
+                )))
+            }
+        }
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        self.put_impl(ctx, key, value, None, None).await?;
+        Ok(())
+    }
+
+    async fn is_present<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreIsPresent> {
+        self.inner.is_present(ctx, key).await
+    }
+}
+
+#[async_trait]
+impl<B: BlobstoreUnlinkOps> BlobstorePutOps for ChunkingBlob<B> {
+    async fn put_explicit<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+    ) -> Result<OverwriteStatus> {
+        self.put_impl(ctx, key, value, Some(put_behaviour), None)
+            .await
+    }
+
+    async fn put_with_status<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<OverwriteStatus> {
+        self.put_impl(ctx, key, value, None, None).await
+    }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        self.put_impl(ctx, key, value, None, Some(ttl)).await
+    }
+}
+
+#[async_trait]
+impl<B: BlobstoreUnlinkOps> BlobstoreUnlinkOps for ChunkingBlob<B> {
+    async fn unlink<'a>(&'a self, ctx: &'a CoreContext, key: &'a str) -> Result<()> {
+        if let Some(manifest) = self.stored_manifest(ctx, key).await? {
+            for index in 0..manifest.chunk_sizes.len() {
+                let chunk_key = Self::chunk_key(key, &manifest.checksum, index);
+                self.inner.unlink(ctx, &chunk_key).await?;
+            }
+        }
+        self.inner.unlink(ctx, key).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    use anyhow::anyhow;
+    use blobstore::BlobstoreKeyParam;
+    use blobstore::BlobstoreKeySource;
+    use borrowed::borrowed;
+    use fbinit::FacebookInit;
+    use futures::future::try_join_all;
+    use memblob::Memblob;
+
+    use super::*;
+
+    const CHUNK_SIZE: usize = 16;
+
+    /// Fails puts of the `failing` key.
+    #[derive(Debug, Default)]
+    struct FailingPuts {
+        inner: Memblob,
+        failing: Mutex<Option<String>>,
+    }
+
+    impl std::fmt::Display for FailingPuts {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "FailingPuts")
+        }
+    }
+
+    impl FailingPuts {
+        fn fail(&self, key: Option<&str>) {
+            *self.failing.lock().unwrap() = key.map(str::to_string);
+        }
+
+        fn check(&self, key: &str) -> Result<()> {
+            match self.failing.lock().unwrap().as_deref() {
+                Some(failing) if failing == key => Err(anyhow!("failed to put {}", key)),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Blobstore for FailingPuts {
+        async fn get<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: &'a str,
+        ) -> Result<Option<BlobstoreGetData>> {
+            self.inner.get(ctx, key).await
+        }
+
+        async fn put<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: String,
+            value: BlobstoreBytes,
+        ) -> Result<()> {
+            self.check(&key)?;
+            self.inner.put(ctx, key, value).await
+        }
+    }
+
+    #[async_trait]
+    impl BlobstorePutOps for FailingPuts {
+        async fn put_explicit<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: String,
+            value: BlobstoreBytes,
+            put_behaviour: PutBehaviour,
+        ) -> Result<OverwriteStatus> {
+            self.check(&key)?;
+            self.inner
+                .put_explicit(ctx, key, value, put_behaviour)
+                .await
+        }
+
+        async fn put_with_status<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: String,
+            value: BlobstoreBytes,
+        ) -> Result<OverwriteStatus> {
+            self.check(&key)?;
+            self.inner.put_with_status(ctx, key, value).await
+        }
+    }
+
+    #[async_trait]
+    impl BlobstoreUnlinkOps for FailingPuts {
+        async fn unlink<'a>(&'a self, ctx: &'a CoreContext, key: &'a str) -> Result<()> {
+            BlobstoreUnlinkOps::unlink(&self.inner, ctx, key).await
+        }
+    }
+
+    fn value(len: usize) -> BlobstoreBytes {
+        BlobstoreBytes::from_bytes((0..len).map(|i| (i % 251) as u8).collect::<Vec<u8>>())
+    }
+
+    /// The keys `value(len)` is stored under.
+    fn stored_keys(key: &str, len: usize) -> Vec<String> {
+        if len <= CHUNK_SIZE {
+            return vec![key.to_string()];
+        }
+        let checksum = checksum(&value(len).into_bytes());
+        let mut keys: Vec<String> = (0..(len + CHUNK_SIZE - 1) / CHUNK_SIZE)
+            .map(|index| ChunkingBlob::<Memblob>::chunk_key(key, &checksum, index))
+            .chain(std::iter::once(key.to_string()))
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    async fn base_keys(ctx: &CoreContext, base: &Memblob) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = base
+            .enumerate(ctx, &BlobstoreKeyParam::from(..))
+            .await?
+            .keys
+            .into_iter()
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    #[fbinit::test]
+    async fn test_roundtrip(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let base = Memblob::default();
+        let blob = ChunkingBlob::new(base.clone(), CHUNK_SIZE, 4);
+
+        // Small values are stored raw.
+        blob.put(ctx, "small".to_string(), value(CHUNK_SIZE))
+            .await?;
+        assert_eq!(
+            base.get(ctx, "small").await?.unwrap().into_bytes(),
+            value(CHUNK_SIZE)
+        );
+
+        // A small value that looks like a manifest is stored as 1 chunk.
+        let tricky = BlobstoreBytes::from_bytes(MANIFEST_MAGIC.to_vec());
+        blob.put(ctx, "tricky".to_string(), tricky.clone()).await?;
+        let tricky_chunk =
+            ChunkingBlob::<Memblob>::chunk_key("tricky", &checksum(MANIFEST_MAGIC), 0);
+        assert!(base.get(ctx, &tricky_chunk).await?.is_some());
+        assert_eq!(blob.get(ctx, "tricky").await?.unwrap().into_bytes(), tricky);
+
+        for (key, len) in [("two", CHUNK_SIZE * 2), ("many", CHUNK_SIZE * 16 + 1)] {
+            blob.put(ctx, key.to_string(), value(len)).await?;
+            for stored in stored_keys(key, len) {
+                assert!(base.get(ctx, &stored).await?.is_some());
+            }
+            assert_eq!(blob.get(ctx, key).await?.unwrap().into_bytes(), value(len));
+            assert!(
+                blob.is_present(ctx, key)
+                    .await?
+                    .assume_not_found_if_unsure()
+            );
+        }
+
+        assert!(blob.get(ctx, "missing").await?.is_none());
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_corrupted_chunk(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let base = Memblob::default();
+        let blob = ChunkingBlob::new(base.clone(), CHUNK_SIZE, 4);
+
+        blob.put(ctx, "key".to_string(), value(CHUNK_SIZE * 3))
+            .await?;
+        let checksum = checksum(&value(CHUNK_SIZE * 3).into_bytes());
+        base.put_explicit(
+            ctx,
+            ChunkingBlob::<Memblob>::chunk_key("key", &checksum, 1),
+            BlobstoreBytes::from_bytes(vec![0u8; CHUNK_SIZE]),
+            PutBehaviour::Overwrite,
+        )
+        .await?;
+
+        let err = blob.get(ctx, "key").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ErrorKind>(),
+            Some(ErrorKind::ChecksumMismatch { .. })
+        ));
+
+        let chunk_key = ChunkingBlob::<Memblob>::chunk_key("key", &checksum, 2);
+        BlobstoreUnlinkOps::unlink(&base, ctx, &chunk_key).await?;
+        let err = blob.get(ctx, "key").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ErrorKind>(),
+            Some(ErrorKind::MissingChunk { .. })
+        ));
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_concurrent_get(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = Arc::new(ChunkingBlob::new(Memblob::default(), CHUNK_SIZE, 3));
+
+        for i in 0..8 {
+            blob.put(ctx, format!("key{}", i), value(CHUNK_SIZE * i + i))
+                .await?;
+        }
+        let gets = (0..32).map(|n| {
+            let blob = blob.clone();
+            async move {
+                let i = n % 8;
+                let data = blob.get(ctx, &format!("key{}", i)).await?.unwrap();
+                assert_eq!(data.into_bytes(), value(CHUNK_SIZE * i + i));
+                Result::<_>::Ok(())
+            }
+        });
+        try_join_all(gets).await?;
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_unlink(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let base = Memblob::default();
+        let blob = ChunkingBlob::new(base.clone(), CHUNK_SIZE, 4);
+
+        blob.put(ctx, "big".to_string(), value(CHUNK_SIZE * 5))
+            .await?;
+        blob.put(ctx, "small".to_string(), value(1)).await?;
+        assert_eq!(base_keys(ctx, &base).await?.len(), 7);
+
+        blob.unlink(ctx, "big").await?;
+        assert_eq!(base_keys(ctx, &base).await?, vec!["small".to_string()]);
+        blob.unlink(ctx, "small").await?;
+        assert!(base_keys(ctx, &base).await?.is_empty());
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_failed_put_keeps_existing_chunks(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = ChunkingBlob::new(FailingPuts::default(), CHUNK_SIZE, 4);
+
+        blob.put(ctx, "key".to_string(), value(CHUNK_SIZE * 3))
+            .await?;
+        blob.as_inner().fail(Some("key"));
+        let longer = value(CHUNK_SIZE * 5);
+        assert!(
+            blob.put_explicit(ctx, "key".to_string(), longer, PutBehaviour::IfAbsent)
+                .await
+                .is_err()
+        );
+        blob.as_inner().fail(None);
+
+        // Only the chunks created by the failed put were unlinked.
+        assert_eq!(
+            base_keys(ctx, &blob.as_inner().inner).await?,
+            stored_keys("key", CHUNK_SIZE * 3)
+        );
+        assert_eq!(
+            blob.get(ctx, "key").await?.unwrap().into_bytes(),
+            value(CHUNK_SIZE * 3)
+        );
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_failed_overwrite_keeps_old_value(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob =
+            ChunkingBlob::new(FailingPuts::default(), CHUNK_SIZE, 4).with_overwrite_cleanup();
+
+        blob.put(ctx, "key".to_string(), value(CHUNK_SIZE * 3))
+            .await?;
+        // The new chunks are written, but not the manifest pointing to them.
+        blob.as_inner().fail(Some("key"));
+        let other = BlobstoreBytes::from_bytes(vec![1u8; CHUNK_SIZE * 3]);
+        assert!(
+            blob.put_explicit(ctx, "key".to_string(), other, PutBehaviour::Overwrite)
+                .await
+                .is_err()
+        );
+        blob.as_inner().fail(None);
+
+        assert_eq!(
+            base_keys(ctx, &blob.as_inner().inner).await?,
+            stored_keys("key", CHUNK_SIZE * 3)
+        );
+        assert_eq!(
+            blob.get(ctx, "key").await?.unwrap().into_bytes(),
+            value(CHUNK_SIZE * 3)
+        );
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_overwrite_unlinks_old_chunks(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let base = Memblob::default();
+        let blob = ChunkingBlob::new(base.clone(), CHUNK_SIZE, 4).with_overwrite_cleanup();
+
+        // Overwriting a value with itself keeps its chunks.
+        for len in [CHUNK_SIZE * 4, CHUNK_SIZE * 4, CHUNK_SIZE * 2, 1] {
+            blob.put_explicit(ctx, "key".to_string(), value(len), PutBehaviour::Overwrite)
+                .await?;
+            assert_eq!(base_keys(ctx, &base).await?, stored_keys("key", len));
+            assert_eq!(
+                blob.get(ctx, "key").await?.unwrap().into_bytes(),
+                value(len)
+            );
+        }
+        Ok(())
+    }
+}
@@ -11,6 +11,7 @@ use anyhow::Result;
 use async_trait::async_trait;
 use blobstore::Blobstore;
 use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreGetWithProvenance;
 use blobstore::BlobstoreIsPresent;
 use blobstore::BlobstorePutOps;
 use blobstore::OverwriteStatus;
@@ -76,6 +77,15 @@ impl<B: BlobstorePutOps> Blobstore for DelayedBlobstore<B> {
         self.inner.get(ctx, key).await
     }
 
+    async fn get_with_provenance<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreGetWithProvenance> {
+        delay(self.get_dist).await;
+        self.inner.get_with_provenance(ctx, key).await
+    }
+
     async fn put<'a>(
         &'a self,
         ctx: &'a CoreContext,
@@ -136,6 +146,17 @@ impl<T: BlobstorePutOps> BlobstorePutOps for DelayedBlobstore<T> {
     ) -> Result<OverwriteStatus> {
         self.put_impl(ctx, key, value, None).await
     }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        delay(self.put_dist).await;
+        self.inner.put_with_ttl(ctx, key, value, ttl).await
+    }
 }
 
 async fn delay<D>(distribution: Option<D>)
@@ -16,6 +16,7 @@ mod blobstore;
 #[cfg(fbcode_build)]
 mod facebook;
 mod sql;
+mod stack;
 
 pub use ::blobstore::PutBehaviour;
 pub use ::blobstore::DEFAULT_PUT_BEHAVIOUR;
@@ -48,6 +49,13 @@ pub use crate::blobstore::make_sql_blobstore_xdb;
 pub use crate::blobstore::BlobstoreOptions;
 pub use crate::sql::MetadataSqlFactory;
 pub use crate::sql::SqlTierInfo;
+pub use crate::stack::BlobstoreStackBuilder;
+pub use crate::stack::LoggingConfig;
+pub use crate::stack::NamespaceConfig;
+pub use crate::stack::StackConfig;
+pub use crate::stack::StackError;
+pub use crate::stack::StackHandles;
+pub use crate::stack::ThrottleConfig;
 
 #[derive(Copy, Clone, PartialEq)]
 pub struct ReadOnlyStorage(pub bool);
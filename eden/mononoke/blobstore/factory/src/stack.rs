@@ -0,0 +1,541 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Assembling the wrappers of a blobstore in a fixed order.
+//!
+//! From the outside in, a stack built by `BlobstoreStackBuilder` is:
+//!
+//! 1. `CountedBlobstore`, counting the operations of the callers.
+//! 2. `LogBlob`, so that logged durations include the time spent waiting
+//!    for the throttle.
+//! 3. `ThrottledBlob`.
+//! 4. `IntegrityBlob`, which must see the keys of the callers to recognize
+//!    the content-addressed ones, and the values as the callers wrote them.
+//! 5. `NamespacedBlob`, right above the base blobstore.
+//!
+//! Layers that are not enabled are left out.
+
+use std::num::NonZeroU32;
+use std::num::NonZeroU64;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+use anyhow::Result;
+use blobstore::BlobstorePutOps;
+use blobstore::CountedBlobstore;
+use blobstore::CountedBlobstoreCounters;
+use fbinit::FacebookInit;
+use integrityblob::IntegrityBlob;
+use integrityblob::IntegrityCounters;
+use integrityblob::KeyParser;
+use logblob::HotKeyTracker;
+use logblob::HotKeyTrackerConfig;
+use logblob::LogBlob;
+use namespacedblob::GenerationHandle;
+use namespacedblob::NamespaceError;
+use namespacedblob::NamespacedBlob;
+use scuba_ext::MononokeScubaSampleBuilder;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+use throttledblob::RequestClassShares;
+use throttledblob::ThrottleCounters;
+use throttledblob::ThrottleOptions;
+use throttledblob::ThrottledBlob;
+
+/// The layers of a stack, for services to configure it from their config
+/// files. Layers are enabled by setting their field.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StackConfig {
+    /// Prefix of the stats of the `CountedBlobstore` layer.
+    pub counted: Option<String>,
+    pub logging: Option<LoggingConfig>,
+    pub throttle: Option<ThrottleConfig>,
+    /// Enable the `IntegrityBlob` layer. Its key parser must be given with
+    /// `BlobstoreStackBuilder::with_key_parser`.
+    pub integrity: bool,
+    pub namespace: Option<NamespaceConfig>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Log to the discard builder if not set.
+    #[serde(default)]
+    pub scuba_table: Option<String>,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: NonZeroU64,
+    /// Track hot keys with the default `HotKeyTrackerConfig`.
+    #[serde(default)]
+    pub track_hot_keys: bool,
+}
+
+fn default_sample_rate() -> NonZeroU64 {
+    NonZeroU64::new(1).unwrap()
+}
+
+/// The fields of `ThrottleOptions`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThrottleConfig {
+    pub read_qps: Option<NonZeroU32>,
+    pub write_qps: Option<NonZeroU32>,
+    pub read_bytes: Option<NonZeroUsize>,
+    pub write_bytes: Option<NonZeroUsize>,
+    pub read_burst_bytes: Option<NonZeroUsize>,
+    pub write_burst_bytes: Option<NonZeroUsize>,
+    pub bytes_min_count: Option<NonZeroUsize>,
+    pub class_shares: Option<ClassSharesConfig>,
+}
+
+/// The fields of `RequestClassShares`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClassSharesConfig {
+    pub interactive: u32,
+    pub background: u32,
+    pub scavenger: u32,
+}
+
+impl From<ClassSharesConfig> for RequestClassShares {
+    fn from(config: ClassSharesConfig) -> Self {
+        RequestClassShares {
+            interactive: config.interactive,
+            background: config.background,
+            scavenger: config.scavenger,
+        }
+    }
+}
+
+impl From<ThrottleConfig> for ThrottleOptions {
+    fn from(config: ThrottleConfig) -> Self {
+        ThrottleOptions {
+            read_qps: config.read_qps,
+            write_qps: config.write_qps,
+            read_bytes: config.read_bytes,
+            write_bytes: config.write_bytes,
+            read_burst_bytes: config.read_burst_bytes,
+            write_burst_bytes: config.write_burst_bytes,
+            bytes_min_count: config.bytes_min_count,
+            class_shares: config.class_shares.map(Into::into),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamespaceConfig {
+    pub namespace: String,
+    #[serde(default)]
+    pub generation: u64,
+}
+
+/// A combination of layers that `BlobstoreStackBuilder::build` refuses.
+#[derive(Debug, Error)]
+pub enum StackError {
+    #[error("Hot key tracking needs the logging layer")]
+    HotKeysWithoutLogging,
+    #[error("The integrity layer needs a key parser")]
+    MissingKeyParser,
+    #[error("The throttle layer has no read or write limit")]
+    NoThrottleLimit,
+    #[error(transparent)]
+    InvalidNamespace(#[from] NamespaceError),
+}
+
+/// The handles of the layers of a stack, to observe or control them once
+/// the stack is type-erased. They are `None` for layers that are not
+/// enabled.
+#[derive(Clone, Default)]
+pub struct StackHandles {
+    pub counted: Option<Arc<CountedBlobstoreCounters>>,
+    pub throttle: Option<Arc<ThrottleCounters>>,
+    pub hot_keys: Option<Arc<HotKeyTracker>>,
+    pub integrity: Option<Arc<IntegrityCounters>>,
+    pub generation: Option<GenerationHandle>,
+}
+
+struct Logging {
+    scuba: MononokeScubaSampleBuilder,
+    sample_rate: NonZeroU64,
+}
+
+/// Builds a stack of wrappers over a blobstore, in the order documented in
+/// this module whatever the order of the calls.
+#[derive(Default)]
+pub struct BlobstoreStackBuilder {
+    counted: Option<String>,
+    logging: Option<Logging>,
+    hot_keys: Option<HotKeyTrackerConfig>,
+    throttle: Option<ThrottleOptions>,
+    integrity: bool,
+    key_parser: Option<KeyParser>,
+    namespace: Option<NamespaceConfig>,
+}
+
+impl BlobstoreStackBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A builder with the layers of `config`.
+    pub fn from_config(fb: FacebookInit, config: &StackConfig) -> Result<Self> {
+        let mut builder = Self::new();
+        if let Some(name) = &config.counted {
+            builder = builder.with_counting(name.clone());
+        }
+        if let Some(logging) = &config.logging {
+            let scuba = match &logging.scuba_table {
+                Some(table) => MononokeScubaSampleBuilder::new(fb, table)?,
+                None => MononokeScubaSampleBuilder::with_discard(),
+            };
+            builder = builder.with_logging(scuba, logging.sample_rate);
+            if logging.track_hot_keys {
+                builder = builder.with_hot_key_tracking(HotKeyTrackerConfig::default());
+            }
+        }
+        if let Some(throttle) = &config.throttle {
+            builder = builder.with_throttling(throttle.clone().into());
+        }
+        builder.integrity = config.integrity;
+        if let Some(namespace) = &config.namespace {
+            builder = builder.with_namespace(namespace.namespace.clone(), namespace.generation);
+        }
+        Ok(builder)
+    }
+
+    pub fn with_counting(mut self, name: impl Into<String>) -> Self {
+        self.counted = Some(name.into());
+        self
+    }
+
+    pub fn with_logging(
+        mut self,
+        scuba: MononokeScubaSampleBuilder,
+        sample_rate: NonZeroU64,
+    ) -> Self {
+        self.logging = Some(Logging { scuba, sample_rate });
+        self
+    }
+
+    /// Track the keys of gets in the logging layer, see
+    /// `StackHandles::hot_keys`.
+    pub fn with_hot_key_tracking(mut self, config: HotKeyTrackerConfig) -> Self {
+        self.hot_keys = Some(config);
+        self
+    }
+
+    pub fn with_throttling(mut self, options: ThrottleOptions) -> Self {
+        self.throttle = Some(options);
+        self
+    }
+
+    pub fn with_integrity(mut self, key_parser: KeyParser) -> Self {
+        self.integrity = true;
+        self.key_parser = Some(key_parser);
+        self
+    }
+
+    /// The key parser of the integrity layer, for an integrity layer enabled
+    /// by a `StackConfig`. It is ignored if the layer is not enabled.
+    pub fn with_key_parser(mut self, key_parser: KeyParser) -> Self {
+        self.key_parser = Some(key_parser);
+        self
+    }
+
+    pub fn with_namespace(mut self, namespace: impl Into<String>, generation: u64) -> Self {
+        self.namespace = Some(NamespaceConfig {
+            namespace: namespace.into(),
+            generation,
+        });
+        self
+    }
+
+    /// Check the layers, and stack them over `base`.
+    pub async fn build(
+        self,
+        base: Arc<dyn BlobstorePutOps>,
+    ) -> Result<(Arc<dyn BlobstorePutOps>, StackHandles), StackError> {
+        if self.hot_keys.is_some() && self.logging.is_none() {
+            return Err(StackError::HotKeysWithoutLogging);
+        }
+        if self.integrity && self.key_parser.is_none() {
+            return Err(StackError::MissingKeyParser);
+        }
+        if let Some(throttle) = &self.throttle {
+            if !throttle.has_throttle() {
+                return Err(StackError::NoThrottleLimit);
+            }
+        }
+
+        let mut handles = StackHandles::default();
+        let mut store = base;
+        if let Some(namespace) = self.namespace {
+            let blob = NamespacedBlob::new(store, &namespace.namespace, namespace.generation)?;
+            handles.generation = Some(blob.generation());
+            store = Arc::new(blob);
+        }
+        if let (true, Some(key_parser)) = (self.integrity, self.key_parser) {
+            let blob = IntegrityBlob::new(store, key_parser);
+            handles.integrity = Some(blob.shared_counters());
+            store = Arc::new(blob);
+        }
+        if let Some(throttle) = self.throttle {
+            let blob = ThrottledBlob::new(store, throttle).await;
+            handles.throttle = Some(blob.shared_counters());
+            store = Arc::new(blob);
+        }
+        if let Some(logging) = self.logging {
+            let mut blob = LogBlob::new(store, logging.scuba, logging.sample_rate);
+            if let Some(config) = self.hot_keys {
+                let tracker = Arc::new(HotKeyTracker::new(config));
+                handles.hot_keys = Some(tracker.clone());
+                blob = blob.with_hot_key_tracker(tracker);
+            }
+            store = Arc::new(blob);
+        }
+        if let Some(name) = self.counted {
+            let blob = CountedBlobstore::new(name, store);
+            handles.counted = Some(blob.shared_counters());
+            store = Arc::new(blob);
+        }
+        Ok((store, handles))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use blobstore::Blobstore;
+    use blobstore_test_utils::TtlSpy;
+    use borrowed::borrowed;
+    use context::CoreContext;
+    use integrityblob::ExpectedHash;
+    use integrityblob::HashAlgorithm;
+    use memblob::Memblob;
+    use mononoke_types::BlobstoreBytes;
+    use sha2::Digest;
+    use sha2::Sha256;
+
+    use super::*;
+
+    /// Keys of the form `content.<sha256>`.
+    fn key_parser() -> KeyParser {
+        Arc::new(|key: &str| {
+            let digest = key.strip_prefix("content.")?;
+            Some(ExpectedHash {
+                algorithm: HashAlgorithm::Sha256,
+                digest: hex::decode(digest).ok()?,
+            })
+        })
+    }
+
+    fn content_key(value: &[u8]) -> String {
+        format!("content.{}", hex::encode(Sha256::digest(value)))
+    }
+
+    fn throttle() -> ThrottleOptions {
+        ThrottleOptions {
+            read_qps: NonZeroU32::new(1000),
+            ..Default::default()
+        }
+    }
+
+    fn sample_rate() -> NonZeroU64 {
+        NonZeroU64::new(1).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_canonical_order() -> Result<()> {
+        let base = Arc::new(Memblob::default());
+        let (store, _) = BlobstoreStackBuilder::new()
+            .with_namespace("tenant", 3)
+            .with_integrity(key_parser())
+            .with_counting("test")
+            .with_throttling(throttle())
+            .with_logging(MononokeScubaSampleBuilder::with_discard(), sample_rate())
+            .build(base.clone())
+            .await?;
+        assert_eq!(
+            store.to_string(),
+            "CountedBlob<LogBlob<ThrottledBlob<IntegrityBlob<NamespacedBlob<tenant, g3, Memblob>>>>>"
+        );
+
+        let (store, _) = BlobstoreStackBuilder::new()
+            .with_integrity(key_parser())
+            .with_logging(MononokeScubaSampleBuilder::with_discard(), sample_rate())
+            .build(base.clone())
+            .await?;
+        assert_eq!(store.to_string(), "LogBlob<IntegrityBlob<Memblob>>");
+
+        let (store, _) = BlobstoreStackBuilder::new().build(base).await?;
+        assert_eq!(store.to_string(), "Memblob");
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_config(fb: FacebookInit) -> Result<()> {
+        let config: StackConfig = serde_json::from_str(
+            r#"{
+                "counted": "test",
+                "logging": {"track_hot_keys": true},
+                "throttle": {
+                    "write_qps": 100,
+                    "class_shares": {"interactive": 6, "background": 3, "scavenger": 1}
+                },
+                "integrity": true,
+                "namespace": {"namespace": "tenant"}
+            }"#,
+        )?;
+        let options = ThrottleOptions::from(config.throttle.clone().unwrap());
+        assert_eq!(
+            options.class_shares,
+            Some(RequestClassShares {
+                interactive: 6,
+                background: 3,
+                scavenger: 1,
+            })
+        );
+        let (store, handles) = BlobstoreStackBuilder::from_config(fb, &config)?
+            .with_key_parser(key_parser())
+            .build(Arc::new(Memblob::default()))
+            .await?;
+        assert_eq!(
+            store.to_string(),
+            "CountedBlob<LogBlob<ThrottledBlob<IntegrityBlob<NamespacedBlob<tenant, g0, Memblob>>>>>"
+        );
+        assert!(handles.counted.is_some());
+        assert!(handles.throttle.is_some());
+        assert!(handles.hot_keys.is_some());
+
+        assert!(serde_json::from_str::<StackConfig>(r#"{"compression": true}"#).is_err());
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_invalid(fb: FacebookInit) -> Result<()> {
+        let base: Arc<dyn BlobstorePutOps> = Arc::new(Memblob::default());
+
+        let err = BlobstoreStackBuilder::new()
+            .with_hot_key_tracking(HotKeyTrackerConfig::default())
+            .build(base.clone())
+            .await
+            .err();
+        assert!(matches!(err, Some(StackError::HotKeysWithoutLogging)));
+
+        let config = StackConfig {
+            integrity: true,
+            ..Default::default()
+        };
+        let err = BlobstoreStackBuilder::from_config(fb, &config)?
+            .build(base.clone())
+            .await
+            .err();
+        assert!(matches!(err, Some(StackError::MissingKeyParser)));
+
+        let err = BlobstoreStackBuilder::new()
+            .with_throttling(ThrottleOptions::default())
+            .build(base.clone())
+            .await
+            .err();
+        assert!(matches!(err, Some(StackError::NoThrottleLimit)));
+
+        let err = BlobstoreStackBuilder::new()
+            .with_namespace("a.b", 0)
+            .build(base)
+            .await
+            .err();
+        assert!(matches!(
+            err,
+            Some(StackError::InvalidNamespace(
+                NamespaceError::InvalidNamespace(_)
+            ))
+        ));
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_full_stack(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let base = Arc::new(Memblob::default());
+        let (store, handles) = BlobstoreStackBuilder::new()
+            .with_counting("test")
+            .with_logging(MononokeScubaSampleBuilder::with_discard(), sample_rate())
+            .with_hot_key_tracking(HotKeyTrackerConfig::default())
+            .with_throttling(throttle())
+            .with_integrity(key_parser())
+            .with_namespace("tenant", 0)
+            .build(base.clone())
+            .await?;
+
+        let key = content_key(b"hello");
+        store
+            .put(ctx, key.clone(), BlobstoreBytes::from_bytes(&b"hello"[..]))
+            .await?;
+        let value = store.get(ctx, &key).await?.map(|d| d.into_raw_bytes());
+        assert_eq!(value.as_deref(), Some(&b"hello"[..]));
+
+        // The put and get went through every layer.
+        let counted = handles.counted.unwrap();
+        assert_eq!(counted.gets.load(Ordering::Relaxed), 1);
+        assert_eq!(counted.puts.load(Ordering::Relaxed), 1);
+        let throttle = handles.throttle.unwrap();
+        assert_eq!(throttle.reads.load(Ordering::Relaxed), 1);
+        assert_eq!(throttle.writes.load(Ordering::Relaxed), 1);
+        // The value was hashed on put and get.
+        let integrity = handles.integrity.unwrap();
+        assert_eq!(integrity.hashed.load(Ordering::Relaxed), 2);
+        // The get was logged.
+        let hot_keys = handles.hot_keys.unwrap().hot_keys(1);
+        assert_eq!(hot_keys, vec![(key.clone(), 1)]);
+        // The value was stored under the namespace of the generation.
+        let generation = handles.generation.unwrap();
+        let raw_key = format!("nstenant.g0.{}", key);
+        assert!(base.get(ctx, &raw_key).await?.is_some());
+        generation.bump();
+        assert!(store.get(ctx, &key).await?.is_none());
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_ttl_through_stack(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let spy = TtlSpy::new();
+        let (store, _) = BlobstoreStackBuilder::new()
+            .with_counting("test")
+            .with_logging(MononokeScubaSampleBuilder::with_discard(), sample_rate())
+            .with_throttling(ThrottleOptions {
+                write_qps: NonZeroU32::new(1000),
+                ..Default::default()
+            })
+            .with_integrity(key_parser())
+            .with_namespace("tenant", 0)
+            .build(Arc::new(spy.clone()))
+            .await?;
+
+        let key = content_key(b"hello");
+        let ttl = Duration::from_secs(60);
+        store
+            .put_with_ttl(
+                ctx,
+                key.clone(),
+                BlobstoreBytes::from_bytes(&b"hello"[..]),
+                ttl,
+            )
+            .await?;
+        assert_eq!(
+            spy.puts(),
+            vec![(format!("nstenant.g0.{}", key), Some(ttl))]
+        );
+        Ok(())
+    }
+}
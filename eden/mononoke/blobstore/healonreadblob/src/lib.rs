@@ -0,0 +1,581 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! A blobstore over a primary and secondary stores holding copies of the
+//! same blobs, that repairs missing copies when it reads them.
+//!
+//! Gets try the primary, then the secondaries in order. When the value is
+//! found in a store after others returned nothing, it is put into those
+//! stores from a background task, unless they got the key meanwhile. A
+//! sample of gets served by the primary also check from the background
+//! whether the secondaries have the key, and heal those that don't. Heals never delay or change the result returned
+//! to the caller: at most `max_concurrency` of them run at once, at most
+//! `max_queued` wait, and further ones are dropped and counted.
+
+use std::fmt;
+use std::num::NonZeroU64;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use blobstore::Blobstore;
+use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreIsPresent;
+use blobstore::BlobstorePutOps;
+use blobstore::BlobstoreUnlinkOps;
+use blobstore::OverwriteStatus;
+use blobstore::PutBehaviour;
+use context::CoreContext;
+use futures::future::join_all;
+use futures::Future;
+use mononoke_types::BlobstoreBytes;
+use rand::Rng;
+use slog::warn;
+use tokio::sync::Notify;
+use tokio::sync::Semaphore;
+
+/// How writes to all stores succeed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Fail if any store fails, with the error of the first failing store.
+    AllStores,
+    /// Succeed if any store succeeds. Stores missing the blob are healed
+    /// by later reads.
+    BestEffort,
+}
+
+#[derive(Clone, Debug)]
+pub struct HealOnReadOptions {
+    /// One in this many gets served by the primary checks whether the
+    /// secondaries have the key. `None` never checks.
+    pub probe_sampling: Option<NonZeroU64>,
+    /// Maximum number of heals running at once, for all stores.
+    pub max_concurrency: usize,
+    /// Maximum number of heals running or waiting to run. Further ones are
+    /// dropped.
+    pub max_queued: usize,
+    pub write_policy: WritePolicy,
+}
+
+impl Default for HealOnReadOptions {
+    fn default() -> Self {
+        Self {
+            probe_sampling: None,
+            max_concurrency: 10,
+            max_queued: 1000,
+            write_policy: WritePolicy::AllStores,
+        }
+    }
+}
+
+/// Heals of a store since the `HealOnReadBlob` was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StoreHealStats {
+    /// Puts of a missing blob into the store that succeeded. Those finding
+    /// that the store got the blob meanwhile are not counted.
+    pub healed: u64,
+    /// Puts of a missing blob into the store that failed.
+    pub failed: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HealStats {
+    /// By store, the primary first.
+    pub stores: Vec<StoreHealStats>,
+    /// Heals dropped because `max_queued` were running or waiting.
+    pub dropped: u64,
+    /// Heals running or waiting to run.
+    pub queued: u64,
+}
+
+#[derive(Default)]
+struct StoreCounters {
+    healed: AtomicU64,
+    failed: AtomicU64,
+}
+
+struct Shared {
+    stores: Vec<StoreCounters>,
+    dropped: AtomicU64,
+    queued: AtomicU64,
+    max_queued: u64,
+    permits: Semaphore,
+    /// Notified when no heal is queued.
+    idle: Notify,
+}
+
+impl Shared {
+    fn done(&self) {
+        if self.queued.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.idle.notify_waiters();
+        }
+    }
+}
+
+/// Handle to the heal counters of a `HealOnReadBlob`.
+#[derive(Clone)]
+pub struct HealHandle {
+    shared: Arc<Shared>,
+}
+
+impl HealHandle {
+    pub fn stats(&self) -> HealStats {
+        HealStats {
+            stores: self
+                .shared
+                .stores
+                .iter()
+                .map(|counters| StoreHealStats {
+                    healed: counters.healed.load(Ordering::Relaxed),
+                    failed: counters.failed.load(Ordering::Relaxed),
+                })
+                .collect(),
+            dropped: self.shared.dropped.load(Ordering::Relaxed),
+            queued: self.shared.queued.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Wait until the heals queued so far finished.
+    pub async fn wait_idle(&self) {
+        loop {
+            let idle = self.shared.idle.notified();
+            if self.shared.queued.load(Ordering::Acquire) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+/// Stores to heal with a value found by a get.
+enum HealTargets {
+    /// Stores that returned nothing for the key.
+    Missing(Vec<usize>),
+    /// Secondaries that don't have the key, found with `is_present`.
+    Probe,
+}
+
+/// Reads from the first store that has a blob, and heals the others. See
+/// the crate documentation.
+pub struct HealOnReadBlob<B> {
+    /// The primary first, then the secondaries.
+    stores: Arc<Vec<B>>,
+    probe_sampling: Option<NonZeroU64>,
+    write_policy: WritePolicy,
+    shared: Arc<Shared>,
+}
+
+impl<B: fmt::Display> fmt::Display for HealOnReadBlob<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HealOnReadBlob<")?;
+        for (i, store) in self.stores.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", store)?;
+        }
+        write!(f, ">")
+    }
+}
+
+impl<B: fmt::Debug> fmt::Debug for HealOnReadBlob<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HealOnReadBlob")
+            .field("stores", &self.stores)
+            .field("probe_sampling", &self.probe_sampling)
+            .field("write_policy", &self.write_policy)
+            .finish()
+    }
+}
+
+impl<B> HealOnReadBlob<B> {
+    pub fn new(primary: B, secondaries: Vec<B>, options: HealOnReadOptions) -> Self {
+        let mut stores = vec![primary];
+        stores.extend(secondaries);
+        let shared = Shared {
+            stores: stores.iter().map(|_| StoreCounters::default()).collect(),
+            dropped: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+            max_queued: options.max_queued as u64,
+            permits: Semaphore::new(options.max_concurrency),
+            idle: Notify::new(),
+        };
+        Self {
+            stores: Arc::new(stores),
+            probe_sampling: options.probe_sampling,
+            write_policy: options.write_policy,
+            shared: Arc::new(shared),
+        }
+    }
+
+    pub fn handle(&self) -> HealHandle {
+        HealHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    fn probe_sampled(&self) -> bool {
+        match self.probe_sampling {
+            Some(rate) if self.stores.len() > 1 => {
+                rate.get() == 1 || rand::thread_rng().gen_range(0..rate.get()) == 0
+            }
+            _ => false,
+        }
+    }
+
+    /// Run `write` on every store, and combine the results according to
+    /// the write policy.
+    async fn write_all<'a, T, Fut>(&'a self, write: impl Fn(&'a B) -> Fut) -> Result<T>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        let results = join_all(self.stores.iter().map(write)).await;
+        let mut first_ok = None;
+        let mut first_err = None;
+        for result in results {
+            match result {
+                Ok(value) => {
+                    first_ok.get_or_insert(value);
+                }
+                Err(e) => {
+                    first_err.get_or_insert(e);
+                }
+            }
+        }
+        match (self.write_policy, first_ok, first_err) {
+            (WritePolicy::AllStores, _, Some(e)) => Err(e),
+            (_, Some(value), _) => Ok(value),
+            (_, None, Some(e)) => Err(e),
+            (_, None, None) => unreachable!("HealOnReadBlob has at least one store"),
+        }
+    }
+}
+
+impl<B: BlobstorePutOps + 'static> HealOnReadBlob<B> {
+    /// Put `value` into the stores missing it in the background, without
+    /// overwriting a value put since it was read. Must be called from a
+    /// Tokio runtime.
+    fn heal(&self, ctx: &CoreContext, key: &str, value: BlobstoreBytes, targets: HealTargets) {
+        let shared = &self.shared;
+        if shared.queued.fetch_add(1, Ordering::AcqRel) >= shared.max_queued {
+            shared.dropped.fetch_add(1, Ordering::Relaxed);
+            shared.done();
+            return;
+        }
+        let ctx = ctx.clone();
+        let key = key.to_string();
+        let stores = self.stores.clone();
+        let shared = self.shared.clone();
+        tokio::spawn(async move {
+            if let Ok(_permit) = shared.permits.acquire().await {
+                let targets = match targets {
+                    HealTargets::Missing(targets) => targets,
+                    HealTargets::Probe => probe(&ctx, &stores, &key).await,
+                };
+                for i in targets {
+                    let counters = &shared.stores[i];
+                    let put = stores[i].put_explicit(
+                        &ctx,
+                        key.clone(),
+                        value.clone(),
+                        PutBehaviour::IfAbsent,
+                    );
+                    match put.await {
+                        Ok(OverwriteStatus::Prevented) => {}
+                        Ok(_) => {
+                            counters.healed.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(e) => {
+                            counters.failed.fetch_add(1, Ordering::Relaxed);
+                            warn!(
+                                ctx.logger(),
+                                "Failed to heal key {} in {}: {:#}", key, stores[i], e
+                            );
+                        }
+                    }
+                }
+            }
+            shared.done();
+        });
+    }
+}
+
+/// The secondaries in `stores` that don't have `key`.
+async fn probe<B: Blobstore>(ctx: &CoreContext, stores: &[B], key: &str) -> Vec<usize> {
+    let present = join_all(stores[1..].iter().map(|store| store.is_present(ctx, key))).await;
+    present
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, present)| match present {
+            Ok(BlobstoreIsPresent::Absent) => Some(i + 1),
+            _ => None,
+        })
+        .collect()
+}
+
+#[async_trait]
+impl<B: BlobstorePutOps + 'static> Blobstore for HealOnReadBlob<B> {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        let mut missing = Vec::new();
+        let mut first_err = None;
+        for (i, store) in self.stores.iter().enumerate() {
+            match store.get(ctx, key).await {
+                Ok(Some(value)) => {
+                    if !missing.is_empty() {
+                        let targets = HealTargets::Missing(missing);
+                        self.heal(ctx, key, value.as_bytes().clone(), targets);
+                    } else if i == 0 && self.probe_sampled() {
+                        self.heal(ctx, key, value.as_bytes().clone(), HealTargets::Probe);
+                    }
+                    return Ok(Some(value));
+                }
+                Ok(None) => missing.push(i),
+                Err(e) => {
+                    first_err.get_or_insert(e);
+                }
+            }
+        }
+        // A store that failed may have the blob.
+        match first_err {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        self.write_all(|store| store.put(ctx, key.clone(), value.clone()))
+            .await
+    }
+
+    async fn is_present<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreIsPresent> {
+        let mut result = BlobstoreIsPresent::Absent;
+        for store in self.stores.iter() {
+            match store.is_present(ctx, key).await {
+                Ok(BlobstoreIsPresent::Present) => return Ok(BlobstoreIsPresent::Present),
+                Ok(BlobstoreIsPresent::Absent) => {}
+                Ok(BlobstoreIsPresent::ProbablyNotPresent(e)) | Err(e) => {
+                    if let BlobstoreIsPresent::Absent = result {
+                        result = BlobstoreIsPresent::ProbablyNotPresent(e);
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    async fn copy<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        old_key: &'a str,
+        new_key: String,
+    ) -> Result<()> {
+        self.write_all(|store| store.copy(ctx, old_key, new_key.clone()))
+            .await
+    }
+}
+
+#[async_trait]
+impl<B: BlobstorePutOps + 'static> BlobstorePutOps for HealOnReadBlob<B> {
+    async fn put_explicit<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+    ) -> Result<OverwriteStatus> {
+        self.write_all(|store| store.put_explicit(ctx, key.clone(), value.clone(), put_behaviour))
+            .await
+    }
+
+    async fn put_with_status<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<OverwriteStatus> {
+        self.write_all(|store| store.put_with_status(ctx, key.clone(), value.clone()))
+            .await
+    }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        self.write_all(|store| store.put_with_ttl(ctx, key.clone(), value.clone(), ttl))
+            .await
+    }
+}
+
+#[async_trait]
+impl<B: BlobstoreUnlinkOps + 'static> BlobstoreUnlinkOps for HealOnReadBlob<B> {
+    async fn unlink<'a>(&'a self, ctx: &'a CoreContext, key: &'a str) -> Result<()> {
+        self.write_all(|store| store.unlink(ctx, key)).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use borrowed::borrowed;
+    use fbinit::FacebookInit;
+    use memblob::Memblob;
+
+    use super::*;
+
+    fn bytes(value: &[u8]) -> BlobstoreBytes {
+        BlobstoreBytes::from_bytes(value.to_vec())
+    }
+
+    async fn value(ctx: &CoreContext, store: &Memblob, key: &str) -> Result<Option<Vec<u8>>> {
+        let data = store.get(ctx, key).await?;
+        Ok(data.map(|d| d.into_raw_bytes().to_vec()))
+    }
+
+    fn stats(healed: [u64; 3]) -> HealStats {
+        HealStats {
+            stores: healed
+                .iter()
+                .map(|healed| StoreHealStats {
+                    healed: *healed,
+                    failed: 0,
+                })
+                .collect(),
+            dropped: 0,
+            queued: 0,
+        }
+    }
+
+    #[fbinit::test]
+    async fn test_heal_on_read(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let stores = [Memblob::default(), Memblob::default(), Memblob::default()];
+        let blob = HealOnReadBlob::new(
+            stores[0].clone(),
+            vec![stores[1].clone(), stores[2].clone()],
+            Default::default(),
+        );
+        let handle = blob.handle();
+
+        blob.put(ctx, "key".to_string(), bytes(b"value")).await?;
+        for store in &stores {
+            assert_eq!(value(ctx, store, "key").await?, Some(b"value".to_vec()));
+        }
+
+        // Lost by the primary and the first secondary.
+        stores[0].unlink(ctx, "key").await?;
+        stores[1].unlink(ctx, "key").await?;
+        let data = blob.get(ctx, "key").await?.unwrap();
+        assert_eq!(data.into_raw_bytes().as_ref(), b"value");
+        handle.wait_idle().await;
+        for store in &stores {
+            assert_eq!(value(ctx, store, "key").await?, Some(b"value".to_vec()));
+        }
+        assert_eq!(handle.stats(), stats([1, 1, 0]));
+
+        // Nothing to heal.
+        assert!(blob.get(ctx, "key").await?.is_some());
+        assert!(blob.get(ctx, "absent").await?.is_none());
+        handle.wait_idle().await;
+        assert_eq!(handle.stats(), stats([1, 1, 0]));
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_probe(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let stores = [Memblob::default(), Memblob::default(), Memblob::default()];
+        let blob = HealOnReadBlob::new(
+            stores[0].clone(),
+            vec![stores[1].clone(), stores[2].clone()],
+            HealOnReadOptions {
+                probe_sampling: NonZeroU64::new(1),
+                ..Default::default()
+            },
+        );
+        let handle = blob.handle();
+
+        // Only the primary has the key, so a get without probes would
+        // never heal the secondaries.
+        stores[0]
+            .put(ctx, "key".to_string(), bytes(b"value"))
+            .await?;
+        assert!(blob.get(ctx, "key").await?.is_some());
+        handle.wait_idle().await;
+        for store in &stores {
+            assert_eq!(value(ctx, store, "key").await?, Some(b"value".to_vec()));
+        }
+        assert_eq!(handle.stats(), stats([0, 1, 1]));
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_heal_if_absent(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let stores = [Memblob::default(), Memblob::default()];
+        let blob = HealOnReadBlob::new(
+            stores[0].clone(),
+            vec![stores[1].clone()],
+            Default::default(),
+        );
+        let handle = blob.handle();
+
+        // The primary got a new value after the old one was read from the
+        // secondary.
+        stores[0].put(ctx, "key".to_string(), bytes(b"new")).await?;
+        let targets = HealTargets::Missing(vec![0]);
+        blob.heal(ctx, "key", bytes(b"old"), targets);
+        handle.wait_idle().await;
+        assert_eq!(value(ctx, &stores[0], "key").await?, Some(b"new".to_vec()));
+        assert_eq!(handle.stats().stores[0], StoreHealStats::default());
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_dropped(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let stores = [Memblob::default(), Memblob::default()];
+        let blob = HealOnReadBlob::new(
+            stores[0].clone(),
+            vec![stores[1].clone()],
+            HealOnReadOptions {
+                max_queued: 0,
+                ..Default::default()
+            },
+        );
+        stores[1]
+            .put(ctx, "key".to_string(), bytes(b"value"))
+            .await?;
+        assert!(blob.get(ctx, "key").await?.is_some());
+        let handle = blob.handle();
+        handle.wait_idle().await;
+        assert_eq!(handle.stats().dropped, 1);
+        assert!(stores[0].get(ctx, "key").await?.is_none());
+        Ok(())
+    }
+}
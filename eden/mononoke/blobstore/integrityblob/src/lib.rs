@@ -0,0 +1,389 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::fmt;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use async_trait::async_trait;
+use blobstore::Blobstore;
+use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreIsPresent;
+use blobstore::BlobstorePutOps;
+use blobstore::BlobstoreUnlinkOps;
+use blobstore::OverwriteStatus;
+use blobstore::PutBehaviour;
+use context::CoreContext;
+use mononoke_types::hash::Context as HashContext;
+use mononoke_types::BlobstoreBytes;
+use sha2::Digest;
+use sha2::Sha256;
+use thiserror::Error;
+
+/// Hash algorithms used in content-addressed keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// Blake2b with a 32 byte digest, keyed with `key` as in
+    /// `mononoke_types::hash::Context`.
+    Blake2 {
+        key: &'static [u8],
+    },
+    Sha256,
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashAlgorithm::Blake2 { .. } => write!(f, "blake2"),
+            HashAlgorithm::Sha256 => write!(f, "sha256"),
+        }
+    }
+}
+
+/// The hash a content-addressed key says its value has.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExpectedHash {
+    pub algorithm: HashAlgorithm,
+    pub digest: Vec<u8>,
+}
+
+/// Returns the expected hash of the value for a content-addressed key, or
+/// `None` if the key is not content-addressed.
+pub type KeyParser = Arc<dyn Fn(&str) -> Option<ExpectedHash> + Send + Sync>;
+
+#[derive(Debug, Error)]
+#[error("{algorithm} hash mismatch for key {key}: expected {expected}, got {actual}")]
+pub struct HashMismatch {
+    pub key: String,
+    pub algorithm: HashAlgorithm,
+    /// Hex-encoded.
+    pub expected: String,
+    /// Hex-encoded.
+    pub actual: String,
+}
+
+#[derive(Debug, Default)]
+pub struct IntegrityCounters {
+    /// Values hashed, on both get and put.
+    pub hashed: AtomicU64,
+    /// Gets that returned bytes not matching their key.
+    pub corruptions: AtomicU64,
+}
+
+enum Hasher {
+    Blake2(HashContext),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Blake2 { key } => Hasher::Blake2(HashContext::new(key)),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Blake2(context) => context.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        match self {
+            Hasher::Blake2(context) => context.finish().as_ref().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+/// A layer over an existing blobstore that checks values of content-addressed
+/// keys against the hash in the key. Puts of mismatching values are rejected
+/// before reaching the inner blobstore, and gets of mismatching values fail
+/// instead of returning bad data. Other keys are passed through unchecked.
+pub struct IntegrityBlob<B> {
+    inner: B,
+    key_parser: KeyParser,
+    counters: Arc<IntegrityCounters>,
+}
+
+impl<B: fmt::Display> fmt::Display for IntegrityBlob<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "IntegrityBlob<{}>", &self.inner)
+    }
+}
+
+impl<B: fmt::Debug> fmt::Debug for IntegrityBlob<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IntegrityBlob")
+            .field("inner", &self.inner)
+            .field("counters", &self.counters)
+            .finish()
+    }
+}
+
+impl<B> IntegrityBlob<B> {
+    pub fn new(inner: B, key_parser: KeyParser) -> Self {
+        Self {
+            inner,
+            key_parser,
+            counters: Default::default(),
+        }
+    }
+
+    pub fn counters(&self) -> &IntegrityCounters {
+        &self.counters
+    }
+
+    /// The counters, to keep reading them once the blobstore is wrapped or
+    /// type-erased.
+    pub fn shared_counters(&self) -> Arc<IntegrityCounters> {
+        self.counters.clone()
+    }
+
+    /// Check `value` against `key`, if it is content-addressed.
+    fn verify(&self, key: &str, value: &[u8]) -> Result<(), HashMismatch> {
+        let expected = match (self.key_parser)(key) {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+        self.counters.hashed.fetch_add(1, Ordering::Relaxed);
+        let mut hasher = Hasher::new(expected.algorithm);
+        hasher.update(value);
+        let actual = hasher.finish();
+        if actual == expected.digest {
+            Ok(())
+        } else {
+            Err(HashMismatch {
+                key: key.to_string(),
+                algorithm: expected.algorithm,
+                expected: hex::encode(expected.digest),
+                actual: hex::encode(actual),
+            })
+        }
+    }
+}
+
+#[async_trait]
+impl<B: Blobstore> Blobstore for IntegrityBlob<B> {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        let value = self.inner.get(ctx, key).await?;
+        if let Some(value) = &value {
+            if let Err(e) = self.verify(key, value.as_raw_bytes()) {
+                self.counters.corruptions.fetch_add(1, Ordering::Relaxed);
+                return Err(e.into());
+            }
+        }
+        Ok(value)
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        self.verify(&key, value.as_bytes())?;
+        self.inner.put(ctx, key, value).await
+    }
+
+    async fn is_present<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreIsPresent> {
+        self.inner.is_present(ctx, key).await
+    }
+
+    async fn copy<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        old_key: &'a str,
+        new_key: String,
+    ) -> Result<()> {
+        if (self.key_parser)(&new_key).is_none() {
+            return self.inner.copy(ctx, old_key, new_key).await;
+        }
+        // The value was checked against the old key, if at all, so check it
+        // against the new one before linking it there.
+        let value = self
+            .get(ctx, old_key)
+            .await?
+            .with_context(|| format!("key {} not present", old_key))?;
+        self.put(ctx, new_key, value.into_bytes()).await
+    }
+}
+
+#[async_trait]
+impl<B: BlobstorePutOps> BlobstorePutOps for IntegrityBlob<B> {
+    async fn put_explicit<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+    ) -> Result<OverwriteStatus> {
+        self.verify(&key, value.as_bytes())?;
+        self.inner
+            .put_explicit(ctx, key, value, put_behaviour)
+            .await
+    }
+
+    async fn put_with_status<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<OverwriteStatus> {
+        self.verify(&key, value.as_bytes())?;
+        self.inner.put_with_status(ctx, key, value).await
+    }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        self.verify(&key, value.as_bytes())?;
+        self.inner.put_with_ttl(ctx, key, value, ttl).await
+    }
+}
+
+#[async_trait]
+impl<B: BlobstoreUnlinkOps> BlobstoreUnlinkOps for IntegrityBlob<B> {
+    async fn unlink<'a>(&'a self, ctx: &'a CoreContext, key: &'a str) -> Result<()> {
+        self.inner.unlink(ctx, key).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use borrowed::borrowed;
+    use fbinit::FacebookInit;
+    use memblob::Memblob;
+
+    use super::*;
+
+    const BLAKE2_KEY: &[u8] = b"test";
+
+    /// Parses "content.blake2.<hex>" and "content.sha256.<hex>".
+    fn key_parser() -> KeyParser {
+        Arc::new(|key: &str| {
+            let (algorithm, digest) = key.strip_prefix("content.")?.split_once('.')?;
+            let algorithm = match algorithm {
+                "blake2" => HashAlgorithm::Blake2 { key: BLAKE2_KEY },
+                "sha256" => HashAlgorithm::Sha256,
+                _ => return None,
+            };
+            Some(ExpectedHash {
+                algorithm,
+                digest: hex::decode(digest).ok()?,
+            })
+        })
+    }
+
+    fn content_key(algorithm: HashAlgorithm, value: &[u8]) -> String {
+        let mut hasher = Hasher::new(algorithm);
+        hasher.update(value);
+        format!("content.{}.{}", algorithm, hex::encode(hasher.finish()))
+    }
+
+    fn bytes(value: &[u8]) -> BlobstoreBytes {
+        BlobstoreBytes::from_bytes(value.to_vec())
+    }
+
+    fn value(data: Option<BlobstoreGetData>) -> Option<Vec<u8>> {
+        data.map(|d| d.into_raw_bytes().to_vec())
+    }
+
+    #[fbinit::test]
+    async fn test_round_trip(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = IntegrityBlob::new(Memblob::default(), key_parser());
+
+        for algorithm in [
+            HashAlgorithm::Blake2 { key: BLAKE2_KEY },
+            HashAlgorithm::Sha256,
+        ] {
+            let key = content_key(algorithm, b"hello");
+            blob.put(ctx, key.clone(), bytes(b"hello")).await?;
+            assert_eq!(value(blob.get(ctx, &key).await?), Some(b"hello".to_vec()));
+
+            let err = blob
+                .put(ctx, key.clone(), bytes(b"goodbye"))
+                .await
+                .expect_err("mismatching put should fail");
+            let mismatch = err.downcast_ref::<HashMismatch>().unwrap();
+            assert_eq!(mismatch.algorithm, algorithm);
+            // The rejected put did not reach the inner blobstore.
+            assert_eq!(
+                value(blob.inner.get(ctx, &key).await?),
+                Some(b"hello".to_vec())
+            );
+        }
+        assert_eq!(blob.counters().hashed.load(Ordering::Relaxed), 6);
+        assert_eq!(blob.counters().corruptions.load(Ordering::Relaxed), 0);
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_corrupt_get(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = IntegrityBlob::new(Memblob::default(), key_parser());
+
+        let key = content_key(HashAlgorithm::Sha256, b"hello");
+        blob.put(ctx, key.clone(), bytes(b"hello")).await?;
+        blob.inner
+            .put_explicit(ctx, key.clone(), bytes(b"hellp"), PutBehaviour::Overwrite)
+            .await?;
+
+        let err = blob
+            .get(ctx, &key)
+            .await
+            .expect_err("corrupt get should fail");
+        let mismatch = err.downcast_ref::<HashMismatch>().unwrap();
+        assert_eq!(mismatch.key, key);
+        assert_eq!(blob.counters().corruptions.load(Ordering::Relaxed), 1);
+
+        // Copying the corrupt value to another content-addressed key fails too.
+        let other_key = format!("content.sha256.{}", "00".repeat(32));
+        assert!(blob.copy(ctx, &key, other_key.clone()).await.is_err());
+        assert!(blob.inner.get(ctx, &other_key).await?.is_none());
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_unaddressed_keys_not_hashed(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = IntegrityBlob::new(Memblob::default(), key_parser());
+
+        blob.put(ctx, "alias.sha1.abc".to_string(), bytes(b"anything"))
+            .await?;
+        blob.put_with_status(ctx, "content.unknown.abc".to_string(), bytes(b"x"))
+            .await?;
+        assert!(blob.get(ctx, "alias.sha1.abc").await?.is_some());
+        blob.copy(ctx, "alias.sha1.abc", "alias.sha1.def".to_string())
+            .await?;
+        assert!(blob.get(ctx, "missing").await?.is_none());
+        assert_eq!(blob.counters().hashed.load(Ordering::Relaxed), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,630 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! A blobstore layer that records each put in a local log before running
+//! it, so that puts lost by a crash can be found when the process restarts.
+//!
+//! The log is a file of JSON lines: an intent record is appended before a
+//! put is passed to the inner blobstore, and a completion record once it
+//! returns. Intents left without a completion by a previous run are checked
+//! against the inner blobstore by `IntentLogBlob::recover`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use anyhow::Result;
+use async_trait::async_trait;
+use blobstore::Blobstore;
+use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreIsPresent;
+use blobstore::BlobstorePutOps;
+use blobstore::BlobstoreUnlinkOps;
+use blobstore::OverwriteStatus;
+use blobstore::PutBehaviour;
+use context::CoreContext;
+use futures::stream;
+use futures::Future;
+use futures::StreamExt;
+use mononoke_types::BlobstoreBytes;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use slog::warn;
+use tokio::task::spawn_blocking;
+
+/// Keys checked at once by `recover`.
+const RECOVER_CONCURRENCY: usize = 100;
+
+/// When records are flushed to disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogSync {
+    /// After every record.
+    Always,
+    /// After intent records only. A lost completion only makes `recover`
+    /// check a put that succeeded.
+    Intents,
+    /// Never, leaving it to the OS. Intents may be lost on power failure,
+    /// but not when only the process crashes.
+    Never,
+}
+
+#[derive(Clone, Debug)]
+pub struct IntentLogOptions {
+    pub path: PathBuf,
+    pub sync: LogSync,
+    /// The log is compacted once it grows past this size, keeping only the
+    /// intents without a completion.
+    pub max_log_bytes: u64,
+    /// Whether `recover` removes the intents it resolved or found lost from
+    /// the log, so they are not reported again.
+    pub forget_on_recover: bool,
+}
+
+impl IntentLogOptions {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            sync: LogSync::Intents,
+            max_log_bytes: 64 << 20,
+            forget_on_recover: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Intent {
+        id: u64,
+        #[serde(flatten)]
+        intent: Intent,
+    },
+    Complete {
+        id: u64,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Intent {
+    key: String,
+    size: u64,
+    sha256: String,
+    timestamp: u64,
+}
+
+/// A put started by a previous run that is not in the inner blobstore.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LostPut {
+    pub key: String,
+    pub size: u64,
+    /// Hex SHA-256 of the value that was put.
+    pub sha256: String,
+    /// When the put started, in seconds since the epoch.
+    pub timestamp: u64,
+}
+
+/// The intents left without a completion by previous runs, sorted by key.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Keys present in the inner blobstore: the put completed.
+    pub resolved: Vec<String>,
+    /// Puts absent from the inner blobstore, to be redone or reported.
+    pub lost: Vec<LostPut>,
+    /// Keys whose presence could not be established. Their intents stay in
+    /// the log for the next `recover`.
+    pub unknown: Vec<String>,
+}
+
+struct LogState {
+    file: File,
+    size: u64,
+    /// Size at which the log is compacted.
+    compact_at: u64,
+    next_id: u64,
+    /// Puts of this run that have not returned yet.
+    in_flight: BTreeMap<u64, Intent>,
+    /// Intents left without a completion by previous runs.
+    previous: BTreeMap<u64, Intent>,
+}
+
+/// A layer over an existing blobstore that logs each put to a local file
+/// before running it, see the module documentation. Other operations pass
+/// through.
+///
+/// Writing the intent is part of the put: if it fails, the put fails
+/// without reaching the inner blobstore. Puts wait for the log to be
+/// written, off the async executor, so it should be on a local disk.
+pub struct IntentLogBlob<B> {
+    inner: B,
+    log: Arc<IntentLog>,
+}
+
+/// The log file, shared with the blocking tasks that write it.
+struct IntentLog {
+    options: IntentLogOptions,
+    state: Mutex<LogState>,
+}
+
+impl<B: fmt::Display> fmt::Display for IntentLogBlob<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "IntentLogBlob<{}>", &self.inner)
+    }
+}
+
+impl<B: fmt::Debug> fmt::Debug for IntentLogBlob<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IntentLogBlob")
+            .field("inner", &self.inner)
+            .field("options", &self.log.options)
+            .finish()
+    }
+}
+
+impl<B> IntentLogBlob<B> {
+    /// Open the log at `options.path`, creating it if needed, and load the
+    /// intents left without a completion by previous runs. Records that
+    /// can't be parsed, e.g. torn by a crash, are skipped.
+    pub fn open(inner: B, options: IntentLogOptions) -> Result<Self> {
+        let (previous, next_id) = read_log(&options.path)
+            .with_context(|| format!("Failed to read intent log {:?}", options.path))?;
+        let (file, size) = write_log(&options.path, &previous)?;
+        let state = LogState {
+            file,
+            size,
+            compact_at: compact_at(&options, size),
+            next_id,
+            in_flight: BTreeMap::new(),
+            previous,
+        };
+        let log = IntentLog {
+            options,
+            state: Mutex::new(state),
+        };
+        Ok(Self {
+            inner,
+            log: Arc::new(log),
+        })
+    }
+
+    /// Rewrite the log with only the intents without a completion.
+    pub fn compact(&self) -> Result<()> {
+        let mut state = self.log.state.lock().unwrap();
+        self.log.compact_locked(&mut state)
+    }
+
+    /// Run `put` between an intent and a completion record. The completion
+    /// is also written when the put fails, since the caller knows about it.
+    async fn logged<T>(
+        &self,
+        ctx: &CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let log = self.log.clone();
+        let intent_key = key.clone();
+        let id = spawn_blocking(move || log.log_intent(&intent_key, &value)).await??;
+        let result = put.await;
+        let log = self.log.clone();
+        let completed = spawn_blocking(move || log.log_complete(id))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|r| r);
+        if let Err(e) = completed {
+            warn!(
+                ctx.logger(),
+                "Failed to log completion of put {}, recover will check it: {:#}", key, e
+            );
+        }
+        result
+    }
+}
+
+impl IntentLog {
+    fn compact_locked(&self, state: &mut LogState) -> Result<()> {
+        let mut intents = state.previous.clone();
+        intents.extend(state.in_flight.clone());
+        let (file, size) = write_log(&self.options.path, &intents)?;
+        state.file = file;
+        state.size = size;
+        state.compact_at = compact_at(&self.options, size);
+        Ok(())
+    }
+
+    fn append(&self, state: &mut LogState, record: &Record) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        state.file.write_all(&line)?;
+        let sync = match record {
+            Record::Intent { .. } => self.options.sync != LogSync::Never,
+            Record::Complete { .. } => self.options.sync == LogSync::Always,
+        };
+        if sync {
+            state.file.sync_data()?;
+        }
+        state.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Record the intent to put `value` at `key`, returning its id.
+    fn log_intent(&self, key: &str, value: &BlobstoreBytes) -> Result<u64> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs());
+        let intent = Intent {
+            key: key.to_string(),
+            size: value.len() as u64,
+            sha256: hex::encode(Sha256::digest(value.as_bytes())),
+            timestamp,
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.size >= state.compact_at {
+            self.compact_locked(&mut state)?;
+        }
+        let id = state.next_id;
+        self.append(
+            &mut state,
+            &Record::Intent {
+                id,
+                intent: intent.clone(),
+            },
+        )
+        .with_context(|| format!("Failed to log intent to put {}", key))?;
+        state.next_id += 1;
+        state.in_flight.insert(id, intent);
+        Ok(id)
+    }
+
+    fn log_complete(&self, id: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight.remove(&id);
+        self.append(&mut state, &Record::Complete { id })
+    }
+}
+
+impl<B: Blobstore> IntentLogBlob<B> {
+    /// Check the intents left without a completion by previous runs against
+    /// the inner blobstore. With `forget_on_recover`, the resolved and lost
+    /// ones are removed from the log.
+    pub async fn recover(&self, ctx: &CoreContext) -> Result<RecoveryReport> {
+        let previous = self.log.state.lock().unwrap().previous.clone();
+        let checked: Vec<_> = stream::iter(previous)
+            .map(|(id, intent)| async move {
+                let present = self.inner.is_present(ctx, &intent.key).await;
+                (id, intent, present)
+            })
+            .buffer_unordered(RECOVER_CONCURRENCY)
+            .collect()
+            .await;
+
+        let mut report = RecoveryReport::default();
+        let mut done = Vec::new();
+        for (id, intent, present) in checked {
+            match present {
+                Ok(BlobstoreIsPresent::Present) => {
+                    report.resolved.push(intent.key);
+                    done.push(id);
+                }
+                Ok(BlobstoreIsPresent::Absent) => {
+                    report.lost.push(LostPut {
+                        key: intent.key,
+                        size: intent.size,
+                        sha256: intent.sha256,
+                        timestamp: intent.timestamp,
+                    });
+                    done.push(id);
+                }
+                Ok(BlobstoreIsPresent::ProbablyNotPresent(e)) | Err(e) => {
+                    warn!(
+                        ctx.logger(),
+                        "Can't check whether put {} was lost: {:#}", intent.key, e
+                    );
+                    report.unknown.push(intent.key);
+                }
+            }
+        }
+        report.resolved.sort();
+        report.lost.sort_by(|a, b| a.key.cmp(&b.key));
+        report.unknown.sort();
+
+        if self.log.options.forget_on_recover && !done.is_empty() {
+            let log = self.log.clone();
+            spawn_blocking(move || {
+                let mut state = log.state.lock().unwrap();
+                for id in done {
+                    state.previous.remove(&id);
+                }
+                log.compact_locked(&mut state)
+            })
+            .await??;
+        }
+        Ok(report)
+    }
+}
+
+fn compact_at(options: &IntentLogOptions, size: u64) -> u64 {
+    // Leave room to grow when many intents are kept, so that compaction
+    // doesn't run again on every put.
+    options.max_log_bytes.max(size.saturating_mul(2))
+}
+
+/// The intents without a completion in the log at `path`, and the next id.
+fn read_log(path: &Path) -> Result<(BTreeMap<u64, Intent>, u64)> {
+    let mut intents = BTreeMap::new();
+    let mut next_id = 0;
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((intents, next_id)),
+        Err(e) => return Err(e.into()),
+    };
+    for line in BufReader::new(file).split(b'\n') {
+        match serde_json::from_slice(&line?) {
+            Ok(Record::Intent { id, intent }) => {
+                next_id = next_id.max(id + 1);
+                intents.insert(id, intent);
+            }
+            Ok(Record::Complete { id }) => {
+                next_id = next_id.max(id + 1);
+                intents.remove(&id);
+            }
+            Err(_) => {}
+        }
+    }
+    Ok((intents, next_id))
+}
+
+/// Replace the log at `path` with `intents`, returning it opened for
+/// appending, and its size.
+fn write_log(path: &Path, intents: &BTreeMap<u64, Intent>) -> Result<(File, u64)> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut contents = Vec::new();
+    for (id, intent) in intents {
+        let record = Record::Intent {
+            id: *id,
+            intent: intent.clone(),
+        };
+        serde_json::to_writer(&mut contents, &record)?;
+        contents.push(b'\n');
+    }
+    let mut file =
+        File::create(&tmp).with_context(|| format!("Failed to create intent log {:?}", tmp))?;
+    file.write_all(&contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace intent log {:?}", path))?;
+    let file = OpenOptions::new().append(true).open(path)?;
+    Ok((file, contents.len() as u64))
+}
+
+#[async_trait]
+impl<B: Blobstore> Blobstore for IntentLogBlob<B> {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        self.inner.get(ctx, key).await
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        let intent = (key.clone(), value.clone());
+        self.logged(ctx, intent.0, intent.1, self.inner.put(ctx, key, value))
+            .await
+    }
+
+    async fn is_present<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreIsPresent> {
+        self.inner.is_present(ctx, key).await
+    }
+
+    async fn copy<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        old_key: &'a str,
+        new_key: String,
+    ) -> Result<()> {
+        self.inner.copy(ctx, old_key, new_key).await
+    }
+}
+
+#[async_trait]
+impl<B: BlobstorePutOps> BlobstorePutOps for IntentLogBlob<B> {
+    async fn put_explicit<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+    ) -> Result<OverwriteStatus> {
+        let intent = (key.clone(), value.clone());
+        let put = self.inner.put_explicit(ctx, key, value, put_behaviour);
+        self.logged(ctx, intent.0, intent.1, put).await
+    }
+
+    async fn put_with_status<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<OverwriteStatus> {
+        let intent = (key.clone(), value.clone());
+        let put = self.inner.put_with_status(ctx, key, value);
+        self.logged(ctx, intent.0, intent.1, put).await
+    }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        let intent = (key.clone(), value.clone());
+        let put = self.inner.put_with_ttl(ctx, key, value, ttl);
+        self.logged(ctx, intent.0, intent.1, put).await
+    }
+}
+
+#[async_trait]
+impl<B: BlobstoreUnlinkOps> BlobstoreUnlinkOps for IntentLogBlob<B> {
+    async fn unlink<'a>(&'a self, ctx: &'a CoreContext, key: &'a str) -> Result<()> {
+        self.inner.unlink(ctx, key).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use borrowed::borrowed;
+    use fbinit::FacebookInit;
+    use memblob::Memblob;
+
+    use super::*;
+
+    fn bytes(value: &[u8]) -> BlobstoreBytes {
+        BlobstoreBytes::from_bytes(value.to_vec())
+    }
+
+    fn log_lines(path: &Path) -> Result<usize> {
+        Ok(fs::read_to_string(path)?.lines().count())
+    }
+
+    #[fbinit::test]
+    async fn test_recover(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let dir = tempfile::tempdir()?;
+        let options = IntentLogOptions::new(dir.path().join("intents"));
+        let store = Memblob::default();
+
+        let blob = IntentLogBlob::open(store.clone(), options.clone())?;
+        blob.put(ctx, "done".to_string(), bytes(b"done")).await?;
+        // Crash after the puts started: the first reached the backend, the
+        // second didn't.
+        blob.log.log_intent("resolved", &bytes(b"resolved"))?;
+        store
+            .put(ctx, "resolved".to_string(), bytes(b"resolved"))
+            .await?;
+        blob.log.log_intent("lost", &bytes(b"lost"))?;
+        drop(blob);
+
+        let blob = IntentLogBlob::open(store.clone(), options.clone())?;
+        // Only the intents without a completion are kept.
+        assert_eq!(log_lines(&options.path)?, 2);
+        let report = blob.recover(ctx).await?;
+        assert_eq!(report.resolved, vec!["resolved".to_string()]);
+        assert_eq!(
+            report.lost,
+            vec![LostPut {
+                key: "lost".to_string(),
+                size: 4,
+                sha256: hex::encode(Sha256::digest(b"lost")),
+                timestamp: report.lost[0].timestamp,
+            }]
+        );
+        assert!(report.unknown.is_empty());
+        assert_eq!(log_lines(&options.path)?, 0);
+        drop(blob);
+
+        let blob = IntentLogBlob::open(store, options)?;
+        assert_eq!(blob.recover(ctx).await?, RecoveryReport::default());
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_recover_keeps_intents(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let dir = tempfile::tempdir()?;
+        let options = IntentLogOptions {
+            forget_on_recover: false,
+            ..IntentLogOptions::new(dir.path().join("intents"))
+        };
+
+        let blob = IntentLogBlob::open(Memblob::default(), options.clone())?;
+        blob.log.log_intent("lost", &bytes(b"lost"))?;
+        drop(blob);
+
+        // A torn record left by the crash is skipped.
+        let mut file = OpenOptions::new().append(true).open(&options.path)?;
+        file.write_all(b"{\"op\":\"intent\",\"id\":1,\"ke")?;
+
+        let blob = IntentLogBlob::open(Memblob::default(), options)?;
+        for _ in 0..2 {
+            let report = blob.recover(ctx).await?;
+            assert_eq!(report.lost.len(), 1);
+            assert_eq!(report.lost[0].key, "lost");
+        }
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_compaction(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let dir = tempfile::tempdir()?;
+        let options = IntentLogOptions {
+            sync: LogSync::Never,
+            max_log_bytes: 1024,
+            ..IntentLogOptions::new(dir.path().join("intents"))
+        };
+        let store = Memblob::default();
+
+        let blob = IntentLogBlob::open(store.clone(), options.clone())?;
+        blob.log.log_intent("lost", &bytes(b"lost"))?;
+        for i in 0..100 {
+            blob.put(ctx, format!("key{}", i), bytes(b"value")).await?;
+            assert!(fs::metadata(&options.path)?.len() < 2048);
+        }
+        assert_eq!(
+            store
+                .get(ctx, "key99")
+                .await?
+                .unwrap()
+                .into_raw_bytes()
+                .as_ref(),
+            b"value"
+        );
+
+        // The incomplete intent survived the compactions.
+        blob.compact()?;
+        assert_eq!(log_lines(&options.path)?, 1);
+        drop(blob);
+
+        let blob = IntentLogBlob::open(store, options.clone())?;
+        let report = blob.recover(ctx).await?;
+        assert!(report.resolved.is_empty());
+        assert_eq!(report.lost.len(), 1);
+        assert_eq!(report.lost[0].key, "lost");
+        assert_eq!(log_lines(&options.path)?, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,458 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Tracking of the most fetched keys, so that a cache can be warmed with
+//! them, e.g. after a restart.
+
+use std::hash::Hasher;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::RwLock;
+use std::sync::TryLockError;
+use std::time::Duration;
+use std::time::Instant;
+
+use blobstore::Blobstore;
+use context::CoreContext;
+use futures::future;
+use futures::stream;
+use futures::StreamExt;
+use twox_hash::XxHash64;
+
+/// Candidates are split in stripes, by key hash, so that concurrent
+/// admissions of different keys rarely wait for each other.
+const STRIPES: usize = 16;
+
+/// Sizes of a `HotKeyTracker`. It uses `8 * sketch_width * sketch_depth`
+/// bytes, plus the `tracked_keys` keys themselves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HotKeyTrackerConfig {
+    /// Counters per row of the count-min sketch. Wider rows make counts of
+    /// rare keys less likely to be overestimated.
+    pub sketch_width: usize,
+    /// Rows of the count-min sketch, each hashing keys differently.
+    pub sketch_depth: usize,
+    /// How many of the hottest keys are remembered.
+    pub tracked_keys: usize,
+    /// Counts are halved this often, so that keys that were hot a long time
+    /// ago make room for keys that are hot now.
+    pub half_life: Duration,
+}
+
+impl Default for HotKeyTrackerConfig {
+    fn default() -> Self {
+        Self {
+            sketch_width: 1 << 16,
+            sketch_depth: 4,
+            tracked_keys: 1024,
+            half_life: Duration::from_secs(600),
+        }
+    }
+}
+
+struct Stripe {
+    candidates: RwLock<Vec<Candidate>>,
+    /// Hashes of the candidates, at the same indexes, or 0 for free slots,
+    /// so that recording a tracked key does not lock the stripe.
+    hashes: Vec<AtomicU64>,
+    /// Lowest estimate among the candidates when the stripe is full, or 0.
+    /// Keys estimated below it are not admitted. It can be stale, as counts
+    /// only grow between decays.
+    floor: AtomicU64,
+}
+
+struct Candidate {
+    hash: u64,
+    key: String,
+}
+
+/// Approximate access counts of keys, and the keys accessed most, within a
+/// fixed amount of memory. Recording a key updates a few atomic counters,
+/// and takes a lock only when a key that is not tracked yet is hot enough to
+/// be. If another thread holds it, the key is admitted on a later access.
+pub struct HotKeyTracker {
+    config: HotKeyTrackerConfig,
+    sketch: Vec<AtomicU64>,
+    stripes: Vec<Stripe>,
+    per_stripe: usize,
+    created: Instant,
+    /// Milliseconds since `created` of the last decay.
+    last_decay_ms: AtomicU64,
+}
+
+impl HotKeyTracker {
+    pub fn new(config: HotKeyTrackerConfig) -> Self {
+        let width = config.sketch_width.max(1);
+        let depth = config.sketch_depth.max(1);
+        let per_stripe = (config.tracked_keys + STRIPES - 1) / STRIPES;
+        Self {
+            config: HotKeyTrackerConfig {
+                sketch_width: width,
+                sketch_depth: depth,
+                ..config
+            },
+            sketch: (0..width * depth).map(|_| AtomicU64::new(0)).collect(),
+            stripes: (0..STRIPES)
+                .map(|_| Stripe {
+                    candidates: RwLock::new(Vec::new()),
+                    hashes: (0..per_stripe).map(|_| AtomicU64::new(0)).collect(),
+                    floor: AtomicU64::new(0),
+                })
+                .collect(),
+            per_stripe,
+            created: Instant::now(),
+            last_decay_ms: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts an access to `key`.
+    pub fn record(&self, key: &str) {
+        self.maybe_decay();
+        let hashes = KeyHashes::new(key);
+        let estimate = self
+            .sketch_indexes(&hashes)
+            .map(|i| self.sketch[i].fetch_add(1, Ordering::Relaxed) + 1)
+            .min()
+            .unwrap_or(0);
+        let stripe = &self.stripes[hashes.first as usize % STRIPES];
+        if estimate <= stripe.floor.load(Ordering::Relaxed) {
+            return;
+        }
+        if self.is_candidate(stripe, &hashes) {
+            return;
+        }
+        self.admit(stripe, hashes, key, estimate);
+    }
+
+    /// The `k` most accessed keys, with their estimated access counts since
+    /// decay, most accessed first.
+    pub fn hot_keys(&self, k: usize) -> Vec<(String, u64)> {
+        let mut hot_keys: Vec<_> = self
+            .stripes
+            .iter()
+            .flat_map(|stripe| {
+                let candidates = stripe.candidates.read().expect("lock poisoned");
+                candidates
+                    .iter()
+                    .map(|c| (c.key.clone(), self.estimate(c.hash)))
+                    .collect::<Vec<_>>()
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        hot_keys.sort_by(|(key1, count1), (key2, count2)| {
+            count2.cmp(count1).then_with(|| key1.cmp(key2))
+        });
+        hot_keys.truncate(k);
+        hot_keys
+    }
+
+    /// Keys are told apart by hash only, so a key colliding with a tracked
+    /// one is not admitted.
+    fn is_candidate(&self, stripe: &Stripe, hashes: &KeyHashes) -> bool {
+        stripe
+            .hashes
+            .iter()
+            .any(|hash| hash.load(Ordering::Relaxed) == hashes.first)
+    }
+
+    fn admit(&self, stripe: &Stripe, hashes: KeyHashes, key: &str, estimate: u64) {
+        let mut candidates = match stripe.candidates.try_write() {
+            Ok(candidates) => candidates,
+            Err(TryLockError::WouldBlock) => return,
+            Err(TryLockError::Poisoned(_)) => panic!("lock poisoned"),
+        };
+        if candidates
+            .iter()
+            .any(|c| c.hash == hashes.first && c.key == key)
+        {
+            return;
+        }
+        let candidate = Candidate {
+            hash: hashes.first,
+            key: key.to_string(),
+        };
+        if candidates.len() < self.per_stripe {
+            stripe.hashes[candidates.len()].store(hashes.first, Ordering::Relaxed);
+            candidates.push(candidate);
+            if candidates.len() < self.per_stripe {
+                return;
+            }
+        } else {
+            let (coldest, coldest_estimate) = candidates
+                .iter()
+                .enumerate()
+                .map(|(i, c)| (i, self.estimate(c.hash)))
+                .min_by_key(|(_, estimate)| *estimate)
+                .expect("stripe is full");
+            if coldest_estimate >= estimate {
+                stripe.floor.store(coldest_estimate, Ordering::Relaxed);
+                return;
+            }
+            stripe.hashes[coldest].store(hashes.first, Ordering::Relaxed);
+            candidates[coldest] = candidate;
+        }
+        let floor = candidates
+            .iter()
+            .map(|c| self.estimate(c.hash))
+            .min()
+            .unwrap_or(0);
+        stripe.floor.store(floor, Ordering::Relaxed);
+    }
+
+    fn estimate(&self, hash: u64) -> u64 {
+        let hashes = KeyHashes::from_first(hash);
+        self.sketch_indexes(&hashes)
+            .map(|i| self.sketch[i].load(Ordering::Relaxed))
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn sketch_indexes<'a>(&'a self, hashes: &'a KeyHashes) -> impl Iterator<Item = usize> + 'a {
+        let width = self.config.sketch_width;
+        (0..self.config.sketch_depth).map(move |row| {
+            let hash = hashes
+                .first
+                .wrapping_add((row as u64).wrapping_mul(hashes.second));
+            row * width + (hash % width as u64) as usize
+        })
+    }
+
+    fn maybe_decay(&self) {
+        let half_life_ms = self.config.half_life.as_millis() as u64;
+        if half_life_ms == 0 {
+            return;
+        }
+        let now_ms = self.created.elapsed().as_millis() as u64;
+        let last_decay_ms = self.last_decay_ms.load(Ordering::Relaxed);
+        if now_ms.saturating_sub(last_decay_ms) < half_life_ms {
+            return;
+        }
+        // Only the caller that moves the timestamp decays.
+        if self
+            .last_decay_ms
+            .compare_exchange(last_decay_ms, now_ms, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.decay();
+        }
+    }
+
+    /// Halves all counts.
+    fn decay(&self) {
+        let halve = |count: u64| Some(count / 2);
+        for counter in &self.sketch {
+            let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, halve);
+        }
+        for stripe in &self.stripes {
+            let _ = stripe
+                .floor
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, halve);
+        }
+    }
+}
+
+/// The hashes a key's sketch counters are derived from. Only the first one
+/// identifies candidates, the second is derived from it.
+struct KeyHashes {
+    first: u64,
+    second: u64,
+}
+
+impl KeyHashes {
+    fn new(key: &str) -> Self {
+        let mut hasher = XxHash64::with_seed(0);
+        hasher.write(key.as_bytes());
+        Self::from_first(hasher.finish())
+    }
+
+    fn from_first(first: u64) -> Self {
+        let mut hasher = XxHash64::with_seed(1);
+        hasher.write_u64(first);
+        // Odd, so that rows use different counters.
+        let second = hasher.finish() | 1;
+        Self { first, second }
+    }
+}
+
+/// Outcome of `warm`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WarmStats {
+    pub found: usize,
+    pub missing: usize,
+    pub failed: usize,
+}
+
+/// Fetches each of `keys` from `store`, `concurrency` at a time, so that
+/// the caches of `store` hold them. Typically used with the keys of
+/// `HotKeyTracker::hot_keys`. Failures are counted, not returned.
+pub async fn warm(
+    store: &dyn Blobstore,
+    ctx: &CoreContext,
+    keys: impl IntoIterator<Item = String>,
+    concurrency: usize,
+) -> WarmStats {
+    stream::iter(keys)
+        .map(|key| async move { store.get(ctx, &key).await })
+        .buffer_unordered(concurrency.max(1))
+        .fold(WarmStats::default(), |mut stats, result| {
+            match result {
+                Ok(Some(_)) => stats.found += 1,
+                Ok(None) => stats.missing += 1,
+                Err(_) => stats.failed += 1,
+            }
+            future::ready(stats)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::sync::Mutex;
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use blobstore::BlobstoreGetData;
+    use borrowed::borrowed;
+    use fbinit::FacebookInit;
+    use memblob::Memblob;
+    use mononoke_types::BlobstoreBytes;
+
+    use super::*;
+
+    /// Counts the gets of each key.
+    #[derive(Debug, Default)]
+    struct CountingBlob {
+        inner: Memblob,
+        gets: Mutex<HashMap<String, usize>>,
+    }
+
+    impl fmt::Display for CountingBlob {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "CountingBlob")
+        }
+    }
+
+    #[async_trait]
+    impl Blobstore for CountingBlob {
+        async fn get<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: &'a str,
+        ) -> Result<Option<BlobstoreGetData>> {
+            *self
+                .gets
+                .lock()
+                .unwrap()
+                .entry(key.to_string())
+                .or_default() += 1;
+            self.inner.get(ctx, key).await
+        }
+
+        async fn put<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: String,
+            value: BlobstoreBytes,
+        ) -> Result<()> {
+            self.inner.put(ctx, key, value).await
+        }
+    }
+
+    #[test]
+    fn test_decay() {
+        let tracker = HotKeyTracker::new(HotKeyTrackerConfig {
+            sketch_width: 64,
+            tracked_keys: 64,
+            ..Default::default()
+        });
+        for _ in 0..10 {
+            tracker.record("old");
+        }
+        tracker.decay();
+        for _ in 0..6 {
+            tracker.record("new");
+        }
+        assert_eq!(
+            tracker.hot_keys(2),
+            vec![("new".to_string(), 6), ("old".to_string(), 5)]
+        );
+    }
+
+    #[test]
+    fn test_bounded() {
+        let tracker = HotKeyTracker::new(HotKeyTrackerConfig {
+            sketch_width: 64,
+            tracked_keys: 32,
+            ..Default::default()
+        });
+        for i in 0..10_000 {
+            tracker.record(&format!("key{}", i));
+        }
+        let tracked: usize = tracker
+            .stripes
+            .iter()
+            .map(|stripe| stripe.candidates.read().unwrap().len())
+            .sum();
+        assert!(tracked <= 32, "{} keys tracked", tracked);
+        assert_eq!(tracker.sketch.len(), 64 * 4);
+    }
+
+    #[test]
+    fn test_busy_stripe() {
+        let tracker = HotKeyTracker::new(HotKeyTrackerConfig {
+            sketch_width: 64,
+            tracked_keys: 64,
+            ..Default::default()
+        });
+        tracker.record("tracked");
+        {
+            // Recording does not wait for the stripes.
+            let _locked: Vec<_> = tracker
+                .stripes
+                .iter()
+                .map(|stripe| stripe.candidates.write().unwrap())
+                .collect();
+            tracker.record("tracked");
+            tracker.record("skipped");
+        }
+        assert_eq!(tracker.hot_keys(2), vec![("tracked".to_string(), 2)]);
+        tracker.record("skipped");
+        assert_eq!(
+            tracker.hot_keys(2),
+            vec![("skipped".to_string(), 2), ("tracked".to_string(), 2)]
+        );
+    }
+
+    #[fbinit::test]
+    async fn test_warm(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let store = CountingBlob::default();
+        let keys: Vec<_> = (0..20).map(|i| format!("key{}", i)).collect();
+        for key in &keys[..15] {
+            store
+                .put(ctx, key.clone(), BlobstoreBytes::from_bytes("v"))
+                .await?;
+        }
+
+        let stats = warm(&store, ctx, keys.clone(), 4).await;
+        assert_eq!(
+            stats,
+            WarmStats {
+                found: 15,
+                missing: 5,
+                failed: 0,
+            }
+        );
+        let gets = store.gets.lock().unwrap();
+        assert_eq!(gets.len(), keys.len());
+        assert!(gets.values().all(|count| *count == 1));
+        Ok(())
+    }
+}
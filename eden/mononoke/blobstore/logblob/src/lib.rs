@@ -5,31 +5,77 @@
  * GNU General Public License version 2.
  */
 
+mod hot_keys;
+
+use std::fmt;
+use std::future::Future;
 use std::num::NonZeroU64;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::Result;
 use async_trait::async_trait;
 use blobstore::Blobstore;
+use blobstore::BlobstoreEnumerationData;
 use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreGetManyData;
+use blobstore::BlobstoreGetWithProvenance;
 use blobstore::BlobstoreIsPresent;
+use blobstore::BlobstoreKeyParam;
+use blobstore::BlobstoreKeySource;
+use blobstore::BlobstorePutBatchResults;
 use blobstore::BlobstorePutOps;
 use blobstore::OverwriteStatus;
+use blobstore::Provenance;
 use blobstore::PutBehaviour;
+use blobstore_stats::record_get_many_stats;
 use blobstore_stats::record_get_stats;
 use blobstore_stats::record_is_present_stats;
+use blobstore_stats::record_put_batch_stats;
 use blobstore_stats::record_put_stats;
 use blobstore_stats::OperationType;
+use blobstore_stats::CHAIN_MISSES;
+use blobstore_stats::CHAIN_MISS_LATENCY_US;
+use blobstore_stats::KEY_COUNT;
+use blobstore_stats::REQUEST_CLASS;
+use blobstore_stats::SERVED_BY;
+use blobstore_stats::SERVED_BY_INDEX;
+use blobstore_stats::TIMED_OUT;
+use blobstore_stats::TTL_SECS;
 use context::CoreContext;
 use context::PerfCounterType;
 use futures_stats::TimedFutureExt;
 use mononoke_types::BlobstoreBytes;
 use scuba_ext::MononokeScubaSampleBuilder;
+use thiserror::Error;
+
+pub use crate::hot_keys::warm;
+pub use crate::hot_keys::HotKeyTracker;
+pub use crate::hot_keys::HotKeyTrackerConfig;
+pub use crate::hot_keys::WarmStats;
+
+/// Returns the deadline of the request `ctx` belongs to, if it has one.
+pub type DeadlineExtractor = Arc<dyn Fn(&CoreContext) -> Option<Instant> + Send + Sync>;
+
+/// The request deadline passed before the inner blobstore operation
+/// completed. Unlike a backend timeout, retrying will not help.
+#[derive(Debug, Error)]
+#[error("Blobstore {operation} aborted: request deadline exceeded after {budget:?}")]
+pub struct DeadlineExceeded {
+    pub operation: OperationType,
+    /// Time that was left until the deadline when the operation started.
+    pub budget: Duration,
+}
 
-#[derive(Debug)]
 pub struct LogBlob<B> {
     inner: B,
     scuba: MononokeScubaSampleBuilder,
     scuba_sample_rate: NonZeroU64,
+    deadline_extractor: Option<DeadlineExtractor>,
+    get_many_key_sample_rate: Option<NonZeroU64>,
+    put_batch_key_sample_rate: Option<NonZeroU64>,
+    hot_key_tracker: Option<Arc<HotKeyTracker>>,
 }
 
 impl<B: std::fmt::Debug> LogBlob<B> {
@@ -43,10 +89,99 @@ impl<B: std::fmt::Debug> LogBlob<B> {
             inner,
             scuba,
             scuba_sample_rate,
+            deadline_extractor: None,
+            get_many_key_sample_rate: None,
+            put_batch_key_sample_rate: None,
+            hot_key_tracker: None,
+        }
+    }
+
+    /// Abort inner operations that are still running when the deadline
+    /// returned by `extractor` passes. Operations on contexts without a
+    /// deadline are not affected.
+    pub fn with_deadline_extractor(mut self, extractor: DeadlineExtractor) -> Self {
+        self.deadline_extractor = Some(extractor);
+        self
+    }
+
+    /// Besides the sample of each `get_many`, log a sample per key, sampled
+    /// at `sample_rate`.
+    pub fn with_get_many_key_sampling(mut self, sample_rate: NonZeroU64) -> Self {
+        self.get_many_key_sample_rate = Some(sample_rate);
+        self
+    }
+
+    /// Besides the sample of each `put_batch`, log a sample per item, sampled
+    /// at `sample_rate`.
+    pub fn with_put_batch_key_sampling(mut self, sample_rate: NonZeroU64) -> Self {
+        self.put_batch_key_sample_rate = Some(sample_rate);
+        self
+    }
+
+    /// Record the keys of gets in `tracker`, see `warm`.
+    pub fn with_hot_key_tracker(mut self, tracker: Arc<HotKeyTracker>) -> Self {
+        self.hot_key_tracker = Some(tracker);
+        self
+    }
+}
+
+impl<B> LogBlob<B> {
+    async fn with_deadline<T>(
+        &self,
+        ctx: &CoreContext,
+        operation: OperationType,
+        fut: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let deadline = match self.deadline_extractor.as_ref().and_then(|e| e(ctx)) {
+            Some(deadline) => deadline,
+            None => return fut.await,
+        };
+        let budget = deadline.saturating_duration_since(Instant::now());
+        // A deadline too far in the future to represent is as good as none.
+        let timeout_at = match tokio::time::Instant::now().checked_add(budget) {
+            Some(timeout_at) => timeout_at,
+            None => return fut.await,
+        };
+        match tokio::time::timeout_at(timeout_at, fut).await {
+            Ok(result) => result,
+            Err(_) => Err(DeadlineExceeded { operation, budget }.into()),
+        }
+    }
+}
+
+/// Log which store of a composed blobstore, like `ChainedBlob`, served a get.
+fn add_provenance(scuba: &mut MononokeScubaSampleBuilder, provenance: &Provenance) {
+    if let Some(served_by) = &provenance.served_by {
+        scuba.add(SERVED_BY, served_by.name.as_str());
+        scuba.add(SERVED_BY_INDEX, served_by.index);
+    }
+    let miss_latency: std::time::Duration = provenance.misses.iter().map(|m| m.latency).sum();
+    scuba.add(CHAIN_MISSES, provenance.misses.len());
+    scuba.add(CHAIN_MISS_LATENCY_US, miss_latency.as_micros() as u64);
+}
+
+fn add_timed_out<T>(scuba: &mut MononokeScubaSampleBuilder, result: &Result<T>) {
+    if let Err(e) = result {
+        if e.is::<DeadlineExceeded>() {
+            scuba.add(TIMED_OUT, true);
         }
     }
 }
 
+impl<B: fmt::Debug> fmt::Debug for LogBlob<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogBlob")
+            .field("inner", &self.inner)
+            .field("scuba", &self.scuba)
+            .field("scuba_sample_rate", &self.scuba_sample_rate)
+            .field("has_deadline", &self.deadline_extractor.is_some())
+            .field("get_many_key_sample_rate", &self.get_many_key_sample_rate)
+            .field("put_batch_key_sample_rate", &self.put_batch_key_sample_rate)
+            .field("tracks_hot_keys", &self.hot_key_tracker.is_some())
+            .finish()
+    }
+}
+
 impl<T: std::fmt::Display> std::fmt::Display for LogBlob<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "LogBlob<{}>", &self.inner)
@@ -60,22 +195,46 @@ impl<B: Blobstore + BlobstorePutOps> Blobstore for LogBlob<B> {
         ctx: &'a CoreContext,
         key: &'a str,
     ) -> Result<Option<BlobstoreGetData>> {
+        Ok(self.get_with_provenance(ctx, key).await?.data)
+    }
+
+    async fn get_with_provenance<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreGetWithProvenance> {
         let mut ctx = ctx.clone();
         let mut scuba = self.scuba.clone();
         scuba.sampled(self.scuba_sample_rate);
+        scuba.add(REQUEST_CLASS, ctx.request_class().as_str());
 
         ctx.perf_counters()
             .increment_counter(PerfCounterType::BlobGets);
+        if let Some(tracker) = &self.hot_key_tracker {
+            tracker.record(key);
+        }
 
         let pc = ctx.fork_perf_counters();
 
-        let get = self.inner.get(&ctx, key);
+        let get = self.with_deadline(
+            &ctx,
+            OperationType::Get,
+            self.inner.get_with_provenance(&ctx, key),
+        );
         let (stats, result) = get.timed().await;
+        add_timed_out(&mut scuba, &result);
+        if let Ok(BlobstoreGetWithProvenance {
+            provenance: Some(provenance),
+            ..
+        }) = &result
+        {
+            add_provenance(&mut scuba, provenance);
+        }
         record_get_stats(
             &mut scuba,
             &pc,
             stats,
-            result.as_ref(),
+            result.as_ref().map(|get| &get.data),
             key,
             ctx.metadata().session_id().as_str(),
             OperationType::Get,
@@ -83,14 +242,84 @@ impl<B: Blobstore + BlobstorePutOps> Blobstore for LogBlob<B> {
             &self.inner,
         );
 
-        match result {
-            Ok(Some(ref data)) => {
-                ctx.perf_counters().add_to_counter(
-                    PerfCounterType::BlobGetsTotalSize,
-                    data.len().try_into().unwrap_or(0),
-                );
+        if let Ok(BlobstoreGetWithProvenance {
+            data: Some(ref data),
+            ..
+        }) = result
+        {
+            ctx.perf_counters().add_to_counter(
+                PerfCounterType::BlobGetsTotalSize,
+                data.len().try_into().unwrap_or(0),
+            );
+        }
+
+        result
+    }
+
+    async fn get_many<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        keys: &'a [&'a str],
+    ) -> Result<BlobstoreGetManyData> {
+        let mut ctx = ctx.clone();
+        let mut scuba = self.scuba.clone();
+        scuba.sampled(self.scuba_sample_rate);
+        scuba.add(REQUEST_CLASS, ctx.request_class().as_str());
+
+        ctx.perf_counters()
+            .add_to_counter(PerfCounterType::BlobGets, keys.len() as i64);
+        if let Some(tracker) = &self.hot_key_tracker {
+            keys.iter().for_each(|key| tracker.record(key));
+        }
+
+        let pc = ctx.fork_perf_counters();
+
+        let get_many = self.with_deadline(
+            &ctx,
+            OperationType::GetMany,
+            self.inner.get_many(&ctx, keys),
+        );
+        let (stats, result) = get_many.timed().await;
+        add_timed_out(&mut scuba, &result);
+        let session = ctx.metadata().session_id().as_str();
+        record_get_many_stats(
+            &mut scuba,
+            &pc,
+            stats.clone(),
+            result.as_ref(),
+            keys.len(),
+            session,
+            None,
+            &self.inner,
+        );
+
+        if let Ok(results) = &result {
+            if let Some(sample_rate) = self.get_many_key_sample_rate {
+                for (key, key_result) in results {
+                    let mut scuba = self.scuba.clone();
+                    scuba.sampled(sample_rate);
+                    scuba.add(REQUEST_CLASS, ctx.request_class().as_str());
+                    record_get_stats(
+                        &mut scuba,
+                        &pc,
+                        stats.clone(),
+                        key_result.as_ref(),
+                        key,
+                        session,
+                        OperationType::GetMany,
+                        None,
+                        &self.inner,
+                    );
+                }
             }
-            _ => {}
+            let size: usize = results
+                .values()
+                .filter_map(|result| Some(result.as_ref().ok()?.as_ref()?.len()))
+                .sum();
+            ctx.perf_counters().add_to_counter(
+                PerfCounterType::BlobGetsTotalSize,
+                size.try_into().unwrap_or(0),
+            );
         }
 
         result
@@ -104,14 +333,20 @@ impl<B: Blobstore + BlobstorePutOps> Blobstore for LogBlob<B> {
         let mut ctx = ctx.clone();
         let mut scuba = self.scuba.clone();
         scuba.sampled(self.scuba_sample_rate);
+        scuba.add(REQUEST_CLASS, ctx.request_class().as_str());
 
         ctx.perf_counters()
             .increment_counter(PerfCounterType::BlobPresenceChecks);
 
         let pc = ctx.fork_perf_counters();
 
-        let is_present = self.inner.is_present(&ctx, key);
+        let is_present = self.with_deadline(
+            &ctx,
+            OperationType::IsPresent,
+            self.inner.is_present(&ctx, key),
+        );
         let (stats, result) = is_present.timed().await;
+        add_timed_out(&mut scuba, &result);
         record_is_present_stats(
             &mut scuba,
             &pc,
@@ -144,23 +379,32 @@ impl<B: BlobstorePutOps> LogBlob<B> {
         key: String,
         value: BlobstoreBytes,
         put_behaviour: Option<PutBehaviour>,
+        ttl: Option<Duration>,
     ) -> Result<OverwriteStatus> {
         let mut ctx = ctx.clone();
         let mut scuba = self.scuba.clone();
+        scuba.add(REQUEST_CLASS, ctx.request_class().as_str());
         let size = value.len();
+        if let Some(ttl) = ttl {
+            scuba.add(TTL_SECS, ttl.as_secs());
+        }
 
         ctx.perf_counters()
             .increment_counter(PerfCounterType::BlobPuts);
 
         let pc = ctx.fork_perf_counters();
 
-        let put = if let Some(put_behaviour) = put_behaviour {
-            self.inner
-                .put_explicit(&ctx, key.clone(), value, put_behaviour)
-        } else {
-            self.inner.put_with_status(&ctx, key.clone(), value)
+        let put = match (put_behaviour, ttl) {
+            (Some(put_behaviour), _) => {
+                self.inner
+                    .put_explicit(&ctx, key.clone(), value, put_behaviour)
+            }
+            (None, Some(ttl)) => self.inner.put_with_ttl(&ctx, key.clone(), value, ttl),
+            (None, None) => self.inner.put_with_status(&ctx, key.clone(), value),
         };
+        let put = self.with_deadline(&ctx, OperationType::Put, put);
         let (stats, result) = put.timed().await;
+        add_timed_out(&mut scuba, &result);
         record_put_stats(
             &mut scuba,
             &pc,
@@ -194,7 +438,8 @@ impl<B: BlobstorePutOps> BlobstorePutOps for LogBlob<B> {
         value: BlobstoreBytes,
         put_behaviour: PutBehaviour,
     ) -> Result<OverwriteStatus> {
-        self.put_impl(ctx, key, value, Some(put_behaviour)).await
+        self.put_impl(ctx, key, value, Some(put_behaviour), None)
+            .await
     }
 
     async fn put_with_status<'a>(
@@ -203,6 +448,451 @@ impl<B: BlobstorePutOps> BlobstorePutOps for LogBlob<B> {
         key: String,
         value: BlobstoreBytes,
     ) -> Result<OverwriteStatus> {
-        self.put_impl(ctx, key, value, None).await
+        self.put_impl(ctx, key, value, None, None).await
+    }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        self.put_impl(ctx, key, value, None, Some(ttl)).await
+    }
+
+    async fn put_batch<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        items: Vec<(String, BlobstoreBytes)>,
+    ) -> Result<BlobstorePutBatchResults> {
+        let mut ctx = ctx.clone();
+        let mut scuba = self.scuba.clone();
+        scuba.add(REQUEST_CLASS, ctx.request_class().as_str());
+        let key_count = items.len();
+        let sizes: Vec<usize> = items.iter().map(|(_, value)| value.len()).collect();
+
+        ctx.perf_counters()
+            .add_to_counter(PerfCounterType::BlobPuts, key_count as i64);
+
+        let pc = ctx.fork_perf_counters();
+
+        let put_batch = self.with_deadline(
+            &ctx,
+            OperationType::PutBatch,
+            self.inner.put_batch(&ctx, items),
+        );
+        let (stats, result) = put_batch.timed().await;
+        add_timed_out(&mut scuba, &result);
+        let session = ctx.metadata().session_id().as_str();
+        record_put_batch_stats(
+            &mut scuba,
+            &pc,
+            stats.clone(),
+            result.as_ref(),
+            key_count,
+            sizes.iter().sum(),
+            session,
+            None,
+            &self.inner,
+        );
+
+        if let Ok(results) = &result {
+            if let Some(sample_rate) = self.put_batch_key_sample_rate {
+                for ((key, key_result), size) in results.iter().zip(&sizes) {
+                    let mut scuba = self.scuba.clone();
+                    scuba.sampled(sample_rate);
+                    record_put_stats(
+                        &mut scuba,
+                        &pc,
+                        stats.clone(),
+                        key_result.as_ref(),
+                        key,
+                        session,
+                        *size,
+                        None,
+                        &self.inner,
+                        None,
+                    );
+                }
+            }
+            let size: usize = results
+                .iter()
+                .zip(&sizes)
+                .filter_map(|((_, result), size)| result.is_ok().then_some(size))
+                .sum();
+            ctx.perf_counters().add_to_counter(
+                PerfCounterType::BlobPutsTotalSize,
+                size.try_into().unwrap_or(0),
+            );
+        }
+
+        result
+    }
+}
+
+#[async_trait]
+impl<B: BlobstoreKeySource + BlobstorePutOps> BlobstoreKeySource for LogBlob<B> {
+    async fn enumerate<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        range: &'a BlobstoreKeyParam,
+    ) -> Result<BlobstoreEnumerationData> {
+        self.with_deadline(
+            ctx,
+            OperationType::Enumerate,
+            self.inner.enumerate(ctx, range),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use blobstore_test_utils::TtlSpy;
+    use borrowed::borrowed;
+    use chainedblob::ChainedBlob;
+    use context::with_request_class;
+    use context::RequestClass;
+    use fbinit::FacebookInit;
+    use memblob::Memblob;
+
+    use super::*;
+
+    /// Sleeps before every operation.
+    #[derive(Debug)]
+    struct SlowBlob {
+        inner: Memblob,
+        delay: Duration,
+    }
+
+    impl fmt::Display for SlowBlob {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "SlowBlob")
+        }
+    }
+
+    #[async_trait]
+    impl Blobstore for SlowBlob {
+        async fn get<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: &'a str,
+        ) -> Result<Option<BlobstoreGetData>> {
+            tokio::time::sleep(self.delay).await;
+            self.inner.get(ctx, key).await
+        }
+
+        async fn put<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: String,
+            value: BlobstoreBytes,
+        ) -> Result<()> {
+            tokio::time::sleep(self.delay).await;
+            self.inner.put(ctx, key, value).await
+        }
+    }
+
+    #[async_trait]
+    impl BlobstorePutOps for SlowBlob {
+        async fn put_explicit<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: String,
+            value: BlobstoreBytes,
+            put_behaviour: PutBehaviour,
+        ) -> Result<OverwriteStatus> {
+            tokio::time::sleep(self.delay).await;
+            self.inner
+                .put_explicit(ctx, key, value, put_behaviour)
+                .await
+        }
+
+        async fn put_with_status<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: String,
+            value: BlobstoreBytes,
+        ) -> Result<OverwriteStatus> {
+            tokio::time::sleep(self.delay).await;
+            self.inner.put_with_status(ctx, key, value).await
+        }
+    }
+
+    fn log_blob(
+        delay: Duration,
+        deadline: Instant,
+        log_file: &std::path::Path,
+    ) -> Result<LogBlob<SlowBlob>> {
+        let scuba = MononokeScubaSampleBuilder::with_discard().with_log_file(log_file)?;
+        let inner = SlowBlob {
+            inner: Memblob::default(),
+            delay,
+        };
+        Ok(LogBlob::new(inner, scuba, NonZeroU64::new(1).unwrap())
+            .with_deadline_extractor(Arc::new(move |_ctx| Some(deadline))))
+    }
+
+    #[fbinit::test]
+    async fn test_deadline_exceeded(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let dir = tempfile::tempdir()?;
+        let log_file = dir.path().join("scuba.json");
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let blob = log_blob(Duration::from_secs(30), deadline, &log_file)?;
+
+        let start = Instant::now();
+        let err = blob.get(ctx, "key").await.unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        let err = err.downcast::<DeadlineExceeded>()?;
+        assert_eq!(err.operation, OperationType::Get);
+
+        let err = blob
+            .put(ctx, "key".to_string(), BlobstoreBytes::from_bytes("v"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<DeadlineExceeded>()?.operation,
+            OperationType::Put
+        );
+
+        let samples = std::fs::read_to_string(&log_file)?;
+        let samples: Vec<_> = samples.lines().collect();
+        assert_eq!(samples.len(), 2);
+        assert!(samples.iter().all(|s| s.contains(TIMED_OUT)));
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_put_with_ttl(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let dir = tempfile::tempdir()?;
+        let log_file = dir.path().join("scuba.json");
+        let scuba = MononokeScubaSampleBuilder::with_discard().with_log_file(&log_file)?;
+        let spy = TtlSpy::new();
+        let blob = LogBlob::new(spy.clone(), scuba, NonZeroU64::new(1).unwrap());
+
+        let ttl = Duration::from_secs(300);
+        blob.put_with_ttl(
+            ctx,
+            "ephemeral".to_string(),
+            BlobstoreBytes::from_bytes("v"),
+            ttl,
+        )
+        .await?;
+        blob.put(ctx, "key".to_string(), BlobstoreBytes::from_bytes("v"))
+            .await?;
+        assert_eq!(
+            spy.puts(),
+            vec![
+                ("ephemeral".to_string(), Some(ttl)),
+                ("key".to_string(), None)
+            ]
+        );
+
+        let samples = std::fs::read_to_string(&log_file)?;
+        let samples: Vec<_> = samples.lines().collect();
+        assert_eq!(samples.len(), 2);
+        assert!(samples[0].contains(&format!("\"{}\":300", TTL_SECS)));
+        assert!(!samples[1].contains(TTL_SECS));
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_get_many(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let dir = tempfile::tempdir()?;
+        let log_file = dir.path().join("scuba.json");
+        let scuba = MononokeScubaSampleBuilder::with_discard().with_log_file(&log_file)?;
+        let blob = LogBlob::new(Memblob::default(), scuba, NonZeroU64::new(1).unwrap());
+        blob.put(ctx, "a".to_string(), BlobstoreBytes::from_bytes("aa"))
+            .await?;
+        blob.put(ctx, "b".to_string(), BlobstoreBytes::from_bytes("bbb"))
+            .await?;
+
+        let results = blob.get_many(ctx, &["a", "b", "missing"]).await?;
+        assert_eq!(results.len(), 3);
+        assert!(results["missing"].as_ref().unwrap().is_none());
+
+        // One sample for the batch.
+        let samples = std::fs::read_to_string(&log_file)?;
+        let samples: Vec<_> = samples.lines().collect();
+        assert_eq!(samples.len(), 3);
+        assert!(samples[2].contains(&format!("\"{}\":3", KEY_COUNT)));
+        assert!(samples[2].contains("\"size\":5"));
+
+        // And one per key with key sampling.
+        let blob = blob.with_get_many_key_sampling(NonZeroU64::new(1).unwrap());
+        blob.get_many(ctx, &["a", "b", "missing"]).await?;
+        let samples = std::fs::read_to_string(&log_file)?;
+        assert_eq!(samples.lines().count(), 7);
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_put_batch(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let dir = tempfile::tempdir()?;
+        let log_file = dir.path().join("scuba.json");
+        let scuba = MononokeScubaSampleBuilder::with_discard().with_log_file(&log_file)?;
+        let blob = LogBlob::new(Memblob::default(), scuba, NonZeroU64::new(1).unwrap());
+        let items = vec![
+            ("a".to_string(), BlobstoreBytes::from_bytes("aa")),
+            ("b".to_string(), BlobstoreBytes::from_bytes("bbb")),
+        ];
+
+        let results = blob.put_batch(ctx, items.clone()).await?;
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+
+        // One sample for the batch.
+        let samples = std::fs::read_to_string(&log_file)?;
+        let samples: Vec<_> = samples.lines().collect();
+        assert_eq!(samples.len(), 1);
+        assert!(samples[0].contains(&format!("\"{}\":2", KEY_COUNT)));
+        assert!(samples[0].contains("\"size\":5"));
+
+        // And one per item with key sampling.
+        let blob = blob.with_put_batch_key_sampling(NonZeroU64::new(1).unwrap());
+        blob.put_batch(ctx, items).await?;
+        let samples = std::fs::read_to_string(&log_file)?;
+        assert_eq!(samples.lines().count(), 4);
+        assert!(blob.get(ctx, "b").await?.is_some());
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_generous_deadline(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let dir = tempfile::tempdir()?;
+        let log_file = dir.path().join("scuba.json");
+        // Far enough in the future to overflow if naively added to now.
+        let deadline = Instant::now() + Duration::from_secs(1 << 40);
+        let blob = log_blob(Duration::from_millis(10), deadline, &log_file)?;
+
+        blob.put(ctx, "key".to_string(), BlobstoreBytes::from_bytes("v"))
+            .await?;
+        let data = blob.get(ctx, "key").await?;
+        assert_eq!(
+            data.map(|d| d.into_raw_bytes()),
+            Some(BlobstoreBytes::from_bytes("v").into_bytes())
+        );
+        assert!(
+            blob.is_present(ctx, "key")
+                .await?
+                .assume_not_found_if_unsure()
+        );
+
+        let samples = std::fs::read_to_string(&log_file)?;
+        assert_eq!(samples.lines().count(), 3);
+        assert!(!samples.contains(TIMED_OUT));
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_request_class(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let background = with_request_class(&ctx, RequestClass::Background);
+        let dir = tempfile::tempdir()?;
+        let log_file = dir.path().join("scuba.json");
+        let scuba = MononokeScubaSampleBuilder::with_discard().with_log_file(&log_file)?;
+        let blob = LogBlob::new(Memblob::default(), scuba, NonZeroU64::new(1).unwrap());
+
+        blob.put(
+            &background,
+            "key".to_string(),
+            BlobstoreBytes::from_bytes("v"),
+        )
+        .await?;
+        blob.get(&ctx, "key").await?;
+        blob.is_present(&background, "key").await?;
+        blob.get_many(&background, &["key"]).await?;
+
+        let samples = std::fs::read_to_string(&log_file)?;
+        let samples: Vec<_> = samples.lines().collect();
+        assert_eq!(samples.len(), 4);
+        let class = |class| format!("\"{}\":\"{}\"", REQUEST_CLASS, class);
+        assert!(samples[0].contains(&class("background")));
+        assert!(samples[1].contains(&class("interactive")));
+        assert!(samples[2].contains(&class("background")));
+        assert!(samples[3].contains(&class("background")));
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_provenance(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let dir = tempfile::tempdir()?;
+        let log_file = dir.path().join("scuba.json");
+        let scuba = MononokeScubaSampleBuilder::with_discard().with_log_file(&log_file)?;
+        let (cache, origin) = (Memblob::default(), Memblob::default());
+        let chain = ChainedBlob::new(
+            vec![
+                ("cache".to_string(), cache.clone()),
+                ("origin".to_string(), origin.clone()),
+            ],
+            Default::default(),
+        )?;
+        let blob = LogBlob::new(chain, scuba, NonZeroU64::new(1).unwrap());
+        origin
+            .put(ctx, "key".to_string(), BlobstoreBytes::from_bytes("v"))
+            .await?;
+
+        let result = blob.get_with_provenance(ctx, "key").await?;
+        assert!(result.data.is_some());
+        assert_eq!(result.provenance.unwrap().served_by.unwrap().name, "origin");
+        blob.get(ctx, "absent").await?;
+
+        let samples = std::fs::read_to_string(&log_file)?;
+        let samples: Vec<_> = samples.lines().collect();
+        assert_eq!(samples.len(), 2);
+        assert!(samples[0].contains(&format!("\"{}\":\"origin\"", SERVED_BY)));
+        assert!(samples[0].contains(&format!("\"{}\":1", SERVED_BY_INDEX)));
+        assert!(samples[0].contains(&format!("\"{}\":1", CHAIN_MISSES)));
+        assert!(!samples[1].contains(SERVED_BY));
+        assert!(samples[1].contains(&format!("\"{}\":2", CHAIN_MISSES)));
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_hot_keys(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let tracker = Arc::new(HotKeyTracker::new(HotKeyTrackerConfig {
+            sketch_width: 1024,
+            tracked_keys: 64,
+            ..Default::default()
+        }));
+        let scuba = MononokeScubaSampleBuilder::with_discard();
+        let blob = LogBlob::new(Memblob::default(), scuba, NonZeroU64::new(1).unwrap())
+            .with_hot_key_tracker(tracker.clone());
+
+        // A few hot keys, fetched among many cold ones, alone and in batches.
+        let hot: Vec<_> = (0..4).map(|i| format!("hot{}", i)).collect();
+        for round in 0..50 {
+            for key in &hot {
+                blob.get(ctx, key).await?;
+            }
+            let cold: Vec<_> = (0..40).map(|i| format!("cold{}_{}", round, i)).collect();
+            let mut batch: Vec<&str> = cold.iter().map(|k| k.as_str()).collect();
+            batch.push(&hot[0]);
+            blob.get_many(ctx, &batch).await?;
+        }
+
+        let hot_keys = tracker.hot_keys(4);
+        let mut keys: Vec<_> = hot_keys.iter().map(|(key, _)| key.clone()).collect();
+        keys.sort();
+        assert_eq!(keys, hot);
+        assert_eq!(hot_keys[0].0, "hot0");
+        assert!(hot_keys[0].1 >= 100);
+        assert!(hot_keys.iter().all(|(_, count)| *count >= 50));
+        Ok(())
     }
 }
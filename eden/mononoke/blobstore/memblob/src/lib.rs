@@ -10,6 +10,8 @@ use std::collections::HashMap;
 use std::fmt;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::format_err;
 use anyhow::Result;
@@ -17,8 +19,10 @@ use async_trait::async_trait;
 use blobstore::Blobstore;
 use blobstore::BlobstoreEnumerationData;
 use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreGetManyData;
 use blobstore::BlobstoreKeyParam;
 use blobstore::BlobstoreKeySource;
+use blobstore::BlobstorePutBatchResults;
 use blobstore::BlobstorePutOps;
 use blobstore::BlobstoreUnlinkOps;
 use blobstore::OverwriteStatus;
@@ -29,12 +33,18 @@ use futures::future::BoxFuture;
 use futures::future::FutureExt;
 use mononoke_types::BlobstoreBytes;
 
+/// Returns the current time. Can be replaced in tests to expire blobs put
+/// with a TTL without waiting.
+pub type MemblobClock = Arc<dyn Fn() -> Instant + Send + Sync>;
+
 // Implements hardlink-style links
 #[derive(Default, Debug)]
 struct MemState {
     next_id: usize,
     data: HashMap<usize, BlobstoreBytes>,
     links: BTreeMap<String, usize>,
+    // Expiry time of the data put with a TTL. Links share the expiry.
+    expiry: HashMap<usize, Instant>,
 }
 
 impl MemState {
@@ -43,60 +53,78 @@ impl MemState {
         key: String,
         value: BlobstoreBytes,
         put_behaviour: PutBehaviour,
+        expires_at: Option<Instant>,
+        now: Instant,
     ) -> OverwriteStatus {
         match put_behaviour {
             PutBehaviour::Overwrite => {
                 let id = self.next_id;
                 self.data.insert(id, value);
                 self.links.insert(key, id);
+                if let Some(expires_at) = expires_at {
+                    self.expiry.insert(id, expires_at);
+                }
                 self.next_id += 1;
                 OverwriteStatus::NotChecked
             }
             PutBehaviour::IfAbsent | PutBehaviour::OverwriteAndLog => {
-                if self.links.contains_key(&key) {
+                if self.live_id(&key, now).is_some() {
                     if put_behaviour.should_overwrite() {
-                        self.put(key, value, PutBehaviour::Overwrite);
+                        self.put(key, value, PutBehaviour::Overwrite, expires_at, now);
                         OverwriteStatus::Overwrote
                     } else {
                         OverwriteStatus::Prevented
                     }
                 } else {
-                    self.put(key, value, PutBehaviour::Overwrite);
+                    self.put(key, value, PutBehaviour::Overwrite, expires_at, now);
                     OverwriteStatus::New
                 }
             }
         }
     }
 
-    fn link(&mut self, existing_key: &str, link_key: String) -> Result<()> {
-        if let Some(existing_id) = self.links.get(existing_key) {
-            let existing_id = *existing_id;
+    /// The id of the data `key` links to, unless it expired.
+    fn live_id(&self, key: &str, now: Instant) -> Option<usize> {
+        let id = *self.links.get(key)?;
+        match self.expiry.get(&id) {
+            Some(expires_at) if *expires_at <= now => None,
+            _ => Some(id),
+        }
+    }
+
+    fn link(&mut self, existing_key: &str, link_key: String, now: Instant) -> Result<()> {
+        if let Some(existing_id) = self.live_id(existing_key, now) {
             self.links.insert(link_key, existing_id);
             return Ok(());
         }
         panic!("Unknown existing_key {} {:?}", existing_key, self.links)
     }
 
-    fn get(&self, key: &str) -> Option<&BlobstoreBytes> {
-        if let Some(id) = self.links.get(key) {
-            self.data.get(id)
+    fn get(&self, key: &str, now: Instant) -> Option<&BlobstoreBytes> {
+        if let Some(id) = self.live_id(key, now) {
+            self.data.get(&id)
         } else {
             None
         }
     }
 
-    fn unlink(&mut self, key: &str) -> Option<()> {
-        self.links.remove(key).map(|_| ())
+    fn unlink(&mut self, key: &str, now: Instant) -> Option<()> {
+        let live = self.live_id(key, now).is_some();
+        self.links.remove(key);
+        live.then_some(())
     }
 }
 
 /// In-memory "blob store"
 ///
-/// Pure in-memory implementation for testing.
+/// Pure in-memory implementation for testing. Blobs put with a TTL expire
+/// according to the blobstore's clock, see `with_clock`.
 #[derive(Clone)]
 pub struct Memblob {
     state: Arc<Mutex<MemState>>,
     put_behaviour: PutBehaviour,
+    clock: MemblobClock,
+    enumeration_page_size: Option<usize>,
 }
 
 impl std::fmt::Display for Memblob {
@@ -110,18 +138,49 @@ impl Memblob {
         Self {
             state: Arc::new(Mutex::new(MemState::default())),
             put_behaviour,
+            clock: Arc::new(Instant::now),
+            enumeration_page_size: None,
         }
     }
 
+    /// Use `clock` instead of the system clock to expire blobs. The clock is
+    /// read once each time the blobs are locked.
+    pub fn with_clock(mut self, clock: MemblobClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Return at most `page_size` keys per `enumerate`, with a token to
+    /// enumerate the next ones. By default, all keys are returned at once.
+    pub fn with_enumeration_page_size(mut self, page_size: usize) -> Self {
+        self.enumeration_page_size = Some(page_size.max(1));
+        self
+    }
+
     pub fn unlink(&self, key: String) -> BoxFuture<'static, Result<Option<()>>> {
         let state = self.state.clone();
+        let now = (self.clock)();
 
         async move {
             let mut inner = state.lock().expect("lock poison");
-            Ok(inner.unlink(&key))
+            Ok(inner.unlink(&key, now))
         }
         .boxed()
     }
+
+    fn put_impl(
+        &self,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+        ttl: Option<Duration>,
+    ) -> OverwriteStatus {
+        let now = (self.clock)();
+        // A TTL too long to represent never expires.
+        let expires_at = ttl.and_then(|ttl| now.checked_add(ttl));
+        let mut inner = self.state.lock().expect("lock poison");
+        inner.put(key, value, put_behaviour, expires_at, now)
+    }
 }
 
 impl Default for Memblob {
@@ -139,10 +198,7 @@ impl BlobstorePutOps for Memblob {
         value: BlobstoreBytes,
         put_behaviour: PutBehaviour,
     ) -> Result<OverwriteStatus> {
-        let state = self.state.clone();
-
-        let mut inner = state.lock().expect("lock poison");
-        Ok(inner.put(key, value, put_behaviour))
+        Ok(self.put_impl(key, value, put_behaviour, None))
     }
 
     async fn put_with_status<'a>(
@@ -153,6 +209,32 @@ impl BlobstorePutOps for Memblob {
     ) -> Result<OverwriteStatus> {
         self.put_explicit(ctx, key, value, self.put_behaviour).await
     }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        _ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        Ok(self.put_impl(key, value, self.put_behaviour, Some(ttl)))
+    }
+
+    async fn put_batch<'a>(
+        &'a self,
+        _ctx: &'a CoreContext,
+        items: Vec<(String, BlobstoreBytes)>,
+    ) -> Result<BlobstorePutBatchResults> {
+        let now = (self.clock)();
+        let mut inner = self.state.lock().expect("lock poison");
+        Ok(items
+            .into_iter()
+            .map(|(key, value)| {
+                let status = inner.put(key.clone(), value, self.put_behaviour, None, now);
+                (key, Ok(status))
+            })
+            .collect())
+    }
 }
 
 #[async_trait]
@@ -164,8 +246,25 @@ impl Blobstore for Memblob {
     ) -> Result<Option<BlobstoreGetData>> {
         let state = self.state.clone();
 
+        let now = (self.clock)();
         let inner = state.lock().expect("lock poison");
-        Ok(inner.get(key).map(|bytes| bytes.clone().into()))
+        Ok(inner.get(key, now).map(|bytes| bytes.clone().into()))
+    }
+
+    async fn get_many<'a>(
+        &'a self,
+        _ctx: &'a CoreContext,
+        keys: &'a [&'a str],
+    ) -> Result<BlobstoreGetManyData> {
+        let now = (self.clock)();
+        let inner = self.state.lock().expect("lock poison");
+        Ok(keys
+            .iter()
+            .map(|key| {
+                let data = inner.get(key, now).map(|bytes| bytes.clone().into());
+                (key.to_string(), Ok(data))
+            })
+            .collect())
     }
 
     async fn put<'a>(
@@ -186,8 +285,9 @@ impl Blobstore for Memblob {
     ) -> Result<()> {
         let state = self.state.clone();
 
+        let now = (self.clock)();
         let mut inner = state.lock().expect("lock poison");
-        inner.link(old_key, new_key)
+        inner.link(old_key, new_key, now)
     }
 }
 
@@ -195,8 +295,9 @@ impl Blobstore for Memblob {
 impl BlobstoreUnlinkOps for Memblob {
     async fn unlink<'a>(&'a self, _ctx: &'a CoreContext, key: &'a str) -> Result<()> {
         let state = self.state.clone();
+        let now = (self.clock)();
         let mut inner = state.lock().expect("lock poison");
-        if inner.unlink(key).is_some() {
+        if inner.unlink(key, now).is_some() {
             Ok(())
         } else {
             Err(format_err!("Unknown key {} to Memblob::unlink()", key))
@@ -213,10 +314,26 @@ impl BlobstoreKeySource for Memblob {
     ) -> Result<BlobstoreEnumerationData> {
         match range {
             BlobstoreKeyParam::Start(range) => {
+                let now = (self.clock)();
                 let state = self.state.lock().expect("lock poison");
+                let page_size = self.enumeration_page_size.unwrap_or(usize::MAX);
+                let mut keys: Vec<_> = state
+                    .links
+                    .range(range)
+                    .filter(|(k, _)| state.live_id(k, now).is_some())
+                    .map(|(k, _)| k.clone())
+                    .take(page_size.saturating_add(1))
+                    .collect();
+                let next_token = if keys.len() > page_size {
+                    keys.pop();
+                    let last = keys.last().expect("page_size is at least 1");
+                    Some(BlobstoreKeyParam::Start(range.after(last)))
+                } else {
+                    None
+                };
                 Ok(BlobstoreEnumerationData {
-                    keys: state.links.range(range).map(|(k, _)| k.clone()).collect(),
-                    next_token: None,
+                    keys: keys.into_iter().collect(),
+                    next_token,
                 })
             }
             BlobstoreKeyParam::Continuation(_) => {
@@ -0,0 +1,781 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! A blobstore that mirrors writes to a secondary blobstore, for migrating
+//! from one backend to another.
+//!
+//! Writes go to the primary, and once they succeed, to the secondary. Reads
+//! only use the primary. Failures of the secondary never fail the caller:
+//! they are counted in `MirrorCounters`, so the secondary has to be
+//! backfilled or audited before it replaces the primary. To help with the
+//! audit, a sample of gets can also be compared with the secondary, and the
+//! differences are recorded in a `DivergenceReport`.
+
+use std::fmt;
+use std::num::NonZeroU64;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use async_trait::async_trait;
+use blobstore::Blobstore;
+use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreIsPresent;
+use blobstore::BlobstorePutOps;
+use blobstore::BlobstoreUnlinkOps;
+use blobstore::OverwriteStatus;
+use blobstore::PutBehaviour;
+use context::CoreContext;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use mononoke_types::BlobstoreBytes;
+use rand::Rng;
+use slog::warn;
+use tokio::sync::mpsc;
+use tokio::sync::Notify;
+
+/// When writes reach the secondary.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MirrorMode {
+    /// Before the write returns to the caller.
+    Inline,
+    /// From a background task, in order. Writes wait for room in the queue
+    /// when `queue_size` writes are already pending.
+    Background { queue_size: usize },
+}
+
+#[derive(Clone, Debug)]
+pub struct MirrorOptions {
+    pub mode: MirrorMode,
+    /// Compare one in this many gets with the secondary. `None` disables the
+    /// comparison.
+    pub compare_sample_rate: Option<NonZeroU64>,
+    /// Log secondary failures with their key.
+    pub log_failures: bool,
+    /// Number of divergences kept in the report. Later ones are only
+    /// counted.
+    pub max_divergences: usize,
+}
+
+impl Default for MirrorOptions {
+    fn default() -> Self {
+        Self {
+            mode: MirrorMode::Background { queue_size: 1000 },
+            compare_sample_rate: None,
+            log_failures: true,
+            max_divergences: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MirrorCounters {
+    /// Writes applied to the secondary.
+    pub mirrored: AtomicU64,
+    /// Writes the secondary failed.
+    pub failed: AtomicU64,
+    /// Writes queued for the secondary and not yet applied.
+    pub queued: AtomicU64,
+    /// Gets compared with the secondary.
+    pub compared: AtomicU64,
+    /// Comparisons that could not read the secondary.
+    pub compare_errors: AtomicU64,
+    pub missing_in_secondary: AtomicU64,
+    pub content_differs: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DivergenceKind {
+    /// The primary has the key, the secondary does not.
+    MissingInSecondary,
+    /// Both have the key, with different values.
+    ContentDiffers,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    pub key: String,
+    pub kind: DivergenceKind,
+}
+
+/// Handle to the divergences found by a `MirroringBlob`. Clones share the
+/// same report.
+#[derive(Clone, Debug, Default)]
+pub struct DivergenceReport {
+    divergences: Arc<Mutex<Vec<Divergence>>>,
+}
+
+impl DivergenceReport {
+    /// The divergences found so far, oldest first.
+    pub fn divergences(&self) -> Vec<Divergence> {
+        self.divergences.lock().expect("lock poisoned").clone()
+    }
+
+    /// Take the divergences found so far, making room for new ones.
+    pub fn take(&self) -> Vec<Divergence> {
+        std::mem::take(&mut *self.divergences.lock().expect("lock poisoned"))
+    }
+
+    fn record(&self, divergence: Divergence, max: usize) {
+        let mut divergences = self.divergences.lock().expect("lock poisoned");
+        if divergences.len() < max {
+            divergences.push(divergence);
+        }
+    }
+}
+
+/// A write to apply to the secondary.
+struct MirrorOp {
+    ctx: CoreContext,
+    operation: &'static str,
+    key: String,
+    write: BoxFuture<'static, Result<()>>,
+}
+
+struct Shared {
+    counters: MirrorCounters,
+    report: DivergenceReport,
+    log_failures: bool,
+    max_divergences: usize,
+    /// Notified when the queue becomes empty.
+    idle: Notify,
+}
+
+impl Shared {
+    fn record_write(&self, op: &MirrorOp, result: Result<()>) {
+        match result {
+            Ok(()) => {
+                self.counters.mirrored.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                self.counters.failed.fetch_add(1, Ordering::Relaxed);
+                if self.log_failures {
+                    warn!(
+                        op.ctx.logger(),
+                        "Failed to mirror {} of key {} to secondary blobstore: {:#}",
+                        op.operation,
+                        op.key,
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    fn dequeued(&self) {
+        if self.counters.queued.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.idle.notify_waiters();
+        }
+    }
+
+    fn record_divergence(&self, key: &str, kind: DivergenceKind) {
+        let counter = match kind {
+            DivergenceKind::MissingInSecondary => &self.counters.missing_in_secondary,
+            DivergenceKind::ContentDiffers => &self.counters.content_differs,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.report.record(
+            Divergence {
+                key: key.to_string(),
+                kind,
+            },
+            self.max_divergences,
+        );
+    }
+}
+
+async fn mirror_worker(mut queue: mpsc::Receiver<MirrorOp>, shared: Arc<Shared>) {
+    while let Some(mut op) = queue.recv().await {
+        let result = (&mut op.write).await;
+        shared.record_write(&op, result);
+        shared.dequeued();
+    }
+}
+
+/// Mirrors writes from `primary` to `secondary`. See the crate documentation.
+pub struct MirroringBlob<P, S> {
+    primary: P,
+    secondary: Arc<S>,
+    compare_sample_rate: Option<NonZeroU64>,
+    queue: Option<mpsc::Sender<MirrorOp>>,
+    shared: Arc<Shared>,
+}
+
+impl<P: fmt::Display, S: fmt::Display> fmt::Display for MirroringBlob<P, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MirroringBlob<{}, {}>", &self.primary, &self.secondary)
+    }
+}
+
+impl<P: fmt::Debug, S: fmt::Debug> fmt::Debug for MirroringBlob<P, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MirroringBlob")
+            .field("primary", &self.primary)
+            .field("secondary", &self.secondary)
+            .field("counters", &self.shared.counters)
+            .finish()
+    }
+}
+
+impl<P, S> MirroringBlob<P, S> {
+    /// In `MirrorMode::Background`, this spawns the task writing to the
+    /// secondary, so it must be called from a Tokio runtime. The task exits
+    /// when the `MirroringBlob` is dropped.
+    pub fn new(primary: P, secondary: S, options: MirrorOptions) -> Self {
+        let shared = Arc::new(Shared {
+            counters: MirrorCounters::default(),
+            report: DivergenceReport::default(),
+            log_failures: options.log_failures,
+            max_divergences: options.max_divergences,
+            idle: Notify::new(),
+        });
+        let queue = match options.mode {
+            MirrorMode::Inline => None,
+            MirrorMode::Background { queue_size } => {
+                let (sender, receiver) = mpsc::channel(queue_size.max(1));
+                tokio::spawn(mirror_worker(receiver, shared.clone()));
+                Some(sender)
+            }
+        };
+        Self {
+            primary,
+            secondary: Arc::new(secondary),
+            compare_sample_rate: options.compare_sample_rate,
+            queue,
+            shared,
+        }
+    }
+
+    pub fn counters(&self) -> &MirrorCounters {
+        &self.shared.counters
+    }
+
+    pub fn divergence_report(&self) -> DivergenceReport {
+        self.shared.report.clone()
+    }
+
+    /// Wait until every write queued so far reached the secondary, or failed
+    /// to. Call it before shutting down to avoid losing queued writes.
+    pub async fn drain(&self) {
+        loop {
+            let idle = self.shared.idle.notified();
+            if self.shared.counters.queued.load(Ordering::Acquire) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    /// Apply `write` to the secondary, now or in the background.
+    async fn mirror(
+        &self,
+        ctx: &CoreContext,
+        operation: &'static str,
+        key: String,
+        write: BoxFuture<'static, Result<()>>,
+    ) {
+        let op = MirrorOp {
+            ctx: ctx.clone(),
+            operation,
+            key,
+            write,
+        };
+        match &self.queue {
+            None => {
+                let mut op = op;
+                let result = (&mut op.write).await;
+                self.shared.record_write(&op, result);
+            }
+            Some(queue) => {
+                self.shared.counters.queued.fetch_add(1, Ordering::AcqRel);
+                if let Err(mpsc::error::SendError(op)) = queue.send(op).await {
+                    // The worker only exits if its runtime shut down.
+                    self.shared
+                        .record_write(&op, Err(anyhow!("mirroring task is not running")));
+                    self.shared.dequeued();
+                }
+            }
+        }
+    }
+
+    fn should_compare(&self) -> bool {
+        match self.compare_sample_rate {
+            None => false,
+            Some(rate) => rate.get() == 1 || rand::thread_rng().gen_range(0..rate.get()) == 0,
+        }
+    }
+}
+
+impl<P, S: Blobstore + 'static> MirroringBlob<P, S> {
+    /// Compare the value the primary returned for `key` with the secondary.
+    async fn compare(&self, ctx: &CoreContext, key: &str, primary: &BlobstoreGetData) {
+        self.shared
+            .counters
+            .compared
+            .fetch_add(1, Ordering::Relaxed);
+        match self.secondary.get(ctx, key).await {
+            Ok(None) => self
+                .shared
+                .record_divergence(key, DivergenceKind::MissingInSecondary),
+            Ok(Some(secondary)) => {
+                if secondary.as_raw_bytes() != primary.as_raw_bytes() {
+                    self.shared
+                        .record_divergence(key, DivergenceKind::ContentDiffers);
+                }
+            }
+            Err(e) => {
+                self.shared
+                    .counters
+                    .compare_errors
+                    .fetch_add(1, Ordering::Relaxed);
+                if self.shared.log_failures {
+                    warn!(
+                        ctx.logger(),
+                        "Failed to compare key {} with secondary blobstore: {:#}", key, e
+                    );
+                }
+            }
+        }
+    }
+
+    async fn mirror_put(&self, ctx: &CoreContext, key: String, value: BlobstoreBytes) {
+        let secondary = self.secondary.clone();
+        let write = {
+            let ctx = ctx.clone();
+            let key = key.clone();
+            async move { secondary.put(&ctx, key, value).await }.boxed()
+        };
+        self.mirror(ctx, "put", key, write).await
+    }
+}
+
+#[async_trait]
+impl<P: Blobstore, S: Blobstore + 'static> Blobstore for MirroringBlob<P, S> {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        let value = self.primary.get(ctx, key).await?;
+        if let Some(value) = &value {
+            if self.should_compare() {
+                self.compare(ctx, key, value).await;
+            }
+        }
+        Ok(value)
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        self.primary.put(ctx, key.clone(), value.clone()).await?;
+        self.mirror_put(ctx, key, value).await;
+        Ok(())
+    }
+
+    async fn is_present<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreIsPresent> {
+        self.primary.is_present(ctx, key).await
+    }
+
+    async fn copy<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        old_key: &'a str,
+        new_key: String,
+    ) -> Result<()> {
+        self.primary.copy(ctx, old_key, new_key.clone()).await?;
+        let secondary = self.secondary.clone();
+        let write = {
+            let ctx = ctx.clone();
+            let old_key = old_key.to_string();
+            let new_key = new_key.clone();
+            async move { secondary.copy(&ctx, &old_key, new_key).await }.boxed()
+        };
+        self.mirror(ctx, "copy", new_key, write).await;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<P: BlobstorePutOps, S: BlobstorePutOps + 'static> BlobstorePutOps for MirroringBlob<P, S> {
+    async fn put_explicit<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+    ) -> Result<OverwriteStatus> {
+        let status = self
+            .primary
+            .put_explicit(ctx, key.clone(), value.clone(), put_behaviour)
+            .await?;
+        // If the primary kept its value, the secondary should keep its own.
+        if status != OverwriteStatus::Prevented {
+            let secondary = self.secondary.clone();
+            let write = {
+                let ctx = ctx.clone();
+                let key = key.clone();
+                async move {
+                    secondary
+                        .put_explicit(&ctx, key, value, put_behaviour)
+                        .await
+                        .map(|_| ())
+                }
+                .boxed()
+            };
+            self.mirror(ctx, "put", key, write).await;
+        }
+        Ok(status)
+    }
+
+    async fn put_with_status<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<OverwriteStatus> {
+        let status = self
+            .primary
+            .put_with_status(ctx, key.clone(), value.clone())
+            .await?;
+        if status != OverwriteStatus::Prevented {
+            self.mirror_put(ctx, key, value).await;
+        }
+        Ok(status)
+    }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        let status = self
+            .primary
+            .put_with_ttl(ctx, key.clone(), value.clone(), ttl)
+            .await?;
+        if status != OverwriteStatus::Prevented {
+            let secondary = self.secondary.clone();
+            let write = {
+                let ctx = ctx.clone();
+                let key = key.clone();
+                async move {
+                    secondary
+                        .put_with_ttl(&ctx, key, value, ttl)
+                        .await
+                        .map(|_| ())
+                }
+                .boxed()
+            };
+            self.mirror(ctx, "put", key, write).await;
+        }
+        Ok(status)
+    }
+}
+
+#[async_trait]
+impl<P: BlobstoreUnlinkOps, S: BlobstoreUnlinkOps + 'static> BlobstoreUnlinkOps
+    for MirroringBlob<P, S>
+{
+    async fn unlink<'a>(&'a self, ctx: &'a CoreContext, key: &'a str) -> Result<()> {
+        self.primary.unlink(ctx, key).await?;
+        let secondary = self.secondary.clone();
+        let write = {
+            let ctx = ctx.clone();
+            let key = key.to_string();
+            async move { secondary.unlink(&ctx, &key).await }.boxed()
+        };
+        self.mirror(ctx, "unlink", key.to_string(), write).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicBool;
+
+    use borrowed::borrowed;
+    use fbinit::FacebookInit;
+    use memblob::Memblob;
+
+    use super::*;
+
+    /// A memory blobstore that can be taken down and slowed down.
+    #[derive(Debug, Default)]
+    struct FlakyBlob {
+        inner: Memblob,
+        down: AtomicBool,
+        delay: Option<Duration>,
+    }
+
+    impl fmt::Display for FlakyBlob {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "FlakyBlob")
+        }
+    }
+
+    impl FlakyBlob {
+        async fn check(&self) -> Result<()> {
+            if let Some(delay) = self.delay {
+                tokio::time::sleep(delay).await;
+            }
+            if self.down.load(Ordering::Relaxed) {
+                Err(anyhow!("blobstore is down"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Blobstore for FlakyBlob {
+        async fn get<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: &'a str,
+        ) -> Result<Option<BlobstoreGetData>> {
+            self.check().await?;
+            self.inner.get(ctx, key).await
+        }
+
+        async fn put<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: String,
+            value: BlobstoreBytes,
+        ) -> Result<()> {
+            self.check().await?;
+            self.inner.put(ctx, key, value).await
+        }
+
+        async fn is_present<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: &'a str,
+        ) -> Result<BlobstoreIsPresent> {
+            self.check().await?;
+            self.inner.is_present(ctx, key).await
+        }
+    }
+
+    #[async_trait]
+    impl BlobstorePutOps for FlakyBlob {
+        async fn put_explicit<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: String,
+            value: BlobstoreBytes,
+            put_behaviour: PutBehaviour,
+        ) -> Result<OverwriteStatus> {
+            self.check().await?;
+            self.inner
+                .put_explicit(ctx, key, value, put_behaviour)
+                .await
+        }
+
+        async fn put_with_status<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: String,
+            value: BlobstoreBytes,
+        ) -> Result<OverwriteStatus> {
+            self.check().await?;
+            self.inner.put_with_status(ctx, key, value).await
+        }
+    }
+
+    #[async_trait]
+    impl BlobstoreUnlinkOps for FlakyBlob {
+        async fn unlink<'a>(&'a self, ctx: &'a CoreContext, key: &'a str) -> Result<()> {
+            self.check().await?;
+            BlobstoreUnlinkOps::unlink(&self.inner, ctx, key).await
+        }
+    }
+
+    fn bytes(value: &[u8]) -> BlobstoreBytes {
+        BlobstoreBytes::from_bytes(value.to_vec())
+    }
+
+    fn value(data: Option<BlobstoreGetData>) -> Option<Vec<u8>> {
+        data.map(|d| d.into_raw_bytes().to_vec())
+    }
+
+    fn options(mode: MirrorMode) -> MirrorOptions {
+        MirrorOptions {
+            mode,
+            ..Default::default()
+        }
+    }
+
+    fn counter(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+
+    #[fbinit::test]
+    async fn test_mirror_writes(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+
+        for mode in [
+            MirrorMode::Inline,
+            MirrorMode::Background { queue_size: 10 },
+        ] {
+            let secondary = Arc::new(FlakyBlob::default());
+            let blob = MirroringBlob::new(Memblob::default(), secondary.clone(), options(mode));
+
+            blob.put(ctx, "a".to_string(), bytes(b"1")).await?;
+            blob.put_with_status(ctx, "b".to_string(), bytes(b"2"))
+                .await?;
+            blob.copy(ctx, "a", "c".to_string()).await?;
+            BlobstoreUnlinkOps::unlink(&blob, ctx, "b").await?;
+            blob.drain().await;
+
+            for (key, expected) in [("a", Some(b"1")), ("b", None), ("c", Some(b"1"))] {
+                let expected = expected.map(|v| v.to_vec());
+                assert_eq!(value(blob.get(ctx, key).await?), expected);
+                assert_eq!(value(secondary.get(ctx, key).await?), expected);
+            }
+            assert_eq!(counter(&blob.counters().mirrored), 4);
+            assert_eq!(counter(&blob.counters().failed), 0);
+            assert_eq!(counter(&blob.counters().queued), 0);
+        }
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_secondary_outage(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+
+        for mode in [
+            MirrorMode::Inline,
+            MirrorMode::Background { queue_size: 10 },
+        ] {
+            let secondary = Arc::new(FlakyBlob::default());
+            let blob = MirroringBlob::new(Memblob::default(), secondary.clone(), options(mode));
+
+            secondary.down.store(true, Ordering::Relaxed);
+            blob.put(ctx, "a".to_string(), bytes(b"1")).await?;
+            blob.put(ctx, "b".to_string(), bytes(b"2")).await?;
+            blob.drain().await;
+            assert_eq!(value(blob.get(ctx, "a").await?), Some(b"1".to_vec()));
+            assert_eq!(counter(&blob.counters().failed), 2);
+            assert_eq!(counter(&blob.counters().mirrored), 0);
+
+            // Once the secondary is back, later writes reach it again.
+            secondary.down.store(false, Ordering::Relaxed);
+            blob.put(ctx, "c".to_string(), bytes(b"3")).await?;
+            blob.drain().await;
+            assert!(secondary.get(ctx, "a").await?.is_none());
+            assert_eq!(value(secondary.get(ctx, "c").await?), Some(b"3".to_vec()));
+            assert_eq!(counter(&blob.counters().failed), 2);
+            assert_eq!(counter(&blob.counters().mirrored), 1);
+        }
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_compare_mode(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+
+        let primary = Memblob::default();
+        let secondary = Arc::new(FlakyBlob::default());
+        let blob = MirroringBlob::new(
+            primary.clone(),
+            secondary.clone(),
+            MirrorOptions {
+                mode: MirrorMode::Inline,
+                compare_sample_rate: NonZeroU64::new(1),
+                ..Default::default()
+            },
+        );
+
+        blob.put(ctx, "same".to_string(), bytes(b"1")).await?;
+        blob.put(ctx, "differs".to_string(), bytes(b"2")).await?;
+        secondary
+            .put_explicit(
+                ctx,
+                "differs".to_string(),
+                bytes(b"other"),
+                PutBehaviour::Overwrite,
+            )
+            .await?;
+        primary.put(ctx, "missing".to_string(), bytes(b"3")).await?;
+
+        for key in ["same", "differs", "missing", "absent"] {
+            blob.get(ctx, key).await?;
+        }
+        // Comparison failures don't fail the get.
+        secondary.down.store(true, Ordering::Relaxed);
+        assert_eq!(value(blob.get(ctx, "same").await?), Some(b"1".to_vec()));
+
+        let counters = blob.counters();
+        assert_eq!(counter(&counters.compared), 4);
+        assert_eq!(counter(&counters.compare_errors), 1);
+        assert_eq!(counter(&counters.missing_in_secondary), 1);
+        assert_eq!(counter(&counters.content_differs), 1);
+        let report = blob.divergence_report();
+        assert_eq!(
+            report.take(),
+            vec![
+                Divergence {
+                    key: "differs".to_string(),
+                    kind: DivergenceKind::ContentDiffers,
+                },
+                Divergence {
+                    key: "missing".to_string(),
+                    kind: DivergenceKind::MissingInSecondary,
+                },
+            ]
+        );
+        assert!(report.divergences().is_empty());
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_drain(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+
+        let secondary = Arc::new(FlakyBlob {
+            delay: Some(Duration::from_millis(5)),
+            ..Default::default()
+        });
+        let blob = MirroringBlob::new(
+            Memblob::default(),
+            secondary.clone(),
+            options(MirrorMode::Background { queue_size: 4 }),
+        );
+
+        for i in 0..20 {
+            blob.put(ctx, format!("key{}", i), bytes(b"v")).await?;
+        }
+        assert!(counter(&blob.counters().queued) > 0);
+        blob.drain().await;
+        assert_eq!(counter(&blob.counters().queued), 0);
+        assert_eq!(counter(&blob.counters().mirrored), 20);
+        for i in 0..20 {
+            assert!(secondary.get(ctx, &format!("key{}", i)).await?.is_some());
+        }
+        // Draining an empty queue returns immediately.
+        blob.drain().await;
+        Ok(())
+    }
+}
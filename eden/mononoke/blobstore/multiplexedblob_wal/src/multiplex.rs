@@ -12,6 +12,7 @@ use std::num::NonZeroUsize;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use anyhow::Context as _;
@@ -223,6 +224,7 @@ impl WalMultiplexedBlobstore {
         key: String,
         value: BlobstoreBytes,
         put_behaviour: Option<PutBehaviour>,
+        ttl: Option<Duration>,
         scuba: &Scuba,
     ) -> Result<OverwriteStatus> {
         ctx.perf_counters()
@@ -259,6 +261,7 @@ impl WalMultiplexedBlobstore {
             &key,
             &value,
             put_behaviour,
+            ttl,
             scuba,
             self.inflight_ops_counter.clone(),
         );
@@ -284,6 +287,7 @@ impl WalMultiplexedBlobstore {
                                 &key,
                                 &value,
                                 put_behaviour,
+                                ttl,
                                 scuba,
                                 self.inflight_ops_counter.clone(),
                             );
@@ -554,7 +558,14 @@ impl BlobstorePutOps for WalMultiplexedBlobstore {
     ) -> Result<OverwriteStatus> {
         let size = value.len();
         let (stats, result) = self
-            .put_impl(ctx, key.clone(), value, Some(put_behaviour), &self.scuba)
+            .put_impl(
+                ctx,
+                key.clone(),
+                value,
+                Some(put_behaviour),
+                None,
+                &self.scuba,
+            )
             .timed()
             .await;
         scuba::record_put(
@@ -577,7 +588,31 @@ impl BlobstorePutOps for WalMultiplexedBlobstore {
     ) -> Result<OverwriteStatus> {
         let size = value.len();
         let (stats, result) = self
-            .put_impl(ctx, key.clone(), value, None, &self.scuba)
+            .put_impl(ctx, key.clone(), value, None, None, &self.scuba)
+            .timed()
+            .await;
+        scuba::record_put(
+            ctx,
+            &mut self.scuba.multiplex_scuba.clone(),
+            &self.multiplex_id,
+            &key,
+            size,
+            stats,
+            &result,
+        );
+        result
+    }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        let size = value.len();
+        let (stats, result) = self
+            .put_impl(ctx, key.clone(), value, None, Some(ttl), &self.scuba)
             .timed()
             .await;
         scuba::record_put(
@@ -605,6 +640,7 @@ fn inner_multi_put(
     key: &str,
     value: &BlobstoreBytes,
     put_behaviour: Option<PutBehaviour>,
+    ttl: Option<Duration>,
     scuba: &Scuba,
     counter: Arc<AtomicU64>,
 ) -> FuturesUnordered<impl Future<Output = Result<OverwriteStatus, (BlobstoreId, Error)>>> {
@@ -625,7 +661,7 @@ fn inner_multi_put(
             async move {
                 counter.fetch_add(1, Ordering::Relaxed);
                 let result = bs
-                    .put(&ctx, key, value, put_behaviour, inner_blobstores_scuba)
+                    .put(&ctx, key, value, put_behaviour, ttl, inner_blobstores_scuba)
                     .await;
                 counter.fetch_sub(1, Ordering::Relaxed);
                 result
@@ -7,6 +7,7 @@
 
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use anyhow::Context;
@@ -218,4 +219,14 @@ impl BlobstorePutOps for WalScrubBlobstore {
     ) -> Result<OverwriteStatus> {
         self.inner.put_with_status(ctx, key, value).await
     }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        self.inner.put_with_ttl(ctx, key, value, ttl).await
+    }
 }
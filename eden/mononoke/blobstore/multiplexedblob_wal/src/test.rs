@@ -21,6 +21,7 @@ use blobstore_sync_queue::BlobstoreWal;
 use blobstore_sync_queue::BlobstoreWalEntry;
 use blobstore_sync_queue::SqlBlobstoreWal;
 use blobstore_test_utils::Tickable;
+use blobstore_test_utils::TtlSpy;
 use borrowed::borrowed;
 use bytes::Bytes;
 use context::CoreContext;
@@ -797,6 +798,49 @@ async fn test_timeout_on_request(fb: FacebookInit) -> Result<()> {
     Ok(())
 }
 
+#[fbinit::test]
+async fn test_put_with_ttl(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let (tickable_queue, wal_queue) = setup_queue();
+    let spies: Vec<_> = (0..3).map(|_| TtlSpy::new()).collect();
+    let blobstores: Vec<_> = spies
+        .iter()
+        .enumerate()
+        .map(|(id, spy)| {
+            let store = Arc::new(spy.clone()) as Arc<dyn BlobstorePutOps>;
+            (BlobstoreId::new(id as u64), store)
+        })
+        .collect();
+    let scuba = Scuba::new(
+        MononokeScubaSampleBuilder::with_discard(),
+        MononokeScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    )?;
+    let multiplex = WalMultiplexedBlobstore::new(
+        MultiplexId::new(1),
+        wal_queue,
+        blobstores,
+        vec![],
+        3,
+        None,
+        scuba,
+    )?;
+
+    let ttl = Duration::from_secs(300);
+    let mut put_fut = multiplex
+        .put_with_ttl(&ctx, "k".to_owned(), make_value("v"), ttl)
+        .boxed();
+    assert_pending(&mut put_fut).await;
+    // wal queue write succeeds, then every blobstore is written
+    tickable_queue.tick(None);
+    put_fut.await?;
+
+    for spy in &spies {
+        assert_eq!(spy.puts(), vec![("k".to_string(), Some(ttl))]);
+    }
+    Ok(())
+}
+
 async fn assert_pending<T: Debug>(fut: &mut (impl Future<Output = T> + Unpin)) {
     match futures::poll!(fut) {
         Poll::Pending => {}
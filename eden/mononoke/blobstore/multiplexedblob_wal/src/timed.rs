@@ -94,14 +94,17 @@ impl TimedStore {
         key: String,
         value: BlobstoreBytes,
         put_behaviour: Option<PutBehaviour>,
+        ttl: Option<Duration>,
         mut scuba: MononokeScubaSampleBuilder,
     ) -> Result<OverwriteStatus, (BlobstoreId, Error)> {
         let size = value.len();
-        let put_fut = if let Some(put_behaviour) = put_behaviour {
-            self.inner
-                .put_explicit(ctx, key.clone(), value, put_behaviour)
-        } else {
-            self.inner.put_with_status(ctx, key.clone(), value)
+        let put_fut = match (put_behaviour, ttl) {
+            (Some(put_behaviour), _) => {
+                self.inner
+                    .put_explicit(ctx, key.clone(), value, put_behaviour)
+            }
+            (None, Some(ttl)) => self.inner.put_with_ttl(ctx, key.clone(), value, ttl),
+            (None, None) => self.inner.put_with_status(ctx, key.clone(), value),
         };
 
         let pc = ctx.clone().fork_perf_counters();
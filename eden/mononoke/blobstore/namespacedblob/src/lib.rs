@@ -0,0 +1,392 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! A blobstore isolating the keys of a tenant of a shared blobstore.
+//!
+//! Keys are stored as `ns<namespace>.g<generation>.<key>`. Bumping the
+//! generation makes all the blobs of the namespace unreachable at once;
+//! removing them from the underlying blobstore is left to its garbage
+//! collection.
+
+use std::fmt;
+use std::fmt::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use blobstore::put_batch_mapped;
+use blobstore::Blobstore;
+use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreGetWithProvenance;
+use blobstore::BlobstoreIsPresent;
+use blobstore::BlobstorePutBatchResults;
+use blobstore::BlobstorePutOps;
+use blobstore::BlobstoreUnlinkOps;
+use blobstore::OverwriteStatus;
+use blobstore::PutBehaviour;
+use context::CoreContext;
+use mononoke_types::BlobstoreBytes;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum NamespaceError {
+    #[error("Invalid namespace {0:?}: it must be non-empty and not contain '.'")]
+    InvalidNamespace(String),
+    /// The key looks like the key of a `NamespacedBlob`, which suggests two
+    /// of them were stacked by mistake.
+    #[error("Key {0:?} already has a namespace prefix")]
+    AlreadyNamespaced(String),
+}
+
+/// The generation of a `NamespacedBlob`, shared by its clones. Changes apply
+/// to operations started afterwards.
+#[derive(Clone, Debug)]
+pub struct GenerationHandle(Arc<AtomicU64>);
+
+impl GenerationHandle {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Acquire)
+    }
+
+    pub fn set(&self, generation: u64) {
+        self.0.store(generation, Ordering::Release)
+    }
+
+    /// Switch to the next generation, and return it.
+    pub fn bump(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::AcqRel) + 1
+    }
+}
+
+/// Prefixes keys with a namespace and a generation. See the crate
+/// documentation.
+#[derive(Clone, Debug)]
+pub struct NamespacedBlob<B> {
+    blobstore: B,
+    namespace: Arc<str>,
+    generation: GenerationHandle,
+}
+
+impl<B: fmt::Display> fmt::Display for NamespacedBlob<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "NamespacedBlob<{}, g{}, {}>",
+            self.namespace,
+            self.generation.get(),
+            self.blobstore
+        )
+    }
+}
+
+impl<B> NamespacedBlob<B> {
+    pub fn new(blobstore: B, namespace: &str, generation: u64) -> Result<Self, NamespaceError> {
+        if namespace.is_empty() || namespace.contains('.') {
+            return Err(NamespaceError::InvalidNamespace(namespace.to_string()));
+        }
+        Ok(Self {
+            blobstore,
+            namespace: namespace.into(),
+            generation: GenerationHandle(Arc::new(AtomicU64::new(generation))),
+        })
+    }
+
+    pub fn as_inner(&self) -> &B {
+        &self.blobstore
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    pub fn generation(&self) -> GenerationHandle {
+        self.generation.clone()
+    }
+
+    /// Switch operations started from now on to the next generation, and
+    /// return it. The blobs of the previous generations become unreachable.
+    pub fn bump_generation(&self) -> u64 {
+        self.generation.bump()
+    }
+
+    /// The key of `key` in the underlying blobstore, at the current
+    /// generation.
+    pub fn raw_key(&self, key: &str) -> Result<String, NamespaceError> {
+        self.raw_key_at(key, self.generation.get())
+    }
+
+    fn raw_key_at(&self, key: &str, generation: u64) -> Result<String, NamespaceError> {
+        if is_namespaced(key) {
+            return Err(NamespaceError::AlreadyNamespaced(key.to_string()));
+        }
+        // "ns" + namespace + ".g" + generation (at most 20 digits) + "."
+        let mut raw_key = String::with_capacity(self.namespace.len() + key.len() + 25);
+        write!(raw_key, "ns{}.g{}.{}", self.namespace, generation, key)
+            .expect("writing to a String cannot fail");
+        Ok(raw_key)
+    }
+}
+
+/// Whether `key` starts with `ns<namespace>.g<generation>.`.
+fn is_namespaced(key: &str) -> bool {
+    let rest = match key.strip_prefix("ns") {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let rest = match rest.split_once('.') {
+        Some((namespace, rest)) if !namespace.is_empty() => rest,
+        _ => return false,
+    };
+    match rest.strip_prefix('g').and_then(|rest| rest.split_once('.')) {
+        Some((generation, _)) => {
+            !generation.is_empty() && generation.bytes().all(|b| b.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+#[async_trait]
+impl<B: Blobstore> Blobstore for NamespacedBlob<B> {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        self.blobstore.get(ctx, &self.raw_key(key)?).await
+    }
+
+    async fn get_with_provenance<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreGetWithProvenance> {
+        self.blobstore
+            .get_with_provenance(ctx, &self.raw_key(key)?)
+            .await
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        self.blobstore.put(ctx, self.raw_key(&key)?, value).await
+    }
+
+    async fn is_present<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreIsPresent> {
+        self.blobstore.is_present(ctx, &self.raw_key(key)?).await
+    }
+
+    async fn copy<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        old_key: &'a str,
+        new_key: String,
+    ) -> Result<()> {
+        // Both keys must be of the same generation, even if it is bumped
+        // concurrently.
+        let generation = self.generation.get();
+        let old_key = self.raw_key_at(old_key, generation)?;
+        let new_key = self.raw_key_at(&new_key, generation)?;
+        self.blobstore.copy(ctx, &old_key, new_key).await
+    }
+}
+
+#[async_trait]
+impl<B: BlobstorePutOps> BlobstorePutOps for NamespacedBlob<B> {
+    async fn put_explicit<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+    ) -> Result<OverwriteStatus> {
+        self.blobstore
+            .put_explicit(ctx, self.raw_key(&key)?, value, put_behaviour)
+            .await
+    }
+
+    async fn put_with_status<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<OverwriteStatus> {
+        self.blobstore
+            .put_with_status(ctx, self.raw_key(&key)?, value)
+            .await
+    }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        self.blobstore
+            .put_with_ttl(ctx, self.raw_key(&key)?, value, ttl)
+            .await
+    }
+
+    async fn put_batch<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        items: Vec<(String, BlobstoreBytes)>,
+    ) -> Result<BlobstorePutBatchResults> {
+        // The whole batch goes to the same generation.
+        let generation = self.generation.get();
+        put_batch_mapped(&self.blobstore, ctx, items, |key, value| {
+            Ok((self.raw_key_at(key, generation)?, value))
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl<B: BlobstoreUnlinkOps> BlobstoreUnlinkOps for NamespacedBlob<B> {
+    async fn unlink<'a>(&'a self, ctx: &'a CoreContext, key: &'a str) -> Result<()> {
+        self.blobstore.unlink(ctx, &self.raw_key(key)?).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use borrowed::borrowed;
+    use fbinit::FacebookInit;
+    use memblob::Memblob;
+
+    use super::*;
+
+    fn bytes(value: &[u8]) -> BlobstoreBytes {
+        BlobstoreBytes::from_bytes(value.to_vec())
+    }
+
+    async fn value<B: Blobstore>(
+        ctx: &CoreContext,
+        blob: &B,
+        key: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        let data = blob.get(ctx, key).await?;
+        Ok(data.map(|d| d.into_raw_bytes().to_vec()))
+    }
+
+    #[fbinit::test]
+    async fn test_isolation(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let inner = Memblob::default();
+        let a = NamespacedBlob::new(inner.clone(), "a", 0)?;
+        let b = NamespacedBlob::new(inner.clone(), "b", 0)?;
+
+        a.put(ctx, "key".to_string(), bytes(b"a")).await?;
+        assert_eq!(value(ctx, &a, "key").await?, Some(b"a".to_vec()));
+        assert_eq!(value(ctx, &b, "key").await?, None);
+        assert!(!b.is_present(ctx, "key").await?.fail_if_unsure()?);
+
+        let status = b
+            .put_with_status(ctx, "key".to_string(), bytes(b"b"))
+            .await?;
+        assert_eq!(status, OverwriteStatus::New);
+        assert_eq!(value(ctx, &a, "key").await?, Some(b"a".to_vec()));
+        assert_eq!(value(ctx, &b, "key").await?, Some(b"b".to_vec()));
+
+        assert_eq!(a.raw_key("key")?, "nsa.g0.key");
+        assert_eq!(value(ctx, &inner, "nsb.g0.key").await?, Some(b"b".to_vec()));
+        assert_eq!(a.to_string(), "NamespacedBlob<a, g0, Memblob>");
+
+        assert_eq!(
+            NamespacedBlob::new(inner, "a.b", 0).unwrap_err(),
+            NamespaceError::InvalidNamespace("a.b".to_string())
+        );
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_bump_generation(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = NamespacedBlob::new(Memblob::default(), "ns", 7)?;
+        let clone = blob.clone();
+
+        blob.put(ctx, "key".to_string(), bytes(b"old")).await?;
+        assert_eq!(blob.bump_generation(), 8);
+        assert_eq!(clone.generation().get(), 8);
+        assert_eq!(value(ctx, &clone, "key").await?, None);
+        assert_eq!(clone.raw_key("key")?, "nsns.g8.key");
+
+        blob.put(ctx, "key".to_string(), bytes(b"new")).await?;
+        assert_eq!(value(ctx, &blob, "key").await?, Some(b"new".to_vec()));
+        assert_eq!(
+            value(ctx, blob.as_inner(), "nsns.g7.key").await?,
+            Some(b"old".to_vec())
+        );
+
+        blob.generation().set(7);
+        assert_eq!(value(ctx, &blob, "key").await?, Some(b"old".to_vec()));
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_double_wrap(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let inner = NamespacedBlob::new(Memblob::default(), "inner", 0)?;
+        let outer = NamespacedBlob::new(inner, "outer", 0)?;
+
+        let err = outer
+            .put(ctx, "key".to_string(), bytes(b"value"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<NamespaceError>(),
+            Some(&NamespaceError::AlreadyNamespaced(
+                "nsouter.g0.key".to_string()
+            ))
+        );
+        assert!(outer.get(ctx, "key").await.is_err());
+
+        // Keys that only look alike are fine.
+        for key in [
+            "nsfoo",
+            "ns.g1.key",
+            "nsfoo.g.key",
+            "nsfoo.gx.key",
+            "nsfoo.g1",
+        ] {
+            assert!(!is_namespaced(key), "{}", key);
+        }
+        assert!(is_namespaced("nsfoo.g12.key"));
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_unlink(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let inner = Memblob::default();
+        let blob = NamespacedBlob::new(inner.clone(), "ns", 0)?;
+
+        inner.put(ctx, "key".to_string(), bytes(b"raw")).await?;
+        blob.put(ctx, "key".to_string(), bytes(b"value")).await?;
+        blob.copy(ctx, "key", "copy".to_string()).await?;
+        blob.unlink(ctx, "key").await?;
+
+        assert_eq!(value(ctx, &blob, "key").await?, None);
+        assert_eq!(value(ctx, &blob, "copy").await?, Some(b"value".to_vec()));
+        assert_eq!(value(ctx, &inner, "key").await?, Some(b"raw".to_vec()));
+        Ok(())
+    }
+}
@@ -5,16 +5,22 @@
  * GNU General Public License version 2.
  */
 
+use std::time::Duration;
+
 use anyhow::Context;
 use anyhow::Result;
 use async_trait::async_trait;
+use blobstore::put_batch_mapped;
 use blobstore::Blobstore;
 use blobstore::BlobstoreEnumerationData;
 use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreGetManyData;
+use blobstore::BlobstoreGetWithProvenance;
 use blobstore::BlobstoreIsPresent;
 use blobstore::BlobstoreKeyParam;
 use blobstore::BlobstoreKeySource;
 use blobstore::BlobstoreMetadata;
+use blobstore::BlobstorePutBatchResults;
 use blobstore::BlobstorePutOps;
 use blobstore::BlobstoreUnlinkOps;
 use blobstore::OverwriteStatus;
@@ -72,23 +78,51 @@ impl<T: Blobstore + BlobstorePutOps> Blobstore for PackBlob<T> {
         ctx: &'a CoreContext,
         key: &'a str,
     ) -> Result<Option<BlobstoreGetData>> {
-        let inner_get_data = {
+        Ok(self.get_with_provenance(ctx, key).await?.data)
+    }
+
+    async fn get_with_provenance<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreGetWithProvenance> {
+        let inner_get = {
             let inner_key = &[key, ENVELOPE_SUFFIX].concat();
             self.inner
-                .get(ctx, inner_key)
+                .get_with_provenance(ctx, inner_key)
                 .await
                 .with_context(|| format!("While getting inner data for {:?}", key))?
         };
-        let inner_get_data = match inner_get_data {
-            Some(inner_get_data) => inner_get_data,
-            None => return Ok(None),
-        };
+        Ok(BlobstoreGetWithProvenance {
+            data: decode_get_data(key, inner_get.data)?,
+            provenance: inner_get.provenance,
+        })
+    }
 
-        let ctime = inner_get_data.as_meta().ctime();
-        let envelope: PackEnvelope = inner_get_data.into_bytes().try_into()?;
-        let (decoded, sizing) = envelope.decode(key)?;
-        let meta = BlobstoreMetadata::new(ctime, Some(sizing));
-        Ok(Some(BlobstoreGetData::new(meta, decoded)))
+    async fn get_many<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        keys: &'a [&'a str],
+    ) -> Result<BlobstoreGetManyData> {
+        let owned_keys: Vec<String> = keys
+            .iter()
+            .map(|key| [*key, ENVELOPE_SUFFIX].concat())
+            .collect();
+        let inner_keys: Vec<&str> = owned_keys.iter().map(String::as_str).collect();
+        let inner_results = self.inner.get_many(ctx, &inner_keys).await?;
+        Ok(inner_results
+            .into_iter()
+            .map(|(inner_key, result)| {
+                let key = inner_key
+                    .strip_suffix(ENVELOPE_SUFFIX)
+                    .unwrap_or(&inner_key)
+                    .to_string();
+                let result = result
+                    .with_context(|| format!("While getting inner data for {:?}", key))
+                    .and_then(|inner_get_data| decode_get_data(&key, inner_get_data));
+                (key, result)
+            })
+            .collect())
     }
 
     async fn is_present<'a>(
@@ -112,6 +146,23 @@ impl<T: Blobstore + BlobstorePutOps> Blobstore for PackBlob<T> {
     }
 }
 
+/// Unpack the data stored by `PackBlob` under `key`.
+fn decode_get_data(
+    key: &str,
+    inner_get_data: Option<BlobstoreGetData>,
+) -> Result<Option<BlobstoreGetData>> {
+    let inner_get_data = match inner_get_data {
+        Some(inner_get_data) => inner_get_data,
+        None => return Ok(None),
+    };
+
+    let ctime = inner_get_data.as_meta().ctime();
+    let envelope: PackEnvelope = inner_get_data.into_bytes().try_into()?;
+    let (decoded, sizing) = envelope.decode(key)?;
+    let meta = BlobstoreMetadata::new(ctime, Some(sizing));
+    Ok(Some(BlobstoreGetData::new(meta, decoded)))
+}
+
 impl<T: BlobstorePutOps> PackBlob<T> {
     async fn put_impl<'a>(
         &'a self,
@@ -119,25 +170,31 @@ impl<T: BlobstorePutOps> PackBlob<T> {
         mut key: String,
         value: BlobstoreBytes,
         put_behaviour: Option<PutBehaviour>,
+        ttl: Option<Duration>,
     ) -> Result<OverwriteStatus> {
         key.push_str(ENVELOPE_SUFFIX);
+        let bytes = self.pack_single(value)?;
 
-        let bytes = match self.put_format {
+        // pass through the put after wrapping
+        match (put_behaviour, ttl) {
+            (Some(put_behaviour), _) => {
+                self.inner
+                    .put_explicit(ctx, key, bytes, put_behaviour)
+                    .await
+            }
+            (None, Some(ttl)) => self.inner.put_with_ttl(ctx, key, bytes, ttl).await,
+            (None, None) => self.inner.put_with_status(ctx, key, bytes).await,
+        }
+    }
+
+    fn pack_single(&self, value: BlobstoreBytes) -> Result<BlobstoreBytes> {
+        let single = match self.put_format {
             PackFormat::ZstdIndividual(zstd_level) => {
                 pack::SingleCompressed::new(zstd_level, value)?
             }
             PackFormat::Raw => pack::SingleCompressed::new_uncompressed(value),
-        }
-        .into_blobstore_bytes();
-
-        // pass through the put after wrapping
-        if let Some(put_behaviour) = put_behaviour {
-            self.inner
-                .put_explicit(ctx, key, bytes, put_behaviour)
-                .await
-        } else {
-            self.inner.put_with_status(ctx, key, bytes).await
-        }
+        };
+        Ok(single.into_blobstore_bytes())
     }
 }
 
@@ -150,7 +207,8 @@ impl<B: BlobstorePutOps> BlobstorePutOps for PackBlob<B> {
         value: BlobstoreBytes,
         put_behaviour: PutBehaviour,
     ) -> Result<OverwriteStatus> {
-        self.put_impl(ctx, key, value, Some(put_behaviour)).await
+        self.put_impl(ctx, key, value, Some(put_behaviour), None)
+            .await
     }
 
     async fn put_with_status<'a>(
@@ -159,7 +217,28 @@ impl<B: BlobstorePutOps> BlobstorePutOps for PackBlob<B> {
         key: String,
         value: BlobstoreBytes,
     ) -> Result<OverwriteStatus> {
-        self.put_impl(ctx, key, value, None).await
+        self.put_impl(ctx, key, value, None, None).await
+    }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        self.put_impl(ctx, key, value, None, Some(ttl)).await
+    }
+
+    async fn put_batch<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        items: Vec<(String, BlobstoreBytes)>,
+    ) -> Result<BlobstorePutBatchResults> {
+        put_batch_mapped(&self.inner, ctx, items, |key, value| {
+            Ok(([key, ENVELOPE_SUFFIX].concat(), self.pack_single(value)?))
+        })
+        .await
     }
 }
 
@@ -256,6 +335,7 @@ impl<T: Blobstore + BlobstoreUnlinkOps> PackBlob<T> {
 mod tests {
     use std::sync::Arc;
 
+    use blobstore_test_utils::TtlSpy;
     use borrowed::borrowed;
     use bytes::Bytes;
     use fbinit::FacebookInit;
@@ -279,6 +359,33 @@ mod tests {
         Ok(())
     }
 
+    #[fbinit::test]
+    async fn put_with_ttl_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let spy = TtlSpy::new();
+        let packblob = PackBlob::new(spy.clone(), PackFormat::Raw);
+
+        let ttl = Duration::from_secs(300);
+        let value = BlobstoreBytes::from_bytes(Bytes::copy_from_slice(b"appleveldata"));
+        packblob
+            .put_with_ttl(ctx, "repo0000.ephemeral".to_string(), value.clone(), ttl)
+            .await?;
+        packblob
+            .put(ctx, "repo0000.permanent".to_string(), value.clone())
+            .await?;
+        assert_eq!(
+            spy.puts(),
+            vec![
+                (format!("repo0000.ephemeral{}", ENVELOPE_SUFFIX), Some(ttl)),
+                (format!("repo0000.permanent{}", ENVELOPE_SUFFIX), None),
+            ]
+        );
+        let fetched = packblob.get(ctx, "repo0000.ephemeral").await?;
+        assert_eq!(fetched.map(|d| d.into_bytes()), Some(value));
+        Ok(())
+    }
+
     #[fbinit::test]
     async fn compressible_roundtrip_test(fb: FacebookInit) -> Result<()> {
         let ctx = CoreContext::test_mock(fb);
@@ -0,0 +1,524 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use blobstore::Blobstore;
+use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreIsPresent;
+use blobstore::BlobstorePutOps;
+use blobstore::OverwriteStatus;
+use blobstore::PutBehaviour;
+use context::CoreContext;
+use linked_hash_map::LinkedHashMap;
+use mononoke_types::BlobstoreBytes;
+use tokio::sync::Semaphore;
+
+/// Maps an accessed key to keys that are likely to be accessed soon.
+pub type Predictor = Arc<dyn Fn(&str) -> Vec<String> + Send + Sync>;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PrefetchOptions {
+    /// Maximum number of prefetched values kept in memory.
+    pub cache_capacity: usize,
+    /// Maximum number of predicted keys prefetched per get.
+    pub fan_out: usize,
+    /// Maximum number of prefetches running at the same time.
+    pub concurrency: usize,
+}
+
+impl Default for PrefetchOptions {
+    fn default() -> Self {
+        Self {
+            cache_capacity: 1000,
+            fan_out: 4,
+            concurrency: 8,
+        }
+    }
+}
+
+/// Counters describing how well prefetching is working.
+#[derive(Debug, Default)]
+pub struct PrefetchCounters {
+    /// Gets served from prefetched values.
+    pub hits: AtomicU64,
+    /// Gets that had to go to the inner blobstore.
+    pub misses: AtomicU64,
+    /// Prefetches started.
+    pub prefetches: AtomicU64,
+    /// Prefetches that failed. These errors are not returned to anyone.
+    pub prefetch_errors: AtomicU64,
+}
+
+#[derive(Default)]
+struct State {
+    /// Recently prefetched values, least recently used first.
+    cache: LinkedHashMap<String, BlobstoreGetData>,
+    /// Keys being prefetched, with the generation of their prefetch. Writing
+    /// a key removes it, so that a prefetch of the new value can start, and
+    /// the result of a prefetch is only stored if its generation is still
+    /// here: an older prefetch must not overwrite a newer one's result.
+    in_flight: HashMap<String, u64>,
+    next_generation: u64,
+}
+
+/// A layer over an existing blobstore that, after each get, fetches the keys
+/// the `predictor` expects to be needed next in the background, and serves
+/// later gets for them from memory.
+pub struct PrefetchingBlob<B> {
+    inner: Arc<B>,
+    predictor: Predictor,
+    options: PrefetchOptions,
+    state: Arc<Mutex<State>>,
+    semaphore: Arc<Semaphore>,
+    counters: Arc<PrefetchCounters>,
+}
+
+impl<B: std::fmt::Display> std::fmt::Display for PrefetchingBlob<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PrefetchingBlob<{}>", &self.inner)
+    }
+}
+
+impl<B: std::fmt::Debug> std::fmt::Debug for PrefetchingBlob<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PrefetchingBlob")
+            .field("inner", &self.inner)
+            .field("options", &self.options)
+            .field("counters", &self.counters)
+            .finish()
+    }
+}
+
+impl<B> PrefetchingBlob<B> {
+    pub fn new(inner: B, predictor: Predictor, options: PrefetchOptions) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            predictor,
+            options,
+            state: Default::default(),
+            semaphore: Arc::new(Semaphore::new(options.concurrency.max(1))),
+            counters: Default::default(),
+        }
+    }
+
+    pub fn counters(&self) -> &PrefetchCounters {
+        &self.counters
+    }
+
+    fn get_cached(&self, key: &str) -> Option<BlobstoreGetData> {
+        let mut state = self.state.lock().expect("lock poisoned");
+        state.cache.get_refresh(key).cloned()
+    }
+
+    fn invalidate(&self, key: &str) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        state.cache.remove(key);
+        state.in_flight.remove(key);
+    }
+}
+
+impl<B: Blobstore + 'static> PrefetchingBlob<B> {
+    /// Start background gets for the keys predicted to follow `key`.
+    fn prefetch(&self, ctx: &CoreContext, key: &str) {
+        let predicted = (self.predictor)(key);
+        let mut to_fetch = Vec::new();
+        {
+            let mut state = self.state.lock().expect("lock poisoned");
+            for predicted_key in predicted {
+                if to_fetch.len() >= self.options.fan_out {
+                    break;
+                }
+                if predicted_key == key
+                    || state.cache.contains_key(&predicted_key)
+                    || state.in_flight.contains_key(&predicted_key)
+                {
+                    continue;
+                }
+                let generation = state.next_generation;
+                state.next_generation += 1;
+                state.in_flight.insert(predicted_key.clone(), generation);
+                to_fetch.push((predicted_key, generation));
+            }
+        }
+
+        for (predicted_key, generation) in to_fetch {
+            self.counters.prefetches.fetch_add(1, Ordering::Relaxed);
+            let ctx = ctx.clone();
+            let inner = self.inner.clone();
+            let state = self.state.clone();
+            let semaphore = self.semaphore.clone();
+            let counters = self.counters.clone();
+            let capacity = self.options.cache_capacity;
+            tokio::spawn(async move {
+                let result = match semaphore.acquire_owned().await {
+                    Ok(_permit) => inner.get(&ctx, &predicted_key).await,
+                    Err(e) => Err(e.into()),
+                };
+                let mut state = state.lock().expect("lock poisoned");
+                let valid = state.in_flight.get(&predicted_key) == Some(&generation);
+                if valid {
+                    state.in_flight.remove(&predicted_key);
+                }
+                match result {
+                    Ok(Some(value)) if valid && capacity > 0 => {
+                        state.cache.insert(predicted_key, value);
+                        while state.cache.len() > capacity {
+                            state.cache.pop_front();
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(_) => {
+                        counters.prefetch_errors.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl<B: BlobstorePutOps + 'static> Blobstore for PrefetchingBlob<B> {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        let result = match self.get_cached(key) {
+            Some(value) => {
+                self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                Some(value)
+            }
+            None => {
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                self.inner.get(ctx, key).await?
+            }
+        };
+        self.prefetch(ctx, key);
+        Ok(result)
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        self.put_impl(ctx, key, value, None).await?;
+        Ok(())
+    }
+
+    async fn is_present<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreIsPresent> {
+        if self.get_cached(key).is_some() {
+            return Ok(BlobstoreIsPresent::Present);
+        }
+        self.inner.is_present(ctx, key).await
+    }
+}
+
+impl<B: BlobstorePutOps> PrefetchingBlob<B> {
+    async fn put_impl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: Option<PutBehaviour>,
+    ) -> Result<OverwriteStatus> {
+        self.invalidate(&key);
+        let result = if let Some(put_behaviour) = put_behaviour {
+            self.inner
+                .put_explicit(ctx, key.clone(), value, put_behaviour)
+                .await
+        } else {
+            self.inner.put_with_status(ctx, key.clone(), value).await
+        };
+        // A prefetch may have completed while the put was in progress.
+        self.invalidate(&key);
+        result
+    }
+}
+
+#[async_trait]
+impl<B: BlobstorePutOps + 'static> BlobstorePutOps for PrefetchingBlob<B> {
+    async fn put_explicit<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+    ) -> Result<OverwriteStatus> {
+        self.put_impl(ctx, key, value, Some(put_behaviour)).await
+    }
+
+    async fn put_with_status<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<OverwriteStatus> {
+        self.put_impl(ctx, key, value, None).await
+    }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        self.invalidate(&key);
+        let result = self.inner.put_with_ttl(ctx, key.clone(), value, ttl).await;
+        // A prefetch may have completed while the put was in progress.
+        self.invalidate(&key);
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicUsize;
+
+    use anyhow::anyhow;
+    use borrowed::borrowed;
+    use fbinit::FacebookInit;
+    use memblob::Memblob;
+
+    use super::*;
+
+    /// Memblob that sleeps for `delay_ms` after reading each get, counts
+    /// gets and fails gets for keys containing "broken".
+    #[derive(Debug)]
+    struct SlowBlob {
+        inner: Memblob,
+        delay_ms: AtomicU64,
+        gets: AtomicUsize,
+    }
+
+    impl std::fmt::Display for SlowBlob {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "SlowBlob")
+        }
+    }
+
+    #[async_trait]
+    impl Blobstore for SlowBlob {
+        async fn get<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: &'a str,
+        ) -> Result<Option<BlobstoreGetData>> {
+            self.gets.fetch_add(1, Ordering::SeqCst);
+            let result = self.inner.get(ctx, key).await;
+            let delay = Duration::from_millis(self.delay_ms.load(Ordering::SeqCst));
+            tokio::time::sleep(delay).await;
+            if key.contains("broken") {
+                return Err(anyhow!("broken key {}", key));
+            }
+            result
+        }
+
+        async fn put<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: String,
+            value: BlobstoreBytes,
+        ) -> Result<()> {
+            self.inner.put(ctx, key, value).await
+        }
+    }
+
+    #[async_trait]
+    impl BlobstorePutOps for SlowBlob {
+        async fn put_explicit<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: String,
+            value: BlobstoreBytes,
+            put_behaviour: PutBehaviour,
+        ) -> Result<OverwriteStatus> {
+            self.inner
+                .put_explicit(ctx, key, value, put_behaviour)
+                .await
+        }
+
+        async fn put_with_status<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: String,
+            value: BlobstoreBytes,
+        ) -> Result<OverwriteStatus> {
+            self.inner.put_with_status(ctx, key, value).await
+        }
+    }
+
+    /// Predicts "<prefix>.<n>" is followed by "<prefix>.<n + 1>".
+    fn next_key_predictor() -> Predictor {
+        Arc::new(|key: &str| {
+            key.rsplit_once('.')
+                .and_then(|(prefix, n)| Some(format!("{}.{}", prefix, n.parse::<u64>().ok()? + 1)))
+                .into_iter()
+                .collect()
+        })
+    }
+
+    async fn slow_blob(ctx: &CoreContext) -> Result<SlowBlob> {
+        let inner = Memblob::default();
+        for i in 0..5 {
+            inner
+                .put(
+                    ctx,
+                    format!("key.{}", i),
+                    BlobstoreBytes::from_bytes(vec![i]),
+                )
+                .await?;
+        }
+        Ok(SlowBlob {
+            inner,
+            delay_ms: AtomicU64::new(10),
+            gets: AtomicUsize::new(0),
+        })
+    }
+
+    async fn setup(ctx: &CoreContext) -> Result<PrefetchingBlob<SlowBlob>> {
+        Ok(PrefetchingBlob::new(
+            slow_blob(ctx).await?,
+            next_key_predictor(),
+            PrefetchOptions::default(),
+        ))
+    }
+
+    async fn wait_for_prefetches<B>(blob: &PrefetchingBlob<B>) {
+        while !blob.state.lock().unwrap().in_flight.is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    fn value(data: Option<BlobstoreGetData>) -> Option<Vec<u8>> {
+        data.map(|d| d.into_raw_bytes().to_vec())
+    }
+
+    #[fbinit::test]
+    async fn test_predicted_get_served_from_cache(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = setup(ctx).await?;
+
+        assert_eq!(value(blob.get(ctx, "key.0").await?), Some(vec![0]));
+        wait_for_prefetches(&blob).await;
+        // key.0, and the prefetch of key.1.
+        assert_eq!(blob.inner.gets.load(Ordering::SeqCst), 2);
+
+        assert_eq!(value(blob.get(ctx, "key.1").await?), Some(vec![1]));
+        wait_for_prefetches(&blob).await;
+        // Only the prefetch of key.2 went to the inner store.
+        assert_eq!(blob.inner.gets.load(Ordering::SeqCst), 3);
+        assert_eq!(blob.counters().hits.load(Ordering::SeqCst), 1);
+        assert_eq!(blob.counters().misses.load(Ordering::SeqCst), 1);
+        assert_eq!(blob.counters().prefetches.load(Ordering::SeqCst), 2);
+
+        // Repeated gets don't refetch what is already cached.
+        assert_eq!(value(blob.get(ctx, "key.1").await?), Some(vec![1]));
+        wait_for_prefetches(&blob).await;
+        assert_eq!(blob.inner.gets.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_put_invalidates(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = setup(ctx).await?;
+
+        blob.get(ctx, "key.0").await?;
+        wait_for_prefetches(&blob).await;
+        blob.put(
+            ctx,
+            "key.1".to_string(),
+            BlobstoreBytes::from_bytes(vec![42]),
+        )
+        .await?;
+        assert_eq!(value(blob.get(ctx, "key.1").await?), Some(vec![42]));
+        assert_eq!(blob.counters().hits.load(Ordering::SeqCst), 0);
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_stale_prefetch_discarded(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = setup(ctx).await?;
+
+        // The prefetch of key.1 reads the old value, then takes a while.
+        blob.inner.delay_ms.store(100, Ordering::SeqCst);
+        blob.get(ctx, "key.0").await?;
+        blob.inner.delay_ms.store(0, Ordering::SeqCst);
+        blob.put(
+            ctx,
+            "key.1".to_string(),
+            BlobstoreBytes::from_bytes(vec![42]),
+        )
+        .await?;
+        // A new prefetch of key.1 completes first.
+        blob.prefetch(ctx, "key.0");
+        wait_for_prefetches(&blob).await;
+        // The old prefetch completes without overwriting it.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(blob.counters().prefetches.load(Ordering::SeqCst), 2);
+        assert_eq!(value(blob.get(ctx, "key.1").await?), Some(vec![42]));
+        assert_eq!(blob.counters().hits.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_prefetch_errors_are_swallowed(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = PrefetchingBlob::new(
+            slow_blob(ctx).await?,
+            Arc::new(|_: &str| vec!["broken.1".to_string(), "missing.1".to_string()]),
+            PrefetchOptions::default(),
+        );
+
+        assert_eq!(value(blob.get(ctx, "key.0").await?), Some(vec![0]));
+        wait_for_prefetches(&blob).await;
+        assert_eq!(blob.counters().prefetch_errors.load(Ordering::SeqCst), 1);
+        assert!(blob.state.lock().unwrap().cache.is_empty());
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_capacity_and_fan_out(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = PrefetchingBlob::new(
+            slow_blob(ctx).await?,
+            Arc::new(|_: &str| (1..5).map(|i| format!("key.{}", i)).collect()),
+            PrefetchOptions {
+                cache_capacity: 2,
+                fan_out: 3,
+                concurrency: 1,
+            },
+        );
+
+        blob.get(ctx, "key.0").await?;
+        wait_for_prefetches(&blob).await;
+        assert_eq!(blob.counters().prefetches.load(Ordering::SeqCst), 3);
+        assert_eq!(blob.state.lock().unwrap().cache.len(), 2);
+        Ok(())
+    }
+}
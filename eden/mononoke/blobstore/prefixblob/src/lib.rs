@@ -5,15 +5,20 @@
  * GNU General Public License version 2.
  */
 
+use std::time::Duration;
+
 use anyhow::Result;
 use async_trait::async_trait;
+use blobstore::put_batch_mapped;
 use blobstore::Blobstore;
 use blobstore::BlobstoreEnumerationData;
 use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreGetWithProvenance;
 use blobstore::BlobstoreIsPresent;
 use blobstore::BlobstoreKeyParam;
 use blobstore::BlobstoreKeyRange;
 use blobstore::BlobstoreKeySource;
+use blobstore::BlobstorePutBatchResults;
 use blobstore::BlobstorePutOps;
 use blobstore::BlobstoreUnlinkOps;
 use blobstore::OverwriteStatus;
@@ -78,6 +83,16 @@ impl<T: Blobstore> Blobstore for PrefixBlobstore<T> {
         self.blobstore.get(ctx, &self.prepend(key)).await
     }
 
+    async fn get_with_provenance<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreGetWithProvenance> {
+        self.blobstore
+            .get_with_provenance(ctx, &self.prepend(key))
+            .await
+    }
+
     #[inline]
     async fn put<'a>(
         &'a self,
@@ -133,6 +148,28 @@ impl<T: BlobstorePutOps> BlobstorePutOps for PrefixBlobstore<T> {
             .put_with_status(ctx, self.prepend(key), value)
             .await
     }
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        self.blobstore
+            .put_with_ttl(ctx, self.prepend(key), value, ttl)
+            .await
+    }
+
+    async fn put_batch<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        items: Vec<(String, BlobstoreBytes)>,
+    ) -> Result<BlobstorePutBatchResults> {
+        put_batch_mapped(&self.blobstore, ctx, items, |key, value| {
+            Ok((self.prepend(key), value))
+        })
+        .await
+    }
 }
 
 #[async_trait]
@@ -0,0 +1,587 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Error;
+use anyhow::Result;
+use async_trait::async_trait;
+use blobstore::Blobstore;
+use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreIsPresent;
+use blobstore::BlobstorePutOps;
+use blobstore::BlobstoreUnlinkOps;
+use blobstore::OverwriteStatus;
+use blobstore::PutBehaviour;
+use context::CoreContext;
+use integrityblob::HashMismatch;
+use mononoke_types::BlobstoreBytes;
+use slog::warn;
+use thiserror::Error;
+
+/// Returns whether a get error means the stored value is corrupt.
+pub type CorruptionClassifier = Arc<dyn Fn(&Error) -> bool + Send + Sync>;
+
+/// Whether `error` is a corruption detected by `IntegrityBlob` or
+/// `ChunkingBlob`.
+pub fn is_known_corruption(error: &Error) -> bool {
+    error.chain().any(|e| {
+        e.is::<HashMismatch>()
+            || matches!(
+                e.downcast_ref::<chunkingblob::ErrorKind>(),
+                Some(
+                    chunkingblob::ErrorKind::ChecksumMismatch { .. }
+                        | chunkingblob::ErrorKind::ChunkSizeMismatch { .. }
+                )
+            )
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct QuarantineOptions {
+    /// Consecutive corrupt gets of a key before it is quarantined.
+    pub threshold: u32,
+    /// How long a key stays quarantined.
+    pub duration: Duration,
+    /// Failures older than this are forgotten, so occasional failures never
+    /// add up to a quarantine.
+    pub decay: Duration,
+    /// Keys with failures tracked at once. When full, the key whose last
+    /// failure is the oldest is forgotten.
+    pub max_tracked_keys: usize,
+}
+
+impl Default for QuarantineOptions {
+    fn default() -> Self {
+        Self {
+            threshold: 3,
+            duration: Duration::from_secs(600),
+            decay: Duration::from_secs(600),
+            max_tracked_keys: 10_000,
+        }
+    }
+}
+
+/// Returned by gets of a quarantined key, without reaching the inner
+/// blobstore. Also describes quarantined keys in `quarantined_keys`.
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("Blob {key} is quarantined for {remaining:?} after {failures} corrupt reads")]
+pub struct Quarantined {
+    pub key: String,
+    pub failures: u32,
+    pub remaining: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct QuarantineCounters {
+    /// Gets that failed with a corruption error from the inner blobstore.
+    pub corruptions: AtomicU64,
+    /// Keys that were quarantined.
+    pub quarantines: AtomicU64,
+    /// Gets that failed with `Quarantined`.
+    pub rejected: AtomicU64,
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+struct Quarantine {
+    failures: u32,
+    until: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    failures: HashMap<String, Failures>,
+    quarantined: HashMap<String, Quarantine>,
+}
+
+/// A layer over an existing blobstore that stops reading keys whose values
+/// repeatedly fail to decode or verify. After `threshold` consecutive
+/// corrupt gets, gets of a key fail with `Quarantined` for `duration`, so
+/// readers retrying a corrupt blob do not keep loading the backend.
+///
+/// A successful get resets the failures of a key, and a successful put or
+/// unlink lifts its quarantine, as the new value may be good.
+pub struct QuarantineBlob<B> {
+    inner: B,
+    options: QuarantineOptions,
+    classifier: Option<CorruptionClassifier>,
+    state: Mutex<State>,
+    counters: Arc<QuarantineCounters>,
+}
+
+impl<B: fmt::Display> fmt::Display for QuarantineBlob<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QuarantineBlob<{}>", &self.inner)
+    }
+}
+
+impl<B: fmt::Debug> fmt::Debug for QuarantineBlob<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuarantineBlob")
+            .field("inner", &self.inner)
+            .field("options", &self.options)
+            .field("counters", &self.counters)
+            .finish()
+    }
+}
+
+impl<B> QuarantineBlob<B> {
+    /// Count errors accepted by `is_known_corruption` as corrupt gets.
+    pub fn new(inner: B, options: QuarantineOptions) -> Self {
+        Self {
+            inner,
+            options,
+            classifier: None,
+            state: Default::default(),
+            counters: Default::default(),
+        }
+    }
+
+    /// Also count errors accepted by `classifier` as corrupt gets.
+    pub fn with_classifier(mut self, classifier: CorruptionClassifier) -> Self {
+        self.classifier = Some(classifier);
+        self
+    }
+
+    pub fn counters(&self) -> &QuarantineCounters {
+        &self.counters
+    }
+
+    /// Lift the quarantine of `key`, and forget its failures. Returns
+    /// whether it was quarantined.
+    pub fn unquarantine(&self, key: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.failures.remove(key);
+        state.quarantined.remove(key).is_some()
+    }
+
+    /// The keys currently quarantined, sorted by key.
+    pub fn quarantined_keys(&self) -> Vec<Quarantined> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.quarantined.retain(|_, q| q.until > now);
+        let mut keys: Vec<_> = state
+            .quarantined
+            .iter()
+            .map(|(key, q)| Quarantined {
+                key: key.clone(),
+                failures: q.failures,
+                remaining: q.until - now,
+            })
+            .collect();
+        keys.sort_by(|a, b| a.key.cmp(&b.key));
+        keys
+    }
+
+    fn is_corruption(&self, error: &Error) -> bool {
+        is_known_corruption(error) || self.classifier.as_ref().map_or(false, |c| c(error))
+    }
+
+    fn check(&self, key: &str) -> Result<(), Quarantined> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        match state.quarantined.get(key) {
+            Some(q) if q.until > now => Err(Quarantined {
+                key: key.to_string(),
+                failures: q.failures,
+                remaining: q.until - now,
+            }),
+            Some(_) => {
+                state.quarantined.remove(key);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Record a corrupt get of `key`. Returns whether it is now quarantined.
+    fn record_failure(&self, key: &str) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let decay = self.options.decay;
+        if !state.failures.contains_key(key)
+            && state.failures.len() >= self.options.max_tracked_keys
+        {
+            state.failures.retain(|_, f| now - f.last < decay);
+            if state.failures.len() >= self.options.max_tracked_keys {
+                let oldest = state
+                    .failures
+                    .iter()
+                    .min_by_key(|(_, f)| f.last)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    state.failures.remove(&oldest);
+                }
+            }
+        }
+
+        let failures = state.failures.entry(key.to_string()).or_insert(Failures {
+            count: 0,
+            last: now,
+        });
+        if now - failures.last >= decay {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = now;
+        let count = failures.count;
+        if count < self.options.threshold {
+            return false;
+        }
+        state.failures.remove(key);
+        state.quarantined.insert(
+            key.to_string(),
+            Quarantine {
+                failures: count,
+                until: now + self.options.duration,
+            },
+        );
+        true
+    }
+
+    fn clear(&self, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.failures.remove(key);
+        state.quarantined.remove(key);
+    }
+
+    fn reset_failures(&self, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.failures.remove(key);
+    }
+
+    fn after_put<T>(&self, key: &str, result: Result<T>) -> Result<T> {
+        if result.is_ok() {
+            self.clear(key);
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl<B: Blobstore> Blobstore for QuarantineBlob<B> {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        if let Err(e) = self.check(key) {
+            self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(e.into());
+        }
+        match self.inner.get(ctx, key).await {
+            Ok(value) => {
+                self.reset_failures(key);
+                Ok(value)
+            }
+            Err(e) => {
+                if self.is_corruption(&e) {
+                    self.counters.corruptions.fetch_add(1, Ordering::Relaxed);
+                    if self.record_failure(key) {
+                        self.counters.quarantines.fetch_add(1, Ordering::Relaxed);
+                        warn!(
+                            ctx.logger(),
+                            "Quarantined blob {} for {:?} after repeated corrupt reads: {:#}",
+                            key,
+                            self.options.duration,
+                            e
+                        );
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        let result = self.inner.put(ctx, key.clone(), value).await;
+        self.after_put(&key, result)
+    }
+
+    async fn is_present<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreIsPresent> {
+        self.inner.is_present(ctx, key).await
+    }
+
+    async fn copy<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        old_key: &'a str,
+        new_key: String,
+    ) -> Result<()> {
+        // Don't spread a value known to be corrupt.
+        self.check(old_key)?;
+        let result = self.inner.copy(ctx, old_key, new_key.clone()).await;
+        self.after_put(&new_key, result)
+    }
+}
+
+#[async_trait]
+impl<B: BlobstorePutOps> BlobstorePutOps for QuarantineBlob<B> {
+    async fn put_explicit<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+    ) -> Result<OverwriteStatus> {
+        let result = self
+            .inner
+            .put_explicit(ctx, key.clone(), value, put_behaviour)
+            .await;
+        self.after_put(&key, result)
+    }
+
+    async fn put_with_status<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<OverwriteStatus> {
+        let result = self.inner.put_with_status(ctx, key.clone(), value).await;
+        self.after_put(&key, result)
+    }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        let result = self.inner.put_with_ttl(ctx, key.clone(), value, ttl).await;
+        self.after_put(&key, result)
+    }
+}
+
+#[async_trait]
+impl<B: BlobstoreUnlinkOps> BlobstoreUnlinkOps for QuarantineBlob<B> {
+    async fn unlink<'a>(&'a self, ctx: &'a CoreContext, key: &'a str) -> Result<()> {
+        let result = self.inner.unlink(ctx, key).await;
+        self.after_put(key, result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::atomic::AtomicUsize;
+
+    use anyhow::anyhow;
+    use borrowed::borrowed;
+    use fbinit::FacebookInit;
+    use integrityblob::HashAlgorithm;
+    use memblob::Memblob;
+
+    use super::*;
+
+    /// Fails gets of `corrupt` keys with a `HashMismatch`, and counts gets.
+    #[derive(Debug, Default)]
+    struct FailingBlob {
+        inner: Memblob,
+        corrupt: Mutex<HashSet<String>>,
+        gets: AtomicUsize,
+    }
+
+    impl fmt::Display for FailingBlob {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "FailingBlob")
+        }
+    }
+
+    impl FailingBlob {
+        fn set_corrupt(&self, key: &str, corrupt: bool) {
+            let mut keys = self.corrupt.lock().unwrap();
+            if corrupt {
+                keys.insert(key.to_string());
+            } else {
+                keys.remove(key);
+            }
+        }
+
+        fn gets(&self) -> usize {
+            self.gets.load(Ordering::Relaxed)
+        }
+    }
+
+    #[async_trait]
+    impl Blobstore for FailingBlob {
+        async fn get<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: &'a str,
+        ) -> Result<Option<BlobstoreGetData>> {
+            self.gets.fetch_add(1, Ordering::Relaxed);
+            if self.corrupt.lock().unwrap().contains(key) {
+                return Err(HashMismatch {
+                    key: key.to_string(),
+                    algorithm: HashAlgorithm::Sha256,
+                    expected: "00".to_string(),
+                    actual: "11".to_string(),
+                }
+                .into());
+            }
+            if key == "unavailable" {
+                return Err(anyhow!("backend unavailable"));
+            }
+            self.inner.get(ctx, key).await
+        }
+
+        async fn put<'a>(
+            &'a self,
+            ctx: &'a CoreContext,
+            key: String,
+            value: BlobstoreBytes,
+        ) -> Result<()> {
+            self.set_corrupt(&key, false);
+            self.inner.put(ctx, key, value).await
+        }
+    }
+
+    fn options(duration: Duration) -> QuarantineOptions {
+        QuarantineOptions {
+            threshold: 2,
+            duration,
+            ..Default::default()
+        }
+    }
+
+    fn bytes(value: &[u8]) -> BlobstoreBytes {
+        BlobstoreBytes::from_bytes(value.to_vec())
+    }
+
+    #[fbinit::test]
+    async fn test_quarantine(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = QuarantineBlob::new(FailingBlob::default(), options(Duration::from_secs(600)));
+        blob.put(ctx, "key".to_string(), bytes(b"value")).await?;
+        blob.inner.set_corrupt("key", true);
+
+        for _ in 0..2 {
+            let err = blob.get(ctx, "key").await.unwrap_err();
+            assert!(err.is::<HashMismatch>(), "{:?}", err);
+        }
+        assert_eq!(blob.inner.gets(), 2);
+
+        // Fails fast, without reaching the inner blobstore.
+        let err = blob.get(ctx, "key").await.unwrap_err();
+        let quarantined = err.downcast_ref::<Quarantined>().unwrap();
+        assert_eq!(quarantined.failures, 2);
+        assert_eq!(blob.inner.gets(), 2);
+        assert_eq!(blob.quarantined_keys(), vec![quarantined.clone()]);
+        assert!(blob.copy(ctx, "key", "copy".to_string()).await.is_err());
+        assert_eq!(blob.counters().rejected.load(Ordering::Relaxed), 1);
+        assert_eq!(blob.counters().quarantines.load(Ordering::Relaxed), 1);
+
+        // Lifted by the operator.
+        assert!(blob.unquarantine("key"));
+        assert!(!blob.unquarantine("key"));
+        assert!(blob.get(ctx, "key").await.is_err());
+        assert_eq!(blob.inner.gets(), 3);
+        assert!(blob.quarantined_keys().is_empty());
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_expiry_and_put(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = QuarantineBlob::new(FailingBlob::default(), options(Duration::from_millis(50)));
+        blob.put(ctx, "key".to_string(), bytes(b"value")).await?;
+        blob.inner.set_corrupt("key", true);
+        for _ in 0..3 {
+            assert!(blob.get(ctx, "key").await.is_err());
+        }
+        assert_eq!(blob.inner.gets(), 2);
+
+        // Once expired, gets reach the inner blobstore again.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(blob.quarantined_keys().is_empty());
+        assert!(blob.get(ctx, "key").await.is_err());
+        assert_eq!(blob.inner.gets(), 3);
+
+        // A put lifts the quarantine before it expires.
+        assert!(blob.get(ctx, "key").await.is_err());
+        assert_eq!(blob.quarantined_keys().len(), 1);
+        blob.put(ctx, "key".to_string(), bytes(b"fixed")).await?;
+        assert!(blob.quarantined_keys().is_empty());
+        let value = blob.get(ctx, "key").await?.unwrap();
+        assert_eq!(value.into_raw_bytes().as_ref(), b"fixed");
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_consecutive_failures(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = QuarantineBlob::new(FailingBlob::default(), options(Duration::from_secs(600)));
+        blob.put(ctx, "key".to_string(), bytes(b"value")).await?;
+
+        // A successful get in between resets the count.
+        for _ in 0..3 {
+            blob.inner.set_corrupt("key", true);
+            assert!(blob.get(ctx, "key").await.is_err());
+            blob.inner.set_corrupt("key", false);
+            assert!(blob.get(ctx, "key").await?.is_some());
+        }
+        // Errors that are not corruption are not counted, unless classified.
+        for _ in 0..3 {
+            assert!(blob.get(ctx, "unavailable").await.is_err());
+        }
+        assert!(blob.quarantined_keys().is_empty());
+
+        let blob =
+            blob.with_classifier(Arc::new(|e: &Error| e.to_string().contains("unavailable")));
+        for _ in 0..3 {
+            assert!(blob.get(ctx, "unavailable").await.is_err());
+        }
+        assert_eq!(blob.quarantined_keys().len(), 1);
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_max_tracked_keys(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let options = QuarantineOptions {
+            max_tracked_keys: 2,
+            ..options(Duration::from_secs(600))
+        };
+        let blob = QuarantineBlob::new(FailingBlob::default(), options);
+        for key in ["a", "b", "c"] {
+            blob.inner.set_corrupt(key, true);
+            assert!(blob.get(ctx, key).await.is_err());
+        }
+        // The failure of "a" was forgotten to make room for "c".
+        assert_eq!(blob.state.lock().unwrap().failures.len(), 2);
+        assert!(blob.get(ctx, "a").await.is_err());
+        assert!(blob.get(ctx, "c").await.is_err());
+        let keys: Vec<_> = blob.quarantined_keys().into_iter().map(|q| q.key).collect();
+        assert_eq!(keys, vec!["c".to_string()]);
+        Ok(())
+    }
+}
@@ -9,6 +9,7 @@ use anyhow::Result;
 use async_trait::async_trait;
 use blobstore::Blobstore;
 use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreGetWithProvenance;
 use blobstore::BlobstoreIsPresent;
 use blobstore::BlobstorePutOps;
 use blobstore::OverwriteStatus;
@@ -47,6 +48,14 @@ impl<T: Blobstore> Blobstore for ReadOnlyBlobstore<T> {
         self.blobstore.get(ctx, key).await
     }
 
+    async fn get_with_provenance<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreGetWithProvenance> {
+        self.blobstore.get_with_provenance(ctx, key).await
+    }
+
     #[inline]
     async fn put<'a>(
         &'a self,
@@ -18,6 +18,7 @@ use anyhow::Result;
 use async_trait::async_trait;
 use blobstore::Blobstore;
 use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreGetWithProvenance;
 use blobstore::BlobstoreIsPresent;
 use context::CoreContext;
 use mononoke_types::BlobstoreBytes;
@@ -208,6 +209,15 @@ impl<T: Blobstore> Blobstore for RedactedBlobstoreInner<T> {
         blobstore.get(ctx, key).await
     }
 
+    async fn get_with_provenance<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreGetWithProvenance> {
+        let blobstore = self.access_blobstore(ctx, key, config::GET_OPERATION)?;
+        blobstore.get_with_provenance(ctx, key).await
+    }
+
     async fn put<'a>(
         &'a self,
         ctx: &'a CoreContext,
@@ -247,6 +257,13 @@ impl<B: Blobstore> Blobstore for RedactedBlobstore<B> {
     ) -> Result<Option<BlobstoreGetData>> {
         self.inner.get(ctx, key).await
     }
+    async fn get_with_provenance<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreGetWithProvenance> {
+        self.inner.get_with_provenance(ctx, key).await
+    }
     async fn put<'a>(
         &'a self,
         ctx: &'a CoreContext,
@@ -6,11 +6,13 @@
  */
 
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
 use async_trait::async_trait;
 use blobstore::Blobstore;
 use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreGetWithProvenance;
 use blobstore::BlobstoreIsPresent;
 use blobstore::BlobstorePutOps;
 use blobstore::OverwriteStatus;
@@ -69,9 +71,18 @@ impl<T: Blobstore> Blobstore for SamplingBlobstore<T> {
         ctx: &'a CoreContext,
         key: &'a str,
     ) -> Result<Option<BlobstoreGetData>> {
-        let opt_blob = self.inner.get(ctx, key).await?;
-        self.handler.sample_get(ctx, key, opt_blob.as_ref())?;
-        Ok(opt_blob)
+        Ok(self.get_with_provenance(ctx, key).await?.data)
+    }
+
+    #[inline]
+    async fn get_with_provenance<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreGetWithProvenance> {
+        let get = self.inner.get_with_provenance(ctx, key).await?;
+        self.handler.sample_get(ctx, key, get.data.as_ref())?;
+        Ok(get)
     }
 
     #[inline]
@@ -170,10 +181,19 @@ impl<T: Blobstore + BlobstorePutOps> Blobstore for SamplingBlobstorePutOps<T> {
         ctx: &'a CoreContext,
         key: &'a str,
     ) -> Result<Option<BlobstoreGetData>> {
-        let opt_blob = self.inner.get(ctx, key).await?;
+        Ok(self.get_with_provenance(ctx, key).await?.data)
+    }
+
+    #[inline]
+    async fn get_with_provenance<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreGetWithProvenance> {
+        let get = self.inner.get_with_provenance(ctx, key).await?;
         self.handler
-            .sample_get(ctx, key, opt_blob.as_ref(), self.inner_id)?;
-        Ok(opt_blob)
+            .sample_get(ctx, key, get.data.as_ref(), self.inner_id)?;
+        Ok(get)
     }
 
     #[inline]
@@ -226,6 +246,17 @@ impl<T: BlobstorePutOps> BlobstorePutOps for SamplingBlobstorePutOps<T> {
         self.handler.sample_put(ctx, &key, &value, self.inner_id)?;
         self.inner.put_with_status(ctx, key, value).await
     }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        self.handler.sample_put(ctx, &key, &value, self.inner_id)?;
+        self.inner.put_with_ttl(ctx, key, value, ttl).await
+    }
 }
 
 #[cfg(test)]
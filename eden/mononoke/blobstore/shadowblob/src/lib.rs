@@ -0,0 +1,544 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! A blobstore that verifies a new backend against the current one before
+//! reads are moved to it.
+//!
+//! All operations are served by the primary. For a sample of gets, the same
+//! get is also issued to the shadow store from a background task, and the
+//! two results are compared. Shadow reads never delay or change the result
+//! returned to the caller: at most `max_concurrency` of them run at once,
+//! and further ones are dropped and counted. Mismatches are recorded in a
+//! bounded report, available from a `ShadowReadHandle`.
+
+use std::fmt;
+use std::hash::Hasher;
+use std::num::NonZeroU64;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use blobstore::Blobstore;
+use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreIsPresent;
+use blobstore::BlobstorePutOps;
+use blobstore::BlobstoreUnlinkOps;
+use blobstore::OverwriteStatus;
+use blobstore::PutBehaviour;
+use context::CoreContext;
+use mononoke_types::BlobstoreBytes;
+use rand::Rng;
+use slog::warn;
+use tokio::sync::Notify;
+use tokio::sync::Semaphore;
+use twox_hash::XxHash64;
+
+/// Which gets are compared with the shadow store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShadowSampling {
+    /// One in this many gets, chosen at random.
+    Rate(NonZeroU64),
+    /// One in this many keys, chosen by a hash of the key, so the same keys
+    /// are compared on every run.
+    KeyHash(NonZeroU64),
+}
+
+impl ShadowSampling {
+    fn sampled(&self, key: &str) -> bool {
+        match *self {
+            ShadowSampling::Rate(rate) => {
+                rate.get() == 1 || rand::thread_rng().gen_range(0..rate.get()) == 0
+            }
+            ShadowSampling::KeyHash(rate) => {
+                let mut hasher = XxHash64::with_seed(0);
+                hasher.write(key.as_bytes());
+                hasher.finish() % rate.get() == 0
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ShadowReadOptions {
+    pub sampling: ShadowSampling,
+    /// Maximum number of shadow reads in flight. Sampled gets beyond it are
+    /// not compared, and counted as dropped.
+    pub max_concurrency: usize,
+    /// Number of mismatches kept in the report. Later ones are only counted.
+    pub max_mismatches: usize,
+    /// Log shadow read failures with their key.
+    pub log_failures: bool,
+}
+
+impl Default for ShadowReadOptions {
+    fn default() -> Self {
+        Self {
+            sampling: ShadowSampling::Rate(NonZeroU64::new(100).unwrap()),
+            max_concurrency: 100,
+            max_mismatches: 1000,
+            log_failures: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MismatchKind {
+    /// The primary has the key, the shadow does not.
+    MissingInShadow,
+    /// The shadow has the key, the primary does not.
+    MissingInPrimary,
+    /// Both have the key, with different values.
+    ContentDiffers,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    pub key: String,
+    pub kind: MismatchKind,
+    /// Size of the value in the primary, if it has the key.
+    pub primary_size: Option<usize>,
+    /// Size of the value in the shadow, if it has the key.
+    pub shadow_size: Option<usize>,
+}
+
+/// The comparisons made since the `ShadowReadBlob` was created, or the
+/// report was reset.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShadowReadReport {
+    /// Gets compared with the shadow.
+    pub compared: u64,
+    pub equal: u64,
+    pub mismatched: u64,
+    /// Comparisons that could not read the shadow.
+    pub shadow_errors: u64,
+    /// Sampled gets not compared because too many shadow reads were in
+    /// flight.
+    pub dropped: u64,
+    /// The first `max_mismatches` mismatches, oldest first.
+    pub mismatches: Vec<Mismatch>,
+}
+
+#[derive(Default)]
+struct Counters {
+    compared: AtomicU64,
+    equal: AtomicU64,
+    mismatched: AtomicU64,
+    shadow_errors: AtomicU64,
+    dropped: AtomicU64,
+}
+
+struct Shared {
+    counters: Counters,
+    mismatches: Mutex<Vec<Mismatch>>,
+    max_mismatches: usize,
+    log_failures: bool,
+    in_flight: AtomicU64,
+    /// Notified when no shadow read is in flight.
+    idle: Notify,
+}
+
+impl Shared {
+    fn compare(
+        &self,
+        key: String,
+        primary: Option<&BlobstoreGetData>,
+        shadow: Option<&BlobstoreGetData>,
+    ) {
+        self.counters.compared.fetch_add(1, Ordering::Relaxed);
+        let kind = match (primary, shadow) {
+            (None, None) => None,
+            (Some(_), None) => Some(MismatchKind::MissingInShadow),
+            (None, Some(_)) => Some(MismatchKind::MissingInPrimary),
+            (Some(primary), Some(shadow)) => (primary.as_raw_bytes() != shadow.as_raw_bytes())
+                .then_some(MismatchKind::ContentDiffers),
+        };
+        let kind = match kind {
+            None => {
+                self.counters.equal.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            Some(kind) => kind,
+        };
+        self.counters.mismatched.fetch_add(1, Ordering::Relaxed);
+        let mut mismatches = self.mismatches.lock().expect("lock poisoned");
+        if mismatches.len() < self.max_mismatches {
+            mismatches.push(Mismatch {
+                key,
+                kind,
+                primary_size: primary.map(|data| data.as_raw_bytes().len()),
+                shadow_size: shadow.map(|data| data.as_raw_bytes().len()),
+            });
+        }
+    }
+
+    fn done(&self) {
+        if self.in_flight.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.idle.notify_waiters();
+        }
+    }
+}
+
+/// Handle to the report of a `ShadowReadBlob`. Clones share the same report.
+#[derive(Clone)]
+pub struct ShadowReadHandle {
+    shared: Arc<Shared>,
+}
+
+impl ShadowReadHandle {
+    pub fn report(&self) -> ShadowReadReport {
+        let counters = &self.shared.counters;
+        ShadowReadReport {
+            compared: counters.compared.load(Ordering::Relaxed),
+            equal: counters.equal.load(Ordering::Relaxed),
+            mismatched: counters.mismatched.load(Ordering::Relaxed),
+            shadow_errors: counters.shadow_errors.load(Ordering::Relaxed),
+            dropped: counters.dropped.load(Ordering::Relaxed),
+            mismatches: self
+                .shared
+                .mismatches
+                .lock()
+                .expect("lock poisoned")
+                .clone(),
+        }
+    }
+
+    /// Clear the counters and mismatches, e.g. after fixing the shadow.
+    /// Shadow reads in flight are counted in the new report.
+    pub fn reset(&self) {
+        let counters = &self.shared.counters;
+        for counter in [
+            &counters.compared,
+            &counters.equal,
+            &counters.mismatched,
+            &counters.shadow_errors,
+            &counters.dropped,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.shared
+            .mismatches
+            .lock()
+            .expect("lock poisoned")
+            .clear();
+    }
+
+    /// Wait until the shadow reads started so far finished.
+    pub async fn wait_idle(&self) {
+        loop {
+            let idle = self.shared.idle.notified();
+            if self.shared.in_flight.load(Ordering::Acquire) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+/// Serves everything from `primary`, and compares a sample of gets with
+/// `shadow`. See the crate documentation.
+pub struct ShadowReadBlob<P, S> {
+    primary: P,
+    shadow: Arc<S>,
+    sampling: ShadowSampling,
+    permits: Arc<Semaphore>,
+    shared: Arc<Shared>,
+}
+
+impl<P: fmt::Display, S: fmt::Display> fmt::Display for ShadowReadBlob<P, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ShadowReadBlob<{}, {}>", &self.primary, &self.shadow)
+    }
+}
+
+impl<P: fmt::Debug, S: fmt::Debug> fmt::Debug for ShadowReadBlob<P, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShadowReadBlob")
+            .field("primary", &self.primary)
+            .field("shadow", &self.shadow)
+            .field("sampling", &self.sampling)
+            .finish()
+    }
+}
+
+impl<P, S> ShadowReadBlob<P, S> {
+    pub fn new(primary: P, shadow: S, options: ShadowReadOptions) -> Self {
+        Self {
+            primary,
+            shadow: Arc::new(shadow),
+            sampling: options.sampling,
+            permits: Arc::new(Semaphore::new(options.max_concurrency)),
+            shared: Arc::new(Shared {
+                counters: Counters::default(),
+                mismatches: Mutex::new(Vec::new()),
+                max_mismatches: options.max_mismatches,
+                log_failures: options.log_failures,
+                in_flight: AtomicU64::new(0),
+                idle: Notify::new(),
+            }),
+        }
+    }
+
+    pub fn handle(&self) -> ShadowReadHandle {
+        ShadowReadHandle {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<P, S: Blobstore + 'static> ShadowReadBlob<P, S> {
+    /// Compare `primary`, the value the primary returned for `key`, with the
+    /// shadow, in the background. Must be called from a Tokio runtime.
+    fn shadow_get(&self, ctx: &CoreContext, key: &str, primary: Option<BlobstoreGetData>) {
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                self.shared.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        self.shared.in_flight.fetch_add(1, Ordering::AcqRel);
+        let ctx = ctx.clone();
+        let key = key.to_string();
+        let shadow = self.shadow.clone();
+        let shared = self.shared.clone();
+        tokio::spawn(async move {
+            match shadow.get(&ctx, &key).await {
+                Ok(value) => shared.compare(key, primary.as_ref(), value.as_ref()),
+                Err(e) => {
+                    shared
+                        .counters
+                        .shadow_errors
+                        .fetch_add(1, Ordering::Relaxed);
+                    if shared.log_failures {
+                        warn!(
+                            ctx.logger(),
+                            "Failed to read key {} from shadow blobstore: {:#}", key, e
+                        );
+                    }
+                }
+            }
+            drop(permit);
+            shared.done();
+        });
+    }
+}
+
+#[async_trait]
+impl<P: Blobstore, S: Blobstore + 'static> Blobstore for ShadowReadBlob<P, S> {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        let value = self.primary.get(ctx, key).await?;
+        if self.sampling.sampled(key) {
+            self.shadow_get(ctx, key, value.clone());
+        }
+        Ok(value)
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        self.primary.put(ctx, key, value).await
+    }
+
+    async fn is_present<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreIsPresent> {
+        self.primary.is_present(ctx, key).await
+    }
+
+    async fn copy<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        old_key: &'a str,
+        new_key: String,
+    ) -> Result<()> {
+        self.primary.copy(ctx, old_key, new_key).await
+    }
+}
+
+#[async_trait]
+impl<P: BlobstorePutOps, S: Blobstore + 'static> BlobstorePutOps for ShadowReadBlob<P, S> {
+    async fn put_explicit<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+    ) -> Result<OverwriteStatus> {
+        self.primary
+            .put_explicit(ctx, key, value, put_behaviour)
+            .await
+    }
+
+    async fn put_with_status<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<OverwriteStatus> {
+        self.primary.put_with_status(ctx, key, value).await
+    }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        self.primary.put_with_ttl(ctx, key, value, ttl).await
+    }
+}
+
+#[async_trait]
+impl<P: BlobstoreUnlinkOps, S: Blobstore + 'static> BlobstoreUnlinkOps for ShadowReadBlob<P, S> {
+    async fn unlink<'a>(&'a self, ctx: &'a CoreContext, key: &'a str) -> Result<()> {
+        self.primary.unlink(ctx, key).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use borrowed::borrowed;
+    use fbinit::FacebookInit;
+    use memblob::Memblob;
+
+    use super::*;
+
+    fn bytes(value: &[u8]) -> BlobstoreBytes {
+        BlobstoreBytes::from_bytes(value.to_vec())
+    }
+
+    fn value(data: Option<BlobstoreGetData>) -> Option<Vec<u8>> {
+        data.map(|d| d.into_raw_bytes().to_vec())
+    }
+
+    fn options(sampling: ShadowSampling) -> ShadowReadOptions {
+        ShadowReadOptions {
+            sampling,
+            ..Default::default()
+        }
+    }
+
+    #[fbinit::test]
+    async fn test_shadow_reads(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+
+        let primary = Memblob::default();
+        let shadow = Memblob::default();
+        for key in ["a", "b", "c"] {
+            primary.put(ctx, key.to_string(), bytes(b"same")).await?;
+            shadow.put(ctx, key.to_string(), bytes(b"same")).await?;
+        }
+        shadow.put(ctx, "b".to_string(), bytes(b"other")).await?;
+        let blob = ShadowReadBlob::new(
+            primary,
+            shadow.clone(),
+            options(ShadowSampling::Rate(NonZeroU64::new(1).unwrap())),
+        );
+        let handle = blob.handle();
+
+        for key in ["a", "b", "c", "absent"] {
+            let expected = (key != "absent").then(|| b"same".to_vec());
+            assert_eq!(value(blob.get(ctx, key).await?), expected);
+        }
+        handle.wait_idle().await;
+        assert_eq!(
+            handle.report(),
+            ShadowReadReport {
+                compared: 4,
+                equal: 3,
+                mismatched: 1,
+                mismatches: vec![Mismatch {
+                    key: "b".to_string(),
+                    kind: MismatchKind::ContentDiffers,
+                    primary_size: Some(4),
+                    shadow_size: Some(5),
+                }],
+                ..Default::default()
+            }
+        );
+
+        // Writes only reach the primary.
+        blob.put(ctx, "d".to_string(), bytes(b"new")).await?;
+        assert!(shadow.get(ctx, "d").await?.is_none());
+        handle.reset();
+        assert_eq!(value(blob.get(ctx, "d").await?), Some(b"new".to_vec()));
+        handle.wait_idle().await;
+        let report = handle.report();
+        assert_eq!(report.compared, 1);
+        assert_eq!(
+            report.mismatches,
+            vec![Mismatch {
+                key: "d".to_string(),
+                kind: MismatchKind::MissingInShadow,
+                primary_size: Some(3),
+                shadow_size: None,
+            }]
+        );
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_sampling(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+
+        let keys: Vec<_> = (0..100).map(|i| format!("key{}", i)).collect();
+        let sampling = ShadowSampling::KeyHash(NonZeroU64::new(4).unwrap());
+        let sampled: Vec<_> = keys.iter().filter(|key| sampling.sampled(key)).collect();
+        assert!(!sampled.is_empty() && sampled.len() < keys.len());
+
+        // The same keys are sampled every time.
+        let blob = ShadowReadBlob::new(Memblob::default(), Memblob::default(), options(sampling));
+        let handle = blob.handle();
+        for _ in 0..2 {
+            for key in &keys {
+                blob.get(ctx, key).await?;
+            }
+        }
+        handle.wait_idle().await;
+        let report = handle.report();
+        assert_eq!(report.compared + report.dropped, 2 * sampled.len() as u64);
+        assert_eq!(report.mismatched, 0);
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_backpressure(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+
+        let blob = ShadowReadBlob::new(
+            Memblob::default(),
+            Memblob::default(),
+            ShadowReadOptions {
+                sampling: ShadowSampling::Rate(NonZeroU64::new(1).unwrap()),
+                max_concurrency: 0,
+                ..Default::default()
+            },
+        );
+        assert!(blob.get(ctx, "a").await?.is_none());
+        let report = blob.handle().report();
+        assert_eq!(report.dropped, 1);
+        assert_eq!(report.compared, 0);
+        Ok(())
+    }
+}
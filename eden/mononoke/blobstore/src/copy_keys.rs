@@ -0,0 +1,197 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::sync::Arc;
+
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Result;
+use context::CoreContext;
+use futures::stream;
+use futures::StreamExt;
+
+use crate::Blobstore;
+use crate::BlobstoreKeyParam;
+use crate::BlobstoreKeySource;
+
+/// The destination key of a source key, or `None` to skip the key.
+pub type KeyTransform = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// Saves the token to resume a `copy_keys` from, see
+/// `CopyKeysOptions::checkpoint`.
+pub type CopyCheckpoint = Arc<dyn Fn(&BlobstoreKeyParam) -> Result<()> + Send + Sync>;
+
+/// How `copy_keys` copies.
+#[derive(Clone)]
+pub struct CopyKeysOptions {
+    /// Maximum number of keys being copied at once.
+    pub concurrency: usize,
+    /// Read each value back from the destination after writing it, and
+    /// fail the key if it differs.
+    pub verify: bool,
+    /// Skip keys whose destination key is already present.
+    pub skip_existing: bool,
+    /// Enumerate and count the keys that would be copied, without reading
+    /// or writing values.
+    pub dry_run: bool,
+    /// Maximum number of failures listed in `CopyReport::failure_samples`.
+    pub max_failure_samples: usize,
+    /// Called with the token to resume from once all keys before it were
+    /// handled, that is after each page of the enumeration but the last.
+    /// Failing it fails the copy.
+    pub checkpoint: Option<CopyCheckpoint>,
+}
+
+impl Default for CopyKeysOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 16,
+            verify: false,
+            skip_existing: false,
+            dry_run: false,
+            max_failure_samples: 10,
+            checkpoint: None,
+        }
+    }
+}
+
+/// What `copy_keys` did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CopyReport {
+    /// Keys copied, or that would have been for a dry run.
+    pub copied: u64,
+    /// Keys skipped by the transform, or because their destination key was
+    /// present.
+    pub skipped: u64,
+    pub failed: u64,
+    /// Size of the values copied.
+    pub bytes: u64,
+    /// Source keys that failed to copy, with their error, up to
+    /// `CopyKeysOptions::max_failure_samples`.
+    pub failure_samples: Vec<(String, String)>,
+}
+
+enum KeyOutcome {
+    Copied(u64),
+    Skipped,
+}
+
+/// Copy the keys of `range` from `source` to `dest`, under the keys given by
+/// `transform`. Keys are handled one enumeration page at a time, in key
+/// order within a page.
+///
+/// Keys failing to copy are counted in the report and do not stop the copy,
+/// while enumeration failures do. `range` can be a token saved by
+/// `CopyKeysOptions::checkpoint`, to resume an interrupted copy. Keys that
+/// failed before the checkpoint are not retried.
+pub async fn copy_keys<S>(
+    ctx: &CoreContext,
+    source: &S,
+    dest: &dyn Blobstore,
+    range: BlobstoreKeyParam,
+    transform: KeyTransform,
+    options: &CopyKeysOptions,
+) -> Result<CopyReport>
+where
+    S: BlobstoreKeySource + ?Sized,
+{
+    let mut report = CopyReport::default();
+    let mut param = range;
+    loop {
+        let page = source
+            .enumerate(ctx, &param)
+            .await
+            .with_context(|| format!("Failed to enumerate {}", source))?;
+        let mut keys: Vec<_> = page.keys.into_iter().collect();
+        keys.sort();
+
+        let outcomes: Vec<_> = stream::iter(keys.into_iter().map(|key| {
+            let transform = &transform;
+            async move {
+                let outcome = copy_key(ctx, source, dest, &key, transform, options).await;
+                (key, outcome)
+            }
+        }))
+        .buffered(options.concurrency.max(1))
+        .collect()
+        .await;
+        for (key, outcome) in outcomes {
+            match outcome {
+                Ok(KeyOutcome::Copied(bytes)) => {
+                    report.copied += 1;
+                    report.bytes += bytes;
+                }
+                Ok(KeyOutcome::Skipped) => report.skipped += 1,
+                Err(e) => {
+                    report.failed += 1;
+                    if report.failure_samples.len() < options.max_failure_samples {
+                        report.failure_samples.push((key, format!("{:#}", e)));
+                    }
+                }
+            }
+        }
+
+        match page.next_token {
+            Some(next) => {
+                if let Some(checkpoint) = &options.checkpoint {
+                    checkpoint(&next).context("Failed to checkpoint copy")?;
+                }
+                param = next;
+            }
+            None => return Ok(report),
+        }
+    }
+}
+
+async fn copy_key<S>(
+    ctx: &CoreContext,
+    source: &S,
+    dest: &dyn Blobstore,
+    key: &str,
+    transform: &KeyTransform,
+    options: &CopyKeysOptions,
+) -> Result<KeyOutcome>
+where
+    S: BlobstoreKeySource + ?Sized,
+{
+    let dest_key = match transform(key) {
+        Some(dest_key) => dest_key,
+        None => return Ok(KeyOutcome::Skipped),
+    };
+    if options.skip_existing
+        && dest
+            .is_present(ctx, &dest_key)
+            .await?
+            .assume_not_found_if_unsure()
+    {
+        return Ok(KeyOutcome::Skipped);
+    }
+    if options.dry_run {
+        return Ok(KeyOutcome::Copied(0));
+    }
+
+    let value = source
+        .get(ctx, key)
+        .await?
+        .ok_or_else(|| format_err!("Key is missing from the source"))?
+        .into_bytes();
+    let len = value.len() as u64;
+    dest.put(ctx, dest_key.clone(), value.clone()).await?;
+    if options.verify {
+        let written = dest
+            .get(ctx, &dest_key)
+            .await?
+            .ok_or_else(|| format_err!("Verification failed: {} is missing", dest_key))?;
+        if written.as_bytes() != &value {
+            return Err(format_err!(
+                "Verification failed: {} differs from the source",
+                dest_key
+            ));
+        }
+    }
+    Ok(KeyOutcome::Copied(len))
+}
@@ -7,6 +7,10 @@
 
 use std::fmt::Display;
 use std::ops::Deref;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
 use async_trait::async_trait;
@@ -17,9 +21,12 @@ use crate::Blobstore;
 use crate::BlobstoreBytes;
 use crate::BlobstoreEnumerationData;
 use crate::BlobstoreGetData;
+use crate::BlobstoreGetManyData;
+use crate::BlobstoreGetWithProvenance;
 use crate::BlobstoreIsPresent;
 use crate::BlobstoreKeyParam;
 use crate::BlobstoreKeySource;
+use crate::BlobstorePutBatchResults;
 use crate::BlobstorePutOps;
 use crate::BlobstoreUnlinkOps;
 use crate::OverwriteStatus;
@@ -30,6 +37,7 @@ define_stats_struct! {
     get: timeseries(Rate, Sum),
     get_ok: timeseries(Rate, Sum),
     get_err: timeseries(Rate, Sum),
+    get_many: timeseries(Rate, Sum),
     put: timeseries(Rate, Sum),
     put_ok: timeseries(Rate, Sum),
     put_err: timeseries(Rate, Sum),
@@ -37,6 +45,7 @@ define_stats_struct! {
     put_new: timeseries(Rate, Sum),
     put_overwrote: timeseries(Rate, Sum),
     put_prevented: timeseries(Rate, Sum),
+    put_batch: timeseries(Rate, Sum),
     is_present: timeseries(Rate, Sum),
     is_present_ok: timeseries(Rate, Sum),
     is_present_err: timeseries(Rate, Sum),
@@ -51,10 +60,21 @@ define_stats_struct! {
     enumerate_err: timeseries(Rate, Sum),
 }
 
+/// Totals of the operations counted by a `CountedBlobstore`, readable
+/// unlike its stats.
+#[derive(Debug, Default)]
+pub struct CountedBlobstoreCounters {
+    /// Keys fetched, by get or get_many.
+    pub gets: AtomicU64,
+    /// Values written, by any kind of put.
+    pub puts: AtomicU64,
+}
+
 #[derive(Debug)]
 pub struct CountedBlobstore<T> {
     blobstore: T,
     stats: CountedBlobstoreStats,
+    counters: Arc<CountedBlobstoreCounters>,
 }
 
 impl<T: Display> Display for CountedBlobstore<T> {
@@ -68,9 +88,16 @@ impl<T> CountedBlobstore<T> {
         Self {
             blobstore,
             stats: CountedBlobstoreStats::new(name),
+            counters: Default::default(),
         }
     }
 
+    /// The counters, to keep reading them once the blobstore is wrapped or
+    /// type-erased.
+    pub fn shared_counters(&self) -> Arc<CountedBlobstoreCounters> {
+        self.counters.clone()
+    }
+
     pub fn into_inner(self) -> T {
         self.blobstore
     }
@@ -87,8 +114,17 @@ impl<T: Blobstore> Blobstore for CountedBlobstore<T> {
         ctx: &'a CoreContext,
         key: &'a str,
     ) -> Result<Option<BlobstoreGetData>> {
+        Ok(self.get_with_provenance(ctx, key).await?.data)
+    }
+
+    async fn get_with_provenance<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreGetWithProvenance> {
         self.stats.get.add_value(1);
-        let res = self.blobstore.get(ctx, key).await;
+        self.counters.gets.fetch_add(1, Ordering::Relaxed);
+        let res = self.blobstore.get_with_provenance(ctx, key).await;
         match res {
             Ok(_) => self.stats.get_ok.add_value(1),
             Err(_) => self.stats.get_err.add_value(1),
@@ -96,6 +132,27 @@ impl<T: Blobstore> Blobstore for CountedBlobstore<T> {
         res
     }
 
+    async fn get_many<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        keys: &'a [&'a str],
+    ) -> Result<BlobstoreGetManyData> {
+        // Keys are counted as gets, so that rates do not depend on batching.
+        self.stats.get_many.add_value(1);
+        self.stats.get.add_value(keys.len() as i64);
+        self.counters.gets.fetch_add(keys.len() as u64, Ordering::Relaxed);
+        let res = self.blobstore.get_many(ctx, keys).await;
+        match &res {
+            Ok(results) => {
+                let ok = results.values().filter(|result| result.is_ok()).count();
+                self.stats.get_ok.add_value(ok as i64);
+                self.stats.get_err.add_value((results.len() - ok) as i64);
+            }
+            Err(_) => self.stats.get_err.add_value(keys.len() as i64),
+        }
+        res
+    }
+
     async fn put<'a>(
         &'a self,
         ctx: &'a CoreContext,
@@ -103,6 +160,7 @@ impl<T: Blobstore> Blobstore for CountedBlobstore<T> {
         value: BlobstoreBytes,
     ) -> Result<()> {
         self.stats.put.add_value(1);
+        self.counters.puts.fetch_add(1, Ordering::Relaxed);
         let res = self.blobstore.put(ctx, key, value).await;
         match res {
             Ok(()) => self.stats.put_ok.add_value(1),
@@ -150,6 +208,7 @@ impl<T: BlobstorePutOps> CountedBlobstore<T> {
         put_behaviour: Option<PutBehaviour>,
     ) -> Result<OverwriteStatus> {
         self.stats.put.add_value(1);
+        self.counters.puts.fetch_add(1, Ordering::Relaxed);
         let res = if let Some(put_behaviour) = put_behaviour {
             self.blobstore
                 .put_explicit(ctx, key, value, put_behaviour)
@@ -157,6 +216,11 @@ impl<T: BlobstorePutOps> CountedBlobstore<T> {
         } else {
             self.blobstore.put_with_status(ctx, key, value).await
         };
+        self.record_put(&res);
+        res
+    }
+
+    fn record_put(&self, res: &Result<OverwriteStatus>) {
         match res {
             Ok(status) => {
                 self.stats.put_ok.add_value(1);
@@ -169,7 +233,6 @@ impl<T: BlobstorePutOps> CountedBlobstore<T> {
             }
             Err(_) => self.stats.put_err.add_value(1),
         }
-        res
     }
 }
 
@@ -193,6 +256,42 @@ impl<T: BlobstorePutOps> BlobstorePutOps for CountedBlobstore<T> {
     ) -> Result<OverwriteStatus> {
         self.put_impl(ctx, key, value, None).await
     }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        self.stats.put.add_value(1);
+        self.counters.puts.fetch_add(1, Ordering::Relaxed);
+        let res = self.blobstore.put_with_ttl(ctx, key, value, ttl).await;
+        self.record_put(&res);
+        res
+    }
+
+    async fn put_batch<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        items: Vec<(String, BlobstoreBytes)>,
+    ) -> Result<BlobstorePutBatchResults> {
+        // Items are counted as puts, so that rates do not depend on batching.
+        let count = items.len() as i64;
+        self.stats.put_batch.add_value(1);
+        self.stats.put.add_value(count);
+        self.counters.puts.fetch_add(count as u64, Ordering::Relaxed);
+        let res = self.blobstore.put_batch(ctx, items).await;
+        match &res {
+            Ok(results) => {
+                for (_, result) in results {
+                    self.record_put(result);
+                }
+            }
+            Err(_) => self.stats.put_err.add_value(count),
+        }
+        res
+    }
 }
 
 #[async_trait]
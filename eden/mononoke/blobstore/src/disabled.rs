@@ -5,16 +5,24 @@
  * GNU General Public License version 2.
  */
 
-use anyhow::anyhow;
+use std::time::Duration;
+
+use anyhow::Error;
 use anyhow::Result;
 use async_trait::async_trait;
 use context::CoreContext;
 
 use super::Blobstore;
 use super::BlobstoreBytes;
+use super::BlobstoreEnumerationData;
 use super::BlobstoreGetData;
+use super::BlobstoreGetManyData;
+use super::BlobstoreKeyParam;
+use super::BlobstoreKeySource;
+use super::BlobstorePutBatchResults;
 use super::BlobstorePutOps;
 use super::BlobstoreUnlinkOps;
+use super::ErrorKind;
 use super::OverwriteStatus;
 use super::PutBehaviour;
 
@@ -31,6 +39,10 @@ impl DisabledBlob {
             reason: reason.into(),
         }
     }
+
+    fn error(&self) -> Error {
+        ErrorKind::Disabled(self.reason.clone()).into()
+    }
 }
 
 impl std::fmt::Display for DisabledBlob {
@@ -46,7 +58,15 @@ impl Blobstore for DisabledBlob {
         _ctx: &'a CoreContext,
         _key: &'a str,
     ) -> Result<Option<BlobstoreGetData>> {
-        Err(anyhow!("Blobstore disabled: {}", self.reason))
+        Err(self.error())
+    }
+
+    async fn get_many<'a>(
+        &'a self,
+        _ctx: &'a CoreContext,
+        _keys: &'a [&'a str],
+    ) -> Result<BlobstoreGetManyData> {
+        Err(self.error())
     }
 
     async fn put<'a>(
@@ -65,7 +85,7 @@ impl Blobstore for DisabledBlob {
         _old_key: &'a str,
         _new_key: String,
     ) -> Result<()> {
-        Err(anyhow!("Blobstore disabled: {}", self.reason))
+        Err(self.error())
     }
 }
 
@@ -78,7 +98,7 @@ impl BlobstorePutOps for DisabledBlob {
         _value: BlobstoreBytes,
         _put_behaviour: PutBehaviour,
     ) -> Result<OverwriteStatus> {
-        Err(anyhow!("Blobstore disabled: {}", self.reason))
+        Err(self.error())
     }
 
     async fn put_with_status<'a>(
@@ -87,14 +107,43 @@ impl BlobstorePutOps for DisabledBlob {
         _key: String,
         _value: BlobstoreBytes,
     ) -> Result<OverwriteStatus> {
-        Err(anyhow!("Blobstore disabled: {}", self.reason))
+        Err(self.error())
+    }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        _ctx: &'a CoreContext,
+        _key: String,
+        _value: BlobstoreBytes,
+        _ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        Err(self.error())
+    }
+
+    async fn put_batch<'a>(
+        &'a self,
+        _ctx: &'a CoreContext,
+        _items: Vec<(String, BlobstoreBytes)>,
+    ) -> Result<BlobstorePutBatchResults> {
+        Err(self.error())
     }
 }
 
 #[async_trait]
 impl BlobstoreUnlinkOps for DisabledBlob {
     async fn unlink<'a>(&'a self, _ctx: &'a CoreContext, _key: &'a str) -> Result<()> {
-        Err(anyhow!("Blobstore disabled: {}", self.reason))
+        Err(self.error())
+    }
+}
+
+#[async_trait]
+impl BlobstoreKeySource for DisabledBlob {
+    async fn enumerate<'a>(
+        &'a self,
+        _ctx: &'a CoreContext,
+        _range: &'a BlobstoreKeyParam,
+    ) -> Result<BlobstoreEnumerationData> {
+        Err(self.error())
     }
 }
 
@@ -114,6 +163,11 @@ mod test {
             Err(err) => println!("Got error: {:?}", err),
         }
 
+        match disabled.get_many(&ctx, &["foo", "bar"]).await {
+            Ok(_) => panic!("Unexpected success"),
+            Err(err) => println!("Got error: {:?}", err),
+        }
+
         match disabled
             .put(
                 &ctx,
@@ -125,5 +179,29 @@ mod test {
             Ok(_) => panic!("Unexpected success"),
             Err(err) => println!("Got error: {:?}", err),
         }
+
+        match disabled
+            .put_with_ttl(
+                &ctx,
+                "foobar".to_string(),
+                BlobstoreBytes::from_bytes(vec![]),
+                Duration::from_secs(60),
+            )
+            .await
+        {
+            Ok(_) => panic!("Unexpected success"),
+            Err(err) => println!("Got error: {:?}", err),
+        }
+
+        let items = vec![("foobar".to_string(), BlobstoreBytes::from_bytes(vec![]))];
+        match disabled.put_batch(&ctx, items).await {
+            Ok(_) => panic!("Unexpected success"),
+            Err(err) => println!("Got error: {:?}", err),
+        }
+
+        match disabled.enumerate(&ctx, &BlobstoreKeyParam::from(..)).await {
+            Ok(_) => panic!("Unexpected success"),
+            Err(err) => println!("Got error: {:?}", err),
+        }
     }
 }
@@ -0,0 +1,53 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use anyhow::Result;
+use context::CoreContext;
+use futures::stream;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use tokio::sync::mpsc;
+
+use crate::BlobstoreEnumerationData;
+use crate::BlobstoreKeyParam;
+use crate::BlobstoreKeySource;
+
+/// Enumerate all keys of `range`, following the continuation tokens of
+/// `blobstore`. Pages are fetched in a background task, up to `prefetch`
+/// pages ahead of the consumer.
+///
+/// The `next_token` of each page can be saved to resume the enumeration
+/// from the next page. The stream ends after the first error.
+pub fn enumerate_all<B>(
+    ctx: CoreContext,
+    blobstore: B,
+    range: BlobstoreKeyParam,
+    prefetch: usize,
+) -> BoxStream<'static, Result<BlobstoreEnumerationData>>
+where
+    B: BlobstoreKeySource + 'static,
+{
+    let (tx, rx) = mpsc::channel(prefetch.max(1));
+    tokio::spawn(async move {
+        let mut param = Some(range);
+        while let Some(current) = param.take() {
+            let page = blobstore.enumerate(&ctx, &current).await;
+            if let Ok(page) = &page {
+                param = page.next_token.clone();
+            }
+            if tx.send(page).await.is_err() {
+                // The stream was dropped.
+                break;
+            }
+        }
+    });
+    stream::unfold(rx, |mut rx| async move {
+        let page = rx.recv().await?;
+        Some((page, rx))
+    })
+    .boxed()
+}
@@ -13,4 +13,6 @@ pub enum ErrorKind {
     NotFound(String),
     #[error("Error while opening state for blob store")]
     StateOpen,
+    #[error("Blobstore disabled: {0}")]
+    Disabled(String),
 }
@@ -0,0 +1,201 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::format_err;
+use anyhow::Error;
+use anyhow::Result;
+use context::CoreContext;
+use futures::future;
+use futures::stream;
+use futures::StreamExt;
+
+use crate::Blobstore;
+use crate::BlobstoreGetData;
+
+/// Results of `Blobstore::get_many`, by key. A key failing does not fail the
+/// others.
+pub type BlobstoreGetManyData = HashMap<String, Result<Option<BlobstoreGetData>>>;
+
+/// Maximum number of single gets in flight in the default implementation of
+/// `Blobstore::get_many`.
+pub const GET_MANY_CONCURRENCY: usize = 100;
+
+/// Fetch `keys` with single `get`s, at most `GET_MANY_CONCURRENCY` at a time.
+/// This is the default implementation of `Blobstore::get_many`, usable by
+/// blobstores that override it for some keys only.
+pub async fn get_many_by_key<B: Blobstore + ?Sized>(
+    blobstore: &B,
+    ctx: &CoreContext,
+    keys: &[&str],
+) -> BlobstoreGetManyData {
+    stream::iter(keys.iter().map(|key| async move {
+        let result = blobstore.get(ctx, key).await;
+        (key.to_string(), result)
+    }))
+    .buffer_unordered(GET_MANY_CONCURRENCY)
+    .collect()
+    .await
+}
+
+/// How `get_many_until` schedules its gets.
+#[derive(Clone, Debug)]
+pub struct GetManyUntilOptions {
+    /// Maximum number of `get_many` calls in flight.
+    pub concurrency: usize,
+    /// Keys per `get_many` call. The results of a call are only used if all
+    /// of its keys are fetched before the deadline.
+    pub batch_size: usize,
+    /// Schedule keys in the given order, so that must-have keys can be put
+    /// first. Otherwise keys are sorted, so neighbouring keys share batches.
+    pub prioritized: bool,
+}
+
+impl Default for GetManyUntilOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: GET_MANY_CONCURRENCY,
+            batch_size: 1,
+            prioritized: false,
+        }
+    }
+}
+
+/// Results of `get_many_until`. Each distinct key is in exactly one of the
+/// outcome fields.
+#[derive(Debug, Default)]
+pub struct PartialGetResults {
+    pub found: HashMap<String, BlobstoreGetData>,
+    /// Keys the blobstore confirmed are absent.
+    pub missing: HashSet<String>,
+    pub failed: HashMap<String, Error>,
+    /// Keys being fetched at the deadline.
+    pub timed_out: HashSet<String>,
+    /// Keys not scheduled before the deadline.
+    pub not_attempted: HashSet<String>,
+    pub elapsed: Duration,
+}
+
+/// Per-outcome counts of `PartialGetResults`, for logging.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PartialGetSummary {
+    pub found: usize,
+    pub missing: usize,
+    pub failed: usize,
+    pub timed_out: usize,
+    pub not_attempted: usize,
+    pub elapsed: Duration,
+}
+
+impl PartialGetResults {
+    /// Whether every key was fetched, successfully or not, before the
+    /// deadline.
+    pub fn is_complete(&self) -> bool {
+        self.timed_out.is_empty() && self.not_attempted.is_empty()
+    }
+
+    pub fn summary(&self) -> PartialGetSummary {
+        PartialGetSummary {
+            found: self.found.len(),
+            missing: self.missing.len(),
+            failed: self.failed.len(),
+            timed_out: self.timed_out.len(),
+            not_attempted: self.not_attempted.len(),
+            elapsed: self.elapsed,
+        }
+    }
+
+    fn is_done(&self, key: &str) -> bool {
+        self.found.contains_key(key) || self.missing.contains(key) || self.failed.contains_key(key)
+    }
+
+    fn record(&mut self, batch: &[&str], result: Result<BlobstoreGetManyData>) {
+        let mut values = match result {
+            Ok(values) => values,
+            Err(e) => {
+                for key in batch {
+                    self.failed.insert(key.to_string(), format_err!("{:#}", e));
+                }
+                return;
+            }
+        };
+        for key in batch {
+            match values.remove(*key) {
+                Some(Ok(Some(value))) => {
+                    self.found.insert(key.to_string(), value);
+                }
+                Some(Ok(None)) => {
+                    self.missing.insert(key.to_string());
+                }
+                Some(Err(e)) => {
+                    self.failed.insert(key.to_string(), e);
+                }
+                None => {
+                    let e = format_err!("get_many returned no result for {}", key);
+                    self.failed.insert(key.to_string(), e);
+                }
+            }
+        }
+    }
+}
+
+/// Fetch `keys` with `get_many` calls until `deadline`, and return what was
+/// fetched by then, for callers that would rather serve partial data than
+/// fail. Gets still in flight at the deadline are dropped, which is safe as
+/// they are reads.
+pub async fn get_many_until<B: Blobstore + ?Sized>(
+    ctx: &CoreContext,
+    blobstore: &B,
+    keys: &[&str],
+    deadline: Instant,
+    options: &GetManyUntilOptions,
+) -> PartialGetResults {
+    let start = Instant::now();
+    let mut keys = keys.to_vec();
+    if options.prioritized {
+        let mut seen = HashSet::new();
+        keys.retain(|key| seen.insert(*key));
+    } else {
+        keys.sort_unstable();
+        keys.dedup();
+    }
+
+    let batch_size = options.batch_size.max(1);
+    // Batches are started in order, so the first `started` were attempted.
+    let started = AtomicUsize::new(0);
+    let mut results = PartialGetResults::default();
+    let fetch = stream::iter(keys.chunks(batch_size).map(|batch| {
+        started.fetch_add(1, Ordering::Relaxed);
+        async move { (batch, blobstore.get_many(ctx, batch).await) }
+    }))
+    .buffer_unordered(options.concurrency.max(1))
+    .for_each(|(batch, result)| {
+        results.record(batch, result);
+        future::ready(())
+    });
+    let _ = tokio::time::timeout_at(deadline.into(), fetch).await;
+
+    let attempted = started.load(Ordering::Relaxed) * batch_size;
+    for (index, key) in keys.iter().enumerate() {
+        if results.is_done(key) {
+            continue;
+        }
+        if index < attempted {
+            results.timed_out.insert(key.to_string());
+        } else {
+            results.not_attempted.insert(key.to_string());
+        }
+    }
+    results.elapsed = start.elapsed();
+    results
+}
@@ -5,10 +5,17 @@
  * GNU General Public License version 2.
  */
 
+mod copy_keys;
 mod counted_blobstore;
 mod disabled;
+mod enumeration;
 mod errors;
+mod get_many;
 pub mod macros;
+mod provenance;
+mod put_batch;
+pub mod selftest;
+pub mod sniff;
 
 use std::collections::HashSet;
 use std::fmt;
@@ -19,6 +26,7 @@ use std::ops::RangeFrom;
 use std::ops::RangeFull;
 use std::ops::RangeInclusive;
 use std::ops::RangeToInclusive;
+use std::time::Duration;
 
 use abomonation_derive::Abomonation;
 use anyhow::Context;
@@ -39,9 +47,31 @@ use strum::IntoStaticStr;
 use thiserror::Error;
 use trait_set::trait_set;
 
+pub use crate::copy_keys::copy_keys;
+pub use crate::copy_keys::CopyCheckpoint;
+pub use crate::copy_keys::CopyKeysOptions;
+pub use crate::copy_keys::CopyReport;
+pub use crate::copy_keys::KeyTransform;
 pub use crate::counted_blobstore::CountedBlobstore;
+pub use crate::counted_blobstore::CountedBlobstoreCounters;
 pub use crate::disabled::DisabledBlob;
+pub use crate::enumeration::enumerate_all;
 pub use crate::errors::ErrorKind;
+pub use crate::get_many::get_many_by_key;
+pub use crate::get_many::get_many_until;
+pub use crate::get_many::BlobstoreGetManyData;
+pub use crate::get_many::GetManyUntilOptions;
+pub use crate::get_many::PartialGetResults;
+pub use crate::get_many::PartialGetSummary;
+pub use crate::get_many::GET_MANY_CONCURRENCY;
+pub use crate::provenance::BlobstoreGetWithProvenance;
+pub use crate::provenance::Provenance;
+pub use crate::provenance::StoreMiss;
+pub use crate::provenance::StoreRef;
+pub use crate::put_batch::put_batch_by_key;
+pub use crate::put_batch::put_batch_mapped;
+pub use crate::put_batch::BlobstorePutBatchResults;
+pub use crate::put_batch::PUT_BATCH_CONCURRENCY;
 
 // This module exists to namespace re-exported
 // imports, needed for macro exports.
@@ -354,6 +384,30 @@ pub trait Blobstore: fmt::Display + fmt::Debug + Send + Sync {
         ctx: &'a CoreContext,
         key: &'a str,
     ) -> Result<Option<BlobstoreGetData>>;
+    /// Fetch the values associated with `keys`, with a result per key. The provided
+    /// implementation issues single `get`s with bounded concurrency; blobstores that can read
+    /// many keys in one round trip should override it. An `Err` for the whole batch means that
+    /// none of the keys could be fetched. Duplicate keys are fetched once.
+    async fn get_many<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        keys: &'a [&'a str],
+    ) -> Result<BlobstoreGetManyData> {
+        Ok(get_many_by_key(self, ctx, keys).await)
+    }
+    /// Like `get`, but also tells which store served the value, for blobstores composed of
+    /// several stores like `ChainedBlob`. The provided implementation reports no provenance, so
+    /// blobstores wrapping another one should forward it.
+    async fn get_with_provenance<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreGetWithProvenance> {
+        Ok(BlobstoreGetWithProvenance {
+            data: self.get(ctx, key).await?,
+            provenance: None,
+        })
+    }
     /// Associate `value` with `key` for future gets; if `put` is called with different `value`s
     /// for the same key, the implementation may return any `value` it's been given in response
     /// to a `get` for that `key`.
@@ -482,6 +536,38 @@ pub trait BlobstorePutOps: Blobstore {
         key: String,
         value: BlobstoreBytes,
     ) -> Result<OverwriteStatus>;
+
+    /// Like `put_with_status`, but asks for the blob to expire `ttl` after the put, for
+    /// ephemeral data such as snapshots or CI artifacts. Once expired, `get` returns None.
+    ///
+    /// The default implementation ignores the TTL and keeps the blob forever, so expiry is
+    /// best effort: callers must not rely on the blob going away. Wrappers should pass the
+    /// TTL through to the blobstores they wrap.
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        let _ = ttl;
+        self.put_with_status(ctx, key, value).await
+    }
+
+    /// Put `items`, with a result per item in the same order, for bursts of small blobs where
+    /// the overhead of single puts dominates. An item failing does not fail the others; an
+    /// `Err` for the whole batch means that none of the items could be put.
+    ///
+    /// The provided implementation issues `put_with_status`es with bounded concurrency.
+    /// Blobstores that can put many blobs at once should override it, and wrappers should
+    /// pass the batch through, see `put_batch_mapped`.
+    async fn put_batch<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        items: Vec<(String, BlobstoreBytes)>,
+    ) -> Result<BlobstorePutBatchResults> {
+        Ok(put_batch_by_key(self, ctx, items).await)
+    }
 }
 
 /// Mixin trait for blobstores that support the `unlink()` operation
@@ -521,6 +607,34 @@ pub struct BlobstoreKeyRange {
     pub end_key: String,
 }
 
+impl BlobstoreKeyRange {
+    /// The range of keys starting with `prefix`. Keys continuing `prefix`
+    /// with `char::MAX` and more characters are left out, which does not
+    /// matter for ASCII keys.
+    pub fn with_prefix(prefix: &str) -> Self {
+        if prefix.is_empty() {
+            return Self {
+                begin_key: String::new(),
+                end_key: String::new(),
+            };
+        }
+        Self {
+            begin_key: prefix.to_string(),
+            end_key: format!("{}{}", prefix, char::MAX),
+        }
+    }
+
+    /// The rest of this range after `key`, for stores that resume an
+    /// enumeration from a range.
+    pub fn after(&self, key: &str) -> Self {
+        Self {
+            // The smallest key greater than `key`.
+            begin_key: format!("{}\0", key),
+            end_key: self.end_key.clone(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub enum BlobstoreKeyToken {
     // For fileblob and manifold
@@ -534,6 +648,13 @@ pub enum BlobstoreKeyParam {
     Continuation(BlobstoreKeyToken),
 }
 
+impl BlobstoreKeyParam {
+    /// Enumerate the keys starting with `prefix`.
+    pub fn prefix(prefix: &str) -> Self {
+        BlobstoreKeyParam::Start(BlobstoreKeyRange::with_prefix(prefix))
+    }
+}
+
 impl From<RangeInclusive<String>> for BlobstoreKeyParam {
     fn from(range: RangeInclusive<String>) -> Self {
         let (start, end) = range.into_inner();
@@ -592,7 +713,8 @@ impl RangeBounds<String> for &BlobstoreKeyRange {
 #[derive(Debug, Clone)]
 pub struct BlobstoreEnumerationData {
     pub keys: HashSet<String>,
-    /// current range being iterated, this range can be used to resume enumeration
+    /// current range being iterated, this range can be used to resume enumeration.
+    /// It is serializable, so long enumerations can be checkpointed.
     pub next_token: Option<BlobstoreKeyParam>,
 }
 
@@ -0,0 +1,44 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::time::Duration;
+
+use crate::BlobstoreGetData;
+
+/// A store of a blobstore composed of several, like `ChainedBlob`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoreRef {
+    /// Position of the store, the first tried is 0.
+    pub index: usize,
+    pub name: String,
+}
+
+/// A store tried before the one that answered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoreMiss {
+    pub store: StoreRef,
+    pub latency: Duration,
+    /// The store failed, rather than not having the blob.
+    pub failed: bool,
+}
+
+/// Which store answered an operation, and the stores tried before it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Provenance {
+    /// `None` if no store had the blob.
+    pub served_by: Option<StoreRef>,
+    /// In the order they were tried.
+    pub misses: Vec<StoreMiss>,
+}
+
+/// Result of `Blobstore::get_with_provenance`.
+#[derive(Clone, Debug)]
+pub struct BlobstoreGetWithProvenance {
+    pub data: Option<BlobstoreGetData>,
+    /// `None` for blobstores that don't keep track of provenance.
+    pub provenance: Option<Provenance>,
+}
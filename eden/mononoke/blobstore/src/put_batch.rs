@@ -0,0 +1,83 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use anyhow::format_err;
+use anyhow::Error;
+use anyhow::Result;
+use context::CoreContext;
+use futures::stream;
+use futures::StreamExt;
+
+use crate::BlobstoreBytes;
+use crate::BlobstorePutOps;
+use crate::OverwriteStatus;
+
+/// Results of `BlobstorePutOps::put_batch`, one per item in the order of the
+/// items. An item failing does not fail the others.
+pub type BlobstorePutBatchResults = Vec<(String, Result<OverwriteStatus>)>;
+
+/// Maximum number of single puts in flight in the default implementation of
+/// `BlobstorePutOps::put_batch`.
+pub const PUT_BATCH_CONCURRENCY: usize = 100;
+
+/// Put `items` with single `put_with_status`, at most `PUT_BATCH_CONCURRENCY`
+/// at a time. This is the default implementation of
+/// `BlobstorePutOps::put_batch`.
+pub async fn put_batch_by_key<B: BlobstorePutOps + ?Sized>(
+    blobstore: &B,
+    ctx: &CoreContext,
+    items: Vec<(String, BlobstoreBytes)>,
+) -> BlobstorePutBatchResults {
+    stream::iter(items.into_iter().map(|(key, value)| async move {
+        let result = blobstore.put_with_status(ctx, key.clone(), value).await;
+        (key, result)
+    }))
+    .buffered(PUT_BATCH_CONCURRENCY)
+    .collect()
+    .await
+}
+
+/// Put `items` to `inner` in a single `put_batch`, with their keys and values
+/// transformed by `map`, for wrappers. Items `map` fails for are not put, and
+/// get its error as result. Results have the keys of `items`.
+pub async fn put_batch_mapped<B: BlobstorePutOps + ?Sized>(
+    inner: &B,
+    ctx: &CoreContext,
+    items: Vec<(String, BlobstoreBytes)>,
+    mut map: impl FnMut(&str, BlobstoreBytes) -> Result<(String, BlobstoreBytes)>,
+) -> Result<BlobstorePutBatchResults> {
+    let mut keys = Vec::with_capacity(items.len());
+    let mut mapped = Vec::with_capacity(items.len());
+    let mut failed: Vec<(usize, Error)> = Vec::new();
+    for (key, value) in items {
+        match map(&key, value) {
+            Ok(item) => mapped.push(item),
+            Err(e) => failed.push((keys.len(), e)),
+        }
+        keys.push(key);
+    }
+
+    let mut results = inner
+        .put_batch(ctx, mapped)
+        .await?
+        .into_iter()
+        .map(|(_, result)| result);
+    let mut failed = failed.into_iter().peekable();
+    Ok(keys
+        .into_iter()
+        .enumerate()
+        .map(|(index, key)| {
+            let result = match failed.next_if(|(failed_index, _)| *failed_index == index) {
+                Some((_, e)) => Err(e),
+                None => results.next().unwrap_or_else(|| {
+                    Err(format_err!("put_batch returned no result for {}", key))
+                }),
+            };
+            (key, result)
+        })
+        .collect())
+}
@@ -0,0 +1,547 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Self-test of a configured blobstore stack, to run at startup so that a
+//! misconfigured stack is found before the first real request.
+//!
+//! The self-test writes, reads, overwrites, links and unlinks a few keys
+//! in a reserved namespace, timing each step. Failures are classified as
+//! connectivity, permission or data integrity problems, and the report can
+//! be serialized for startup logs and health endpoints.
+
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use anyhow::ensure;
+use anyhow::Error;
+use anyhow::Result;
+use bytes::Bytes;
+use context::CoreContext;
+use serde_derive::Serialize;
+use tokio::time::Instant;
+
+use crate::Blobstore;
+use crate::BlobstoreBytes;
+use crate::BlobstoreIsPresent;
+use crate::BlobstorePutOps;
+use crate::BlobstoreUnlinkOps;
+use crate::ErrorKind;
+use crate::OverwriteStatus;
+use crate::PutBehaviour;
+
+/// Lowercase fragments of error messages that indicate a permission problem.
+const PERMISSION_MESSAGES: &[&str] = &[
+    "permission denied",
+    "access denied",
+    "unauthorized",
+    "unauthenticated",
+    "forbidden",
+];
+
+/// Lowercase fragments of error messages that indicate a connectivity problem.
+const CONNECTIVITY_MESSAGES: &[&str] = &["connection", "unreachable", "timed out", "unavailable"];
+
+/// Lowercase fragments of error messages that indicate corrupt data.
+const DATA_INTEGRITY_MESSAGES: &[&str] = &["checksum", "corrupt", "mismatch", "missing"];
+
+#[derive(Clone, Debug)]
+pub struct SelfTestConfig {
+    /// Prefix of the keys written by the self-test. Nothing else should
+    /// write keys with this prefix. Each run adds a unique suffix.
+    pub key_prefix: String,
+    /// Size of the values written by the self-test. Values are compressible,
+    /// and should be larger than any chunk size of the stack, so that
+    /// compressing and chunking layers are exercised.
+    pub value_size: usize,
+    /// Deadline for the whole self-test. Steps that have not completed by
+    /// then fail with `FailureClass::Timeout`, and the rest are skipped.
+    pub deadline: Duration,
+}
+
+impl Default for SelfTestConfig {
+    fn default() -> Self {
+        Self {
+            key_prefix: "selftest.".to_string(),
+            value_size: 64 * 1024,
+            deadline: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A step of the self-test, in the order they run.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelfTestStep {
+    /// `put` a new value.
+    Put,
+    /// `is_present` finds the value.
+    IsPresent,
+    /// `get` returns the value put.
+    Get,
+    /// `put_explicit` with `IfAbsent` then `OverwriteAndLog` reports the
+    /// expected `OverwriteStatus`, and leaves the expected value.
+    Overwrite,
+    /// `copy` the value to another key, and `get` it from there.
+    Link,
+    /// `unlink` the copied key, which is then absent.
+    Unlink,
+    /// `unlink` the keys left by the self-test.
+    Cleanup,
+}
+
+/// Why a step failed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureClass {
+    /// The store or one of its backends could not be reached.
+    Connectivity,
+    /// The store was administratively disabled, see `DisabledBlob`.
+    Disabled,
+    /// The store refused the operation.
+    Permission,
+    /// The store returned data other than what was put, or lost it.
+    DataIntegrity,
+    /// The deadline of the self-test expired during the step.
+    Timeout,
+    Other,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum StepOutcome {
+    Passed,
+    Skipped { reason: String },
+    Failed { class: FailureClass, error: String },
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StepReport {
+    pub step: SelfTestStep,
+    #[serde(flatten)]
+    pub outcome: StepOutcome,
+    pub elapsed_ms: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SelfTestReport {
+    /// The store tested, as displayed.
+    pub blobstore: String,
+    /// The reserved key of this run. Other keys of the run start with it.
+    pub key: String,
+    /// Whether no step failed. Skipped steps do not count as failures.
+    pub passed: bool,
+    pub elapsed_ms: u64,
+    pub steps: Vec<StepReport>,
+}
+
+impl SelfTestReport {
+    pub fn failures(&self) -> impl Iterator<Item = &StepReport> {
+        self.steps
+            .iter()
+            .filter(|report| matches!(report.outcome, StepOutcome::Failed { .. }))
+    }
+
+    pub fn outcome(&self, step: SelfTestStep) -> Option<&StepOutcome> {
+        self.steps
+            .iter()
+            .find(|report| report.step == step)
+            .map(|report| &report.outcome)
+    }
+}
+
+/// Classifies an error returned by a blobstore, from the typed errors in its
+/// chain or, failing that, from its message.
+pub fn classify_error(error: &Error) -> FailureClass {
+    for cause in error.chain() {
+        if let Some(ErrorKind::Disabled(_)) = cause.downcast_ref::<ErrorKind>() {
+            return FailureClass::Disabled;
+        }
+        if let Some(error) = cause.downcast_ref::<io::Error>() {
+            match error.kind() {
+                io::ErrorKind::PermissionDenied => return FailureClass::Permission,
+                io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::AddrNotAvailable
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::TimedOut => return FailureClass::Connectivity,
+                io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+                    return FailureClass::DataIntegrity;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let message = format!("{:#}", error).to_lowercase();
+    let matches = |fragments: &[&str]| fragments.iter().any(|f| message.contains(f));
+    if matches(PERMISSION_MESSAGES) {
+        FailureClass::Permission
+    } else if matches(CONNECTIVITY_MESSAGES) {
+        FailureClass::Connectivity
+    } else if matches(DATA_INTEGRITY_MESSAGES) {
+        FailureClass::DataIntegrity
+    } else {
+        FailureClass::Other
+    }
+}
+
+/// Runs the self-test against `store`: put, is_present and get. Stores
+/// implementing `BlobstorePutOps` or `BlobstoreUnlinkOps` should be tested
+/// with `run_selftest_with_put_ops` or `run_selftest_with_unlink_ops`,
+/// which also check overwrites, links and clean up the reserved keys.
+///
+/// Failures of the store are part of the report. An error is only returned
+/// if `config` is invalid.
+pub async fn run_selftest(
+    ctx: &CoreContext,
+    store: &dyn Blobstore,
+    config: SelfTestConfig,
+) -> Result<SelfTestReport> {
+    run(ctx, store, None, None, config).await
+}
+
+/// Like `run_selftest`, also checking overwrites.
+pub async fn run_selftest_with_put_ops<B: BlobstorePutOps>(
+    ctx: &CoreContext,
+    store: &B,
+    config: SelfTestConfig,
+) -> Result<SelfTestReport> {
+    run(ctx, store, Some(store), None, config).await
+}
+
+/// Like `run_selftest`, also checking overwrites, links and unlinks, and
+/// unlinking the reserved keys at the end.
+pub async fn run_selftest_with_unlink_ops<B: BlobstoreUnlinkOps>(
+    ctx: &CoreContext,
+    store: &B,
+    config: SelfTestConfig,
+) -> Result<SelfTestReport> {
+    run(ctx, store, Some(store), Some(store), config).await
+}
+
+/// A failed step.
+struct StepFailure {
+    class: FailureClass,
+    error: String,
+}
+
+impl From<Error> for StepFailure {
+    fn from(error: Error) -> Self {
+        Self {
+            class: classify_error(&error),
+            error: format!("{:#}", error),
+        }
+    }
+}
+
+fn integrity_failure(error: String) -> StepFailure {
+    StepFailure {
+        class: FailureClass::DataIntegrity,
+        error,
+    }
+}
+
+struct Steps {
+    deadline: Instant,
+    timeout: Duration,
+    timed_out: bool,
+    reports: Vec<StepReport>,
+}
+
+impl Steps {
+    /// Runs a step, unless the deadline expired. Returns whether it passed.
+    async fn run(
+        &mut self,
+        step: SelfTestStep,
+        fut: impl Future<Output = Result<(), StepFailure>>,
+    ) -> bool {
+        if self.timed_out {
+            self.skip(step, "");
+            return false;
+        }
+        let start = Instant::now();
+        let outcome = match tokio::time::timeout_at(self.deadline, fut).await {
+            Ok(Ok(())) => StepOutcome::Passed,
+            Ok(Err(failure)) => StepOutcome::Failed {
+                class: failure.class,
+                error: failure.error,
+            },
+            Err(_) => {
+                self.timed_out = true;
+                StepOutcome::Failed {
+                    class: FailureClass::Timeout,
+                    error: format!("Self-test deadline of {:?} expired", self.timeout),
+                }
+            }
+        };
+        let passed = outcome == StepOutcome::Passed;
+        self.reports.push(StepReport {
+            step,
+            outcome,
+            elapsed_ms: start.elapsed().as_millis() as u64,
+        });
+        passed
+    }
+
+    fn skip(&mut self, step: SelfTestStep, reason: &str) {
+        let reason = if self.timed_out {
+            "Deadline expired"
+        } else {
+            reason
+        };
+        self.reports.push(StepReport {
+            step,
+            outcome: StepOutcome::Skipped {
+                reason: reason.to_string(),
+            },
+            elapsed_ms: 0,
+        });
+    }
+}
+
+/// A key that no other run, on any host, uses.
+fn reserved_key(prefix: &str) -> String {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    format!(
+        "{}{}.{}.{:016x}",
+        prefix,
+        now.as_secs(),
+        std::process::id(),
+        rand::random::<u64>()
+    )
+}
+
+/// A compressible value of `size` bytes, unique to this call.
+fn test_value(size: usize) -> BlobstoreBytes {
+    let token = format!("selftest-{:016x}\n", rand::random::<u64>());
+    let bytes: Vec<u8> = token.bytes().cycle().take(size).collect();
+    BlobstoreBytes::from_bytes(bytes)
+}
+
+/// Checks that `get` of `key` returns `expected`.
+async fn verify(
+    ctx: &CoreContext,
+    store: &dyn Blobstore,
+    key: &str,
+    expected: &Bytes,
+) -> Result<(), StepFailure> {
+    match store.get(ctx, key).await? {
+        None => Err(integrity_failure(format!("Key {} is missing", key))),
+        Some(data) => {
+            let actual = data.into_raw_bytes();
+            if actual == *expected {
+                Ok(())
+            } else {
+                Err(integrity_failure(format!(
+                    "Key {} has {} bytes that differ from the {} bytes put",
+                    key,
+                    actual.len(),
+                    expected.len()
+                )))
+            }
+        }
+    }
+}
+
+/// Checks that `put_explicit` reports the expected overwrite statuses for
+/// `key`, which has `value`, and leaves the expected value. Returns the
+/// value `key` has at the end.
+async fn overwrite(
+    ctx: &CoreContext,
+    store: &dyn Blobstore,
+    put_ops: &dyn BlobstorePutOps,
+    key: &str,
+    value: &Bytes,
+) -> Result<Bytes, StepFailure> {
+    let other = test_value(value.len().max(1));
+    match put_ops
+        .put_explicit(ctx, key.to_string(), other.clone(), PutBehaviour::IfAbsent)
+        .await?
+    {
+        OverwriteStatus::Prevented => verify(ctx, store, key, value).await?,
+        // The store may have overwritten the key, the next put does anyway.
+        OverwriteStatus::NotChecked => {}
+        status => {
+            return Err(integrity_failure(format!(
+                "Put of existing key {} with IfAbsent returned {:?}",
+                key, status
+            )));
+        }
+    }
+
+    let other = other.into_bytes();
+    let status = put_ops
+        .put_explicit(
+            ctx,
+            key.to_string(),
+            BlobstoreBytes::from_bytes(other.clone()),
+            PutBehaviour::OverwriteAndLog,
+        )
+        .await?;
+    match status {
+        OverwriteStatus::Overwrote | OverwriteStatus::NotChecked => {}
+        status => {
+            return Err(integrity_failure(format!(
+                "Put of existing key {} with OverwriteAndLog returned {:?}",
+                key, status
+            )));
+        }
+    }
+    verify(ctx, store, key, &other).await?;
+    Ok(other)
+}
+
+async fn check_absent(
+    ctx: &CoreContext,
+    store: &dyn Blobstore,
+    key: &str,
+) -> Result<(), StepFailure> {
+    match store.is_present(ctx, key).await? {
+        BlobstoreIsPresent::Absent => Ok(()),
+        BlobstoreIsPresent::Present => Err(integrity_failure(format!(
+            "Key {} is present after unlink",
+            key
+        ))),
+        BlobstoreIsPresent::ProbablyNotPresent(e) => Err(e.into()),
+    }
+}
+
+async fn run(
+    ctx: &CoreContext,
+    store: &dyn Blobstore,
+    put_ops: Option<&dyn BlobstorePutOps>,
+    unlink_ops: Option<&dyn BlobstoreUnlinkOps>,
+    config: SelfTestConfig,
+) -> Result<SelfTestReport> {
+    ensure!(
+        config.value_size > 0,
+        "Self-test value size must be positive"
+    );
+    ensure!(
+        !config.key_prefix.is_empty(),
+        "Self-test key prefix must not be empty"
+    );
+
+    let start = Instant::now();
+    let key = reserved_key(&config.key_prefix);
+    let link_key = format!("{}.link", key);
+    let value = test_value(config.value_size);
+    let mut expected = value.clone().into_bytes();
+    let mut steps = Steps {
+        deadline: start + config.deadline,
+        timeout: config.deadline,
+        timed_out: false,
+        reports: Vec::new(),
+    };
+
+    let stored = steps
+        .run(SelfTestStep::Put, async {
+            Ok(store.put(ctx, key.clone(), value).await?)
+        })
+        .await;
+
+    if stored {
+        steps
+            .run(SelfTestStep::IsPresent, async {
+                match store.is_present(ctx, &key).await? {
+                    BlobstoreIsPresent::Present => Ok(()),
+                    BlobstoreIsPresent::Absent => Err(integrity_failure(format!(
+                        "Key {} is absent after put",
+                        key
+                    ))),
+                    BlobstoreIsPresent::ProbablyNotPresent(e) => Err(e.into()),
+                }
+            })
+            .await;
+        steps
+            .run(SelfTestStep::Get, verify(ctx, store, &key, &expected))
+            .await;
+    } else {
+        steps.skip(SelfTestStep::IsPresent, "Put failed");
+        steps.skip(SelfTestStep::Get, "Put failed");
+    }
+
+    match put_ops {
+        Some(put_ops) if stored => {
+            let mut overwritten = None;
+            steps
+                .run(SelfTestStep::Overwrite, async {
+                    overwritten = Some(overwrite(ctx, store, put_ops, &key, &expected).await?);
+                    Ok(())
+                })
+                .await;
+            if let Some(overwritten) = overwritten {
+                expected = overwritten;
+            }
+        }
+        Some(_) => steps.skip(SelfTestStep::Overwrite, "Put failed"),
+        None => steps.skip(
+            SelfTestStep::Overwrite,
+            "Store does not implement BlobstorePutOps",
+        ),
+    }
+
+    match unlink_ops {
+        Some(unlink_ops) if stored => {
+            let linked = steps
+                .run(SelfTestStep::Link, async {
+                    store.copy(ctx, &key, link_key.clone()).await?;
+                    verify(ctx, store, &link_key, &expected).await
+                })
+                .await;
+            let unlinked = if linked {
+                steps
+                    .run(SelfTestStep::Unlink, async {
+                        unlink_ops.unlink(ctx, &link_key).await?;
+                        check_absent(ctx, store, &link_key).await
+                    })
+                    .await
+            } else {
+                steps.skip(SelfTestStep::Unlink, "Link failed");
+                false
+            };
+            steps
+                .run(SelfTestStep::Cleanup, async {
+                    // A failed link may still have written the copy.
+                    if !unlinked {
+                        let _ = unlink_ops.unlink(ctx, &link_key).await;
+                    }
+                    unlink_ops.unlink(ctx, &key).await?;
+                    check_absent(ctx, store, &key).await
+                })
+                .await;
+        }
+        Some(_) => {
+            steps.skip(SelfTestStep::Link, "Put failed");
+            steps.skip(SelfTestStep::Unlink, "Put failed");
+            steps.skip(SelfTestStep::Cleanup, "Put failed");
+        }
+        None => {
+            let reason = "Store does not implement BlobstoreUnlinkOps";
+            steps.skip(SelfTestStep::Link, reason);
+            steps.skip(SelfTestStep::Unlink, reason);
+            steps.skip(SelfTestStep::Cleanup, reason);
+        }
+    }
+
+    let reports = steps.reports;
+    Ok(SelfTestReport {
+        blobstore: store.to_string(),
+        key,
+        passed: !reports
+            .iter()
+            .any(|report| matches!(report.outcome, StepOutcome::Failed { .. })),
+        elapsed_ms: start.elapsed().as_millis() as u64,
+        steps: reports,
+    })
+}
@@ -0,0 +1,532 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Guessing what a blob contains from its bytes, for admin tooling that
+//! shows arbitrary blobs.
+//!
+//! Classification looks at the bytes only, not at the key, and never
+//! allocates. Formats the built-in checks do not know can be recognized by
+//! registering a `Matcher`.
+
+use std::fmt;
+use std::sync::RwLock;
+
+use anyhow::Result;
+use context::CoreContext;
+
+use crate::Blobstore;
+use crate::BlobstoreGetData;
+use crate::COMPRESSED;
+
+/// Prefix of the manifests `chunkingblob` stores in place of large blobs.
+/// Must match `chunkingblob`.
+const CHUNKING_MANIFEST_MAGIC: &[u8] = b"\0chunkingblob-manifest-v1\0";
+
+/// Header of `packblob` envelopes in thrift compact format.
+const PACKBLOB_COMPACT_HEADER: [u8; 4] = [0, 0, 0, 0];
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Gzip magic, followed by the deflate compression method.
+const GZIP_MAGIC: [u8; 3] = [0x1f, 0x8b, 0x08];
+
+/// Size of the smallest gzip file: header and trailer around an empty
+/// deflate stream.
+const GZIP_MIN_LEN: usize = 20;
+
+/// Nesting of thrift structs and containers beyond which a blob is not
+/// considered thrift.
+const MAX_THRIFT_DEPTH: usize = 32;
+
+/// Bytes `BlobStats::entropy` is estimated from.
+const ENTROPY_SAMPLE: usize = 1 << 20;
+
+static MATCHERS: RwLock<Vec<Matcher>> = RwLock::new(Vec::new());
+
+/// What a blob looks like it contains.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ContentClass {
+    /// No bytes at all.
+    Empty,
+    /// Recognized by the registered `Matcher` with this name.
+    Custom(&'static str),
+    /// A `chunkingblob` manifest, listing the chunks of a large blob.
+    ChunkingManifest,
+    /// A `packblob` envelope holding a single or packed blob.
+    PackblobEnvelope,
+    /// `BlobstoreBytes::encode` output, as stored in caches, compressed.
+    EncodedCompressed,
+    /// A zstd frame.
+    Zstd,
+    /// A gzip stream.
+    Gzip,
+    /// A struct in thrift binary protocol.
+    ThriftBinary,
+    /// A struct in thrift compact protocol, like most Mononoke blobs.
+    ThriftCompact,
+    /// UTF-8 text without control characters besides whitespace.
+    Text,
+    /// Anything else.
+    Binary,
+}
+
+impl fmt::Display for ContentClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ContentClass::Empty => "empty",
+            ContentClass::Custom(name) => *name,
+            ContentClass::ChunkingManifest => "chunking-manifest",
+            ContentClass::PackblobEnvelope => "packblob-envelope",
+            ContentClass::EncodedCompressed => "encoded-compressed",
+            ContentClass::Zstd => "zstd",
+            ContentClass::Gzip => "gzip",
+            ContentClass::ThriftBinary => "thrift-binary",
+            ContentClass::ThriftCompact => "thrift-compact",
+            ContentClass::Text => "text",
+            ContentClass::Binary => "binary",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A check for a format the built-in ones do not know. `matches` must not
+/// panic, and should not allocate.
+#[derive(Clone, Copy, Debug)]
+pub struct Matcher {
+    pub name: &'static str,
+    pub matches: fn(&[u8]) -> bool,
+}
+
+/// Make `classify` try `matcher` before the built-in checks, and after the
+/// matchers registered before it.
+pub fn register_matcher(matcher: Matcher) {
+    MATCHERS
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(matcher);
+}
+
+/// Classify `bytes`, trying the registered matchers first.
+pub fn classify(bytes: &[u8]) -> ContentClass {
+    let matchers = MATCHERS.read().unwrap_or_else(|e| e.into_inner());
+    classify_with(bytes, &matchers)
+}
+
+/// Classify `bytes`, trying `matchers` first.
+pub fn classify_with(bytes: &[u8], matchers: &[Matcher]) -> ContentClass {
+    if bytes.is_empty() {
+        return ContentClass::Empty;
+    }
+    if let Some(matcher) = matchers.iter().find(|m| (m.matches)(bytes)) {
+        return ContentClass::Custom(matcher.name);
+    }
+    if bytes.starts_with(CHUNKING_MANIFEST_MAGIC) {
+        ContentClass::ChunkingManifest
+    } else if bytes.starts_with(&ZSTD_MAGIC) {
+        ContentClass::Zstd
+    } else if bytes.starts_with(&GZIP_MAGIC) && bytes.len() >= GZIP_MIN_LEN {
+        ContentClass::Gzip
+    } else if bytes.first() == Some(&COMPRESSED) && bytes[1..].starts_with(&ZSTD_MAGIC) {
+        ContentClass::EncodedCompressed
+    } else if bytes
+        .strip_prefix(&PACKBLOB_COMPACT_HEADER)
+        .map_or(false, is_thrift_compact)
+    {
+        ContentClass::PackblobEnvelope
+    } else if is_thrift_binary(bytes) {
+        ContentClass::ThriftBinary
+    } else if is_thrift_compact(bytes) {
+        ContentClass::ThriftCompact
+    } else if is_text(bytes) {
+        ContentClass::Text
+    } else {
+        ContentClass::Binary
+    }
+}
+
+fn is_text(bytes: &[u8]) -> bool {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => !s
+            .chars()
+            .any(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r')),
+        Err(_) => false,
+    }
+}
+
+/// Whether `bytes` is exactly one thrift struct with at least one field, in
+/// compact protocol. Fields must be in increasing id order, as serializers
+/// write them.
+fn is_thrift_compact(bytes: &[u8]) -> bool {
+    let mut reader = Reader { bytes, pos: 0 };
+    bytes.len() > 1 && reader.compact_struct(0).is_some() && reader.remaining() == 0
+}
+
+/// Whether `bytes` is exactly one thrift struct with at least one field, in
+/// binary protocol. Fields must be in increasing id order.
+fn is_thrift_binary(bytes: &[u8]) -> bool {
+    let mut reader = Reader { bytes, pos: 0 };
+    bytes.len() > 1 && reader.binary_struct(0).is_some() && reader.remaining() == 0
+}
+
+/// Walks thrift without decoding it. Every method returns `None` if the
+/// bytes are not valid at this point.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        if len > self.remaining() {
+            return None;
+        }
+        self.pos += len;
+        Some(())
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.bytes.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn be_i16(&mut self) -> Option<i16> {
+        Some(i16::from_be_bytes([self.byte()?, self.byte()?]))
+    }
+
+    fn be_i32(&mut self) -> Option<i32> {
+        Some(i32::from_be_bytes([
+            self.byte()?,
+            self.byte()?,
+            self.byte()?,
+            self.byte()?,
+        ]))
+    }
+
+    /// Check that a container of `size` elements of at least `min_len` bytes
+    /// each fits in the rest of the bytes.
+    fn check_size(&self, size: u64, min_len: usize) -> Option<u64> {
+        let len = usize::try_from(size).ok()?.checked_mul(min_len)?;
+        (len <= self.remaining()).then_some(size)
+    }
+
+    fn compact_struct(&mut self, depth: usize) -> Option<()> {
+        if depth > MAX_THRIFT_DEPTH {
+            return None;
+        }
+        let mut last_id = 0i32;
+        loop {
+            let header = self.byte()?;
+            if header == 0 {
+                return Some(());
+            }
+            let id = match header >> 4 {
+                0 => {
+                    let zigzag = self.varint()?;
+                    let id = (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64);
+                    i32::try_from(id).ok()?
+                }
+                delta => last_id + i32::from(delta),
+            };
+            if id <= last_id || id > i32::from(i16::MAX) {
+                return None;
+            }
+            last_id = id;
+            match header & 0x0f {
+                // Booleans are in the field header.
+                1 | 2 => {}
+                kind => self.compact_value(kind, depth)?,
+            }
+        }
+    }
+
+    fn compact_value(&mut self, kind: u8, depth: usize) -> Option<()> {
+        match kind {
+            // Booleans outside field headers, in containers.
+            1 | 2 | 3 => self.skip(1),
+            4 | 5 | 6 => self.varint().map(drop),
+            7 => self.skip(8),
+            8 => {
+                let len = self.varint()?;
+                self.skip(usize::try_from(len).ok()?)
+            }
+            9 | 10 => {
+                let header = self.byte()?;
+                let size = match header >> 4 {
+                    15 => self.varint()?,
+                    size => u64::from(size),
+                };
+                let size = self.check_size(size, 1)?;
+                let element = header & 0x0f;
+                (0..size).try_for_each(|_| self.compact_value(element, depth + 1))
+            }
+            11 => {
+                let size = self.varint()?;
+                if size == 0 {
+                    return Some(());
+                }
+                let kinds = self.byte()?;
+                let size = self.check_size(size, 2)?;
+                (0..size).try_for_each(|_| {
+                    self.compact_value(kinds >> 4, depth + 1)?;
+                    self.compact_value(kinds & 0x0f, depth + 1)
+                })
+            }
+            12 => self.compact_struct(depth + 1),
+            _ => None,
+        }
+    }
+
+    fn binary_struct(&mut self, depth: usize) -> Option<()> {
+        if depth > MAX_THRIFT_DEPTH {
+            return None;
+        }
+        let mut last_id = 0;
+        loop {
+            let kind = self.byte()?;
+            if kind == 0 {
+                return Some(());
+            }
+            let id = self.be_i16()?;
+            if id <= last_id {
+                return None;
+            }
+            last_id = id;
+            self.binary_value(kind, depth)?;
+        }
+    }
+
+    fn binary_value(&mut self, kind: u8, depth: usize) -> Option<()> {
+        match kind {
+            2 | 3 => self.skip(1),
+            4 | 10 => self.skip(8),
+            6 => self.skip(2),
+            8 => self.skip(4),
+            11 => {
+                let len = self.be_i32()?;
+                self.skip(usize::try_from(len).ok()?)
+            }
+            12 => self.binary_struct(depth + 1),
+            13 => {
+                let key = self.byte()?;
+                let value = self.byte()?;
+                let size = u64::try_from(self.be_i32()?).ok()?;
+                let size = self.check_size(size, 2)?;
+                (0..size).try_for_each(|_| {
+                    self.binary_value(key, depth + 1)?;
+                    self.binary_value(value, depth + 1)
+                })
+            }
+            14 | 15 => {
+                let element = self.byte()?;
+                let size = u64::try_from(self.be_i32()?).ok()?;
+                let size = self.check_size(size, 1)?;
+                (0..size).try_for_each(|_| self.binary_value(element, depth + 1))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Basic statistics about the bytes of a blob.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlobStats {
+    pub size: usize,
+    /// Shannon entropy in bits per byte, from 0 to 8, estimated from the
+    /// first MiB. Compressed or encrypted data is close to 8.
+    pub entropy: f64,
+}
+
+impl BlobStats {
+    pub fn new(bytes: &[u8]) -> Self {
+        let sample = &bytes[..bytes.len().min(ENTROPY_SAMPLE)];
+        let mut counts = [0u64; 256];
+        for byte in sample {
+            counts[*byte as usize] += 1;
+        }
+        let total = sample.len() as f64;
+        let entropy = counts
+            .iter()
+            .filter(|count| **count > 0)
+            .map(|count| {
+                let p = *count as f64 / total;
+                p * (1.0 / p).log2()
+            })
+            .sum();
+        BlobStats {
+            size: bytes.len(),
+            entropy,
+        }
+    }
+}
+
+/// A blob with what its bytes look like.
+#[derive(Clone, Debug)]
+pub struct InspectedBlob {
+    pub data: BlobstoreGetData,
+    pub class: ContentClass,
+    pub stats: BlobStats,
+}
+
+impl InspectedBlob {
+    pub fn new(data: BlobstoreGetData) -> Self {
+        let bytes = data.as_raw_bytes();
+        InspectedBlob {
+            class: classify(bytes),
+            stats: BlobStats::new(bytes),
+            data,
+        }
+    }
+}
+
+/// Get `key` from `blobstore`, with its metadata, content class and stats.
+pub async fn get_inspected<B: Blobstore + ?Sized>(
+    blobstore: &B,
+    ctx: &CoreContext,
+    key: &str,
+) -> Result<Option<InspectedBlob>> {
+    Ok(blobstore.get(ctx, key).await?.map(InspectedBlob::new))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BlobstoreBytes;
+
+    /// `{1: binary "hello", 2: i64 5, 3: list<i32> [1, 2]}`
+    const COMPACT: &[u8] = b"\x18\x05hello\x16\x0a\x19\x25\x02\x04\x00";
+
+    /// `{1: string "hi", 2: i32 7}`
+    const BINARY: &[u8] = b"\x0b\x00\x01\x00\x00\x00\x02hi\x08\x00\x02\x00\x00\x00\x07\x00";
+
+    /// Gzip of nothing.
+    const GZIP: &[u8] = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x00\xff\x03\x00\
+        \x00\x00\x00\x00\x00\x00\x00\x00";
+
+    #[test]
+    fn test_classify() {
+        let cases: Vec<(Vec<u8>, ContentClass)> = vec![
+            (vec![], ContentClass::Empty),
+            (COMPACT.to_vec(), ContentClass::ThriftCompact),
+            (BINARY.to_vec(), ContentClass::ThriftBinary),
+            (zstd::encode_all(COMPACT, 0).unwrap(), ContentClass::Zstd),
+            (GZIP.to_vec(), ContentClass::Gzip),
+            (
+                [CHUNKING_MANIFEST_MAGIC, b"{\"chunks\": []}".as_slice()].concat(),
+                ContentClass::ChunkingManifest,
+            ),
+            (
+                [PACKBLOB_COMPACT_HEADER.as_slice(), COMPACT].concat(),
+                ContentClass::PackblobEnvelope,
+            ),
+            (
+                BlobstoreBytes::from_bytes(COMPACT)
+                    .encode(Some(0))
+                    .unwrap()
+                    .to_vec(),
+                ContentClass::EncodedCompressed,
+            ),
+            (
+                "hello wörld\n\tbye\r\n".as_bytes().to_vec(),
+                ContentClass::Text,
+            ),
+            (b"\xff\xfe\x00\x01binary".to_vec(), ContentClass::Binary),
+            (b"text\x07bell".to_vec(), ContentClass::Binary),
+            // Truncated or with trailing bytes.
+            (COMPACT[..COMPACT.len() - 1].to_vec(), ContentClass::Binary),
+            ([COMPACT, b"\x00".as_slice()].concat(), ContentClass::Binary),
+            (BINARY[..BINARY.len() - 1].to_vec(), ContentClass::Binary),
+            (GZIP[..10].to_vec(), ContentClass::Binary),
+        ];
+        for (bytes, class) in cases {
+            assert_eq!(classify_with(&bytes, &[]), class, "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_matchers() {
+        let matcher = Matcher {
+            name: "test-format",
+            matches: |bytes| bytes.starts_with(b"\x89SNIFFTEST"),
+        };
+        let bytes = [b"\x89SNIFFTEST".as_slice(), COMPACT].concat();
+        assert_eq!(classify_with(&bytes, &[]), ContentClass::Binary);
+        assert_eq!(
+            classify_with(&bytes, &[matcher]),
+            ContentClass::Custom("test-format")
+        );
+
+        register_matcher(matcher);
+        assert_eq!(classify(&bytes), ContentClass::Custom("test-format"));
+        assert_eq!(classify(COMPACT), ContentClass::ThriftCompact);
+        assert_eq!(
+            ContentClass::Custom("test-format").to_string(),
+            "test-format"
+        );
+    }
+
+    #[test]
+    fn test_random_bytes() {
+        // splitmix64, so the test is deterministic without extra dependencies.
+        let mut state = 0x5eed_u64;
+        let mut next = move || {
+            state = state.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^ (z >> 31)
+        };
+        for _ in 0..256 {
+            let len = 64 + (next() % 4096) as usize;
+            let bytes: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            assert_eq!(classify_with(&bytes, &[]), ContentClass::Binary);
+            // Every prefix is classified without panicking.
+            for end in (0..len).step_by(97) {
+                classify_with(&bytes[..end], &[]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = BlobStats::new(b"");
+        assert_eq!(stats.size, 0);
+        assert_eq!(stats.entropy, 0.0);
+
+        let stats = BlobStats::new(b"aaaa");
+        assert_eq!(stats.size, 4);
+        assert_eq!(stats.entropy, 0.0);
+
+        let all: Vec<u8> = (0..=255).collect();
+        assert_eq!(BlobStats::new(&all).entropy, 8.0);
+        assert_eq!(BlobStats::new(b"abab").entropy, 1.0);
+    }
+
+    #[test]
+    fn test_inspected_blob() {
+        let inspected = InspectedBlob::new(BlobstoreGetData::from_bytes(COMPACT));
+        assert_eq!(inspected.class, ContentClass::ThriftCompact);
+        assert_eq!(inspected.stats.size, COMPACT.len());
+        assert_eq!(inspected.data.as_raw_bytes().as_ref(), COMPACT);
+    }
+}
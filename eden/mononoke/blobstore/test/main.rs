@@ -10,21 +10,65 @@
 #![cfg_attr(not(fbcode_build), allow(unused_crate_dependencies))]
 #![feature(never_type)]
 
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::num::NonZeroU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
 
+use anyhow::format_err;
 use anyhow::Error;
+use anyhow::Result;
+use async_trait::async_trait;
+use blobstore::copy_keys;
+use blobstore::enumerate_all;
+use blobstore::get_many_by_key;
+use blobstore::get_many_until;
+use blobstore::put_batch_by_key;
+use blobstore::put_batch_mapped;
+use blobstore::selftest::classify_error;
+use blobstore::selftest::run_selftest;
+use blobstore::selftest::run_selftest_with_unlink_ops;
+use blobstore::selftest::FailureClass;
+use blobstore::selftest::SelfTestConfig;
+use blobstore::selftest::SelfTestStep;
+use blobstore::selftest::StepOutcome;
 use blobstore::Blobstore;
+use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreGetManyData;
+use blobstore::BlobstoreIsPresent;
+use blobstore::BlobstoreKeyParam;
+use blobstore::BlobstoreKeySource;
+use blobstore::BlobstorePutBatchResults;
 use blobstore::BlobstorePutOps;
 use blobstore::BlobstoreUnlinkOps;
+use blobstore::CopyKeysOptions;
+use blobstore::DisabledBlob;
+use blobstore::GetManyUntilOptions;
+use blobstore::KeyTransform;
 use blobstore::OverwriteStatus;
+use blobstore::PartialGetSummary;
 use blobstore::PutBehaviour;
+use blobstore_stats::COMPLETION_TIME;
+use blobstore_stats::KEY_COUNT;
 use borrowed::borrowed;
 use bytes::Bytes;
+use chunkingblob::ChunkingBlob;
 use context::CoreContext;
 use fbinit::FacebookInit;
 use fileblob::Fileblob;
+use futures::TryStreamExt;
+use logblob::LogBlob;
 use memblob::Memblob;
+use metaconfig_types::PackFormat;
 use mononoke_types::BlobstoreBytes;
+use packblob::PackBlob;
+use scuba_ext::MononokeScubaSampleBuilder;
 use sqlblob::get_test_config_store;
 use sqlblob::Sqlblob;
 use strum::IntoEnumIterator;
@@ -228,6 +272,112 @@ blobstore_test_impl! {
     }
 }
 
+#[fbinit::test]
+async fn test_memblob_ttl(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+
+    let now = Arc::new(Mutex::new(Instant::now()));
+    let clock = now.clone();
+    let blobstore =
+        Memblob::new(PutBehaviour::IfAbsent).with_clock(Arc::new(move || *clock.lock().unwrap()));
+    let advance = |by: Duration| *now.lock().unwrap() += by;
+
+    let value = BlobstoreBytes::from_bytes(Bytes::copy_from_slice(b"snapshot"));
+    let ttl = Duration::from_secs(5 * 60);
+    let status = blobstore
+        .put_with_ttl(ctx, "ephemeral".to_string(), value.clone(), ttl)
+        .await?;
+    assert_eq!(status, OverwriteStatus::New);
+    blobstore
+        .put(ctx, "permanent".to_string(), value.clone())
+        .await?;
+    blobstore.copy(ctx, "ephemeral", "link".to_string()).await?;
+
+    advance(Duration::from_secs(4 * 60));
+    let fetched = blobstore.get(ctx, "ephemeral").await?;
+    assert_eq!(fetched.map(|d| d.into_bytes()), Some(value.clone()));
+    assert!(matches!(
+        blobstore.is_present(ctx, "link").await?,
+        BlobstoreIsPresent::Present
+    ));
+
+    advance(Duration::from_secs(60));
+    assert!(blobstore.get(ctx, "ephemeral").await?.is_none());
+    // Links expire with the blob.
+    assert!(blobstore.get(ctx, "link").await?.is_none());
+    assert!(matches!(
+        blobstore.is_present(ctx, "ephemeral").await?,
+        BlobstoreIsPresent::Absent
+    ));
+    assert!(blobstore.get(ctx, "permanent").await?.is_some());
+
+    // An expired key can be written again.
+    let status = blobstore
+        .put_with_status(ctx, "ephemeral".to_string(), value)
+        .await?;
+    assert_eq!(status, OverwriteStatus::New);
+    advance(Duration::from_secs(3600));
+    assert!(blobstore.get(ctx, "ephemeral").await?.is_some());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_memblob_enumerate_pages(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+
+    let blobstore = Memblob::new(PutBehaviour::IfAbsent).with_enumeration_page_size(7);
+    let value = BlobstoreBytes::from_bytes(Bytes::copy_from_slice(b"value"));
+    let mut expected = Vec::new();
+    for repo in 0..3 {
+        for i in 0..50 {
+            for kind in ["content", "hgchangeset"] {
+                let key = format!("repo{:04}.{}.blake2.{:04}", repo, kind, i);
+                if repo == 1 && kind == "content" {
+                    expected.push(key.clone());
+                }
+                blobstore.put(ctx, key, value.clone()).await?;
+            }
+        }
+    }
+
+    // Tokens can be saved and used to resume.
+    let first = blobstore
+        .enumerate(ctx, &BlobstoreKeyParam::prefix("repo0001.content."))
+        .await?;
+    assert_eq!(first.keys.len(), 7);
+    let token = first.next_token.expect("more pages");
+    let token: BlobstoreKeyParam = serde_json::from_str(&serde_json::to_string(&token)?)?;
+    let second = blobstore.enumerate(ctx, &token).await?;
+    let mut second_keys: Vec<_> = second.keys.into_iter().collect();
+    second_keys.sort();
+    assert_eq!(second_keys, expected[7..14]);
+
+    let pages: Vec<_> = enumerate_all(
+        ctx.clone(),
+        blobstore.clone(),
+        BlobstoreKeyParam::prefix("repo0001.content."),
+        2,
+    )
+    .try_collect()
+    .await?;
+    assert_eq!(pages.len(), 8);
+    let mut keys = Vec::new();
+    for page in pages {
+        assert!(page.keys.len() <= 7);
+        let mut page_keys: Vec<_> = page.keys.into_iter().collect();
+        page_keys.sort();
+        // Pages are in key order.
+        if let (Some(last), Some(first)) = (keys.last(), page_keys.first()) {
+            assert!(last < first);
+        }
+        keys.extend(page_keys);
+    }
+    assert_eq!(keys, expected);
+    Ok(())
+}
+
 blobstore_test_impl! {
     box_blobstore_test => {
         state: (),
@@ -375,3 +525,882 @@ async fn cache_blob_tests(fb: FacebookInit, expect_zstd: bool) -> Result<(), Err
 
     Ok(())
 }
+
+/// Fails gets of the `failing` keys. With `batched`, `get_many` reads the
+/// other keys with a single `get_many` of the inner store.
+#[derive(Debug)]
+struct ScriptedBlob {
+    inner: Memblob,
+    failing: HashSet<String>,
+    batched: bool,
+}
+
+impl fmt::Display for ScriptedBlob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ScriptedBlob")
+    }
+}
+
+#[async_trait]
+impl Blobstore for ScriptedBlob {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        if self.failing.contains(key) {
+            return Err(format_err!("scripted failure for {}", key));
+        }
+        self.inner.get(ctx, key).await
+    }
+
+    async fn get_many<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        keys: &'a [&'a str],
+    ) -> Result<BlobstoreGetManyData> {
+        if !self.batched {
+            return Ok(get_many_by_key(self, ctx, keys).await);
+        }
+        let (failing, ok): (Vec<&str>, Vec<&str>) = keys
+            .iter()
+            .copied()
+            .partition(|key| self.failing.contains(*key));
+        let mut results = self.inner.get_many(ctx, &ok).await?;
+        for key in failing {
+            results.insert(
+                key.to_string(),
+                Err(format_err!("scripted failure for {}", key)),
+            );
+        }
+        Ok(results)
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        self.inner.put(ctx, key, value).await
+    }
+}
+
+/// `get_many` results in a comparable form.
+fn comparable(results: BlobstoreGetManyData) -> HashMap<String, Result<Option<Bytes>, String>> {
+    results
+        .into_iter()
+        .map(|(key, result)| {
+            let result = result
+                .map(|data| data.map(|data| data.into_raw_bytes()))
+                .map_err(|e| e.to_string());
+            (key, result)
+        })
+        .collect()
+}
+
+#[fbinit::test]
+async fn test_get_many(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+
+    let inner = Memblob::default();
+    for i in 0..20 {
+        let value = BlobstoreBytes::from_bytes(format!("value{}", i));
+        inner.put(ctx, format!("key{}", i), value).await?;
+    }
+    let keys: Vec<String> = (10..30).map(|i| format!("key{}", i)).collect();
+    let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+
+    // Memblob's batched read matches single gets.
+    let batched = comparable(inner.get_many(ctx, &keys).await?);
+    let single = comparable(get_many_by_key(&inner, ctx, &keys).await);
+    assert_eq!(batched, single);
+
+    let failing = HashSet::from(["key15".to_string()]);
+    let fan_out = ScriptedBlob {
+        inner: inner.clone(),
+        failing: failing.clone(),
+        batched: false,
+    };
+    let batched = ScriptedBlob {
+        inner,
+        failing,
+        batched: true,
+    };
+    let fan_out = comparable(fan_out.get_many(ctx, &keys).await?);
+    assert_eq!(fan_out, comparable(batched.get_many(ctx, &keys).await?));
+
+    assert_eq!(fan_out.len(), 20);
+    assert_eq!(fan_out["key12"], Ok(Some(Bytes::from("value12"))));
+    assert_eq!(fan_out["key25"], Ok(None));
+    assert_eq!(
+        fan_out["key15"],
+        Err("scripted failure for key15".to_string())
+    );
+    let hits = fan_out
+        .values()
+        .filter(|result| matches!(result, Ok(Some(_))))
+        .count();
+    assert_eq!(hits, 9);
+    Ok(())
+}
+
+/// Delays gets of the `slow` keys.
+#[derive(Debug)]
+struct SlowBlob {
+    inner: Memblob,
+    slow: HashMap<String, Duration>,
+}
+
+impl fmt::Display for SlowBlob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SlowBlob")
+    }
+}
+
+#[async_trait]
+impl Blobstore for SlowBlob {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        if let Some(delay) = self.slow.get(key) {
+            tokio::time::sleep(*delay).await;
+        }
+        self.inner.get(ctx, key).await
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        self.inner.put(ctx, key, value).await
+    }
+}
+
+#[fbinit::test]
+async fn test_get_many_until(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+
+    let inner = Memblob::default();
+    for key in ["fast1", "fast2", "fast3", "slow1", "slow2"] {
+        let value = BlobstoreBytes::from_bytes(key);
+        inner.put(ctx, key.to_string(), value).await?;
+    }
+    let keys = ["fast1", "absent", "slow1", "fast2", "slow2", "fast3"];
+    let options = GetManyUntilOptions {
+        concurrency: 2,
+        batch_size: 1,
+        prioritized: true,
+    };
+
+    // Both slots are taken by slow keys, so the keys after them are not
+    // attempted.
+    let slow = [
+        ("slow1", Duration::from_secs(600)),
+        ("slow2", Duration::from_secs(600)),
+    ];
+    let blobstore = SlowBlob {
+        inner: inner.clone(),
+        slow: slow.iter().map(|(k, d)| (k.to_string(), *d)).collect(),
+    };
+    let deadline = Instant::now() + Duration::from_millis(200);
+    let results = get_many_until(ctx, &blobstore, &keys, deadline, &options).await;
+    assert!(!results.is_complete());
+    assert_eq!(
+        results.found.keys().collect::<HashSet<_>>(),
+        HashSet::from([&"fast1".to_string(), &"fast2".to_string()])
+    );
+    assert_eq!(results.missing, HashSet::from(["absent".to_string()]));
+    assert_eq!(
+        results.timed_out,
+        HashSet::from(["slow1".to_string(), "slow2".to_string()])
+    );
+    assert_eq!(results.not_attempted, HashSet::from(["fast3".to_string()]));
+    assert_eq!(
+        results.summary(),
+        PartialGetSummary {
+            found: 2,
+            missing: 1,
+            failed: 0,
+            timed_out: 2,
+            not_attempted: 1,
+            elapsed: results.elapsed,
+        }
+    );
+    assert!(results.elapsed >= Duration::from_millis(200));
+
+    // A generous deadline returns everything.
+    let slow = [
+        ("slow1", Duration::from_millis(20)),
+        ("slow2", Duration::from_millis(20)),
+    ];
+    let blobstore = SlowBlob {
+        inner,
+        slow: slow.iter().map(|(k, d)| (k.to_string(), *d)).collect(),
+    };
+    let deadline = Instant::now() + Duration::from_secs(600);
+    let results = get_many_until(ctx, &blobstore, &keys, deadline, &options).await;
+    assert!(results.is_complete());
+    assert_eq!(results.found.len(), 5);
+    assert_eq!(
+        results.found["slow2"].clone().into_raw_bytes(),
+        Bytes::from("slow2")
+    );
+    assert_eq!(results.missing.len(), 1);
+    assert!(results.failed.is_empty());
+    Ok(())
+}
+
+fn selftest_config(value_size: usize) -> SelfTestConfig {
+    SelfTestConfig {
+        key_prefix: "selftest.test.".to_string(),
+        value_size,
+        deadline: Duration::from_secs(60),
+    }
+}
+
+#[fbinit::test]
+async fn test_selftest_memblob(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+    let memblob = Memblob::new(PutBehaviour::IfAbsent);
+
+    let report = run_selftest_with_unlink_ops(ctx, &memblob, selftest_config(1024)).await?;
+    assert!(report.passed, "{:?}", report);
+    let steps: Vec<_> = report.steps.iter().map(|report| report.step).collect();
+    assert_eq!(
+        steps,
+        [
+            SelfTestStep::Put,
+            SelfTestStep::IsPresent,
+            SelfTestStep::Get,
+            SelfTestStep::Overwrite,
+            SelfTestStep::Link,
+            SelfTestStep::Unlink,
+            SelfTestStep::Cleanup,
+        ]
+    );
+    assert!(
+        report
+            .steps
+            .iter()
+            .all(|report| report.outcome == StepOutcome::Passed)
+    );
+    assert!(report.key.starts_with("selftest.test."));
+    // The reserved keys were cleaned up.
+    assert!(
+        !memblob
+            .is_present(ctx, &report.key)
+            .await?
+            .fail_if_unsure()?
+    );
+
+    let json = serde_json::to_value(&report)?;
+    assert_eq!(json["passed"], serde_json::json!(true));
+    assert_eq!(json["steps"][3]["step"], serde_json::json!("overwrite"));
+    assert_eq!(json["steps"][3]["status"], serde_json::json!("passed"));
+
+    // Without the put and unlink ops, the steps needing them are skipped.
+    let plain = run_selftest(ctx, &memblob, selftest_config(1024)).await?;
+    assert!(plain.passed);
+    assert_ne!(plain.key, report.key);
+    assert_eq!(plain.outcome(SelfTestStep::Get), Some(&StepOutcome::Passed));
+    assert!(matches!(
+        plain.outcome(SelfTestStep::Overwrite),
+        Some(StepOutcome::Skipped { .. })
+    ));
+    assert!(matches!(
+        plain.outcome(SelfTestStep::Cleanup),
+        Some(StepOutcome::Skipped { .. })
+    ));
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_selftest_disabled(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let disabled = DisabledBlob::new("test");
+
+    let report = run_selftest_with_unlink_ops(&ctx, &disabled, selftest_config(1024)).await?;
+    assert!(!report.passed);
+    let failures: Vec<_> = report.failures().collect();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].step, SelfTestStep::Put);
+    match &failures[0].outcome {
+        StepOutcome::Failed { class, error } => {
+            assert_eq!(*class, FailureClass::Disabled);
+            assert!(error.contains("Blobstore disabled: test"), "{}", error);
+        }
+        outcome => panic!("Unexpected outcome {:?}", outcome),
+    }
+    assert!(matches!(
+        report.outcome(SelfTestStep::Get),
+        Some(StepOutcome::Skipped { .. })
+    ));
+
+    assert_eq!(
+        classify_error(&format_err!("Connection refused by host")),
+        FailureClass::Connectivity
+    );
+    assert_eq!(
+        classify_error(&Error::from(std::io::Error::from(
+            std::io::ErrorKind::PermissionDenied
+        ))),
+        FailureClass::Permission
+    );
+    Ok(())
+}
+
+/// Records the size of the values put in the inner store.
+#[derive(Debug)]
+struct RecordingBlob {
+    inner: Memblob,
+    puts: Mutex<Vec<(String, usize)>>,
+}
+
+impl fmt::Display for RecordingBlob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RecordingBlob")
+    }
+}
+
+impl RecordingBlob {
+    fn record(&self, key: &str, value: &BlobstoreBytes) {
+        self.puts
+            .lock()
+            .unwrap()
+            .push((key.to_string(), value.len()));
+    }
+}
+
+#[async_trait]
+impl Blobstore for RecordingBlob {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        self.inner.get(ctx, key).await
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        self.record(&key, &value);
+        self.inner.put(ctx, key, value).await
+    }
+
+    async fn is_present<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreIsPresent> {
+        self.inner.is_present(ctx, key).await
+    }
+}
+
+#[async_trait]
+impl BlobstorePutOps for RecordingBlob {
+    async fn put_explicit<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+    ) -> Result<OverwriteStatus> {
+        self.record(&key, &value);
+        self.inner
+            .put_explicit(ctx, key, value, put_behaviour)
+            .await
+    }
+
+    async fn put_with_status<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<OverwriteStatus> {
+        self.record(&key, &value);
+        self.inner.put_with_status(ctx, key, value).await
+    }
+}
+
+#[async_trait]
+impl BlobstoreUnlinkOps for RecordingBlob {
+    async fn unlink<'a>(&'a self, ctx: &'a CoreContext, key: &'a str) -> Result<()> {
+        BlobstoreUnlinkOps::unlink(&self.inner, ctx, key).await
+    }
+}
+
+#[fbinit::test]
+async fn test_selftest_wrapper_stack(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+    let recording = Arc::new(RecordingBlob {
+        inner: Memblob::new(PutBehaviour::IfAbsent),
+        puts: Mutex::new(Vec::new()),
+    });
+    let chunk_size = 1024;
+    let stack = ChunkingBlob::new(
+        PackBlob::new(recording.clone(), PackFormat::ZstdIndividual(0)),
+        chunk_size,
+        4,
+    )
+    .with_overwrite_cleanup();
+
+    let report = run_selftest_with_unlink_ops(ctx, &stack, selftest_config(16 * 1024)).await?;
+    assert!(report.passed, "{:?}", report);
+    assert_eq!(report.failures().count(), 0);
+
+    // The values were split into chunks, which were compressed.
+    let puts = recording.puts.lock().unwrap().clone();
+    let chunks: Vec<_> = puts
+        .iter()
+        .filter(|(key, _)| key.starts_with(&report.key) && key.contains(".chunk."))
+        .collect();
+    assert!(chunks.len() >= 16, "{:?}", puts);
+    assert!(chunks.iter().all(|(_, size)| *size < chunk_size));
+
+    // Chunks were cleaned up along with the manifests.
+    for (key, _) in &puts {
+        assert!(
+            !recording.is_present(ctx, key).await?.fail_if_unsure()?,
+            "{} was not cleaned up",
+            key
+        );
+    }
+    Ok(())
+}
+
+/// A memblob with 30 keys under `old.` and 5 under `old.skip.`, enumerated
+/// 7 keys at a time.
+async fn copy_source(ctx: &CoreContext) -> Result<Memblob, Error> {
+    let source = Memblob::new(PutBehaviour::IfAbsent).with_enumeration_page_size(7);
+    for i in 0..30 {
+        let value = BlobstoreBytes::from_bytes(format!("value {}", i));
+        source.put(ctx, format!("old.{:04}", i), value).await?;
+    }
+    for i in 0..5 {
+        let value = BlobstoreBytes::from_bytes(format!("skipped {}", i));
+        source.put(ctx, format!("old.skip.{:04}", i), value).await?;
+    }
+    Ok(source)
+}
+
+/// Renames `old.` keys to `new.`, skipping `old.skip.` keys.
+fn rename_old_to_new() -> KeyTransform {
+    Arc::new(|key| {
+        let rest = key.strip_prefix("old.")?;
+        if rest.starts_with("skip.") {
+            None
+        } else {
+            Some(format!("new.{}", rest))
+        }
+    })
+}
+
+#[fbinit::test]
+async fn test_copy_keys(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+    let source = copy_source(ctx).await?;
+    let dest = Memblob::new(PutBehaviour::IfAbsent);
+
+    let report = copy_keys(
+        ctx,
+        &source,
+        &dest,
+        BlobstoreKeyParam::prefix("old."),
+        rename_old_to_new(),
+        &CopyKeysOptions::default(),
+    )
+    .await?;
+    assert_eq!(report.copied, 30);
+    assert_eq!(report.skipped, 5);
+    assert_eq!(report.failed, 0);
+    let expected_bytes: usize = (0..30).map(|i| format!("value {}", i).len()).sum();
+    assert_eq!(report.bytes, expected_bytes as u64);
+    let value = dest.get(ctx, "new.0012").await?.expect("copied");
+    assert_eq!(value.as_raw_bytes(), &Bytes::from("value 12"));
+    assert!(dest.get(ctx, "old.0012").await?.is_none());
+    assert!(dest.get(ctx, "new.skip.0001").await?.is_none());
+
+    // A dry run counts without writing.
+    let dry_dest = Memblob::new(PutBehaviour::IfAbsent);
+    let options = CopyKeysOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    let report = copy_keys(
+        ctx,
+        &source,
+        &dry_dest,
+        BlobstoreKeyParam::prefix("old."),
+        rename_old_to_new(),
+        &options,
+    )
+    .await?;
+    assert_eq!((report.copied, report.skipped, report.bytes), (30, 5, 0));
+    assert!(dry_dest.get(ctx, "new.0012").await?.is_none());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_copy_keys_resume(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+    let source = copy_source(ctx).await?;
+    let dest = Memblob::new(PutBehaviour::IfAbsent);
+
+    // The job is killed after its second checkpoint.
+    let saved = Arc::new(Mutex::new(Vec::new()));
+    let options = CopyKeysOptions {
+        skip_existing: true,
+        checkpoint: Some(Arc::new({
+            let saved = saved.clone();
+            move |token| {
+                let mut saved = saved.lock().unwrap();
+                saved.push(serde_json::to_string(token)?);
+                if saved.len() == 2 {
+                    return Err(format_err!("killed"));
+                }
+                Ok(())
+            }
+        })),
+        ..Default::default()
+    };
+    let transform: KeyTransform = Arc::new(|key| Some(format!("new.{}", key)));
+    let range = BlobstoreKeyParam::prefix("old.");
+    let err = copy_keys(
+        ctx,
+        &source,
+        &dest,
+        range.clone(),
+        transform.clone(),
+        &options,
+    )
+    .await
+    .unwrap_err();
+    assert!(format!("{:#}", err).contains("killed"));
+
+    // Resuming copies the remaining keys only.
+    let token: BlobstoreKeyParam = serde_json::from_str(&saved.lock().unwrap()[1])?;
+    let options = CopyKeysOptions {
+        skip_existing: true,
+        ..Default::default()
+    };
+    let report = copy_keys(ctx, &source, &dest, token, transform.clone(), &options).await?;
+    assert_eq!(
+        (report.copied, report.skipped, report.failed),
+        (35 - 14, 0, 0)
+    );
+    for i in 0..30 {
+        assert!(dest.get(ctx, &format!("new.old.{:04}", i)).await?.is_some());
+    }
+
+    // Running again from the start copies nothing.
+    let report = copy_keys(ctx, &source, &dest, range, transform, &options).await?;
+    assert_eq!((report.copied, report.skipped), (0, 35));
+    Ok(())
+}
+
+/// Corrupts the values put under `corrupted` keys.
+#[derive(Debug)]
+struct CorruptingBlob {
+    inner: Memblob,
+    corrupted: HashSet<String>,
+}
+
+impl fmt::Display for CorruptingBlob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CorruptingBlob")
+    }
+}
+
+#[async_trait]
+impl Blobstore for CorruptingBlob {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        self.inner.get(ctx, key).await
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        let value = if self.corrupted.contains(&key) {
+            let mut bytes = value.into_bytes().to_vec();
+            bytes[0] ^= 0xff;
+            BlobstoreBytes::from_bytes(bytes)
+        } else {
+            value
+        };
+        self.inner.put(ctx, key, value).await
+    }
+
+    async fn is_present<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreIsPresent> {
+        self.inner.is_present(ctx, key).await
+    }
+}
+
+#[fbinit::test]
+async fn test_copy_keys_verify(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+    let source = copy_source(ctx).await?;
+    let new_dest = || CorruptingBlob {
+        inner: Memblob::new(PutBehaviour::IfAbsent),
+        corrupted: HashSet::from(["new.0003".to_string()]),
+    };
+    let range = BlobstoreKeyParam::prefix("old.");
+
+    // Without verification, the corruption goes unnoticed.
+    let report = copy_keys(
+        ctx,
+        &source,
+        &new_dest(),
+        range.clone(),
+        rename_old_to_new(),
+        &CopyKeysOptions::default(),
+    )
+    .await?;
+    assert_eq!((report.copied, report.failed), (30, 0));
+
+    let options = CopyKeysOptions {
+        verify: true,
+        ..Default::default()
+    };
+    let report = copy_keys(
+        ctx,
+        &source,
+        &new_dest(),
+        range,
+        rename_old_to_new(),
+        &options,
+    )
+    .await?;
+    assert_eq!((report.copied, report.failed), (29, 1));
+    assert_eq!(report.failure_samples.len(), 1);
+    let (key, error) = &report.failure_samples[0];
+    assert_eq!(key, "old.0003");
+    assert!(error.contains("differs from the source"), "{}", error);
+    Ok(())
+}
+
+/// Refuses values over `max_size`, and counts the single puts and the batches
+/// reaching it, and the locks of its memory store.
+#[derive(Debug)]
+struct SizeLimitedBlob {
+    inner: Memblob,
+    max_size: usize,
+    puts: AtomicUsize,
+    batches: AtomicUsize,
+    locks: Arc<AtomicUsize>,
+}
+
+impl fmt::Display for SizeLimitedBlob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SizeLimitedBlob")
+    }
+}
+
+impl SizeLimitedBlob {
+    fn new(max_size: usize) -> Self {
+        let locks = Arc::new(AtomicUsize::new(0));
+        let clock_reads = locks.clone();
+        // Memblob reads its clock each time it locks the blobs.
+        let clock = Arc::new(move || {
+            clock_reads.fetch_add(1, Ordering::Relaxed);
+            Instant::now()
+        });
+        Self {
+            inner: Memblob::default().with_clock(clock),
+            max_size,
+            puts: AtomicUsize::new(0),
+            batches: AtomicUsize::new(0),
+            locks,
+        }
+    }
+
+    fn check(&self, key: &str, value: &BlobstoreBytes) -> Result<()> {
+        if value.len() > self.max_size {
+            return Err(format_err!("{} is too large: {} bytes", key, value.len()));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Blobstore for SizeLimitedBlob {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        self.inner.get(ctx, key).await
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        self.put_with_status(ctx, key, value).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlobstorePutOps for SizeLimitedBlob {
+    async fn put_explicit<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+    ) -> Result<OverwriteStatus> {
+        self.puts.fetch_add(1, Ordering::Relaxed);
+        self.check(&key, &value)?;
+        self.inner
+            .put_explicit(ctx, key, value, put_behaviour)
+            .await
+    }
+
+    async fn put_with_status<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<OverwriteStatus> {
+        self.puts.fetch_add(1, Ordering::Relaxed);
+        self.check(&key, &value)?;
+        self.inner.put_with_status(ctx, key, value).await
+    }
+
+    async fn put_batch<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        items: Vec<(String, BlobstoreBytes)>,
+    ) -> Result<BlobstorePutBatchResults> {
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        put_batch_mapped(&self.inner, ctx, items, |key, value| {
+            self.check(key, &value)?;
+            Ok((key.to_string(), value))
+        })
+        .await
+    }
+}
+
+/// `put_batch` results in a comparable form.
+fn comparable_puts(
+    results: BlobstorePutBatchResults,
+) -> Vec<(String, Result<OverwriteStatus, String>)> {
+    results
+        .into_iter()
+        .map(|(key, result)| (key, result.map_err(|e| e.to_string())))
+        .collect()
+}
+
+#[fbinit::test]
+async fn test_put_batch(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+
+    // Small blobs, but for one too large for `SizeLimitedBlob`.
+    let items: Vec<(String, BlobstoreBytes)> = (0..500)
+        .map(|i| {
+            let value = if i == 250 {
+                vec![b'x'; 1000]
+            } else {
+                format!("value{}", i).into_bytes()
+            };
+            (format!("key{:03}", i), BlobstoreBytes::from_bytes(value))
+        })
+        .collect();
+
+    let batched_inner = Arc::new(SizeLimitedBlob::new(100));
+    let batched = PackBlob::new(batched_inner.clone(), PackFormat::Raw);
+    let results = batched.put_batch(ctx, items.clone()).await?;
+    // The batch went through the wrapper to the memory store as a whole,
+    // which locked the blobs once.
+    assert_eq!(batched_inner.batches.load(Ordering::Relaxed), 1);
+    assert_eq!(batched_inner.puts.load(Ordering::Relaxed), 0);
+    assert_eq!(batched_inner.locks.load(Ordering::Relaxed), 1);
+
+    assert_eq!(results.len(), 500);
+    for (i, (key, result)) in results.iter().enumerate() {
+        assert_eq!(key, &items[i].0);
+        match result {
+            Err(e) if i == 250 => assert!(e.to_string().contains("too large"), "{}", e),
+            Ok(_) if i != 250 => {}
+            _ => panic!("unexpected result for {}: {:?}", key, result),
+        }
+    }
+
+    // Same results as single puts.
+    let fan_out_inner = Arc::new(SizeLimitedBlob::new(100));
+    let fan_out = PackBlob::new(fan_out_inner.clone(), PackFormat::Raw);
+    let fan_out_results = put_batch_by_key(&fan_out, ctx, items.clone()).await;
+    assert_eq!(fan_out_inner.batches.load(Ordering::Relaxed), 0);
+    assert_eq!(fan_out_inner.puts.load(Ordering::Relaxed), 500);
+    assert_eq!(fan_out_inner.locks.load(Ordering::Relaxed), 499);
+    assert_eq!(comparable_puts(results), comparable_puts(fan_out_results));
+    for (key, _) in &items {
+        let batched = batched
+            .get(ctx, key)
+            .await?
+            .map(|data| data.into_raw_bytes());
+        let fan_out = fan_out
+            .get(ctx, key)
+            .await?
+            .map(|data| data.into_raw_bytes());
+        assert_eq!(batched, fan_out);
+    }
+    assert!(batched.get(ctx, "key250").await?.is_none());
+
+    // LogBlob logs one sample for the batch, with the time the whole batch
+    // took.
+    let dir = TempDir::new("put_batch")?;
+    let log_file = dir.path().join("scuba.json");
+    let scuba = MononokeScubaSampleBuilder::with_discard().with_log_file(&log_file)?;
+    let logged = LogBlob::new(SizeLimitedBlob::new(100), scuba, NonZeroU64::new(1).unwrap());
+    let start = Instant::now();
+    logged.put_batch(ctx, items).await?;
+    let max_latency = start.elapsed();
+    let samples = std::fs::read_to_string(&log_file)?;
+    let samples: Vec<_> = samples.lines().collect();
+    assert_eq!(samples.len(), 1);
+    assert!(samples[0].contains(&format!("\"{}\":500", KEY_COUNT)));
+    let completion_time = int_field(samples[0], COMPLETION_TIME).unwrap();
+    assert!(completion_time <= max_latency.as_micros() as u64);
+    Ok(())
+}
+
+/// The value of the integer field `name` of a logged sample.
+fn int_field(sample: &str, name: &str) -> Option<u64> {
+    let start = sample.find(&format!("\"{}\":", name))? + name.len() + 3;
+    let digits: String = sample[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse().ok()
+}
@@ -11,6 +11,7 @@ use std::fmt;
 use std::future::Future;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 use std::time::SystemTime;
 
 use anyhow::bail;
@@ -222,3 +223,90 @@ impl BlobstoreWal for Tickable<BlobstoreWalEntry> {
         self.delete(ctx, entries).await
     }
 }
+
+/// Records the TTL of every put, to check that wrappers pass it through.
+/// Blobs never expire.
+#[derive(Clone, Debug, Default)]
+pub struct TtlSpy {
+    storage: Arc<Mutex<HashMap<String, BlobstoreBytes>>>,
+    puts: Arc<Mutex<Vec<(String, Option<Duration>)>>>,
+}
+
+impl fmt::Display for TtlSpy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TtlSpy")
+    }
+}
+
+impl TtlSpy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The key and TTL of every put so far, in order.
+    pub fn puts(&self) -> Vec<(String, Option<Duration>)> {
+        self.puts.with(|p| p.clone())
+    }
+
+    fn record(&self, key: String, value: BlobstoreBytes, ttl: Option<Duration>) -> OverwriteStatus {
+        self.puts.with(|p| p.push((key.clone(), ttl)));
+        self.storage.with(|s| s.insert(key, value));
+        OverwriteStatus::NotChecked
+    }
+}
+
+#[async_trait]
+impl Blobstore for TtlSpy {
+    async fn get<'a>(
+        &'a self,
+        _ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        Ok(self
+            .storage
+            .with(|s| s.get(key).cloned())
+            .map(|v| BlobstoreGetData::new(BlobstoreMetadata::new(None, None), v)))
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        BlobstorePutOps::put_with_status(self, ctx, key, value).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlobstorePutOps for TtlSpy {
+    async fn put_explicit<'a>(
+        &'a self,
+        _ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        _put_behaviour: PutBehaviour,
+    ) -> Result<OverwriteStatus> {
+        Ok(self.record(key, value, None))
+    }
+
+    async fn put_with_status<'a>(
+        &'a self,
+        _ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<OverwriteStatus> {
+        Ok(self.record(key, value, None))
+    }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        _ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        Ok(self.record(key, value, Some(ttl)))
+    }
+}
@@ -8,17 +8,23 @@
 use std::fmt;
 use std::num::NonZeroU32;
 use std::num::NonZeroUsize;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Result;
 use async_trait::async_trait;
 use blobstore::Blobstore;
 use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreGetManyData;
+use blobstore::BlobstoreGetWithProvenance;
 use blobstore::BlobstoreIsPresent;
 use blobstore::BlobstorePutOps;
 use blobstore::OverwriteStatus;
 use blobstore::PutBehaviour;
 use context::CoreContext;
+use context::RequestClass;
 use governor::clock::DefaultClock;
 use governor::state::direct::NotKeyed;
 use governor::state::InMemoryState;
@@ -37,6 +43,38 @@ pub struct ThrottleOptions {
     pub read_burst_bytes: Option<NonZeroUsize>,
     pub write_burst_bytes: Option<NonZeroUsize>,
     pub bytes_min_count: Option<NonZeroUsize>,
+    /// Split the limits between request classes, instead of sharing them.
+    pub class_shares: Option<RequestClassShares>,
+}
+
+/// Relative weights of the request classes. Each class is limited to the
+/// share of every limit proportional to its weight, or 1 qps or
+/// `bytes_min_count` bytes/s if that is less. Classes do not borrow the
+/// unused share of others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestClassShares {
+    pub interactive: u32,
+    pub background: u32,
+    pub scavenger: u32,
+}
+
+impl RequestClassShares {
+    fn weight(&self, class: RequestClass) -> u64 {
+        match class {
+            RequestClass::Interactive => self.interactive as u64,
+            RequestClass::Background => self.background as u64,
+            RequestClass::Scavenger => self.scavenger as u64,
+        }
+    }
+
+    /// The share of `limit` of `class`.
+    fn share(&self, class: RequestClass, limit: u64) -> u64 {
+        let total = self.interactive as u64 + self.background as u64 + self.scavenger as u64;
+        if total == 0 {
+            return limit;
+        }
+        (limit as u128 * self.weight(class) as u128 / total as u128) as u64
+    }
 }
 
 impl ThrottleOptions {
@@ -61,14 +99,69 @@ pub const DEFAULT_BYTES_MIN_COUNT: usize = 1_000;
 // Default is set high as we'd rather throttle than error unless specified
 pub const DEFAULT_BURST_BYTES_S: usize = 100_000_000;
 
+type Limiter = RateLimiter<NotKeyed, InMemoryState, DefaultClock>;
+
+/// The rate limiters of a request class.
+struct Limiters {
+    read_qps: Option<Limiter>,
+    write_qps: Option<Limiter>,
+    read_bytes: Option<Limiter>,
+    write_bytes: Option<Limiter>,
+}
+
+impl Limiters {
+    /// Limiters for the limits of `options`, scaled by `share`.
+    fn new(options: &ThrottleOptions, bytes_min_count: usize, share: impl Fn(u64) -> u64) -> Self {
+        let qps_limiter = |qps: Option<NonZeroU32>| {
+            qps.map(|qps| {
+                let qps = NonZeroU32::new(share(qps.get() as u64) as u32).unwrap_or(nonzero!(1u32));
+                RateLimiter::direct(Quota::per_second(qps))
+            })
+        };
+        let bytes_limiter = |bytes_s: Option<NonZeroUsize>, burst_bytes_s: Option<NonZeroUsize>| {
+            bytes_s.map(|bytes_s| {
+                let bytes_s = share(bytes_s.get() as u64) as usize;
+                let count_s = bytes_to_count(bytes_min_count, bytes_s);
+                // The burst is the largest blob that can be throttled, so it
+                // is not shared.
+                RateLimiter::direct(Quota::per_second(count_s).allow_burst(
+                    burst_bytes_s.map_or_else(
+                        || bytes_to_count(bytes_min_count, DEFAULT_BURST_BYTES_S),
+                        |burst_bytes_s| bytes_to_count(bytes_min_count, burst_bytes_s.get()),
+                    ),
+                ))
+            })
+        };
+        Self {
+            read_qps: qps_limiter(options.read_qps),
+            write_qps: qps_limiter(options.write_qps),
+            read_bytes: bytes_limiter(options.read_bytes, options.read_burst_bytes),
+            write_bytes: bytes_limiter(options.write_bytes, options.write_burst_bytes),
+        }
+    }
+}
+
+/// Operations admitted by a `ThrottledBlob`, once their limiters were ready,
+/// over all request classes.
+#[derive(Debug, Default)]
+pub struct ThrottleCounters {
+    /// Keys read, by get, get_many or is_present.
+    pub reads: AtomicU64,
+    /// Values written.
+    pub writes: AtomicU64,
+}
+
 /// A Blobstore that rate limits the number of read and write operations.
+/// With `class_shares`, each `RequestClass` has its own limits, so
+/// background work cannot starve interactive requests.
 pub struct ThrottledBlob<T: fmt::Debug> {
     blobstore: T,
-    read_qps_limiter: Option<RateLimiter<NotKeyed, InMemoryState, DefaultClock>>,
-    write_qps_limiter: Option<RateLimiter<NotKeyed, InMemoryState, DefaultClock>>,
-    read_bytes_limiter: Option<RateLimiter<NotKeyed, InMemoryState, DefaultClock>>,
-    write_bytes_limiter: Option<RateLimiter<NotKeyed, InMemoryState, DefaultClock>>,
+    /// The same limiters, unless the options have `class_shares`.
+    interactive: Arc<Limiters>,
+    background: Arc<Limiters>,
+    scavenger: Arc<Limiters>,
     bytes_min_count: usize,
+    counters: Arc<ThrottleCounters>,
     /// The options fields are used for Debug. They are not consulted at runtime.
     options: ThrottleOptions,
 }
@@ -87,43 +180,71 @@ fn jitter() -> Jitter {
 
 impl<T: fmt::Debug + Send + Sync> ThrottledBlob<T> {
     pub async fn new(blobstore: T, options: ThrottleOptions) -> Self {
-        let qps_limiter =
-            |qps: Option<NonZeroU32>| qps.map(|qps| RateLimiter::direct(Quota::per_second(qps)));
-        let read_qps_limiter = qps_limiter(options.read_qps);
-        let write_qps_limiter = qps_limiter(options.write_qps);
-
         let bytes_min_count = options
             .bytes_min_count
             .map_or(DEFAULT_BYTES_MIN_COUNT, |v| v.get());
-        let bytes_limiter = |bytes_s: Option<NonZeroUsize>, burst_bytes_s: Option<NonZeroUsize>| {
-            bytes_s.map(|bytes_s| {
-                let count_s = bytes_to_count(bytes_min_count, bytes_s.get());
-                RateLimiter::direct(Quota::per_second(count_s).allow_burst(
-                    burst_bytes_s.map_or_else(
-                        || bytes_to_count(bytes_min_count, DEFAULT_BURST_BYTES_S),
-                        |burst_bytes_s| bytes_to_count(bytes_min_count, burst_bytes_s.get()),
-                    ),
-                ))
-            })
+        let limiters = |class| {
+            Arc::new(Limiters::new(
+                &options,
+                bytes_min_count,
+                |limit| match options.class_shares {
+                    Some(shares) => shares.share(class, limit),
+                    None => limit,
+                },
+            ))
+        };
+        let interactive = limiters(RequestClass::Interactive);
+        let (background, scavenger) = match options.class_shares {
+            Some(_) => (
+                limiters(RequestClass::Background),
+                limiters(RequestClass::Scavenger),
+            ),
+            None => (interactive.clone(), interactive.clone()),
         };
-        let read_bytes_limiter = bytes_limiter(options.read_bytes, options.read_burst_bytes);
-        let write_bytes_limiter = bytes_limiter(options.write_bytes, options.write_burst_bytes);
 
         Self {
             blobstore,
-            read_qps_limiter,
-            write_qps_limiter,
-            read_bytes_limiter,
-            write_bytes_limiter,
+            interactive,
+            background,
+            scavenger,
             bytes_min_count,
+            counters: Default::default(),
             options,
         }
     }
 
+    /// The counters, to keep reading them once the blobstore is wrapped or
+    /// type-erased.
+    pub fn shared_counters(&self) -> Arc<ThrottleCounters> {
+        self.counters.clone()
+    }
+
+    fn limiters(&self, ctx: &CoreContext) -> &Limiters {
+        match ctx.request_class() {
+            RequestClass::Interactive => &self.interactive,
+            RequestClass::Background => &self.background,
+            RequestClass::Scavenger => &self.scavenger,
+        }
+    }
+
     // Convert from number of bytes to the count to request from until_n_ready
     fn count_n(&self, num_bytes: usize) -> NonZeroU32 {
         bytes_to_count(self.bytes_min_count, num_bytes)
     }
+
+    async fn wait_for_write(&self, ctx: &CoreContext, num_bytes: usize) -> Result<()> {
+        let limiters = self.limiters(ctx);
+        if let Some(limiter) = limiters.write_qps.as_ref() {
+            limiter.until_ready_with_jitter(jitter()).await;
+        }
+        if let Some(limiter) = limiters.write_bytes.as_ref() {
+            limiter
+                .until_n_ready_with_jitter(self.count_n(num_bytes), jitter())
+                .await?;
+        }
+        self.counters.writes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -134,19 +255,29 @@ impl<T: Blobstore> Blobstore for ThrottledBlob<T> {
         ctx: &'a CoreContext,
         key: &'a str,
     ) -> Result<Option<BlobstoreGetData>> {
-        if let Some(limiter) = self.read_qps_limiter.as_ref() {
+        Ok(self.get_with_provenance(ctx, key).await?.data)
+    }
+
+    async fn get_with_provenance<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreGetWithProvenance> {
+        let limiters = self.limiters(ctx);
+        if let Some(limiter) = limiters.read_qps.as_ref() {
             limiter.until_ready_with_jitter(jitter()).await;
         }
-        if let Some(limiter) = self.read_bytes_limiter.as_ref() {
+        if let Some(limiter) = limiters.read_bytes.as_ref() {
             // Only know we'll use some bytes. Access one count so we throttle if already over the limit
             limiter.until_ready_with_jitter(jitter()).await;
         }
+        self.counters.reads.fetch_add(1, Ordering::Relaxed);
 
-        let get_data = self.blobstore.get(ctx, key).await?;
+        let get_data = self.blobstore.get_with_provenance(ctx, key).await?;
 
-        if let Some(limiter) = self.read_bytes_limiter.as_ref() {
+        if let Some(limiter) = limiters.read_bytes.as_ref() {
             // Now we know the size, request rest of the quota
-            if let Some(data) = get_data.as_ref() {
+            if let Some(data) = get_data.data.as_ref() {
                 let count_n = self.count_n(data.as_bytes().len());
                 let adjusted_n = NonZeroU32::new(count_n.get().saturating_sub(1));
                 if let Some(adjusted_n) = adjusted_n {
@@ -159,20 +290,49 @@ impl<T: Blobstore> Blobstore for ThrottledBlob<T> {
         Ok(get_data)
     }
 
+    async fn get_many<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        keys: &'a [&'a str],
+    ) -> Result<BlobstoreGetManyData> {
+        let limiters = self.limiters(ctx);
+        // Each key counts as a get, as if it was fetched on its own.
+        for _ in keys {
+            if let Some(limiter) = limiters.read_qps.as_ref() {
+                limiter.until_ready_with_jitter(jitter()).await;
+            }
+            if let Some(limiter) = limiters.read_bytes.as_ref() {
+                limiter.until_ready_with_jitter(jitter()).await;
+            }
+        }
+        self.counters.reads.fetch_add(keys.len() as u64, Ordering::Relaxed);
+
+        let results = self.blobstore.get_many(ctx, keys).await?;
+
+        if let Some(limiter) = limiters.read_bytes.as_ref() {
+            for data in results
+                .values()
+                .filter_map(|result| result.as_ref().ok()?.as_ref())
+            {
+                let count_n = self.count_n(data.as_bytes().len());
+                let adjusted_n = NonZeroU32::new(count_n.get().saturating_sub(1));
+                if let Some(adjusted_n) = adjusted_n {
+                    limiter
+                        .until_n_ready_with_jitter(adjusted_n, jitter())
+                        .await?;
+                }
+            }
+        }
+        Ok(results)
+    }
+
     async fn put<'a>(
         &'a self,
         ctx: &'a CoreContext,
         key: String,
         value: BlobstoreBytes,
     ) -> Result<()> {
-        if let Some(limiter) = self.write_qps_limiter.as_ref() {
-            limiter.until_ready_with_jitter(jitter()).await;
-        }
-        if let Some(limiter) = self.write_bytes_limiter.as_ref() {
-            limiter
-                .until_n_ready_with_jitter(self.count_n(value.len()), jitter())
-                .await?;
-        }
+        self.wait_for_write(ctx, value.len()).await?;
         self.blobstore.put(ctx, key, value).await
     }
 
@@ -181,14 +341,16 @@ impl<T: Blobstore> Blobstore for ThrottledBlob<T> {
         ctx: &'a CoreContext,
         key: &'a str,
     ) -> Result<BlobstoreIsPresent> {
-        if let Some(limiter) = self.read_qps_limiter.as_ref() {
+        let limiters = self.limiters(ctx);
+        if let Some(limiter) = limiters.read_qps.as_ref() {
             limiter.until_ready_with_jitter(jitter()).await;
         }
         // TODO(ahornby) would need to enhance Blobstore::is_present() to know how many bytes it transferred.
         // Some stores fetch just a flag, some fetch all the data then throw it away.
-        if let Some(limiter) = self.read_bytes_limiter.as_ref() {
+        if let Some(limiter) = limiters.read_bytes.as_ref() {
             limiter.until_ready_with_jitter(jitter()).await;
         }
+        self.counters.reads.fetch_add(1, Ordering::Relaxed);
         self.blobstore.is_present(ctx, key).await
     }
 }
@@ -202,14 +364,7 @@ impl<T: BlobstorePutOps> BlobstorePutOps for ThrottledBlob<T> {
         value: BlobstoreBytes,
         put_behaviour: PutBehaviour,
     ) -> Result<OverwriteStatus> {
-        if let Some(limiter) = self.write_qps_limiter.as_ref() {
-            limiter.until_ready_with_jitter(jitter()).await;
-        }
-        if let Some(limiter) = self.write_bytes_limiter.as_ref() {
-            limiter
-                .until_n_ready_with_jitter(self.count_n(value.len()), jitter())
-                .await?;
-        }
+        self.wait_for_write(ctx, value.len()).await?;
         self.blobstore
             .put_explicit(ctx, key, value, put_behaviour)
             .await
@@ -221,16 +376,20 @@ impl<T: BlobstorePutOps> BlobstorePutOps for ThrottledBlob<T> {
         key: String,
         value: BlobstoreBytes,
     ) -> Result<OverwriteStatus> {
-        if let Some(limiter) = self.write_qps_limiter.as_ref() {
-            limiter.until_ready_with_jitter(jitter()).await;
-        }
-        if let Some(limiter) = self.write_bytes_limiter.as_ref() {
-            limiter
-                .until_n_ready_with_jitter(self.count_n(value.len()), jitter())
-                .await?;
-        }
+        self.wait_for_write(ctx, value.len()).await?;
         self.blobstore.put_with_status(ctx, key, value).await
     }
+
+    async fn put_with_ttl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        self.wait_for_write(ctx, value.len()).await?;
+        self.blobstore.put_with_ttl(ctx, key, value, ttl).await
+    }
 }
 
 impl<T: fmt::Debug> fmt::Debug for ThrottledBlob<T> {
@@ -238,6 +397,113 @@ impl<T: fmt::Debug> fmt::Debug for ThrottledBlob<T> {
         f.debug_struct("ThrottledBlob")
             .field("blobstore", &self.blobstore)
             .field("options", &self.options)
+            .field("counters", &self.counters)
             .finish()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::time::Instant;
+
+    use context::with_request_class;
+    use fbinit::FacebookInit;
+    use memblob::Memblob;
+
+    use super::*;
+
+    // The limiters wait on the real clock, so these tests do too, with
+    // bounds loose enough not to be flaky.
+    #[fbinit::test]
+    async fn test_class_shares(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let background = with_request_class(&ctx, RequestClass::Background);
+        let options = ThrottleOptions {
+            read_qps: NonZeroU32::new(200),
+            class_shares: Some(RequestClassShares {
+                interactive: 1,
+                background: 1,
+                scavenger: 0,
+            }),
+            ..Default::default()
+        };
+        let blob = Arc::new(ThrottledBlob::new(Memblob::default(), options).await);
+
+        // Saturate the background share: 100 qps.
+        let stop = Arc::new(AtomicBool::new(false));
+        let background_gets = Arc::new(AtomicUsize::new(0));
+        let load: Vec<_> = (0..16)
+            .map(|_| {
+                let (blob, ctx) = (blob.clone(), background.clone());
+                let (stop, gets) = (stop.clone(), background_gets.clone());
+                tokio::spawn(async move {
+                    while !stop.load(Ordering::Relaxed) {
+                        blob.get(&ctx, "key").await?;
+                        gets.fetch_add(1, Ordering::Relaxed);
+                    }
+                    anyhow::Ok(())
+                })
+            })
+            .collect();
+        let load_start = Instant::now();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // The interactive share is 100 qps, with a burst of 100: the 50 gets
+        // over the burst take about 0.5s, whatever the background load.
+        let start = Instant::now();
+        for _ in 0..150 {
+            blob.get(&ctx, "key").await?;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(300), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+
+        stop.store(true, Ordering::Relaxed);
+        for task in load {
+            task.await??;
+        }
+        // And background gets were limited to their share.
+        let allowed = 100.0 + 100.0 * load_start.elapsed().as_secs_f64() + 16.0;
+        let gets = background_gets.load(Ordering::Relaxed);
+        assert!((gets as f64) <= allowed, "{} > {}", gets, allowed);
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_shared_without_class_shares(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let scavenger = with_request_class(&ctx, RequestClass::Scavenger);
+        let options = ThrottleOptions {
+            read_qps: NonZeroU32::new(20),
+            ..Default::default()
+        };
+        let blob = ThrottledBlob::new(Memblob::default(), options).await;
+
+        // The scavenger drains the burst of all classes.
+        for _ in 0..20 {
+            blob.get(&scavenger, "key").await?;
+        }
+        let start = Instant::now();
+        for _ in 0..5 {
+            blob.get(&ctx, "key").await?;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        Ok(())
+    }
+
+    #[test]
+    fn test_share() {
+        let shares = RequestClassShares {
+            interactive: 6,
+            background: 3,
+            scavenger: 1,
+        };
+        assert_eq!(shares.share(RequestClass::Interactive, 1000), 600);
+        assert_eq!(shares.share(RequestClass::Background, 1000), 300);
+        assert_eq!(shares.share(RequestClass::Scavenger, 1000), 100);
+        assert_eq!(shares.share(RequestClass::Scavenger, 5), 0);
+    }
+}
@@ -0,0 +1,742 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use async_trait::async_trait;
+use blobstore::Blobstore;
+use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreIsPresent;
+use blobstore::BlobstorePutOps;
+use blobstore::BlobstoreUnlinkOps;
+use blobstore::OverwriteStatus;
+use blobstore::PutBehaviour;
+use context::CoreContext;
+use mononoke_types::BlobstoreBytes;
+use mononoke_types::Timestamp;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+
+/// Marks a value stored under the original key as a version head.
+const HEAD_MAGIC: &[u8] = b"\0versionedblob-head-v1\0";
+
+/// How many times a put writes the head again after a concurrent writer
+/// replaced it, or picks another version after a version key was taken.
+const MAX_ATTEMPTS: usize = 10;
+
+/// How long the blob of a version dropped from the head is kept, for readers
+/// and concurrent writers of a head that still lists it.
+const UNLINK_GRACE: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Error)]
+pub enum ErrorKind {
+    #[error("Version {version} of {key} is missing")]
+    MissingVersion { key: String, version: u64 },
+    #[error("Gave up updating the head of {key} after {attempts} concurrent updates")]
+    HeadConflict { key: String, attempts: usize },
+}
+
+/// Which value of a key `VersionedBlob::get_version` reads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VersionSelector {
+    /// The current value, what `get` returns.
+    Latest,
+    /// The value current at that time: the last one put at or before it.
+    AtTimestamp(Timestamp),
+    /// The `n`th value before the current one. `Nth(0)` is the current one.
+    Nth(usize),
+}
+
+/// A version of a key, as listed by `VersionedBlob::list_versions`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlobVersion {
+    pub version: u64,
+    /// When the version was put.
+    pub timestamp: Timestamp,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct VersionEntry {
+    version: u64,
+    timestamp_nanos: i64,
+}
+
+/// A version dropped from the head, whose blob is not removed yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct DroppedVersion {
+    version: u64,
+    dropped_at_nanos: i64,
+}
+
+/// Stored under the original key, lists the retained versions.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct VersionHead {
+    /// Oldest first. The last one is the current value.
+    versions: Vec<VersionEntry>,
+    /// Removed once `UNLINK_GRACE` passed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    dropped: Vec<DroppedVersion>,
+}
+
+impl VersionHead {
+    fn encode(&self) -> Result<BlobstoreBytes> {
+        let mut encoded = HEAD_MAGIC.to_vec();
+        serde_json::to_writer(&mut encoded, self)?;
+        Ok(BlobstoreBytes::from_bytes(encoded))
+    }
+
+    /// Returns `None` if `bytes` is not a head.
+    fn decode(key: &str, bytes: &[u8]) -> Result<Option<Self>> {
+        match bytes.strip_prefix(HEAD_MAGIC) {
+            None => Ok(None),
+            Some(json) => {
+                let head = serde_json::from_slice(json)
+                    .with_context(|| format!("Invalid version head for {}", key))?;
+                Ok(Some(head))
+            }
+        }
+    }
+
+    fn latest(&self) -> Option<&VersionEntry> {
+        self.versions.last()
+    }
+
+    fn contains(&self, version: u64) -> bool {
+        self.versions.iter().any(|e| e.version == version)
+    }
+
+    /// Add `entry`, and drop the oldest entries beyond `max_versions` at
+    /// `now_nanos`.
+    fn insert(&mut self, entry: VersionEntry, max_versions: usize, now_nanos: i64) {
+        if !self.contains(entry.version) {
+            self.versions.push(entry);
+            self.versions.sort_by_key(|e| e.version);
+        }
+        let excess = self.versions.len().saturating_sub(max_versions);
+        let dropped: Vec<_> = self.versions.drain(..excess).collect();
+        for entry in dropped {
+            if !self.dropped.iter().any(|d| d.version == entry.version) {
+                self.dropped.push(DroppedVersion {
+                    version: entry.version,
+                    dropped_at_nanos: now_nanos,
+                });
+            }
+        }
+    }
+
+    /// Forget the dropped versions whose grace period passed at `now_nanos`,
+    /// and return them.
+    fn take_expired(&mut self, now_nanos: i64) -> Vec<u64> {
+        let grace = UNLINK_GRACE.as_nanos() as i64;
+        let (expired, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.dropped)
+            .into_iter()
+            .partition(|d| d.dropped_at_nanos.saturating_add(grace) <= now_nanos);
+        self.dropped = kept;
+        expired.into_iter().map(|d| d.version).collect()
+    }
+
+    fn select(&self, selector: VersionSelector) -> Option<&VersionEntry> {
+        match selector {
+            VersionSelector::Latest => self.latest(),
+            VersionSelector::AtTimestamp(timestamp) => {
+                let nanos = timestamp.timestamp_nanos();
+                self.versions
+                    .iter()
+                    .rev()
+                    .find(|e| e.timestamp_nanos <= nanos)
+            }
+            VersionSelector::Nth(n) => self.versions.iter().rev().nth(n),
+        }
+    }
+}
+
+/// What is stored under the original key.
+enum Stored {
+    /// A value put before the key was versioned.
+    Plain(BlobstoreGetData),
+    Versioned(VersionHead),
+}
+
+/// A layer over an existing blobstore that keeps the previous values of
+/// overwritten keys, so they can be read with `get_version` when reviewing
+/// what a key contained before.
+///
+/// Each put stores the value under `<key>.v<version>`, and a head under the
+/// original key listing the last `max_versions` versions. Versions are
+/// timestamps in nanoseconds, made unique per key. Older versions dropped
+/// from the head are removed by a later put, once `UNLINK_GRACE` passed, as
+/// readers and concurrent writers may still have a head listing them.
+///
+/// This multiplies writes and storage, so it is meant for low-volume keys
+/// that are overwritten, like configs, not for content.
+#[derive(Debug)]
+pub struct VersionedBlob<B> {
+    inner: B,
+    put_behaviour: PutBehaviour,
+    max_versions: usize,
+}
+
+impl<B: std::fmt::Display> std::fmt::Display for VersionedBlob<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "VersionedBlob<{}>", &self.inner)
+    }
+}
+
+impl<B> VersionedBlob<B> {
+    pub fn new(inner: B, put_behaviour: PutBehaviour, max_versions: usize) -> Self {
+        assert!(max_versions > 0, "max_versions must be positive");
+        Self {
+            inner,
+            put_behaviour,
+            max_versions,
+        }
+    }
+
+    pub fn as_inner(&self) -> &B {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+
+    fn version_key(key: &str, version: u64) -> String {
+        format!("{}.v{}", key, version)
+    }
+}
+
+impl<B: BlobstoreUnlinkOps> VersionedBlob<B> {
+    /// Read the value of `key` selected by `selector`. Returns `None` if
+    /// there is no such version, or if it was removed.
+    ///
+    /// A value put before the key was versioned is its only version, with
+    /// an unknown timestamp.
+    pub async fn get_version<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+        selector: VersionSelector,
+    ) -> Result<Option<BlobstoreGetData>> {
+        match self.get_stored(ctx, key).await? {
+            None => Ok(None),
+            Some(Stored::Plain(data)) => match selector {
+                VersionSelector::Latest | VersionSelector::Nth(0) => Ok(Some(data)),
+                _ => Ok(None),
+            },
+            Some(Stored::Versioned(head)) => match head.select(selector) {
+                None => Ok(None),
+                Some(entry) => {
+                    self.inner
+                        .get(ctx, &Self::version_key(key, entry.version))
+                        .await
+                }
+            },
+        }
+    }
+
+    /// The retained versions of `key`, the current one first.
+    pub async fn list_versions<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Vec<BlobVersion>> {
+        Ok(match self.get_stored(ctx, key).await? {
+            Some(Stored::Versioned(head)) => head
+                .versions
+                .iter()
+                .rev()
+                .map(|e| BlobVersion {
+                    version: e.version,
+                    timestamp: Timestamp::from_timestamp_nanos(e.timestamp_nanos),
+                })
+                .collect(),
+            _ => Vec::new(),
+        })
+    }
+
+    async fn get_stored<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<Stored>> {
+        let data = match self.inner.get(ctx, key).await? {
+            None => return Ok(None),
+            Some(data) => data,
+        };
+        Ok(Some(match VersionHead::decode(key, data.as_raw_bytes())? {
+            None => Stored::Plain(data),
+            Some(head) => Stored::Versioned(head),
+        }))
+    }
+
+    async fn get_head<'a>(&'a self, ctx: &'a CoreContext, key: &'a str) -> Result<VersionHead> {
+        Ok(match self.get_stored(ctx, key).await? {
+            Some(Stored::Versioned(head)) => head,
+            _ => VersionHead::default(),
+        })
+    }
+
+    async fn put_impl<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+        timestamp: Timestamp,
+    ) -> Result<OverwriteStatus> {
+        let stored = self.get_stored(ctx, &key).await?;
+        let status = match stored {
+            None => OverwriteStatus::New,
+            Some(_) if !put_behaviour.should_overwrite() => return Ok(OverwriteStatus::Prevented),
+            Some(_) => OverwriteStatus::Overwrote,
+        };
+        let mut head = match stored {
+            Some(Stored::Versioned(head)) => head,
+            _ => VersionHead::default(),
+        };
+
+        // Version blobs are never overwritten: a taken version was put by a
+        // concurrent writer.
+        let timestamp_nanos = timestamp.timestamp_nanos();
+        let mut version =
+            (timestamp_nanos.max(0) as u64).max(head.latest().map_or(0, |e| e.version + 1));
+        let mut attempts = 0;
+        while self
+            .inner
+            .put_explicit(
+                ctx,
+                Self::version_key(&key, version),
+                value.clone(),
+                PutBehaviour::IfAbsent,
+            )
+            .await?
+            == OverwriteStatus::Prevented
+        {
+            attempts += 1;
+            if attempts >= MAX_ATTEMPTS {
+                return Err(ErrorKind::HeadConflict { key, attempts }.into());
+            }
+            version += 1;
+        }
+
+        // A concurrent writer can replace the head with one that lacks this
+        // version. It is then merged into the new head and written again.
+        let entry = VersionEntry {
+            version,
+            timestamp_nanos,
+        };
+        for _ in 0..MAX_ATTEMPTS {
+            let mut new_head = head.clone();
+            new_head.insert(entry, self.max_versions, timestamp_nanos);
+            let expired = new_head.take_expired(timestamp_nanos);
+            self.inner
+                .put_explicit(
+                    ctx,
+                    key.clone(),
+                    new_head.encode()?,
+                    PutBehaviour::Overwrite,
+                )
+                .await?;
+
+            head = self.get_head(ctx, &key).await?;
+            let retained = head.contains(version)
+                || (head.versions.len() >= self.max_versions
+                    && head.versions.first().map_or(false, |e| e.version > version));
+            if retained {
+                // If newer versions replaced this one, its blob is left in
+                // place: a head written concurrently may still list it.
+                //
+                // Best effort: unreferenced versions are never read.
+                for version in expired.iter().filter(|v| !head.contains(**v)) {
+                    let _ = self
+                        .inner
+                        .unlink(ctx, &Self::version_key(&key, *version))
+                        .await;
+                }
+                return Ok(status);
+            }
+        }
+        Err(ErrorKind::HeadConflict {
+            key,
+            attempts: MAX_ATTEMPTS,
+        }
+        .into())
+    }
+}
+
+#[async_trait]
+impl<B: BlobstoreUnlinkOps> Blobstore for VersionedBlob<B> {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        match self.get_stored(ctx, key).await? {
+            None => Ok(None),
+            Some(Stored::Plain(data)) => Ok(Some(data)),
+            Some(Stored::Versioned(head)) => {
+                let version = match head.latest() {
+                    None => return Ok(None),
+                    Some(entry) => entry.version,
+                };
+                let data = self
+                    .inner
+                    .get(ctx, &Self::version_key(key, version))
+                    .await?
+                    .ok_or_else(|| ErrorKind::MissingVersion {
+                        key: key.to_string(),
+                        version,
+                    })?;
+                Ok(Some(data))
+            }
+        }
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        self.put_with_status(ctx, key, value).await?;
+        Ok(())
+    }
+
+    async fn is_present<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreIsPresent> {
+        self.inner.is_present(ctx, key).await
+    }
+}
+
+#[async_trait]
+impl<B: BlobstoreUnlinkOps> BlobstorePutOps for VersionedBlob<B> {
+    async fn put_explicit<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+    ) -> Result<OverwriteStatus> {
+        self.put_impl(ctx, key, value, put_behaviour, Timestamp::now())
+            .await
+    }
+
+    async fn put_with_status<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<OverwriteStatus> {
+        self.put_impl(ctx, key, value, self.put_behaviour, Timestamp::now())
+            .await
+    }
+}
+
+#[async_trait]
+impl<B: BlobstoreUnlinkOps> BlobstoreUnlinkOps for VersionedBlob<B> {
+    async fn unlink<'a>(&'a self, ctx: &'a CoreContext, key: &'a str) -> Result<()> {
+        if let Some(Stored::Versioned(head)) = self.get_stored(ctx, key).await? {
+            let versions = head.versions.iter().map(|e| e.version);
+            for version in versions.chain(head.dropped.iter().map(|d| d.version)) {
+                // Might have been removed by a concurrent put.
+                let _ = self
+                    .inner
+                    .unlink(ctx, &Self::version_key(key, version))
+                    .await;
+            }
+        }
+        self.inner.unlink(ctx, key).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use blobstore::BlobstoreKeyParam;
+    use blobstore::BlobstoreKeySource;
+    use borrowed::borrowed;
+    use fbinit::FacebookInit;
+    use futures::future::try_join_all;
+    use memblob::Memblob;
+
+    use super::*;
+
+    const MAX_VERSIONS: usize = 3;
+
+    fn value(i: u64) -> BlobstoreBytes {
+        BlobstoreBytes::from_bytes(format!("value{}", i))
+    }
+
+    fn at(secs: i64) -> Timestamp {
+        Timestamp::from_timestamp_secs(secs)
+    }
+
+    async fn put_at(
+        ctx: &CoreContext,
+        blob: &VersionedBlob<Memblob>,
+        key: &str,
+        i: u64,
+        secs: i64,
+    ) -> Result<OverwriteStatus> {
+        blob.put_impl(
+            ctx,
+            key.to_string(),
+            value(i),
+            PutBehaviour::Overwrite,
+            at(secs),
+        )
+        .await
+    }
+
+    async fn get_version(
+        ctx: &CoreContext,
+        blob: &VersionedBlob<Memblob>,
+        selector: VersionSelector,
+    ) -> Result<Option<BlobstoreBytes>> {
+        Ok(blob
+            .get_version(ctx, "key", selector)
+            .await?
+            .map(|data| data.into_bytes()))
+    }
+
+    async fn base_keys(ctx: &CoreContext, base: &Memblob) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = base
+            .enumerate(ctx, &BlobstoreKeyParam::from(..))
+            .await?
+            .keys
+            .into_iter()
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    #[fbinit::test]
+    async fn test_history(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = VersionedBlob::new(Memblob::default(), PutBehaviour::Overwrite, MAX_VERSIONS);
+
+        assert!(blob.get(ctx, "key").await?.is_none());
+        assert!(
+            !blob
+                .is_present(ctx, "key")
+                .await?
+                .assume_not_found_if_unsure()
+        );
+        assert_eq!(
+            put_at(ctx, &blob, "key", 0, 100).await?,
+            OverwriteStatus::New
+        );
+        for i in 1..MAX_VERSIONS as u64 {
+            assert_eq!(
+                put_at(ctx, &blob, "key", i, 100 + i as i64 * 10).await?,
+                OverwriteStatus::Overwrote
+            );
+        }
+        assert!(
+            blob.is_present(ctx, "key")
+                .await?
+                .assume_not_found_if_unsure()
+        );
+
+        let last = MAX_VERSIONS as u64 - 1;
+        assert_eq!(
+            blob.get(ctx, "key").await?.unwrap().into_bytes(),
+            value(last)
+        );
+        assert_eq!(
+            get_version(ctx, &blob, VersionSelector::Latest).await?,
+            Some(value(last))
+        );
+        for n in 0..MAX_VERSIONS {
+            assert_eq!(
+                get_version(ctx, &blob, VersionSelector::Nth(n)).await?,
+                Some(value(last - n as u64))
+            );
+        }
+        assert_eq!(
+            get_version(ctx, &blob, VersionSelector::Nth(MAX_VERSIONS)).await?,
+            None
+        );
+
+        let versions = blob.list_versions(ctx, "key").await?;
+        assert_eq!(versions.len(), MAX_VERSIONS);
+        assert_eq!(versions[0].timestamp, at(100 + last as i64 * 10));
+        assert!(versions.windows(2).all(|w| w[0].version > w[1].version));
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_at_timestamp(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let blob = VersionedBlob::new(Memblob::default(), PutBehaviour::Overwrite, 10);
+        put_at(ctx, &blob, "key", 0, 100).await?;
+        put_at(ctx, &blob, "key", 1, 200).await?;
+        put_at(ctx, &blob, "key", 2, 300).await?;
+
+        for (secs, expected) in [
+            (99, None),
+            (100, Some(0)),
+            (199, Some(0)),
+            (200, Some(1)),
+            (299, Some(1)),
+            (300, Some(2)),
+            (1000, Some(2)),
+        ] {
+            assert_eq!(
+                get_version(ctx, &blob, VersionSelector::AtTimestamp(at(secs))).await?,
+                expected.map(value),
+                "at {}",
+                secs
+            );
+        }
+
+        // Puts in the same nanosecond, or with a clock going backwards, get
+        // distinct, increasing versions.
+        put_at(ctx, &blob, "key", 3, 300).await?;
+        put_at(ctx, &blob, "key", 4, 250).await?;
+        let versions = blob.list_versions(ctx, "key").await?;
+        assert_eq!(versions.len(), 5);
+        assert!(versions.windows(2).all(|w| w[0].version > w[1].version));
+        assert_eq!(blob.get(ctx, "key").await?.unwrap().into_bytes(), value(4));
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_pruning(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let base = Memblob::default();
+        let blob = VersionedBlob::new(base.clone(), PutBehaviour::Overwrite, MAX_VERSIONS);
+
+        for i in 0..MAX_VERSIONS as u64 {
+            put_at(ctx, &blob, "key", i, 100 + i as i64).await?;
+        }
+        let before = base_keys(ctx, &base).await?;
+        assert_eq!(before.len(), MAX_VERSIONS + 1);
+
+        put_at(ctx, &blob, "key", 10, 200).await?;
+        assert_eq!(
+            get_version(ctx, &blob, VersionSelector::AtTimestamp(at(100))).await?,
+            None
+        );
+        assert_eq!(
+            get_version(ctx, &blob, VersionSelector::AtTimestamp(at(101))).await?,
+            Some(value(1))
+        );
+        // The dropped version is kept for the grace period.
+        let kept = base_keys(ctx, &base).await?;
+        assert!(before.iter().all(|k| kept.contains(k)));
+
+        // Then removed by a put.
+        let later = 200 + UNLINK_GRACE.as_secs() as i64;
+        put_at(ctx, &blob, "key", 11, later).await?;
+        let after = base_keys(ctx, &base).await?;
+        let removed: Vec<_> = before.iter().filter(|k| !after.contains(k)).collect();
+        let oldest = VersionedBlob::<Memblob>::version_key("key", at(100).timestamp_nanos() as u64);
+        assert_eq!(removed, vec![&oldest]);
+
+        blob.unlink(ctx, "key").await?;
+        assert!(base_keys(ctx, &base).await?.is_empty());
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_stale_head(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let base = Memblob::default();
+        let blob = VersionedBlob::new(base.clone(), PutBehaviour::Overwrite, MAX_VERSIONS);
+        for i in 0..MAX_VERSIONS as u64 {
+            put_at(ctx, &blob, "key", i, 100 + i as i64).await?;
+        }
+        let stale = blob.get_head(ctx, "key").await?;
+        put_at(ctx, &blob, "key", 10, 200).await?;
+
+        // A concurrent writer that read the head before the last put writes
+        // it back. The version the put dropped is still readable.
+        base.put(ctx, "key".to_string(), stale.encode()?).await?;
+        assert_eq!(
+            get_version(ctx, &blob, VersionSelector::AtTimestamp(at(100))).await?,
+            Some(value(0))
+        );
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_put_behaviour(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        borrowed!(ctx);
+        let base = Memblob::default();
+        base.put(ctx, "key".to_string(), value(0)).await?;
+        let blob = VersionedBlob::new(base, PutBehaviour::IfAbsent, MAX_VERSIONS);
+
+        // Values put before the key was versioned are read as is.
+        assert_eq!(blob.get(ctx, "key").await?.unwrap().into_bytes(), value(0));
+        assert_eq!(
+            get_version(ctx, &blob, VersionSelector::Nth(0)).await?,
+            Some(value(0))
+        );
+        assert_eq!(
+            blob.put_with_status(ctx, "key".to_string(), value(1))
+                .await?,
+            OverwriteStatus::Prevented
+        );
+        assert_eq!(
+            blob.put_explicit(ctx, "key".to_string(), value(1), PutBehaviour::Overwrite)
+                .await?,
+            OverwriteStatus::Overwrote
+        );
+        assert_eq!(blob.get(ctx, "key").await?.unwrap().into_bytes(), value(1));
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_concurrent_writers(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let base = Memblob::default();
+        let blob = Arc::new(VersionedBlob::new(
+            base.clone(),
+            PutBehaviour::Overwrite,
+            MAX_VERSIONS,
+        ));
+
+        let writers = (0..16u64).map(|i| {
+            let (ctx, blob) = (ctx.clone(), blob.clone());
+            tokio::spawn(async move {
+                blob.put(&ctx, "key".to_string(), value(i)).await?;
+                blob.put(&ctx, "key".to_string(), value(i + 100)).await
+            })
+        });
+        for result in try_join_all(writers).await? {
+            result?;
+        }
+
+        // The head lists at most `MAX_VERSIONS` versions, and the current
+        // one is readable.
+        let versions = blob.list_versions(&ctx, "key").await?;
+        assert!(!versions.is_empty() && versions.len() <= MAX_VERSIONS);
+        assert!(versions.windows(2).all(|w| w[0].version > w[1].version));
+        let current = blob.get(&ctx, "key").await?.unwrap().into_bytes();
+        assert_eq!(
+            base.get(&ctx, &format!("key.v{}", versions[0].version))
+                .await?
+                .unwrap()
+                .into_bytes(),
+            current
+        );
+        Ok(())
+    }
+}
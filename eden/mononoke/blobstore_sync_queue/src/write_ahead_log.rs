@@ -489,7 +489,9 @@ async fn insert_entries(
         .map(|(a, b, c, d, e)| (a, b, c, d, e)) // &(a, b, ...) into (&a, &b, ...)
         .collect();
 
-    WalInsertEntry::query(write_connection, &entries_ref).await
+    Ok(WalInsertEntry::query(write_connection, &entries_ref)
+        .await?
+        .into())
 }
 
 mononoke_queries! {
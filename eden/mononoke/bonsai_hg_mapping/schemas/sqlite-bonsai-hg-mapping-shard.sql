@@ -0,0 +1,21 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+-- A shard of bonsai_hg_mapping, see `SqlBonsaiHgMappingBuilder::with_shards`.
+-- Created once per shard, with the index of the shard in place of the
+-- placeholder.
+CREATE TABLE IF NOT EXISTS bonsai_hg_mapping_shard_{shard} (
+  repo_id INTEGER NOT NULL,
+  hg_cs_id BINARY(20) NOT NULL,
+  bcs_id BINARY(32) NOT NULL,
+  added_at_secs BIGINT DEFAULT (CAST(strftime('%s', 'now') AS INTEGER)),
+  UNIQUE (repo_id, hg_cs_id),
+  PRIMARY KEY (repo_id, bcs_id)
+);
+
+CREATE INDEX IF NOT EXISTS repo_added_at_shard_{shard}
+  ON bonsai_hg_mapping_shard_{shard} (repo_id, added_at_secs);
@@ -9,6 +9,20 @@ CREATE TABLE IF NOT EXISTS bonsai_hg_mapping (
   repo_id INTEGER NOT NULL,
   hg_cs_id BINARY(20) NOT NULL,
   bcs_id BINARY(32) NOT NULL,
+  -- Seconds since the epoch when the row was inserted. NULL for rows that
+  -- predate the column.
+  added_at_secs BIGINT DEFAULT (CAST(strftime('%s', 'now') AS INTEGER)),
   UNIQUE (repo_id, hg_cs_id),
   PRIMARY KEY (repo_id, bcs_id)
 );
+
+CREATE INDEX IF NOT EXISTS repo_added_at ON bonsai_hg_mapping (repo_id, added_at_secs);
+
+-- Progress of `SqlBonsaiHgMapping::migrate_repo_id`: the rows of
+-- `from_repo_id` up to `last_bcs_id` were migrated to `to_repo_id`.
+CREATE TABLE IF NOT EXISTS bonsai_hg_mapping_repo_id_migration (
+  from_repo_id INTEGER NOT NULL,
+  to_repo_id INTEGER NOT NULL,
+  last_bcs_id BINARY(32) NOT NULL,
+  PRIMARY KEY (from_repo_id, to_repo_id)
+);
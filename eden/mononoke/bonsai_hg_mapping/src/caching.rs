@@ -7,6 +7,8 @@
 
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use abomonation_derive::Abomonation;
@@ -16,6 +18,7 @@ use anyhow::Result;
 use async_trait::async_trait;
 use bonsai_hg_mapping_entry_thrift as thrift;
 use bytes::Bytes;
+use caching_ext::fill_cache;
 use caching_ext::get_or_fill_chunked;
 use caching_ext::CacheDisposition;
 use caching_ext::CacheHandlerFactory;
@@ -29,17 +32,24 @@ use caching_ext::MemcacheEntity;
 use caching_ext::MemcacheHandler;
 use context::CoreContext;
 use fbthrift::compact_protocol;
+use futures::channel::mpsc;
 use memcache::KeyGen;
 use mercurial_types::HgChangesetId;
 use mercurial_types::HgNodeHash;
 use mononoke_types::ChangesetId;
 use mononoke_types::RepositoryId;
+use mononoke_types::Timestamp;
 use stats::prelude::*;
 use tunables::tunables;
 
 use super::BonsaiHgMapping;
 use super::BonsaiHgMappingEntry;
 use super::BonsaiOrHgChangesetIds;
+use super::Freshness;
+use crate::mapping_stats::MappingOperation;
+use crate::mapping_stats::MappingStats;
+use crate::mapping_stats::RepoMappingStats;
+use crate::subscribers::Subscribers;
 
 define_stats! {
     prefix = "mononoke.bonsai_hg_mapping";
@@ -134,6 +144,10 @@ pub struct CachingBonsaiHgMapping {
     cachelib: CachelibHandler<BonsaiHgMappingCacheEntry>,
     memcache: MemcacheHandler,
     keygen: KeyGen,
+    subscribers: Subscribers,
+    stats: Option<Arc<RepoMappingStats>>,
+    /// Part of the cache keys, bumped by `invalidate_repos`.
+    generation: AtomicU64,
 }
 
 impl CachingBonsaiHgMapping {
@@ -146,6 +160,9 @@ impl CachingBonsaiHgMapping {
             cachelib: cache_handler_factory.cachelib(),
             memcache: cache_handler_factory.memcache(),
             keygen: CachingBonsaiHgMapping::create_key_gen(),
+            subscribers: Subscribers::default(),
+            stats: None,
+            generation: AtomicU64::new(0),
         }
     }
 
@@ -153,6 +170,103 @@ impl CachingBonsaiHgMapping {
         Self::new(mapping, CacheHandlerFactory::Mocked)
     }
 
+    /// Record the latency of every call in `stats`, including cache hits.
+    pub fn with_stats(mut self, stats: Arc<MappingStats>) -> Self {
+        self.stats = Some(stats.for_repo(self.mapping.repo_id()));
+        self
+    }
+
+    /// Receive every entry newly added through this mapping. See
+    /// `SqlBonsaiHgMapping::subscribe`.
+    pub fn subscribe(&self) -> mpsc::Receiver<BonsaiHgMappingEntry> {
+        self.subscribers.subscribe()
+    }
+
+    /// Number of notifications dropped because a subscriber fell behind.
+    pub fn dropped_notifications(&self) -> u64 {
+        self.subscribers.dropped_notifications()
+    }
+
+    /// Stop using the entries cached so far if this mapping is for one of
+    /// `repo_ids`, after their rows were changed directly in the database,
+    /// e.g. by `SqlBonsaiHgMapping::migrate_repo_id`. Returns whether the
+    /// entries were invalidated.
+    ///
+    /// Only this process is affected: other processes keep the entries they
+    /// cached. Bump the `bonsai_hg_mapping_sitever` tunable to invalidate
+    /// the entries of every process.
+    pub fn invalidate_repos(&self, repo_ids: &[RepositoryId]) -> bool {
+        if !repo_ids.contains(&self.repo_id()) {
+            return false;
+        }
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// `get_with_freshness`, without recording it in the stats, for the
+    /// methods using it that record their own call.
+    async fn get_cached(
+        &self,
+        ctx: &CoreContext,
+        cs: BonsaiOrHgChangesetIds,
+        freshness: Freshness,
+    ) -> Result<Vec<BonsaiHgMappingEntry>, Error> {
+        let cache_request = (ctx, self);
+        let repo_id = self.repo_id();
+
+        if freshness == Freshness::MostRecent {
+            // Skip the cache lookup, but still fill it with what we found.
+            let entries = self
+                .mapping
+                .get_with_freshness(ctx, cs, Freshness::MostRecent)
+                .await?;
+            let cache_entries = entries
+                .iter()
+                .map(|e| (e, BonsaiHgMappingCacheEntry::from_entry(e.clone(), repo_id)))
+                .collect::<Vec<_>>();
+            fill_cache(
+                &cache_request,
+                cache_entries.iter().map(|(e, v)| (&e.bcs_id, v)),
+            )
+            .await;
+            fill_cache(
+                &cache_request,
+                cache_entries.iter().map(|(e, v)| (&e.hg_cs_id, v)),
+            )
+            .await;
+            return Ok(entries);
+        }
+
+        let cache_entry = match cs {
+            BonsaiOrHgChangesetIds::Bonsai(cs_ids) => get_or_fill_chunked(
+                &cache_request,
+                cs_ids.into_iter().collect(),
+                CHUNK_SIZE,
+                PARALLEL_CHUNKS,
+            )
+            .await?
+            .into_values()
+            .map(|val| val.into_entry(repo_id))
+            .collect::<Result<_>>()?,
+            BonsaiOrHgChangesetIds::Hg(hg_ids) => get_or_fill_chunked(
+                &cache_request,
+                hg_ids.into_iter().collect(),
+                CHUNK_SIZE,
+                PARALLEL_CHUNKS,
+            )
+            .await?
+            .into_values()
+            .map(|val| val.into_entry(repo_id))
+            .collect::<Result<_>>()?,
+        };
+
+        Ok(cache_entry)
+    }
+
+    fn cache_key(&self, cs: &BonsaiOrHgChangesetId) -> String {
+        get_cache_key(self.repo_id(), self.generation.load(Ordering::Relaxed), cs)
+    }
+
     fn create_key_gen() -> KeyGen {
         let key_prefix = "scm.mononoke.bonsai_hg_mapping";
 
@@ -188,41 +302,28 @@ impl BonsaiHgMapping for CachingBonsaiHgMapping {
     }
 
     async fn add(&self, ctx: &CoreContext, entry: BonsaiHgMappingEntry) -> Result<bool, Error> {
-        self.mapping.add(ctx, entry).await
+        RepoMappingStats::measure(self.stats.as_deref(), MappingOperation::Add, async {
+            let added = self.mapping.add(ctx, entry.clone()).await?;
+            if added {
+                self.subscribers.publish(&entry);
+            }
+            Ok(added)
+        })
+        .await
     }
 
-    async fn get(
+    async fn get_with_freshness(
         &self,
         ctx: &CoreContext,
         cs: BonsaiOrHgChangesetIds,
+        freshness: Freshness,
     ) -> Result<Vec<BonsaiHgMappingEntry>, Error> {
-        let cache_request = (ctx, self);
-        let repo_id = self.repo_id();
-
-        let cache_entry = match cs {
-            BonsaiOrHgChangesetIds::Bonsai(cs_ids) => get_or_fill_chunked(
-                &cache_request,
-                cs_ids.into_iter().collect(),
-                CHUNK_SIZE,
-                PARALLEL_CHUNKS,
-            )
-            .await?
-            .into_values()
-            .map(|val| val.into_entry(repo_id))
-            .collect::<Result<_>>()?,
-            BonsaiOrHgChangesetIds::Hg(hg_ids) => get_or_fill_chunked(
-                &cache_request,
-                hg_ids.into_iter().collect(),
-                CHUNK_SIZE,
-                PARALLEL_CHUNKS,
-            )
-            .await?
-            .into_values()
-            .map(|val| val.into_entry(repo_id))
-            .collect::<Result<_>>()?,
-        };
-
-        Ok(cache_entry)
+        RepoMappingStats::measure(
+            self.stats.as_deref(),
+            MappingOperation::Get,
+            self.get_cached(ctx, cs, freshness),
+        )
+        .await
     }
 
     /// Use caching for the ranges of one element, use slower path otherwise.
@@ -233,21 +334,81 @@ impl BonsaiHgMapping for CachingBonsaiHgMapping {
         high: HgChangesetId,
         limit: usize,
     ) -> Result<Vec<HgChangesetId>, Error> {
-        if low == high {
-            let res = self.get(ctx, low.into()).await?;
-            if res.is_empty() {
-                return Ok(vec![]);
-            } else {
-                return Ok(vec![low]);
-            }
-        }
+        RepoMappingStats::measure(
+            self.stats.as_deref(),
+            MappingOperation::GetHgInRange,
+            async {
+                if low == high {
+                    let res = self
+                        .get_cached(ctx, low.into(), Freshness::MaybeStale)
+                        .await?;
+                    if res.is_empty() {
+                        return Ok(vec![]);
+                    } else {
+                        return Ok(vec![low]);
+                    }
+                }
+
+                self.mapping.get_hg_in_range(ctx, low, high, limit).await
+            },
+        )
+        .await
+    }
+
+    /// Use caching for the ranges of one element, use slower path otherwise.
+    async fn get_bonsai_in_range(
+        &self,
+        ctx: &CoreContext,
+        low: ChangesetId,
+        high: ChangesetId,
+        limit: usize,
+    ) -> Result<Vec<ChangesetId>, Error> {
+        RepoMappingStats::measure(
+            self.stats.as_deref(),
+            MappingOperation::GetBonsaiInRange,
+            async {
+                if low == high {
+                    let res = self
+                        .get_cached(ctx, low.into(), Freshness::MaybeStale)
+                        .await?;
+                    if res.is_empty() {
+                        return Ok(vec![]);
+                    } else {
+                        return Ok(vec![low]);
+                    }
+                }
+
+                self.mapping
+                    .get_bonsai_in_range(ctx, low, high, limit)
+                    .await
+            },
+        )
+        .await
+    }
 
-        self.mapping.get_hg_in_range(ctx, low, high, limit).await
+    /// Not cached, as the result changes with every added entry.
+    async fn get_entries_added_since(
+        &self,
+        ctx: &CoreContext,
+        since: Timestamp,
+        limit: usize,
+    ) -> Result<Vec<(BonsaiHgMappingEntry, Timestamp)>, Error> {
+        self.mapping
+            .get_entries_added_since(ctx, since, limit)
+            .await
+    }
+
+    async fn newest_entry_timestamp(&self, ctx: &CoreContext) -> Result<Option<Timestamp>, Error> {
+        self.mapping.newest_entry_timestamp(ctx).await
     }
 }
 
-fn get_cache_key(repo_id: RepositoryId, cs: &BonsaiOrHgChangesetId) -> String {
-    format!("{}.{:?}", repo_id.prefix(), cs)
+fn get_cache_key(repo_id: RepositoryId, generation: u64, cs: &BonsaiOrHgChangesetId) -> String {
+    if generation == 0 {
+        format!("{}.{:?}", repo_id.prefix(), cs)
+    } else {
+        format!("{}.gen{}.{:?}", repo_id.prefix(), generation, cs)
+    }
 }
 
 impl MemcacheEntity for BonsaiHgMappingCacheEntry {
@@ -289,7 +450,7 @@ impl EntityStore<BonsaiHgMappingCacheEntry> for CacheRequest<'_> {
 impl KeyedEntityStore<ChangesetId, BonsaiHgMappingCacheEntry> for CacheRequest<'_> {
     fn get_cache_key(&self, key: &ChangesetId) -> String {
         let (_, mapping) = self;
-        get_cache_key(mapping.repo_id(), &BonsaiOrHgChangesetId::Bonsai(*key))
+        mapping.cache_key(&BonsaiOrHgChangesetId::Bonsai(*key))
     }
 
     async fn get_from_db(
@@ -319,7 +480,7 @@ impl KeyedEntityStore<ChangesetId, BonsaiHgMappingCacheEntry> for CacheRequest<'
 impl KeyedEntityStore<HgChangesetId, BonsaiHgMappingCacheEntry> for CacheRequest<'_> {
     fn get_cache_key(&self, key: &HgChangesetId) -> String {
         let (_, mapping) = self;
-        get_cache_key(mapping.repo_id(), &BonsaiOrHgChangesetId::Hg(*key))
+        mapping.cache_key(&BonsaiOrHgChangesetId::Hg(*key))
     }
 
     async fn get_from_db(
@@ -0,0 +1,336 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Verification and repair of the mapping of a repo, for admin tools.
+//!
+//! The unique constraints of the table should prevent an hg changeset from
+//! being mapped to more than one bonsai changeset (and vice versa), but
+//! manual fixes may have bypassed them. `verify` finds such rows, as well as
+//! rows of bonsai changesets missing from the changesets table. Operators
+//! review the report, write a `RepairPlan` and apply it with `repair`.
+
+use std::collections::HashSet;
+
+use anyhow::Error;
+use anyhow::Result;
+use context::CoreContext;
+use context::PerfCounterType;
+use futures::Future;
+use mercurial_types::HgChangesetId;
+use mercurial_types::NULL_CSID;
+use mononoke_types::hash::Blake2;
+use mononoke_types::ChangesetId;
+use mononoke_types::RepositoryId;
+use serde::Deserialize;
+use serde::Serialize;
+use sql::Connection;
+use sql_ext::mononoke_queries;
+
+use crate::sharding::on_table;
+use crate::BonsaiHgMappingEntry;
+use crate::Freshness;
+use crate::SqlBonsaiHgMapping;
+
+const DEFAULT_CHUNK_SIZE: usize = 10000;
+
+mononoke_queries! {
+    read SelectKnownChangesets(
+        repo_id: RepositoryId,
+        >list cs_id: ChangesetId
+    ) -> (ChangesetId) {
+        "SELECT cs_id
+         FROM changesets
+         WHERE repo_id = {repo_id} AND cs_id IN {cs_id}"
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct VerifyOptions {
+    /// Number of rows read per query.
+    pub chunk_size: usize,
+    /// Whether to look for rows of bonsai changesets missing from the
+    /// changesets table, which must be in the same database.
+    pub check_orphans: bool,
+    /// `MostRecent` reads from the master.
+    pub freshness: Freshness,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            check_orphans: true,
+            freshness: Freshness::MaybeStale,
+        }
+    }
+}
+
+/// The anomalies found by `verify`. Groups and rows are ordered by id.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsistencyReport {
+    /// Number of rows of the repo.
+    pub rows_scanned: u64,
+    /// Groups of rows sharing an hg changeset id. Rows of a group may be
+    /// identical, if the table has no unique constraints.
+    pub duplicate_hg: Vec<Vec<BonsaiHgMappingEntry>>,
+    /// Groups of rows sharing a bonsai changeset id.
+    pub duplicate_bonsai: Vec<Vec<BonsaiHgMappingEntry>>,
+    /// Rows whose bonsai changeset is not in the changesets table.
+    pub orphans: Vec<BonsaiHgMappingEntry>,
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.duplicate_hg.is_empty() && self.duplicate_bonsai.is_empty() && self.orphans.is_empty()
+    }
+}
+
+/// Changes reviewed by an operator, applied by `repair` in a single
+/// transaction. Deletions are applied before overwrites.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepairPlan {
+    /// Rows to delete, including all identical copies.
+    pub delete: Vec<BonsaiHgMappingEntry>,
+    /// Rows to write, replacing every row with the same hg or bonsai
+    /// changeset id.
+    pub overwrite: Vec<BonsaiHgMappingEntry>,
+}
+
+/// Rows changed by `repair`, or that would have been changed in a dry run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepairOutcome {
+    pub deleted: u64,
+    pub written: u64,
+    pub dry_run: bool,
+}
+
+/// Reads rows ordered by a key in chunks, holding back the rows of the last
+/// key of a chunk until the next one, so that rows sharing a key are always
+/// returned together.
+struct ChunkedScan<K> {
+    start: Option<K>,
+    chunk_size: usize,
+}
+
+impl<K: Copy + Eq> ChunkedScan<K> {
+    fn new(start: K, chunk_size: usize) -> Self {
+        Self {
+            start: Some(start),
+            chunk_size: chunk_size.max(1),
+        }
+    }
+
+    /// The next groups of rows sharing a key, or `None` once all rows were
+    /// returned.
+    async fn next<F, Fut>(
+        &mut self,
+        key: fn(&BonsaiHgMappingEntry) -> K,
+        mut fetch: F,
+    ) -> Result<Option<Vec<Vec<BonsaiHgMappingEntry>>>>
+    where
+        F: FnMut(K, usize) -> Fut,
+        Fut: Future<Output = Result<Vec<BonsaiHgMappingEntry>>>,
+    {
+        let mut limit = self.chunk_size;
+        loop {
+            let start = match self.start {
+                Some(start) => start,
+                None => return Ok(None),
+            };
+            let rows = fetch(start, limit).await?;
+            let full = rows.len() >= limit;
+
+            let mut groups: Vec<Vec<BonsaiHgMappingEntry>> = Vec::new();
+            for row in rows {
+                match groups.last_mut() {
+                    Some(group) if key(&group[0]) == key(&row) => group.push(row),
+                    _ => groups.push(vec![row]),
+                }
+            }
+
+            if !full {
+                self.start = None;
+                return Ok(Some(groups));
+            }
+            if groups.len() == 1 {
+                // More rows share this key than fit in a chunk.
+                limit *= 2;
+                continue;
+            }
+            let last = groups.pop().expect("a full chunk has rows");
+            self.start = Some(key(&last[0]));
+            return Ok(Some(groups));
+        }
+    }
+}
+
+fn to_entries(rows: Vec<(HgChangesetId, ChangesetId)>) -> Vec<BonsaiHgMappingEntry> {
+    rows.into_iter()
+        .map(|(hg_cs_id, bcs_id)| BonsaiHgMappingEntry { hg_cs_id, bcs_id })
+        .collect()
+}
+
+impl SqlBonsaiHgMapping {
+    /// Scans the whole mapping of `repo_id` for anomalies.
+    pub async fn verify(
+        &self,
+        ctx: &CoreContext,
+        repo_id: RepositoryId,
+        opts: VerifyOptions,
+    ) -> Result<ConsistencyReport, Error> {
+        let conn = match opts.freshness {
+            Freshness::MostRecent => &self.write_connection,
+            Freshness::MaybeStale => &self.read_connection.conn,
+        };
+        let table = self.table(repo_id);
+        let mut report = ConsistencyReport::default();
+
+        let min_bonsai = ChangesetId::new(Blake2::from_byte_array([0; 32]));
+        let mut scan = ChunkedScan::new(min_bonsai, opts.chunk_size);
+        while let Some(groups) = scan
+            .next(
+                |entry| entry.bcs_id,
+                |start, limit| async move {
+                    ctx.perf_counters()
+                        .increment_counter(PerfCounterType::SqlReadsReplica);
+                    let rows = on_table!(
+                        table,
+                        SelectMappingChunkByBonsai::query(conn, &repo_id, &start, &limit)
+                    )?;
+                    Ok(to_entries(rows))
+                },
+            )
+            .await?
+        {
+            if opts.check_orphans {
+                report
+                    .orphans
+                    .extend(find_orphans(ctx, conn, repo_id, &groups).await?);
+            }
+            for group in groups {
+                report.rows_scanned += group.len() as u64;
+                if group.len() > 1 {
+                    report.duplicate_bonsai.push(group);
+                }
+            }
+        }
+
+        let mut scan = ChunkedScan::new(NULL_CSID, opts.chunk_size);
+        while let Some(groups) = scan
+            .next(
+                |entry| entry.hg_cs_id,
+                |start, limit| async move {
+                    ctx.perf_counters()
+                        .increment_counter(PerfCounterType::SqlReadsReplica);
+                    let rows = on_table!(
+                        table,
+                        SelectMappingChunkByHg::query(conn, &repo_id, &start, &limit)
+                    )?;
+                    Ok(to_entries(rows))
+                },
+            )
+            .await?
+        {
+            report
+                .duplicate_hg
+                .extend(groups.into_iter().filter(|group| group.len() > 1));
+        }
+
+        Ok(report)
+    }
+
+    /// Applies `plan` to the mapping of `repo_id` in a transaction. With
+    /// `dry_run`, the transaction is rolled back, so the outcome shows what
+    /// would change without changing anything.
+    ///
+    /// Caches of the mapping are not invalidated.
+    pub async fn repair(
+        &self,
+        ctx: &CoreContext,
+        repo_id: RepositoryId,
+        plan: &RepairPlan,
+        dry_run: bool,
+    ) -> Result<RepairOutcome, Error> {
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlWrites);
+        let mut outcome = RepairOutcome {
+            dry_run,
+            ..Default::default()
+        };
+
+        let table = self.table(repo_id);
+        let mut txn = self.write_connection.start_transaction().await?;
+        for entry in &plan.delete {
+            let (next, result) = on_table!(
+                table,
+                DeleteMappingEntry::query_with_transaction(
+                    txn,
+                    &repo_id,
+                    &entry.hg_cs_id,
+                    &entry.bcs_id,
+                )
+            )?;
+            txn = next;
+            outcome.deleted += result.affected_rows();
+        }
+        for entry in &plan.overwrite {
+            let (next, result) = on_table!(
+                table,
+                DeleteConflictingEntries::query_with_transaction(
+                    txn,
+                    &repo_id,
+                    &entry.hg_cs_id,
+                    &entry.bcs_id,
+                )
+            )?;
+            outcome.deleted += result.affected_rows();
+            let (next, result) = on_table!(
+                table,
+                InsertMapping::query_with_transaction(
+                    next,
+                    &[(&repo_id, &entry.hg_cs_id, &entry.bcs_id)],
+                )
+            )?;
+            txn = next;
+            outcome.written += result.affected_rows();
+        }
+
+        if dry_run {
+            txn.rollback().await?;
+        } else {
+            txn.commit().await?;
+        }
+        Ok(outcome)
+    }
+}
+
+/// The rows of `groups` whose bonsai changeset is not in the changesets
+/// table.
+async fn find_orphans(
+    ctx: &CoreContext,
+    conn: &Connection,
+    repo_id: RepositoryId,
+    groups: &[Vec<BonsaiHgMappingEntry>],
+) -> Result<Vec<BonsaiHgMappingEntry>, Error> {
+    if groups.is_empty() {
+        return Ok(Vec::new());
+    }
+    let cs_ids: Vec<_> = groups.iter().map(|group| group[0].bcs_id).collect();
+    ctx.perf_counters()
+        .increment_counter(PerfCounterType::SqlReadsReplica);
+    let known: HashSet<_> = SelectKnownChangesets::query(conn, &repo_id, &cs_ids[..])
+        .await?
+        .into_iter()
+        .map(|(cs_id,)| cs_id)
+        .collect();
+    Ok(groups
+        .iter()
+        .filter(|group| !known.contains(&group[0].bcs_id))
+        .flatten()
+        .cloned()
+        .collect())
+}
@@ -5,11 +5,12 @@
  * GNU General Public License version 2.
  */
 
+use mononoke_types::RepositoryId;
 use thiserror::Error;
 
 use super::BonsaiHgMappingEntry;
 
-#[derive(Debug, Eq, Error, PartialEq)]
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
 pub enum ErrorKind {
     #[error("Connection error")]
     ConnectionError,
@@ -17,4 +18,14 @@ pub enum ErrorKind {
     ConflictingEntries(BonsaiHgMappingEntry, BonsaiHgMappingEntry),
     #[error("Conflict detected during insert, but no value was there for: {0:?}")]
     RaceConditionWithDelete(BonsaiHgMappingEntry),
+    #[error("Invalid changeset id prefix: {0:?}")]
+    InvalidHexPrefix(String),
+    #[error("Insertion timestamps are not enabled for this mapping")]
+    InsertionTimestampsDisabled,
+    #[error("Cannot migrate the mapping of repo {0} to itself")]
+    MigrationToSameRepo(RepositoryId),
+    #[error("Failed to resolve missing mapping entries: {0}")]
+    ResolverFailed(String),
+    #[error("Cannot split the mapping over {0} shards, at most {1} are supported")]
+    TooManyShards(usize, usize),
 }
@@ -0,0 +1,294 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use anyhow::Error;
+use anyhow::Result;
+use async_trait::async_trait;
+use context::CoreContext;
+use futures::future;
+use futures::future::BoxFuture;
+use futures::future::FutureExt;
+use futures::future::Shared;
+use lock_ext::LockExt;
+use mercurial_types::HgChangesetId;
+use mononoke_types::ChangesetId;
+use mononoke_types::RepositoryId;
+use mononoke_types::Timestamp;
+
+use crate::BonsaiHgMapping;
+use crate::BonsaiHgMappingEntry;
+use crate::BonsaiOrHgChangesetIds;
+use crate::ErrorKind;
+use crate::Freshness;
+
+/// Finds the entries of changesets missing from a mapping, from the source
+/// the mapping is derived from. Ids without an entry are left out of the
+/// result.
+pub type MappingResolver = Arc<
+    dyn Fn(
+            &CoreContext,
+            RepositoryId,
+            BonsaiOrHgChangesetIds,
+        ) -> BoxFuture<'static, Result<Vec<BonsaiHgMappingEntry>>>
+        + Send
+        + Sync,
+>;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum MappingKey {
+    Bonsai(ChangesetId),
+    Hg(HgChangesetId),
+}
+
+impl MappingKey {
+    fn from_ids(ids: &BonsaiOrHgChangesetIds) -> Vec<Self> {
+        match ids {
+            BonsaiOrHgChangesetIds::Bonsai(ids) => ids.iter().copied().map(Self::Bonsai).collect(),
+            BonsaiOrHgChangesetIds::Hg(ids) => ids.iter().copied().map(Self::Hg).collect(),
+        }
+    }
+
+    fn of_entry(entry: &BonsaiHgMappingEntry) -> [Self; 2] {
+        [Self::Bonsai(entry.bcs_id), Self::Hg(entry.hg_cs_id)]
+    }
+}
+
+/// The ids of `keys`, which are all of the same kind.
+fn into_ids(keys: &[MappingKey]) -> BonsaiOrHgChangesetIds {
+    match keys.first() {
+        Some(MappingKey::Hg(_)) => BonsaiOrHgChangesetIds::Hg(
+            keys.iter()
+                .filter_map(|key| match key {
+                    MappingKey::Hg(id) => Some(*id),
+                    MappingKey::Bonsai(_) => None,
+                })
+                .collect(),
+        ),
+        _ => BonsaiOrHgChangesetIds::Bonsai(
+            keys.iter()
+                .filter_map(|key| match key {
+                    MappingKey::Bonsai(id) => Some(*id),
+                    MappingKey::Hg(_) => None,
+                })
+                .collect(),
+        ),
+    }
+}
+
+/// The entries resolved for some keys, once persisted.
+type Resolution = Shared<BoxFuture<'static, Result<Vec<BonsaiHgMappingEntry>, Arc<Error>>>>;
+
+/// Mapping populated lazily: entries missing from `inner` are looked up
+/// with a `MappingResolver`, added to `inner`, and returned as if they had
+/// been there. Concurrent lookups of the same id call the resolver once.
+///
+/// Resolver failures are returned as `ErrorKind::ResolverFailed`, while ids
+/// the resolver has no entry for are missing from the result, as usual.
+/// Prefix and range queries only see the entries of `inner`.
+pub struct LazyPopulatingBonsaiHgMapping<M> {
+    inner: Arc<M>,
+    resolver: MappingResolver,
+    in_flight: Arc<Mutex<HashMap<MappingKey, Resolution>>>,
+}
+
+impl<M: BonsaiHgMapping + 'static> LazyPopulatingBonsaiHgMapping<M> {
+    pub fn new(inner: Arc<M>, resolver: MappingResolver) -> Self {
+        Self {
+            inner,
+            resolver,
+            in_flight: Default::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &Arc<M> {
+        &self.inner
+    }
+
+    /// Resolves the entries of `missing`, joining the resolutions already in
+    /// flight for some of them. The resolver runs with the context of the
+    /// lookup that started it.
+    async fn resolve(
+        &self,
+        ctx: &CoreContext,
+        missing: Vec<MappingKey>,
+    ) -> Result<Vec<BonsaiHgMappingEntry>, Error> {
+        let resolutions = self.in_flight.with(|in_flight| {
+            let mut resolutions: Vec<Resolution> = Vec::new();
+            let mut started = Vec::new();
+            for key in &missing {
+                match in_flight.get(key) {
+                    Some(resolution) => {
+                        if !resolutions.iter().any(|r| r.ptr_eq(resolution)) {
+                            resolutions.push(resolution.clone());
+                        }
+                    }
+                    None => started.push(*key),
+                }
+            }
+            if !started.is_empty() {
+                let resolution = populate(
+                    ctx.clone(),
+                    self.inner.clone(),
+                    self.resolver.clone(),
+                    started.clone(),
+                    self.in_flight.clone(),
+                )
+                .boxed()
+                .shared();
+                for key in started {
+                    in_flight.insert(key, resolution.clone());
+                }
+                resolutions.push(resolution);
+            }
+            resolutions
+        });
+
+        let resolved = future::try_join_all(resolutions)
+            .await
+            .map_err(shared_error)?;
+        let missing: HashSet<_> = missing.into_iter().collect();
+        let entries: HashSet<_> = resolved
+            .into_iter()
+            .flatten()
+            .filter(|entry| {
+                MappingKey::of_entry(entry)
+                    .iter()
+                    .any(|key| missing.contains(key))
+            })
+            .collect();
+        Ok(entries.into_iter().collect())
+    }
+}
+
+/// Looks up the entries of `keys` with `resolver` and adds them to `inner`.
+/// Entries for other ids are ignored.
+async fn populate<M: BonsaiHgMapping>(
+    ctx: CoreContext,
+    inner: Arc<M>,
+    resolver: MappingResolver,
+    keys: Vec<MappingKey>,
+    in_flight: Arc<Mutex<HashMap<MappingKey, Resolution>>>,
+) -> Result<Vec<BonsaiHgMappingEntry>, Arc<Error>> {
+    let result = async {
+        let requested: HashSet<_> = keys.iter().copied().collect();
+        let resolved = resolver(&ctx, inner.repo_id(), into_ids(&keys))
+            .await
+            .map_err(|e| ErrorKind::ResolverFailed(format!("{:#}", e)))?;
+        let resolved: Vec<_> = resolved
+            .into_iter()
+            .filter(|entry| {
+                MappingKey::of_entry(entry)
+                    .iter()
+                    .any(|key| requested.contains(key))
+            })
+            .collect();
+        future::try_join_all(resolved.iter().map(|entry| persist(&ctx, &*inner, entry))).await?;
+        Ok(resolved)
+    };
+    let result = result.await;
+    in_flight.with(|in_flight| {
+        for key in &keys {
+            in_flight.remove(key);
+        }
+    });
+    result.map_err(Arc::new)
+}
+
+/// Adds `entry`, which another lookup may have added already.
+async fn persist<M: BonsaiHgMapping>(
+    ctx: &CoreContext,
+    inner: &M,
+    entry: &BonsaiHgMappingEntry,
+) -> Result<(), Error> {
+    match inner.add(ctx, entry.clone()).await {
+        Ok(_) => Ok(()),
+        Err(e) => match e.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::ConflictingEntries(stored, added)) if stored == added => Ok(()),
+            _ => Err(e),
+        },
+    }
+}
+
+/// The error of a resolution, for one of the lookups waiting on it.
+fn shared_error(e: Arc<Error>) -> Error {
+    match e.downcast_ref::<ErrorKind>() {
+        Some(kind) => kind.clone().into(),
+        None => anyhow!("{:#}", e),
+    }
+}
+
+#[async_trait]
+impl<M: BonsaiHgMapping + 'static> BonsaiHgMapping for LazyPopulatingBonsaiHgMapping<M> {
+    fn repo_id(&self) -> RepositoryId {
+        self.inner.repo_id()
+    }
+
+    async fn add(&self, ctx: &CoreContext, entry: BonsaiHgMappingEntry) -> Result<bool, Error> {
+        self.inner.add(ctx, entry).await
+    }
+
+    async fn get_with_freshness(
+        &self,
+        ctx: &CoreContext,
+        cs_ids: BonsaiOrHgChangesetIds,
+        freshness: Freshness,
+    ) -> Result<Vec<BonsaiHgMappingEntry>, Error> {
+        let keys = MappingKey::from_ids(&cs_ids);
+        let mut entries = self
+            .inner
+            .get_with_freshness(ctx, cs_ids, freshness)
+            .await?;
+        let found: HashSet<_> = entries.iter().flat_map(MappingKey::of_entry).collect();
+        let missing: Vec<_> = keys
+            .into_iter()
+            .filter(|key| !found.contains(key))
+            .collect();
+        if !missing.is_empty() {
+            entries.extend(self.resolve(ctx, missing).await?);
+        }
+        Ok(entries)
+    }
+
+    async fn get_hg_in_range(
+        &self,
+        ctx: &CoreContext,
+        low: HgChangesetId,
+        high: HgChangesetId,
+        limit: usize,
+    ) -> Result<Vec<HgChangesetId>, Error> {
+        self.inner.get_hg_in_range(ctx, low, high, limit).await
+    }
+
+    async fn get_bonsai_in_range(
+        &self,
+        ctx: &CoreContext,
+        low: ChangesetId,
+        high: ChangesetId,
+        limit: usize,
+    ) -> Result<Vec<ChangesetId>, Error> {
+        self.inner.get_bonsai_in_range(ctx, low, high, limit).await
+    }
+
+    async fn get_entries_added_since(
+        &self,
+        ctx: &CoreContext,
+        since: Timestamp,
+        limit: usize,
+    ) -> Result<Vec<(BonsaiHgMappingEntry, Timestamp)>, Error> {
+        self.inner.get_entries_added_since(ctx, since, limit).await
+    }
+
+    async fn newest_entry_timestamp(&self, ctx: &CoreContext) -> Result<Option<Timestamp>, Error> {
+        self.inner.newest_entry_timestamp(ctx).await
+    }
+}
@@ -5,6 +5,7 @@
  * GNU General Public License version 2.
  */
 
+use std::num::NonZeroUsize;
 use std::sync::Arc;
 
 use anyhow::Error;
@@ -13,31 +14,68 @@ use async_trait::async_trait;
 use context::CoreContext;
 use context::PerfCounterType;
 use fbinit::FacebookInit;
+use futures::channel::mpsc;
 use futures::future;
 use mercurial_types::HgChangesetId;
 use mercurial_types::HgChangesetIdPrefix;
 use mercurial_types::HgChangesetIdsResolvedFromPrefix;
 use mononoke_types::ChangesetId;
+use mononoke_types::ChangesetIdPrefix;
+use mononoke_types::ChangesetIdsResolvedFromPrefix;
 use mononoke_types::RepositoryId;
+use mononoke_types::Timestamp;
 use rand::Rng;
 use rendezvous::RendezVous;
 use rendezvous::RendezVousOptions;
 use rendezvous::RendezVousStats;
 use rendezvous::TunablesRendezVousController;
+use serde::Deserialize;
+use serde::Serialize;
 use sql::Connection;
 use sql_construct::SqlConstruct;
 use sql_construct::SqlConstructFromMetadataDatabaseConfig;
-use sql_ext::mononoke_queries;
+use sql_ext::open_sqlite_in_memory;
 use sql_ext::SqlConnections;
 use stats::prelude::*;
 
 mod caching;
+mod consistency;
 mod errors;
+mod lazy;
+mod mapping_stats;
 mod mem_writes_bonsai_hg_mapping;
+mod migration;
+mod observed;
+mod prefix;
+mod sharding;
+mod subscribers;
 
 pub use crate::caching::CachingBonsaiHgMapping;
+pub use crate::consistency::ConsistencyReport;
+pub use crate::consistency::RepairOutcome;
+pub use crate::consistency::RepairPlan;
+pub use crate::consistency::VerifyOptions;
 pub use crate::errors::ErrorKind;
+pub use crate::lazy::LazyPopulatingBonsaiHgMapping;
+pub use crate::lazy::MappingResolver;
+pub use crate::mapping_stats::MappingOperation;
+pub use crate::mapping_stats::MappingStats;
+pub use crate::mapping_stats::OperationSnapshot;
+pub use crate::mapping_stats::RepoMappingStats;
+pub use crate::mapping_stats::LATENCY_BUCKETS_US;
 pub use crate::mem_writes_bonsai_hg_mapping::MemWritesBonsaiHgMapping;
+pub use crate::migration::MigrationConflict;
+pub use crate::migration::MigrationReport;
+pub use crate::observed::MappingMetricsSink;
+pub use crate::observed::ObservedBonsaiHgMapping;
+pub use crate::prefix::AnyPrefixResolution;
+pub use crate::prefix::PrefixClassification;
+use crate::sharding::on_table;
+pub use crate::sharding::shard_index;
+use crate::sharding::MappingTable;
+pub use crate::sharding::MAX_SHARDS;
+use crate::subscribers::Subscribers;
+pub use crate::subscribers::SUBSCRIBER_BUFFER_SIZE;
 
 define_stats! {
     prefix = "mononoke.bonsai_hg_mapping";
@@ -47,7 +85,7 @@ define_stats! {
     get_many_hg_by_prefix: timeseries(Rate, Sum),
 }
 
-#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub struct BonsaiHgMappingEntry {
     pub hg_cs_id: HgChangesetId,
     pub bcs_id: ChangesetId,
@@ -92,6 +130,17 @@ impl From<Vec<HgChangesetId>> for BonsaiOrHgChangesetIds {
     }
 }
 
+/// How up to date the result of a mapping read needs to be.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub enum Freshness {
+    /// Read from the master, bypassing any caches. Use this when the entries
+    /// may have been added moments ago, e.g. right after a push.
+    MostRecent,
+    /// Read from replicas and caches, which may lag behind the master.
+    #[default]
+    MaybeStale,
+}
+
 #[facet::facet]
 #[async_trait]
 pub trait BonsaiHgMapping: Send + Sync {
@@ -103,6 +152,16 @@ pub trait BonsaiHgMapping: Send + Sync {
         &self,
         ctx: &CoreContext,
         cs_id: BonsaiOrHgChangesetIds,
+    ) -> Result<Vec<BonsaiHgMappingEntry>, Error> {
+        self.get_with_freshness(ctx, cs_id, Freshness::MaybeStale)
+            .await
+    }
+
+    async fn get_with_freshness(
+        &self,
+        ctx: &CoreContext,
+        cs_id: BonsaiOrHgChangesetIds,
+        freshness: Freshness,
     ) -> Result<Vec<BonsaiHgMappingEntry>, Error>;
 
     async fn get_hg_from_bonsai(
@@ -153,6 +212,74 @@ pub trait BonsaiHgMapping: Send + Sync {
         high: HgChangesetId,
         limit: usize,
     ) -> Result<Vec<HgChangesetId>, Error>;
+
+    /// Bonsai changesets that have an hg changeset, matching `cs_prefix`.
+    async fn get_many_bonsai_by_prefix(
+        &self,
+        ctx: &CoreContext,
+        cs_prefix: ChangesetIdPrefix,
+        limit: usize,
+    ) -> Result<ChangesetIdsResolvedFromPrefix, Error> {
+        let fetched_cs = self
+            .get_bonsai_in_range(ctx, cs_prefix.min_bound(), cs_prefix.max_bound(), limit + 1)
+            .await?;
+        Ok(ChangesetIdsResolvedFromPrefix::from_vec_and_limit(
+            fetched_cs, limit,
+        ))
+    }
+
+    async fn get_bonsai_in_range(
+        &self,
+        ctx: &CoreContext,
+        low: ChangesetId,
+        high: ChangesetId,
+        limit: usize,
+    ) -> Result<Vec<ChangesetId>, Error>;
+
+    /// Resolves user input that may be a prefix of either an hg or a bonsai
+    /// changeset id, looking up both kinds concurrently. Up to `limit` ids
+    /// of each kind are returned. Fails with `ErrorKind::InvalidHexPrefix`
+    /// if `hex_prefix` is not a prefix of either kind.
+    async fn resolve_prefix_any(
+        &self,
+        ctx: &CoreContext,
+        hex_prefix: &str,
+        limit: usize,
+    ) -> Result<AnyPrefixResolution, Error> {
+        let (hg_prefix, bonsai_prefix) = prefix::parse_hex_prefix(hex_prefix)?;
+        let hg = async {
+            match hg_prefix {
+                Some(hg_prefix) => self.get_many_hg_by_prefix(ctx, hg_prefix, limit).await,
+                None => Ok(HgChangesetIdsResolvedFromPrefix::NoMatch),
+            }
+        };
+        let bonsai = self.get_many_bonsai_by_prefix(ctx, bonsai_prefix, limit);
+        let (hg, bonsai) = future::try_join(hg, bonsai).await?;
+        Ok(AnyPrefixResolution { hg, bonsai })
+    }
+
+    /// Up to `limit` entries added at or after `since`, newest first, with
+    /// the time they were added. Times have a granularity of one second, and
+    /// entries added before insertion times were recorded are never
+    /// returned.
+    ///
+    /// Fails with `ErrorKind::InsertionTimestampsDisabled` unless the
+    /// mapping records insertion times, see
+    /// `SqlBonsaiHgMappingBuilder::with_insertion_timestamps`.
+    async fn get_entries_added_since(
+        &self,
+        _ctx: &CoreContext,
+        _since: Timestamp,
+        _limit: usize,
+    ) -> Result<Vec<(BonsaiHgMappingEntry, Timestamp)>, Error> {
+        Err(ErrorKind::InsertionTimestampsDisabled.into())
+    }
+
+    /// When the newest entry was added, or `None` if no entry has an
+    /// insertion time. See `get_entries_added_since`.
+    async fn newest_entry_timestamp(&self, _ctx: &CoreContext) -> Result<Option<Timestamp>, Error> {
+        Err(ErrorKind::InsertionTimestampsDisabled.into())
+    }
 }
 
 #[derive(Clone)]
@@ -193,65 +320,19 @@ pub struct SqlBonsaiHgMapping {
     // that set in the database. This should be used only when we try to
     // fix broken entries in the db.
     overwrite: bool,
-}
-
-mononoke_queries! {
-    // Sett almost identical ReplaceMapping below
-    write InsertMapping(values: (
-        repo_id: RepositoryId,
-        hg_cs_id: HgChangesetId,
-        bcs_id: ChangesetId,
-    )) {
-        insert_or_ignore,
-        "{insert_or_ignore} INTO bonsai_hg_mapping (repo_id, hg_cs_id, bcs_id) VALUES {values}"
-    }
-
-    // Sett almost identical InsertMapping above
-    write ReplaceMapping(values: (
-        repo_id: RepositoryId,
-        hg_cs_id: HgChangesetId,
-        bcs_id: ChangesetId,
-    )) {
-        none,
-        "REPLACE INTO bonsai_hg_mapping (repo_id, hg_cs_id, bcs_id) VALUES {values}"
-    }
-
-    read SelectMappingByBonsai(
-        repo_id: RepositoryId,
-        tok: i32,
-        >list bcs_id: ChangesetId
-    ) -> (HgChangesetId, ChangesetId, i32) {
-        "SELECT hg_cs_id, bcs_id, {tok}
-         FROM bonsai_hg_mapping
-         WHERE repo_id = {repo_id}
-           AND bcs_id IN {bcs_id}"
-    }
-
-    read SelectMappingByHg(
-        repo_id: RepositoryId,
-        tok: i32,
-        >list hg_cs_id: HgChangesetId
-    ) -> (HgChangesetId, ChangesetId, i32) {
-        "SELECT hg_cs_id, bcs_id, {tok}
-         FROM bonsai_hg_mapping
-         WHERE repo_id = {repo_id}
-           AND hg_cs_id IN {hg_cs_id}"
-    }
-
-    read SelectHgChangesetsByRange(repo_id: RepositoryId, hg_cs_min: &[u8], hg_cs_max: &[u8], limit: usize) -> (HgChangesetId) {
-        "SELECT hg_cs_id
-         FROM bonsai_hg_mapping
-         WHERE repo_id = {repo_id}
-           AND hg_cs_id >= {hg_cs_min} AND hg_cs_id <= {hg_cs_max}
-           LIMIT {limit}
-        "
-    }
+    subscribers: Subscribers,
+    stats: Option<Arc<RepoMappingStats>>,
+    insertion_timestamps: bool,
+    shards: Option<NonZeroUsize>,
 }
 
 #[derive(Clone)]
 pub struct SqlBonsaiHgMappingBuilder {
     connections: SqlConnections,
     overwrite: bool,
+    stats: Option<Arc<MappingStats>>,
+    insertion_timestamps: bool,
+    shards: Option<NonZeroUsize>,
 }
 
 impl SqlConstruct for SqlBonsaiHgMappingBuilder {
@@ -263,6 +344,9 @@ impl SqlConstruct for SqlBonsaiHgMappingBuilder {
         Self {
             connections,
             overwrite: false,
+            stats: None,
+            insertion_timestamps: false,
+            shards: None,
         }
     }
 }
@@ -273,10 +357,62 @@ impl SqlBonsaiHgMappingBuilder {
         self
     }
 
+    /// Record the latency of every call in `stats`.
+    pub fn with_stats(mut self, stats: Arc<MappingStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Enable `get_entries_added_since` and `newest_entry_timestamp`. The
+    /// database must have the `added_at_secs` column, which it fills in on
+    /// insert.
+    pub fn with_insertion_timestamps(mut self) -> Self {
+        self.insertion_timestamps = true;
+        self
+    }
+
+    /// Split the mapping over `shards` tables, `bonsai_hg_mapping_shard_0`
+    /// to `bonsai_hg_mapping_shard_{shards - 1}`, instead of keeping it in
+    /// `bonsai_hg_mapping`. The mapping of a repo is in a single table,
+    /// picked by `shard_index`, so the number of shards can't change once
+    /// the mapping has rows. See `creation_query` for the schema.
+    ///
+    /// Fails with `ErrorKind::TooManyShards` if `shards` is more than
+    /// `MAX_SHARDS`.
+    pub fn with_shards(mut self, shards: NonZeroUsize) -> Result<Self> {
+        if shards.get() > MAX_SHARDS {
+            return Err(ErrorKind::TooManyShards(shards.get(), MAX_SHARDS).into());
+        }
+        self.shards = Some(shards);
+        Ok(self)
+    }
+
+    /// Query creating an empty instance of the database with `shards`
+    /// shards: the tables of `CREATION_QUERY` and those of every shard.
+    pub fn creation_query(shards: NonZeroUsize) -> String {
+        format!(
+            "{}\n{}",
+            Self::CREATION_QUERY,
+            sharding::shard_creation_query(shards)
+        )
+    }
+
+    /// Like `with_sqlite_in_memory`, with a mapping split over `shards`
+    /// tables.
+    pub fn with_sqlite_in_memory_shards(shards: NonZeroUsize) -> Result<Self> {
+        let conn = open_sqlite_in_memory()?;
+        conn.execute_batch(&Self::creation_query(shards))?;
+        let connections = SqlConnections::new_single(Connection::with_sqlite(conn));
+        Self::from_sql_connections(connections).with_shards(shards)
+    }
+
     pub fn build(self, repo_id: RepositoryId, opts: RendezVousOptions) -> SqlBonsaiHgMapping {
         let SqlBonsaiHgMappingBuilder {
             connections,
             overwrite,
+            stats,
+            insertion_timestamps,
+            shards,
         } = self;
 
         SqlBonsaiHgMapping {
@@ -289,6 +425,10 @@ impl SqlBonsaiHgMappingBuilder {
             ),
             repo_id,
             overwrite,
+            subscribers: Subscribers::default(),
+            stats: stats.map(|stats| stats.for_repo(repo_id)),
+            insertion_timestamps,
+            shards,
         }
     }
 }
@@ -296,24 +436,47 @@ impl SqlBonsaiHgMappingBuilder {
 impl SqlConstructFromMetadataDatabaseConfig for SqlBonsaiHgMappingBuilder {}
 
 impl SqlBonsaiHgMapping {
+    /// Receive every entry newly added through this mapping, once it has
+    /// been written to the database.
+    ///
+    /// Notifications are process-local: entries added by other processes or
+    /// hosts are not delivered. If the receiver falls more than
+    /// `SUBSCRIBER_BUFFER_SIZE` entries behind, further entries are dropped
+    /// (see `dropped_notifications`) rather than slowing down writes.
+    pub fn subscribe(&self) -> mpsc::Receiver<BonsaiHgMappingEntry> {
+        self.subscribers.subscribe()
+    }
+
+    /// Number of notifications dropped because a subscriber fell behind.
+    pub fn dropped_notifications(&self) -> u64 {
+        self.subscribers.dropped_notifications()
+    }
+
+    /// The table holding the mapping of `repo_id`.
+    fn table(&self, repo_id: RepositoryId) -> MappingTable {
+        MappingTable::for_repo(repo_id, self.shards)
+    }
+
     async fn verify_consistency(&self, entry: BonsaiHgMappingEntry) -> Result<(), Error> {
         let BonsaiHgMappingEntry { hg_cs_id, bcs_id } = entry.clone();
 
         let tok: i32 = rand::thread_rng().gen();
+        let table = self.table(self.repo_id);
+        let conn = &self.read_master_connection.conn;
         let hg_ids = &[hg_cs_id];
-        let by_hg = SelectMappingByHg::query(
-            &self.read_master_connection.conn,
-            &self.repo_id,
-            &tok,
-            hg_ids,
-        );
+        let by_hg = async {
+            on_table!(
+                table,
+                SelectMappingByHg::query(conn, &self.repo_id, &tok, hg_ids)
+            )
+        };
         let bcs_ids = &[bcs_id];
-        let by_bcs = SelectMappingByBonsai::query(
-            &self.read_master_connection.conn,
-            &self.repo_id,
-            &tok,
-            bcs_ids,
-        );
+        let by_bcs = async {
+            on_table!(
+                table,
+                SelectMappingByBonsai::query(conn, &self.repo_id, &tok, bcs_ids)
+            )
+        };
 
         let (by_hg_rows, by_bcs_rows) = future::try_join(by_hg, by_bcs).await?;
 
@@ -341,62 +504,91 @@ impl BonsaiHgMapping for SqlBonsaiHgMapping {
     }
 
     async fn add(&self, ctx: &CoreContext, entry: BonsaiHgMappingEntry) -> Result<bool, Error> {
-        STATS::adds.add_value(1);
-        ctx.perf_counters()
-            .increment_counter(PerfCounterType::SqlWrites);
-
-        let BonsaiHgMappingEntry { hg_cs_id, bcs_id } = entry.clone();
-
-        if self.overwrite {
-            let result = ReplaceMapping::query(
-                &self.write_connection,
-                &[(&self.repo_id, &hg_cs_id, &bcs_id)],
-            )
-            .await?;
-            Ok(result.affected_rows() >= 1)
-        } else {
-            let result = InsertMapping::query(
-                &self.write_connection,
-                &[(&self.repo_id, &hg_cs_id, &bcs_id)],
-            )
-            .await?;
-            if result.affected_rows() == 1 {
-                Ok(true)
+        RepoMappingStats::measure(self.stats.as_deref(), MappingOperation::Add, async {
+            STATS::adds.add_value(1);
+            ctx.perf_counters()
+                .increment_counter(PerfCounterType::SqlWrites);
+
+            let BonsaiHgMappingEntry { hg_cs_id, bcs_id } = entry.clone();
+            let table = self.table(self.repo_id);
+
+            if self.overwrite {
+                let result = on_table!(
+                    table,
+                    ReplaceMapping::query(
+                        &self.write_connection,
+                        &[(&self.repo_id, &hg_cs_id, &bcs_id)],
+                    )
+                )?;
+                let added = result.affected_rows() >= 1;
+                if added {
+                    self.subscribers.publish(&entry);
+                }
+                Ok(added)
             } else {
-                self.verify_consistency(entry).await?;
-                Ok(false)
+                let result = on_table!(
+                    table,
+                    InsertMapping::query(
+                        &self.write_connection,
+                        &[(&self.repo_id, &hg_cs_id, &bcs_id)],
+                    )
+                )?;
+                if result.affected_rows() == 1 {
+                    self.subscribers.publish(&entry);
+                    Ok(true)
+                } else {
+                    self.verify_consistency(entry).await?;
+                    Ok(false)
+                }
             }
-        }
+        })
+        .await
     }
 
-    async fn get(
+    async fn get_with_freshness(
         &self,
         ctx: &CoreContext,
         ids: BonsaiOrHgChangesetIds,
+        freshness: Freshness,
     ) -> Result<Vec<BonsaiHgMappingEntry>, Error> {
-        STATS::gets.add_value(1);
-        ctx.perf_counters()
-            .increment_counter(PerfCounterType::SqlReadsReplica);
-        let (mut mappings, left_to_fetch) =
-            select_mapping(ctx.fb, &self.read_connection, self.repo_id, ids).await?;
+        RepoMappingStats::measure(self.stats.as_deref(), MappingOperation::Get, async {
+            let table = self.table(self.repo_id);
+            if freshness == Freshness::MostRecent {
+                // Replica reads already fall back to read_master_connection for
+                // missing entries, so go straight to the write connection here.
+                STATS::gets_master.add_value(1);
+                ctx.perf_counters()
+                    .increment_counter(PerfCounterType::SqlReadsMaster);
+                return select_mapping_direct(&self.write_connection, table, self.repo_id, ids)
+                    .await;
+            }
 
-        if left_to_fetch.is_empty() {
-            return Ok(mappings);
-        }
+            STATS::gets.add_value(1);
+            ctx.perf_counters()
+                .increment_counter(PerfCounterType::SqlReadsReplica);
+            let (mut mappings, left_to_fetch) =
+                select_mapping(ctx.fb, &self.read_connection, table, self.repo_id, ids).await?;
 
-        STATS::gets_master.add_value(1);
-        ctx.perf_counters()
-            .increment_counter(PerfCounterType::SqlReadsMaster);
-        let (mut master_mappings, _) = select_mapping(
-            ctx.fb,
-            &self.read_master_connection,
-            self.repo_id,
-            left_to_fetch,
-        )
-        .await?;
+            if left_to_fetch.is_empty() {
+                return Ok(mappings);
+            }
+
+            STATS::gets_master.add_value(1);
+            ctx.perf_counters()
+                .increment_counter(PerfCounterType::SqlReadsMaster);
+            let (mut master_mappings, _) = select_mapping(
+                ctx.fb,
+                &self.read_master_connection,
+                table,
+                self.repo_id,
+                left_to_fetch,
+            )
+            .await?;
 
-        mappings.append(&mut master_mappings);
-        Ok(mappings)
+            mappings.append(&mut master_mappings);
+            Ok(mappings)
+        })
+        .await
     }
 
     /// Return [`HgChangesetId`] entries in the inclusive range described by `low` and `high`.
@@ -408,40 +600,179 @@ impl BonsaiHgMapping for SqlBonsaiHgMapping {
         high: HgChangesetId,
         limit: usize,
     ) -> Result<Vec<HgChangesetId>, Error> {
-        if low > high {
-            return Ok(Vec::new());
+        RepoMappingStats::measure(
+            self.stats.as_deref(),
+            MappingOperation::GetHgInRange,
+            async {
+                if low > high {
+                    return Ok(Vec::new());
+                }
+                let table = self.table(self.repo_id);
+                ctx.perf_counters()
+                    .increment_counter(PerfCounterType::SqlReadsReplica);
+                let rows = on_table!(
+                    table,
+                    SelectHgChangesetsByRange::query(
+                        &self.read_connection.conn,
+                        &self.repo_id,
+                        &low.as_bytes(),
+                        &high.as_bytes(),
+                        &limit
+                    )
+                )?;
+                let mut fetched: Vec<HgChangesetId> = rows.into_iter().map(|row| row.0).collect();
+                if fetched.is_empty() {
+                    ctx.perf_counters()
+                        .increment_counter(PerfCounterType::SqlReadsMaster);
+                    let rows = on_table!(
+                        table,
+                        SelectHgChangesetsByRange::query(
+                            &self.read_master_connection.conn,
+                            &self.repo_id,
+                            &low.as_bytes(),
+                            &high.as_bytes(),
+                            &limit
+                        )
+                    )?;
+                    fetched = rows.into_iter().map(|row| row.0).collect();
+                }
+                Ok(fetched)
+            },
+        )
+        .await
+    }
+
+    /// Return [`ChangesetId`] entries in the inclusive range described by `low` and `high`.
+    /// Maximum `limit` entries will be returned.
+    async fn get_bonsai_in_range(
+        &self,
+        ctx: &CoreContext,
+        low: ChangesetId,
+        high: ChangesetId,
+        limit: usize,
+    ) -> Result<Vec<ChangesetId>, Error> {
+        RepoMappingStats::measure(
+            self.stats.as_deref(),
+            MappingOperation::GetBonsaiInRange,
+            async {
+                if low > high {
+                    return Ok(Vec::new());
+                }
+                let table = self.table(self.repo_id);
+                ctx.perf_counters()
+                    .increment_counter(PerfCounterType::SqlReadsReplica);
+                let rows = on_table!(
+                    table,
+                    SelectBonsaiChangesetsByRange::query(
+                        &self.read_connection.conn,
+                        &self.repo_id,
+                        &low,
+                        &high,
+                        &limit
+                    )
+                )?;
+                let mut fetched: Vec<ChangesetId> = rows.into_iter().map(|row| row.0).collect();
+                if fetched.is_empty() {
+                    ctx.perf_counters()
+                        .increment_counter(PerfCounterType::SqlReadsMaster);
+                    let rows = on_table!(
+                        table,
+                        SelectBonsaiChangesetsByRange::query(
+                            &self.read_master_connection.conn,
+                            &self.repo_id,
+                            &low,
+                            &high,
+                            &limit
+                        )
+                    )?;
+                    fetched = rows.into_iter().map(|row| row.0).collect();
+                }
+                Ok(fetched)
+            },
+        )
+        .await
+    }
+
+    async fn get_entries_added_since(
+        &self,
+        ctx: &CoreContext,
+        since: Timestamp,
+        limit: usize,
+    ) -> Result<Vec<(BonsaiHgMappingEntry, Timestamp)>, Error> {
+        if !self.insertion_timestamps {
+            return Err(ErrorKind::InsertionTimestampsDisabled.into());
         }
         ctx.perf_counters()
             .increment_counter(PerfCounterType::SqlReadsReplica);
-        let rows = SelectHgChangesetsByRange::query(
-            &self.read_connection.conn,
-            &self.repo_id,
-            &low.as_bytes(),
-            &high.as_bytes(),
-            &limit,
-        )
-        .await?;
-        let mut fetched: Vec<HgChangesetId> = rows.into_iter().map(|row| row.0).collect();
-        if fetched.is_empty() {
-            ctx.perf_counters()
-                .increment_counter(PerfCounterType::SqlReadsMaster);
-            let rows = SelectHgChangesetsByRange::query(
-                &self.read_master_connection.conn,
+        let rows = on_table!(
+            self.table(self.repo_id),
+            SelectMappingAddedSince::query(
+                &self.read_connection.conn,
                 &self.repo_id,
-                &low.as_bytes(),
-                &high.as_bytes(),
+                &since.timestamp_seconds(),
                 &limit,
             )
-            .await?;
-            fetched = rows.into_iter().map(|row| row.0).collect();
+        )?;
+        Ok(rows
+            .into_iter()
+            .map(|(hg_cs_id, bcs_id, added_at)| {
+                let entry = BonsaiHgMappingEntry { hg_cs_id, bcs_id };
+                (entry, Timestamp::from_timestamp_secs(added_at))
+            })
+            .collect())
+    }
+
+    async fn newest_entry_timestamp(&self, ctx: &CoreContext) -> Result<Option<Timestamp>, Error> {
+        if !self.insertion_timestamps {
+            return Err(ErrorKind::InsertionTimestampsDisabled.into());
         }
-        Ok(fetched)
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlReadsReplica);
+        let rows = on_table!(
+            self.table(self.repo_id),
+            SelectNewestAddedAt::query(&self.read_connection.conn, &self.repo_id)
+        )?;
+        Ok(rows
+            .into_iter()
+            .next()
+            .and_then(|(added_at,)| added_at)
+            .map(Timestamp::from_timestamp_secs))
+    }
+}
+
+/// Like `select_mapping`, but without batching requests via rendezvous.
+async fn select_mapping_direct(
+    connection: &Connection,
+    table: MappingTable,
+    repo_id: RepositoryId,
+    cs_ids: BonsaiOrHgChangesetIds,
+) -> Result<Vec<BonsaiHgMappingEntry>, Error> {
+    if cs_ids.is_empty() {
+        return Ok(vec![]);
     }
+
+    let tok: i32 = rand::thread_rng().gen();
+    let rows = match cs_ids {
+        BonsaiOrHgChangesetIds::Bonsai(bcs_ids) => on_table!(
+            table,
+            SelectMappingByBonsai::query(connection, &repo_id, &tok, &bcs_ids[..])
+        )?,
+        BonsaiOrHgChangesetIds::Hg(hg_cs_ids) => on_table!(
+            table,
+            SelectMappingByHg::query(connection, &repo_id, &tok, &hg_cs_ids[..])
+        )?,
+    };
+
+    Ok(rows
+        .into_iter()
+        .map(|(hg_cs_id, bcs_id, _)| BonsaiHgMappingEntry { hg_cs_id, bcs_id })
+        .collect())
 }
 
 async fn select_mapping(
     fb: FacebookInit,
     connection: &RendezVousConnection,
+    table: MappingTable,
     repo_id: RepositoryId,
     cs_ids: BonsaiOrHgChangesetIds,
 ) -> Result<(Vec<BonsaiHgMappingEntry>, BonsaiOrHgChangesetIds), Error> {
@@ -460,13 +791,13 @@ async fn select_mapping(
                     move |bcs_ids| async move {
                         let bcs_ids = bcs_ids.into_iter().collect::<Vec<_>>();
 
-                        Ok(
+                        Ok(on_table!(
+                            table,
                             SelectMappingByBonsai::query(&conn, &repo_id, &tok, &bcs_ids[..])
-                                .await?
-                                .into_iter()
-                                .map(|(hg_cs_id, bcs_id, _)| (bcs_id, hg_cs_id))
-                                .collect(),
-                        )
+                        )?
+                        .into_iter()
+                        .map(|(hg_cs_id, bcs_id, _)| (bcs_id, hg_cs_id))
+                        .collect())
                     }
                 })
                 .await?;
@@ -492,13 +823,13 @@ async fn select_mapping(
                     let conn = connection.conn.clone();
                     move |hg_cs_ids| async move {
                         let hg_cs_ids = hg_cs_ids.into_iter().collect::<Vec<_>>();
-                        Ok(
+                        Ok(on_table!(
+                            table,
                             SelectMappingByHg::query(&conn, &repo_id, &tok, &hg_cs_ids[..])
-                                .await?
-                                .into_iter()
-                                .map(|(hg_cs_id, bcs_id, _)| (hg_cs_id, bcs_id))
-                                .collect(),
-                        )
+                        )?
+                        .into_iter()
+                        .map(|(hg_cs_id, bcs_id, _)| (hg_cs_id, bcs_id))
+                        .collect())
                     }
                 })
                 .await?;
@@ -0,0 +1,195 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+use mononoke_types::RepositoryId;
+
+/// Upper bounds of the latency buckets, in microseconds. Latencies above the
+/// last bound go to an extra overflow bucket.
+pub const LATENCY_BUCKETS_US: [u64; 16] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 5_000_000, 10_000_000,
+];
+
+const BUCKETS: usize = LATENCY_BUCKETS_US.len() + 1;
+
+/// Mapping operations whose latency is recorded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub enum MappingOperation {
+    Add,
+    /// `get_with_freshness`, and the `get*` methods using it.
+    Get,
+    /// `get_hg_in_range`, also used for prefix lookups.
+    GetHgInRange,
+    /// `get_bonsai_in_range`, also used for prefix lookups.
+    GetBonsaiInRange,
+}
+
+impl MappingOperation {
+    pub const ALL: [MappingOperation; 4] = [
+        Self::Add,
+        Self::Get,
+        Self::GetHgInRange,
+        Self::GetBonsaiInRange,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Add => "add",
+            Self::Get => "get",
+            Self::GetHgInRange => "get_hg_in_range",
+            Self::GetBonsaiInRange => "get_bonsai_in_range",
+        }
+    }
+}
+
+#[derive(Default)]
+struct OperationStats {
+    buckets: [AtomicU64; BUCKETS],
+    errors: AtomicU64,
+    total_latency_us: AtomicU64,
+}
+
+impl OperationStats {
+    fn snapshot(&self) -> OperationSnapshot {
+        let buckets: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect();
+        let count = buckets.iter().sum();
+        OperationSnapshot {
+            p50: percentile(&buckets, count, 50),
+            p95: percentile(&buckets, count, 95),
+            p99: percentile(&buckets, count, 99),
+            count,
+            errors: self.errors.load(Ordering::Relaxed),
+            total_latency: Duration::from_micros(self.total_latency_us.load(Ordering::Relaxed)),
+            buckets,
+        }
+    }
+}
+
+/// Upper bound of the bucket containing the `pct`th percentile, or `None`
+/// if nothing was recorded. The overflow bucket is reported as the last
+/// bound.
+fn percentile(buckets: &[u64], count: u64, pct: u64) -> Option<Duration> {
+    if count == 0 {
+        return None;
+    }
+    // 1-based rank of the sample at the percentile.
+    let rank = ((count * pct + 99) / 100).max(1);
+    let mut seen = 0;
+    for (i, n) in buckets.iter().enumerate() {
+        seen += n;
+        if seen >= rank {
+            let bound = LATENCY_BUCKETS_US[i.min(LATENCY_BUCKETS_US.len() - 1)];
+            return Some(Duration::from_micros(bound));
+        }
+    }
+    None
+}
+
+/// Latencies and outcomes of the operations of a single repo.
+#[derive(Default)]
+pub struct RepoMappingStats {
+    operations: [OperationStats; MappingOperation::ALL.len()],
+}
+
+impl RepoMappingStats {
+    pub fn record(&self, operation: MappingOperation, latency: Duration, success: bool) {
+        let stats = &self.operations[operation as usize];
+        let us = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = LATENCY_BUCKETS_US.partition_point(|bound| *bound < us);
+        stats.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        stats.total_latency_us.fetch_add(us, Ordering::Relaxed);
+        if !success {
+            stats.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Run `fut` and record its latency and outcome.
+    pub(crate) async fn measure<T>(
+        stats: Option<&RepoMappingStats>,
+        operation: MappingOperation,
+        fut: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let stats = match stats {
+            Some(stats) => stats,
+            None => return fut.await,
+        };
+        let start = Instant::now();
+        let result = fut.await;
+        stats.record(operation, start.elapsed(), result.is_ok());
+        result
+    }
+}
+
+/// Summary of one operation of one repo.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OperationSnapshot {
+    pub count: u64,
+    pub errors: u64,
+    pub total_latency: Duration,
+    /// Number of calls per bucket of `LATENCY_BUCKETS_US`, followed by the
+    /// overflow bucket.
+    pub buckets: Vec<u64>,
+    /// Percentile estimates: the upper bound of the bucket they fall in.
+    pub p50: Option<Duration>,
+    pub p95: Option<Duration>,
+    pub p99: Option<Duration>,
+}
+
+/// Client-side latency of bonsai-hg mapping operations, per repo. Attach it
+/// with `SqlBonsaiHgMappingBuilder::with_stats` or
+/// `CachingBonsaiHgMapping::with_stats`. Attach it to only one layer of a
+/// stack of mappings, or calls are counted once per layer.
+#[derive(Default)]
+pub struct MappingStats {
+    repos: Mutex<HashMap<RepositoryId, Arc<RepoMappingStats>>>,
+}
+
+impl MappingStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The stats of `repo_id`. Mappings look this up once, so recording a
+    /// call only takes a few atomic increments.
+    pub fn for_repo(&self, repo_id: RepositoryId) -> Arc<RepoMappingStats> {
+        self.repos
+            .lock()
+            .expect("lock poisoned")
+            .entry(repo_id)
+            .or_default()
+            .clone()
+    }
+
+    /// Summary of every operation called at least once, per repo.
+    pub fn snapshot(&self) -> HashMap<(RepositoryId, MappingOperation), OperationSnapshot> {
+        let repos = self.repos.lock().expect("lock poisoned");
+        let mut snapshot = HashMap::new();
+        for (repo_id, stats) in repos.iter() {
+            for operation in MappingOperation::ALL {
+                let op = stats.operations[operation as usize].snapshot();
+                if op.count > 0 {
+                    snapshot.insert((*repo_id, operation), op);
+                }
+            }
+        }
+        snapshot
+    }
+}
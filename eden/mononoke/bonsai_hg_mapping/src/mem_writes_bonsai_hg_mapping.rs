@@ -16,6 +16,7 @@ use anyhow::anyhow;
 use anyhow::Error;
 use async_trait::async_trait;
 use context::CoreContext;
+use futures::Future;
 use lock_ext::LockExt;
 use mercurial_types::HgChangesetId;
 use mononoke_types::ChangesetId;
@@ -24,6 +25,7 @@ use mononoke_types::RepositoryId;
 use crate::BonsaiHgMapping;
 use crate::BonsaiHgMappingEntry;
 use crate::BonsaiOrHgChangesetIds;
+use crate::Freshness;
 
 type Cache = (
     HashMap<ChangesetId, HgChangesetId>,
@@ -69,6 +71,7 @@ impl MemWritesBonsaiHgMapping {
         &self,
         ctx: &CoreContext,
         cs_ids: Vec<I>,
+        freshness: Freshness,
         get_cache: impl Fn(&Cache) -> &HashMap<I, O>,
         make_entry: impl Fn(I, O) -> BonsaiHgMappingEntry,
     ) -> Result<Vec<BonsaiHgMappingEntry>, Error>
@@ -92,11 +95,39 @@ impl MemWritesBonsaiHgMapping {
         }
 
         if !self.no_access_to_inner.load(Ordering::Relaxed) {
-            let from_inner = self.inner.get(ctx, from_inner.into()).await?;
+            let from_inner = self
+                .inner
+                .get_with_freshness(ctx, from_inner.into(), freshness)
+                .await?;
             from_cache.extend(from_inner);
         }
         Ok(from_cache)
     }
+
+    /// The first `limit` of the ids in a range written to memory and, unless
+    /// there is no access to it, returned by the inner mapping.
+    async fn merge_in_range<I: Ord>(
+        &self,
+        mut from_cache: Vec<I>,
+        from_inner: impl Future<Output = Result<Vec<I>, Error>>,
+        limit: usize,
+    ) -> Result<Vec<I>, Error> {
+        if !self.no_access_to_inner.load(Ordering::Relaxed) {
+            from_cache.extend(from_inner.await?);
+        }
+        from_cache.sort();
+        from_cache.dedup();
+        from_cache.truncate(limit);
+        Ok(from_cache)
+    }
+}
+
+fn in_range<I: Ord + Copy, O>(cache: &HashMap<I, O>, low: I, high: I) -> Vec<I> {
+    cache
+        .keys()
+        .filter(|id| low <= **id && **id <= high)
+        .copied()
+        .collect()
 }
 
 #[async_trait]
@@ -126,16 +157,18 @@ impl BonsaiHgMapping for MemWritesBonsaiHgMapping {
         }
     }
 
-    async fn get(
+    async fn get_with_freshness(
         &self,
         ctx: &CoreContext,
         cs_ids: BonsaiOrHgChangesetIds,
+        freshness: Freshness,
     ) -> Result<Vec<BonsaiHgMappingEntry>, Error> {
         match cs_ids {
             BonsaiOrHgChangesetIds::Bonsai(bcs_ids) => {
                 self.get_from_cache_and_inner(
                     ctx,
                     bcs_ids,
+                    freshness,
                     |cache| &cache.0,
                     |bcs_id, hg_cs_id| BonsaiHgMappingEntry { bcs_id, hg_cs_id },
                 )
@@ -145,6 +178,7 @@ impl BonsaiHgMapping for MemWritesBonsaiHgMapping {
                 self.get_from_cache_and_inner(
                     ctx,
                     hg_cs_ids,
+                    freshness,
                     |cache| &cache.1,
                     |hg_cs_id, bcs_id| BonsaiHgMappingEntry { bcs_id, hg_cs_id },
                 )
@@ -155,11 +189,25 @@ impl BonsaiHgMapping for MemWritesBonsaiHgMapping {
 
     async fn get_hg_in_range(
         &self,
-        _ctx: &CoreContext,
-        _low: HgChangesetId,
-        _high: HgChangesetId,
-        _limit: usize,
+        ctx: &CoreContext,
+        low: HgChangesetId,
+        high: HgChangesetId,
+        limit: usize,
     ) -> Result<Vec<HgChangesetId>, Error> {
-        unimplemented!("This is not currently implemented in Gitimport")
+        let from_cache = self.cache.with(|cache| in_range(&cache.1, low, high));
+        let from_inner = async { self.inner.get_hg_in_range(ctx, low, high, limit).await };
+        self.merge_in_range(from_cache, from_inner, limit).await
+    }
+
+    async fn get_bonsai_in_range(
+        &self,
+        ctx: &CoreContext,
+        low: ChangesetId,
+        high: ChangesetId,
+        limit: usize,
+    ) -> Result<Vec<ChangesetId>, Error> {
+        let from_cache = self.cache.with(|cache| in_range(&cache.0, low, high));
+        let from_inner = async { self.inner.get_bonsai_in_range(ctx, low, high, limit).await };
+        self.merge_in_range(from_cache, from_inner, limit).await
     }
 }
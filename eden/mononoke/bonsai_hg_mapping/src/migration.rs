@@ -0,0 +1,288 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Moving the mapping of a repo to another repo id, for repos that were
+//! renumbered.
+//!
+//! Rows are moved in batches ordered by bonsai changeset id, each in its own
+//! transaction along with the progress made, so that an interrupted migration
+//! resumes after the last batch committed. Rows of the source repo whose hg
+//! or bonsai changeset id is mapped differently in the target repo are left
+//! in place and reported for an operator to resolve.
+
+use std::collections::HashMap;
+
+use anyhow::Error;
+use anyhow::Result;
+use context::CoreContext;
+use context::PerfCounterType;
+use mononoke_types::hash::Blake2;
+use mononoke_types::ChangesetId;
+use mononoke_types::RepositoryId;
+use rand::Rng;
+use serde::Deserialize;
+use serde::Serialize;
+use sql::Transaction;
+use sql_ext::mononoke_queries;
+
+use crate::sharding::on_table;
+use crate::sharding::MappingTable;
+use crate::BonsaiHgMappingEntry;
+use crate::ErrorKind;
+use crate::SqlBonsaiHgMapping;
+
+mononoke_queries! {
+    read SelectMigrationCursor(
+        from_repo_id: RepositoryId,
+        to_repo_id: RepositoryId,
+    ) -> (ChangesetId) {
+        "SELECT last_bcs_id
+         FROM bonsai_hg_mapping_repo_id_migration
+         WHERE from_repo_id = {from_repo_id} AND to_repo_id = {to_repo_id}"
+    }
+
+    write ReplaceMigrationCursor(values: (
+        from_repo_id: RepositoryId,
+        to_repo_id: RepositoryId,
+        last_bcs_id: ChangesetId,
+    )) {
+        none,
+        "REPLACE INTO bonsai_hg_mapping_repo_id_migration (from_repo_id, to_repo_id, last_bcs_id)
+         VALUES {values}"
+    }
+
+    write DeleteMigrationCursor(
+        from_repo_id: RepositoryId,
+        to_repo_id: RepositoryId,
+    ) {
+        none,
+        "DELETE FROM bonsai_hg_mapping_repo_id_migration
+         WHERE from_repo_id = {from_repo_id} AND to_repo_id = {to_repo_id}"
+    }
+}
+
+/// A row of the source repo left in place, as the target repo maps its hg or
+/// bonsai changeset id differently.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationConflict {
+    pub source: BonsaiHgMappingEntry,
+    /// The row of the target repo sharing an id with `source`.
+    pub target: BonsaiHgMappingEntry,
+}
+
+/// Rows changed by `migrate_repo_id`, or that would have been changed in a
+/// dry run.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationReport {
+    /// Rows moved to the target repo.
+    pub moved: u64,
+    /// Rows the target repo already had, deleted from the source repo.
+    pub skipped: u64,
+    /// Rows left in the source repo, ordered by bonsai changeset id. A
+    /// resumed migration only reports the conflicts of the rows it scanned.
+    pub conflicts: Vec<MigrationConflict>,
+    /// Whether all rows of the source repo were scanned. Otherwise the
+    /// migration resumes where it stopped.
+    pub complete: bool,
+    pub dry_run: bool,
+}
+
+impl MigrationReport {
+    pub fn conflicted(&self) -> u64 {
+        self.conflicts.len() as u64
+    }
+}
+
+impl SqlBonsaiHgMapping {
+    /// Moves the rows of repo `from` to repo `to`, `batch_size` rows per
+    /// transaction, resuming an interrupted migration between the same
+    /// repos. If the mapping is sharded and the repos are in different
+    /// shards, the rows are moved to the shard of `to`. With `dry_run`,
+    /// every transaction is rolled back, so the report shows what would
+    /// change without changing anything.
+    ///
+    /// Caches of the mapping are not invalidated, see
+    /// `CachingBonsaiHgMapping::invalidate_repos`.
+    pub async fn migrate_repo_id(
+        &self,
+        ctx: &CoreContext,
+        from: RepositoryId,
+        to: RepositoryId,
+        batch_size: usize,
+        dry_run: bool,
+    ) -> Result<MigrationReport, Error> {
+        self.migrate_repo_id_batches(ctx, from, to, batch_size, usize::MAX, dry_run)
+            .await
+    }
+
+    /// Same as `migrate_repo_id`, but stops after `max_batches` batches, to
+    /// spread a large migration over several runs.
+    pub async fn migrate_repo_id_batches(
+        &self,
+        ctx: &CoreContext,
+        from: RepositoryId,
+        to: RepositoryId,
+        batch_size: usize,
+        max_batches: usize,
+        dry_run: bool,
+    ) -> Result<MigrationReport, Error> {
+        if from == to {
+            return Err(ErrorKind::MigrationToSameRepo(from).into());
+        }
+        let batch_size = batch_size.max(1);
+        let mut report = MigrationReport {
+            dry_run,
+            ..Default::default()
+        };
+
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlReadsMaster);
+        // No bonsai changeset id is all zeros.
+        let mut cursor = SelectMigrationCursor::query(&self.write_connection, &from, &to)
+            .await?
+            .into_iter()
+            .next()
+            .map_or_else(
+                || ChangesetId::new(Blake2::from_byte_array([0; 32])),
+                |row| row.0,
+            );
+
+        for _ in 0..max_batches {
+            ctx.perf_counters()
+                .increment_counter(PerfCounterType::SqlWrites);
+            let txn = self.write_connection.start_transaction().await?;
+            let (txn, next) = migrate_batch(
+                txn,
+                (self.table(from), from),
+                (self.table(to), to),
+                cursor,
+                batch_size,
+                &mut report,
+            )
+            .await?;
+            if dry_run {
+                txn.rollback().await?;
+            } else {
+                txn.commit().await?;
+            }
+            match next {
+                Some(next) => cursor = next,
+                None => {
+                    report.complete = true;
+                    break;
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Migrates the rows of `from` after `cursor`, returning the cursor to
+/// resume from, or `None` once no row is left.
+async fn migrate_batch(
+    txn: Transaction,
+    (from_table, from): (MappingTable, RepositoryId),
+    (to_table, to): (MappingTable, RepositoryId),
+    cursor: ChangesetId,
+    batch_size: usize,
+    report: &mut MigrationReport,
+) -> Result<(Transaction, Option<ChangesetId>)> {
+    let (txn, rows) = on_table!(
+        from_table,
+        SelectMigrationBatch::query_with_transaction(txn, &from, &cursor, &batch_size)
+    )?;
+    let last = match rows.last() {
+        Some((_, bcs_id, _)) => *bcs_id,
+        None => {
+            let (txn, _) = DeleteMigrationCursor::query_with_transaction(txn, &from, &to).await?;
+            return Ok((txn, None));
+        }
+    };
+
+    let tok: i32 = rand::thread_rng().gen();
+    let bcs_ids: Vec<_> = rows.iter().map(|(_, bcs_id, _)| *bcs_id).collect();
+    let hg_cs_ids: Vec<_> = rows.iter().map(|(hg_cs_id, _, _)| *hg_cs_id).collect();
+    let (txn, by_bonsai) = on_table!(
+        to_table,
+        SelectMappingByBonsai::query_with_transaction(txn, &to, &tok, &bcs_ids[..])
+    )?;
+    let (txn, by_hg) = on_table!(
+        to_table,
+        SelectMappingByHg::query_with_transaction(txn, &to, &tok, &hg_cs_ids[..])
+    )?;
+    let by_bonsai: HashMap<_, _> = by_bonsai
+        .into_iter()
+        .map(|(hg_cs_id, bcs_id, _)| (bcs_id, hg_cs_id))
+        .collect();
+    let by_hg: HashMap<_, _> = by_hg
+        .into_iter()
+        .map(|(hg_cs_id, bcs_id, _)| (hg_cs_id, bcs_id))
+        .collect();
+
+    let mut moved = Vec::new();
+    let mut skipped = Vec::new();
+    for (hg_cs_id, bcs_id, added_at) in rows {
+        let source = BonsaiHgMappingEntry { hg_cs_id, bcs_id };
+        let target = match (by_bonsai.get(&bcs_id), by_hg.get(&hg_cs_id)) {
+            (None, None) => {
+                moved.push((hg_cs_id, bcs_id, added_at));
+                continue;
+            }
+            (Some(target_hg_cs_id), _) if *target_hg_cs_id == hg_cs_id => {
+                skipped.push(bcs_id);
+                continue;
+            }
+            (Some(target_hg_cs_id), _) => BonsaiHgMappingEntry {
+                hg_cs_id: *target_hg_cs_id,
+                bcs_id,
+            },
+            (None, Some(target_bcs_id)) => BonsaiHgMappingEntry {
+                hg_cs_id,
+                bcs_id: *target_bcs_id,
+            },
+        };
+        report.conflicts.push(MigrationConflict { source, target });
+    }
+
+    let mut txn = txn;
+    if !moved.is_empty() && from_table == to_table {
+        let moved: Vec<_> = moved.iter().map(|(_, bcs_id, _)| *bcs_id).collect();
+        let (next, result) = on_table!(
+            from_table,
+            MoveMappingEntries::query_with_transaction(txn, &from, &to, &moved[..])
+        )?;
+        txn = next;
+        report.moved += result.affected_rows();
+    } else if !moved.is_empty() {
+        let values: Vec<_> = moved
+            .iter()
+            .map(|(hg_cs_id, bcs_id, added_at)| (&to, hg_cs_id, bcs_id, added_at))
+            .collect();
+        let (next, result) = on_table!(
+            to_table,
+            InsertMovedMapping::query_with_transaction(txn, &values[..])
+        )?;
+        report.moved += result.affected_rows();
+        let moved: Vec<_> = moved.iter().map(|(_, bcs_id, _)| *bcs_id).collect();
+        let (next, _) = on_table!(
+            from_table,
+            DeleteMappingEntries::query_with_transaction(next, &from, &moved[..])
+        )?;
+        txn = next;
+    }
+    if !skipped.is_empty() {
+        let (next, result) = on_table!(
+            from_table,
+            DeleteMappingEntries::query_with_transaction(txn, &from, &skipped[..])
+        )?;
+        txn = next;
+        report.skipped += result.affected_rows();
+    }
+    let (txn, _) =
+        ReplaceMigrationCursor::query_with_transaction(txn, &[(&from, &to, &last)]).await?;
+    Ok((txn, Some(last)))
+}
@@ -0,0 +1,386 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Error;
+use async_trait::async_trait;
+use context::CoreContext;
+use mercurial_types::HgChangesetId;
+use mercurial_types::HgChangesetIdPrefix;
+use mercurial_types::HgChangesetIdsResolvedFromPrefix;
+use mononoke_types::ChangesetId;
+use mononoke_types::ChangesetIdPrefix;
+use mononoke_types::ChangesetIdsResolvedFromPrefix;
+use mononoke_types::RepositoryId;
+use mononoke_types::Timestamp;
+use slog::warn;
+
+use crate::mapping_stats::MappingOperation;
+use crate::mapping_stats::MappingStats;
+use crate::AnyPrefixResolution;
+use crate::BonsaiHgMapping;
+use crate::BonsaiHgMappingEntry;
+use crate::BonsaiOrHgChangesetIds;
+use crate::Freshness;
+
+/// Receives the latency and outcome of the calls to an
+/// `ObservedBonsaiHgMapping`, to export them to a metrics backend.
+pub trait MappingMetricsSink: Send + Sync {
+    /// `method` is the name of the `BonsaiHgMapping` method called.
+    fn record(&self, repo_id: RepositoryId, method: &'static str, latency: Duration, success: bool);
+}
+
+/// Records the methods matching a `MappingOperation`, ignoring the others.
+impl MappingMetricsSink for MappingStats {
+    fn record(
+        &self,
+        repo_id: RepositoryId,
+        method: &'static str,
+        latency: Duration,
+        success: bool,
+    ) {
+        let operation = match method {
+            "add" => MappingOperation::Add,
+            "get" | "get_with_freshness" | "get_hg_from_bonsai" | "get_bonsai_from_hg" => {
+                MappingOperation::Get
+            }
+            "get_hg_in_range" | "get_many_hg_by_prefix" => MappingOperation::GetHgInRange,
+            "get_bonsai_in_range" | "get_many_bonsai_by_prefix" => {
+                MappingOperation::GetBonsaiInRange
+            }
+            _ => return,
+        };
+        self.for_repo(repo_id).record(operation, latency, success);
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct SlowCallLogging {
+    threshold: Duration,
+    max_ids: usize,
+}
+
+/// The first ids a call was made with, for logging.
+struct SampledIds {
+    ids: Vec<String>,
+    total: usize,
+}
+
+impl fmt::Display for SampledIds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.ids.join(", "))?;
+        if self.total > self.ids.len() {
+            write!(f, " and {} more", self.total - self.ids.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// Forwards every method to `inner`, recording the latency and outcome of
+/// the calls into a `MappingMetricsSink`, and optionally logging slow calls.
+/// With the `tracing` feature, calls are run in a span named after the
+/// method, like `bonsai_hg_mapping.get`, with `repo_id` and `batch_size`
+/// fields.
+///
+/// Default methods are forwarded too, so a call is recorded once, under the
+/// method the caller called. Wrapped around a `CachingBonsaiHgMapping`, it
+/// sees every call, cache hits being fast ones. Wrapped inside, it only sees
+/// the cache misses and the reads bypassing the cache.
+pub struct ObservedBonsaiHgMapping<M: ?Sized = dyn BonsaiHgMapping> {
+    inner: Arc<M>,
+    sink: Arc<dyn MappingMetricsSink>,
+    slow_calls: Option<SlowCallLogging>,
+}
+
+/// Runs `$call` in the span of `$method`, and records it.
+macro_rules! observe {
+    ($self:ident, $ctx:ident, $method:literal, $batch_size:expr, $ids:expr, $call:expr) => {{
+        let batch_size: usize = $batch_size;
+        let ids = $self.sample_ids(batch_size, $ids);
+        let call = $call;
+        #[cfg(feature = "tracing")]
+        let call = tracing::Instrument::instrument(
+            call,
+            tracing::info_span!(
+                concat!("bonsai_hg_mapping.", $method),
+                repo_id = $self.inner.repo_id().id(),
+                batch_size = batch_size
+            ),
+        );
+        $self.observe($ctx, $method, ids, call).await
+    }};
+}
+
+impl<M: BonsaiHgMapping + ?Sized> ObservedBonsaiHgMapping<M> {
+    pub fn new(inner: Arc<M>, sink: Arc<dyn MappingMetricsSink>) -> Self {
+        Self {
+            inner,
+            sink,
+            slow_calls: None,
+        }
+    }
+
+    /// Log calls taking `threshold` or more as warnings to the logger of
+    /// their context, with up to `max_ids` of the ids they were made with.
+    pub fn with_slow_call_logging(mut self, threshold: Duration, max_ids: usize) -> Self {
+        self.slow_calls = Some(SlowCallLogging { threshold, max_ids });
+        self
+    }
+
+    pub fn inner(&self) -> &Arc<M> {
+        &self.inner
+    }
+
+    /// Formats the first `ids`, unless slow calls are not logged.
+    fn sample_ids<T: fmt::Display>(
+        &self,
+        total: usize,
+        ids: impl IntoIterator<Item = T>,
+    ) -> Option<SampledIds> {
+        let slow_calls = self.slow_calls.as_ref()?;
+        let ids = ids
+            .into_iter()
+            .take(slow_calls.max_ids)
+            .map(|id| id.to_string())
+            .collect();
+        Some(SampledIds { ids, total })
+    }
+
+    async fn observe<T>(
+        &self,
+        ctx: &CoreContext,
+        method: &'static str,
+        ids: Option<SampledIds>,
+        call: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        let start = Instant::now();
+        let result = call.await;
+        let latency = start.elapsed();
+        let repo_id = self.inner.repo_id();
+        self.sink.record(repo_id, method, latency, result.is_ok());
+        if let (Some(slow_calls), Some(ids)) = (&self.slow_calls, ids) {
+            if latency >= slow_calls.threshold {
+                warn!(
+                    ctx.logger(),
+                    "Slow call to bonsai_hg_mapping.{} for repo {}: {:?}, ids: {}",
+                    method,
+                    repo_id,
+                    latency,
+                    ids
+                );
+            }
+        }
+        result
+    }
+}
+
+fn cs_ids(cs_ids: &BonsaiOrHgChangesetIds) -> Box<dyn Iterator<Item = String> + '_> {
+    match cs_ids {
+        BonsaiOrHgChangesetIds::Bonsai(ids) => Box::new(ids.iter().map(|id| id.to_string())),
+        BonsaiOrHgChangesetIds::Hg(ids) => Box::new(ids.iter().map(|id| id.to_string())),
+    }
+}
+
+fn cs_ids_len(cs_ids: &BonsaiOrHgChangesetIds) -> usize {
+    match cs_ids {
+        BonsaiOrHgChangesetIds::Bonsai(ids) => ids.len(),
+        BonsaiOrHgChangesetIds::Hg(ids) => ids.len(),
+    }
+}
+
+#[async_trait]
+impl<M: BonsaiHgMapping + ?Sized> BonsaiHgMapping for ObservedBonsaiHgMapping<M> {
+    fn repo_id(&self) -> RepositoryId {
+        self.inner.repo_id()
+    }
+
+    async fn add(&self, ctx: &CoreContext, entry: BonsaiHgMappingEntry) -> Result<bool, Error> {
+        observe!(
+            self,
+            ctx,
+            "add",
+            1,
+            [entry.bcs_id],
+            self.inner.add(ctx, entry)
+        )
+    }
+
+    async fn get(
+        &self,
+        ctx: &CoreContext,
+        cs_id: BonsaiOrHgChangesetIds,
+    ) -> Result<Vec<BonsaiHgMappingEntry>, Error> {
+        observe!(
+            self,
+            ctx,
+            "get",
+            cs_ids_len(&cs_id),
+            cs_ids(&cs_id),
+            self.inner.get(ctx, cs_id)
+        )
+    }
+
+    async fn get_with_freshness(
+        &self,
+        ctx: &CoreContext,
+        cs_id: BonsaiOrHgChangesetIds,
+        freshness: Freshness,
+    ) -> Result<Vec<BonsaiHgMappingEntry>, Error> {
+        observe!(
+            self,
+            ctx,
+            "get_with_freshness",
+            cs_ids_len(&cs_id),
+            cs_ids(&cs_id),
+            self.inner.get_with_freshness(ctx, cs_id, freshness)
+        )
+    }
+
+    async fn get_hg_from_bonsai(
+        &self,
+        ctx: &CoreContext,
+        cs_id: ChangesetId,
+    ) -> Result<Option<HgChangesetId>, Error> {
+        observe!(
+            self,
+            ctx,
+            "get_hg_from_bonsai",
+            1,
+            [cs_id],
+            self.inner.get_hg_from_bonsai(ctx, cs_id)
+        )
+    }
+
+    async fn get_bonsai_from_hg(
+        &self,
+        ctx: &CoreContext,
+        cs_id: HgChangesetId,
+    ) -> Result<Option<ChangesetId>, Error> {
+        observe!(
+            self,
+            ctx,
+            "get_bonsai_from_hg",
+            1,
+            [cs_id],
+            self.inner.get_bonsai_from_hg(ctx, cs_id)
+        )
+    }
+
+    async fn get_many_hg_by_prefix(
+        &self,
+        ctx: &CoreContext,
+        cs_prefix: HgChangesetIdPrefix,
+        limit: usize,
+    ) -> Result<HgChangesetIdsResolvedFromPrefix, Error> {
+        observe!(
+            self,
+            ctx,
+            "get_many_hg_by_prefix",
+            limit,
+            [cs_prefix],
+            self.inner.get_many_hg_by_prefix(ctx, cs_prefix, limit)
+        )
+    }
+
+    async fn get_hg_in_range(
+        &self,
+        ctx: &CoreContext,
+        low: HgChangesetId,
+        high: HgChangesetId,
+        limit: usize,
+    ) -> Result<Vec<HgChangesetId>, Error> {
+        observe!(
+            self,
+            ctx,
+            "get_hg_in_range",
+            limit,
+            [format!("{}..{}", low, high)],
+            self.inner.get_hg_in_range(ctx, low, high, limit)
+        )
+    }
+
+    async fn get_many_bonsai_by_prefix(
+        &self,
+        ctx: &CoreContext,
+        cs_prefix: ChangesetIdPrefix,
+        limit: usize,
+    ) -> Result<ChangesetIdsResolvedFromPrefix, Error> {
+        observe!(
+            self,
+            ctx,
+            "get_many_bonsai_by_prefix",
+            limit,
+            [cs_prefix],
+            self.inner.get_many_bonsai_by_prefix(ctx, cs_prefix, limit)
+        )
+    }
+
+    async fn get_bonsai_in_range(
+        &self,
+        ctx: &CoreContext,
+        low: ChangesetId,
+        high: ChangesetId,
+        limit: usize,
+    ) -> Result<Vec<ChangesetId>, Error> {
+        observe!(
+            self,
+            ctx,
+            "get_bonsai_in_range",
+            limit,
+            [format!("{}..{}", low, high)],
+            self.inner.get_bonsai_in_range(ctx, low, high, limit)
+        )
+    }
+
+    async fn resolve_prefix_any(
+        &self,
+        ctx: &CoreContext,
+        hex_prefix: &str,
+        limit: usize,
+    ) -> Result<AnyPrefixResolution, Error> {
+        observe!(
+            self,
+            ctx,
+            "resolve_prefix_any",
+            limit,
+            [hex_prefix],
+            self.inner.resolve_prefix_any(ctx, hex_prefix, limit)
+        )
+    }
+
+    async fn get_entries_added_since(
+        &self,
+        ctx: &CoreContext,
+        since: Timestamp,
+        limit: usize,
+    ) -> Result<Vec<(BonsaiHgMappingEntry, Timestamp)>, Error> {
+        observe!(
+            self,
+            ctx,
+            "get_entries_added_since",
+            limit,
+            [since.timestamp_seconds()],
+            self.inner.get_entries_added_since(ctx, since, limit)
+        )
+    }
+
+    async fn newest_entry_timestamp(&self, ctx: &CoreContext) -> Result<Option<Timestamp>, Error> {
+        observe!(
+            self,
+            ctx,
+            "newest_entry_timestamp",
+            0,
+            None::<String>,
+            self.inner.newest_entry_timestamp(ctx)
+        )
+    }
+}
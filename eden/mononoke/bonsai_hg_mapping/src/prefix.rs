@@ -0,0 +1,80 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::str::FromStr;
+
+use mercurial_types::HgChangesetId;
+use mercurial_types::HgChangesetIdPrefix;
+use mercurial_types::HgChangesetIdsResolvedFromPrefix;
+use mononoke_types::ChangesetId;
+use mononoke_types::ChangesetIdPrefix;
+use mononoke_types::ChangesetIdsResolvedFromPrefix;
+
+use crate::errors::ErrorKind;
+
+/// Length of a full hg changeset id, in hex digits.
+const HG_HEX_LEN: usize = 40;
+/// Length of a full bonsai changeset id, in hex digits.
+const BONSAI_HEX_LEN: usize = 64;
+
+/// The changesets matching a hex prefix, of either kind of id. See
+/// `BonsaiHgMapping::resolve_prefix_any`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AnyPrefixResolution {
+    pub hg: HgChangesetIdsResolvedFromPrefix,
+    pub bonsai: ChangesetIdsResolvedFromPrefix,
+}
+
+/// What a hex prefix resolves to, across both kinds of id.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PrefixClassification {
+    /// A single hg changeset id, and no bonsai one.
+    UniqueHg(HgChangesetId),
+    /// A single bonsai changeset id, and no hg one.
+    UniqueBonsai(ChangesetId),
+    /// Ids of both kinds, even if they are the same changeset.
+    AmbiguousAcrossKinds,
+    /// Several ids of one kind.
+    Multiple,
+    NoMatch,
+}
+
+impl AnyPrefixResolution {
+    pub fn classification(&self) -> PrefixClassification {
+        use ChangesetIdsResolvedFromPrefix as Bonsai;
+        use HgChangesetIdsResolvedFromPrefix as Hg;
+
+        match (&self.hg, &self.bonsai) {
+            (Hg::NoMatch, Bonsai::NoMatch) => PrefixClassification::NoMatch,
+            (Hg::Single(hg_cs_id), Bonsai::NoMatch) => PrefixClassification::UniqueHg(*hg_cs_id),
+            (Hg::NoMatch, Bonsai::Single(bcs_id)) => PrefixClassification::UniqueBonsai(*bcs_id),
+            (Hg::NoMatch, _) | (_, Bonsai::NoMatch) => PrefixClassification::Multiple,
+            _ => PrefixClassification::AmbiguousAcrossKinds,
+        }
+    }
+}
+
+/// Parses user input as a prefix of both kinds of id. The hg prefix is
+/// `None` if the input is too long to be one.
+pub(crate) fn parse_hex_prefix(
+    hex_prefix: &str,
+) -> Result<(Option<HgChangesetIdPrefix>, ChangesetIdPrefix), ErrorKind> {
+    let invalid = || ErrorKind::InvalidHexPrefix(hex_prefix.to_string());
+    if hex_prefix.is_empty()
+        || hex_prefix.len() > BONSAI_HEX_LEN
+        || !hex_prefix.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        return Err(invalid());
+    }
+    let hg = if hex_prefix.len() <= HG_HEX_LEN {
+        Some(HgChangesetIdPrefix::from_str(hex_prefix).map_err(|_| invalid())?)
+    } else {
+        None
+    };
+    let bonsai = ChangesetIdPrefix::from_str(hex_prefix).map_err(|_| invalid())?;
+    Ok((hg, bonsai))
+}
@@ -0,0 +1,338 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Sharding of the mapping by repo id, see
+//! `SqlBonsaiHgMappingBuilder::with_shards`.
+//!
+//! Every query on the mapping is generated once per table: in the
+//! `unsharded` module for `bonsai_hg_mapping`, and in `shard_{k}` for
+//! `bonsai_hg_mapping_shard_{k}`. Callers pick the table with `on_table!`.
+
+use std::num::NonZeroUsize;
+
+use mononoke_types::RepositoryId;
+
+/// Most shards a mapping can be split into.
+pub const MAX_SHARDS: usize = 16;
+
+const SHARD_CREATION_QUERY: &str = include_str!("../schemas/sqlite-bonsai-hg-mapping-shard.sql");
+
+/// The shard holding the mapping of `repo_id`, out of `shards`. This must
+/// never change, as the rows of a repo would be looked up in another table.
+pub fn shard_index(repo_id: RepositoryId, shards: NonZeroUsize) -> usize {
+    // The finalizer of splitmix64, so that consecutive repo ids spread over
+    // all shards.
+    let mut z = (repo_id.id() as u32 as u64).wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+    (z % shards.get() as u64) as usize
+}
+
+/// Query creating the tables of `shards` shards.
+pub(crate) fn shard_creation_query(shards: NonZeroUsize) -> String {
+    (0..shards.get())
+        .map(|shard| SHARD_CREATION_QUERY.replace("{shard}", &shard.to_string()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The table holding the mapping of a repo.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MappingTable {
+    /// `bonsai_hg_mapping`.
+    Unsharded,
+    /// `bonsai_hg_mapping_shard_{k}`.
+    Shard(usize),
+}
+
+impl MappingTable {
+    pub(crate) fn for_repo(repo_id: RepositoryId, shards: Option<NonZeroUsize>) -> Self {
+        match shards {
+            None => MappingTable::Unsharded,
+            Some(shards) => MappingTable::Shard(shard_index(repo_id, shards)),
+        }
+    }
+}
+
+/// Awaits the query `$query::$method(...)` on the table `$table`, e.g.
+/// `on_table!(table, SelectMappingByHg::query(conn, &repo_id, &tok, ids))`.
+macro_rules! on_table {
+    ($table:expr, $query:ident :: $method:ident ( $( $arg:expr ),* $(,)? )) => {{
+        use $crate::sharding::*;
+        match $table {
+            MappingTable::Unsharded => unsharded::$query::$method($( $arg ),*).await,
+            MappingTable::Shard(0) => shard_0::$query::$method($( $arg ),*).await,
+            MappingTable::Shard(1) => shard_1::$query::$method($( $arg ),*).await,
+            MappingTable::Shard(2) => shard_2::$query::$method($( $arg ),*).await,
+            MappingTable::Shard(3) => shard_3::$query::$method($( $arg ),*).await,
+            MappingTable::Shard(4) => shard_4::$query::$method($( $arg ),*).await,
+            MappingTable::Shard(5) => shard_5::$query::$method($( $arg ),*).await,
+            MappingTable::Shard(6) => shard_6::$query::$method($( $arg ),*).await,
+            MappingTable::Shard(7) => shard_7::$query::$method($( $arg ),*).await,
+            MappingTable::Shard(8) => shard_8::$query::$method($( $arg ),*).await,
+            MappingTable::Shard(9) => shard_9::$query::$method($( $arg ),*).await,
+            MappingTable::Shard(10) => shard_10::$query::$method($( $arg ),*).await,
+            MappingTable::Shard(11) => shard_11::$query::$method($( $arg ),*).await,
+            MappingTable::Shard(12) => shard_12::$query::$method($( $arg ),*).await,
+            MappingTable::Shard(13) => shard_13::$query::$method($( $arg ),*).await,
+            MappingTable::Shard(14) => shard_14::$query::$method($( $arg ),*).await,
+            MappingTable::Shard(15) => shard_15::$query::$method($( $arg ),*).await,
+            // `with_shards` rejects more than `MAX_SHARDS` shards.
+            MappingTable::Shard(shard) => unreachable!("no shard {}", shard),
+        }
+    }};
+}
+
+pub(crate) use on_table;
+
+macro_rules! mapping_queries {
+    ($( $module:ident: $table:literal, )*) => {
+        $(
+            pub(crate) mod $module {
+                use mercurial_types::HgChangesetId;
+                use mononoke_types::ChangesetId;
+                use mononoke_types::RepositoryId;
+                use sql_ext::mononoke_queries;
+
+                mononoke_queries! {
+                    // See almost identical ReplaceMapping below
+                    pub(crate) write InsertMapping(values: (
+                        repo_id: RepositoryId,
+                        hg_cs_id: HgChangesetId,
+                        bcs_id: ChangesetId,
+                    )) {
+                        insert_or_ignore,
+                        concat!(
+                            "{insert_or_ignore} INTO ", $table,
+                            " (repo_id, hg_cs_id, bcs_id) VALUES {values}"
+                        )
+                    }
+
+                    // See almost identical InsertMapping above
+                    pub(crate) write ReplaceMapping(values: (
+                        repo_id: RepositoryId,
+                        hg_cs_id: HgChangesetId,
+                        bcs_id: ChangesetId,
+                    )) {
+                        none,
+                        concat!(
+                            "REPLACE INTO ", $table,
+                            " (repo_id, hg_cs_id, bcs_id) VALUES {values}"
+                        )
+                    }
+
+                    pub(crate) read SelectMappingByBonsai(
+                        repo_id: RepositoryId,
+                        tok: i32,
+                        >list bcs_id: ChangesetId
+                    ) -> (HgChangesetId, ChangesetId, i32) {
+                        concat!(
+                            "SELECT hg_cs_id, bcs_id, {tok}
+                             FROM ", $table, "
+                             WHERE repo_id = {repo_id}
+                               AND bcs_id IN {bcs_id}"
+                        )
+                    }
+
+                    pub(crate) read SelectMappingByHg(
+                        repo_id: RepositoryId,
+                        tok: i32,
+                        >list hg_cs_id: HgChangesetId
+                    ) -> (HgChangesetId, ChangesetId, i32) {
+                        concat!(
+                            "SELECT hg_cs_id, bcs_id, {tok}
+                             FROM ", $table, "
+                             WHERE repo_id = {repo_id}
+                               AND hg_cs_id IN {hg_cs_id}"
+                        )
+                    }
+
+                    pub(crate) read SelectHgChangesetsByRange(
+                        repo_id: RepositoryId,
+                        hg_cs_min: &[u8],
+                        hg_cs_max: &[u8],
+                        limit: usize,
+                    ) -> (HgChangesetId) {
+                        concat!(
+                            "SELECT hg_cs_id
+                             FROM ", $table, "
+                             WHERE repo_id = {repo_id}
+                               AND hg_cs_id >= {hg_cs_min} AND hg_cs_id <= {hg_cs_max}
+                             LIMIT {limit}"
+                        )
+                    }
+
+                    pub(crate) read SelectBonsaiChangesetsByRange(
+                        repo_id: RepositoryId,
+                        bcs_min: ChangesetId,
+                        bcs_max: ChangesetId,
+                        limit: usize,
+                    ) -> (ChangesetId) {
+                        concat!(
+                            "SELECT bcs_id
+                             FROM ", $table, "
+                             WHERE repo_id = {repo_id}
+                               AND bcs_id >= {bcs_min} AND bcs_id <= {bcs_max}
+                             LIMIT {limit}"
+                        )
+                    }
+
+                    pub(crate) read SelectMappingAddedSince(
+                        repo_id: RepositoryId,
+                        since_secs: i64,
+                        limit: usize,
+                    ) -> (HgChangesetId, ChangesetId, i64) {
+                        concat!(
+                            "SELECT hg_cs_id, bcs_id, added_at_secs
+                             FROM ", $table, "
+                             WHERE repo_id = {repo_id}
+                               AND added_at_secs >= {since_secs}
+                             ORDER BY added_at_secs DESC
+                             LIMIT {limit}"
+                        )
+                    }
+
+                    pub(crate) read SelectNewestAddedAt(repo_id: RepositoryId) -> (Option<i64>) {
+                        concat!(
+                            "SELECT MAX(added_at_secs)
+                             FROM ", $table, "
+                             WHERE repo_id = {repo_id}"
+                        )
+                    }
+
+                    pub(crate) read SelectMappingChunkByBonsai(
+                        repo_id: RepositoryId,
+                        start: ChangesetId,
+                        limit: usize,
+                    ) -> (HgChangesetId, ChangesetId) {
+                        concat!(
+                            "SELECT hg_cs_id, bcs_id
+                             FROM ", $table, "
+                             WHERE repo_id = {repo_id} AND bcs_id >= {start}
+                             ORDER BY bcs_id, hg_cs_id
+                             LIMIT {limit}"
+                        )
+                    }
+
+                    pub(crate) read SelectMappingChunkByHg(
+                        repo_id: RepositoryId,
+                        start: HgChangesetId,
+                        limit: usize,
+                    ) -> (HgChangesetId, ChangesetId) {
+                        concat!(
+                            "SELECT hg_cs_id, bcs_id
+                             FROM ", $table, "
+                             WHERE repo_id = {repo_id} AND hg_cs_id >= {start}
+                             ORDER BY hg_cs_id, bcs_id
+                             LIMIT {limit}"
+                        )
+                    }
+
+                    pub(crate) write DeleteMappingEntry(
+                        repo_id: RepositoryId,
+                        hg_cs_id: HgChangesetId,
+                        bcs_id: ChangesetId,
+                    ) {
+                        none,
+                        concat!(
+                            "DELETE FROM ", $table, "
+                             WHERE repo_id = {repo_id}
+                               AND hg_cs_id = {hg_cs_id} AND bcs_id = {bcs_id}"
+                        )
+                    }
+
+                    pub(crate) write DeleteConflictingEntries(
+                        repo_id: RepositoryId,
+                        hg_cs_id: HgChangesetId,
+                        bcs_id: ChangesetId,
+                    ) {
+                        none,
+                        concat!(
+                            "DELETE FROM ", $table, "
+                             WHERE repo_id = {repo_id}
+                               AND (hg_cs_id = {hg_cs_id} OR bcs_id = {bcs_id})"
+                        )
+                    }
+
+                    pub(crate) read SelectMigrationBatch(
+                        repo_id: RepositoryId,
+                        after: ChangesetId,
+                        limit: usize,
+                    ) -> (HgChangesetId, ChangesetId, Option<i64>) {
+                        concat!(
+                            "SELECT hg_cs_id, bcs_id, added_at_secs
+                             FROM ", $table, "
+                             WHERE repo_id = {repo_id} AND bcs_id > {after}
+                             ORDER BY bcs_id
+                             LIMIT {limit}"
+                        )
+                    }
+
+                    pub(crate) write MoveMappingEntries(
+                        from_repo_id: RepositoryId,
+                        to_repo_id: RepositoryId,
+                        >list bcs_id: ChangesetId
+                    ) {
+                        none,
+                        concat!(
+                            "UPDATE ", $table, "
+                             SET repo_id = {to_repo_id}
+                             WHERE repo_id = {from_repo_id} AND bcs_id IN {bcs_id}"
+                        )
+                    }
+
+                    // Moves rows from another table, keeping their insertion
+                    // times.
+                    pub(crate) write InsertMovedMapping(values: (
+                        repo_id: RepositoryId,
+                        hg_cs_id: HgChangesetId,
+                        bcs_id: ChangesetId,
+                        added_at_secs: Option<i64>,
+                    )) {
+                        none,
+                        concat!(
+                            "INSERT INTO ", $table,
+                            " (repo_id, hg_cs_id, bcs_id, added_at_secs) VALUES {values}"
+                        )
+                    }
+
+                    pub(crate) write DeleteMappingEntries(
+                        repo_id: RepositoryId,
+                        >list bcs_id: ChangesetId
+                    ) {
+                        none,
+                        concat!(
+                            "DELETE FROM ", $table, "
+                             WHERE repo_id = {repo_id} AND bcs_id IN {bcs_id}"
+                        )
+                    }
+                }
+            }
+        )*
+    };
+}
+
+mapping_queries! {
+    unsharded: "bonsai_hg_mapping",
+    shard_0: "bonsai_hg_mapping_shard_0",
+    shard_1: "bonsai_hg_mapping_shard_1",
+    shard_2: "bonsai_hg_mapping_shard_2",
+    shard_3: "bonsai_hg_mapping_shard_3",
+    shard_4: "bonsai_hg_mapping_shard_4",
+    shard_5: "bonsai_hg_mapping_shard_5",
+    shard_6: "bonsai_hg_mapping_shard_6",
+    shard_7: "bonsai_hg_mapping_shard_7",
+    shard_8: "bonsai_hg_mapping_shard_8",
+    shard_9: "bonsai_hg_mapping_shard_9",
+    shard_10: "bonsai_hg_mapping_shard_10",
+    shard_11: "bonsai_hg_mapping_shard_11",
+    shard_12: "bonsai_hg_mapping_shard_12",
+    shard_13: "bonsai_hg_mapping_shard_13",
+    shard_14: "bonsai_hg_mapping_shard_14",
+    shard_15: "bonsai_hg_mapping_shard_15",
+}
@@ -0,0 +1,59 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use futures::channel::mpsc;
+use stats::prelude::*;
+
+use crate::BonsaiHgMappingEntry;
+
+define_stats! {
+    prefix = "mononoke.bonsai_hg_mapping.subscribers";
+    notifications_dropped: timeseries(Rate, Sum),
+}
+
+/// Number of notifications a subscriber can fall behind by before further
+/// notifications to it are dropped.
+pub const SUBSCRIBER_BUFFER_SIZE: usize = 1000;
+
+/// Fan-out of newly added mappings to subscribers in the same process.
+/// Publishing never blocks: notifications to subscribers whose buffer is full
+/// are dropped and counted, and subscribers whose receiver was dropped are
+/// forgotten.
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    senders: Mutex<Vec<mpsc::Sender<BonsaiHgMappingEntry>>>,
+    dropped: AtomicU64,
+}
+
+impl Subscribers {
+    pub(crate) fn subscribe(&self) -> mpsc::Receiver<BonsaiHgMappingEntry> {
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_BUFFER_SIZE);
+        self.senders.lock().expect("lock poisoned").push(sender);
+        receiver
+    }
+
+    pub(crate) fn publish(&self, entry: &BonsaiHgMappingEntry) {
+        let mut senders = self.senders.lock().expect("lock poisoned");
+        senders.retain_mut(|sender| match sender.try_send(entry.clone()) {
+            Ok(()) => true,
+            Err(e) if e.is_disconnected() => false,
+            Err(_) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                STATS::notifications_dropped.add_value(1);
+                true
+            }
+        });
+    }
+
+    pub(crate) fn dropped_notifications(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
@@ -7,34 +7,75 @@
 
 //! Tests for the Changesets store.
 
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
 use std::str::FromStr;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
 
 use anyhow::Error;
 use assert_matches::assert_matches;
-use async_trait::async_trait;
+use blobstore::Blobstore;
+use blobstore::BlobstoreBytes;
+use bonsai_hg_mapping::shard_index;
+use bonsai_hg_mapping::AnyPrefixResolution;
 use bonsai_hg_mapping::BonsaiHgMapping;
 use bonsai_hg_mapping::BonsaiHgMappingEntry;
 use bonsai_hg_mapping::BonsaiOrHgChangesetIds;
 use bonsai_hg_mapping::CachingBonsaiHgMapping;
+use bonsai_hg_mapping::ConsistencyReport;
 use bonsai_hg_mapping::ErrorKind;
+use bonsai_hg_mapping::Freshness;
+use bonsai_hg_mapping::LazyPopulatingBonsaiHgMapping;
+use bonsai_hg_mapping::MappingMetricsSink;
+use bonsai_hg_mapping::MappingOperation;
+use bonsai_hg_mapping::MappingResolver;
+use bonsai_hg_mapping::MappingStats;
+use bonsai_hg_mapping::MemWritesBonsaiHgMapping;
+use bonsai_hg_mapping::MigrationConflict;
+use bonsai_hg_mapping::MigrationReport;
+use bonsai_hg_mapping::ObservedBonsaiHgMapping;
+use bonsai_hg_mapping::PrefixClassification;
+use bonsai_hg_mapping::RepairOutcome;
+use bonsai_hg_mapping::RepairPlan;
+use bonsai_hg_mapping::SqlBonsaiHgMapping;
 use bonsai_hg_mapping::SqlBonsaiHgMappingBuilder;
+use bonsai_hg_mapping::VerifyOptions;
+use bonsai_hg_mapping_test_utils::MappingFixture;
+use bonsai_hg_mapping_test_utils::TestFixture;
 use context::CoreContext;
 use fbinit::FacebookInit;
+use futures::FutureExt;
 use mercurial_types::HgChangesetId;
 use mercurial_types::HgChangesetIdPrefix;
 use mercurial_types::HgChangesetIdsResolvedFromPrefix;
 use mercurial_types_mocks::nodehash as hg;
+use mononoke_types::BlobstoreKey;
+use mononoke_types::ChangesetId;
 use mononoke_types::RepositoryId;
+use mononoke_types::Timestamp;
 use mononoke_types_mocks::changesetid as bonsai;
+use mononoke_types_mocks::repo::REPO_ONE;
 use mononoke_types_mocks::repo::REPO_ZERO;
 use rendezvous::RendezVousOptions;
+use slog::o;
+use slog::Drain;
+use slog::Logger;
+use slog::Never;
+use slog::OwnedKVList;
+use slog::Record;
+use sql::rusqlite::Connection as SqliteConnection;
+use sql::Connection;
 use sql_construct::SqlConstruct;
+use sql_ext::mononoke_queries;
+use sql_ext::SqlConnections;
 
-async fn add_and_get<M: BonsaiHgMapping>(fb: FacebookInit, mapping: M) {
-    let ctx = CoreContext::test_mock(fb);
+async fn add_and_get(fixture: MappingFixture) {
+    let MappingFixture { ctx, mapping, .. } = fixture;
     let entry = BonsaiHgMappingEntry {
         hg_cs_id: hg::ONES_CSID,
         bcs_id: bonsai::ONES_CSID,
@@ -95,8 +136,8 @@ async fn add_and_get<M: BonsaiHgMapping>(fb: FacebookInit, mapping: M) {
     );
 }
 
-async fn missing<M: BonsaiHgMapping>(fb: FacebookInit, mapping: M) {
-    let ctx = CoreContext::test_mock(fb);
+async fn missing(fixture: MappingFixture) {
+    let MappingFixture { ctx, mapping, .. } = fixture;
     let result = mapping
         .get(&ctx, bonsai::ONES_CSID.into())
         .await
@@ -104,8 +145,8 @@ async fn missing<M: BonsaiHgMapping>(fb: FacebookInit, mapping: M) {
     assert_eq!(result, vec![]);
 }
 
-async fn get_many_hg_by_prefix<M: BonsaiHgMapping>(fb: FacebookInit, mapping: M) {
-    let ctx = CoreContext::test_mock(fb);
+async fn get_many_hg_by_prefix(fixture: MappingFixture) {
+    let MappingFixture { ctx, mapping, .. } = fixture;
 
     let entry1 = BonsaiHgMappingEntry {
         hg_cs_id: hg::ONES_CSID,
@@ -233,8 +274,8 @@ async fn get_many_hg_by_prefix<M: BonsaiHgMapping>(fb: FacebookInit, mapping: M)
     assert_eq!(result, HgChangesetIdsResolvedFromPrefix::NoMatch);
 }
 
-async fn get_hg_in_range<M: BonsaiHgMapping>(fb: FacebookInit, mapping: M) {
-    let ctx = CoreContext::test_mock(fb);
+async fn get_hg_in_range(fixture: MappingFixture) {
+    let MappingFixture { ctx, mapping, .. } = fixture;
 
     let entry1 = BonsaiHgMappingEntry {
         hg_cs_id: hg::ONES_CSID,
@@ -294,72 +335,96 @@ async fn get_hg_in_range<M: BonsaiHgMapping>(fb: FacebookInit, mapping: M) {
     );
 }
 
-struct CountedBonsaiHgMapping {
-    mapping: Arc<dyn BonsaiHgMapping>,
-    gets: Arc<AtomicUsize>,
-    adds: Arc<AtomicUsize>,
-    gets_many_hg_by_prefix: Arc<AtomicUsize>,
-}
+async fn resolve_prefix_any(fixture: MappingFixture) -> Result<Vec<AnyPrefixResolution>, Error> {
+    let MappingFixture { ctx, mapping, .. } = fixture;
 
-impl CountedBonsaiHgMapping {
-    fn new(
-        mapping: Arc<dyn BonsaiHgMapping>,
-        gets: Arc<AtomicUsize>,
-        adds: Arc<AtomicUsize>,
-        gets_many_hg_by_prefix: Arc<AtomicUsize>,
-    ) -> Self {
-        Self {
-            mapping,
-            gets,
-            adds,
-            gets_many_hg_by_prefix,
-        }
+    let entries = [
+        (hg::ONES_CSID, bonsai::TWOS_CSID),
+        (hg::AS_CSID, bonsai::BS_CSID),
+        (hg::CS_CSID, bonsai::AS_CSID),
+        (hg::FS_CSID, bonsai::FIVES_CSID),
+        (hg::FS_ES_CSID, bonsai::SIXES_CSID),
+    ];
+    for (hg_cs_id, bcs_id) in entries {
+        assert!(
+            mapping
+                .add(&ctx, BonsaiHgMappingEntry { hg_cs_id, bcs_id })
+                .await?
+        );
     }
-}
 
-#[async_trait]
-impl BonsaiHgMapping for CountedBonsaiHgMapping {
-    fn repo_id(&self) -> RepositoryId {
-        self.mapping.repo_id()
+    let expected = [
+        ("111", PrefixClassification::UniqueHg(hg::ONES_CSID)),
+        (
+            "2222",
+            PrefixClassification::UniqueBonsai(bonsai::TWOS_CSID),
+        ),
+        ("aaa", PrefixClassification::AmbiguousAcrossKinds),
+        ("fff", PrefixClassification::Multiple),
+        ("999", PrefixClassification::NoMatch),
+        // Too long for an hg changeset id.
+        (
+            "555555555555555555555555555555555555555555",
+            PrefixClassification::UniqueBonsai(bonsai::FIVES_CSID),
+        ),
+    ];
+    let mut resolutions = Vec::new();
+    for (prefix, classification) in expected {
+        let resolution = mapping.resolve_prefix_any(&ctx, prefix, 10).await?;
+        assert_eq!(resolution.classification(), classification, "{}", prefix);
+        resolutions.push(resolution);
+    }
+    assert_matches!(
+        resolutions[3].hg,
+        HgChangesetIdsResolvedFromPrefix::Multiple(ref ids) if ids.len() == 2
+    );
+
+    for invalid in ["", "xyz", "12 3", &"1".repeat(65)] {
+        let err = mapping
+            .resolve_prefix_any(&ctx, invalid, 10)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ErrorKind>(),
+            Some(&ErrorKind::InvalidHexPrefix(invalid.to_string()))
+        );
     }
+    Ok(resolutions)
+}
+
+/// Counts the calls to an `ObservedBonsaiHgMapping`, per method.
+#[derive(Default)]
+struct CountingSink {
+    calls: Mutex<HashMap<&'static str, usize>>,
+}
 
-    async fn add(&self, ctx: &CoreContext, entry: BonsaiHgMappingEntry) -> Result<bool, Error> {
-        self.adds.fetch_add(1, Ordering::Relaxed);
-        self.mapping.add(ctx, entry).await
+impl CountingSink {
+    fn count(&self, method: &str) -> usize {
+        self.calls
+            .lock()
+            .unwrap()
+            .get(method)
+            .copied()
+            .unwrap_or_default()
     }
 
-    async fn get(
-        &self,
-        ctx: &CoreContext,
-        cs_id: BonsaiOrHgChangesetIds,
-    ) -> Result<Vec<BonsaiHgMappingEntry>, Error> {
-        self.gets.fetch_add(1, Ordering::Relaxed);
-        self.mapping.get(ctx, cs_id).await
+    /// The reads of entries by id, which `CachingBonsaiHgMapping` makes
+    /// with `get` or, for `MostRecent` reads, `get_with_freshness`.
+    fn gets(&self) -> usize {
+        self.count("get") + self.count("get_with_freshness")
     }
+}
 
-    async fn get_hg_in_range(
-        &self,
-        ctx: &CoreContext,
-        low: HgChangesetId,
-        high: HgChangesetId,
-        limit: usize,
-    ) -> Result<Vec<HgChangesetId>, Error> {
-        self.gets_many_hg_by_prefix.fetch_add(1, Ordering::Relaxed);
-        self.mapping.get_hg_in_range(ctx, low, high, limit).await
+impl MappingMetricsSink for CountingSink {
+    fn record(&self, _repo_id: RepositoryId, method: &'static str, _: Duration, _: bool) {
+        *self.calls.lock().unwrap().entry(method).or_default() += 1;
     }
 }
 
-async fn caching<M: BonsaiHgMapping + 'static>(fb: FacebookInit, mapping: M) {
-    let ctx = CoreContext::test_mock(fb);
-    let gets = Arc::new(AtomicUsize::new(0));
-    let adds = Arc::new(AtomicUsize::new(0));
-    let gets_many_hg_by_prefix = Arc::new(AtomicUsize::new(0));
-    let mapping = CountedBonsaiHgMapping::new(
-        Arc::new(mapping),
-        gets.clone(),
-        adds.clone(),
-        gets_many_hg_by_prefix.clone(),
-    );
+async fn caching(fixture: MappingFixture) {
+    let MappingFixture { ctx, sql, .. } = fixture;
+    let sink = Arc::new(CountingSink::default());
+    let mapping = ObservedBonsaiHgMapping::new(sql, sink.clone());
     let mapping = CachingBonsaiHgMapping::new_test(Arc::new(mapping));
 
     let entry = BonsaiHgMappingEntry {
@@ -378,86 +443,118 @@ async fn caching<M: BonsaiHgMapping + 'static>(fb: FacebookInit, mapping: M) {
         .await
         .expect("Failed to get bonsai changeset by its hg counterpart");
     assert_eq!(result, Some(bonsai::ONES_CSID));
-    assert_eq!(gets.load(Ordering::Relaxed), 1);
+    assert_eq!(sink.gets(), 1);
 
     let result = mapping
         .get_bonsai_from_hg(&ctx, hg::ONES_CSID)
         .await
         .expect("Failed to get bonsai changeset by its hg counterpart");
     assert_eq!(result, Some(bonsai::ONES_CSID));
-    assert_eq!(gets.load(Ordering::Relaxed), 1);
+    assert_eq!(sink.gets(), 1);
 
     let result = mapping
         .get_bonsai_from_hg(&ctx, hg::TWOS_CSID)
         .await
         .expect("Failed to get bonsai changeset by its hg counterpart");
     assert_eq!(result, None);
-    assert_eq!(gets.load(Ordering::Relaxed), 2);
+    assert_eq!(sink.gets(), 2);
 }
 
 #[fbinit::test]
-async fn test_add_and_get(fb: FacebookInit) {
-    add_and_get(
-        fb,
-        SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()
-            .unwrap()
-            .build(REPO_ZERO, RendezVousOptions::for_test()),
-    )
-    .await;
+async fn test_add_and_get(fb: FacebookInit) -> Result<(), Error> {
+    add_and_get(TestFixture::new(fb).build().await?).await;
+    Ok(())
 }
 
 #[fbinit::test]
-async fn test_missing(fb: FacebookInit) {
-    missing(
-        fb,
-        SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()
-            .unwrap()
-            .build(REPO_ZERO, RendezVousOptions::for_test()),
-    )
-    .await;
+async fn test_missing(fb: FacebookInit) -> Result<(), Error> {
+    missing(TestFixture::new(fb).build().await?).await;
+    Ok(())
 }
 
 #[fbinit::test]
-async fn test_caching(fb: FacebookInit) {
-    caching(
-        fb,
-        SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()
-            .unwrap()
-            .build(REPO_ZERO, RendezVousOptions::for_test()),
-    )
-    .await;
+async fn test_caching(fb: FacebookInit) -> Result<(), Error> {
+    caching(TestFixture::new(fb).build().await?).await;
+    Ok(())
 }
 
 #[fbinit::test]
-async fn test_get_many_hg_by_prefix(fb: FacebookInit) {
-    get_many_hg_by_prefix(
-        fb,
-        SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()
-            .unwrap()
-            .build(REPO_ZERO, RendezVousOptions::for_test()),
-    )
-    .await;
+async fn test_get_many_hg_by_prefix(fb: FacebookInit) -> Result<(), Error> {
+    get_many_hg_by_prefix(TestFixture::new(fb).build().await?).await;
+    Ok(())
 }
 
 #[fbinit::test]
-async fn test_get_hg_in_range(fb: FacebookInit) {
-    get_hg_in_range(
-        fb,
-        SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()
-            .unwrap()
-            .build(REPO_ZERO, RendezVousOptions::for_test()),
-    )
-    .await;
+async fn test_resolve_prefix_any(fb: FacebookInit) -> Result<(), Error> {
+    let sql = resolve_prefix_any(TestFixture::new(fb).build().await?).await?;
+    let caching = resolve_prefix_any(TestFixture::new(fb).with_caching().build().await?).await?;
+    assert_eq!(sql, caching);
+    Ok(())
 }
 
 #[fbinit::test]
-async fn test_overwrite(fb: FacebookInit) -> Result<(), Error> {
-    let mapping = SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()
-        .unwrap()
-        .with_overwrite()
-        .build(REPO_ZERO, RendezVousOptions::for_test());
+async fn test_mem_writes_bonsai_in_range(fb: FacebookInit) -> Result<(), Error> {
+    let MappingFixture { ctx, mapping, .. } = TestFixture::new(fb).build().await?;
+    mapping
+        .add(
+            &ctx,
+            BonsaiHgMappingEntry {
+                hg_cs_id: hg::ONES_CSID,
+                bcs_id: bonsai::ONES_CSID,
+            },
+        )
+        .await?;
+    let mem_writes = MemWritesBonsaiHgMapping::new(mapping);
+    mem_writes
+        .add(
+            &ctx,
+            BonsaiHgMappingEntry {
+                hg_cs_id: hg::THREES_CSID,
+                bcs_id: bonsai::TWOS_CSID,
+            },
+        )
+        .await?;
 
-    let ctx = CoreContext::test_mock(fb);
+    // Changesets written to memory or to the inner mapping.
+    let in_range = mem_writes
+        .get_hg_in_range(&ctx, hg::ONES_CSID, hg::THREES_CSID, 10)
+        .await?;
+    assert_eq!(in_range, vec![hg::ONES_CSID, hg::THREES_CSID]);
+    let in_range = mem_writes
+        .get_bonsai_in_range(&ctx, bonsai::ONES_CSID, bonsai::TWOS_CSID, 10)
+        .await?;
+    assert_eq!(in_range, vec![bonsai::ONES_CSID, bonsai::TWOS_CSID]);
+    let in_range = mem_writes
+        .get_bonsai_in_range(&ctx, bonsai::ONES_CSID, bonsai::TWOS_CSID, 1)
+        .await?;
+    assert_eq!(in_range, vec![bonsai::ONES_CSID]);
+    let resolution = mem_writes.resolve_prefix_any(&ctx, "2222", 10).await?;
+    assert_eq!(
+        resolution.classification(),
+        PrefixClassification::UniqueBonsai(bonsai::TWOS_CSID)
+    );
+
+    mem_writes.set_no_access_to_inner(true);
+    let in_range = mem_writes
+        .get_bonsai_in_range(&ctx, bonsai::ONES_CSID, bonsai::TWOS_CSID, 10)
+        .await?;
+    assert_eq!(in_range, vec![bonsai::TWOS_CSID]);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_get_hg_in_range(fb: FacebookInit) -> Result<(), Error> {
+    get_hg_in_range(TestFixture::new(fb).build().await?).await;
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_overwrite(fb: FacebookInit) -> Result<(), Error> {
+    let fixture = TestFixture::new(fb)
+        .with_builder(SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()?.with_overwrite())
+        .build()
+        .await?;
+    let MappingFixture { ctx, mapping, .. } = &fixture;
     let entry = BonsaiHgMappingEntry {
         hg_cs_id: hg::ONES_CSID,
         bcs_id: bonsai::ONES_CSID,
@@ -465,7 +562,7 @@ async fn test_overwrite(fb: FacebookInit) -> Result<(), Error> {
 
     assert!(
         mapping
-            .add(&ctx, entry.clone())
+            .add(ctx, entry.clone())
             .await
             .expect("Adding new entry failed")
     );
@@ -476,13 +573,1114 @@ async fn test_overwrite(fb: FacebookInit) -> Result<(), Error> {
     };
     assert!(
         mapping
-            .add(&ctx, entry.clone())
+            .add(ctx, entry.clone())
             .await
             .expect("Adding new entry failed")
     );
+    fixture.assert_mapped(&entry).await;
+    fixture.assert_not_mapped(bonsai::ONES_CSID).await;
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_resolve_hg_to_bonsai_blobs(fb: FacebookInit) -> Result<(), Error> {
+    let fixture = TestFixture::new(fb)
+        .with_repo(REPO_ONE)
+        .with_seeded_mappings(10)
+        .with_caching()
+        .build()
+        .await?;
+    let MappingFixture {
+        ctx,
+        mapping,
+        blobstore,
+        ..
+    } = &fixture;
+    assert_eq!(mapping.repo_id(), REPO_ONE);
 
-    let result = mapping.get(&ctx, hg::ONES_CSID.into()).await?;
+    // The bonsai changesets are stored, but for the fifth.
+    let seeded = fixture.seeded();
+    for entry in &seeded {
+        if entry.hg_cs_id != hg::make_hg_cs_id(5) {
+            let value = BlobstoreBytes::from_bytes(entry.hg_cs_id.to_string());
+            blobstore
+                .put(ctx, entry.bcs_id.blobstore_key(), value)
+                .await?;
+        }
+    }
+
+    // Changesets are looked up by hg id, as for a pull.
+    let mut missing = Vec::new();
+    for entry in &seeded {
+        let bcs_id = mapping
+            .get_bonsai_from_hg(ctx, entry.hg_cs_id)
+            .await?
+            .expect("seeded entry is not mapped");
+        match blobstore.get(ctx, &bcs_id.blobstore_key()).await? {
+            Some(data) => assert_eq!(
+                data.into_bytes().as_bytes(),
+                entry.hg_cs_id.to_string().as_bytes()
+            ),
+            None => missing.push(entry.hg_cs_id),
+        }
+    }
+    assert_eq!(missing, vec![hg::make_hg_cs_id(5)]);
+
+    // A new changeset is stored, then mapped.
+    let entry = fixture.mint();
+    let value = BlobstoreBytes::from_bytes(entry.hg_cs_id.to_string());
+    blobstore
+        .put(ctx, entry.bcs_id.blobstore_key(), value)
+        .await?;
+    fixture.assert_not_mapped(entry.hg_cs_id).await;
+    assert!(mapping.add(ctx, entry.clone()).await?);
+    fixture.assert_mapped(&entry).await;
+    fixture.assert_not_mapped(fixture.mint().bcs_id).await;
+    Ok(())
+}
+
+/// Build a mapping whose writes go to a "master" database that its replica
+/// reads can't see, as if replication were lagging.
+fn lagging_replica_mapping() -> Result<SqlBonsaiHgMapping, Error> {
+    fn conn() -> Result<Connection, Error> {
+        let con = SqliteConnection::open_in_memory()?;
+        con.execute_batch(SqlBonsaiHgMappingBuilder::CREATION_QUERY)?;
+        Ok(Connection::with_sqlite(con))
+    }
+
+    let master = conn()?;
+    let replica = conn()?;
+    let connections = SqlConnections {
+        write_connection: master,
+        read_connection: replica.clone(),
+        read_master_connection: replica,
+    };
+    Ok(SqlBonsaiHgMappingBuilder::from_sql_connections(connections)
+        .build(REPO_ZERO, RendezVousOptions::for_test()))
+}
+
+#[fbinit::test]
+async fn test_get_with_freshness(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let mapping = lagging_replica_mapping()?;
+    let entry = BonsaiHgMappingEntry {
+        hg_cs_id: hg::ONES_CSID,
+        bcs_id: bonsai::ONES_CSID,
+    };
+    assert!(mapping.add(&ctx, entry.clone()).await?);
+
+    let result = mapping
+        .get_with_freshness(&ctx, hg::ONES_CSID.into(), Freshness::MaybeStale)
+        .await?;
+    assert_eq!(result, vec![]);
+    assert_eq!(mapping.get(&ctx, bonsai::ONES_CSID.into()).await?, vec![]);
+
+    let result = mapping
+        .get_with_freshness(&ctx, hg::ONES_CSID.into(), Freshness::MostRecent)
+        .await?;
     assert_eq!(result, vec![entry.clone()]);
+    let result = mapping
+        .get_with_freshness(&ctx, bonsai::ONES_CSID.into(), Freshness::MostRecent)
+        .await?;
+    assert_eq!(result, vec![entry]);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_caching_get_with_freshness(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let sink = Arc::new(CountingSink::default());
+    let mapping = ObservedBonsaiHgMapping::new(Arc::new(lagging_replica_mapping()?), sink.clone());
+    let mapping = CachingBonsaiHgMapping::new_test(Arc::new(mapping));
+    let entry = BonsaiHgMappingEntry {
+        hg_cs_id: hg::ONES_CSID,
+        bcs_id: bonsai::ONES_CSID,
+    };
+    assert!(mapping.add(&ctx, entry.clone()).await?);
+
+    let result = mapping
+        .get_with_freshness(&ctx, hg::ONES_CSID.into(), Freshness::MostRecent)
+        .await?;
+    assert_eq!(result, vec![entry]);
+    assert_eq!(sink.gets(), 1);
+
+    // MostRecent always bypasses the cache.
+    mapping
+        .get_with_freshness(&ctx, hg::ONES_CSID.into(), Freshness::MostRecent)
+        .await?;
+    assert_eq!(sink.gets(), 2);
+
+    // The cache was filled by the MostRecent reads, for both kinds of ids,
+    // so MaybeStale reads see the entry despite the lagging replica.
+    let result = mapping.get_bonsai_from_hg(&ctx, hg::ONES_CSID).await?;
+    assert_eq!(result, Some(bonsai::ONES_CSID));
+    let result = mapping.get_hg_from_bonsai(&ctx, bonsai::ONES_CSID).await?;
+    assert_eq!(result, Some(hg::ONES_CSID));
+    assert_eq!(sink.gets(), 2);
+    Ok(())
+}
+
+/// Keeps the messages logged.
+#[derive(Clone, Default)]
+struct CapturingDrain(Arc<Mutex<Vec<String>>>);
+
+impl Drain for CapturingDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record<'_>, _values: &OwnedKVList) -> Result<(), Never> {
+        self.0.lock().unwrap().push(record.msg().to_string());
+        Ok(())
+    }
+}
+
+#[fbinit::test]
+async fn test_observed_slow_calls(fb: FacebookInit) -> Result<(), Error> {
+    let drain = CapturingDrain::default();
+    let ctx = CoreContext::new_with_logger(fb, Logger::root(drain.clone(), o!()));
+    let sql_mapping = SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()?
+        .build(REPO_ZERO, RendezVousOptions::for_test());
+    let sink = Arc::new(CountingSink::default());
+    let mapping = ObservedBonsaiHgMapping::new(Arc::new(sql_mapping), sink.clone())
+        .with_slow_call_logging(Duration::ZERO, 2);
+
+    let ids = vec![bonsai::ONES_CSID, bonsai::TWOS_CSID, bonsai::THREES_CSID];
+    assert_eq!(mapping.get(&ctx, ids.into()).await?, vec![]);
+    assert_eq!(sink.count("get"), 1);
+    let logged = drain.0.lock().unwrap().clone();
+    assert_eq!(logged.len(), 1);
+    let prefix = format!(
+        "Slow call to bonsai_hg_mapping.get for repo {}: ",
+        REPO_ZERO
+    );
+    assert!(logged[0].starts_with(&prefix), "{}", logged[0]);
+    // Only the first ids are logged.
+    let ids = format!(
+        "ids: {}, {} and 1 more",
+        bonsai::ONES_CSID,
+        bonsai::TWOS_CSID
+    );
+    assert!(logged[0].ends_with(&ids), "{}", logged[0]);
+
+    // Calls are not logged below the threshold.
+    let mapping = ObservedBonsaiHgMapping::new(mapping.inner().clone(), sink.clone())
+        .with_slow_call_logging(Duration::from_secs(3600), 2);
+    mapping.get_hg_from_bonsai(&ctx, bonsai::ONES_CSID).await?;
+    assert_eq!(drain.0.lock().unwrap().len(), 1);
+    assert_eq!(sink.count("get_hg_from_bonsai"), 1);
+    Ok(())
+}
+
+/// Keeps the names and fields of the spans created.
+#[cfg(feature = "tracing")]
+#[derive(Clone, Default)]
+struct SpanRecorder(Arc<Mutex<Vec<(String, BTreeMap<String, String>)>>>);
+
+#[cfg(feature = "tracing")]
+impl tracing::Subscriber for SpanRecorder {
+    fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        struct Visitor(BTreeMap<String, String>);
+        impl tracing::field::Visit for Visitor {
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                self.0
+                    .insert(field.name().to_string(), format!("{:?}", value));
+            }
+        }
+        let mut visitor = Visitor(BTreeMap::new());
+        span.record(&mut visitor);
+        let mut spans = self.0.lock().unwrap();
+        spans.push((span.metadata().name().to_string(), visitor.0));
+        tracing::span::Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+    fn event(&self, _event: &tracing::Event<'_>) {}
+
+    fn enter(&self, _span: &tracing::span::Id) {}
+
+    fn exit(&self, _span: &tracing::span::Id) {}
+}
+
+#[cfg(feature = "tracing")]
+#[fbinit::test]
+async fn test_observed_spans(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let sql_mapping = SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()?
+        .build(REPO_ONE, RendezVousOptions::for_test());
+    let mapping =
+        ObservedBonsaiHgMapping::new(Arc::new(sql_mapping), Arc::new(CountingSink::default()));
+    let recorder = SpanRecorder::default();
+    let _guard = tracing::subscriber::set_default(recorder.clone());
+
+    let ids = vec![hg::ONES_CSID, hg::TWOS_CSID];
+    mapping.get(&ctx, ids.into()).await?;
+    let prefix = HgChangesetIdPrefix::from_bytes(&hg::ONES_CSID.as_ref()[0..8])?;
+    mapping.get_many_hg_by_prefix(&ctx, prefix, 10).await?;
+
+    let spans: Vec<_> = recorder
+        .0
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(name, _)| name.starts_with("bonsai_hg_mapping."))
+        .cloned()
+        .collect();
+    let fields = |repo_id: i32, batch_size: usize| {
+        BTreeMap::from([
+            ("batch_size".to_string(), batch_size.to_string()),
+            ("repo_id".to_string(), repo_id.to_string()),
+        ])
+    };
+    assert_eq!(
+        spans,
+        vec![
+            (
+                "bonsai_hg_mapping.get".to_string(),
+                fields(REPO_ONE.id(), 2)
+            ),
+            (
+                "bonsai_hg_mapping.get_many_hg_by_prefix".to_string(),
+                fields(REPO_ONE.id(), 10)
+            ),
+        ]
+    );
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_subscribe(fb: FacebookInit) -> Result<(), Error> {
+    let MappingFixture {
+        ctx, sql: mapping, ..
+    } = TestFixture::new(fb).build().await?;
+    let mut receiver = mapping.subscribe();
+
+    let ones = BonsaiHgMappingEntry {
+        hg_cs_id: hg::ONES_CSID,
+        bcs_id: bonsai::ONES_CSID,
+    };
+    let twos = BonsaiHgMappingEntry {
+        hg_cs_id: hg::TWOS_CSID,
+        bcs_id: bonsai::TWOS_CSID,
+    };
+    assert!(mapping.add(&ctx, ones.clone()).await?);
+    // Already present, so not newly inserted.
+    assert!(!mapping.add(&ctx, ones.clone()).await?);
+    assert!(mapping.add(&ctx, twos.clone()).await?);
+
+    assert_eq!(receiver.try_next()?, Some(ones));
+    assert_eq!(receiver.try_next()?, Some(twos));
+    assert!(receiver.try_next().is_err());
+
+    // A dropped subscriber doesn't affect later adds.
+    drop(receiver);
+    let threes = BonsaiHgMappingEntry {
+        hg_cs_id: hg::THREES_CSID,
+        bcs_id: bonsai::THREES_CSID,
+    };
+    assert!(mapping.add(&ctx, threes.clone()).await?);
+    assert_eq!(
+        mapping.get(&ctx, hg::THREES_CSID.into()).await?,
+        vec![threes]
+    );
+    assert_eq!(mapping.dropped_notifications(), 0);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_caching_subscribe(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let mapping = SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()?
+        .build(REPO_ZERO, RendezVousOptions::for_test());
+    let mapping = CachingBonsaiHgMapping::new_test(Arc::new(mapping));
+    let mut receiver = mapping.subscribe();
+
+    let ones = BonsaiHgMappingEntry {
+        hg_cs_id: hg::ONES_CSID,
+        bcs_id: bonsai::ONES_CSID,
+    };
+    let twos = BonsaiHgMappingEntry {
+        hg_cs_id: hg::TWOS_CSID,
+        bcs_id: bonsai::TWOS_CSID,
+    };
+    assert!(mapping.add(&ctx, ones.clone()).await?);
+    assert!(!mapping.add(&ctx, ones.clone()).await?);
+    assert!(mapping.add(&ctx, twos.clone()).await?);
+
+    assert_eq!(receiver.try_next()?, Some(ones));
+    assert_eq!(receiver.try_next()?, Some(twos));
+    assert!(receiver.try_next().is_err());
+    Ok(())
+}
+
+fn make_entry(n: u64, bcs_n: u64) -> BonsaiHgMappingEntry {
+    let mut bytes = [0u8; 32];
+    bytes[..8].copy_from_slice(&bcs_n.to_be_bytes());
+    BonsaiHgMappingEntry {
+        hg_cs_id: hg::make_hg_cs_id(n),
+        bcs_id: ChangesetId::from_bytes(bytes).unwrap(),
+    }
+}
+
+#[fbinit::test]
+async fn test_stats(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let stats = Arc::new(MappingStats::new());
+    let zero = SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()?
+        .with_stats(stats.clone())
+        .build(REPO_ZERO, RendezVousOptions::for_test());
+    let one = SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()?
+        .with_stats(stats.clone())
+        .build(REPO_ONE, RendezVousOptions::for_test());
+
+    for n in 1..=100 {
+        let entry = make_entry(n, n);
+        assert!(zero.add(&ctx, entry.clone()).await?);
+        assert_eq!(
+            zero.get(&ctx, entry.hg_cs_id.into()).await?,
+            vec![entry.clone()]
+        );
+        if n <= 50 {
+            assert!(one.add(&ctx, entry).await?);
+        }
+    }
+    for n in 1..=10 {
+        // Conflicts with the entry added above.
+        assert!(one.add(&ctx, make_entry(n, n + 1000)).await.is_err());
+    }
+    for _ in 0..30 {
+        one.get_hg_in_range(&ctx, hg::ONES_CSID, hg::TWOS_CSID, 10)
+            .await?;
+    }
+
+    let snapshot = stats.snapshot();
+    let expected = [
+        ((REPO_ZERO, MappingOperation::Add), 100, 0),
+        ((REPO_ZERO, MappingOperation::Get), 100, 0),
+        ((REPO_ONE, MappingOperation::Add), 60, 10),
+        ((REPO_ONE, MappingOperation::GetHgInRange), 30, 0),
+    ];
+    assert_eq!(snapshot.len(), expected.len());
+    for (key, count, errors) in expected {
+        let op = &snapshot[&key];
+        assert_eq!(op.count, count, "{:?}", key);
+        assert_eq!(op.errors, errors, "{:?}", key);
+        assert_eq!(op.buckets.iter().sum::<u64>(), count, "{:?}", key);
+        let (p50, p95, p99) = (op.p50.unwrap(), op.p95.unwrap(), op.p99.unwrap());
+        assert!(p50 <= p95 && p95 <= p99, "{:?}: {:?}", key, op);
+    }
+
+    // Stats are shared by mappings of the same repo.
+    let zero_again = SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()?
+        .with_stats(stats.clone())
+        .build(REPO_ZERO, RendezVousOptions::for_test());
+    zero_again.get(&ctx, hg::ONES_CSID.into()).await?;
+    assert_eq!(
+        stats.snapshot()[&(REPO_ZERO, MappingOperation::Get)].count,
+        101
+    );
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_caching_stats(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let stats = Arc::new(MappingStats::new());
+    let sql = SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()?
+        .build(REPO_ZERO, RendezVousOptions::for_test());
+    let caching = CachingBonsaiHgMapping::new_test(Arc::new(sql)).with_stats(stats.clone());
+    let entry = make_entry(1, 1);
+    caching.add(&ctx, entry.clone()).await?;
+
+    // Ranges of one element are looked up in the cache, and only counted
+    // as ranges.
+    for _ in 0..2 {
+        assert_eq!(
+            caching
+                .get_hg_in_range(&ctx, entry.hg_cs_id, entry.hg_cs_id, 10)
+                .await?,
+            vec![entry.hg_cs_id]
+        );
+        assert_eq!(
+            caching
+                .get_bonsai_in_range(&ctx, entry.bcs_id, entry.bcs_id, 10)
+                .await?,
+            vec![entry.bcs_id]
+        );
+    }
+    caching.get(&ctx, entry.bcs_id.into()).await?;
+
+    let snapshot = stats.snapshot();
+    let counts: Vec<_> = MappingOperation::ALL
+        .iter()
+        .map(|op| snapshot.get(&(REPO_ZERO, *op)).map_or(0, |s| s.count))
+        .collect();
+    assert_eq!(counts, vec![1, 1, 2, 2]);
+    Ok(())
+}
+
+/// Build a mapping whose table has no unique constraints, as if they had been
+/// bypassed, holding `rows`. Only `known` are in the changesets table.
+fn unconstrained_mapping(
+    rows: &[BonsaiHgMappingEntry],
+    known: &[ChangesetId],
+) -> Result<SqlBonsaiHgMapping, Error> {
+    let con = SqliteConnection::open_in_memory()?;
+    con.execute_batch(
+        "CREATE TABLE bonsai_hg_mapping (
+           repo_id INTEGER NOT NULL,
+           hg_cs_id BINARY(20) NOT NULL,
+           bcs_id BINARY(32) NOT NULL
+         );
+         CREATE TABLE changesets (
+           id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+           repo_id INTEGER NOT NULL,
+           cs_id VARBINARY(32) NOT NULL,
+           gen BIGINT NOT NULL
+         );",
+    )?;
+    for row in rows {
+        con.execute_batch(&format!(
+            "INSERT INTO bonsai_hg_mapping (repo_id, hg_cs_id, bcs_id) VALUES ({}, X'{}', X'{}')",
+            REPO_ZERO.id(),
+            row.hg_cs_id,
+            row.bcs_id,
+        ))?;
+    }
+    for cs_id in known {
+        con.execute_batch(&format!(
+            "INSERT INTO changesets (repo_id, cs_id, gen) VALUES ({}, X'{}', 1)",
+            REPO_ZERO.id(),
+            cs_id,
+        ))?;
+    }
+    let con = Connection::with_sqlite(con);
+    let connections = SqlConnections {
+        write_connection: con.clone(),
+        read_connection: con.clone(),
+        read_master_connection: con,
+    };
+    Ok(SqlBonsaiHgMappingBuilder::from_sql_connections(connections)
+        .build(REPO_ZERO, RendezVousOptions::for_test()))
+}
+
+fn sorted_groups(groups: &[Vec<BonsaiHgMappingEntry>]) -> Vec<Vec<(HgChangesetId, ChangesetId)>> {
+    let mut groups: Vec<Vec<_>> = groups
+        .iter()
+        .map(|group| {
+            let mut group: Vec<_> = group.iter().map(|e| (e.hg_cs_id, e.bcs_id)).collect();
+            group.sort();
+            group
+        })
+        .collect();
+    groups.sort();
+    groups
+}
+
+#[fbinit::test]
+async fn test_verify_and_repair(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let hg_conflict = (make_entry(3, 3), make_entry(3, 4));
+    let bonsai_conflict = (make_entry(5, 5), make_entry(6, 5));
+    let identical = make_entry(7, 7);
+    let orphan = make_entry(8, 8);
+    let rows = vec![
+        make_entry(1, 1),
+        make_entry(2, 2),
+        hg_conflict.0.clone(),
+        hg_conflict.1.clone(),
+        bonsai_conflict.0.clone(),
+        bonsai_conflict.1.clone(),
+        identical.clone(),
+        identical.clone(),
+        orphan.clone(),
+    ];
+    let known: Vec<_> = (1..=7).map(|n| make_entry(n, n).bcs_id).collect();
+    let mapping = unconstrained_mapping(&rows, &known)?;
 
+    // Small chunks, so that groups straddle chunks.
+    let opts = VerifyOptions {
+        chunk_size: 2,
+        ..Default::default()
+    };
+    let report = mapping.verify(&ctx, REPO_ZERO, opts).await?;
+    assert!(!report.is_consistent());
+    assert_eq!(report.rows_scanned, 9);
+    assert_eq!(
+        sorted_groups(&report.duplicate_hg),
+        sorted_groups(&[
+            vec![hg_conflict.0.clone(), hg_conflict.1.clone()],
+            vec![identical.clone(), identical.clone()],
+        ])
+    );
+    assert_eq!(
+        sorted_groups(&report.duplicate_bonsai),
+        sorted_groups(&[
+            vec![bonsai_conflict.0.clone(), bonsai_conflict.1.clone()],
+            vec![identical.clone(), identical.clone()],
+        ])
+    );
+    assert_eq!(report.orphans, vec![orphan.clone()]);
+    // Other repos are not affected.
+    let other = mapping.verify(&ctx, REPO_ONE, opts).await?;
+    assert_eq!(other, ConsistencyReport::default());
+
+    let plan = RepairPlan {
+        delete: vec![orphan, bonsai_conflict.1],
+        overwrite: vec![hg_conflict.0, identical],
+    };
+    let plan: RepairPlan = serde_json::from_str(&serde_json::to_string(&plan)?)?;
+
+    let outcome = mapping.repair(&ctx, REPO_ZERO, &plan, true).await?;
+    assert_eq!(
+        outcome,
+        RepairOutcome {
+            deleted: 6,
+            written: 2,
+            dry_run: true,
+        }
+    );
+    assert_eq!(mapping.verify(&ctx, REPO_ZERO, opts).await?, report);
+
+    let outcome = mapping.repair(&ctx, REPO_ZERO, &plan, false).await?;
+    assert!(!outcome.dry_run);
+    assert_eq!((outcome.deleted, outcome.written), (6, 2));
+    let report = mapping.verify(&ctx, REPO_ZERO, opts).await?;
+    assert!(report.is_consistent(), "{:?}", report);
+    assert_eq!(report.rows_scanned, 5);
+    Ok(())
+}
+
+/// Build a mapping recording insertion times, with `rows` of (entry, time
+/// added in seconds) already in it. A time of `None` stands for rows that
+/// predate the column.
+fn timestamped_mapping(
+    rows: &[(BonsaiHgMappingEntry, Option<i64>)],
+) -> Result<SqlBonsaiHgMapping, Error> {
+    let con = SqliteConnection::open_in_memory()?;
+    con.execute_batch(SqlBonsaiHgMappingBuilder::CREATION_QUERY)?;
+    for (entry, added_at) in rows {
+        con.execute_batch(&format!(
+            "INSERT INTO bonsai_hg_mapping (repo_id, hg_cs_id, bcs_id, added_at_secs)
+             VALUES ({}, X'{}', X'{}', {})",
+            REPO_ZERO.id(),
+            entry.hg_cs_id,
+            entry.bcs_id,
+            added_at.map_or("NULL".to_string(), |secs| secs.to_string()),
+        ))?;
+    }
+    let connections = SqlConnections::new_single(Connection::with_sqlite(con));
+    Ok(SqlBonsaiHgMappingBuilder::from_sql_connections(connections)
+        .with_insertion_timestamps()
+        .build(REPO_ZERO, RendezVousOptions::for_test()))
+}
+
+#[fbinit::test]
+async fn test_get_entries_added_since(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let mapping = timestamped_mapping(&[
+        (make_entry(1, 1), None),
+        (make_entry(2, 2), Some(100)),
+        (make_entry(3, 3), Some(200)),
+        (make_entry(4, 4), Some(300)),
+    ])?;
+    let ts = Timestamp::from_timestamp_secs;
+
+    let result = mapping.get_entries_added_since(&ctx, ts(150), 10).await?;
+    assert_eq!(
+        result,
+        vec![(make_entry(4, 4), ts(300)), (make_entry(3, 3), ts(200))]
+    );
+    // The limit keeps the newest entries.
+    let result = mapping.get_entries_added_since(&ctx, ts(0), 2).await?;
+    assert_eq!(
+        result,
+        vec![(make_entry(4, 4), ts(300)), (make_entry(3, 3), ts(200))]
+    );
+    // Rows without an insertion time are never returned.
+    let result = mapping.get_entries_added_since(&ctx, ts(0), 10).await?;
+    assert_eq!(result.len(), 3);
+    assert_eq!(mapping.newest_entry_timestamp(&ctx).await?, Some(ts(300)));
+
+    // New entries are stamped by the database.
+    let before = Timestamp::now().timestamp_seconds();
+    assert!(mapping.add(&ctx, make_entry(5, 5)).await?);
+    let after = Timestamp::now().timestamp_seconds();
+    let result = mapping
+        .get_entries_added_since(&ctx, ts(before), 10)
+        .await?;
+    assert_eq!(result.len(), 1);
+    let (entry, added_at) = &result[0];
+    assert_eq!(entry, &make_entry(5, 5));
+    assert!((before..=after).contains(&added_at.timestamp_seconds()));
+    let newest = mapping.newest_entry_timestamp(&ctx).await?;
+    assert_eq!(newest, Some(*added_at));
+
+    // Passed through the cache.
+    let mapping = CachingBonsaiHgMapping::new_test(Arc::new(mapping));
+    let result = mapping.get_entries_added_since(&ctx, ts(150), 2).await?;
+    assert_eq!(result[1], (make_entry(4, 4), ts(300)));
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_insertion_timestamps_disabled(fb: FacebookInit) -> Result<(), Error> {
+    let MappingFixture {
+        ctx, sql: mapping, ..
+    } = TestFixture::new(fb).build().await?;
+    let err = mapping
+        .get_entries_added_since(&ctx, Timestamp::from_timestamp_secs(0), 10)
+        .await
+        .unwrap_err();
+    assert_matches!(
+        err.downcast_ref::<ErrorKind>(),
+        Some(ErrorKind::InsertionTimestampsDisabled)
+    );
+
+    // Rows without an insertion time are excluded.
+    let mapping = timestamped_mapping(&[(make_entry(1, 1), None)])?;
+    assert_eq!(mapping.newest_entry_timestamp(&ctx).await?, None);
+    let result = mapping
+        .get_entries_added_since(&ctx, Timestamp::from_timestamp_secs(0), 10)
+        .await?;
+    assert_eq!(result, vec![]);
+    Ok(())
+}
+
+/// The entries of `mapping` with bonsai changesets `bcs_ns` of `make_entry`,
+/// read from the master.
+async fn entries_by_bonsai(
+    ctx: &CoreContext,
+    mapping: &SqlBonsaiHgMapping,
+    bcs_ns: impl IntoIterator<Item = u64>,
+) -> Result<Vec<(HgChangesetId, ChangesetId)>, Error> {
+    let bcs_ids: Vec<_> = bcs_ns
+        .into_iter()
+        .map(|n| make_entry(n, n).bcs_id)
+        .collect();
+    let entries = mapping
+        .get_with_freshness(ctx, bcs_ids.into(), Freshness::MostRecent)
+        .await?;
+    let mut entries: Vec<_> = entries
+        .into_iter()
+        .map(|e| (e.hg_cs_id, e.bcs_id))
+        .collect();
+    entries.sort();
+    Ok(entries)
+}
+
+async fn migrate_repo_id(
+    fb: FacebookInit,
+    builder: SqlBonsaiHgMappingBuilder,
+) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let source = builder
+        .clone()
+        .build(REPO_ZERO, RendezVousOptions::for_test());
+    let target = builder.build(REPO_ONE, RendezVousOptions::for_test());
+    for n in 1..=9 {
+        source.add(&ctx, make_entry(n, n)).await?;
+    }
+    // Already migrated.
+    target.add(&ctx, make_entry(7, 7)).await?;
+    // Same hg changeset as 8, same bonsai changeset as 9.
+    target.add(&ctx, make_entry(8, 18)).await?;
+    target.add(&ctx, make_entry(19, 9)).await?;
+    target.add(&ctx, make_entry(20, 20)).await?;
+    let conflicts = vec![
+        MigrationConflict {
+            source: make_entry(8, 8),
+            target: make_entry(8, 18),
+        },
+        MigrationConflict {
+            source: make_entry(9, 9),
+            target: make_entry(19, 9),
+        },
+    ];
+    let all = 1..=20;
+    let source_before = entries_by_bonsai(&ctx, &source, all.clone()).await?;
+    let target_before = entries_by_bonsai(&ctx, &target, all.clone()).await?;
+
+    let report = source
+        .migrate_repo_id(&ctx, REPO_ZERO, REPO_ONE, 2, true)
+        .await?;
+    assert_eq!(
+        report,
+        MigrationReport {
+            moved: 6,
+            skipped: 1,
+            conflicts: conflicts.clone(),
+            complete: true,
+            dry_run: true,
+        }
+    );
+    assert_eq!(report.conflicted(), 2);
+    assert_eq!(
+        entries_by_bonsai(&ctx, &source, all.clone()).await?,
+        source_before
+    );
+    assert_eq!(
+        entries_by_bonsai(&ctx, &target, all.clone()).await?,
+        target_before
+    );
+
+    // Interrupted after two batches.
+    let report = source
+        .migrate_repo_id_batches(&ctx, REPO_ZERO, REPO_ONE, 2, 2, false)
+        .await?;
+    assert_eq!((report.moved, report.skipped), (4, 0));
+    assert!(report.conflicts.is_empty());
+    assert!(!report.complete);
+    assert_eq!(entries_by_bonsai(&ctx, &source, 1..=4).await?, vec![]);
+
+    let report = source
+        .migrate_repo_id(&ctx, REPO_ZERO, REPO_ONE, 2, false)
+        .await?;
+    assert_eq!(
+        report,
+        MigrationReport {
+            moved: 2,
+            skipped: 1,
+            conflicts: conflicts.clone(),
+            complete: true,
+            dry_run: false,
+        }
+    );
+
+    let mut expected: Vec<_> = (1..=7)
+        .chain([20])
+        .map(|n| make_entry(n, n))
+        .chain(conflicts.iter().map(|c| c.target.clone()))
+        .map(|e| (e.hg_cs_id, e.bcs_id))
+        .collect();
+    expected.sort();
+    assert_eq!(
+        entries_by_bonsai(&ctx, &target, all.clone()).await?,
+        expected
+    );
+    let left: Vec<_> = conflicts
+        .iter()
+        .map(|c| (c.source.hg_cs_id, c.source.bcs_id))
+        .collect();
+    assert_eq!(entries_by_bonsai(&ctx, &source, all.clone()).await?, left);
+    assert_eq!(
+        target
+            .get_bonsai_from_hg(&ctx, hg::make_hg_cs_id(3))
+            .await?,
+        Some(make_entry(3, 3).bcs_id)
+    );
+
+    // Once complete, a new migration scans all rows left again.
+    let report = source
+        .migrate_repo_id(&ctx, REPO_ZERO, REPO_ONE, 10, false)
+        .await?;
+    assert_eq!((report.moved, report.skipped), (0, 0));
+    assert_eq!(report.conflicts, conflicts);
+
+    let err = source
+        .migrate_repo_id(&ctx, REPO_ZERO, REPO_ZERO, 10, true)
+        .await
+        .unwrap_err();
+    assert_matches!(
+        err.downcast_ref::<ErrorKind>(),
+        Some(ErrorKind::MigrationToSameRepo(_))
+    );
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_migrate_repo_id(fb: FacebookInit) -> Result<(), Error> {
+    migrate_repo_id(fb, SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()?).await
+}
+
+#[fbinit::test]
+async fn test_migrate_repo_id_invalidate_cache(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let builder = SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()?;
+    let sql = builder
+        .clone()
+        .build(REPO_ZERO, RendezVousOptions::for_test());
+    let caching = CachingBonsaiHgMapping::new_test(Arc::new(
+        builder.build(REPO_ZERO, RendezVousOptions::for_test()),
+    ));
+    let entry = make_entry(1, 1);
+    caching.add(&ctx, entry.clone()).await?;
+    assert_eq!(
+        caching.get(&ctx, entry.bcs_id.into()).await?,
+        vec![entry.clone()]
+    );
+
+    sql.migrate_repo_id(&ctx, REPO_ZERO, REPO_ONE, 10, false)
+        .await?;
+    // Still cached.
+    assert_eq!(
+        caching.get(&ctx, entry.bcs_id.into()).await?,
+        vec![entry.clone()]
+    );
+
+    assert!(!caching.invalidate_repos(&[REPO_ONE]));
+    assert!(caching.invalidate_repos(&[REPO_ZERO, REPO_ONE]));
+    assert_eq!(caching.get(&ctx, entry.bcs_id.into()).await?, vec![]);
+    Ok(())
+}
+
+const SHARDS: NonZeroUsize = match NonZeroUsize::new(4) {
+    Some(shards) => shards,
+    None => unreachable!(),
+};
+
+fn sharded() -> Result<SqlBonsaiHgMappingBuilder, Error> {
+    SqlBonsaiHgMappingBuilder::with_sqlite_in_memory_shards(SHARDS)
+}
+
+async fn sharded_fixture(fb: FacebookInit, repo_id: RepositoryId) -> Result<MappingFixture, Error> {
+    TestFixture::new(fb)
+        .with_repo(repo_id)
+        .with_builder(sharded()?)
+        .build()
+        .await
+}
+
+#[fbinit::test]
+async fn test_sharded(fb: FacebookInit) -> Result<(), Error> {
+    // The repos used by the tests are in different shards.
+    assert_ne!(
+        shard_index(REPO_ZERO, SHARDS),
+        shard_index(REPO_ONE, SHARDS)
+    );
+    for repo_id in [REPO_ZERO, REPO_ONE] {
+        add_and_get(sharded_fixture(fb, repo_id).await?).await;
+        missing(sharded_fixture(fb, repo_id).await?).await;
+        caching(sharded_fixture(fb, repo_id).await?).await;
+        get_many_hg_by_prefix(sharded_fixture(fb, repo_id).await?).await;
+        get_hg_in_range(sharded_fixture(fb, repo_id).await?).await;
+        let unsharded = TestFixture::new(fb).with_repo(repo_id).build().await?;
+        assert_eq!(
+            resolve_prefix_any(sharded_fixture(fb, repo_id).await?).await?,
+            resolve_prefix_any(unsharded).await?,
+        );
+    }
+    migrate_repo_id(fb, sharded()?).await
+}
+
+mononoke_queries! {
+    read SelectRowsByTable() -> (String, RepositoryId) {
+        "SELECT 'unsharded', repo_id FROM bonsai_hg_mapping
+         UNION ALL SELECT 'shard_0', repo_id FROM bonsai_hg_mapping_shard_0
+         UNION ALL SELECT 'shard_1', repo_id FROM bonsai_hg_mapping_shard_1
+         UNION ALL SELECT 'shard_2', repo_id FROM bonsai_hg_mapping_shard_2
+         UNION ALL SELECT 'shard_3', repo_id FROM bonsai_hg_mapping_shard_3"
+    }
+}
+
+/// Number of rows of each repo in each table.
+async fn rows_by_table(
+    conn: &Connection,
+) -> Result<BTreeMap<(String, RepositoryId), usize>, Error> {
+    let mut rows = BTreeMap::new();
+    for row in SelectRowsByTable::query(conn).await? {
+        *rows.entry(row).or_default() += 1;
+    }
+    Ok(rows)
+}
+
+#[test]
+fn test_too_many_shards() -> Result<(), Error> {
+    let max = NonZeroUsize::new(bonsai_hg_mapping::MAX_SHARDS).unwrap();
+    SqlBonsaiHgMappingBuilder::with_sqlite_in_memory_shards(max)?;
+    let too_many = NonZeroUsize::new(max.get() + 1).unwrap();
+    let err = SqlBonsaiHgMappingBuilder::with_sqlite_in_memory_shards(too_many).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<ErrorKind>(),
+        Some(&ErrorKind::TooManyShards(17, 16))
+    );
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_sharded_tables(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let con = SqliteConnection::open_in_memory()?;
+    con.execute_batch(&SqlBonsaiHgMappingBuilder::creation_query(SHARDS))?;
+    let con = Connection::with_sqlite(con);
+    let builder =
+        SqlBonsaiHgMappingBuilder::from_sql_connections(SqlConnections::new_single(con.clone()))
+            .with_shards(SHARDS)?;
+    let zero = builder
+        .clone()
+        .build(REPO_ZERO, RendezVousOptions::for_test());
+    let one = builder.build(REPO_ONE, RendezVousOptions::for_test());
+    for n in 1..=3 {
+        zero.add(&ctx, make_entry(n, n)).await?;
+    }
+    for n in 4..=5 {
+        one.add(&ctx, make_entry(n, n)).await?;
+    }
+    // The same entry in both repos.
+    zero.add(&ctx, make_entry(6, 6)).await?;
+    one.add(&ctx, make_entry(6, 6)).await?;
+
+    let shard = |repo_id| format!("shard_{}", shard_index(repo_id, SHARDS));
+    assert_eq!(
+        rows_by_table(&con).await?,
+        BTreeMap::from([
+            ((shard(REPO_ZERO), REPO_ZERO), 4),
+            ((shard(REPO_ONE), REPO_ONE), 3),
+        ])
+    );
+    assert_eq!(entries_by_bonsai(&ctx, &zero, 4..=5).await?, vec![]);
+
+    // Rows move to the shard of the target repo.
+    let report = zero
+        .migrate_repo_id(&ctx, REPO_ZERO, REPO_ONE, 2, false)
+        .await?;
+    assert_eq!((report.moved, report.skipped), (3, 1));
+    assert!(report.complete);
+    assert_eq!(
+        rows_by_table(&con).await?,
+        BTreeMap::from([((shard(REPO_ONE), REPO_ONE), 6)])
+    );
+    let mut expected: Vec<_> = (1..=6)
+        .map(|n| make_entry(n, n))
+        .map(|e| (e.hg_cs_id, e.bcs_id))
+        .collect();
+    expected.sort();
+    assert_eq!(entries_by_bonsai(&ctx, &one, 1..=6).await?, expected);
+    Ok(())
+}
+
+/// A resolver returning `entries`, whatever it is asked for, counting the
+/// ids it is asked for. It sleeps first, so concurrent lookups overlap.
+fn counting_resolver(
+    entries: Result<Vec<BonsaiHgMappingEntry>, &'static str>,
+    asked: Arc<AtomicUsize>,
+) -> MappingResolver {
+    Arc::new(
+        move |_ctx: &CoreContext, _repo_id: RepositoryId, ids: BonsaiOrHgChangesetIds| {
+            let count = match &ids {
+                BonsaiOrHgChangesetIds::Bonsai(ids) => ids.len(),
+                BonsaiOrHgChangesetIds::Hg(ids) => ids.len(),
+            };
+            asked.fetch_add(count, Ordering::Relaxed);
+            let entries = entries.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                entries.map_err(Error::msg)
+            }
+            .boxed()
+        },
+    )
+}
+
+fn lazy_mapping(
+    entries: Result<Vec<BonsaiHgMappingEntry>, &'static str>,
+) -> Result<
+    (
+        LazyPopulatingBonsaiHgMapping<SqlBonsaiHgMapping>,
+        Arc<AtomicUsize>,
+    ),
+    Error,
+> {
+    let inner = SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()?
+        .build(REPO_ZERO, RendezVousOptions::for_test());
+    let asked = Arc::new(AtomicUsize::new(0));
+    let resolver = counting_resolver(entries, asked.clone());
+    Ok((
+        LazyPopulatingBonsaiHgMapping::new(Arc::new(inner), resolver),
+        asked,
+    ))
+}
+
+#[fbinit::test]
+async fn test_lazy_populating(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let (one, two, three) = (make_entry(1, 1), make_entry(2, 2), make_entry(3, 3));
+    let (mapping, asked) = lazy_mapping(Ok(vec![one.clone(), two.clone()]))?;
+
+    assert_eq!(
+        mapping.get_hg_from_bonsai(&ctx, one.bcs_id).await?,
+        Some(one.hg_cs_id)
+    );
+    assert_eq!(asked.load(Ordering::Relaxed), 1);
+    let inner = mapping.inner();
+    assert_eq!(inner.get(&ctx, one.bcs_id.into()).await?, vec![one.clone()]);
+    // Entries for ids that were not asked for are not added.
+    assert_eq!(inner.get(&ctx, two.bcs_id.into()).await?, vec![]);
+
+    // Found in the inner mapping, by either id.
+    assert_eq!(
+        mapping.get_bonsai_from_hg(&ctx, one.hg_cs_id).await?,
+        Some(one.bcs_id)
+    );
+    assert_eq!(
+        mapping.get_hg_from_bonsai(&ctx, one.bcs_id).await?,
+        Some(one.hg_cs_id)
+    );
+    assert_eq!(asked.load(Ordering::Relaxed), 1);
+
+    // Nothing is found for an id the resolver has no entry for.
+    assert_eq!(mapping.get(&ctx, three.bcs_id.into()).await?, vec![]);
+    assert_eq!(asked.load(Ordering::Relaxed), 2);
+    assert_eq!(inner.get(&ctx, two.bcs_id.into()).await?, vec![]);
+    assert_eq!(inner.get(&ctx, three.bcs_id.into()).await?, vec![]);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_lazy_populating_concurrent(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let (one, two) = (make_entry(1, 1), make_entry(2, 2));
+    let (mapping, asked) = lazy_mapping(Ok(vec![one.clone(), two.clone()]))?;
+
+    let (alone, both) = futures::try_join!(
+        mapping.get_hg_from_bonsai(&ctx, one.bcs_id),
+        mapping.get(&ctx, vec![one.bcs_id, two.bcs_id].into()),
+    )?;
+    assert_eq!(alone, Some(one.hg_cs_id));
+    let mut both = both;
+    both.sort_by_key(|entry| entry.bcs_id);
+    assert_eq!(both, vec![one.clone(), two.clone()]);
+    // Whichever lookup came second joined the resolution of the first.
+    assert_eq!(asked.load(Ordering::Relaxed), 2);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_lazy_populating_same_id(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let one = make_entry(1, 1);
+    let (mapping, asked) = lazy_mapping(Ok(vec![one.clone()]))?;
+
+    let lookups = (0..5).map(|_| mapping.get_hg_from_bonsai(&ctx, one.bcs_id));
+    let found = futures::future::try_join_all(lookups).await?;
+    assert!(found.iter().all(|hg_cs_id| *hg_cs_id == Some(one.hg_cs_id)));
+    assert_eq!(asked.load(Ordering::Relaxed), 1);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_lazy_populating_resolver_failed(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let one = make_entry(1, 1);
+    let (mapping, asked) = lazy_mapping(Err("source unavailable"))?;
+    mapping.inner().add(&ctx, one.clone()).await?;
+
+    let result = mapping.get(&ctx, make_entry(2, 2).bcs_id.into()).await;
+    assert_matches!(
+        result.map_err(|e| e.downcast::<ErrorKind>()),
+        Err(Ok(ErrorKind::ResolverFailed(_)))
+    );
+    // Entries of the inner mapping are still found.
+    assert_eq!(
+        mapping.get_hg_from_bonsai(&ctx, one.bcs_id).await?,
+        Some(one.hg_cs_id)
+    );
+    assert_eq!(asked.load(Ordering::Relaxed), 1);
     Ok(())
 }
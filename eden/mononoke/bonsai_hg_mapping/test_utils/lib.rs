@@ -0,0 +1,159 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Fixture for tests of code using a `BonsaiHgMapping` along with a
+//! blobstore, see `TestFixture`.
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bonsai_hg_mapping::BonsaiHgMapping;
+use bonsai_hg_mapping::BonsaiHgMappingEntry;
+use bonsai_hg_mapping::BonsaiOrHgChangesetIds;
+use bonsai_hg_mapping::CachingBonsaiHgMapping;
+use bonsai_hg_mapping::SqlBonsaiHgMapping;
+use bonsai_hg_mapping::SqlBonsaiHgMappingBuilder;
+use context::CoreContext;
+use fbinit::FacebookInit;
+use memblob::Memblob;
+use mercurial_types_mocks::nodehash::make_hg_cs_id;
+use mononoke_types::RepositoryId;
+use mononoke_types_mocks::changesetid::make_changeset_id;
+use mononoke_types_mocks::repo::REPO_ZERO;
+use rendezvous::RendezVousOptions;
+use sql_construct::SqlConstruct;
+
+/// The `n`th entry of the fixture, mapping `make_hg_cs_id(n)` to
+/// `make_changeset_id(n)` of the mocks crates.
+pub fn make_entry(n: u64) -> BonsaiHgMappingEntry {
+    BonsaiHgMappingEntry {
+        hg_cs_id: make_hg_cs_id(n),
+        bcs_id: make_changeset_id(n),
+    }
+}
+
+/// Builds a `MappingFixture`: a mapping backed by an in-memory sqlite
+/// database, with a context and an in-memory blobstore.
+pub struct TestFixture {
+    fb: FacebookInit,
+    repo_id: RepositoryId,
+    builder: Option<SqlBonsaiHgMappingBuilder>,
+    caching: bool,
+    seeded_mappings: u64,
+}
+
+impl TestFixture {
+    pub fn new(fb: FacebookInit) -> Self {
+        Self {
+            fb,
+            repo_id: REPO_ZERO,
+            builder: None,
+            caching: false,
+            seeded_mappings: 0,
+        }
+    }
+
+    /// The repo of the mapping, `REPO_ZERO` by default.
+    pub fn with_repo(mut self, repo_id: RepositoryId) -> Self {
+        self.repo_id = repo_id;
+        self
+    }
+
+    /// Build the mapping with `builder`, for its options, instead of with a
+    /// new in-memory database.
+    pub fn with_builder(mut self, builder: SqlBonsaiHgMappingBuilder) -> Self {
+        self.builder = Some(builder);
+        self
+    }
+
+    /// Wrap the mapping in a `CachingBonsaiHgMapping`, with mocked caches.
+    pub fn with_caching(mut self) -> Self {
+        self.caching = true;
+        self
+    }
+
+    /// Add the entries `make_entry(1)` to `make_entry(n)` to the mapping.
+    pub fn with_seeded_mappings(mut self, n: u64) -> Self {
+        self.seeded_mappings = n;
+        self
+    }
+
+    pub async fn build(self) -> Result<MappingFixture> {
+        let ctx = CoreContext::test_mock(self.fb);
+        let builder = match self.builder {
+            Some(builder) => builder,
+            None => SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()?,
+        };
+        let sql = Arc::new(builder.build(self.repo_id, RendezVousOptions::for_test()));
+        for n in 1..=self.seeded_mappings {
+            sql.add(&ctx, make_entry(n)).await?;
+        }
+        let mapping: Arc<dyn BonsaiHgMapping> = if self.caching {
+            Arc::new(CachingBonsaiHgMapping::new_test(sql.clone()))
+        } else {
+            sql.clone()
+        };
+        Ok(MappingFixture {
+            ctx,
+            repo_id: self.repo_id,
+            sql,
+            mapping,
+            blobstore: Memblob::default(),
+            seeded_mappings: self.seeded_mappings,
+            minted: AtomicU64::new(self.seeded_mappings),
+        })
+    }
+}
+
+pub struct MappingFixture {
+    pub ctx: CoreContext,
+    pub repo_id: RepositoryId,
+    /// The sqlite mapping, for what is not in the `BonsaiHgMapping` trait.
+    pub sql: Arc<SqlBonsaiHgMapping>,
+    /// The mapping under test: `sql`, or the caching layer over it.
+    pub mapping: Arc<dyn BonsaiHgMapping>,
+    pub blobstore: Memblob,
+    seeded_mappings: u64,
+    minted: AtomicU64,
+}
+
+impl MappingFixture {
+    /// The entries the mapping was seeded with, in order.
+    pub fn seeded(&self) -> Vec<BonsaiHgMappingEntry> {
+        (1..=self.seeded_mappings).map(make_entry).collect()
+    }
+
+    /// A new entry, not in the mapping, and different from the entries
+    /// minted before.
+    pub fn mint(&self) -> BonsaiHgMappingEntry {
+        make_entry(self.minted.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    /// Asserts `entry` is found in the mapping by both of its ids.
+    pub async fn assert_mapped(&self, entry: &BonsaiHgMappingEntry) {
+        let ids: [BonsaiOrHgChangesetIds; 2] = [entry.hg_cs_id.into(), entry.bcs_id.into()];
+        for ids in ids {
+            let found = self.get(ids).await;
+            assert_eq!(found, vec![entry.clone()], "{:?} is not mapped", entry);
+        }
+    }
+
+    /// Asserts none of `ids` are in the mapping.
+    pub async fn assert_not_mapped(&self, ids: impl Into<BonsaiOrHgChangesetIds>) {
+        let found = self.get(ids.into()).await;
+        assert_eq!(found, vec![], "found unexpected entries");
+    }
+
+    async fn get(&self, ids: BonsaiOrHgChangesetIds) -> Vec<BonsaiHgMappingEntry> {
+        self.mapping
+            .get(&self.ctx, ids)
+            .await
+            .expect("Failed to get from the mapping")
+    }
+}
@@ -496,6 +496,7 @@ fn create_blobstore_options(
         read_burst_bytes: blobstore_args.blobstore_read_burst_bytes_s,
         write_burst_bytes: blobstore_args.blobstore_write_burst_bytes_s,
         bytes_min_count: blobstore_args.blobstore_bytes_min_throttle,
+        class_shares: None,
     };
 
     let pack_options = PackOptions::new(blobstore_args.put_format_override()?);
@@ -816,6 +816,7 @@ fn parse_blobstore_options(
             read_burst_bytes,
             write_burst_bytes,
             bytes_min_count,
+            class_shares: None,
         },
         #[cfg(fbcode_build)]
         manifold_options,
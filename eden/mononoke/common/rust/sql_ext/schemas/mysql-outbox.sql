@@ -0,0 +1,20 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+-- Events written with the rows they describe, see `sql_ext::outbox`.
+CREATE TABLE IF NOT EXISTS `outbox` (
+  `id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+  `topic` VARCHAR(255) NOT NULL,
+  `payload` LONGBLOB NOT NULL,
+  `created_at` BIGINT NOT NULL COMMENT 'milliseconds since the epoch',
+  `claim_token` BIGINT NULL COMMENT 'set by the last poll that delivered the entry',
+  `claimed_until` BIGINT NOT NULL DEFAULT 0 COMMENT 'milliseconds since the epoch',
+  `deliveries` INT UNSIGNED NOT NULL DEFAULT 0,
+  PRIMARY KEY (`id`),
+  KEY `outbox_topic_claimed_until` (`topic`, `claimed_until`, `id`),
+  KEY `outbox_claim_token` (`claim_token`)
+) ENGINE=InnoDB;
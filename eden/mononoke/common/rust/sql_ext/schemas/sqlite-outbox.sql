@@ -0,0 +1,22 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+-- Events written with the rows they describe, see `sql_ext::outbox`.
+CREATE TABLE IF NOT EXISTS `outbox` (
+  `id` INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  `topic` VARCHAR(255) NOT NULL,
+  `payload` BLOB NOT NULL,
+  `created_at` BIGINT NOT NULL, /* milliseconds since the epoch */
+  `claim_token` BIGINT, /* set by the last poll that delivered the entry */
+  `claimed_until` BIGINT NOT NULL DEFAULT 0, /* milliseconds since the epoch */
+  `deliveries` INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX IF NOT EXISTS `outbox_topic_claimed_until`
+  ON `outbox` (`topic`, `claimed_until`, `id`);
+
+CREATE INDEX IF NOT EXISTS `outbox_claim_token` ON `outbox` (`claim_token`);
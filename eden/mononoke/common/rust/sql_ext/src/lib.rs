@@ -5,18 +5,69 @@
  * GNU General Public License version 2.
  */
 
+pub mod migrations;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 mod mononoke_queries;
 #[cfg(not(fbcode_build))]
 mod oss;
+pub mod outbox;
+mod pools;
+mod query_limit;
+mod query_params;
+mod query_policy;
+pub mod registry;
 pub mod replication;
+mod result_limit;
+mod slow_query;
+mod sql_retry;
+mod sql_value;
 mod sqlite;
+mod write_result;
 
+pub use pools::PoolExhausted;
+pub use pools::PoolLimits;
+pub use pools::PoolRole;
+pub use pools::PooledConnection;
+pub use pools::ReadConsistency;
+pub use pools::SqlConnectionPools;
+pub use query_limit::query_limit_stats;
+pub use query_limit::query_limits;
+pub use query_limit::set_query_limits;
+pub use query_limit::QueryLimitStats;
+pub use query_limit::QueryLimits;
+pub use query_limit::QuerySaturated;
+pub use query_policy::query_policy;
+pub use query_policy::set_query_policy;
+pub use query_policy::InvalidQueryPolicy;
+pub use query_policy::QueryDisabled;
+pub use query_policy::QueryKind;
+pub use query_policy::QueryPolicy;
+pub use result_limit::default_result_limits;
+pub use result_limit::set_default_result_limits;
+pub use result_limit::ResultLimitKind;
+pub use result_limit::ResultLimits;
+pub use result_limit::ResultTooLarge;
+pub use slow_query::set_global_slow_query_explain;
+pub use slow_query::set_slow_query_explain;
+pub use slow_query::set_slow_query_sink;
+pub use slow_query::SlowQuery;
+pub use slow_query::SlowQueryOptions;
+pub use slow_query::SlowQuerySink;
 pub use sql::SqlConnections;
 pub use sql::SqlShardedConnections;
 use sql::Transaction;
+pub use sql_retry::is_retryable_sql_error;
+pub use sql_retry::retry_sql_operation;
+pub use sql_retry::SqlRetryPolicy;
+pub use sql_retry::DEFAULT_SQL_RETRY_POLICY;
+pub use sql_value::SqlConversionError;
 pub use sqlite::open_existing_sqlite_path;
 pub use sqlite::open_sqlite_in_memory;
 pub use sqlite::open_sqlite_path;
+pub use write_result::TypedWriteResult;
+pub use write_result::WriteKind;
+pub use write_result::WriteOutcome;
 
 #[must_use]
 pub enum TransactionResult {
@@ -25,23 +76,63 @@ pub enum TransactionResult {
 }
 
 pub mod _macro_internal {
+    pub use std::borrow::Borrow;
     pub use std::collections::hash_map::DefaultHasher;
     pub use std::hash::Hash;
     pub use std::hash::Hasher;
+    pub use std::time::Instant;
 
     pub use anyhow::Result;
+    pub use futures::stream::BoxStream;
+    #[cfg(feature = "query_registry")]
+    pub use linkme;
+    #[cfg(feature = "query_registry")]
+    pub use linkme::distributed_slice;
     pub use paste;
+    pub use sql::mysql_async::from_value_opt;
+    pub use sql::mysql_async::prelude::ConvIr;
+    pub use sql::mysql_async::prelude::FromValue;
+    pub use sql::mysql_async::FromValueError;
+    pub use sql::mysql_async::Value;
     pub use sql::queries;
+    pub use sql::sql_common::mysql::opt_try_from_rowfield;
+    pub use sql::sql_common::mysql::OptionalTryFromRowField;
+    pub use sql::sql_common::mysql::RowField;
+    pub use sql::sql_common::mysql::ValueError;
     pub use sql::Connection;
+    pub use sql::Transaction;
     pub use sql::WriteResult;
     pub use sql_query_config::SqlQueryConfig;
     pub use twox_hash::xxh3::Hash128;
     pub use twox_hash::xxh3::HasherExt;
 
+    #[cfg(any(test, feature = "mock"))]
+    pub use crate::mock::intercept as mock_intercept;
+    pub use crate::mononoke_queries::query_stream_chunked;
     pub use crate::mononoke_queries::query_with_retry;
+    pub use crate::mononoke_queries::query_with_retry_limited;
     pub use crate::mononoke_queries::query_with_retry_no_cache;
     pub use crate::mononoke_queries::CacheData;
     pub use crate::mononoke_queries::MemcacheWrapper;
+    pub use crate::pools::SqlConnectionPools;
+    pub use crate::query_limit::QueryLimiter;
+    pub use crate::query_limit::QueryLimits;
+    pub use crate::query_params::MockParam;
+    pub use crate::query_params::MockParamDebug;
+    pub use crate::query_params::MockParamFallback;
+    pub use crate::query_policy::PolicyDecision;
+    pub use crate::query_policy::QueryKind;
+    pub use crate::query_policy::QueryPolicyCheck;
+    pub use crate::result_limit::AppendLimit;
+    pub use crate::result_limit::ResultBudget;
+    pub use crate::slow_query::is_mysql;
+    pub use crate::slow_query::mysql_plan;
+    pub use crate::slow_query::sqlite_plan;
+    pub use crate::slow_query::SlowQueryCheck;
+    pub use crate::sql_value::from_sql_rows;
+    pub use crate::sql_value::SqlColumn;
+    pub use crate::write_result::TypedWriteResult;
+    pub use crate::write_result::WriteKinds;
 }
 
 pub mod facebook {
@@ -0,0 +1,521 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Versioned schema migrations. A crate lists its `Migration`s in version
+//! order, and `MigrationRunner::ensure` applies the ones the database
+//! hasn't recorded in its `schema_versions` table yet.
+//!
+//! On SQLite, the SQL of a migration, its post step and the record of its
+//! version are committed in one transaction. MySQL commits DDL implicitly,
+//! so a migration interrupted midway can't be rolled back: its version is
+//! checked again right before applying it, and DDL failing because what it
+//! creates already exists counts as applied, as does a concurrent run
+//! recording the version first.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::Context;
+use anyhow::Result;
+use futures::future::BoxFuture;
+use sql::rusqlite;
+use sql::sqlite::SqliteMultithreaded;
+use sql::sqlite::SqliteQueryType;
+use sql::Connection;
+use thiserror::Error;
+
+use crate::sql_retry::mysql_errno;
+
+/// Table already exists, duplicate column name, duplicate key name.
+const MYSQL_ALREADY_EXISTS_ERRNOS: &[u32] = &[1050, 1060, 1061];
+
+/// Duplicate entry for a key.
+const MYSQL_DUPLICATE_KEY_ERRNO: u32 = 1062;
+
+crate::mononoke_queries! {
+    write CreateSchemaVersions() {
+        none,
+        mysql(
+            "CREATE TABLE IF NOT EXISTS schema_versions (
+                version BIGINT UNSIGNED NOT NULL PRIMARY KEY,
+                name VARCHAR(255) NOT NULL,
+                applied_at BIGINT NOT NULL
+            )"
+        )
+        sqlite(
+            "CREATE TABLE IF NOT EXISTS schema_versions (
+                version INTEGER NOT NULL PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at INTEGER NOT NULL
+            )"
+        )
+    }
+
+    read SelectSchemaVersions() -> (u64, String) {
+        "SELECT version, name FROM schema_versions ORDER BY version"
+    }
+
+    write InsertSchemaVersion(version: u64, name: String, applied_at: i64) {
+        none,
+        "INSERT INTO schema_versions (version, name, applied_at)
+         VALUES ({version}, {name}, {applied_at})"
+    }
+}
+
+/// Rust code run after the SQL of a migration, to backfill data for
+/// example. It runs before the version is recorded, so on MySQL it must be
+/// idempotent: it runs again if the migration is interrupted.
+pub type PostStep = Arc<dyn Fn(&Connection) -> BoxFuture<'_, Result<()>> + Send + Sync + 'static>;
+
+/// A change of the schema, identified by its version.
+#[derive(Clone)]
+pub struct Migration {
+    pub version: u64,
+    pub name: &'static str,
+    /// The SQL of the migration for each flavor. Either can contain
+    /// several statements.
+    pub mysql: &'static str,
+    pub sqlite: &'static str,
+    pub post_step: Option<PostStep>,
+}
+
+impl Migration {
+    pub fn new(
+        version: u64,
+        name: &'static str,
+        mysql: &'static str,
+        sqlite: &'static str,
+    ) -> Self {
+        Self {
+            version,
+            name,
+            mysql,
+            sqlite,
+            post_step: None,
+        }
+    }
+
+    pub fn with_post_step(
+        mut self,
+        post_step: impl Fn(&Connection) -> BoxFuture<'_, Result<()>> + Send + Sync + 'static,
+    ) -> Self {
+        self.post_step = Some(Arc::new(post_step));
+        self
+    }
+}
+
+impl fmt::Debug for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Migration")
+            .field("version", &self.version)
+            .field("name", &self.name)
+            .field("post_step", &self.post_step.is_some())
+            .finish()
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MigrationError {
+    /// The database was migrated by a newer binary, or one with other
+    /// migrations. Running older code against it isn't safe.
+    #[error("Schema version {version} ({name}) is recorded, but is not a known migration")]
+    UnknownVersion { version: u64, name: String },
+    #[error("Migration versions must increase, found {version} after {previous}")]
+    Unordered { previous: u64, version: u64 },
+}
+
+/// What `MigrationRunner::ensure` did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AppliedReport {
+    /// Versions applied by this run, in order.
+    pub applied: Vec<u64>,
+    /// Versions found applied, by an earlier or a concurrent run.
+    pub already_applied: Vec<u64>,
+}
+
+/// Applies `Migration`s, see the module documentation.
+pub struct MigrationRunner;
+
+impl MigrationRunner {
+    /// Apply the `migrations` not recorded in the database yet, in order.
+    /// Fails with `MigrationError::UnknownVersion` without applying
+    /// anything if the database recorded a version not in `migrations`.
+    pub async fn ensure(
+        connection: &Connection,
+        migrations: &[Migration],
+    ) -> Result<AppliedReport> {
+        check_ordered(migrations)?;
+        CreateSchemaVersions::query(connection).await?;
+        let recorded = recorded_versions(connection).await?;
+        if let Some((version, name)) = recorded
+            .iter()
+            .find(|(version, _)| !migrations.iter().any(|m| m.version == **version))
+        {
+            return Err(MigrationError::UnknownVersion {
+                version: *version,
+                name: name.clone(),
+            }
+            .into());
+        }
+
+        let mut report = AppliedReport::default();
+        for migration in migrations {
+            if recorded.contains_key(&migration.version) {
+                report.already_applied.push(migration.version);
+                continue;
+            }
+            let applied = match connection {
+                Connection::Mysql(_) => apply_mysql(connection, migration).await,
+                _ => apply_sqlite(connection, migration).await,
+            }
+            .with_context(|| {
+                format!(
+                    "Failed to apply migration {} ({})",
+                    migration.version, migration.name
+                )
+            })?;
+            if applied {
+                report.applied.push(migration.version);
+            } else {
+                report.already_applied.push(migration.version);
+            }
+        }
+        Ok(report)
+    }
+}
+
+fn check_ordered(migrations: &[Migration]) -> Result<(), MigrationError> {
+    for pair in migrations.windows(2) {
+        if pair[0].version >= pair[1].version {
+            return Err(MigrationError::Unordered {
+                previous: pair[0].version,
+                version: pair[1].version,
+            });
+        }
+    }
+    Ok(())
+}
+
+async fn recorded_versions(connection: &Connection) -> Result<BTreeMap<u64, String>> {
+    Ok(SelectSchemaVersions::query(connection)
+        .await?
+        .into_iter()
+        .collect())
+}
+
+/// Returns false if the migration was applied concurrently.
+async fn apply_mysql(connection: &Connection, migration: &Migration) -> Result<bool> {
+    if recorded_versions(connection)
+        .await?
+        .contains_key(&migration.version)
+    {
+        return Ok(false);
+    }
+    let conn = match connection {
+        Connection::Mysql(conn) => conn,
+        _ => unreachable!(),
+    };
+    for statement in statements(migration.mysql) {
+        if let Err(err) = conn.write_query(statement.to_string()).await {
+            let err = anyhow::Error::from(err);
+            if !mysql_errno(&err)
+                .map_or(false, |errno| MYSQL_ALREADY_EXISTS_ERRNOS.contains(&errno))
+            {
+                return Err(err);
+            }
+        }
+    }
+    if let Some(post_step) = &migration.post_step {
+        post_step(connection).await?;
+    }
+    let inserted = InsertSchemaVersion::query(
+        connection,
+        &migration.version,
+        &migration.name.to_string(),
+        &now_secs(),
+    )
+    .await
+    .map_err(anyhow::Error::from);
+    match inserted {
+        Ok(_) => Ok(true),
+        Err(err) if mysql_errno(&err) == Some(MYSQL_DUPLICATE_KEY_ERRNO) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+async fn apply_sqlite(connection: &Connection, migration: &Migration) -> Result<bool> {
+    let sqlite = match connection {
+        Connection::Sqlite(sqlite) => sqlite,
+        _ => unreachable!(),
+    };
+    // The post step queries through `connection`, which releases the
+    // SQLite connection between queries. A savepoint, unlike a
+    // `rusqlite::Transaction`, spans these queries.
+    execute_sqlite(sqlite, "SAVEPOINT migration").await?;
+    let applied = async {
+        execute_sqlite(sqlite, migration.sqlite).await?;
+        if let Some(post_step) = &migration.post_step {
+            post_step(connection).await?;
+        }
+        let conn = sqlite
+            .acquire_sqlite_connection(SqliteQueryType::SchemaChange)
+            .await?;
+        record_sqlite(&conn, migration)
+    }
+    .await;
+    match applied {
+        Ok(()) => execute_sqlite(sqlite, "RELEASE migration").await?,
+        Err(err) => {
+            let _ = execute_sqlite(sqlite, "ROLLBACK TO migration; RELEASE migration").await;
+            return Err(err);
+        }
+    }
+    Ok(true)
+}
+
+async fn execute_sqlite(sqlite: &SqliteMultithreaded, sql: &str) -> Result<()> {
+    let conn = sqlite
+        .acquire_sqlite_connection(SqliteQueryType::SchemaChange)
+        .await?;
+    conn.execute_batch(sql)?;
+    Ok(())
+}
+
+fn record_sqlite(conn: &rusqlite::Connection, migration: &Migration) -> Result<()> {
+    conn.execute(
+        "INSERT INTO schema_versions (version, name, applied_at) VALUES (?1, ?2, ?3)",
+        rusqlite::params![migration.version as i64, migration.name, now_secs()],
+    )?;
+    Ok(())
+}
+
+/// MySQL queries run a single statement. Semicolons in quoted strings and
+/// identifiers don't end a statement.
+fn statements(sql: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in sql.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some('\'') | Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if matches!(c, '\'' | '"' | '`') => quote = Some(c),
+            None if c == ';' => {
+                statements.push(&sql[start..i]);
+                start = i + 1;
+            }
+            None => {}
+        }
+    }
+    statements.push(&sql[start..]);
+    statements
+        .into_iter()
+        .map(str::trim)
+        .filter(|statement| !statement.is_empty())
+        .collect()
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::open_sqlite_in_memory;
+
+    crate::mononoke_queries! {
+        write InsertItem(id: u64, name: String, size: u64) {
+            none,
+            "INSERT INTO items (id, name, size) VALUES ({id}, {name}, {size})"
+        }
+
+        write BackfillSizes() {
+            none,
+            "UPDATE items SET size = 0 WHERE size IS NULL"
+        }
+    }
+
+    fn migrations(post_steps: &Arc<AtomicUsize>) -> Vec<Migration> {
+        let post_steps = post_steps.clone();
+        vec![
+            Migration::new(
+                1,
+                "create items",
+                "CREATE TABLE items (id BIGINT UNSIGNED PRIMARY KEY, name VARCHAR(255))",
+                "CREATE TABLE items (id INTEGER PRIMARY KEY, name TEXT)",
+            ),
+            Migration::new(
+                2,
+                "add items size",
+                "ALTER TABLE items ADD COLUMN size BIGINT UNSIGNED",
+                "ALTER TABLE items ADD COLUMN size INTEGER",
+            )
+            .with_post_step(move |connection| {
+                post_steps.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    BackfillSizes::query(connection).await?;
+                    Ok(())
+                }
+                .boxed()
+            }),
+            Migration::new(
+                3,
+                "index items name",
+                "CREATE INDEX items_name ON items (name)",
+                "CREATE INDEX items_name ON items (name)",
+            ),
+        ]
+    }
+
+    fn connection() -> Result<Connection> {
+        Ok(Connection::with_sqlite(open_sqlite_in_memory()?))
+    }
+
+    async fn versions(connection: &Connection) -> Result<Vec<u64>> {
+        Ok(recorded_versions(connection).await?.into_keys().collect())
+    }
+
+    #[tokio::test]
+    async fn test_fresh() -> Result<()> {
+        let connection = connection()?;
+        let post_steps = Arc::new(AtomicUsize::new(0));
+        let report = MigrationRunner::ensure(&connection, &migrations(&post_steps)).await?;
+        assert_eq!(report.applied, vec![1, 2, 3]);
+        assert!(report.already_applied.is_empty());
+        assert_eq!(post_steps.load(Ordering::Relaxed), 1);
+        assert_eq!(versions(&connection).await?, vec![1, 2, 3]);
+        InsertItem::query(&connection, &1, &"a".to_string(), &10).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rerun() -> Result<()> {
+        let connection = connection()?;
+        let post_steps = Arc::new(AtomicUsize::new(0));
+        MigrationRunner::ensure(&connection, &migrations(&post_steps)).await?;
+        let report = MigrationRunner::ensure(&connection, &migrations(&post_steps)).await?;
+        assert!(report.applied.is_empty());
+        assert_eq!(report.already_applied, vec![1, 2, 3]);
+        assert_eq!(post_steps.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resume() -> Result<()> {
+        let connection = connection()?;
+        let post_steps = Arc::new(AtomicUsize::new(0));
+        let all = migrations(&post_steps);
+        let report = MigrationRunner::ensure(&connection, &all[..1]).await?;
+        assert_eq!(report.applied, vec![1]);
+        let report = MigrationRunner::ensure(&connection, &all).await?;
+        assert_eq!(report.applied, vec![2, 3]);
+        assert_eq!(report.already_applied, vec![1]);
+        assert_eq!(post_steps.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unknown_version() -> Result<()> {
+        let connection = connection()?;
+        let post_steps = Arc::new(AtomicUsize::new(0));
+        let all = migrations(&post_steps);
+        MigrationRunner::ensure(&connection, &all).await?;
+        let err = MigrationRunner::ensure(&connection, &all[..2])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MigrationError>(),
+            Some(&MigrationError::UnknownVersion {
+                version: 3,
+                name: "index items name".to_string(),
+            })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unordered() -> Result<()> {
+        let connection = connection()?;
+        let post_steps = Arc::new(AtomicUsize::new(0));
+        let mut all = migrations(&post_steps);
+        all.swap(1, 2);
+        let err = MigrationRunner::ensure(&connection, &all)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MigrationError>(),
+            Some(&MigrationError::Unordered {
+                previous: 3,
+                version: 2,
+            })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_rolled_back() -> Result<()> {
+        let connection = connection()?;
+        let broken = [Migration::new(
+            1,
+            "broken",
+            "",
+            "CREATE TABLE items (id INTEGER PRIMARY KEY); CREATE TABLE items (id INTEGER)",
+        )];
+        assert!(MigrationRunner::ensure(&connection, &broken).await.is_err());
+        assert!(versions(&connection).await?.is_empty());
+        // The first statement was rolled back, so the fixed migration applies.
+        let post_steps = Arc::new(AtomicUsize::new(0));
+        let report = MigrationRunner::ensure(&connection, &migrations(&post_steps)).await?;
+        assert_eq!(report.applied, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_post_step_rolled_back() -> Result<()> {
+        let connection = connection()?;
+        let post_steps = Arc::new(AtomicUsize::new(0));
+        let mut all = migrations(&post_steps);
+        all[1] = all[1]
+            .clone()
+            .with_post_step(|_| async { Err(anyhow::anyhow!("backfill failed")) }.boxed());
+        assert!(MigrationRunner::ensure(&connection, &all).await.is_err());
+        assert_eq!(versions(&connection).await?, vec![1]);
+        // The column added by the failed migration was rolled back with it.
+        let report = MigrationRunner::ensure(&connection, &migrations(&post_steps)).await?;
+        assert_eq!(report.applied, vec![2, 3]);
+        assert_eq!(post_steps.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_statements() {
+        assert_eq!(
+            statements("CREATE TABLE a (x INT);\n CREATE INDEX b ON a (x);\n"),
+            vec!["CREATE TABLE a (x INT)", "CREATE INDEX b ON a (x)"]
+        );
+        assert_eq!(
+            statements("INSERT INTO a VALUES ('x;y', 'it\\'s;'); UPDATE `a;b` SET c = \"d;\""),
+            vec![
+                "INSERT INTO a VALUES ('x;y', 'it\\'s;')",
+                "UPDATE `a;b` SET c = \"d;\""
+            ]
+        );
+    }
+}
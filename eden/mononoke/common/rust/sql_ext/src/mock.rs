@@ -0,0 +1,224 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Scripted responses for queries defined with `mononoke_queries!`, so the
+//! code wrapping them can be tested without a database.
+//!
+//! In `cfg(test)` builds of the crate defining the queries, every attempt of
+//! a generated `query` function first checks for a `MockConnection`
+//! installed on the current thread. If there is one, the attempt is recorded
+//! and answered from the script for that query name instead of reaching the
+//! connection passed in. Retries, caching and streaming happen as usual.
+//!
+//! ```ignore
+//! let mock = MockConnection::new()?;
+//! mock.push_mysql_error("SelectMapping", 1213);
+//! mock.push_rows("SelectMapping", vec![(1u64, "a".to_string())]);
+//! let _guard = mock.install();
+//! let rows = SelectMapping::query(mock.connection(), &1).await?;
+//! assert_eq!(mock.invocations().len(), 2);
+//! ```
+//!
+//! The mocks are only built for the tests of this crate, and with the `mock`
+//! feature, which the crates testing their queries with them enable in their
+//! dev-dependencies.
+//!
+//! The mock is per thread, so queries run on spawned tasks of a
+//! multi-threaded runtime are not intercepted.
+
+use std::any::type_name;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use sql::Connection;
+use sql::WriteResult;
+use thiserror::Error;
+
+use crate::open_sqlite_in_memory;
+
+thread_local! {
+    static INSTALLED: RefCell<Option<Arc<Mutex<MockState>>>> = RefCell::new(None);
+}
+
+/// A MySQL error with the given errno, classified for retries like the real
+/// client errors.
+#[derive(Debug, Error)]
+#[error("MySQL error {errno} (mocked)")]
+pub struct MockMysqlError {
+    pub errno: u32,
+}
+
+/// A single attempt of a query, with its parameters formatted with `Debug`.
+/// Parameters without a `Debug` implementation are recorded by type name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Invocation {
+    pub query: String,
+    pub params: Vec<String>,
+}
+
+enum Response {
+    Value(Box<dyn Any + Send>),
+    Error(anyhow::Error),
+}
+
+#[derive(Default)]
+struct Script {
+    responses: VecDeque<Response>,
+    latency: Duration,
+    in_flight: usize,
+    peak_in_flight: usize,
+}
+
+#[derive(Default)]
+struct MockState {
+    scripts: HashMap<String, Script>,
+    invocations: Vec<Invocation>,
+}
+
+/// Answers queries from per-query scripts. Each scripted response is used
+/// by one attempt, in order. An attempt with no response left fails.
+pub struct MockConnection {
+    state: Arc<Mutex<MockState>>,
+    connection: Connection,
+}
+
+impl MockConnection {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            state: Arc::new(Mutex::new(MockState::default())),
+            connection: Connection::with_sqlite(open_sqlite_in_memory()?),
+        })
+    }
+
+    /// A connection to pass to the query functions. It is an empty SQLite
+    /// database, only reached if the mock is not installed.
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Answer the next attempt of read query `query` with `rows`.
+    pub fn push_rows<T: Send + 'static>(&self, query: &str, rows: Vec<T>) {
+        self.push(query, Response::Value(Box::new(rows)));
+    }
+
+    /// Answer the next attempt of write query `query` with `result`.
+    pub fn push_write(&self, query: &str, result: WriteResult) {
+        self.push(query, Response::Value(Box::new(result)));
+    }
+
+    /// Fail the next attempt of `query` with `error`.
+    pub fn push_error(&self, query: &str, error: anyhow::Error) {
+        self.push(query, Response::Error(error));
+    }
+
+    /// Fail the next attempt of `query` with a MySQL error.
+    pub fn push_mysql_error(&self, query: &str, errno: u32) {
+        self.push_error(query, MockMysqlError { errno }.into());
+    }
+
+    /// Delay every attempt of `query` by `latency`.
+    pub fn set_latency(&self, query: &str, latency: Duration) {
+        self.script(query, |script| script.latency = latency);
+    }
+
+    /// The most attempts of `query` that were in flight at once.
+    pub fn peak_concurrency(&self, query: &str) -> usize {
+        let state = self.state.lock().unwrap();
+        state.scripts.get(query).map_or(0, |script| script.peak_in_flight)
+    }
+
+    /// All attempts so far, in order.
+    pub fn invocations(&self) -> Vec<Invocation> {
+        self.state.lock().unwrap().invocations.clone()
+    }
+
+    /// Intercept queries on the current thread until the guard is dropped.
+    pub fn install(&self) -> MockGuard {
+        let previous = INSTALLED.with(|installed| installed.replace(Some(self.state.clone())));
+        MockGuard { previous }
+    }
+
+    fn push(&self, query: &str, response: Response) {
+        self.script(query, |script| script.responses.push_back(response));
+    }
+
+    fn script(&self, query: &str, f: impl FnOnce(&mut Script)) {
+        let mut state = self.state.lock().unwrap();
+        f(state.scripts.entry(query.to_string()).or_default());
+    }
+}
+
+/// Uninstalls the mock, restoring any previously installed one.
+pub struct MockGuard {
+    previous: Option<Arc<Mutex<MockState>>>,
+}
+
+impl Drop for MockGuard {
+    fn drop(&mut self) {
+        INSTALLED.with(|installed| *installed.borrow_mut() = self.previous.take());
+    }
+}
+
+/// Called by the generated query functions for every attempt. Returns `None`
+/// if no mock is installed.
+pub async fn intercept<T: 'static>(
+    query: &'static str,
+    params: impl FnOnce() -> Vec<String>,
+) -> Option<Result<T>> {
+    let state = INSTALLED.with(|installed| installed.borrow().clone())?;
+    let (response, latency) = {
+        let mut state = state.lock().unwrap();
+        state.invocations.push(Invocation {
+            query: query.to_string(),
+            params: params(),
+        });
+        let script = state.scripts.entry(query.to_string()).or_default();
+        script.in_flight += 1;
+        script.peak_in_flight = script.peak_in_flight.max(script.in_flight);
+        (script.responses.pop_front(), script.latency)
+    };
+    let in_flight = InFlight {
+        state: &state,
+        query,
+    };
+    if !latency.is_zero() {
+        tokio::time::sleep(latency).await;
+    }
+    drop(in_flight);
+    Some(match response {
+        None => Err(anyhow!("no scripted response left for query {}", query)),
+        Some(Response::Error(error)) => Err(error),
+        Some(Response::Value(value)) => value.downcast::<T>().map(|v| *v).map_err(|_| {
+            anyhow!(
+                "scripted response for query {} is not a {}",
+                query,
+                type_name::<T>()
+            )
+        }),
+    })
+}
+
+/// Counts an attempt as in flight until dropped, even if it is cancelled.
+struct InFlight<'a> {
+    state: &'a Mutex<MockState>,
+    query: &'static str,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if let Some(script) = self.state.lock().unwrap().scripts.get_mut(self.query) {
+            script.in_flight -= 1;
+        }
+    }
+}
@@ -8,7 +8,6 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::future::Future;
-use std::time::Duration;
 
 use abomonation::Abomonation;
 use abomonation_derive::Abomonation;
@@ -17,16 +16,42 @@ use anyhow::Result;
 use async_trait::async_trait;
 use bytes::Bytes;
 use caching_ext::*;
+use futures::stream;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use futures::TryStreamExt;
 use itertools::Itertools;
 use maplit::hashmap;
 use maplit::hashset;
 use memcache::KeyGen;
-use retry::retry;
-use retry::RetryLogic;
 use sql_query_config::CachingConfig;
 use tunables::tunables;
 
-const RETRY_ATTEMPTS: usize = 2;
+use crate::query_limit::QueryLimiter;
+use crate::query_policy::QueryKind;
+use crate::sql_retry::retry_sql_operation;
+use crate::sql_retry::DEFAULT_SQL_RETRY_POLICY;
+
+/// Checks for an installed `MockConnection` in the `cfg(test)` builds of the
+/// crate expanding it.
+#[cfg(any(test, feature = "mock"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! _mock_intercept {
+    ($( $tt:tt )*) => {
+        #[cfg(test)]
+        {
+            $( $tt )*
+        }
+    };
+}
+
+#[cfg(not(any(test, feature = "mock")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! _mock_intercept {
+    ($( $tt:tt )*) => {};
+}
 
 // This wraps around rust/shed/sql::queries, check that macro: https://fburl.com/code/semq9xm3
 /// Define SQL queries that automatically retry on certain errors.
@@ -35,12 +60,112 @@ const RETRY_ATTEMPTS: usize = 2;
 /// - Adding "cacheable" keyword to your query.
 /// - Make sure all parameters (input) to the query implement the Hash trait.
 /// - Making sure the return values (output) implement Serialize, Deserialize, and Abomonation.
+///
+/// Large read results can be streamed instead of collected by declaring a
+/// "streaming" read with an ordering column, given by its index and type in
+/// the returned tuple:
+///
+/// ```ignore
+/// streaming read SelectAll(max_id: u64) -> (u64, String) order by 0: u64 {
+///     "SELECT id, value FROM t WHERE id > {after} AND id <= {max_id} ORDER BY id LIMIT {limit}"
+/// }
+/// ```
+///
+/// The query is run repeatedly with keyset pagination: `{after}` is bound to
+/// the ordering column of the last row returned so far, and `{limit}` to the
+/// chunk size. The query must therefore return rows in strictly increasing
+/// order of the ordering column, and that column must be unique, or rows will
+/// be skipped. `query_stream` yields rows as each chunk arrives and buffers at
+/// most one chunk. Each chunk is retried separately; a failure after the first
+/// chunk ends the stream with an error.
+///
+/// The sql crate does not expose server-side cursors, so both MySQL and SQLite
+/// use keyset pagination.
+///
+/// The number of concurrent calls of a query can be limited by annotating it:
+///
+/// ```ignore
+/// { max_concurrency = 16, acquire_timeout = std::time::Duration::from_secs(1) }
+/// read SelectMapping(id: u64) -> (String) { "..." }
+/// ```
+///
+/// Calls wait for a permit before their first attempt and hold it across
+/// retries. If `acquire_timeout` is set, calls waiting longer fail with
+/// [`QuerySaturated`](crate::QuerySaturated). The limits can be changed at
+/// runtime with [`set_query_limits`](crate::set_query_limits), using the path
+/// of the module generated for the query as its name. See
+/// [`QueryLimits`](crate::QueryLimits).
+///
+/// The results of read queries can be bounded in the same way, with
+/// `max_rows` and `max_result_bytes`. Calls returning more rows, or more
+/// bytes as estimated from the values received, fail with
+/// [`ResultTooLarge`](crate::ResultTooLarge) before the rows are converted,
+/// and are not retried. The bound of a streaming read applies to all its
+/// chunks together. Queries without bounds get the ones set with
+/// [`set_default_result_limits`](crate::set_default_result_limits), unless
+/// annotated with `unlimited = true`. With `max_rows`, the query runs with
+/// `LIMIT max_rows + 1`, or fetches chunks of a streaming read up to that
+/// many rows, so the client does not read the whole result first. This is
+/// skipped for queries with their own `LIMIT` or a locking clause, whose
+/// rows are only counted once read.
+///
+/// Queries can be disabled at runtime with
+/// [`set_query_policy`](crate::set_query_policy), under the same name, to
+/// fail fast or, for reads, return no rows. See
+/// [`QueryPolicy`](crate::QueryPolicy).
+///
+/// With the `query_registry` feature, every query is listed by
+/// [`registry::all_queries`](crate::registry::all_queries), with its kind,
+/// templates and parameters.
+///
+/// With the `slow_query_explain` feature, slow calls of read queries can
+/// have their plan captured, see
+/// [`set_slow_query_explain`](crate::set_slow_query_explain).
+///
+/// Write queries return a [`TypedWriteResult`](crate::TypedWriteResult),
+/// which derefs to the `WriteResult` and tells the inserted id and the
+/// [`WriteOutcome`](crate::WriteOutcome) of the write. `query_with_transaction`
+/// still returns the plain `WriteResult`.
+///
+/// Parameters and result columns can be of any type the `sql` crate converts
+/// to and from a `Value`, including newtypes declared with
+/// [`sql_newtype!`](crate::sql_newtype). A result column that cannot be
+/// converted to its type fails the query with a
+/// [`SqlConversionError`](crate::SqlConversionError), which is not retried.
+///
+/// Queries given an empty `>list`, and writes given no `values`, return no
+/// rows, or a `WriteResult` with no affected rows, without running: `IN ()`
+/// is not valid MySQL. This holds for `query_with_transaction` too.
+///
+/// A read can instead take a single optional list, which only filters the
+/// rows if it is `Some`. The clause of the query using the list is marked
+/// optional, and left out if the list is `None`:
+///
+/// ```ignore
+/// read SelectValues(repo: u64, >optlist ids: u64) -> (u64, String) {
+///     "SELECT id, value FROM t WHERE repo = {repo}"
+///     optional("AND id IN {ids}")
+///     "ORDER BY id"
+/// }
+/// ```
+///
+/// The parts of such a query are string literals, joined with spaces. An
+/// optional list can't be combined with other lists, caching or streaming.
+///
+/// Read and write queries also get a `query_from` function taking
+/// [`SqlConnectionPools`](crate::SqlConnectionPools) instead of a connection,
+/// which checks out a connection of the read or write pool.
+///
+/// In `cfg(test)` builds, the generated `query` functions can be answered by a
+/// `sql_ext::mock::MockConnection` instead of the database, if the `mock`
+/// feature of this crate is enabled.
 #[macro_export]
 macro_rules! mononoke_queries {
     () => {};
 
     // Read query with a single expression. Redirect to read query with same expression for mysql and sqlite.
     (
+        $( { $( $limit:tt )* } )?
         $vi:vis read $name:ident (
             $( $pname:ident: $ptype:ty ),* $(,)*
             $( >list $lname:ident: $ltype:ty )*
@@ -48,6 +173,7 @@ macro_rules! mononoke_queries {
         $( $rest:tt )*
     ) => {
         $crate::mononoke_queries! {
+            $( { $( $limit )* } )?
             $vi read $name (
                 $( $pname: $ptype, )*
                 $( >list $lname: $ltype )*
@@ -57,6 +183,7 @@ macro_rules! mononoke_queries {
     };
     // Read query with a single expression and cache. Redirect to read query with same expression for mysql and sqlite.
     (
+        $( { $( $limit:tt )* } )?
         $vi:vis cacheable read $name:ident (
             $( $pname:ident: $ptype:ty ),* $(,)*
             $( >list $lname:ident: $ltype:ty )*
@@ -64,6 +191,7 @@ macro_rules! mononoke_queries {
         $( $rest:tt )*
     ) => {
         $crate::mononoke_queries! {
+            $( { $( $limit )* } )?
             $vi cacheable read $name (
                 $( $pname: $ptype, )*
                 $( >list $lname: $ltype )*
@@ -74,6 +202,7 @@ macro_rules! mononoke_queries {
 
     // Full read query without cache. Call `sql::queries!` and re-export stuff, wrapped in retries, on a new module.
     (
+        $( { $( $lkey:ident = $lval:expr ),* $(,)? } )?
         $vi:vis read $name:ident (
             $( $pname:ident: $ptype:ty ),* $(,)*
             $( >list $lname:ident: $ltype:ty )*
@@ -85,7 +214,32 @@ macro_rules! mononoke_queries {
                 pub read [<$name Impl>] (
                     $( $pname: $ptype, )*
                     $( >list $lname: $ltype )*
-                ) -> ($( $rtype ),*) { mysql($mysql_q) sqlite($sqlite_q) }
+                ) -> ($( $crate::_macro_internal::SqlColumn<$rtype> ),*) { mysql($mysql_q) sqlite($sqlite_q) }
+                pub read [<$name BoundedImpl>] (
+                    result_limit: u64,
+                    $( $pname: $ptype, )*
+                    $( >list $lname: $ltype )*
+                ) -> ($( $crate::_macro_internal::SqlColumn<$rtype> ),*) {
+                    mysql(concat!($mysql_q, " LIMIT {result_limit}"))
+                    sqlite(concat!($sqlite_q, " LIMIT {result_limit}"))
+                }
+            }
+
+            $crate::_explain_queries! {
+                pub read [<$name ExplainMysqlImpl>] (
+                    $( $pname: $ptype, )*
+                    $( >list $lname: $ltype )*
+                ) -> (String) {
+                    mysql(concat!("EXPLAIN FORMAT=JSON ", $mysql_q))
+                    sqlite(concat!("EXPLAIN QUERY PLAN ", $sqlite_q))
+                }
+                pub read [<$name ExplainSqliteImpl>] (
+                    $( $pname: $ptype, )*
+                    $( >list $lname: $ltype )*
+                ) -> (i64, i64, i64, String) {
+                    mysql(concat!("EXPLAIN FORMAT=JSON ", $mysql_q))
+                    sqlite(concat!("EXPLAIN QUERY PLAN ", $sqlite_q))
+                }
             }
 
             #[allow(non_snake_case)]
@@ -96,9 +250,33 @@ macro_rules! mononoke_queries {
                 #[allow(unused_imports)]
                 use $crate::_macro_internal::*;
 
+                $crate::_register_query! {
+                    kind: Read,
+                    mysql: $mysql_q,
+                    sqlite: $sqlite_q,
+                    params: [
+                        $( (stringify!($pname), stringify!($ptype)), )*
+                        $( (stringify!($lname), concat!("[", stringify!($ltype), "]")), )*
+                    ],
+                }
+
                 // Not possible to retry query with transaction
-                #[allow(unused_imports)]
-                pub use [<$name Impl>]::query_with_transaction;
+                #[allow(dead_code)]
+                pub async fn query_with_transaction(
+                    transaction: $crate::_macro_internal::Transaction,
+                    $( $pname: & $ptype, )*
+                    $( $lname: & [ $ltype ], )*
+                ) -> Result<($crate::_macro_internal::Transaction, Vec<($( $rtype, )*)>)> {
+                    $(
+                        if $lname.is_empty() {
+                            return Ok((transaction, Vec::new()));
+                        }
+                    )*
+                    let (transaction, rows) = [<$name Impl>]::query_with_transaction(
+                        transaction, $( $pname, )* $( $lname, )*
+                    ).await?;
+                    Ok((transaction, from_sql_rows(rows)?))
+                }
 
                 #[allow(dead_code)]
                 pub async fn query(
@@ -106,9 +284,77 @@ macro_rules! mononoke_queries {
                     $( $pname: & $ptype, )*
                     $( $lname: & [ $ltype ], )*
                 ) -> Result<Vec<($( $rtype, )*)>> {
-                    query_with_retry_no_cache(
-                        || [<$name Impl>]::query(connection, $( $pname, )* $( $lname, )*),
-                    ).await
+                    $(
+                        if $lname.is_empty() {
+                            return Ok(Vec::new());
+                        }
+                    )*
+                    static QUERY_POLICY: QueryPolicyCheck = QueryPolicyCheck::new(module_path!(), QueryKind::Read);
+                    if QUERY_POLICY.check()? == PolicyDecision::ReturnEmpty {
+                        return Ok(Vec::new());
+                    }
+                    #[allow(clippy::needless_update)]
+                    static QUERY_LIMITER: QueryLimiter = QueryLimiter::new(
+                        module_path!(),
+                        QueryLimits { $( $( $lkey: Some($lval), )* )? ..QueryLimits::UNLIMITED },
+                    );
+                    static APPEND_LIMIT: AppendLimit = AppendLimit::new($mysql_q, $sqlite_q);
+                    let limits = QUERY_LIMITER.result_limits();
+                    $crate::_slow_query! {
+                        static SLOW_QUERY: SlowQueryCheck = SlowQueryCheck::new(module_path!());
+                        let start = Instant::now();
+                    }
+                    let rows = query_with_retry_limited(
+                        &QUERY_LIMITER,
+                        QueryKind::Read,
+                        || async move {
+                            $crate::_mock_intercept! {
+                                if let Some(result) = mock_intercept(
+                                    stringify!($name),
+                                    || vec![$( (&MockParam($pname)).mock_format(), )* $( (&MockParam($lname)).mock_format(), )*],
+                                ).await {
+                                    return result;
+                                }
+                            }
+                            let rows = match APPEND_LIMIT.row_limit(&limits) {
+                                Some(row_limit) => [<$name BoundedImpl>]::query(connection, &row_limit, $( $pname, )* $( $lname, )*).await?,
+                                None => [<$name Impl>]::query(connection, $( $pname, )* $( $lname, )*).await?,
+                            };
+                            ResultBudget::new(module_path!(), limits).check(&rows)?;
+                            Ok(from_sql_rows(rows)?)
+                        },
+                    ).await?;
+                    $crate::_slow_query! {
+                        SLOW_QUERY.observe(
+                            start.elapsed(),
+                            || vec![$( (&MockParam($pname)).mock_format(), )* $( (&MockParam($lname)).mock_format(), )*],
+                            || {
+                                let connection = connection.clone();
+                                $( let $pname = ToOwned::to_owned($pname); )*
+                                $( let $lname = ToOwned::to_owned($lname); )*
+                                async move {
+                                    if is_mysql(&connection) {
+                                        Ok(mysql_plan([<$name ExplainMysqlImpl>]::query(&connection, $( Borrow::borrow(&$pname), )* $( Borrow::borrow(&$lname), )*).await?))
+                                    } else {
+                                        Ok(sqlite_plan([<$name ExplainSqliteImpl>]::query(&connection, $( Borrow::borrow(&$pname), )* $( Borrow::borrow(&$lname), )*).await?))
+                                    }
+                                }
+                            },
+                        );
+                    }
+                    Ok(rows)
+                }
+
+                /// Same as `query`, on a connection of the read pool of
+                /// `pools`.
+                #[allow(dead_code)]
+                pub async fn query_from(
+                    pools: &SqlConnectionPools,
+                    $( $pname: & $ptype, )*
+                    $( $lname: & [ $ltype ], )*
+                ) -> Result<Vec<($( $rtype, )*)>> {
+                    let connection = pools.read_connection().await?;
+                    query(&connection, $( $pname, )* $( $lname, )*).await
                 }
             }
 
@@ -118,6 +364,7 @@ macro_rules! mononoke_queries {
 
     // Full read query. Call `sql::queries!` and re-export stuff, wrapped in retries, on a new module.
     (
+        $( { $( $lkey:ident = $lval:expr ),* $(,)? } )?
         $vi:vis cacheable read $name:ident (
             $( $pname:ident: $ptype:ty ),* $(,)*
             $( >list $lname:ident: $ltype:ty )*
@@ -129,7 +376,32 @@ macro_rules! mononoke_queries {
                 pub read [<$name Impl>] (
                     $( $pname: $ptype, )*
                     $( >list $lname: $ltype )*
-                ) -> ($( $rtype ),*) { mysql($mysql_q) sqlite($sqlite_q) }
+                ) -> ($( $crate::_macro_internal::SqlColumn<$rtype> ),*) { mysql($mysql_q) sqlite($sqlite_q) }
+                pub read [<$name BoundedImpl>] (
+                    result_limit: u64,
+                    $( $pname: $ptype, )*
+                    $( >list $lname: $ltype )*
+                ) -> ($( $crate::_macro_internal::SqlColumn<$rtype> ),*) {
+                    mysql(concat!($mysql_q, " LIMIT {result_limit}"))
+                    sqlite(concat!($sqlite_q, " LIMIT {result_limit}"))
+                }
+            }
+
+            $crate::_explain_queries! {
+                pub read [<$name ExplainMysqlImpl>] (
+                    $( $pname: $ptype, )*
+                    $( >list $lname: $ltype )*
+                ) -> (String) {
+                    mysql(concat!("EXPLAIN FORMAT=JSON ", $mysql_q))
+                    sqlite(concat!("EXPLAIN QUERY PLAN ", $sqlite_q))
+                }
+                pub read [<$name ExplainSqliteImpl>] (
+                    $( $pname: $ptype, )*
+                    $( >list $lname: $ltype )*
+                ) -> (i64, i64, i64, String) {
+                    mysql(concat!("EXPLAIN FORMAT=JSON ", $mysql_q))
+                    sqlite(concat!("EXPLAIN QUERY PLAN ", $sqlite_q))
+                }
             }
 
             #[allow(non_snake_case)]
@@ -140,9 +412,33 @@ macro_rules! mononoke_queries {
                 #[allow(unused_imports)]
                 use $crate::_macro_internal::*;
 
+                $crate::_register_query! {
+                    kind: Read,
+                    mysql: $mysql_q,
+                    sqlite: $sqlite_q,
+                    params: [
+                        $( (stringify!($pname), stringify!($ptype)), )*
+                        $( (stringify!($lname), concat!("[", stringify!($ltype), "]")), )*
+                    ],
+                }
+
                 // Not possible to retry query with transaction
-                #[allow(unused_imports)]
-                pub use [<$name Impl>]::query_with_transaction;
+                #[allow(dead_code)]
+                pub async fn query_with_transaction(
+                    transaction: $crate::_macro_internal::Transaction,
+                    $( $pname: & $ptype, )*
+                    $( $lname: & [ $ltype ], )*
+                ) -> Result<($crate::_macro_internal::Transaction, Vec<($( $rtype, )*)>)> {
+                    $(
+                        if $lname.is_empty() {
+                            return Ok((transaction, Vec::new()));
+                        }
+                    )*
+                    let (transaction, rows) = [<$name Impl>]::query_with_transaction(
+                        transaction, $( $pname, )* $( $lname, )*
+                    ).await?;
+                    Ok((transaction, from_sql_rows(rows)?))
+                }
 
                 #[allow(dead_code)]
                 pub async fn query(
@@ -151,6 +447,20 @@ macro_rules! mononoke_queries {
                     $( $pname: & $ptype, )*
                     $( $lname: & [ $ltype ], )*
                 ) -> Result<Vec<($( $rtype, )*)>> {
+                    $(
+                        if $lname.is_empty() {
+                            return Ok(Vec::new());
+                        }
+                    )*
+                    static QUERY_POLICY: QueryPolicyCheck = QueryPolicyCheck::new(module_path!(), QueryKind::Read);
+                    if QUERY_POLICY.check()? == PolicyDecision::ReturnEmpty {
+                        return Ok(Vec::new());
+                    }
+                    #[allow(clippy::needless_update)]
+                    static QUERY_LIMITER: QueryLimiter = QueryLimiter::new(
+                        module_path!(),
+                        QueryLimits { $( $( $lkey: Some($lval), )* )? ..QueryLimits::UNLIMITED },
+                    );
                     let mut hasher = Hash128::with_seed(0);
 
                     $(
@@ -166,10 +476,481 @@ macro_rules! mononoke_queries {
                     let data = CacheData {key, config: config.caching.as_ref()};
 
 
-                    Ok(query_with_retry(
+                    static APPEND_LIMIT: AppendLimit = AppendLimit::new($mysql_q, $sqlite_q);
+                    let limits = QUERY_LIMITER.result_limits();
+                    $crate::_slow_query! {
+                        static SLOW_QUERY: SlowQueryCheck = SlowQueryCheck::new(module_path!());
+                        let start = Instant::now();
+                    }
+                    let rows = query_with_retry(
                         data,
-                        || async move { Ok(MemcacheWrapper([<$name Impl>]::query(connection, $( $pname, )* $( $lname, )*).await?)) },
-                    ).await?.0)
+                        &QUERY_LIMITER,
+                        || async move {
+                            $crate::_mock_intercept! {
+                                if let Some(result) = mock_intercept(
+                                    stringify!($name),
+                                    || vec![$( (&MockParam($pname)).mock_format(), )* $( (&MockParam($lname)).mock_format(), )*],
+                                ).await {
+                                    return result.map(MemcacheWrapper);
+                                }
+                            }
+                            let rows = match APPEND_LIMIT.row_limit(&limits) {
+                                Some(row_limit) => [<$name BoundedImpl>]::query(connection, &row_limit, $( $pname, )* $( $lname, )*).await?,
+                                None => [<$name Impl>]::query(connection, $( $pname, )* $( $lname, )*).await?,
+                            };
+                            ResultBudget::new(module_path!(), limits).check(&rows)?;
+                            Ok(MemcacheWrapper(from_sql_rows(rows)?))
+                        },
+                    ).await?.0;
+                    $crate::_slow_query! {
+                        SLOW_QUERY.observe(
+                            start.elapsed(),
+                            || vec![$( (&MockParam($pname)).mock_format(), )* $( (&MockParam($lname)).mock_format(), )*],
+                            || {
+                                let connection = connection.clone();
+                                $( let $pname = ToOwned::to_owned($pname); )*
+                                $( let $lname = ToOwned::to_owned($lname); )*
+                                async move {
+                                    if is_mysql(&connection) {
+                                        Ok(mysql_plan([<$name ExplainMysqlImpl>]::query(&connection, $( Borrow::borrow(&$pname), )* $( Borrow::borrow(&$lname), )*).await?))
+                                    } else {
+                                        Ok(sqlite_plan([<$name ExplainSqliteImpl>]::query(&connection, $( Borrow::borrow(&$pname), )* $( Borrow::borrow(&$lname), )*).await?))
+                                    }
+                                }
+                            },
+                        );
+                    }
+                    Ok(rows)
+                }
+
+                /// Same as `query`, on a connection of the read pool of
+                /// `pools`.
+                #[allow(dead_code)]
+                pub async fn query_from(
+                    config: &SqlQueryConfig,
+                    pools: &SqlConnectionPools,
+                    $( $pname: & $ptype, )*
+                    $( $lname: & [ $ltype ], )*
+                ) -> Result<Vec<($( $rtype, )*)>> {
+                    let connection = pools.read_connection().await?;
+                    query(config, &connection, $( $pname, )* $( $lname, )*).await
+                }
+            }
+
+            $crate::mononoke_queries! { $( $rest )* }
+        }
+    };
+
+    // Read query with an optional list and a single expression. Redirect to read query with same
+    // expression for mysql and sqlite.
+    (
+        $( { $( $limit:tt )* } )?
+        $vi:vis read $name:ident (
+            $( $pname:ident: $ptype:ty ),* $(,)*
+            >optlist $oname:ident: $otype:ty
+        ) -> ($( $rtype:ty ),* $(,)*) { $head:literal optional($clause:literal) $( $tail:literal )? }
+        $( $rest:tt )*
+    ) => {
+        $crate::mononoke_queries! {
+            $( { $( $limit )* } )?
+            $vi read $name (
+                $( $pname: $ptype, )*
+                >optlist $oname: $otype
+            ) -> ($( $rtype ),*) {
+                mysql($head optional($clause) $( $tail )?)
+                sqlite($head optional($clause) $( $tail )?)
+            }
+            $( $rest )*
+        }
+    };
+
+    // Full read query with an optional list. Call `sql::queries!` for the query with and without the
+    // optional clause, and pick one at runtime, wrapped in retries, on a new module.
+    (
+        $( { $( $lkey:ident = $lval:expr ),* $(,)? } )?
+        $vi:vis read $name:ident (
+            $( $pname:ident: $ptype:ty ),* $(,)*
+            >optlist $oname:ident: $otype:ty
+        ) -> ($( $rtype:ty ),* $(,)*) {
+            mysql($mysql_head:literal optional($mysql_clause:literal) $( $mysql_tail:literal )?)
+            sqlite($sqlite_head:literal optional($sqlite_clause:literal) $( $sqlite_tail:literal )?)
+        }
+        $( $rest:tt )*
+    ) => {
+        $crate::_macro_internal::paste::item! {
+            $crate::_macro_internal::queries! {
+                pub read [<$name Impl>] (
+                    $( $pname: $ptype, )*
+                    >list $oname: $otype
+                ) -> ($( $crate::_macro_internal::SqlColumn<$rtype> ),*) {
+                    mysql(concat!($mysql_head, " ", $mysql_clause $( , " ", $mysql_tail )?))
+                    sqlite(concat!($sqlite_head, " ", $sqlite_clause $( , " ", $sqlite_tail )?))
+                }
+                pub read [<$name BoundedImpl>] (
+                    result_limit: u64,
+                    $( $pname: $ptype, )*
+                    >list $oname: $otype
+                ) -> ($( $crate::_macro_internal::SqlColumn<$rtype> ),*) {
+                    mysql(concat!($mysql_head, " ", $mysql_clause $( , " ", $mysql_tail )?, " LIMIT {result_limit}"))
+                    sqlite(concat!($sqlite_head, " ", $sqlite_clause $( , " ", $sqlite_tail )?, " LIMIT {result_limit}"))
+                }
+                pub read [<$name UnfilteredImpl>] (
+                    $( $pname: $ptype, )*
+                ) -> ($( $crate::_macro_internal::SqlColumn<$rtype> ),*) {
+                    mysql(concat!($mysql_head $( , " ", $mysql_tail )?))
+                    sqlite(concat!($sqlite_head $( , " ", $sqlite_tail )?))
+                }
+                pub read [<$name UnfilteredBoundedImpl>] (
+                    result_limit: u64,
+                    $( $pname: $ptype, )*
+                ) -> ($( $crate::_macro_internal::SqlColumn<$rtype> ),*) {
+                    mysql(concat!($mysql_head $( , " ", $mysql_tail )?, " LIMIT {result_limit}"))
+                    sqlite(concat!($sqlite_head $( , " ", $sqlite_tail )?, " LIMIT {result_limit}"))
+                }
+            }
+
+            $crate::_explain_queries! {
+                pub read [<$name ExplainMysqlImpl>] (
+                    $( $pname: $ptype, )*
+                    >list $oname: $otype
+                ) -> (String) {
+                    mysql(concat!("EXPLAIN FORMAT=JSON ", $mysql_head, " ", $mysql_clause $( , " ", $mysql_tail )?))
+                    sqlite(concat!("EXPLAIN QUERY PLAN ", $sqlite_head, " ", $sqlite_clause $( , " ", $sqlite_tail )?))
+                }
+                pub read [<$name ExplainSqliteImpl>] (
+                    $( $pname: $ptype, )*
+                    >list $oname: $otype
+                ) -> (i64, i64, i64, String) {
+                    mysql(concat!("EXPLAIN FORMAT=JSON ", $mysql_head, " ", $mysql_clause $( , " ", $mysql_tail )?))
+                    sqlite(concat!("EXPLAIN QUERY PLAN ", $sqlite_head, " ", $sqlite_clause $( , " ", $sqlite_tail )?))
+                }
+                pub read [<$name UnfilteredExplainMysqlImpl>] (
+                    $( $pname: $ptype, )*
+                ) -> (String) {
+                    mysql(concat!("EXPLAIN FORMAT=JSON ", $mysql_head $( , " ", $mysql_tail )?))
+                    sqlite(concat!("EXPLAIN QUERY PLAN ", $sqlite_head $( , " ", $sqlite_tail )?))
+                }
+                pub read [<$name UnfilteredExplainSqliteImpl>] (
+                    $( $pname: $ptype, )*
+                ) -> (i64, i64, i64, String) {
+                    mysql(concat!("EXPLAIN FORMAT=JSON ", $mysql_head $( , " ", $mysql_tail )?))
+                    sqlite(concat!("EXPLAIN QUERY PLAN ", $sqlite_head $( , " ", $sqlite_tail )?))
+                }
+            }
+
+            #[allow(non_snake_case)]
+            $vi mod $name {
+                #[allow(unused_imports)]
+                use super::*;
+
+                #[allow(unused_imports)]
+                use $crate::_macro_internal::*;
+
+                $crate::_register_query! {
+                    kind: Read,
+                    mysql: concat!($mysql_head, " ", $mysql_clause $( , " ", $mysql_tail )?),
+                    sqlite: concat!($sqlite_head, " ", $sqlite_clause $( , " ", $sqlite_tail )?),
+                    params: [
+                        $( (stringify!($pname), stringify!($ptype)), )*
+                        (stringify!($oname), concat!("Option<[", stringify!($otype), "]>")),
+                    ],
+                }
+
+                // Not possible to retry query with transaction
+                #[allow(dead_code)]
+                pub async fn query_with_transaction(
+                    transaction: $crate::_macro_internal::Transaction,
+                    $( $pname: & $ptype, )*
+                    $oname: Option<& [ $otype ]>,
+                ) -> Result<($crate::_macro_internal::Transaction, Vec<($( $rtype, )*)>)> {
+                    let (transaction, rows) = match $oname {
+                        None => [<$name UnfilteredImpl>]::query_with_transaction(
+                            transaction, $( $pname, )*
+                        ).await?,
+                        Some($oname) if $oname.is_empty() => return Ok((transaction, Vec::new())),
+                        Some($oname) => [<$name Impl>]::query_with_transaction(
+                            transaction, $( $pname, )* $oname
+                        ).await?,
+                    };
+                    Ok((transaction, from_sql_rows(rows)?))
+                }
+
+                /// Runs the query without its optional clause if the optional
+                /// list is `None`.
+                #[allow(dead_code)]
+                pub async fn query(
+                    connection: &Connection,
+                    $( $pname: & $ptype, )*
+                    $oname: Option<& [ $otype ]>,
+                ) -> Result<Vec<($( $rtype, )*)>> {
+                    if matches!($oname, Some(list) if list.is_empty()) {
+                        return Ok(Vec::new());
+                    }
+                    static QUERY_POLICY: QueryPolicyCheck = QueryPolicyCheck::new(module_path!(), QueryKind::Read);
+                    if QUERY_POLICY.check()? == PolicyDecision::ReturnEmpty {
+                        return Ok(Vec::new());
+                    }
+                    #[allow(clippy::needless_update)]
+                    static QUERY_LIMITER: QueryLimiter = QueryLimiter::new(
+                        module_path!(),
+                        QueryLimits { $( $( $lkey: Some($lval), )* )? ..QueryLimits::UNLIMITED },
+                    );
+                    static APPEND_LIMIT: AppendLimit = AppendLimit::new(
+                        concat!($mysql_head, " ", $mysql_clause $( , " ", $mysql_tail )?),
+                        concat!($sqlite_head, " ", $sqlite_clause $( , " ", $sqlite_tail )?),
+                    );
+                    let limits = QUERY_LIMITER.result_limits();
+                    $crate::_slow_query! {
+                        static SLOW_QUERY: SlowQueryCheck = SlowQueryCheck::new(module_path!());
+                        let start = Instant::now();
+                    }
+                    let rows = query_with_retry_limited(
+                        &QUERY_LIMITER,
+                        QueryKind::Read,
+                        || async move {
+                            $crate::_mock_intercept! {
+                                if let Some(result) = mock_intercept(
+                                    stringify!($name),
+                                    || vec![$( (&MockParam($pname)).mock_format(), )* (&MockParam(&$oname)).mock_format()],
+                                ).await {
+                                    return result;
+                                }
+                            }
+                            let rows = match ($oname, APPEND_LIMIT.row_limit(&limits)) {
+                                (Some($oname), Some(row_limit)) => [<$name BoundedImpl>]::query(connection, &row_limit, $( $pname, )* $oname).await?,
+                                (Some($oname), None) => [<$name Impl>]::query(connection, $( $pname, )* $oname).await?,
+                                (None, Some(row_limit)) => [<$name UnfilteredBoundedImpl>]::query(connection, &row_limit, $( $pname, )*).await?,
+                                (None, None) => [<$name UnfilteredImpl>]::query(connection, $( $pname, )*).await?,
+                            };
+                            ResultBudget::new(module_path!(), limits).check(&rows)?;
+                            Ok(from_sql_rows(rows)?)
+                        },
+                    ).await?;
+                    $crate::_slow_query! {
+                        SLOW_QUERY.observe(
+                            start.elapsed(),
+                            || vec![$( (&MockParam($pname)).mock_format(), )* (&MockParam(&$oname)).mock_format()],
+                            || {
+                                let connection = connection.clone();
+                                $( let $pname = ToOwned::to_owned($pname); )*
+                                let $oname = $oname.map(ToOwned::to_owned);
+                                async move {
+                                    match ($oname.as_deref(), is_mysql(&connection)) {
+                                        (Some($oname), true) => Ok(mysql_plan([<$name ExplainMysqlImpl>]::query(&connection, $( Borrow::borrow(&$pname), )* $oname).await?)),
+                                        (Some($oname), false) => Ok(sqlite_plan([<$name ExplainSqliteImpl>]::query(&connection, $( Borrow::borrow(&$pname), )* $oname).await?)),
+                                        (None, true) => Ok(mysql_plan([<$name UnfilteredExplainMysqlImpl>]::query(&connection, $( Borrow::borrow(&$pname), )*).await?)),
+                                        (None, false) => Ok(sqlite_plan([<$name UnfilteredExplainSqliteImpl>]::query(&connection, $( Borrow::borrow(&$pname), )*).await?)),
+                                    }
+                                }
+                            },
+                        );
+                    }
+                    Ok(rows)
+                }
+
+                /// Same as `query`, on a connection of the read pool of
+                /// `pools`.
+                #[allow(dead_code)]
+                pub async fn query_from(
+                    pools: &SqlConnectionPools,
+                    $( $pname: & $ptype, )*
+                    $oname: Option<& [ $otype ]>,
+                ) -> Result<Vec<($( $rtype, )*)>> {
+                    let connection = pools.read_connection().await?;
+                    query(&connection, $( $pname, )* $oname).await
+                }
+            }
+
+            $crate::mononoke_queries! { $( $rest )* }
+        }
+    };
+
+    // Streaming read query with a single expression. Redirect to streaming read query with same expression for mysql and sqlite.
+    (
+        $( { $( $limit:tt )* } )?
+        $vi:vis streaming read $name:ident (
+            $( $pname:ident: $ptype:ty ),* $(,)*
+        ) -> ($( $rtype:ty ),* $(,)*) order by $oidx:tt: $otype:ty { $q:expr }
+        $( $rest:tt )*
+    ) => {
+        $crate::mononoke_queries! {
+            $( { $( $limit )* } )?
+            $vi streaming read $name (
+                $( $pname: $ptype, )*
+            ) -> ($( $rtype ),*) order by $oidx: $otype { mysql($q) sqlite($q) }
+            $( $rest )*
+        }
+    };
+
+    // Full streaming read query. Call `sql::queries!` with extra `after` and `limit` parameters, and
+    // drive it with keyset pagination.
+    (
+        $( { $( $lkey:ident = $lval:expr ),* $(,)? } )?
+        $vi:vis streaming read $name:ident (
+            $( $pname:ident: $ptype:ty ),* $(,)*
+        ) -> ($( $rtype:ty ),* $(,)*) order by $oidx:tt: $otype:ty { mysql($mysql_q:expr) sqlite($sqlite_q:expr) }
+        $( $rest:tt )*
+    ) => {
+        $crate::_macro_internal::paste::item! {
+            $crate::_macro_internal::queries! {
+                pub read [<$name Impl>] (
+                    after: $otype,
+                    limit: u64,
+                    $( $pname: $ptype, )*
+                ) -> ($( $crate::_macro_internal::SqlColumn<$rtype> ),*) { mysql($mysql_q) sqlite($sqlite_q) }
+            }
+
+            $crate::_explain_queries! {
+                pub read [<$name ExplainMysqlImpl>] (
+                    after: $otype,
+                    limit: u64,
+                    $( $pname: $ptype, )*
+                ) -> (String) {
+                    mysql(concat!("EXPLAIN FORMAT=JSON ", $mysql_q))
+                    sqlite(concat!("EXPLAIN QUERY PLAN ", $sqlite_q))
+                }
+                pub read [<$name ExplainSqliteImpl>] (
+                    after: $otype,
+                    limit: u64,
+                    $( $pname: $ptype, )*
+                ) -> (i64, i64, i64, String) {
+                    mysql(concat!("EXPLAIN FORMAT=JSON ", $mysql_q))
+                    sqlite(concat!("EXPLAIN QUERY PLAN ", $sqlite_q))
+                }
+            }
+
+            #[allow(non_snake_case)]
+            $vi mod $name {
+                #[allow(unused_imports)]
+                use super::*;
+
+                #[allow(unused_imports)]
+                use $crate::_macro_internal::*;
+
+                $crate::_register_query! {
+                    kind: Read,
+                    mysql: $mysql_q,
+                    sqlite: $sqlite_q,
+                    params: [
+                        ("after", stringify!($otype)),
+                        ("limit", "u64"),
+                        $( (stringify!($pname), stringify!($ptype)), )*
+                    ],
+                }
+
+                // Not possible to retry query with transaction
+                #[allow(dead_code)]
+                pub async fn query_with_transaction(
+                    transaction: $crate::_macro_internal::Transaction,
+                    after: & $otype,
+                    limit: &u64,
+                    $( $pname: & $ptype, )*
+                ) -> Result<($crate::_macro_internal::Transaction, Vec<($( $rtype, )*)>)> {
+                    let (transaction, rows) = [<$name Impl>]::query_with_transaction(
+                        transaction, after, limit, $( $pname, )*
+                    ).await?;
+                    Ok((transaction, from_sql_rows(rows)?))
+                }
+
+                #[allow(clippy::needless_update)]
+                static QUERY_LIMITER: QueryLimiter = QueryLimiter::new(
+                    module_path!(),
+                    QueryLimits { $( $( $lkey: Some($lval), )* )? ..QueryLimits::UNLIMITED },
+                );
+
+                /// Fetch a single chunk of up to `limit` rows after `after`.
+                #[allow(dead_code)]
+                pub async fn query(
+                    connection: &Connection,
+                    after: & $otype,
+                    limit: &u64,
+                    $( $pname: & $ptype, )*
+                ) -> Result<Vec<($( $rtype, )*)>> {
+                    let budget = ResultBudget::new(module_path!(), QUERY_LIMITER.result_limits());
+                    query_chunk(connection, after, limit, $( $pname, )* &budget).await
+                }
+
+                /// Same as `query`, counting the rows against `budget`.
+                async fn query_chunk(
+                    connection: &Connection,
+                    after: & $otype,
+                    limit: &u64,
+                    $( $pname: & $ptype, )*
+                    budget: &ResultBudget,
+                ) -> Result<Vec<($( $rtype, )*)>> {
+                    static QUERY_POLICY: QueryPolicyCheck = QueryPolicyCheck::new(module_path!(), QueryKind::Read);
+                    if QUERY_POLICY.check()? == PolicyDecision::ReturnEmpty {
+                        return Ok(Vec::new());
+                    }
+                    // Fetch at most one row above the limit of the stream.
+                    let limit = &budget.chunk_limit(*limit);
+                    $crate::_slow_query! {
+                        static SLOW_QUERY: SlowQueryCheck = SlowQueryCheck::new(module_path!());
+                        let start = Instant::now();
+                    }
+                    let (rows, usage) = query_with_retry_limited(
+                        &QUERY_LIMITER,
+                        QueryKind::Read,
+                        || async move {
+                            $crate::_mock_intercept! {
+                                if let Some(result) = mock_intercept(
+                                    stringify!($name),
+                                    || vec![(&MockParam(after)).mock_format(), (&MockParam(limit)).mock_format(), $( (&MockParam($pname)).mock_format(), )*],
+                                ).await {
+                                    return result.map(|rows| (rows, Default::default()));
+                                }
+                            }
+                            let rows = [<$name Impl>]::query(connection, after, limit, $( $pname, )*).await?;
+                            let usage = budget.check(&rows)?;
+                            Ok((from_sql_rows(rows)?, usage))
+                        },
+                    ).await?;
+                    budget.spend(usage);
+                    $crate::_slow_query! {
+                        SLOW_QUERY.observe(
+                            start.elapsed(),
+                            || vec![(&MockParam(after)).mock_format(), (&MockParam(limit)).mock_format(), $( (&MockParam($pname)).mock_format(), )*],
+                            || {
+                                let connection = connection.clone();
+                                let after = ToOwned::to_owned(after);
+                                let limit = *limit;
+                                $( let $pname = ToOwned::to_owned($pname); )*
+                                async move {
+                                    if is_mysql(&connection) {
+                                        Ok(mysql_plan([<$name ExplainMysqlImpl>]::query(&connection, Borrow::borrow(&after), &limit, $( Borrow::borrow(&$pname), )*).await?))
+                                    } else {
+                                        Ok(sqlite_plan([<$name ExplainSqliteImpl>]::query(&connection, Borrow::borrow(&after), &limit, $( Borrow::borrow(&$pname), )*).await?))
+                                    }
+                                }
+                            },
+                        );
+                    }
+                    Ok(rows)
+                }
+
+                /// Stream all rows after `after`, fetching `chunk_size` rows at a time.
+                /// The result limits of the query apply to the whole stream.
+                #[allow(dead_code)]
+                pub fn query_stream<'a>(
+                    connection: &'a Connection,
+                    chunk_size: u64,
+                    after: $otype,
+                    $( $pname: &'a $ptype, )*
+                ) -> BoxStream<'a, Result<($( $rtype, )*)>> {
+                    let budget = ::std::sync::Arc::new(
+                        ResultBudget::new(module_path!(), QUERY_LIMITER.result_limits()),
+                    );
+                    query_stream_chunked(
+                        after,
+                        chunk_size,
+                        move |after: $otype| {
+                            let budget = budget.clone();
+                            async move {
+                                query_chunk(connection, &after, &chunk_size, $( $pname, )* &budget).await
+                            }
+                        },
+                        |row: &($( $rtype, )*)| row.$oidx.clone(),
+                    )
                 }
             }
 
@@ -179,6 +960,7 @@ macro_rules! mononoke_queries {
 
     // Write query with a single expression. Redirect to write query with same expression for mysql and sqlite.
     (
+        $( { $( $limit:tt )* } )?
         $vi:vis write $name:ident (
             values: ($( $vname:ident: $vtype:ty ),* $(,)*)
             $( , $pname:ident: $ptype:ty )* $(,)*
@@ -186,6 +968,7 @@ macro_rules! mononoke_queries {
         $( $rest:tt )*
     ) => {
         $crate::mononoke_queries! {
+            $( { $( $limit )* } )?
             $vi write $name (
                 values: ( $( $vname: $vtype ),* )
                 $( , $pname: $ptype )*
@@ -196,6 +979,7 @@ macro_rules! mononoke_queries {
 
     // Full write query with a list of values. Call `sql::queries!` and re-export stuff, wrapped in retries, on a new module.
     (
+        $( { $( $lkey:ident = $lval:expr ),* $(,)? } )?
         $vi:vis write $name:ident (
             values: ($( $vname:ident: $vtype:ty ),* $(,)*)
             $( , $pname:ident: $ptype:ty )* $(,)*
@@ -218,19 +1002,84 @@ macro_rules! mononoke_queries {
                 #[allow(unused_imports)]
                 use $crate::_macro_internal::*;
 
+                $crate::_register_query! {
+                    kind: Write,
+                    mysql: $mysql_q,
+                    sqlite: $sqlite_q,
+                    params: [
+                        ("values", stringify!(($( $vname: $vtype ),*))),
+                        $( (stringify!($pname), stringify!($ptype)), )*
+                    ],
+                }
+
                 // Not possible to retry query with transaction
-                #[allow(unused_imports)]
-                pub use [<$name Impl>]::query_with_transaction;
+                #[allow(dead_code)]
+                pub async fn query_with_transaction(
+                    transaction: $crate::_macro_internal::Transaction,
+                    values: &[($( & $vtype, )*)],
+                    $( $pname: & $ptype ),*
+                ) -> Result<($crate::_macro_internal::Transaction, WriteResult)> {
+                    if values.is_empty() {
+                        return Ok((transaction, WriteResult::new(None, 0)));
+                    }
+                    [<$name Impl>]::query_with_transaction(transaction, values $( , $pname )* ).await
+                }
 
                 #[allow(dead_code)]
                 pub async fn query(
                     connection: &Connection,
                     values: &[($( & $vtype, )*)],
                     $( $pname: & $ptype ),*
-                ) -> Result<WriteResult> {
-                    query_with_retry_no_cache(
-                        || [<$name Impl>]::query(connection, values $( , $pname )* ),
-                    ).await
+                ) -> Result<TypedWriteResult> {
+                    static WRITE_KINDS: WriteKinds = WriteKinds::new(stringify!($qtype), $mysql_q, $sqlite_q);
+                    if values.is_empty() {
+                        return Ok(TypedWriteResult::new(
+                            WriteResult::new(None, 0),
+                            connection,
+                            &WRITE_KINDS,
+                            0,
+                        ));
+                    }
+                    static QUERY_POLICY: QueryPolicyCheck = QueryPolicyCheck::new(module_path!(), QueryKind::Write);
+                    QUERY_POLICY.check()?;
+                    #[allow(clippy::needless_update)]
+                    static QUERY_LIMITER: QueryLimiter = QueryLimiter::new(
+                        module_path!(),
+                        QueryLimits { $( $( $lkey: Some($lval), )* )? ..QueryLimits::UNLIMITED },
+                    );
+                    let result = query_with_retry_limited(
+                        &QUERY_LIMITER,
+                        QueryKind::Write,
+                        || async move {
+                            $crate::_mock_intercept! {
+                                if let Some(result) = mock_intercept(
+                                    stringify!($name),
+                                    || vec![(&MockParam(values)).mock_format(), $( (&MockParam($pname)).mock_format(), )*],
+                                ).await {
+                                    return result;
+                                }
+                            }
+                            [<$name Impl>]::query(connection, values $( , $pname )* ).await
+                        },
+                    ).await?;
+                    Ok(TypedWriteResult::new(
+                        result,
+                        connection,
+                        &WRITE_KINDS,
+                        values.len(),
+                    ))
+                }
+
+                /// Same as `query`, on a connection of the write pool of
+                /// `pools`.
+                #[allow(dead_code)]
+                pub async fn query_from(
+                    pools: &SqlConnectionPools,
+                    values: &[($( & $vtype, )*)],
+                    $( $pname: & $ptype ),*
+                ) -> Result<TypedWriteResult> {
+                    let connection = pools.write_connection().await?;
+                    query(&connection, values $( , $pname )* ).await
                 }
             }
 
@@ -240,6 +1089,7 @@ macro_rules! mononoke_queries {
 
     // Write query with a single expression. Redirect to write query with same expression for mysql and sqlite.
     (
+        $( { $( $limit:tt )* } )?
         $vi:vis write $name:ident (
             $( $pname:ident: $ptype:ty ),* $(,)*
             $( >list $lname:ident: $ltype:ty )*
@@ -247,6 +1097,7 @@ macro_rules! mononoke_queries {
         $( $rest:tt )*
     ) => {
         $crate::mononoke_queries! {
+            $( { $( $limit )* } )?
             $vi write $name (
                 $( $pname: $ptype, )*
                 $( >list $lname: $ltype )*
@@ -257,6 +1108,7 @@ macro_rules! mononoke_queries {
 
     // Full write query without a list of values. Call `sql::queries!` and re-export stuff, wrapped in retries, on a new module.
     (
+        $( { $( $lkey:ident = $lval:expr ),* $(,)? } )?
         $vi:vis write $name:ident (
             $( $pname:ident: $ptype:ty ),* $(,)*
             $( >list $lname:ident: $ltype:ty )*
@@ -279,19 +1131,88 @@ macro_rules! mononoke_queries {
                 #[allow(unused_imports)]
                 use $crate::_macro_internal::*;
 
+                $crate::_register_query! {
+                    kind: Write,
+                    mysql: $mysql_q,
+                    sqlite: $sqlite_q,
+                    params: [
+                        $( (stringify!($pname), stringify!($ptype)), )*
+                        $( (stringify!($lname), concat!("[", stringify!($ltype), "]")), )*
+                    ],
+                }
+
                 // Not possible to retry query with transaction
-                #[allow(unused_imports)]
-                pub use [<$name Impl>]::query_with_transaction;
+                #[allow(dead_code)]
+                pub async fn query_with_transaction(
+                    transaction: $crate::_macro_internal::Transaction,
+                    $( $pname: & $ptype, )*
+                    $( $lname: & [ $ltype ], )*
+                ) -> Result<($crate::_macro_internal::Transaction, WriteResult)> {
+                    $(
+                        if $lname.is_empty() {
+                            return Ok((transaction, WriteResult::new(None, 0)));
+                        }
+                    )*
+                    [<$name Impl>]::query_with_transaction(transaction, $( $pname, )* $( $lname, )*).await
+                }
 
                 #[allow(dead_code)]
                 pub async fn query(
                     connection: &Connection,
                     $( $pname: & $ptype, )*
                     $( $lname: & [ $ltype ], )*
-                ) -> Result<WriteResult> {
-                    query_with_retry_no_cache(
-                        || [<$name Impl>]::query(connection, $( $pname, )* $( $lname, )*),
-                    ).await
+                ) -> Result<TypedWriteResult> {
+                    static WRITE_KINDS: WriteKinds = WriteKinds::new(stringify!($qtype), $mysql_q, $sqlite_q);
+                    $(
+                        if $lname.is_empty() {
+                            return Ok(TypedWriteResult::new(
+                                WriteResult::new(None, 0),
+                                connection,
+                                &WRITE_KINDS,
+                                0,
+                            ));
+                        }
+                    )*
+                    static QUERY_POLICY: QueryPolicyCheck = QueryPolicyCheck::new(module_path!(), QueryKind::Write);
+                    QUERY_POLICY.check()?;
+                    #[allow(clippy::needless_update)]
+                    static QUERY_LIMITER: QueryLimiter = QueryLimiter::new(
+                        module_path!(),
+                        QueryLimits { $( $( $lkey: Some($lval), )* )? ..QueryLimits::UNLIMITED },
+                    );
+                    let result = query_with_retry_limited(
+                        &QUERY_LIMITER,
+                        QueryKind::Write,
+                        || async move {
+                            $crate::_mock_intercept! {
+                                if let Some(result) = mock_intercept(
+                                    stringify!($name),
+                                    || vec![$( (&MockParam($pname)).mock_format(), )* $( (&MockParam($lname)).mock_format(), )*],
+                                ).await {
+                                    return result;
+                                }
+                            }
+                            [<$name Impl>]::query(connection, $( $pname, )* $( $lname, )*).await
+                        },
+                    ).await?;
+                    Ok(TypedWriteResult::new(
+                        result,
+                        connection,
+                        &WRITE_KINDS,
+                        1,
+                    ))
+                }
+
+                /// Same as `query`, on a connection of the write pool of
+                /// `pools`.
+                #[allow(dead_code)]
+                pub async fn query_from(
+                    pools: &SqlConnectionPools,
+                    $( $pname: & $ptype, )*
+                    $( $lname: & [ $ltype ], )*
+                ) -> Result<TypedWriteResult> {
+                    let connection = pools.write_connection().await?;
+                    query(&connection, $( $pname, )* $( $lname, )*).await
                 }
             }
 
@@ -301,35 +1222,6 @@ macro_rules! mononoke_queries {
 
 }
 
-#[cfg(fbcode_build)]
-/// See https://fburl.com/sv/uk8w71td for error descriptions
-fn retryable_mysql_errno(errno: u32) -> bool {
-    match errno {
-        // Deadlock error, advice is restarting transaction, so it's retryable
-        1213 => true,
-        // Admission control errors
-        // Safe to retry on writes as well as the query didn't even start
-        1914..=1916 => true,
-        _ => false,
-    }
-}
-
-#[cfg(fbcode_build)]
-fn should_retry_mysql_query(err: &anyhow::Error) -> bool {
-    use mysql_client::MysqlError;
-    use MysqlError::*;
-    match err.downcast_ref::<MysqlError>() {
-        Some(ConnectionOperationError { mysql_errno, .. })
-        | Some(QueryResultError { mysql_errno, .. }) => retryable_mysql_errno(*mysql_errno),
-        _ => false,
-    }
-}
-
-#[cfg(not(fbcode_build))]
-fn should_retry_mysql_query(_err: &anyhow::Error) -> bool {
-    false
-}
-
 type Key = u128;
 
 pub struct CacheData<'a> {
@@ -408,33 +1300,34 @@ where
 }
 
 pub async fn query_with_retry_no_cache<T, Fut>(
+    kind: QueryKind,
     do_query: impl Fn() -> Fut + Send + Sync,
 ) -> Result<T>
 where
     T: Send + 'static,
     Fut: Future<Output = Result<T>>,
 {
-    if tunables().disable_sql_auto_retries().unwrap_or_default() {
-        return do_query().await;
-    }
-    Ok(retry(
-        None,
-        |_| do_query(),
-        should_retry_mysql_query,
-        // See https://fburl.com/7dmedu1u for backoff reasoning
-        RetryLogic::ExponentialWithJitter {
-            base: Duration::from_secs(10),
-            factor: 1.2,
-            jitter: Duration::from_secs(5),
-        },
-        RETRY_ATTEMPTS,
-    )
-    .await?
-    .0)
+    retry_sql_operation(&DEFAULT_SQL_RETRY_POLICY, kind, do_query).await
+}
+
+/// Like `query_with_retry_no_cache`, but waits for a permit from `limiter`
+/// first, and holds it across retries.
+pub async fn query_with_retry_limited<T, Fut>(
+    limiter: &QueryLimiter,
+    kind: QueryKind,
+    do_query: impl Fn() -> Fut + Send + Sync,
+) -> Result<T>
+where
+    T: Send + 'static,
+    Fut: Future<Output = Result<T>>,
+{
+    let _permit = limiter.acquire().await?;
+    query_with_retry_no_cache(kind, do_query).await
 }
 
 pub async fn query_with_retry<T, Fut>(
     cache_data: CacheData<'_>,
+    limiter: &QueryLimiter,
     do_query: impl Fn() -> Fut + Send + Sync,
 ) -> Result<T>
 where
@@ -442,9 +1335,10 @@ where
     Fut: Future<Output = Result<T>> + Send,
 {
     if tunables().disable_sql_auto_cache().unwrap_or_default() {
-        return query_with_retry_no_cache(&do_query).await;
+        return query_with_retry_limited(limiter, QueryKind::Read, &do_query).await;
     }
-    let fetch = || query_with_retry_no_cache(&do_query);
+    // Cache hits don't need a permit.
+    let fetch = || query_with_retry_limited(limiter, QueryKind::Read, &do_query);
     let key = cache_data.key;
     if let Some(config) = cache_data.config.as_ref() {
         let store = QueryCacheStore {
@@ -465,6 +1359,86 @@ where
     }
 }
 
+/// Yields the rows of a keyset-paginated read query, one chunk at a time.
+///
+/// `fetch_chunk` is called with the ordering column value of the last row
+/// fetched so far (`start_after` for the first chunk), as extracted by
+/// `last_key`. The stream ends after the first chunk with fewer than
+/// `chunk_size` rows. The next chunk is only fetched once the previous one
+/// has been consumed.
+pub fn query_stream_chunked<'a, K, T, F, Fut>(
+    start_after: K,
+    chunk_size: u64,
+    fetch_chunk: F,
+    last_key: fn(&T) -> K,
+) -> BoxStream<'a, Result<T>>
+where
+    K: Send + 'a,
+    T: Send + 'a,
+    F: FnMut(K) -> Fut + Send + 'a,
+    Fut: Future<Output = Result<Vec<T>>> + Send + 'a,
+{
+    stream::try_unfold(
+        (Some(start_after), fetch_chunk),
+        move |(after, mut fetch_chunk)| async move {
+            let after = match after {
+                Some(after) => after,
+                None => return Ok::<_, anyhow::Error>(None),
+            };
+            let rows = fetch_chunk(after).await?;
+            let next = if (rows.len() as u64) < chunk_size {
+                None
+            } else {
+                rows.last().map(last_key)
+            };
+            #[cfg(test)]
+            buffered_rows::add(rows.len());
+            Ok(Some((rows, (next, fetch_chunk))))
+        },
+    )
+    .map_ok(|rows| {
+        stream::iter(rows.into_iter().map(|row| {
+            #[cfg(test)]
+            buffered_rows::remove(1);
+            Ok(row)
+        }))
+    })
+    .try_flatten()
+    .boxed()
+}
+
+/// Counts rows fetched by `query_stream_chunked` but not yet yielded, so
+/// tests can check buffering stays bounded.
+#[cfg(test)]
+mod buffered_rows {
+    use std::cell::Cell;
+
+    thread_local! {
+        static BUFFERED: Cell<usize> = Cell::new(0);
+        static PEAK: Cell<usize> = Cell::new(0);
+    }
+
+    pub(crate) fn add(count: usize) {
+        BUFFERED.with(|buffered| {
+            buffered.set(buffered.get() + count);
+            PEAK.with(|peak| peak.set(peak.get().max(buffered.get())));
+        });
+    }
+
+    pub(crate) fn remove(count: usize) {
+        BUFFERED.with(|buffered| buffered.set(buffered.get() - count));
+    }
+
+    pub(crate) fn reset() {
+        BUFFERED.with(|buffered| buffered.set(0));
+        PEAK.with(|peak| peak.set(0));
+    }
+
+    pub(crate) fn peak() -> usize {
+        PEAK.with(|peak| peak.get())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     mononoke_queries! {
@@ -485,8 +1459,113 @@ mod tests {
             mysql("DELETE FROM my_table where id = {id}")
             sqlite("DELETE FROM mytable2 where id = {id}")
         }
+        streaming read TestQuery5(max_id: u64) -> (u64, String) order by 0: u64 {
+            "SELECT id, value FROM stream_rows WHERE id > {after} AND id <= {max_id} ORDER BY id LIMIT {limit}"
+        }
+        write InsertWriteRow(values: (id: u64, value: String)) {
+            none,
+            "INSERT INTO write_rows (id, value) VALUES {values}"
+        }
+        write InsertWriteRowOrIgnore(values: (id: u64, value: String)) {
+            insert_or_ignore,
+            "{insert_or_ignore} INTO write_rows (id, value) VALUES {values}"
+        }
+        write ReplaceWriteRow(values: (id: u64, value: String)) {
+            none,
+            mysql("REPLACE INTO write_rows (id, value) VALUES {values}")
+            sqlite("INSERT OR REPLACE INTO write_rows (id, value) VALUES {values}")
+        }
+        write UpdateWriteRow(id: u64, value: String) {
+            none,
+            "UPDATE write_rows SET value = {value} WHERE id = {id}"
+        }
+
+        cacheable read PolicyRead() -> (u64) {
+            "SELECT 44"
+        }
+        write PolicyWrite(id: u64) {
+            none,
+            "DELETE FROM my_table WHERE id = {id}"
+        }
+
+        { max_concurrency = 4 }
+        read LimitedQuery(id: u64) -> (u64) {
+            "SELECT {id}"
+        }
+        {
+            max_concurrency = 1,
+            acquire_timeout = std::time::Duration::from_millis(10),
+        }
+        write SaturatedQuery(id: u64) {
+            none,
+            "DELETE FROM my_table WHERE id = {id}"
+        }
+
+        { max_rows = 1000 }
+        read SelectBoundedRows() -> (u64, String) {
+            "SELECT id, value FROM large_rows"
+        }
+        { max_rows = 1000 }
+        streaming read StreamBoundedRows() -> (u64, String) order by 0: u64 {
+            "SELECT id, value FROM large_rows WHERE id > {after} ORDER BY id LIMIT {limit}"
+        }
+        { unlimited = true }
+        read SelectUnlimitedRows() -> (u64, String) {
+            "SELECT id, value FROM large_rows"
+        }
+        read SelectLargeRows() -> (u64, String) {
+            "SELECT id, value FROM large_rows"
+        }
+
+        read ExplainedQuery(id: u64) -> (String) {
+            "SELECT value FROM explain_rows WHERE id = {id}"
+        }
+
+        read SelectMarkers() -> (String) {
+            "SELECT name FROM markers ORDER BY name"
+        }
+        write InsertMarker(values: (name: String)) {
+            none,
+            "INSERT INTO markers (name) VALUES {values}"
+        }
+
+        write InsertTyped(values: (repo: RepoNumber, hash: ContentHash)) {
+            none,
+            "INSERT INTO typed_rows (repo, hash) VALUES {values}"
+        }
+        read SelectTyped(repo: RepoNumber, >list hashes: ContentHash) -> (RepoNumber, ContentHash) {
+            "SELECT repo, hash FROM typed_rows WHERE repo = {repo} AND hash IN {hashes} ORDER BY hash"
+        }
+        read SelectTypedWrong(repo: RepoNumber) -> (ContentHash) {
+            "SELECT repo FROM typed_rows WHERE repo = {repo}"
+        }
+
+        read SelectListRows(>list ids: u64) -> (u64, String) {
+            "SELECT id, value FROM list_rows WHERE id IN {ids} ORDER BY id"
+        }
+        read SelectOptListRows(min_id: u64, >optlist ids: u64) -> (u64, String) {
+            "SELECT id, value FROM list_rows WHERE id >= {min_id}"
+            optional("AND id IN {ids}")
+            "ORDER BY id"
+        }
+        read SelectOptListValues(>optlist ids: u64) -> (String) {
+            mysql("SELECT value FROM list_rows WHERE 1" optional("AND id IN {ids}") "ORDER BY id")
+            sqlite("SELECT value FROM list_rows WHERE 1" optional("AND id IN {ids}"))
+        }
+        write DeleteListRows(>list ids: u64) {
+            none,
+            "DELETE FROM list_rows WHERE id IN {ids}"
+        }
     }
 
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    struct RepoNumber(i32);
+    crate::sql_newtype!(RepoNumber(i32));
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    struct ContentHash([u8; 32]);
+    crate::sql_newtype!(ContentHash([u8; 32]));
+
     #[allow(
         dead_code,
         unreachable_code,
@@ -505,6 +1584,904 @@ mod tests {
         TestQuery3::query(connection, &[(&12,)]).await?;
         TestQuery3::query_with_transaction(todo!(), &[(&12,)]).await?;
         TestQuery4::query(connection, &"hello").await?;
+        TestQuery5::query(connection, &0, &10, &100).await?;
+        TestQuery5::query_with_transaction(todo!(), &0, &10, &100).await?;
+        SelectOptListRows::query(connection, &0, None).await?;
+        SelectOptListRows::query_with_transaction(todo!(), &0, Some(&[1, 2])).await?;
+        DeleteListRows::query_with_transaction(todo!(), &[1, 2]).await?;
+
+        let pools: &crate::SqlConnectionPools = todo!();
+        TestQuery::query_from(pools, todo!(), todo!()).await?;
+        TestQuery2::query_from(config, pools).await?;
+        TestQuery3::query_from(pools, &[(&12,)]).await?;
+        TestQuery4::query_from(pools, &"hello").await?;
+        SelectOptListRows::query_from(pools, &0, None).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query_stream() -> anyhow::Result<()> {
+        use futures::TryStreamExt;
+        use sql::Connection;
+
+        use crate::open_sqlite_in_memory;
+
+        let con = open_sqlite_in_memory()?;
+        con.execute_batch("CREATE TABLE stream_rows (id INTEGER PRIMARY KEY, value TEXT NOT NULL);")?;
+        let values = (1..=10_000)
+            .map(|id| format!("({}, 'value{}')", id, id))
+            .collect::<Vec<_>>()
+            .join(", ");
+        con.execute_batch(&format!("INSERT INTO stream_rows (id, value) VALUES {};", values))?;
+        let connection = Connection::with_sqlite(con);
+
+        super::buffered_rows::reset();
+        let rows: Vec<_> = TestQuery5::query_stream(&connection, 64, 0, &10_000)
+            .try_collect()
+            .await?;
+        assert_eq!(rows.len(), 10_000);
+        for (i, (id, value)) in rows.iter().enumerate() {
+            assert_eq!(*id, i as u64 + 1);
+            assert_eq!(*value, format!("value{}", id));
+        }
+        assert!(super::buffered_rows::peak() <= 64);
+
+        // Exclusive start and the query's own bounds are respected.
+        let rows: Vec<_> = TestQuery5::query_stream(&connection, 64, 9_990, &9_995)
+            .try_collect()
+            .await?;
+        let ids: Vec<_> = rows.into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![9_991, 9_992, 9_993, 9_994, 9_995]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_outcome() -> anyhow::Result<()> {
+        use sql::Connection;
+
+        use crate::open_sqlite_in_memory;
+        use crate::WriteOutcome;
+
+        let con = open_sqlite_in_memory()?;
+        con.execute_batch(
+            "CREATE TABLE write_rows (id INTEGER PRIMARY KEY, value TEXT NOT NULL);",
+        )?;
+        let connection = Connection::with_sqlite(con);
+        let a = "a".to_string();
+        let b = "b".to_string();
+
+        let result = InsertWriteRow::query(&connection, &[(&1, &a), (&2, &a)]).await?;
+        assert_eq!(result.outcome(), WriteOutcome::Inserted);
+        assert_eq!(result.affected_rows(), 2);
+        assert_eq!(result.inserted_id(), Some(2));
+
+        // Duplicates are ignored.
+        let result = InsertWriteRowOrIgnore::query(&connection, &[(&1, &b)]).await?;
+        assert_eq!(result.outcome(), WriteOutcome::NoOp);
+        assert_eq!(result.inserted_id(), None);
+        let result = InsertWriteRowOrIgnore::query(&connection, &[(&1, &b), (&3, &b)]).await?;
+        assert_eq!(result.outcome(), WriteOutcome::Inserted);
+        assert_eq!(result.inserted_id(), Some(3));
+
+        let result = ReplaceWriteRow::query(&connection, &[(&2, &b)]).await?;
+        assert_eq!(result.outcome(), WriteOutcome::Replaced);
+        assert_eq!(result.affected_rows(), 1);
+
+        let result = UpdateWriteRow::query(&connection, &1, &b).await?;
+        assert_eq!(result.outcome(), WriteOutcome::Updated);
+        assert_eq!(result.inserted_id(), None);
+        let result = UpdateWriteRow::query(&connection, &4, &b).await?;
+        assert_eq!(result.outcome(), WriteOutcome::NoOp);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mock_retry_classification() -> anyhow::Result<()> {
+        use crate::mock::MockConnection;
+        use crate::mock::MockMysqlError;
+
+        let mock = MockConnection::new()?;
+        let _guard = mock.install();
+
+        // Deadlocks are retried.
+        mock.push_mysql_error("TestQuery", 1213);
+        mock.push_rows("TestQuery", vec![(44u64, None::<i32>, "a".to_string(), 5i64)]);
+        let rows = TestQuery::query(mock.connection(), &"a".to_string(), &5).await?;
+        assert_eq!(rows, vec![(44, None, "a".to_string(), 5)]);
+        let invocations = mock.invocations();
+        assert_eq!(invocations.len(), 2);
+        assert_eq!(invocations[1].query, "TestQuery");
+        assert_eq!(invocations[1].params, vec!["\"a\"", "5"]);
+
+        // Lock wait timeouts are retried for reads, but not for writes.
+        mock.push_mysql_error("TestQuery", 1205);
+        mock.push_rows("TestQuery", vec![(44u64, None::<i32>, "b".to_string(), 6i64)]);
+        TestQuery::query(mock.connection(), &"b".to_string(), &6).await?;
+        assert_eq!(mock.invocations().len(), 4);
+        mock.push_mysql_error("TestQuery4", 1205);
+        let err = TestQuery4::query(mock.connection(), &"b")
+            .await
+            .unwrap_err();
+        assert_matches::assert_matches!(
+            err.downcast_ref::<MockMysqlError>(),
+            Some(MockMysqlError { errno: 1205 })
+        );
+        assert_eq!(mock.invocations().len(), 5);
+
+        // Retries are bounded.
+        mock.push_mysql_error("TestQuery", 1914);
+        mock.push_mysql_error("TestQuery", 1914);
+        mock.push_rows("TestQuery", vec![(44u64, None::<i32>, "c".to_string(), 7i64)]);
+        assert!(
+            TestQuery::query(mock.connection(), &"c".to_string(), &7)
+                .await
+                .is_err()
+        );
+        assert_eq!(mock.invocations().len(), 7);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_sql_operation_matches_queries() -> anyhow::Result<()> {
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::atomic::Ordering;
+
+        use crate::mock::MockConnection;
+        use crate::mock::MockMysqlError;
+        use crate::query_policy::QueryKind;
+        use crate::retry_sql_operation;
+        use crate::DEFAULT_SQL_RETRY_POLICY;
+
+        let mock = MockConnection::new()?;
+        let _guard = mock.install();
+
+        for errnos in [&[][..], &[1213], &[1205], &[2013], &[1062], &[1914, 1914]] {
+            let before = mock.invocations().len();
+            for errno in errnos {
+                mock.push_mysql_error("TestQuery", *errno);
+            }
+            mock.push_rows("TestQuery", vec![(44u64, None::<i32>, "a".to_string(), 5i64)]);
+            let macro_ok = TestQuery::query(mock.connection(), &"a".to_string(), &5)
+                .await
+                .is_ok();
+            let macro_attempts = mock.invocations().len() - before;
+            // Consume what a failed query left behind.
+            if !macro_ok {
+                while TestQuery::query(mock.connection(), &"a".to_string(), &5)
+                    .await
+                    .is_err()
+                {}
+            }
+
+            let attempts = AtomicUsize::new(0);
+            let operation = || async {
+                let attempt = attempts.fetch_add(1, Ordering::Relaxed);
+                match errnos.get(attempt) {
+                    Some(errno) => Err(MockMysqlError { errno: *errno }.into()),
+                    None => Ok(()),
+                }
+            };
+            let function_ok =
+                retry_sql_operation(&DEFAULT_SQL_RETRY_POLICY, QueryKind::Read, operation)
+                    .await
+                    .is_ok();
+
+            assert_eq!(macro_ok, function_ok, "{:?}", errnos);
+            assert_eq!(
+                macro_attempts,
+                attempts.load(Ordering::Relaxed),
+                "{:?}",
+                errnos
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(not(fbcode_build))]
+    #[tokio::test(start_paused = true)]
+    async fn test_mock_retry_mysql_async_error() -> anyhow::Result<()> {
+        use sql::mysql_async::Error;
+        use sql::mysql_async::ServerError;
+
+        use crate::mock::MockConnection;
+
+        let mock = MockConnection::new()?;
+        let _guard = mock.install();
+        let deadlock = || {
+            Error::Server(ServerError {
+                code: 1213,
+                message: "Deadlock found when trying to get lock".to_string(),
+                state: "40001".to_string(),
+            })
+        };
+
+        mock.push_error("TestQuery", deadlock().into());
+        mock.push_rows("TestQuery", vec![(44u64, None::<i32>, "a".to_string(), 5i64)]);
+        TestQuery::query(mock.connection(), &"a".to_string(), &5).await?;
+        assert_eq!(mock.invocations().len(), 2);
+
+        mock.push_error("TestQuery4", deadlock().into());
+        mock.push_write("TestQuery4", sql::WriteResult::new(None, 1));
+        TestQuery4::query(mock.connection(), &"a").await?;
+        assert_eq!(mock.invocations().len(), 4);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mock_query_variants() -> anyhow::Result<()> {
+        use sql::WriteResult;
+        use sql_query_config::SqlQueryConfig;
+
+        use crate::mock::MockConnection;
+
+        let mock = MockConnection::new()?;
+        let _guard = mock.install();
+
+        mock.push_rows("TestQuery2", vec![(1u64, Some("x".to_string()))]);
+        let config = SqlQueryConfig { caching: None };
+        let rows = TestQuery2::query(&config, mock.connection()).await?;
+        assert_eq!(rows, vec![(1, Some("x".to_string()))]);
+
+        mock.push_write("TestQuery3", WriteResult::new(Some(7), 2));
+        let result = TestQuery3::query(mock.connection(), &[(&12,), (&13,)]).await?;
+        assert_eq!(result.affected_rows(), 2);
+        assert_eq!(result.last_insert_id(), Some(7));
+
+        mock.push_mysql_error("TestQuery4", 1213);
+        mock.push_write("TestQuery4", WriteResult::new(None, 1));
+        TestQuery4::query(mock.connection(), &"hello").await?;
+
+        // A response of the wrong type fails the query.
+        mock.push_rows("TestQuery2", vec![1u64]);
+        assert!(TestQuery2::query(&config, mock.connection()).await.is_err());
+
+        let params: Vec<_> = mock
+            .invocations()
+            .into_iter()
+            .map(|invocation| (invocation.query, invocation.params))
+            .collect();
+        assert_eq!(
+            params,
+            vec![
+                ("TestQuery2".to_string(), vec![]),
+                ("TestQuery3".to_string(), vec!["[(12,), (13,)]".to_string()]),
+                ("TestQuery4".to_string(), vec!["\"hello\"".to_string()]),
+                ("TestQuery4".to_string(), vec!["\"hello\"".to_string()]),
+                ("TestQuery2".to_string(), vec![]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mock_stream_latency() -> anyhow::Result<()> {
+        use std::time::Duration;
+
+        use futures::TryStreamExt;
+
+        use crate::mock::MockConnection;
+
+        let mock = MockConnection::new()?;
+        let _guard = mock.install();
+
+        mock.set_latency("TestQuery5", Duration::from_secs(1));
+        mock.push_rows("TestQuery5", vec![(1u64, "a".to_string()), (2, "b".to_string())]);
+        mock.push_rows("TestQuery5", vec![(3u64, "c".to_string())]);
+
+        let start = tokio::time::Instant::now();
+        let rows: Vec<_> = TestQuery5::query_stream(mock.connection(), 2, 0, &100)
+            .try_collect()
+            .await?;
+        assert_eq!(rows.len(), 3);
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+
+        let params: Vec<_> = mock
+            .invocations()
+            .into_iter()
+            .map(|invocation| invocation.params)
+            .collect();
+        assert_eq!(params, vec![vec!["0", "2", "100"], vec!["2", "2", "100"]]);
+        Ok(())
+    }
+    #[tokio::test(start_paused = true)]
+    async fn test_max_concurrency() -> anyhow::Result<()> {
+        use std::time::Duration;
+
+        use crate::mock::MockConnection;
+
+        let mock = MockConnection::new()?;
+        let _guard = mock.install();
+
+        mock.set_latency("LimitedQuery", Duration::from_secs(1));
+        for id in 0..50u64 {
+            mock.push_rows("LimitedQuery", vec![(id,)]);
+        }
+
+        let start = tokio::time::Instant::now();
+        let ids: Vec<_> = (0..50u64).collect();
+        let calls = ids
+            .iter()
+            .map(|id| LimitedQuery::query(mock.connection(), id));
+        let results = futures::future::join_all(calls).await;
+        assert_eq!(results.len(), 50);
+        for result in results {
+            assert_eq!(result?.len(), 1);
+        }
+        assert_eq!(mock.peak_concurrency("LimitedQuery"), 4);
+        // 50 calls, 4 at a time.
+        assert_eq!(start.elapsed(), Duration::from_secs(13));
+
+        let name = format!("{}::LimitedQuery", module_path!());
+        let stats = crate::query_limit_stats(&name).unwrap();
+        assert_eq!(stats.acquired, 50);
+        assert_eq!(stats.saturated, 0);
+        assert!(stats.total_wait > Duration::ZERO);
+
+        // Limits can be changed at runtime.
+        crate::set_query_limits(
+            &name,
+            crate::QueryLimits {
+                max_concurrency: Some(2),
+                acquire_timeout: None,
+                ..Default::default()
+            },
+        );
+        let mock = MockConnection::new()?;
+        let _guard = mock.install();
+        mock.set_latency("LimitedQuery", Duration::from_secs(1));
+        for id in 0..10u64 {
+            mock.push_rows("LimitedQuery", vec![(id,)]);
+        }
+        let calls = ids[..10]
+            .iter()
+            .map(|id| LimitedQuery::query(mock.connection(), id));
+        let results = futures::future::join_all(calls).await;
+        assert!(results.into_iter().all(|result| result.is_ok()));
+        assert_eq!(mock.peak_concurrency("LimitedQuery"), 2);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_saturated() -> anyhow::Result<()> {
+        use std::time::Duration;
+
+        use sql::WriteResult;
+
+        use crate::mock::MockConnection;
+        use crate::QuerySaturated;
+
+        let mock = MockConnection::new()?;
+        let _guard = mock.install();
+
+        mock.set_latency("SaturatedQuery", Duration::from_secs(1));
+        mock.push_write("SaturatedQuery", WriteResult::new(None, 1));
+
+        let (slow, saturated) = futures::join!(
+            SaturatedQuery::query(mock.connection(), &1),
+            SaturatedQuery::query(mock.connection(), &2),
+        );
+        assert_eq!(slow?.affected_rows(), 1);
+        let err = saturated.unwrap_err();
+        let saturated = err.downcast_ref::<QuerySaturated>().unwrap();
+        assert_eq!(saturated.max_concurrency, 1);
+        assert_eq!(saturated.timeout, Duration::from_millis(10));
+        // The saturated call never reached the database.
+        assert_eq!(mock.invocations().len(), 1);
+
+        let name = format!("{}::SaturatedQuery", module_path!());
+        let stats = crate::query_limit_stats(&name).unwrap();
+        assert_eq!(stats.acquired, 1);
+        assert_eq!(stats.saturated, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query_policy() -> anyhow::Result<()> {
+        use sql::WriteResult;
+        use sql_query_config::SqlQueryConfig;
+
+        use crate::mock::MockConnection;
+        use crate::set_query_policy;
+        use crate::QueryDisabled;
+        use crate::QueryPolicy;
+
+        let mock = MockConnection::new()?;
+        let _guard = mock.install();
+        let config = SqlQueryConfig { caching: None };
+        let read = format!("{}::PolicyRead", module_path!());
+        let write = format!("{}::PolicyWrite", module_path!());
+
+        // Without the registry, queries are known once called.
+        mock.push_rows("PolicyRead", vec![(1u64,)]);
+        assert_eq!(PolicyRead::query(&config, mock.connection()).await?, vec![(1,)]);
+        assert_eq!(mock.invocations().len(), 1);
+
+        // Disabled reads return nothing without reaching the connection.
+        set_query_policy(&read, QueryPolicy::ReturnEmpty)?;
+        assert_eq!(PolicyRead::query(&config, mock.connection()).await?, vec![]);
+        assert_eq!(mock.invocations().len(), 1);
+
+        set_query_policy(
+            &read,
+            QueryPolicy::FailFast {
+                error_message: "shedding load".to_string(),
+            },
+        )?;
+        let err = PolicyRead::query(&config, mock.connection())
+            .await
+            .unwrap_err();
+        let disabled = err.downcast_ref::<QueryDisabled>().unwrap();
+        assert_eq!(disabled.query, read);
+        assert_eq!(disabled.message, "shedding load");
+        assert_eq!(mock.invocations().len(), 1);
+
+        // Re-enabling restores normal behavior.
+        set_query_policy(&read, QueryPolicy::Enabled)?;
+        mock.push_rows("PolicyRead", vec![(1u64,)]);
+        assert_eq!(PolicyRead::query(&config, mock.connection()).await?, vec![(1,)]);
+        assert_eq!(mock.invocations().len(), 2);
+
+        // Writes can't return empty once they are known.
+        mock.push_write("PolicyWrite", WriteResult::new(None, 1));
+        PolicyWrite::query(mock.connection(), &1).await?;
+        assert!(set_query_policy(&write, QueryPolicy::ReturnEmpty).is_err());
+        set_query_policy(
+            &write,
+            QueryPolicy::FailFast {
+                error_message: "shedding load".to_string(),
+            },
+        )?;
+        let err = PolicyWrite::query(mock.connection(), &2).await.unwrap_err();
+        assert!(err.is::<QueryDisabled>());
+        assert_eq!(mock.invocations().len(), 3);
+        set_query_policy(&write, QueryPolicy::Enabled)?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "query_registry"))]
+    #[test]
+    fn test_query_policy_unknown_kind() {
+        use crate::set_query_policy;
+        use crate::InvalidQueryPolicy;
+        use crate::QueryPolicy;
+
+        // Queries that were never called may be writes.
+        let name = format!("{}::NeverCalled", module_path!());
+        assert!(matches!(
+            set_query_policy(&name, QueryPolicy::ReturnEmpty),
+            Err(InvalidQueryPolicy::UnknownKind { .. })
+        ));
+        let fail_fast = QueryPolicy::FailFast {
+            error_message: "shedding load".to_string(),
+        };
+        assert!(set_query_policy(&name, fail_fast).is_ok());
+        assert!(set_query_policy(&name, QueryPolicy::Enabled).is_ok());
+    }
+
+    #[cfg(feature = "query_registry")]
+    #[test]
+    fn test_registry() {
+        use crate::registry;
+        use crate::set_query_policy;
+        use crate::InvalidQueryPolicy;
+        use crate::QueryKind;
+        use crate::QueryPolicy;
+
+        let name = |query: &str| format!("{}::{}", module_path!(), query);
+        let names: Vec<_> = registry::all_queries().iter().map(|q| q.name).collect();
+        for query in ["TestQuery", "TestQuery2", "TestQuery3", "TestQuery4"] {
+            assert!(names.contains(&name(query).as_str()), "{} not found", query);
+        }
+
+        let query = registry::find(&name("TestQuery")).unwrap();
+        assert_eq!(query.kind, QueryKind::Read);
+        assert_eq!(query.mysql, "SELECT 44, NULL, {param_str}, {param_uint}");
+        assert_eq!(query.sqlite, query.mysql);
+        assert_eq!(
+            query.params,
+            &[("param_str", "String"), ("param_uint", "u64")]
+        );
+        assert_eq!(query.source_crate(), "sql_ext");
+
+        let query = registry::find(&name("TestQuery2")).unwrap();
+        assert_eq!(query.kind, QueryKind::Read);
+        assert_eq!(query.mysql, "SELECT 44, NULL");
+        assert!(query.params.is_empty());
+
+        let query = registry::find(&name("TestQuery3")).unwrap();
+        assert_eq!(query.kind, QueryKind::Write);
+        assert_eq!(
+            query.mysql,
+            "INSERT INTO my_table (num, str) VALUES {values}"
+        );
+        assert_eq!(query.params[0].0, "values");
+
+        let query = registry::find(&name("TestQuery4")).unwrap();
+        assert_eq!(query.kind, QueryKind::Write);
+        assert_eq!(query.mysql, "DELETE FROM my_table where id = {id}");
+        assert_eq!(query.sqlite, "DELETE FROM mytable2 where id = {id}");
+        assert_eq!(query.params.len(), 1);
+
+        let query = registry::find(&name("SelectOptListRows")).unwrap();
+        assert_eq!(
+            query.mysql,
+            "SELECT id, value FROM list_rows WHERE id >= {min_id} AND id IN {ids} ORDER BY id"
+        );
+        assert_eq!(query.params[1], ("ids", "Option<[u64]>"));
+
+        // Policies can only be set for queries in the inventory.
+        assert!(registry::find("sql_ext::no_such::Query").is_none());
+        assert!(matches!(
+            set_query_policy("sql_ext::no_such::Query", QueryPolicy::ReturnEmpty),
+            Err(InvalidQueryPolicy::UnknownQuery { .. })
+        ));
+        // Writes are known before their first call.
+        assert!(matches!(
+            set_query_policy(&name("TestQuery4"), QueryPolicy::ReturnEmpty),
+            Err(InvalidQueryPolicy::NotForWrites { .. })
+        ));
+    }
+
+    #[cfg(feature = "slow_query_explain")]
+    #[tokio::test]
+    async fn test_slow_query_explain() -> anyhow::Result<()> {
+        use std::sync::Arc;
+        use std::sync::Mutex;
+        use std::time::Duration;
+
+        use sql::Connection;
+
+        use crate::open_sqlite_in_memory;
+        use crate::set_slow_query_explain;
+        use crate::set_slow_query_sink;
+        use crate::SlowQuery;
+        use crate::SlowQueryOptions;
+        use crate::SlowQuerySink;
+
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<SlowQuery>>);
+
+        impl SlowQuerySink for Recorder {
+            fn record(&self, query: SlowQuery) {
+                self.0.lock().unwrap().push(query);
+            }
+        }
+
+        let con = open_sqlite_in_memory()?;
+        con.execute_batch(
+            "CREATE TABLE explain_rows (id INTEGER PRIMARY KEY, value TEXT NOT NULL);
+            INSERT INTO explain_rows (id, value) VALUES (1, 'a');",
+        )?;
+        let connection = Connection::with_sqlite(con);
+        let name = format!("{}::ExplainedQuery", module_path!());
+        let recorder = Arc::new(Recorder::default());
+        set_slow_query_sink(Some(recorder.clone()));
+        let take = || std::mem::take(&mut *recorder.0.lock().unwrap());
+        // The explain runs in its own task.
+        let wait_recorded = || async {
+            for _ in 0..1000 {
+                if !recorder.0.lock().unwrap().is_empty() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            take()
+        };
+
+        // Calls under the threshold are not explained.
+        set_slow_query_explain(
+            &name,
+            Some(SlowQueryOptions::new(Duration::from_secs(3600))),
+        );
+        let rows = ExplainedQuery::query(&connection, &1).await?;
+        assert_eq!(rows, vec![("a".to_string(),)]);
+        assert!(take().is_empty());
+
+        // Any call is slower than a zero threshold.
+        let options = SlowQueryOptions {
+            threshold: Duration::ZERO,
+            interval: Duration::from_secs(3600),
+            explain_timeout: Duration::from_secs(10),
+            log_params: true,
+        };
+        set_slow_query_explain(&name, Some(options));
+        let rows = ExplainedQuery::query(&connection, &1).await?;
+        assert_eq!(rows, vec![("a".to_string(),)]);
+        let recorded = wait_recorded().await;
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].query, name);
+        assert_eq!(recorded[0].params, vec!["1"]);
+        let plan = recorded[0].plan.as_ref().unwrap();
+        assert!(
+            plan.iter().any(|step| step.contains("explain_rows")),
+            "{:?}",
+            plan
+        );
+
+        // Repeats within the interval are not explained.
+        ExplainedQuery::query(&connection, &2).await?;
+        assert!(take().is_empty());
+
+        // Parameters are redacted by default.
+        let options = SlowQueryOptions {
+            interval: Duration::ZERO,
+            ..SlowQueryOptions::new(Duration::ZERO)
+        };
+        set_slow_query_explain(&name, Some(options));
+        ExplainedQuery::query(&connection, &12).await?;
+        let recorded = wait_recorded().await;
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].params, vec!["<redacted, 2 bytes>"]);
+
+        set_slow_query_explain(&name, None);
+        set_slow_query_sink(None);
+        Ok(())
+    }
+
+    fn marker_connection(marker: &str) -> anyhow::Result<sql::Connection> {
+        let con = crate::open_sqlite_in_memory()?;
+        con.execute_batch(&format!(
+            "CREATE TABLE markers (name TEXT NOT NULL);
+            INSERT INTO markers (name) VALUES ('{}');",
+            marker
+        ))?;
+        Ok(sql::Connection::with_sqlite(con))
+    }
+
+    fn markers(rows: Vec<(String,)>) -> Vec<String> {
+        rows.into_iter().map(|(name,)| name).collect()
+    }
+
+    #[tokio::test]
+    async fn test_query_from_pools() -> anyhow::Result<()> {
+        use crate::ReadConsistency;
+        use crate::SqlConnectionPools;
+
+        let write = marker_connection("write")?;
+        let read = marker_connection("read")?;
+        let read_master = marker_connection("read_master")?;
+        let pools = SqlConnectionPools::new(write.clone(), read.clone(), read_master.clone());
+
+        assert_eq!(markers(SelectMarkers::query_from(&pools).await?), ["read"]);
+        let master = pools.with_consistency(ReadConsistency::Master);
+        assert_eq!(
+            markers(SelectMarkers::query_from(&master).await?),
+            ["read_master"]
+        );
+
+        InsertMarker::query_from(&pools, &[(&"new".to_string(),)]).await?;
+        assert_eq!(
+            markers(SelectMarkers::query(&write).await?),
+            ["new", "write"]
+        );
+        assert_eq!(markers(SelectMarkers::query(&read).await?), ["read"]);
+        assert_eq!(
+            markers(SelectMarkers::query(&read_master).await?),
+            ["read_master"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pool_exhausted() -> anyhow::Result<()> {
+        use std::time::Duration;
+
+        use crate::PoolExhausted;
+        use crate::PoolLimits;
+        use crate::PoolRole;
+        use crate::SqlConnectionPools;
+
+        let pools = SqlConnectionPools::new(
+            marker_connection("write")?,
+            marker_connection("read")?,
+            marker_connection("read_master")?,
+        )
+        .with_limits(
+            PoolRole::Read,
+            PoolLimits {
+                max_checkouts: 1,
+                checkout_timeout: Duration::from_millis(10),
+            },
+        );
+
+        let held = pools.read_connection().await?;
+        let err = SelectMarkers::query_from(&pools).await.unwrap_err();
+        let exhausted = err.downcast_ref::<PoolExhausted>().unwrap();
+        assert_eq!(exhausted.role, PoolRole::Read);
+        assert_eq!(exhausted.max_checkouts, 1);
+        assert_eq!(exhausted.timeout, Duration::from_millis(10));
+
+        // Other pools are not limited.
+        InsertMarker::query_from(&pools, &[(&"new".to_string(),)]).await?;
+
+        drop(held);
+        assert_eq!(markers(SelectMarkers::query_from(&pools).await?), ["read"]);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_typed_columns() -> anyhow::Result<()> {
+        use sql::Connection;
+
+        use crate::open_sqlite_in_memory;
+        use crate::SqlConversionError;
+
+        let con = open_sqlite_in_memory()?;
+        con.execute_batch("CREATE TABLE typed_rows (repo INTEGER NOT NULL, hash BLOB NOT NULL);")?;
+        let connection = Connection::with_sqlite(con);
+        let repo = RepoNumber(1);
+        let hashes = [ContentHash([1; 32]), ContentHash([2; 32])];
+        InsertTyped::query(
+            &connection,
+            &[(&repo, &hashes[0]), (&repo, &hashes[1]), (&RepoNumber(2), &hashes[0])],
+        )
+        .await?;
+
+        let rows = SelectTyped::query(&connection, &repo, &hashes).await?;
+        assert_eq!(rows, vec![(repo, hashes[0]), (repo, hashes[1])]);
+        let rows = SelectTyped::query(&connection, &RepoNumber(2), &hashes[1..]).await?;
+        assert_eq!(rows, vec![]);
+
+        let transaction = connection.start_transaction().await?;
+        let (transaction, rows) =
+            SelectTyped::query_with_transaction(transaction, &RepoNumber(2), &hashes).await?;
+        assert_eq!(rows, vec![(RepoNumber(2), hashes[0])]);
+        transaction.commit().await?;
+
+        // A column of the wrong type fails the query instead of panicking.
+        let err = SelectTypedWrong::query(&connection, &repo).await.unwrap_err();
+        let err = err.downcast_ref::<SqlConversionError>().unwrap();
+        assert_eq!(err.column, 0);
+        assert!(err.expected.ends_with("ContentHash"));
+        assert_eq!(err.actual, "int 1");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_lists() -> anyhow::Result<()> {
+        use crate::mock::MockConnection;
+        use crate::WriteOutcome;
+
+        // Nothing is scripted, and the connection of the mock has no
+        // tables, so any query that runs fails.
+        let mock = MockConnection::new()?;
+        let _guard = mock.install();
+        let connection = mock.connection();
+
+        assert_eq!(SelectListRows::query(connection, &[]).await?, vec![]);
+        assert_eq!(SelectTyped::query(connection, &RepoNumber(1), &[]).await?, vec![]);
+        assert_eq!(SelectOptListRows::query(connection, &0, Some(&[])).await?, vec![]);
+        let result = DeleteListRows::query(connection, &[]).await?;
+        assert_eq!(result.affected_rows(), 0);
+        assert_eq!(result.outcome(), WriteOutcome::NoOp);
+        let result = InsertWriteRow::query(connection, &[]).await?;
+        assert_eq!(result.affected_rows(), 0);
+        assert_eq!(result.inserted_id(), None);
+
+        let transaction = connection.start_transaction().await?;
+        let (transaction, rows) = SelectListRows::query_with_transaction(transaction, &[]).await?;
+        assert_eq!(rows, vec![]);
+        let (transaction, rows) =
+            SelectOptListRows::query_with_transaction(transaction, &0, Some(&[])).await?;
+        assert_eq!(rows, vec![]);
+        let (transaction, result) = DeleteListRows::query_with_transaction(transaction, &[]).await?;
+        assert_eq!(result.affected_rows(), 0);
+        let (transaction, result) = InsertWriteRow::query_with_transaction(transaction, &[]).await?;
+        assert_eq!(result.affected_rows(), 0);
+        transaction.commit().await?;
+
+        assert!(mock.invocations().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_optional_list() -> anyhow::Result<()> {
+        use sql::Connection;
+
+        use crate::open_sqlite_in_memory;
+
+        let con = open_sqlite_in_memory()?;
+        con.execute_batch(
+            "CREATE TABLE list_rows (id INTEGER PRIMARY KEY, value TEXT NOT NULL);
+             INSERT INTO list_rows (id, value) VALUES (1, 'a'), (2, 'b'), (3, 'c'), (4, 'd');",
+        )?;
+        let connection = Connection::with_sqlite(con);
+        let ids = |rows: Vec<(u64, String)>| rows.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+
+        assert_eq!(ids(SelectOptListRows::query(&connection, &2, None).await?), [2, 3, 4]);
+        let filter: &[u64] = &[1, 3, 4];
+        assert_eq!(ids(SelectOptListRows::query(&connection, &2, Some(filter)).await?), [3, 4]);
+        assert_eq!(
+            SelectOptListValues::query(&connection, None).await?,
+            ["a", "b", "c", "d"]
+        );
+        assert_eq!(SelectOptListValues::query(&connection, Some(&[2])).await?, ["b"]);
+
+        let transaction = connection.start_transaction().await?;
+        let (transaction, rows) =
+            SelectOptListRows::query_with_transaction(transaction, &1, None).await?;
+        assert_eq!(ids(rows), [1, 2, 3, 4]);
+        let (transaction, rows) =
+            SelectOptListRows::query_with_transaction(transaction, &1, Some(filter)).await?;
+        assert_eq!(ids(rows), [1, 3, 4]);
+        transaction.commit().await?;
+
+        // Lists of a single element.
+        assert_eq!(ids(SelectListRows::query(&connection, &[3]).await?), [3]);
+        assert_eq!(ids(SelectOptListRows::query(&connection, &0, Some(&[3])).await?), [3]);
+        let result = DeleteListRows::query(&connection, &[3]).await?;
+        assert_eq!(result.affected_rows(), 1);
+        assert_eq!(ids(SelectOptListRows::query(&connection, &0, None).await?), [1, 2, 4]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_result_limits() -> anyhow::Result<()> {
+        use futures::TryStreamExt;
+        use sql::Connection;
+
+        use crate::open_sqlite_in_memory;
+        use crate::query_policy::QueryKind;
+        use crate::ResultLimitKind;
+        use crate::ResultLimits;
+        use crate::ResultTooLarge;
+
+        let con = open_sqlite_in_memory()?;
+        con.execute_batch("CREATE TABLE large_rows (id INTEGER PRIMARY KEY, value TEXT NOT NULL);")?;
+        let values = (1..=10_000)
+            .map(|id| format!("({}, '{}')", id, "x".repeat(200)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        con.execute_batch(&format!("INSERT INTO large_rows (id, value) VALUES {};", values))?;
+        let connection = Connection::with_sqlite(con);
+
+        let err = SelectBoundedRows::query(&connection).await.unwrap_err();
+        assert!(!crate::is_retryable_sql_error(&err, QueryKind::Read));
+        let err = err.downcast_ref::<ResultTooLarge>().expect("ResultTooLarge");
+        assert_eq!(err.query, format!("{}::SelectBoundedRows", module_path!()));
+        assert_eq!(err.kind, ResultLimitKind::Rows);
+        assert_eq!(err.limit, 1000);
+
+        // The limit applies to the whole stream, whatever the chunk size.
+        let stream = StreamBoundedRows::query_stream(&connection, 100, 0);
+        let err = stream.try_collect::<Vec<_>>().await.unwrap_err();
+        assert!(err.downcast_ref::<ResultTooLarge>().is_some());
+        let rows: Vec<_> = StreamBoundedRows::query_stream(&connection, 100, 9_000)
+            .try_collect()
+            .await?;
+        assert_eq!(rows.len(), 1000);
+
+        // The default limits apply to queries without their own, unless
+        // they are unlimited. They are restored even if the test fails.
+        struct RestoreDefaults(ResultLimits);
+        impl Drop for RestoreDefaults {
+            fn drop(&mut self) {
+                crate::set_default_result_limits(self.0);
+            }
+        }
+        assert_eq!(SelectLargeRows::query(&connection).await?.len(), 10_000);
+        let restore = RestoreDefaults(crate::default_result_limits());
+        crate::set_default_result_limits(ResultLimits {
+            max_rows: None,
+            max_bytes: Some(1_000_000),
+        });
+        let result = SelectLargeRows::query(&connection).await;
+        let unlimited = SelectUnlimitedRows::query(&connection).await;
+        crate::set_query_limits(
+            &format!("{}::SelectLargeRows", module_path!()),
+            crate::QueryLimits {
+                unlimited: Some(true),
+                ..Default::default()
+            },
+        );
+        let overridden = SelectLargeRows::query(&connection).await;
+        drop(restore);
+
+        let err = result.unwrap_err();
+        let err = err.downcast_ref::<ResultTooLarge>().expect("ResultTooLarge");
+        assert_eq!(err.kind, ResultLimitKind::Bytes);
+        assert_eq!(err.limit, 1_000_000);
+        assert_eq!(unlimited?.len(), 10_000);
+        assert_eq!(overridden?.len(), 10_000);
         Ok(())
     }
 }
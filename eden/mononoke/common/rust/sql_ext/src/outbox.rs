@@ -0,0 +1,313 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Transactional outbox: events are written to the `outbox` table in the
+//! transaction that writes the rows they describe, so they are emitted if
+//! and only if the transaction commits. Another process polls them.
+//!
+//! A poll claims entries of a topic for a visibility timeout, by setting a
+//! claim token on them in a single `UPDATE`, so concurrent pollers never get
+//! the same entries. Entries not acked before the timeout expires are
+//! delivered again. Entries of a topic are delivered in the order they were
+//! enqueued, entries delivered again before newer ones. Visibility timeouts
+//! are measured with the clock of the database, so pollers on hosts with
+//! skewed clocks do not take over each other's claims early.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use sql::Connection;
+use sql::Transaction;
+
+/// Schema of the outbox table for MySQL.
+pub const OUTBOX_MYSQL_SCHEMA: &str = include_str!("../schemas/mysql-outbox.sql");
+
+/// Schema of the outbox table for SQLite.
+pub const OUTBOX_SQLITE_SCHEMA: &str = include_str!("../schemas/sqlite-outbox.sql");
+
+crate::mononoke_queries! {
+    write InsertEntries(values: (topic: String, payload: Vec<u8>, created_at: i64)) {
+        none,
+        "INSERT INTO outbox (topic, payload, created_at) VALUES {values}"
+    }
+
+    // MySQL can't select from the table an UPDATE changes, SQLite can't
+    // limit an UPDATE. Both read the current time once per statement.
+    write ClaimEntries(topic: String, token: i64, timeout_ms: i64, limit: u64) {
+        none,
+        mysql(
+            "UPDATE outbox
+             SET claim_token = {token},
+                 claimed_until = CAST(UNIX_TIMESTAMP(NOW(3)) * 1000 AS SIGNED) + {timeout_ms},
+                 deliveries = deliveries + 1
+             WHERE topic = {topic}
+               AND claimed_until <= CAST(UNIX_TIMESTAMP(NOW(3)) * 1000 AS SIGNED)
+             ORDER BY id
+             LIMIT {limit}"
+        )
+        sqlite(
+            "UPDATE outbox
+             SET claim_token = {token},
+                 claimed_until =
+                     CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER) + {timeout_ms},
+                 deliveries = deliveries + 1
+             WHERE id IN (
+                 SELECT id FROM outbox
+                 WHERE topic = {topic}
+                   AND claimed_until <= CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER)
+                 ORDER BY id
+                 LIMIT {limit}
+             )"
+        )
+    }
+
+    read SelectClaimed(token: i64) -> (u64, String, Vec<u8>, u64) {
+        "SELECT id, topic, payload, deliveries FROM outbox WHERE claim_token = {token} ORDER BY id"
+    }
+
+    write DeleteEntries(>list ids: u64) {
+        none,
+        "DELETE FROM outbox WHERE id IN {ids}"
+    }
+}
+
+/// An entry delivered by `OutboxPoller::poll`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutboxEntry {
+    /// Increasing in the order entries were enqueued. Identifies the entry
+    /// for `OutboxPoller::ack`.
+    pub id: u64,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    /// Number of times the entry was delivered, this time included.
+    pub deliveries: u64,
+}
+
+/// Writes entries to the outbox.
+pub struct OutboxWriter;
+
+impl OutboxWriter {
+    /// Enqueue `payload` on `topic` in `txn`. Pollers only see it once `txn`
+    /// is committed.
+    pub async fn enqueue_in_transaction(
+        txn: Transaction,
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<Transaction> {
+        let topic = topic.to_string();
+        let payload = payload.to_vec();
+        let (txn, _) =
+            InsertEntries::query_with_transaction(txn, &[(&topic, &payload, &now_ms())]).await?;
+        Ok(txn)
+    }
+}
+
+/// Delivers entries of the outbox, and removes them once processed.
+pub struct OutboxPoller;
+
+impl OutboxPoller {
+    /// Claim and return the next `batch` entries of `topic`, at most. They
+    /// are not delivered to other polls until `visibility_timeout` expires,
+    /// then they are delivered again unless acked.
+    ///
+    /// `connection` must be a connection to the primary database, as the
+    /// entries are read back after claiming them.
+    pub async fn poll(
+        connection: &Connection,
+        topic: &str,
+        batch: usize,
+        visibility_timeout: Duration,
+    ) -> Result<Vec<OutboxEntry>> {
+        if batch == 0 {
+            return Ok(Vec::new());
+        }
+        let token = new_claim_token();
+        // Leaves room for the current time to be added.
+        let timeout_ms = visibility_timeout.as_millis().min(i64::MAX as u128 / 2) as i64;
+        let claimed = ClaimEntries::query(
+            connection,
+            &topic.to_string(),
+            &token,
+            &timeout_ms,
+            &(batch as u64),
+        )
+        .await?;
+        if claimed.affected_rows() == 0 {
+            return Ok(Vec::new());
+        }
+        let rows = SelectClaimed::query(connection, &token).await?;
+        Ok(rows
+            .into_iter()
+            .map(|(id, topic, payload, deliveries)| OutboxEntry {
+                id,
+                topic,
+                payload,
+                deliveries,
+            })
+            .collect())
+    }
+
+    /// Remove the entries `ids` once processed, so they are not delivered
+    /// again. Acking an entry again has no effect.
+    pub async fn ack(connection: &Connection, ids: &[u64]) -> Result<()> {
+        if !ids.is_empty() {
+            DeleteEntries::query(connection, ids).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A token unique to a poll, so it reads back only the entries it claimed.
+fn new_claim_token() -> i64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    // Non-negative, like all the other integers in the table.
+    (hasher.finish() >> 1) as i64
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::open_sqlite_in_memory;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    fn outbox() -> Result<Connection> {
+        let con = open_sqlite_in_memory()?;
+        con.execute_batch(OUTBOX_SQLITE_SCHEMA)?;
+        Ok(Connection::with_sqlite(con))
+    }
+
+    async fn enqueue(connection: &Connection, topic: &str, payloads: &[&[u8]]) -> Result<()> {
+        let mut txn = connection.start_transaction().await?;
+        for payload in payloads {
+            txn = OutboxWriter::enqueue_in_transaction(txn, topic, payload).await?;
+        }
+        txn.commit().await?;
+        Ok(())
+    }
+
+    fn payloads(entries: &[OutboxEntry]) -> Vec<&[u8]> {
+        entries.iter().map(|e| e.payload.as_slice()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_rolled_back() -> Result<()> {
+        let connection = outbox()?;
+        let txn = connection.start_transaction().await?;
+        let txn = OutboxWriter::enqueue_in_transaction(txn, "topic", b"a").await?;
+        txn.rollback().await?;
+        assert!(
+            OutboxPoller::poll(&connection, "topic", 10, TIMEOUT)
+                .await?
+                .is_empty()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delivered_in_order() -> Result<()> {
+        let connection = outbox()?;
+        enqueue(&connection, "a", &[b"1", b"2", b"3"]).await?;
+        enqueue(&connection, "b", &[b"x"]).await?;
+        enqueue(&connection, "a", &[b"4", b"5"]).await?;
+
+        let first = OutboxPoller::poll(&connection, "a", 3, TIMEOUT).await?;
+        assert_eq!(payloads(&first), vec![b"1", b"2", b"3"]);
+        assert!(first.iter().all(|e| e.topic == "a" && e.deliveries == 1));
+        assert!(first.windows(2).all(|w| w[0].id < w[1].id));
+        let second = OutboxPoller::poll(&connection, "a", 3, TIMEOUT).await?;
+        assert_eq!(payloads(&second), vec![b"4", b"5"]);
+        assert!(first.last().unwrap().id < second[0].id);
+        assert!(
+            OutboxPoller::poll(&connection, "a", 3, TIMEOUT)
+                .await?
+                .is_empty()
+        );
+
+        let other = OutboxPoller::poll(&connection, "b", 3, TIMEOUT).await?;
+        assert_eq!(payloads(&other), vec![b"x"]);
+
+        let ids: Vec<_> = first.iter().map(|e| e.id).collect();
+        OutboxPoller::ack(&connection, &ids).await?;
+        OutboxPoller::ack(&connection, &ids).await?;
+        OutboxPoller::ack(&connection, &[]).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_redelivery() -> Result<()> {
+        let connection = outbox()?;
+        let timeout = Duration::from_millis(500);
+        enqueue(&connection, "topic", &[b"1", b"2"]).await?;
+
+        let first = OutboxPoller::poll(&connection, "topic", 10, timeout).await?;
+        assert_eq!(payloads(&first), vec![b"1", b"2"]);
+        OutboxPoller::ack(&connection, &[first[1].id]).await?;
+        enqueue(&connection, "topic", &[b"3"]).await?;
+        let newer = OutboxPoller::poll(&connection, "topic", 10, timeout).await?;
+        assert_eq!(payloads(&newer), vec![b"3"]);
+
+        // Entries not acked are delivered again once their visibility
+        // timeout expired, before newer ones.
+        tokio::time::sleep(timeout * 2).await;
+        enqueue(&connection, "topic", &[b"4"]).await?;
+        let again = OutboxPoller::poll(&connection, "topic", 10, timeout).await?;
+        assert_eq!(payloads(&again), vec![b"1", b"3", b"4"]);
+        assert_eq!(again[0].id, first[0].id);
+        assert_eq!(again[0].deliveries, 2);
+        assert_eq!(again[2].deliveries, 1);
+
+        let ids: Vec<_> = again.iter().map(|e| e.id).collect();
+        OutboxPoller::ack(&connection, &ids).await?;
+        tokio::time::sleep(timeout * 2).await;
+        assert!(
+            OutboxPoller::poll(&connection, "topic", 10, timeout)
+                .await?
+                .is_empty()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_pollers() -> Result<()> {
+        let connection = outbox()?;
+        let payloads: Vec<Vec<u8>> = (0..10).map(|i| vec![i]).collect();
+        let refs: Vec<&[u8]> = payloads.iter().map(|p| p.as_slice()).collect();
+        enqueue(&connection, "topic", &refs).await?;
+
+        let (a, b) = futures::try_join!(
+            OutboxPoller::poll(&connection, "topic", 6, TIMEOUT),
+            OutboxPoller::poll(&connection, "topic", 6, TIMEOUT),
+        )?;
+        assert_eq!(a.len() + b.len(), 10);
+        assert!(a.windows(2).all(|w| w[0].id < w[1].id));
+        assert!(b.windows(2).all(|w| w[0].id < w[1].id));
+        let a_ids: HashSet<_> = a.iter().map(|e| e.id).collect();
+        assert!(b.iter().all(|e| !a_ids.contains(&e.id)));
+        let mut delivered: Vec<_> = a.iter().chain(b.iter()).map(|e| e.payload[0]).collect();
+        delivered.sort();
+        assert_eq!(delivered, (0..10).collect::<Vec<u8>>());
+        Ok(())
+    }
+}
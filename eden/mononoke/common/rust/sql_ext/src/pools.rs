@@ -0,0 +1,214 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Writer and reader connection pools, for the `query_from` functions
+//! generated by `mononoke_queries!`: reads check out a connection from the
+//! read pool, or the read master pool for reads that must see recent writes,
+//! and writes from the write pool.
+//!
+//! Pools are unlimited by default. With `PoolLimits`, at most
+//! `max_checkouts` connections of a pool are used at once, and a call that
+//! can't check one out in time fails with `PoolExhausted` without running
+//! its query.
+
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use sql::Connection;
+use sql::SqlConnections;
+use thiserror::Error;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PoolRole {
+    Write,
+    Read,
+    ReadMaster,
+}
+
+impl fmt::Display for PoolRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PoolRole::Write => "write",
+            PoolRole::Read => "read",
+            PoolRole::ReadMaster => "read master",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Where reads are sent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReadConsistency {
+    /// The read pool, which may lag behind writes.
+    #[default]
+    Replica,
+    /// The read master pool, to read after write.
+    Master,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolLimits {
+    /// Connections of the pool used at once.
+    pub max_checkouts: usize,
+    /// How long a call waits for a connection before failing with
+    /// `PoolExhausted`.
+    pub checkout_timeout: Duration,
+}
+
+/// No connection of a pool was available in time. Unlike query errors, the
+/// query was not sent to the database.
+#[derive(Debug, Error)]
+#[error("No connection in the {role} pool after {timeout:?} ({max_checkouts} in use)")]
+pub struct PoolExhausted {
+    pub role: PoolRole,
+    pub max_checkouts: usize,
+    pub timeout: Duration,
+}
+
+struct ConnectionPool {
+    role: PoolRole,
+    connection: Connection,
+    limits: Option<(PoolLimits, Arc<Semaphore>)>,
+}
+
+impl ConnectionPool {
+    fn new(role: PoolRole, connection: Connection) -> Self {
+        Self {
+            role,
+            connection,
+            limits: None,
+        }
+    }
+
+    fn with_limits(&self, limits: PoolLimits) -> Self {
+        Self {
+            role: self.role,
+            connection: self.connection.clone(),
+            limits: Some((limits, Arc::new(Semaphore::new(limits.max_checkouts)))),
+        }
+    }
+
+    async fn checkout(&self) -> Result<PooledConnection<'_>, PoolExhausted> {
+        let permit = match &self.limits {
+            None => None,
+            Some((limits, semaphore)) => {
+                let acquire = semaphore.clone().acquire_owned();
+                match tokio::time::timeout(limits.checkout_timeout, acquire).await {
+                    Ok(Ok(permit)) => Some(permit),
+                    _ => {
+                        return Err(PoolExhausted {
+                            role: self.role,
+                            max_checkouts: limits.max_checkouts,
+                            timeout: limits.checkout_timeout,
+                        });
+                    }
+                }
+            }
+        };
+        Ok(PooledConnection {
+            connection: &self.connection,
+            _permit: permit,
+        })
+    }
+}
+
+/// A connection checked out of a pool, returned to it when dropped.
+pub struct PooledConnection<'a> {
+    connection: &'a Connection,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.connection
+    }
+}
+
+/// The pools of a database, see the module documentation. Clones share
+/// their pools.
+#[derive(Clone)]
+pub struct SqlConnectionPools {
+    write: Arc<ConnectionPool>,
+    read: Arc<ConnectionPool>,
+    read_master: Arc<ConnectionPool>,
+    consistency: ReadConsistency,
+}
+
+impl SqlConnectionPools {
+    pub fn new(
+        write_connection: Connection,
+        read_connection: Connection,
+        read_master_connection: Connection,
+    ) -> Self {
+        Self {
+            write: Arc::new(ConnectionPool::new(PoolRole::Write, write_connection)),
+            read: Arc::new(ConnectionPool::new(PoolRole::Read, read_connection)),
+            read_master: Arc::new(ConnectionPool::new(
+                PoolRole::ReadMaster,
+                read_master_connection,
+            )),
+            consistency: ReadConsistency::default(),
+        }
+    }
+
+    /// Limit the checkouts of the pool `role`. This replaces the pool, so
+    /// clones made before don't share the limit.
+    pub fn with_limits(mut self, role: PoolRole, limits: PoolLimits) -> Self {
+        let pool = match role {
+            PoolRole::Write => &mut self.write,
+            PoolRole::Read => &mut self.read,
+            PoolRole::ReadMaster => &mut self.read_master,
+        };
+        *pool = Arc::new(pool.with_limits(limits));
+        self
+    }
+
+    /// A clone sending reads according to `consistency`.
+    pub fn with_consistency(&self, consistency: ReadConsistency) -> Self {
+        Self {
+            consistency,
+            ..self.clone()
+        }
+    }
+
+    pub fn consistency(&self) -> ReadConsistency {
+        self.consistency
+    }
+
+    pub async fn write_connection(&self) -> Result<PooledConnection<'_>, PoolExhausted> {
+        self.write.checkout().await
+    }
+
+    /// A connection of the read pool, or of the read master pool with
+    /// `ReadConsistency::Master`.
+    pub async fn read_connection(&self) -> Result<PooledConnection<'_>, PoolExhausted> {
+        match self.consistency {
+            ReadConsistency::Replica => self.read.checkout().await,
+            ReadConsistency::Master => self.read_master.checkout().await,
+        }
+    }
+
+    pub async fn read_master_connection(&self) -> Result<PooledConnection<'_>, PoolExhausted> {
+        self.read_master.checkout().await
+    }
+}
+
+impl From<SqlConnections> for SqlConnectionPools {
+    fn from(connections: SqlConnections) -> Self {
+        Self::new(
+            connections.write_connection,
+            connections.read_connection,
+            connections.read_master_connection,
+        )
+    }
+}
@@ -0,0 +1,253 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Per-query concurrency limits for queries defined with `mononoke_queries!`.
+//!
+//! Each query has a limiter, keyed by the path of the module generated for
+//! it (e.g. `bonsai_hg_mapping::sql::SelectMapping`). Its limits come from
+//! the query definition, and can be replaced at runtime with
+//! `set_query_limits`.
+
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use arc_swap::ArcSwapOption;
+use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
+use thiserror::Error;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+
+use crate::result_limit::default_result_limits;
+use crate::result_limit::invalidate_result_limits;
+use crate::result_limit::limits_generation;
+use crate::result_limit::ResultLimits;
+
+static REGISTRY: Lazy<Mutex<HashMap<String, Arc<LimiterState>>>> = Lazy::new(Default::default);
+
+/// Limits of a query, set in its definition:
+///
+/// ```ignore
+/// mononoke_queries! {
+///     { max_concurrency = 16, acquire_timeout = Duration::from_secs(1) }
+///     read SelectMapping(id: u64) -> (String) { "..." }
+///     { max_rows = 1000 }
+///     read SelectMappings(repo: u64) -> (String) { "..." }
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueryLimits {
+    /// Maximum number of calls of the query running at once in this process.
+    /// A call holds its permit across retries.
+    pub max_concurrency: Option<usize>,
+    /// How long a call waits for a permit before failing with
+    /// `QuerySaturated`. Without it, calls wait as long as needed.
+    pub acquire_timeout: Option<Duration>,
+    /// Maximum number of rows returned by a call of a read query, or by all
+    /// the chunks of a streaming read, before it fails with
+    /// `ResultTooLarge`.
+    pub max_rows: Option<usize>,
+    /// Same as `max_rows`, for the estimated size of the rows, see
+    /// `ResultLimits`.
+    pub max_result_bytes: Option<usize>,
+    /// If `Some(true)`, the result limits set with
+    /// `set_default_result_limits` do not apply, for queries known to
+    /// return large results.
+    pub unlimited: Option<bool>,
+}
+
+impl QueryLimits {
+    pub const UNLIMITED: QueryLimits = QueryLimits {
+        max_concurrency: None,
+        acquire_timeout: None,
+        max_rows: None,
+        max_result_bytes: None,
+        unlimited: None,
+    };
+
+    /// The limits of the results of the query, given the `default` ones.
+    pub fn result_limits(&self, default: ResultLimits) -> ResultLimits {
+        let default = match self.unlimited {
+            Some(true) => ResultLimits::default(),
+            _ => default,
+        };
+        ResultLimits {
+            max_rows: self.max_rows.or(default.max_rows),
+            max_bytes: self.max_result_bytes.or(default.max_bytes),
+        }
+    }
+}
+
+/// A query call gave up waiting for a concurrency permit.
+#[derive(Debug, Error)]
+#[error("Query {query} saturated: no permit in {timeout:?} (max concurrency {max_concurrency})")]
+pub struct QuerySaturated {
+    pub query: String,
+    pub max_concurrency: usize,
+    pub timeout: Duration,
+}
+
+/// Counters of a query limiter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueryLimitStats {
+    /// Calls that got a permit.
+    pub acquired: u64,
+    /// Calls that failed with `QuerySaturated`.
+    pub saturated: u64,
+    /// Total time spent waiting for permits, including by saturated calls.
+    pub total_wait: Duration,
+}
+
+struct LimiterState {
+    /// Read without locking by every call, replaced by `set_query_limits`.
+    current: ArcSwap<(QueryLimits, Option<Arc<Semaphore>>)>,
+    /// The result limits, and the generation of the limits they were
+    /// computed from.
+    result_limits: ArcSwapOption<(u64, ResultLimits)>,
+    acquired: AtomicU64,
+    saturated: AtomicU64,
+    total_wait_us: AtomicU64,
+}
+
+impl LimiterState {
+    fn new(limits: QueryLimits) -> Self {
+        Self {
+            current: ArcSwap::from_pointee((limits, semaphore(&limits))),
+            result_limits: ArcSwapOption::empty(),
+            acquired: AtomicU64::new(0),
+            saturated: AtomicU64::new(0),
+            total_wait_us: AtomicU64::new(0),
+        }
+    }
+
+    fn record_wait(&self, start: Instant) {
+        let waited = start.elapsed().as_micros().min(u64::MAX as u128) as u64;
+        self.total_wait_us.fetch_add(waited, Ordering::Relaxed);
+    }
+}
+
+fn semaphore(limits: &QueryLimits) -> Option<Arc<Semaphore>> {
+    limits
+        .max_concurrency
+        .map(|max| Arc::new(Semaphore::new(max.max(1))))
+}
+
+fn registered(name: &str, limits: QueryLimits) -> Arc<LimiterState> {
+    REGISTRY
+        .lock()
+        .expect("lock poisoned")
+        .entry(name.to_string())
+        .or_insert_with(|| Arc::new(LimiterState::new(limits)))
+        .clone()
+}
+
+/// Replace the limits of query `name`, whether or not it was called yet.
+/// Calls already holding or waiting for a permit keep the previous limits,
+/// so the new ones fully apply once they finish.
+pub fn set_query_limits(name: &str, limits: QueryLimits) {
+    let state = registered(name, limits);
+    state.current.store(Arc::new((limits, semaphore(&limits))));
+    invalidate_result_limits();
+}
+
+/// The current limits of query `name`, if it was called or had its limits
+/// set.
+pub fn query_limits(name: &str) -> Option<QueryLimits> {
+    let registry = REGISTRY.lock().expect("lock poisoned");
+    let state = registry.get(name)?;
+    let limits = state.current.load().0;
+    Some(limits)
+}
+
+/// The counters of query `name`, if it was called or had its limits set.
+pub fn query_limit_stats(name: &str) -> Option<QueryLimitStats> {
+    let registry = REGISTRY.lock().expect("lock poisoned");
+    let state = registry.get(name)?;
+    Some(QueryLimitStats {
+        acquired: state.acquired.load(Ordering::Relaxed),
+        saturated: state.saturated.load(Ordering::Relaxed),
+        total_wait: Duration::from_micros(state.total_wait_us.load(Ordering::Relaxed)),
+    })
+}
+
+/// The limiter of a single query, declared as a static by
+/// `mononoke_queries!`. It registers itself on first use.
+pub struct QueryLimiter {
+    name: &'static str,
+    defaults: QueryLimits,
+    state: OnceCell<Arc<LimiterState>>,
+}
+
+impl QueryLimiter {
+    pub const fn new(name: &'static str, defaults: QueryLimits) -> Self {
+        Self {
+            name,
+            defaults,
+            state: OnceCell::new(),
+        }
+    }
+
+    fn state(&self) -> &Arc<LimiterState> {
+        self.state
+            .get_or_init(|| registered(self.name, self.defaults))
+    }
+
+    /// The current result limits of the query, including the default ones.
+    /// They are only computed again after limits were changed.
+    pub fn result_limits(&self) -> ResultLimits {
+        let state = self.state();
+        let generation = limits_generation();
+        if let Some(cached) = state.result_limits.load().as_deref() {
+            if cached.0 == generation {
+                return cached.1;
+            }
+        }
+        let limits = state.current.load().0;
+        let result_limits = limits.result_limits(default_result_limits());
+        state
+            .result_limits
+            .store(Some(Arc::new((generation, result_limits))));
+        result_limits
+    }
+
+    /// Wait for a permit to run the query, if its concurrency is limited.
+    pub async fn acquire(&self) -> Result<Option<OwnedSemaphorePermit>, QuerySaturated> {
+        let state = self.state();
+        let (limits, semaphore) = match &**state.current.load() {
+            (limits, Some(semaphore)) => (*limits, semaphore.clone()),
+            (_, None) => return Ok(None),
+        };
+
+        let start = Instant::now();
+        let acquire = semaphore.acquire_owned();
+        let permit = match limits.acquire_timeout {
+            None => acquire.await,
+            Some(timeout) => match tokio::time::timeout(timeout, acquire).await {
+                Ok(permit) => permit,
+                Err(_) => {
+                    state.record_wait(start);
+                    state.saturated.fetch_add(1, Ordering::Relaxed);
+                    return Err(QuerySaturated {
+                        query: self.name.to_string(),
+                        max_concurrency: limits.max_concurrency.unwrap_or_default(),
+                        timeout,
+                    });
+                }
+            },
+        };
+        state.record_wait(start);
+        state.acquired.fetch_add(1, Ordering::Relaxed);
+        // The semaphore is never closed.
+        Ok(Some(permit.expect("query semaphore closed")))
+    }
+}
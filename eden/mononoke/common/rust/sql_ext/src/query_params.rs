@@ -0,0 +1,34 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::any::type_name;
+use std::fmt::Debug;
+
+/// Formats a query parameter for mock invocations and slow query logs.
+/// Resolves to the `Debug` implementation if there is one, and to the type
+/// name otherwise: `(&MockParam(param)).mock_format()`.
+pub struct MockParam<'a, T: ?Sized>(pub &'a T);
+
+pub trait MockParamDebug {
+    fn mock_format(&self) -> String;
+}
+
+impl<T: Debug + ?Sized> MockParamDebug for MockParam<'_, T> {
+    fn mock_format(&self) -> String {
+        format!("{:?}", self.0)
+    }
+}
+
+pub trait MockParamFallback {
+    fn mock_format(&self) -> String;
+}
+
+impl<T: ?Sized> MockParamFallback for &MockParam<'_, T> {
+    fn mock_format(&self) -> String {
+        format!("<{}>", type_name::<T>())
+    }
+}
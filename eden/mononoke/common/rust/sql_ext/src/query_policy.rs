@@ -0,0 +1,174 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Runtime disablement of queries defined with `mononoke_queries!`, to shed
+//! load from an expensive query without a deploy.
+//!
+//! Queries are named like for `set_query_limits`, by the path of the module
+//! generated for them (e.g. `bonsai_hg_mapping::sql::SelectMapping`). Every
+//! call of a generated `query` function looks its policy up before running,
+//! with a single read of a sharded map. Queries run inside a transaction
+//! with `query_with_transaction` are not affected.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
+use thiserror::Error;
+
+use crate::registry;
+
+/// Policies other than `Enabled`, by query name.
+static POLICIES: Lazy<DashMap<String, QueryPolicy>> = Lazy::new(Default::default);
+
+/// Kinds of the queries called so far, by query name.
+static KINDS: Lazy<Mutex<HashMap<String, QueryKind>>> = Lazy::new(Default::default);
+
+/// What happens when a query is called.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum QueryPolicy {
+    /// The query runs normally.
+    #[default]
+    Enabled,
+    /// The query fails with `QueryDisabled` without reaching the database.
+    FailFast { error_message: String },
+    /// The query returns no rows without reaching the database. Only valid
+    /// for read queries.
+    ReturnEmpty,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryKind {
+    Read,
+    Write,
+}
+
+/// A query call was rejected by its `QueryPolicy`.
+#[derive(Debug, Error)]
+#[error("Query {query} is disabled: {message}")]
+pub struct QueryDisabled {
+    pub query: String,
+    pub message: String,
+}
+
+/// `set_query_policy` was given a policy that does not apply to the query.
+#[derive(Debug, Error)]
+pub enum InvalidQueryPolicy {
+    #[error("Policy {policy:?} is not valid for write query {query}")]
+    NotForWrites { query: String, policy: QueryPolicy },
+    /// The query is not in the inventory of `registry`.
+    #[error("Unknown query {query}")]
+    UnknownQuery { query: String },
+    /// Without the inventory of `registry`, the kind of a query is only
+    /// known once it was called.
+    #[error("Policy {policy:?} is only valid for read queries, and query {query} is not known")]
+    UnknownKind { query: String, policy: QueryPolicy },
+}
+
+/// Set the policy of query `name`, whether or not it was called yet.
+/// `ReturnEmpty` is only accepted for queries known to be reads.
+///
+/// With the `query_registry` feature, names of queries not linked into the
+/// binary are rejected, and all queries are known. Without it, queries are
+/// known once called, so `ReturnEmpty` can't be set before the first call.
+pub fn set_query_policy(name: &str, policy: QueryPolicy) -> Result<(), InvalidQueryPolicy> {
+    let registered = registry::find(name);
+    if registered.is_none() && registry::is_enabled() {
+        return Err(InvalidQueryPolicy::UnknownQuery {
+            query: name.to_string(),
+        });
+    }
+    if policy == QueryPolicy::ReturnEmpty {
+        let kind = match registered {
+            Some(query) => Some(query.kind),
+            None => KINDS.lock().expect("lock poisoned").get(name).copied(),
+        };
+        match kind {
+            Some(QueryKind::Read) => {}
+            Some(QueryKind::Write) => {
+                return Err(InvalidQueryPolicy::NotForWrites {
+                    query: name.to_string(),
+                    policy,
+                });
+            }
+            None => {
+                return Err(InvalidQueryPolicy::UnknownKind {
+                    query: name.to_string(),
+                    policy,
+                });
+            }
+        }
+    }
+    match policy {
+        QueryPolicy::Enabled => {
+            POLICIES.remove(name);
+        }
+        policy => {
+            POLICIES.insert(name.to_string(), policy);
+        }
+    }
+    Ok(())
+}
+
+/// The current policy of query `name`.
+pub fn query_policy(name: &str) -> QueryPolicy {
+    POLICIES
+        .get(name)
+        .map_or(QueryPolicy::Enabled, |policy| policy.clone())
+}
+
+/// How a call should proceed, as decided by `QueryPolicyCheck::check`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyDecision {
+    Run,
+    ReturnEmpty,
+}
+
+/// Looks up the policy of a single query, declared as a static by
+/// `mononoke_queries!`. It records the kind of the query on first use.
+pub struct QueryPolicyCheck {
+    name: &'static str,
+    kind: QueryKind,
+    registered: OnceCell<()>,
+}
+
+impl QueryPolicyCheck {
+    pub const fn new(name: &'static str, kind: QueryKind) -> Self {
+        Self {
+            name,
+            kind,
+            registered: OnceCell::new(),
+        }
+    }
+
+    pub fn check(&self) -> Result<PolicyDecision, QueryDisabled> {
+        self.registered.get_or_init(|| {
+            KINDS
+                .lock()
+                .expect("lock poisoned")
+                .insert(self.name.to_string(), self.kind);
+        });
+        let policy = match POLICIES.get(self.name) {
+            None => return Ok(PolicyDecision::Run),
+            Some(policy) => policy.clone(),
+        };
+        match (policy, self.kind) {
+            (QueryPolicy::Enabled, _) => Ok(PolicyDecision::Run),
+            (QueryPolicy::ReturnEmpty, QueryKind::Read) => Ok(PolicyDecision::ReturnEmpty),
+            (QueryPolicy::ReturnEmpty, QueryKind::Write) => Err(QueryDisabled {
+                query: self.name.to_string(),
+                message: "write queries can not return empty".to_string(),
+            }),
+            (QueryPolicy::FailFast { error_message }, _) => Err(QueryDisabled {
+                query: self.name.to_string(),
+                message: error_message,
+            }),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Inventory of the queries defined with `mononoke_queries!` linked into the
+//! binary, for review, documentation, and validating query names.
+//!
+//! Each query adds its `QueryDescriptor` to a distributed slice, which the
+//! linker assembles, so there is no cost at runtime. Queries are named by the
+//! path of the module generated for them, like for `set_query_limits` and
+//! `set_query_policy`.
+//!
+//! The inventory is only kept with the `query_registry` feature, enabled by
+//! default. Without it, `all_queries` is empty.
+
+use crate::query_policy::QueryKind;
+
+/// A query defined with `mononoke_queries!`.
+#[derive(Debug)]
+pub struct QueryDescriptor {
+    /// The path of the module generated for the query.
+    pub name: &'static str,
+    pub kind: QueryKind,
+    /// The templates of the query, with `{param}` placeholders. For reads
+    /// with an optional list, the optional clause is included.
+    pub mysql: &'static str,
+    pub sqlite: &'static str,
+    /// Names and types of the parameters, as written in the definition.
+    /// Lists are typed `[T]`, and the values of writes `(name: T, ...)`.
+    pub params: &'static [(&'static str, &'static str)],
+}
+
+impl QueryDescriptor {
+    /// The crate defining the query.
+    pub fn source_crate(&self) -> &'static str {
+        self.name.split("::").next().unwrap_or(self.name)
+    }
+}
+
+#[cfg(feature = "query_registry")]
+#[linkme::distributed_slice]
+pub static QUERIES: [QueryDescriptor];
+
+/// Whether this build keeps the inventory of queries.
+pub const fn is_enabled() -> bool {
+    cfg!(feature = "query_registry")
+}
+
+/// All the queries linked into the binary, in no particular order.
+pub fn all_queries() -> &'static [QueryDescriptor] {
+    #[cfg(feature = "query_registry")]
+    {
+        &QUERIES
+    }
+    #[cfg(not(feature = "query_registry"))]
+    {
+        &[]
+    }
+}
+
+/// The query named `name`, if linked into the binary.
+pub fn find(name: &str) -> Option<&'static QueryDescriptor> {
+    all_queries().iter().find(|query| query.name == name)
+}
+
+/// Adds the query of the module it is expanded in to the inventory.
+#[cfg(feature = "query_registry")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! _register_query {
+    (
+        kind: $kind:ident,
+        mysql: $mysql_q:expr,
+        sqlite: $sqlite_q:expr,
+        params: [ $( ($pname:expr, $ptype:expr) ),* $(,)? ] $(,)?
+    ) => {
+        #[$crate::_macro_internal::distributed_slice($crate::registry::QUERIES)]
+        #[linkme(crate = $crate::_macro_internal::linkme)]
+        static QUERY_DESCRIPTOR: $crate::registry::QueryDescriptor =
+            $crate::registry::QueryDescriptor {
+                name: module_path!(),
+                kind: $crate::_macro_internal::QueryKind::$kind,
+                mysql: $mysql_q,
+                sqlite: $sqlite_q,
+                params: &[ $( ($pname, $ptype) ),* ],
+            };
+    };
+}
+
+#[cfg(not(feature = "query_registry"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! _register_query {
+    ($( $tt:tt )*) => {};
+}
@@ -0,0 +1,225 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Bounds on the results of read queries defined with `mononoke_queries!`,
+//! so that a query missing a WHERE clause fails instead of running the
+//! process out of memory.
+//!
+//! Queries are limited by the `max_rows` and `max_result_bytes` fields of
+//! their `QueryLimits`, and otherwise by the process-wide defaults set with
+//! `set_default_result_limits`, unless they are `unlimited`.
+
+use std::fmt;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
+use thiserror::Error;
+
+use crate::sql_value::FromSqlRow;
+
+static DEFAULT_LIMITS: Lazy<Mutex<ResultLimits>> = Lazy::new(Default::default);
+
+/// Changed whenever the result limits of any query may have changed, so that
+/// the limits cached by queries are computed again.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn limits_generation() -> u64 {
+    GENERATION.load(Ordering::SeqCst)
+}
+
+/// To be called after changing the limits the result limits are computed
+/// from.
+pub(crate) fn invalidate_result_limits() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Bounds on the result of a query call. The byte size of a result is
+/// estimated from the values received: the length of strings and blobs, and
+/// 8 bytes for other non-NULL values.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResultLimits {
+    pub max_rows: Option<usize>,
+    pub max_bytes: Option<usize>,
+}
+
+/// Set the result limits of the queries that don't set their own, whether
+/// or not they were called yet. There are none by default.
+pub fn set_default_result_limits(limits: ResultLimits) {
+    *DEFAULT_LIMITS.lock().expect("lock poisoned") = limits;
+    invalidate_result_limits();
+}
+
+/// The result limits of the queries that don't set their own.
+pub fn default_result_limits() -> ResultLimits {
+    *DEFAULT_LIMITS.lock().expect("lock poisoned")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResultLimitKind {
+    Rows,
+    Bytes,
+}
+
+impl fmt::Display for ResultLimitKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResultLimitKind::Rows => write!(f, "rows"),
+            ResultLimitKind::Bytes => write!(f, "bytes"),
+        }
+    }
+}
+
+/// A query call returned more than its `ResultLimits` allow. It is not
+/// retried, and none of the rows are returned.
+#[derive(Debug, Error)]
+#[error("Query {query} returned more than {limit} {kind}")]
+pub struct ResultTooLarge {
+    pub query: String,
+    pub kind: ResultLimitKind,
+    pub limit: usize,
+}
+
+/// Whether a `LIMIT` can be appended to the text of a query, to bound the
+/// rows read by the client: the query must not have a `LIMIT` of its own,
+/// nor a locking clause, which would have to come after it. Checked once.
+pub struct AppendLimit {
+    queries: [&'static str; 2],
+    allowed: OnceCell<bool>,
+}
+
+impl AppendLimit {
+    pub const fn new(mysql: &'static str, sqlite: &'static str) -> Self {
+        Self {
+            queries: [mysql, sqlite],
+            allowed: OnceCell::new(),
+        }
+    }
+
+    /// The `LIMIT` to run the query with, one row above `max_rows` so that
+    /// a result above it is detected, if it can be appended.
+    pub fn row_limit(&self, limits: &ResultLimits) -> Option<u64> {
+        let max_rows = limits.max_rows?;
+        let allowed = self
+            .allowed
+            .get_or_init(|| self.queries.iter().all(|q| can_append_limit(q)));
+        allowed.then(|| max_rows as u64 + 1)
+    }
+}
+
+fn can_append_limit(query: &str) -> bool {
+    let query = query.to_ascii_uppercase();
+    let words: Vec<_> = query
+        .split(|c: char| c.is_whitespace() || c == '(' || c == ')')
+        .filter(|w| !w.is_empty())
+        .collect();
+    !query.trim_end().ends_with(';')
+        && !words.contains(&"LIMIT")
+        && !words
+            .windows(2)
+            .any(|w| w == ["FOR", "UPDATE"] || w == ["FOR", "SHARE"] || w == ["SHARE", "MODE"])
+}
+
+/// The rows returned by a query call, as counted by `ResultBudget::check`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ResultUsage {
+    rows: usize,
+    bytes: usize,
+}
+
+/// Counts the rows returned by a query call, or all the calls of a
+/// streaming query, against its limits.
+pub struct ResultBudget {
+    query: &'static str,
+    limits: ResultLimits,
+    rows: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl ResultBudget {
+    pub fn new(query: &'static str, limits: ResultLimits) -> Self {
+        Self {
+            query,
+            limits,
+            rows: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Check `rows`, before they are converted, failing if they exceed the
+    /// limits together with the rows counted so far. They are only counted
+    /// once passed to `spend`, so that the attempts of a retried call are
+    /// not added up.
+    pub fn check<R: FromSqlRow>(&self, rows: &[R]) -> Result<ResultUsage, ResultTooLarge> {
+        let mut usage = ResultUsage {
+            rows: rows.len(),
+            bytes: 0,
+        };
+        if let Some(max_rows) = self.limits.max_rows {
+            if self.rows.load(Ordering::Relaxed) + usage.rows > max_rows {
+                return Err(self.too_large(ResultLimitKind::Rows, max_rows));
+            }
+        }
+        if let Some(max_bytes) = self.limits.max_bytes {
+            usage.bytes = rows.iter().map(FromSqlRow::size).sum();
+            if self.bytes.load(Ordering::Relaxed) + usage.bytes > max_bytes {
+                return Err(self.too_large(ResultLimitKind::Bytes, max_bytes));
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Count the rows of a call that succeeded.
+    pub fn spend(&self, usage: ResultUsage) {
+        self.rows.fetch_add(usage.rows, Ordering::Relaxed);
+        self.bytes.fetch_add(usage.bytes, Ordering::Relaxed);
+    }
+
+    /// The number of rows to fetch in the next chunk of a streaming query,
+    /// instead of `limit`, so that no more than one row above the limit is
+    /// read.
+    pub fn chunk_limit(&self, limit: u64) -> u64 {
+        match self.limits.max_rows {
+            Some(max_rows) => {
+                let remaining = max_rows.saturating_sub(self.rows.load(Ordering::Relaxed));
+                limit.min(remaining as u64 + 1)
+            }
+            None => limit,
+        }
+    }
+
+    fn too_large(&self, kind: ResultLimitKind, limit: usize) -> ResultTooLarge {
+        ResultTooLarge {
+            query: self.query.to_string(),
+            kind,
+            limit,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_can_append_limit() {
+        assert!(can_append_limit(
+            "SELECT id FROM t WHERE id > {id} ORDER BY id"
+        ));
+        assert!(can_append_limit("SELECT id FROM t WHERE name = 'limited'"));
+        assert!(!can_append_limit("SELECT id FROM t ORDER BY id limit 10"));
+        assert!(!can_append_limit("SELECT id FROM t LIMIT\n{limit}"));
+        assert!(!can_append_limit(
+            "SELECT id FROM t WHERE id = {id} FOR UPDATE"
+        ));
+        assert!(!can_append_limit("SELECT id FROM t LOCK IN SHARE MODE"));
+        assert!(!can_append_limit("SELECT id FROM t;"));
+    }
+}
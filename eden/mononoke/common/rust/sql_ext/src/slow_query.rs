@@ -0,0 +1,281 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Capture of the plans of slow read queries defined with
+//! `mononoke_queries!`, to diagnose a query that got slow without
+//! reconstructing its parameters from logs.
+//!
+//! Only built with the `slow_query_explain` feature, meant for debug and
+//! canary builds: without it, queries don't get the `EXPLAIN` variants, and
+//! enabling explains has no effect.
+//!
+//! Disabled by default. Once enabled for a query with
+//! `set_slow_query_explain`, under the same name as for `set_query_limits`,
+//! or for all queries with `set_global_slow_query_explain`, a successful
+//! call slower than the threshold is run again prefixed with `EXPLAIN`
+//! (`EXPLAIN FORMAT=JSON` on MySQL, `EXPLAIN QUERY PLAN` on SQLite) on the
+//! same connection, at most once per interval for each query. The plan is
+//! passed to the sink set with `set_slow_query_sink`. Write queries are never
+//! explained.
+//!
+//! The explain runs in a task of its own, up to its timeout, so the caller
+//! neither waits for it nor sees its result: explain failures are passed to
+//! the sink instead. Parameters are redacted unless the options of the query
+//! allow logging them.
+
+use std::future::Future;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use sql::Connection;
+
+/// Options of queries explained by name.
+static OPTIONS: Lazy<DashMap<String, SlowQueryOptions>> = Lazy::new(Default::default);
+
+/// Options of the queries without options of their own.
+static GLOBAL_OPTIONS: RwLock<Option<SlowQueryOptions>> = RwLock::new(None);
+
+/// Set once any query may be explained, so calls don't look options up
+/// otherwise.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static SINK: RwLock<Option<Arc<dyn SlowQuerySink>>> = RwLock::new(None);
+
+/// Parameters are cut to this many characters, so large values aren't
+/// copied to the sink.
+const MAX_PARAM_LEN: usize = 64;
+
+/// When and how slow calls of a query are explained.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SlowQueryOptions {
+    /// Calls taking longer than this, including retries, are explained.
+    pub threshold: Duration,
+    /// A query is explained at most once per interval.
+    pub interval: Duration,
+    /// How long to wait for the plan before giving up.
+    pub explain_timeout: Duration,
+    /// Pass the values of the parameters to the sink, rather than only their
+    /// length. Only for queries whose parameters are not sensitive.
+    pub log_params: bool,
+}
+
+impl SlowQueryOptions {
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            interval: Duration::from_secs(60),
+            explain_timeout: Duration::from_secs(1),
+            log_params: false,
+        }
+    }
+}
+
+/// A slow call of a query, and its plan.
+#[derive(Debug)]
+pub struct SlowQuery {
+    pub query: String,
+    /// The parameters of the call formatted with `Debug`, or as their type
+    /// name if they have no `Debug` implementation, cut to 64 characters.
+    /// Without `log_params`, only the length of each is kept, as
+    /// `<redacted, N bytes>`.
+    pub params: Vec<String>,
+    pub latency: Duration,
+    /// The rows returned by `EXPLAIN`: a single JSON document on MySQL, the
+    /// detail of each step of the plan on SQLite.
+    pub plan: Result<Vec<String>>,
+}
+
+/// Receives the plans of slow queries.
+pub trait SlowQuerySink: Send + Sync {
+    fn record(&self, query: SlowQuery);
+}
+
+/// Explain slow calls of query `name` according to `options`, or stop
+/// explaining them with `None`. This overrides the global options.
+pub fn set_slow_query_explain(name: &str, options: Option<SlowQueryOptions>) {
+    match options {
+        Some(options) => {
+            OPTIONS.insert(name.to_string(), options);
+            ENABLED.store(true, Ordering::Relaxed);
+        }
+        None => {
+            OPTIONS.remove(name);
+        }
+    }
+}
+
+/// Explain slow calls of all queries without options of their own.
+pub fn set_global_slow_query_explain(options: Option<SlowQueryOptions>) {
+    *GLOBAL_OPTIONS.write().expect("lock poisoned") = options;
+    if options.is_some() {
+        ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Where to send the plans of slow queries. Nothing is explained without a
+/// sink.
+pub fn set_slow_query_sink(sink: Option<Arc<dyn SlowQuerySink>>) {
+    *SINK.write().expect("lock poisoned") = sink;
+}
+
+/// Watches the latency of a single read query, declared as a static by
+/// `mononoke_queries!`.
+pub struct SlowQueryCheck {
+    name: &'static str,
+    last_explain: Mutex<Option<Instant>>,
+}
+
+impl SlowQueryCheck {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            last_explain: Mutex::new(None),
+        }
+    }
+
+    /// Called after a successful call that took `latency`. If the call is
+    /// slow and the query wasn't explained during the interval, spawns the
+    /// future returned by `explain` and passes its result to the sink. Must
+    /// be called from a Tokio runtime.
+    pub fn observe<Fut>(
+        &self,
+        latency: Duration,
+        params: impl FnOnce() -> Vec<String>,
+        explain: impl FnOnce() -> Fut,
+    ) where
+        Fut: Future<Output = Result<Vec<String>>> + Send + 'static,
+    {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        let options = match self.options() {
+            Some(options) if latency > options.threshold => options,
+            _ => return,
+        };
+        let sink = match SINK.read().expect("lock poisoned").clone() {
+            Some(sink) => sink,
+            None => return,
+        };
+        if !self.claim(options.interval) {
+            return;
+        }
+        let params = params()
+            .into_iter()
+            .map(|param| {
+                if options.log_params {
+                    truncate_param(param)
+                } else {
+                    format!("<redacted, {} bytes>", param.len())
+                }
+            })
+            .collect();
+        let query = self.name.to_string();
+        let explain = explain();
+        tokio::spawn(async move {
+            let plan = match tokio::time::timeout(options.explain_timeout, explain).await {
+                Ok(plan) => plan,
+                Err(_) => Err(anyhow!(
+                    "EXPLAIN timed out after {:?}",
+                    options.explain_timeout
+                )),
+            };
+            sink.record(SlowQuery {
+                query,
+                params,
+                latency,
+                plan,
+            });
+        });
+    }
+
+    fn options(&self) -> Option<SlowQueryOptions> {
+        if let Some(options) = OPTIONS.get(self.name) {
+            return Some(*options);
+        }
+        *GLOBAL_OPTIONS.read().expect("lock poisoned")
+    }
+
+    /// Whether the query can be explained now, recording it if so.
+    fn claim(&self, interval: Duration) -> bool {
+        let mut last = self.last_explain.lock().expect("lock poisoned");
+        let now = Instant::now();
+        if let Some(last) = *last {
+            if now.duration_since(last) < interval {
+                return false;
+            }
+        }
+        *last = Some(now);
+        true
+    }
+}
+
+fn truncate_param(param: String) -> String {
+    match param.char_indices().nth(MAX_PARAM_LEN) {
+        Some((end, _)) => format!("{}... ({} bytes)", &param[..end], param.len()),
+        None => param,
+    }
+}
+
+/// Whether `EXPLAIN` should be run with the MySQL syntax.
+pub fn is_mysql(connection: &Connection) -> bool {
+    matches!(connection, Connection::Mysql(_))
+}
+
+/// The plan of `EXPLAIN FORMAT=JSON`.
+pub fn mysql_plan(rows: Vec<(String,)>) -> Vec<String> {
+    rows.into_iter().map(|(plan,)| plan).collect()
+}
+
+/// The details of the rows of `EXPLAIN QUERY PLAN`.
+pub fn sqlite_plan(rows: Vec<(i64, i64, i64, String)>) -> Vec<String> {
+    rows.into_iter().map(|(_, _, _, detail)| detail).collect()
+}
+
+/// The `EXPLAIN` variants of a query, passed to `sql::queries!` with the
+/// `slow_query_explain` feature.
+#[cfg(feature = "slow_query_explain")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! _explain_queries {
+    ($( $tt:tt )*) => {
+        $crate::_macro_internal::queries! { $( $tt )* }
+    };
+}
+
+#[cfg(not(feature = "slow_query_explain"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! _explain_queries {
+    ($( $tt:tt )*) => {};
+}
+
+/// Statements watching the latency of a query, kept with the
+/// `slow_query_explain` feature.
+#[cfg(feature = "slow_query_explain")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! _slow_query {
+    ($( $tt:tt )*) => {
+        $( $tt )*
+    };
+}
+
+#[cfg(not(feature = "slow_query_explain"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! _slow_query {
+    ($( $tt:tt )*) => {};
+}
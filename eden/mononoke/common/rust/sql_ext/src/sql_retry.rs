@@ -0,0 +1,303 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Retries of SQL operations, as done by the queries generated by
+//! `mononoke_queries!`. Code running SQL without the macro, like
+//! transactions, can use `retry_sql_operation` to get the same behavior.
+
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+use anyhow::Result;
+use retry::retry;
+use retry::RetryLogic;
+use sql::rusqlite;
+use tunables::tunables;
+
+use crate::query_policy::QueryKind;
+
+/// Client errors, for connection failures. The OSS client reports them as
+/// I/O errors, see `io_error_errno`.
+const CR_CONN_HOST_ERROR: u32 = 2003;
+const CR_SERVER_GONE_ERROR: u32 = 2006;
+const CR_SERVER_LOST: u32 = 2013;
+
+/// How many times, and how fast, an operation is attempted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SqlRetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub attempts: usize,
+    /// Delay before the second attempt.
+    pub base_delay: Duration,
+    /// Factor the delay is multiplied by for each further attempt.
+    pub factor: f64,
+    /// Upper bound of the random delay added to each delay.
+    pub jitter: Duration,
+}
+
+/// The policy of the queries generated by `mononoke_queries!`.
+// See https://fburl.com/7dmedu1u for backoff reasoning
+pub const DEFAULT_SQL_RETRY_POLICY: SqlRetryPolicy = SqlRetryPolicy {
+    attempts: 2,
+    base_delay: Duration::from_secs(10),
+    factor: 1.2,
+    jitter: Duration::from_secs(5),
+};
+
+impl SqlRetryPolicy {
+    fn retry_logic(&self) -> RetryLogic {
+        RetryLogic::ExponentialWithJitter {
+            base: self.base_delay,
+            factor: self.factor,
+            jitter: self.jitter,
+        }
+    }
+}
+
+/// See https://fburl.com/sv/uk8w71td for error descriptions
+fn retryable_mysql_errno(errno: u32, kind: QueryKind) -> bool {
+    match errno {
+        // Too many connections, or the server can't be reached: the query
+        // didn't even start
+        1040 | CR_CONN_HOST_ERROR => true,
+        // Deadlock error, advice is restarting transaction, so it's retryable
+        1213 => true,
+        // Admission control errors
+        // Safe to retry on writes as well as the query didn't even start
+        1914..=1916 => true,
+        // Lock wait timeout. A write waiting again would keep the locks it
+        // already holds meanwhile, so only reads are retried.
+        1205 => kind == QueryKind::Read,
+        // The connection was lost. A write may have been applied before it
+        // was, so only reads are retried.
+        CR_SERVER_GONE_ERROR | CR_SERVER_LOST => kind == QueryKind::Read,
+        _ => false,
+    }
+}
+
+/// The client error an I/O error of the connection stands for.
+#[cfg_attr(fbcode_build, allow(dead_code))]
+fn io_error_errno(err: &io::Error) -> Option<u32> {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => Some(CR_CONN_HOST_ERROR),
+        io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::UnexpectedEof => Some(CR_SERVER_LOST),
+        _ => None,
+    }
+}
+
+/// Whether a `kind` operation failing with `err` can be attempted again:
+/// MySQL deadlocks, admission control rejections and connection failures
+/// before the query started, busy SQLite databases, and for reads, lock
+/// wait timeouts and lost connections. The cause is looked up in the whole
+/// chain of `err`, so context can be added to it.
+pub fn is_retryable_sql_error(err: &anyhow::Error, kind: QueryKind) -> bool {
+    err.chain().any(|cause| {
+        if let Some(errno) = mock_mysql_errno(cause) {
+            return retryable_mysql_errno(errno, kind);
+        }
+        if let Some(rusqlite::Error::SqliteFailure(error, _)) = cause.downcast_ref() {
+            return error.code == rusqlite::ErrorCode::DatabaseBusy;
+        }
+        mysql_client_errno(cause).map_or(false, |errno| retryable_mysql_errno(errno, kind))
+    })
+}
+
+/// The MySQL error number of `err`, looked up in its whole chain.
+pub(crate) fn mysql_errno(err: &anyhow::Error) -> Option<u32> {
+    err.chain()
+        .find_map(|cause| mock_mysql_errno(cause).or_else(|| mysql_client_errno(cause)))
+}
+
+#[cfg(any(test, feature = "mock"))]
+fn mock_mysql_errno(cause: &(dyn std::error::Error + 'static)) -> Option<u32> {
+    use crate::mock::MockMysqlError;
+    cause
+        .downcast_ref::<MockMysqlError>()
+        .map(|MockMysqlError { errno }| *errno)
+}
+
+#[cfg(not(any(test, feature = "mock")))]
+fn mock_mysql_errno(_cause: &(dyn std::error::Error + 'static)) -> Option<u32> {
+    None
+}
+
+#[cfg(fbcode_build)]
+fn mysql_client_errno(cause: &(dyn std::error::Error + 'static)) -> Option<u32> {
+    use mysql_client::MysqlError;
+    use MysqlError::*;
+    match cause.downcast_ref::<MysqlError>() {
+        Some(ConnectionOperationError { mysql_errno, .. })
+        | Some(QueryResultError { mysql_errno, .. }) => Some(*mysql_errno),
+        _ => None,
+    }
+}
+
+/// Extracts the error number of errors of the `mysql_async` client of OSS
+/// builds.
+#[cfg(not(fbcode_build))]
+fn mysql_client_errno(cause: &(dyn std::error::Error + 'static)) -> Option<u32> {
+    use sql::mysql_async::DriverError;
+    use sql::mysql_async::Error;
+    use sql::mysql_async::IoError;
+
+    match cause.downcast_ref::<Error>() {
+        Some(Error::Server(server)) => Some(server.code as u32),
+        Some(Error::Io(IoError::Io(err))) => io_error_errno(err),
+        Some(Error::Driver(DriverError::ConnectionClosed)) => Some(CR_SERVER_LOST),
+        _ => None,
+    }
+}
+
+/// Run `operation`, and run it again following `policy` as long as it fails
+/// with errors accepted by `is_retryable_sql_error` for `kind`. The
+/// `disable_sql_auto_retries` tunable disables retries.
+///
+/// `operation` must be safe to run again after a failure, e.g. a whole
+/// transaction rather than a single statement of it. Operations that write
+/// anything are `QueryKind::Write`.
+pub async fn retry_sql_operation<T, Fut>(
+    policy: &SqlRetryPolicy,
+    kind: QueryKind,
+    operation: impl Fn() -> Fut + Send + Sync,
+) -> Result<T>
+where
+    T: Send + 'static,
+    Fut: Future<Output = Result<T>>,
+{
+    if tunables().disable_sql_auto_retries().unwrap_or_default() {
+        return operation().await;
+    }
+    Ok(retry(
+        None,
+        |_| operation(),
+        |err| is_retryable_sql_error(err, kind),
+        policy.retry_logic(),
+        policy.attempts,
+    )
+    .await?
+    .0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    use anyhow::anyhow;
+    use anyhow::Context;
+
+    use super::*;
+    use crate::mock::MockMysqlError;
+
+    fn sqlite_error(code: i32) -> anyhow::Error {
+        rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(code), None).into()
+    }
+
+    #[test]
+    fn test_is_retryable_sql_error() {
+        use QueryKind::*;
+
+        let cases = [
+            (MockMysqlError { errno: 1213 }.into(), true, true),
+            (MockMysqlError { errno: 1914 }.into(), true, true),
+            (MockMysqlError { errno: 1916 }.into(), true, true),
+            (MockMysqlError { errno: 1040 }.into(), true, true),
+            (MockMysqlError { errno: 1205 }.into(), true, false),
+            (MockMysqlError { errno: 2006 }.into(), true, false),
+            (MockMysqlError { errno: 2013 }.into(), true, false),
+            (MockMysqlError { errno: 1062 }.into(), false, false),
+            (
+                anyhow::Error::from(MockMysqlError { errno: 1213 }).context("in transaction"),
+                true,
+                true,
+            ),
+            // SQLITE_BUSY
+            (sqlite_error(5), true, true),
+            // SQLITE_CONSTRAINT
+            (sqlite_error(19), false, false),
+            (anyhow!("something else"), false, false),
+        ];
+        for (err, read, write) in cases {
+            assert_eq!(is_retryable_sql_error(&err, Read), read, "{:#}", err);
+            assert_eq!(is_retryable_sql_error(&err, Write), write, "{:#}", err);
+        }
+    }
+
+    #[cfg(not(fbcode_build))]
+    #[test]
+    fn test_is_retryable_mysql_async_error() {
+        use sql::mysql_async::DriverError;
+        use sql::mysql_async::Error;
+        use sql::mysql_async::IoError;
+        use sql::mysql_async::ServerError;
+        use QueryKind::*;
+
+        let server_error = |code| {
+            Error::Server(ServerError {
+                code,
+                message: format!("error {}", code),
+                state: "HY000".to_string(),
+            })
+        };
+        let io_error = |kind| Error::Io(IoError::Io(io::Error::from(kind)));
+        let cases = [
+            (server_error(1213), true, true),
+            (server_error(1040), true, true),
+            (server_error(1205), true, false),
+            (server_error(1062), false, false),
+            (io_error(io::ErrorKind::ConnectionRefused), true, true),
+            (io_error(io::ErrorKind::ConnectionReset), true, false),
+            (io_error(io::ErrorKind::UnexpectedEof), true, false),
+            (io_error(io::ErrorKind::PermissionDenied), false, false),
+            (Error::Driver(DriverError::ConnectionClosed), true, false),
+        ];
+        for (err, read, write) in cases {
+            let err = anyhow::Error::from(err).context("while querying");
+            assert_eq!(is_retryable_sql_error(&err, Read), read, "{:#}", err);
+            assert_eq!(is_retryable_sql_error(&err, Write), write, "{:#}", err);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_sql_operation() -> Result<()> {
+        // Fails with each of `errnos` in turn, then succeeds.
+        async fn run(kind: QueryKind, errnos: &[u32]) -> (Result<usize>, usize) {
+            let attempts = AtomicUsize::new(0);
+            let result = retry_sql_operation(&DEFAULT_SQL_RETRY_POLICY, kind, || async {
+                let attempt = attempts.fetch_add(1, Ordering::Relaxed);
+                match errnos.get(attempt) {
+                    Some(errno) => Err(MockMysqlError { errno: *errno })
+                        .context(format!("attempt {}", attempt)),
+                    None => Ok(attempt),
+                }
+            })
+            .await;
+            (result, attempts.load(Ordering::Relaxed))
+        }
+
+        let (result, attempts) = run(QueryKind::Write, &[1213]).await;
+        assert_eq!(result?, 1);
+        assert_eq!(attempts, 2);
+
+        let (result, attempts) = run(QueryKind::Write, &[1205]).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+
+        let (result, attempts) = run(QueryKind::Read, &[1205]).await;
+        assert_eq!(result?, 1);
+        assert_eq!(attempts, 2);
+
+        let (result, attempts) = run(QueryKind::Read, &[1914, 1914]).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, DEFAULT_SQL_RETRY_POLICY.attempts);
+        Ok(())
+    }
+}
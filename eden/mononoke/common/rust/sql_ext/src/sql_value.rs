@@ -0,0 +1,282 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Conversion of query parameters and result columns.
+//!
+//! Queries take and return any type the `sql` crate converts to and from a
+//! `Value`: parameters must implement `Into<Value>`, and result columns
+//! `FromValue` (and `OptionalTryFromRowField` for the MySQL client). For
+//! newtypes, `sql_newtype!` implements all of them.
+//!
+//! Result columns are read as `SqlColumn`s, which never fail to decode, and
+//! converted by the generated `query` functions, so a column of an
+//! unexpected type fails the query with a `SqlConversionError` rather than
+//! panicking.
+
+use std::any::type_name;
+
+use sql::mysql_async::from_value_opt;
+use sql::mysql_async::prelude::ConvIr;
+use sql::mysql_async::prelude::FromValue;
+use sql::mysql_async::FromValueError;
+use sql::mysql_async::Value;
+use sql::sql_common::mysql::opt_try_from_rowfield;
+use sql::sql_common::mysql::OptionalTryFromRowField;
+use sql::sql_common::mysql::RowField;
+use sql::sql_common::mysql::ValueError;
+use thiserror::Error;
+
+/// How many characters of a value a `SqlConversionError` shows.
+const SUMMARY_LEN: usize = 64;
+
+/// A result column that could not be converted to the type of the query.
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Cannot convert column {column} to {expected}: got {actual}")]
+pub struct SqlConversionError {
+    /// Index of the column in the returned tuple.
+    pub column: usize,
+    pub expected: &'static str,
+    /// A summary of the value, truncated.
+    pub actual: String,
+}
+
+fn summarize(value: &Value) -> String {
+    let summary = match value {
+        Value::NULL => "NULL".to_string(),
+        Value::Bytes(bytes) => match std::str::from_utf8(bytes) {
+            Ok(s) => format!("bytes {:?}", s),
+            Err(_) => format!("{} bytes", bytes.len()),
+        },
+        Value::Int(i) => format!("int {}", i),
+        Value::UInt(u) => format!("uint {}", u),
+        value => format!("{:?}", value),
+    };
+    if summary.len() <= SUMMARY_LEN {
+        return summary;
+    }
+    let mut end = SUMMARY_LEN;
+    while !summary.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &summary[..end])
+}
+
+/// Estimated size of a received value, see `ResultLimits`.
+fn value_size(value: &Value) -> usize {
+    match value {
+        Value::NULL => 0,
+        Value::Bytes(bytes) => bytes.len(),
+        _ => 8,
+    }
+}
+
+/// A result column of type `T`, or the value that could not be converted,
+/// with the estimated size of the value.
+pub struct SqlColumn<T>(Result<T, Value>, usize);
+
+impl<T> SqlColumn<T> {
+    pub fn into_result(self, column: usize) -> Result<T, SqlConversionError> {
+        self.0.map_err(|value| SqlConversionError {
+            column,
+            expected: type_name::<T>(),
+            actual: summarize(&value),
+        })
+    }
+}
+
+pub struct SqlColumnIr<T>(Result<T, Value>, usize);
+
+impl<T: FromValue + Into<Value>> ConvIr<SqlColumn<T>> for SqlColumnIr<T> {
+    fn new(v: Value) -> Result<Self, FromValueError> {
+        let size = value_size(&v);
+        Ok(Self(from_value_opt(v).map_err(|e| e.0), size))
+    }
+
+    fn commit(self) -> SqlColumn<T> {
+        SqlColumn(self.0, self.1)
+    }
+
+    fn rollback(self) -> Value {
+        match self.0 {
+            Ok(value) => value.into(),
+            Err(value) => value,
+        }
+    }
+}
+
+impl<T: FromValue + Into<Value>> FromValue for SqlColumn<T> {
+    type Intermediate = SqlColumnIr<T>;
+}
+
+impl<T: FromValue + Into<Value>> OptionalTryFromRowField for SqlColumn<T> {
+    fn try_from_opt(field: RowField) -> Result<Option<Self>, ValueError> {
+        opt_try_from_rowfield(field)
+    }
+}
+
+/// A row of `SqlColumn`s, see `from_sql_rows`.
+pub trait FromSqlRow {
+    type Row;
+
+    fn from_sql_row(self) -> Result<Self::Row, SqlConversionError>;
+
+    /// Estimated size of the values of the row.
+    fn size(&self) -> usize;
+}
+
+macro_rules! impl_from_sql_row {
+    ($( $t:ident $idx:tt ),*) => {
+        impl<$( $t ),*> FromSqlRow for ($( SqlColumn<$t>, )*) {
+            type Row = ($( $t, )*);
+
+            fn from_sql_row(self) -> Result<Self::Row, SqlConversionError> {
+                Ok(($( self.$idx.into_result($idx)?, )*))
+            }
+
+            fn size(&self) -> usize {
+                [$( self.$idx.1 ),*].iter().sum()
+            }
+        }
+    };
+}
+
+impl_from_sql_row!(A 0);
+impl_from_sql_row!(A 0, B 1);
+impl_from_sql_row!(A 0, B 1, C 2);
+impl_from_sql_row!(A 0, B 1, C 2, D 3);
+impl_from_sql_row!(A 0, B 1, C 2, D 3, E 4);
+impl_from_sql_row!(A 0, B 1, C 2, D 3, E 4, F 5);
+impl_from_sql_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_from_sql_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+impl_from_sql_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8);
+impl_from_sql_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9);
+impl_from_sql_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10);
+impl_from_sql_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11);
+
+/// Convert the rows read by a query.
+pub fn from_sql_rows<R: FromSqlRow>(rows: Vec<R>) -> Result<Vec<R::Row>, SqlConversionError> {
+    rows.into_iter().map(FromSqlRow::from_sql_row).collect()
+}
+
+/// Implement the conversions of a tuple struct wrapping a single value, so
+/// that it can be a parameter or a result column of `mononoke_queries!`.
+///
+/// The wrapped value is either of a type the `sql` crate converts, like an
+/// integer or a `String`, or a byte array, stored as a blob of that length:
+///
+/// ```ignore
+/// pub struct ShardId(i32);
+/// sql_newtype!(ShardId(i32));
+///
+/// pub struct ContentHash([u8; 32]);
+/// sql_newtype!(ContentHash([u8; 32]));
+/// ```
+#[macro_export]
+macro_rules! sql_newtype {
+    ($name:ident([u8; $len:expr])) => {
+        impl ::std::convert::From<$name> for $crate::_macro_internal::Value {
+            fn from(value: $name) -> Self {
+                $crate::_macro_internal::Value::Bytes(value.0.to_vec())
+            }
+        }
+
+        impl $crate::_macro_internal::ConvIr<$name> for $name {
+            fn new(
+                v: $crate::_macro_internal::Value,
+            ) -> ::std::result::Result<Self, $crate::_macro_internal::FromValueError> {
+                match v {
+                    $crate::_macro_internal::Value::Bytes(bytes) => {
+                        match <[u8; $len]>::try_from(bytes.as_slice()) {
+                            Ok(array) => Ok($name(array)),
+                            Err(_) => Err($crate::_macro_internal::FromValueError(
+                                $crate::_macro_internal::Value::Bytes(bytes),
+                            )),
+                        }
+                    }
+                    v => Err($crate::_macro_internal::FromValueError(v)),
+                }
+            }
+
+            fn commit(self) -> Self {
+                self
+            }
+
+            fn rollback(self) -> $crate::_macro_internal::Value {
+                self.into()
+            }
+        }
+
+        $crate::sql_newtype!(@common $name);
+    };
+
+    ($name:ident($inner:ty)) => {
+        impl ::std::convert::From<$name> for $crate::_macro_internal::Value {
+            fn from(value: $name) -> Self {
+                value.0.into()
+            }
+        }
+
+        impl $crate::_macro_internal::ConvIr<$name> for $name {
+            fn new(
+                v: $crate::_macro_internal::Value,
+            ) -> ::std::result::Result<Self, $crate::_macro_internal::FromValueError> {
+                $crate::_macro_internal::from_value_opt::<$inner>(v).map($name)
+            }
+
+            fn commit(self) -> Self {
+                self
+            }
+
+            fn rollback(self) -> $crate::_macro_internal::Value {
+                self.into()
+            }
+        }
+
+        $crate::sql_newtype!(@common $name);
+    };
+
+    (@common $name:ident) => {
+        impl $crate::_macro_internal::FromValue for $name {
+            type Intermediate = $name;
+        }
+
+        impl $crate::_macro_internal::OptionalTryFromRowField for $name {
+            fn try_from_opt(
+                field: $crate::_macro_internal::RowField,
+            ) -> ::std::result::Result<
+                ::std::option::Option<Self>,
+                $crate::_macro_internal::ValueError,
+            > {
+                $crate::_macro_internal::opt_try_from_rowfield(field)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_sql_rows() {
+        let rows = vec![(
+            SqlColumn::<u64>(Ok(1), 8),
+            SqlColumn::<String>(Ok("a".to_string()), 1),
+        )];
+        assert_eq!(rows[0].size(), 9);
+        assert_eq!(from_sql_rows(rows).unwrap(), vec![(1, "a".to_string())]);
+
+        let rows = vec![(
+            SqlColumn::<u64>(Ok(1), 8),
+            SqlColumn::<u64>(Err(Value::Bytes(vec![b'x'; 100])), 100),
+        )];
+        let err = from_sql_rows(rows).unwrap_err();
+        assert_eq!(err.column, 1);
+        assert_eq!(err.expected, "u64");
+        assert_eq!(err.actual, format!("bytes \"{}...", "x".repeat(57)));
+    }
+}
@@ -0,0 +1,248 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::ops::Deref;
+
+use once_cell::sync::OnceCell;
+use sql::Connection;
+use sql::WriteResult;
+
+/// What a write query does, as far as interpreting its affected rows goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteKind {
+    /// `INSERT`.
+    Insert,
+    /// A query declared `insert_or_ignore`.
+    InsertOrIgnore,
+    /// `REPLACE` or `INSERT OR REPLACE`.
+    Replace,
+    /// `INSERT ... ON DUPLICATE KEY UPDATE` or `ON CONFLICT ... DO UPDATE`.
+    Upsert,
+    /// Anything else, like `UPDATE` or `DELETE`.
+    Other,
+}
+
+impl WriteKind {
+    /// Classify `sql`, a write query declared with `query_type` (`none` or
+    /// `insert_or_ignore`).
+    pub fn detect(query_type: &str, sql: &str) -> Self {
+        if query_type == "insert_or_ignore" {
+            return WriteKind::InsertOrIgnore;
+        }
+        let sql = sql
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_uppercase();
+        if sql.starts_with("REPLACE") || sql.starts_with("INSERT OR REPLACE") {
+            WriteKind::Replace
+        } else if sql.contains("ON DUPLICATE KEY UPDATE")
+            || (sql.contains("ON CONFLICT") && sql.contains("DO UPDATE"))
+        {
+            WriteKind::Upsert
+        } else if sql.starts_with("INSERT") {
+            WriteKind::Insert
+        } else {
+            WriteKind::Other
+        }
+    }
+}
+
+/// The kinds of a write query, for each database flavor, declared as a
+/// static by `mononoke_queries!` so its SQL is only classified once.
+pub struct WriteKinds {
+    query_type: &'static str,
+    mysql: &'static str,
+    sqlite: &'static str,
+    kinds: OnceCell<(WriteKind, WriteKind)>,
+}
+
+impl WriteKinds {
+    /// `mysql` and `sqlite` are the query's SQL for each flavor.
+    pub const fn new(query_type: &'static str, mysql: &'static str, sqlite: &'static str) -> Self {
+        Self {
+            query_type,
+            mysql,
+            sqlite,
+            kinds: OnceCell::new(),
+        }
+    }
+
+    fn get(&self, flavor: Flavor) -> WriteKind {
+        let (mysql, sqlite) = *self.kinds.get_or_init(|| {
+            (
+                WriteKind::detect(self.query_type, self.mysql),
+                WriteKind::detect(self.query_type, self.sqlite),
+            )
+        });
+        match flavor {
+            Flavor::Mysql => mysql,
+            Flavor::Sqlite => sqlite,
+        }
+    }
+}
+
+/// What a write did, summarized over all its rows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteOutcome {
+    /// New rows were inserted, and no existing row was changed.
+    Inserted,
+    /// Existing rows were changed, by an upsert, `UPDATE` or `DELETE`.
+    Updated,
+    /// Nothing changed, e.g. all rows of an `insert_or_ignore` were
+    /// duplicates, or an upsert wrote the values already there.
+    NoOp,
+    /// A `REPLACE` wrote rows, replacing some existing ones.
+    Replaced,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Flavor {
+    Mysql,
+    Sqlite,
+}
+
+/// The `WriteResult` of a write query generated by `mononoke_queries!`,
+/// with what is needed to interpret it. Derefs to the `WriteResult`.
+#[derive(Debug)]
+pub struct TypedWriteResult {
+    result: WriteResult,
+    kind: WriteKind,
+    flavor: Flavor,
+    /// Number of rows the query was given, 1 for queries without values.
+    rows: u64,
+}
+
+impl TypedWriteResult {
+    /// The kind of the query is that of the flavor of `connection`.
+    pub fn new(
+        result: WriteResult,
+        connection: &Connection,
+        kinds: &WriteKinds,
+        rows: usize,
+    ) -> Self {
+        let flavor = match connection {
+            Connection::Mysql(_) => Flavor::Mysql,
+            _ => Flavor::Sqlite,
+        };
+        Self {
+            result,
+            kind: kinds.get(flavor),
+            flavor,
+            rows: rows as u64,
+        }
+    }
+
+    pub fn kind(&self) -> WriteKind {
+        self.kind
+    }
+
+    /// The id generated for an inserted row, if a row was inserted into a
+    /// table with an auto-increment column. For a multi-row insert, MySQL
+    /// returns the id of the first row and SQLite that of the last one.
+    pub fn inserted_id(&self) -> Option<u64> {
+        if self.result.affected_rows() == 0 {
+            // SQLite returns the id of the last insert on the connection,
+            // even if this query inserted nothing.
+            return None;
+        }
+        // MySQL returns 0 if no id was generated.
+        self.result.last_insert_id().filter(|id| *id != 0)
+    }
+
+    /// Interprets the affected rows according to the kind of query and the
+    /// database conventions. MySQL counts 2 affected rows for each row
+    /// updated by an upsert or replaced by a `REPLACE`, while SQLite counts
+    /// 1 either way. So on SQLite, upserts that wrote rows are `Updated`,
+    /// and `REPLACE`s `Replaced`, even if they only inserted new rows.
+    ///
+    /// MySQL only reports the total, so an upsert of several rows is
+    /// `Inserted` as long as its affected rows do not exceed its rows: rows
+    /// already holding the upserted values count 0, and make up for as
+    /// many updated rows. Only single row upserts are exact. Every row of a
+    /// `REPLACE` counts at least 1, so it is `Replaced` exactly when it
+    /// replaced some row.
+    pub fn outcome(&self) -> WriteOutcome {
+        let affected = self.result.affected_rows();
+        if affected == 0 {
+            return WriteOutcome::NoOp;
+        }
+        match (self.kind, self.flavor) {
+            (WriteKind::Insert | WriteKind::InsertOrIgnore, _) => WriteOutcome::Inserted,
+            (WriteKind::Other, _) => WriteOutcome::Updated,
+            (WriteKind::Replace, Flavor::Mysql) if affected <= self.rows => WriteOutcome::Inserted,
+            (WriteKind::Replace, _) => WriteOutcome::Replaced,
+            (WriteKind::Upsert, Flavor::Mysql) if affected <= self.rows => WriteOutcome::Inserted,
+            (WriteKind::Upsert, _) => WriteOutcome::Updated,
+        }
+    }
+
+    pub fn into_inner(self) -> WriteResult {
+        self.result
+    }
+}
+
+impl Deref for TypedWriteResult {
+    type Target = WriteResult;
+
+    fn deref(&self) -> &WriteResult {
+        &self.result
+    }
+}
+
+impl From<TypedWriteResult> for WriteResult {
+    fn from(result: TypedWriteResult) -> Self {
+        result.result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            WriteKind::detect("none", "INSERT INTO t (a) VALUES {values}"),
+            WriteKind::Insert
+        );
+        assert_eq!(
+            WriteKind::detect(
+                "insert_or_ignore",
+                "{insert_or_ignore} INTO t VALUES {values}"
+            ),
+            WriteKind::InsertOrIgnore
+        );
+        assert_eq!(
+            WriteKind::detect("none", "replace into t (a) VALUES {values}"),
+            WriteKind::Replace
+        );
+        assert_eq!(
+            WriteKind::detect("none", "INSERT OR REPLACE INTO t (a) VALUES {values}"),
+            WriteKind::Replace
+        );
+        assert_eq!(
+            WriteKind::detect(
+                "none",
+                "INSERT INTO t (a, b) VALUES {values}
+                 ON DUPLICATE   KEY UPDATE b = VALUES(b)"
+            ),
+            WriteKind::Upsert
+        );
+        assert_eq!(
+            WriteKind::detect(
+                "none",
+                "INSERT INTO t (a, b) VALUES {values} ON CONFLICT(a) DO UPDATE SET b = excluded.b"
+            ),
+            WriteKind::Upsert
+        );
+        assert_eq!(
+            WriteKind::detect("none", "  DELETE FROM t WHERE a = {a}"),
+            WriteKind::Other
+        );
+    }
+}
@@ -11,6 +11,8 @@ use anyhow::Result;
 use sql::Connection;
 use sql::SqlConnections;
 use sql::SqlShardedConnections;
+use sql_ext::migrations::Migration;
+use sql_ext::migrations::MigrationRunner;
 use sql_ext::open_existing_sqlite_path;
 use sql_ext::open_sqlite_in_memory;
 use sql_ext::open_sqlite_path;
@@ -27,6 +29,12 @@ pub trait SqlConstruct: Sized + Send + Sync + 'static {
     /// Query used to create an empty instance of the database
     const CREATION_QUERY: &'static str;
 
+    /// Migrations of the schema created by `CREATION_QUERY`, applied by
+    /// the SQLite constructors
+    fn migrations() -> Vec<Migration> {
+        Vec::new()
+    }
+
     /// Construct an instance from SqlConnections
     ///
     /// This function may be called in an async context and must not block.
@@ -36,7 +44,9 @@ pub trait SqlConstruct: Sized + Send + Sync + 'static {
     fn with_sqlite_in_memory() -> Result<Self> {
         let conn = open_sqlite_in_memory()?;
         conn.execute_batch(Self::CREATION_QUERY)?;
-        let connections = SqlConnections::new_single(Connection::with_sqlite(conn));
+        let connection = Connection::with_sqlite(conn);
+        apply_migrations(&connection, &Self::migrations())?;
+        let connections = SqlConnections::new_single(connection);
         Ok(Self::from_sql_connections(connections))
     }
 
@@ -46,6 +56,7 @@ pub trait SqlConstruct: Sized + Send + Sync + 'static {
         let conn = open_sqlite_path(path, false)?;
         conn.execute_batch(Self::CREATION_QUERY)?;
         let write_connection = Connection::with_sqlite(conn);
+        apply_migrations(&write_connection, &Self::migrations())?;
         let read_connection = Connection::with_sqlite(open_existing_sqlite_path(path, true)?);
         let connections = SqlConnections {
             write_connection: if readonly {
@@ -71,6 +82,12 @@ pub trait SqlShardedConstruct: Sized + Send + Sync + 'static {
     /// Query used to create an empty instance of a shard
     const CREATION_QUERY: &'static str;
 
+    /// Migrations of the schema of each shard, see
+    /// `SqlConstruct::migrations`
+    fn migrations() -> Vec<Migration> {
+        Vec::new()
+    }
+
     /// Construct an instance from a vector of SqlConnections, one for each shard
     ///
     /// This function may be called in an async context and must not block.
@@ -84,6 +101,10 @@ where
     const LABEL: &'static str = <T as SqlShardedConstruct>::LABEL;
     const CREATION_QUERY: &'static str = <T as SqlShardedConstruct>::CREATION_QUERY;
 
+    fn migrations() -> Vec<Migration> {
+        <T as SqlShardedConstruct>::migrations()
+    }
+
     fn from_sql_connections(conns: SqlConnections) -> Self {
         Self::from_sql_shard_connections(SqlShardedConnections {
             write_connections: vec1![conns.write_connection],
@@ -92,3 +113,24 @@ where
         })
     }
 }
+
+/// The SQLite constructors are synchronous, and may be called from a
+/// runtime, where blocking on the runner in place would stall or panic. It
+/// runs on a thread of its own, with a runtime of its own.
+fn apply_migrations(connection: &Connection, migrations: &[Migration]) -> Result<()> {
+    if migrations.is_empty() {
+        return Ok(());
+    }
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| -> Result<()> {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?;
+                runtime.block_on(MigrationRunner::ensure(connection, migrations))?;
+                Ok(())
+            })
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
@@ -28,3 +28,8 @@ pub const FS_CSID: ChangesetId = ChangesetId::new(hash::FS);
 
 // Definition for the hash ff...ffee..eee
 pub const FS_ES_CSID: ChangesetId = ChangesetId::new(hash::FS_ES);
+
+/// Generate a changeset ID from a number.
+pub fn make_changeset_id(number: u64) -> ChangesetId {
+    ChangesetId::new(hash::make_hash(b"bonsai", number))
+}
@@ -33,6 +33,19 @@ pub const FS_ES: Blake2 = Blake2::from_byte_array([
     0xee, 0xee, 0xee, 0xee, 0xee, 0xee, 0xee, 0xee, 0xee, 0xee, 0xee, 0xee, 0xee, 0xee, 0xee, 0xee,
 ]);
 
+/// Synthesize a hash from a byte array prefix (of up to 24 bytes) and a number.
+///
+/// Generates a hash where the first 24 bytes are the byte array prefix padded
+/// with 0, and the following 8 bytes are the big-endian value of the number.
+pub fn make_hash(prefix: &'static [u8], number: u64) -> Blake2 {
+    let mut buffer = [0u8; 32];
+    const NUMBER_OFFSET: usize = 32 - std::mem::size_of::<u64>();
+    let prefix_len = prefix.len().min(NUMBER_OFFSET);
+    buffer[..prefix_len].copy_from_slice(&prefix[..prefix_len]);
+    buffer[NUMBER_OFFSET..].copy_from_slice(&number.to_be_bytes());
+    Blake2::from_byte_array(buffer)
+}
+
 // Definitions for SHA-1 hashes 1111...1111 to ffff...ffff.
 
 pub const ONES_GIT_SHA1: GitSha1 = GitSha1::from_byte_array([0x11; 20]);
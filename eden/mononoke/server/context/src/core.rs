@@ -18,6 +18,7 @@ use crate::logging::LoggingContainer;
 use crate::logging::SamplingKey;
 use crate::perf_counters::PerfCounters;
 use crate::perf_counters_stack::PerfCountersStack;
+use crate::request_class::RequestClass;
 use crate::session::SessionClass;
 use crate::session::SessionContainer;
 
@@ -131,6 +132,10 @@ impl CoreContext {
         &mut self.session
     }
 
+    pub fn request_class(&self) -> RequestClass {
+        self.session.request_class()
+    }
+
     pub fn scribe(&self) -> &Scribe {
         self.logging.scribe()
     }
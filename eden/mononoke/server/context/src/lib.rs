@@ -15,6 +15,8 @@ pub use crate::logging::LoggingContainer;
 pub use crate::logging::SamplingKey;
 pub use crate::perf_counters::PerfCounterType;
 pub use crate::perf_counters::PerfCounters;
+pub use crate::request_class::with_request_class;
+pub use crate::request_class::RequestClass;
 pub use crate::session::SessionClass;
 pub use crate::session::SessionContainer;
 pub use crate::session::SessionContainerBuilder;
@@ -23,4 +25,5 @@ mod core;
 mod logging;
 mod perf_counters;
 mod perf_counters_stack;
+mod request_class;
 mod session;
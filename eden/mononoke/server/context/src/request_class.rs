@@ -0,0 +1,48 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::fmt;
+
+use crate::core::CoreContext;
+
+/// The priority of the requests of a context, for the blobstores that
+/// schedule them. Contexts are `Interactive` unless tagged otherwise with
+/// `with_request_class`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum RequestClass {
+    /// Someone is waiting for the request to complete.
+    #[default]
+    Interactive,
+    /// Bulk work, like backfilling, that should yield to interactive work.
+    Background,
+    /// Work that should only use capacity nobody else needs, like scrubbing.
+    Scavenger,
+}
+
+impl RequestClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RequestClass::Interactive => "interactive",
+            RequestClass::Background => "background",
+            RequestClass::Scavenger => "scavenger",
+        }
+    }
+}
+
+impl fmt::Display for RequestClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A copy of `ctx` whose requests are of class `class`, e.g. for the job
+/// frameworks to tag the contexts of their jobs.
+pub fn with_request_class(ctx: &CoreContext, class: RequestClass) -> CoreContext {
+    let mut ctx = ctx.clone();
+    ctx.session_mut().override_request_class(class);
+    ctx
+}
@@ -18,11 +18,13 @@ use rate_limiting::BoxRateLimiter;
 use super::SessionClass;
 use super::SessionContainer;
 use super::SessionContainerInner;
+use crate::request_class::RequestClass;
 
 pub struct SessionContainerBuilder {
     fb: FacebookInit,
     inner: SessionContainerInner,
     session_class: SessionClass,
+    request_class: RequestClass,
 }
 
 impl SessionContainerBuilder {
@@ -31,6 +33,7 @@ impl SessionContainerBuilder {
             fb: self.fb,
             inner: Arc::new(self.inner),
             session_class: self.session_class,
+            request_class: self.request_class,
         }
     }
 
@@ -45,6 +48,7 @@ impl SessionContainerBuilder {
                 readonly: false,
             },
             session_class: SessionClass::UserWaiting,
+            request_class: RequestClass::default(),
         }
     }
 
@@ -88,6 +92,11 @@ impl SessionContainerBuilder {
         self
     }
 
+    pub fn request_class(mut self, value: RequestClass) -> Self {
+        self.request_class = value;
+        self
+    }
+
     pub fn readonly(mut self, readonly: bool) -> Self {
         self.inner.readonly = readonly;
         self
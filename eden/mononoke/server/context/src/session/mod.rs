@@ -23,6 +23,7 @@ use slog::Logger;
 pub use self::builder::SessionContainerBuilder;
 use crate::core::CoreContext;
 use crate::logging::LoggingContainer;
+use crate::request_class::RequestClass;
 
 mod builder;
 
@@ -31,6 +32,7 @@ pub struct SessionContainer {
     fb: FacebookInit,
     inner: Arc<SessionContainerInner>,
     session_class: SessionClass,
+    request_class: RequestClass,
 }
 
 /// Represents the reason this session is running
@@ -151,4 +153,12 @@ impl SessionContainer {
     pub fn override_session_class(&mut self, session_class: SessionClass) {
         self.session_class = session_class;
     }
+
+    pub fn request_class(&self) -> RequestClass {
+        self.request_class
+    }
+
+    pub fn override_request_class(&mut self, request_class: RequestClass) {
+        self.request_class = request_class;
+    }
 }
@@ -127,9 +127,7 @@ py_class!(class checkoutplan |py| {
     def apply(&self, store: ImplInto<ArcReadFileContents>) -> PyResult<PyNone> {
         let plan = self.plan(py);
         let store = store.into();
-        py.allow_threads(|| try_block_unless_interrupted(
-            plan.apply_store(store.as_ref())
-        )).map_pyerr(py)?;
+        py.allow_threads(|| plan.blocking_apply_store(store.as_ref())).map_pyerr(py)?;
         Ok(PyNone)
     }
 
@@ -13,6 +13,8 @@ use std::ops::DerefMut;
 use std::sync::Arc;
 
 use anyhow::Result;
+use futures::Stream;
+use futures::TryStreamExt;
 use manifest::DiffEntry;
 use manifest::DiffType;
 use manifest::FileMetadata;
@@ -53,38 +55,26 @@ impl ActionMap {
         let mut map = HashMap::new();
         for entry in diff {
             let entry = entry?;
-            match entry.diff_type {
-                DiffType::LeftOnly(_) => {
-                    map.insert(entry.path, Action::Remove);
-                }
-                DiffType::RightOnly(meta) => {
-                    if meta.file_type != FileType::GitSubmodule {
-                        map.insert(entry.path, Action::Update(UpdateAction::new(None, meta)));
-                    }
-                }
-                DiffType::Changed(old, new) => {
-                    match (old.hgid == new.hgid, old.file_type, new.file_type) {
-                        (true, FileType::Executable, FileType::Regular) => {
-                            map.insert(entry.path, Action::UpdateExec(false));
-                        }
-                        (true, FileType::Regular, FileType::Executable) => {
-                            map.insert(entry.path, Action::UpdateExec(true));
-                        }
-                        _ => {
-                            if new.file_type != FileType::GitSubmodule {
-                                map.insert(
-                                    entry.path,
-                                    Action::Update(UpdateAction::new(Some(old), new)),
-                                );
-                            }
-                        }
-                    }
-                }
+            if let Some(action) = Action::from_diff_type(entry.diff_type) {
+                map.insert(entry.path, action);
             }
         }
         Ok(Self { map })
     }
 
+    /// Same as `from_diff`, for diffs produced asynchronously.
+    pub async fn from_diff_stream(diff: impl Stream<Item = Result<DiffEntry>>) -> Result<Self> {
+        let map = diff
+            .try_fold(HashMap::new(), |mut map, entry| async move {
+                if let Some(action) = Action::from_diff_type(entry.diff_type) {
+                    map.insert(entry.path, action);
+                }
+                Ok(map)
+            })
+            .await?;
+        Ok(Self { map })
+    }
+
     pub fn with_sparse_profile_change<
         M1: 'static + Matcher + Send + Sync,
         M2: 'static + Matcher + Send + Sync,
@@ -196,6 +186,37 @@ impl fmt::Display for ActionMap {
 }
 
 impl Action {
+    /// The action needed to apply a single diff entry, if any.
+    pub(crate) fn from_diff_type(diff_type: DiffType) -> Option<Self> {
+        match diff_type {
+            DiffType::LeftOnly(_) => Some(Action::Remove),
+            DiffType::RightOnly(meta) => {
+                if meta.file_type != FileType::GitSubmodule {
+                    Some(Action::Update(UpdateAction::new(None, meta)))
+                } else {
+                    None
+                }
+            }
+            DiffType::Changed(old, new) => {
+                match (old.hgid == new.hgid, old.file_type, new.file_type) {
+                    (true, FileType::Executable, FileType::Regular) => {
+                        Some(Action::UpdateExec(false))
+                    }
+                    (true, FileType::Regular, FileType::Executable) => {
+                        Some(Action::UpdateExec(true))
+                    }
+                    _ => {
+                        if new.file_type != FileType::GitSubmodule {
+                            Some(Action::Update(UpdateAction::new(Some(old), new)))
+                        } else {
+                            None
+                        }
+                    }
+                }
+            }
+        }
+    }
+
     pub fn pymerge_action(&self) -> (&'static str, (&'static str, bool), &'static str) {
         match self {
             Action::Update(up) => ("g", (pyflags(&up.to.file_type), false), "created/changed"),
@@ -0,0 +1,233 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Canonical form of a `CheckoutPlan`, so that the plans built from the
+//! same pair of commits compare equal byte for byte, whatever order the
+//! diff was iterated in.
+
+use std::cmp::Ordering;
+
+use manifest::FileType;
+use serde::Serialize;
+use sha2::Digest;
+use types::RepoPathBuf;
+use types::Sha256;
+
+use crate::CheckoutPlan;
+use crate::UpdateContentAction;
+use crate::UpdateMetaAction;
+
+/// Version of the canonical encoding, part of the encoding itself.
+const CANONICAL_VERSION: u32 = 1;
+
+pub(crate) fn cmp_update_content(a: &UpdateContentAction, b: &UpdateContentAction) -> Ordering {
+    a.path
+        .cmp(&b.path)
+        .then_with(|| a.content_hgid.cmp(&b.content_hgid))
+        .then_with(|| a.file_type.cmp(&b.file_type))
+        .then_with(|| a.new_file.cmp(&b.new_file))
+}
+
+pub(crate) fn cmp_update_meta(a: &UpdateMetaAction, b: &UpdateMetaAction) -> Ordering {
+    a.path
+        .cmp(&b.path)
+        .then_with(|| a.set_x_flag.cmp(&b.set_x_flag))
+}
+
+#[derive(Serialize)]
+struct CanonicalPlan<'a> {
+    version: u32,
+    remove: Vec<&'a RepoPathBuf>,
+    update_content: Vec<CanonicalUpdateContent<'a>>,
+    update_meta: Vec<CanonicalUpdateMeta<'a>>,
+}
+
+#[derive(Serialize)]
+struct CanonicalUpdateContent<'a> {
+    path: &'a RepoPathBuf,
+    hgid: String,
+    file_type: &'static str,
+    new_file: bool,
+}
+
+#[derive(Serialize)]
+struct CanonicalUpdateMeta<'a> {
+    path: &'a RepoPathBuf,
+    executable: bool,
+}
+
+fn file_type_name(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::Regular => "regular",
+        FileType::Executable => "executable",
+        FileType::Symlink => "symlink",
+        FileType::GitSubmodule => "gitsubmodule",
+    }
+}
+
+impl CheckoutPlan {
+    /// Sorts each list of actions of the plan by path, in the order of
+    /// `RepoPath`: component by component, comparing the UTF-8 bytes of
+    /// components, so the files of a directory come right after it.
+    ///
+    /// Plans made from an `ActionMap` have at most one action per path and
+    /// list, so ties only happen in plans built otherwise. They are broken
+    /// by the rest of the action: content id, then file type, then new
+    /// files last for content updates, and removal of the exec bit first
+    /// for exec bit updates.
+    ///
+    /// This does not change what the plan does, nor the order it is applied
+    /// in, see `Checkout::with_deterministic` for that.
+    pub fn normalize(&mut self) {
+        self.remove.sort();
+        self.update_content.sort_by(cmp_update_content);
+        self.filtered_update_content.sort_by(cmp_update_content);
+        self.update_meta.sort_by(cmp_update_meta);
+    }
+
+    /// The plan in a canonical encoding: compact JSON of the removed paths,
+    /// content updates and exec bit updates, each in the order of
+    /// `normalize`, whether or not the plan was normalized. Plans doing the
+    /// same thing have the same encoding.
+    ///
+    /// Files already written by an interrupted checkout, see `add_progress`,
+    /// are still included.
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut remove: Vec<_> = self.remove.iter().collect();
+        remove.sort();
+        let mut update_content: Vec<_> = self.update_content.iter().collect();
+        update_content.sort_by(|a, b| cmp_update_content(a, b));
+        let mut update_meta: Vec<_> = self.update_meta.iter().collect();
+        update_meta.sort_by(|a, b| cmp_update_meta(a, b));
+
+        let plan = CanonicalPlan {
+            version: CANONICAL_VERSION,
+            remove,
+            update_content: update_content
+                .into_iter()
+                .map(|u| CanonicalUpdateContent {
+                    path: &u.path,
+                    hgid: u.content_hgid.to_hex(),
+                    file_type: file_type_name(u.file_type),
+                    new_file: u.new_file,
+                })
+                .collect(),
+            update_meta: update_meta
+                .into_iter()
+                .map(|u| CanonicalUpdateMeta {
+                    path: &u.path,
+                    executable: u.set_x_flag,
+                })
+                .collect(),
+        };
+        serde_json::to_vec(&plan).expect("canonical plan is serializable")
+    }
+
+    /// SHA-256 of `canonical_bytes`, to cheaply check that two plans are
+    /// the same.
+    pub fn plan_digest(&self) -> Sha256 {
+        let digest: [u8; Sha256::len()] = sha2::Sha256::digest(self.canonical_bytes()).into();
+        Sha256::from(&digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use manifest::DiffEntry;
+    use manifest::DiffType;
+    use manifest::FileMetadata;
+    use types::HgId;
+    use vfs::VFS;
+
+    use super::*;
+    use crate::ActionMap;
+    use crate::Checkout;
+
+    fn rp(p: &str) -> RepoPathBuf {
+        RepoPathBuf::from_string(p.to_string()).unwrap()
+    }
+
+    fn hgid(p: u8) -> HgId {
+        let mut r = HgId::default().into_byte_array();
+        r[0] = p;
+        HgId::from_byte_array(r)
+    }
+
+    fn diff() -> Vec<DiffEntry> {
+        let mut entries = Vec::new();
+        for i in 0..20u8 {
+            let path = rp(&format!("dir{}/file{}", i % 3, i));
+            let diff_type = match i % 4 {
+                0 => DiffType::LeftOnly(FileMetadata::regular(hgid(i))),
+                1 => DiffType::RightOnly(FileMetadata::symlink(hgid(i))),
+                2 => DiffType::Changed(
+                    FileMetadata::regular(hgid(i)),
+                    FileMetadata::executable(hgid(i)),
+                ),
+                _ => DiffType::Changed(
+                    FileMetadata::regular(hgid(i)),
+                    FileMetadata::regular(hgid(i + 100)),
+                ),
+            };
+            entries.push(DiffEntry::new(path, diff_type));
+        }
+        entries
+    }
+
+    fn plan(vfs: &VFS, diff: Vec<DiffEntry>) -> Result<CheckoutPlan> {
+        let map = ActionMap::from_diff(diff.into_iter().map(Ok))?;
+        Ok(Checkout::default_config(vfs.clone()).plan_action_map(map))
+    }
+
+    #[test]
+    fn test_plan_digest() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+
+        let forward = diff();
+        let mut reversed = diff();
+        reversed.reverse();
+        let mut rotated = diff();
+        rotated.rotate_left(7);
+
+        let mut plans = vec![
+            plan(&vfs, forward)?,
+            plan(&vfs, reversed)?,
+            plan(&vfs, rotated)?,
+        ];
+        let digest = plans[0].plan_digest();
+        let bytes = plans[0].canonical_bytes();
+        for plan in plans.iter_mut() {
+            assert_eq!(plan.plan_digest(), digest);
+            plan.normalize();
+            assert_eq!(plan.canonical_bytes(), bytes);
+            assert_eq!(plan.plan_digest(), digest);
+        }
+        // Normalized plans also list their actions in the same order.
+        assert_eq!(plans[0].to_string(), plans[1].to_string());
+        assert_eq!(plans[0].to_string(), plans[2].to_string());
+        let removed: Vec<_> = plans[0].removed_files().cloned().collect();
+        let mut sorted = removed.clone();
+        sorted.sort();
+        assert_eq!(removed, sorted);
+
+        let json: serde_json::Value = serde_json::from_slice(&bytes)?;
+        assert_eq!(json["version"], 1);
+        assert_eq!(json["remove"][0], "dir0/file0");
+        assert_eq!(json["update_content"][0]["path"], "dir0/file15");
+        assert_eq!(json["update_content"][0]["hgid"], hgid(115).to_hex());
+        assert_eq!(json["update_meta"][0]["path"], "dir0/file18");
+        assert_eq!(json["update_meta"][0]["executable"], true);
+
+        // A different plan has a different digest.
+        let mut changed = diff();
+        changed.pop();
+        assert_ne!(plan(&vfs, changed)?.plan_digest(), digest);
+        Ok(())
+    }
+}
@@ -0,0 +1,373 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Fixes up working copy entries whose names differ from the checkout plan
+//! only by case.
+//!
+//! On a case-insensitive filesystem, writing `foo.txt` over an existing
+//! `Foo.txt` reuses the existing directory entry, so the working copy keeps
+//! the old casing. Before writing, we rename such entries to the exact casing
+//! in the plan, or remove them if the plan removes them anyway.
+
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+
+use anyhow::Context;
+use anyhow::Result;
+use serde::Serialize;
+use types::PathComponent;
+use types::RepoPath;
+use types::RepoPathBuf;
+use vfs::VFS;
+
+/// Whether checkout renames on-disk entries to the plan's exact casing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaseNormalization {
+    /// Normalize if the working copy filesystem is case-insensitive.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl CaseNormalization {
+    pub(crate) fn enabled(self, vfs: &VFS) -> bool {
+        match self {
+            CaseNormalization::Auto => !vfs.case_sensitive(),
+            CaseNormalization::Always => true,
+            CaseNormalization::Never => false,
+        }
+    }
+}
+
+/// The checkout plan writes paths that would end up as the same file on a
+/// case-insensitive filesystem.
+#[derive(Debug)]
+pub struct CaseCollisionError {
+    pub pairs: Vec<(RepoPathBuf, RepoPathBuf)>,
+}
+
+impl fmt::Display for CaseCollisionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "checkout would write paths differing only by case:")?;
+        for (a, b) in &self.pairs {
+            write!(f, " ({}, {})", a, b)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CaseCollisionError {}
+
+/// Returns an error listing every pair of `paths` (or their parent
+/// directories) that only differ by case.
+pub(crate) fn check_case_collisions<'a>(
+    paths: impl Iterator<Item = &'a RepoPathBuf>,
+) -> Result<(), CaseCollisionError> {
+    // Lower cased file or directory -> exact casing, and the first plan
+    // entry it came from.
+    let mut seen: HashMap<RepoPathBuf, (&RepoPath, &RepoPathBuf)> = HashMap::new();
+    let mut pairs = BTreeSet::new();
+    for path in paths {
+        for prefix in path.parents().skip(1).chain(Some(path.as_repo_path())) {
+            let (exact, first) = *seen.entry(prefix.to_lower_case()).or_insert((prefix, path));
+            if exact != prefix {
+                pairs.insert((first.clone(), path.clone()));
+            }
+        }
+    }
+    if pairs.is_empty() {
+        Ok(())
+    } else {
+        Err(CaseCollisionError {
+            pairs: pairs.into_iter().collect(),
+        })
+    }
+}
+
+/// Renames on-disk entries along each of `targets` that match a component
+/// case-insensitively, but not exactly. If a mismatched file is in `remove`,
+/// it is removed instead. Returns the number of entries renamed or removed.
+pub(crate) fn normalize_case(
+    vfs: &VFS,
+    targets: &[RepoPathBuf],
+    remove: &HashSet<RepoPathBuf>,
+) -> Result<usize> {
+    let mut listings = DirListings::default();
+    let mut count = 0;
+    for target in targets {
+        for (dir, name) in target.parents().zip(target.components()) {
+            let actual = match listings.find_case_mismatch(vfs, dir, name.as_str())? {
+                Probe::Exact => continue,
+                Probe::Missing => break,
+                Probe::Mismatch(actual) => actual,
+            };
+
+            let mut actual_path = dir.to_owned();
+            actual_path.push(PathComponent::from_str(&actual)?);
+            let mut exact_path = dir.to_owned();
+            exact_path.push(name);
+
+            if remove.contains(&actual_path) {
+                vfs.remove(&actual_path).with_context(|| {
+                    format!("Removing {} before writing {}", actual_path, target)
+                })?;
+                // Removing may also have removed now empty parent directories.
+                listings.clear();
+                count += 1;
+                break;
+            }
+
+            fs::rename(vfs.join(&actual_path), vfs.join(&exact_path))
+                .with_context(|| format!("Renaming {} to {}", actual_path, exact_path))?;
+            // Renames are rare, so just start over rather than fixing up
+            // listings below the renamed entry.
+            listings.clear();
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+enum Probe {
+    /// An entry with the exact name exists.
+    Exact,
+    /// No entry matches, even ignoring case.
+    Missing,
+    /// The only entry matching ignoring case has this name.
+    Mismatch(String),
+}
+
+/// Cache of directory contents, keyed by lower cased names.
+#[derive(Default)]
+struct DirListings {
+    dirs: HashMap<RepoPathBuf, Option<HashMap<String, Vec<String>>>>,
+}
+
+impl DirListings {
+    fn find_case_mismatch(&mut self, vfs: &VFS, dir: &RepoPath, name: &str) -> Result<Probe> {
+        let listing = match self.listing(vfs, dir)? {
+            Some(listing) => listing,
+            None => return Ok(Probe::Missing),
+        };
+        let candidates = match listing.get(&name.to_lowercase()) {
+            Some(candidates) => candidates,
+            None => return Ok(Probe::Missing),
+        };
+        if candidates.iter().any(|c| c == name) {
+            return Ok(Probe::Exact);
+        }
+        // More than one candidate can only happen if the filesystem is in
+        // fact case-sensitive. Pick one deterministically.
+        Ok(Probe::Mismatch(candidates.iter().min().unwrap().clone()))
+    }
+
+    fn listing(
+        &mut self,
+        vfs: &VFS,
+        dir: &RepoPath,
+    ) -> Result<Option<&HashMap<String, Vec<String>>>> {
+        if !self.dirs.contains_key(dir) {
+            let listing = read_dir_ignorecase(vfs, dir)?;
+            self.dirs.insert(dir.to_owned(), listing);
+        }
+        Ok(self.dirs[dir].as_ref())
+    }
+
+    fn clear(&mut self) {
+        self.dirs.clear();
+    }
+}
+
+/// Lists `dir` grouped by lower cased name. Returns `None` if `dir` is not a
+/// directory. Symlinks are not followed.
+fn read_dir_ignorecase(vfs: &VFS, dir: &RepoPath) -> Result<Option<HashMap<String, Vec<String>>>> {
+    let path = vfs.join(dir);
+    match fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => return Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Reading {}", path.display())),
+    }
+    let mut listing: HashMap<String, Vec<String>> = HashMap::new();
+    for entry in fs::read_dir(&path).with_context(|| format!("Reading {}", path.display()))? {
+        let entry = entry?;
+        if let Ok(name) = entry.file_name().into_string() {
+            listing.entry(name.to_lowercase()).or_default().push(name);
+        }
+    }
+    Ok(Some(listing))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+
+    use futures::stream;
+    use futures::stream::BoxStream;
+    use futures::StreamExt;
+    use manifest::FileMetadata;
+    use minibytes::Bytes;
+    use storemodel::ReadFileContents;
+    use tempfile::TempDir;
+    use types::HgId;
+    use types::Key;
+
+    use super::*;
+    use crate::actions::UpdateAction;
+    use crate::Action;
+    use crate::ActionMap;
+    use crate::Checkout;
+    use crate::CheckoutError;
+
+    struct HgIdStore;
+
+    #[async_trait::async_trait]
+    impl ReadFileContents for HgIdStore {
+        type Error = anyhow::Error;
+
+        async fn read_file_contents(&self, keys: Vec<Key>) -> BoxStream<Result<(Bytes, Key)>> {
+            stream::iter(keys)
+                .map(|key| Ok((key.hgid.to_string().into_bytes().into(), key)))
+                .boxed()
+        }
+
+        async fn read_rename_metadata(
+            &self,
+            _keys: Vec<Key>,
+        ) -> BoxStream<Result<(Key, Option<Key>), Self::Error>> {
+            stream::empty().boxed()
+        }
+    }
+
+    fn rp(p: &str) -> RepoPathBuf {
+        RepoPathBuf::from_string(p.to_string()).unwrap()
+    }
+
+    fn update(map: &mut ActionMap, path: &str) {
+        let meta = FileMetadata::regular(HgId::from_byte_array([1; HgId::len()]));
+        map.insert(rp(path), Action::Update(UpdateAction::new(None, meta)));
+    }
+
+    /// Lists `dir` on disk, as a stand-in for a case-insensitive lookup on
+    /// a case-sensitive test filesystem.
+    fn names(dir: &std::path::Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn checkout(tempdir: &TempDir) -> Result<Checkout> {
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        Ok(Checkout::default_config(vfs).with_case_normalization(CaseNormalization::Always))
+    }
+
+    #[tokio::test]
+    async fn test_rename_file() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        fs::write(tempdir.path().join("Foo.txt"), b"old")?;
+        let mut map = ActionMap::empty();
+        update(&mut map, "foo.txt");
+
+        let plan = checkout(&tempdir)?.plan_action_map(map);
+        let stats = plan.apply_store(&HgIdStore).await?;
+
+        assert_eq!(stats.case_renamed.load(Ordering::Relaxed), 1);
+        assert_eq!(names(tempdir.path()), vec!["foo.txt"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rename_directory() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        fs::create_dir(tempdir.path().join("DIR"))?;
+        fs::write(tempdir.path().join("DIR/File"), b"old")?;
+        fs::write(tempdir.path().join("DIR/other"), b"other")?;
+        let mut map = ActionMap::empty();
+        update(&mut map, "dir/file");
+
+        let plan = checkout(&tempdir)?.plan_action_map(map);
+        let stats = plan.apply_store(&HgIdStore).await?;
+
+        assert_eq!(stats.case_renamed.load(Ordering::Relaxed), 2);
+        assert_eq!(names(tempdir.path()), vec!["dir"]);
+        assert_eq!(names(&tempdir.path().join("dir")), vec!["file", "other"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remove_mismatched() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        fs::write(tempdir.path().join("Foo.txt"), b"old")?;
+        let mut map = ActionMap::empty();
+        map.insert(rp("Foo.txt"), Action::Remove);
+        update(&mut map, "foo.txt");
+
+        let plan = checkout(&tempdir)?.plan_action_map(map);
+        let stats = plan.apply_store(&HgIdStore).await?;
+
+        assert_eq!(stats.case_renamed.load(Ordering::Relaxed), 1);
+        assert_eq!(names(tempdir.path()), vec!["foo.txt"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_never() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        fs::write(tempdir.path().join("Foo.txt"), b"old")?;
+        let mut map = ActionMap::empty();
+        update(&mut map, "foo.txt");
+
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        let plan = Checkout::default_config(vfs)
+            .with_case_normalization(CaseNormalization::Never)
+            .plan_action_map(map);
+        let stats = plan.apply_store(&HgIdStore).await?;
+
+        assert_eq!(stats.case_renamed.load(Ordering::Relaxed), 0);
+        assert!(names(tempdir.path()).contains(&"Foo.txt".to_string()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_collision() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let mut map = ActionMap::empty();
+        update(&mut map, "a/foo");
+        update(&mut map, "a/FOO");
+        update(&mut map, "b/x");
+
+        let plan = checkout(&tempdir)?.plan_action_map(map);
+        let err = match plan.apply_store(&HgIdStore).await {
+            Err(CheckoutError::CaseCollision(err)) => err,
+            other => panic!("expected a case collision, got {:?}", other.err()),
+        };
+
+        assert_eq!(err.pairs.len(), 1);
+        let (a, b) = &err.pairs[0];
+        let mut pair = vec![a.as_str(), b.as_str()];
+        pair.sort();
+        assert_eq!(pair, vec!["a/FOO", "a/foo"]);
+        assert!(names(tempdir.path()).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_collision() {
+        let paths = vec![rp("A/x"), rp("a/y"), rp("b")];
+        let err = check_case_collisions(paths.iter()).unwrap_err();
+        assert_eq!(err.pairs, vec![(rp("A/x"), rp("a/y"))]);
+    }
+}
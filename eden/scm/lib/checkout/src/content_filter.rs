@@ -0,0 +1,367 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Filters of file content applied as files are written, like line ending
+//! conversion, see `Checkout::with_content_filter`.
+//!
+//! Only what is written to the working copy is filtered. Content is fetched,
+//! and checked against its hash by the store, as it is in the repository.
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::Result;
+use manifest::FileType;
+use minibytes::Bytes;
+use types::RepoPath;
+use types::RepoPathBuf;
+use vfs::BatchFailure;
+use vfs::UpdateFlag;
+
+use crate::spawner::run_blocking;
+use crate::spawner::BlockingSpawner;
+
+/// Transforms the content of files before they are written.
+pub trait ContentFilter: Send + Sync {
+    /// Whether to call `filter` for `path`. Called for every file written,
+    /// so it should be cheap.
+    fn wants(&self, path: &RepoPath) -> bool;
+
+    /// The content to write at `path` instead of `data`, the content in the
+    /// repository. Only called for regular and executable files.
+    fn filter(&self, path: &RepoPath, data: Vec<u8>, file_type: FileType) -> Result<FilterOutcome>;
+}
+
+pub enum FilterOutcome {
+    /// Write these bytes instead.
+    Filtered(Vec<u8>),
+    /// Write the content unchanged.
+    PassThrough,
+}
+
+/// The content `filter` writes at `path` with `flag`, in place of `data`.
+/// Symlinks are never filtered.
+pub(crate) fn filter_content(
+    filter: &dyn ContentFilter,
+    path: &RepoPath,
+    data: Bytes,
+    flag: UpdateFlag,
+) -> Result<Bytes> {
+    let file_type = match flag {
+        UpdateFlag::Regular => FileType::Regular,
+        UpdateFlag::Executable => FileType::Executable,
+        UpdateFlag::Symlink => return Ok(data),
+    };
+    if !filter.wants(path) {
+        return Ok(data);
+    }
+    match filter.filter(path, data.to_vec(), file_type)? {
+        FilterOutcome::Filtered(filtered) => Ok(filtered.into()),
+        FilterOutcome::PassThrough => Ok(data),
+    }
+}
+
+/// The content filter of a checkout, and how much content it was given.
+pub(crate) struct ContentFiltering {
+    filter: Arc<dyn ContentFilter>,
+    spawner: Arc<dyn BlockingSpawner>,
+    /// Set if `Checkout::with_content_filter` reports the unfiltered size.
+    unfiltered_bytes: Option<AtomicUsize>,
+}
+
+impl ContentFiltering {
+    pub(crate) fn new(
+        filter: Arc<dyn ContentFilter>,
+        spawner: Arc<dyn BlockingSpawner>,
+        report_unfiltered_bytes: bool,
+    ) -> Self {
+        Self {
+            filter,
+            spawner,
+            unfiltered_bytes: report_unfiltered_bytes.then(AtomicUsize::default),
+        }
+    }
+
+    pub(crate) fn filter(&self) -> &Arc<dyn ContentFilter> {
+        &self.filter
+    }
+
+    /// Filters the content of `files`. Errors name the failed path with a
+    /// `BatchFailure`.
+    pub(crate) async fn filter_batch(
+        &self,
+        files: Vec<(RepoPathBuf, Bytes, UpdateFlag)>,
+    ) -> Result<Vec<(RepoPathBuf, Bytes, UpdateFlag)>> {
+        let filter = self.filter.clone();
+        run_blocking(&*self.spawner, move || {
+            files
+                .into_iter()
+                .map(
+                    |(path, data, flag)| match filter_content(&*filter, &path, data, flag) {
+                        Ok(data) => Ok((path, data, flag)),
+                        Err(e) => Err(e.context(BatchFailure { path })),
+                    },
+                )
+                .collect()
+        })
+        .await?
+    }
+
+    /// Adds the size before filtering of files written.
+    pub(crate) fn record_written(&self, unfiltered_bytes: usize) {
+        if let Some(total) = &self.unfiltered_bytes {
+            total.fetch_add(unfiltered_bytes, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn unfiltered_bytes(&self) -> Option<usize> {
+        self.unfiltered_bytes
+            .as_ref()
+            .map(|total| total.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::stream;
+    use futures::stream::BoxStream;
+    use futures::StreamExt;
+    use manifest::FileMetadata;
+    use parking_lot::Mutex;
+    use storemodel::ReadFileContents;
+    use tempfile::TempDir;
+    use types::HgId;
+    use types::Key;
+    use types::Parents;
+    use vfs::VFS;
+
+    use super::*;
+    use crate::actions::UpdateAction;
+    use crate::Action;
+    use crate::ActionMap;
+    use crate::Checkout;
+    use crate::CheckoutPlan;
+
+    const TEXT: &[u8] = b"one\ntwo\n";
+    const CRLF_TEXT: &[u8] = b"one\r\ntwo\r\n";
+
+    /// Writes `*.txt` files with CRLF line endings, and records the paths it
+    /// was called for.
+    #[derive(Default)]
+    struct CrlfFilter {
+        filtered: Mutex<Vec<RepoPathBuf>>,
+    }
+
+    impl ContentFilter for CrlfFilter {
+        fn wants(&self, path: &RepoPath) -> bool {
+            path.as_str().ends_with(".txt")
+        }
+
+        fn filter(
+            &self,
+            path: &RepoPath,
+            data: Vec<u8>,
+            _file_type: FileType,
+        ) -> Result<FilterOutcome> {
+            self.filtered.lock().push(path.to_owned());
+            if !data.contains(&b'\n') {
+                return Ok(FilterOutcome::PassThrough);
+            }
+            let mut filtered = Vec::with_capacity(data.len() * 2);
+            for byte in data {
+                if byte == b'\n' {
+                    filtered.push(b'\r');
+                }
+                filtered.push(byte);
+            }
+            Ok(FilterOutcome::Filtered(filtered))
+        }
+    }
+
+    /// Returns `TEXT` for `hgid(1)`, and `a.txt` for other keys.
+    struct ContentStore;
+
+    #[async_trait::async_trait]
+    impl ReadFileContents for ContentStore {
+        type Error = anyhow::Error;
+
+        async fn read_file_contents(&self, keys: Vec<Key>) -> BoxStream<Result<(Bytes, Key)>> {
+            stream::iter(keys)
+                .map(|key| {
+                    let data = if key.hgid == hgid(1) {
+                        Bytes::from_static(TEXT)
+                    } else {
+                        Bytes::from_static(b"a.txt")
+                    };
+                    Ok((data, key))
+                })
+                .boxed()
+        }
+
+        async fn read_rename_metadata(
+            &self,
+            _keys: Vec<Key>,
+        ) -> BoxStream<Result<(Key, Option<Key>), Self::Error>> {
+            stream::empty().boxed()
+        }
+    }
+
+    /// Returns `TEXT` for its hash, verified like stores verify fetched
+    /// content, and counts the verified files.
+    #[derive(Default)]
+    struct VerifyingStore {
+        verified: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl ReadFileContents for VerifyingStore {
+        type Error = anyhow::Error;
+
+        async fn read_file_contents(&self, keys: Vec<Key>) -> BoxStream<Result<(Bytes, Key)>> {
+            stream::iter(keys)
+                .map(|key| {
+                    let data = Bytes::from_static(TEXT);
+                    if HgId::from_content(&data, Parents::None) != key.hgid {
+                        anyhow::bail!("{} does not match its hash", key.path);
+                    }
+                    self.verified.fetch_add(1, Ordering::Relaxed);
+                    Ok((data, key))
+                })
+                .boxed()
+        }
+
+        async fn read_rename_metadata(
+            &self,
+            _keys: Vec<Key>,
+        ) -> BoxStream<Result<(Key, Option<Key>), Self::Error>> {
+            stream::empty().boxed()
+        }
+    }
+
+    fn rp(p: &str) -> RepoPathBuf {
+        RepoPathBuf::from_string(p.to_string()).unwrap()
+    }
+
+    fn hgid(n: u8) -> HgId {
+        HgId::from_byte_array([n; HgId::len()])
+    }
+
+    /// A plan writing text files, a binary file and a symlink, with
+    /// `filter`.
+    fn crlf_plan(vfs: &VFS, filter: Arc<CrlfFilter>) -> CheckoutPlan {
+        let mut map = ActionMap::empty();
+        for (path, meta) in [
+            ("a.txt", FileMetadata::regular(hgid(1))),
+            ("tool.txt", FileMetadata::executable(hgid(1))),
+            ("b.bin", FileMetadata::regular(hgid(1))),
+            ("link.txt", FileMetadata::symlink(hgid(2))),
+        ] {
+            map.insert(rp(path), Action::Update(UpdateAction::new(None, meta)));
+        }
+        Checkout::default_config(vfs.clone())
+            .with_content_filter(filter, true)
+            .plan_action_map(map)
+    }
+
+    #[tokio::test]
+    async fn test_crlf_filter() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        let filter = Arc::new(CrlfFilter::default());
+        let stats = crlf_plan(&vfs, filter.clone())
+            .apply_store(&ContentStore)
+            .await?;
+
+        assert_eq!(vfs.read(&rp("a.txt"))?, CRLF_TEXT);
+        assert_eq!(vfs.read(&rp("tool.txt"))?, CRLF_TEXT);
+        assert_eq!(vfs.read(&rp("b.bin"))?, TEXT);
+        assert_eq!(vfs.read(&rp("link.txt"))?, b"a.txt");
+        let mut filtered = filter.filtered.lock().clone();
+        filtered.sort();
+        assert_eq!(filtered, vec![rp("a.txt"), rp("tool.txt")]);
+
+        assert_eq!(
+            stats.written_bytes.load(Ordering::Relaxed),
+            2 * CRLF_TEXT.len() + TEXT.len() + 5
+        );
+        assert_eq!(stats.unfiltered_bytes(), Some(3 * TEXT.len() + 5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_staged_crlf_filter() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let root = tempdir.path().join("wc");
+        std::fs::create_dir(&root)?;
+        let vfs = VFS::new(root)?;
+        let staging_dir = tempdir.path().join("staging");
+        let filter = Arc::new(CrlfFilter::default());
+        let staged = crlf_plan(&vfs, filter)
+            .stage(&ContentStore, &staging_dir)
+            .await?;
+        let stats = staged.commit().await?;
+
+        // Staged content is filtered when placed, even if it is the last
+        // file with that content, which is otherwise moved.
+        assert_eq!(vfs.read(&rp("a.txt"))?, CRLF_TEXT);
+        assert_eq!(vfs.read(&rp("tool.txt"))?, CRLF_TEXT);
+        assert_eq!(vfs.read(&rp("b.bin"))?, TEXT);
+        assert_eq!(vfs.read(&rp("link.txt"))?, b"a.txt");
+        assert_eq!(stats.unfiltered_bytes(), Some(3 * TEXT.len() + 5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_verification() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        let text_id = HgId::from_content(TEXT, Parents::None);
+        let mut map = ActionMap::empty();
+        for path in ["a.txt", "b.bin"] {
+            let meta = FileMetadata::regular(text_id);
+            map.insert(rp(path), Action::Update(UpdateAction::new(None, meta)));
+        }
+        let store = VerifyingStore::default();
+        Checkout::default_config(vfs.clone())
+            .with_content_filter(Arc::new(CrlfFilter::default()), false)
+            .plan_action_map(map)
+            .apply_store(&store)
+            .await?;
+
+        // The store verified the content in the repository. The filtered
+        // content does not match the hash, but is only written.
+        assert_eq!(store.verified.load(Ordering::Relaxed), 2);
+        assert_ne!(HgId::from_content(CRLF_TEXT, Parents::None), text_id);
+        assert_eq!(vfs.read(&rp("a.txt"))?, CRLF_TEXT);
+        assert_eq!(vfs.read(&rp("b.bin"))?, TEXT);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unknown_files_check_filtered_content() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        vfs.write(&rp("same.txt"), CRLF_TEXT, UpdateFlag::Regular)?;
+        vfs.write(&rp("unfiltered.txt"), TEXT, UpdateFlag::Regular)?;
+        vfs.write(&rp("same.bin"), TEXT, UpdateFlag::Regular)?;
+        let files = ["same.txt", "unfiltered.txt", "same.bin"]
+            .into_iter()
+            .map(|path| {
+                let key = Key::new(rp(path), hgid(1));
+                (Bytes::from_static(TEXT), key, UpdateFlag::Regular)
+            })
+            .collect();
+
+        // Unknown files are compared with the content that would be written,
+        // not the content in the repository.
+        let filter = CrlfFilter::default();
+        let differ = CheckoutPlan::check_content(&vfs, files, Some(&filter as &dyn ContentFilter))?;
+        assert_eq!(differ, vec![rp("unfiltered.txt")]);
+        Ok(())
+    }
+}
@@ -0,0 +1,677 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Checkout driven directly by a stream of diff entries, so that work on the
+//! working copy starts before the diff is complete.
+//!
+//! Removals and exec bit updates are started as soon as they arrive. Content
+//! updates are grouped into fetches of `DiffStreamOptions::fetch_batch_size`.
+//!
+//! Writing a file and removing a file at one of its parents (or below it, if
+//! a directory turns into a file) must not run at the same time. A write
+//! that conflicts with a removal that is still running is held back until
+//! that removal completes. A removal that conflicts with a write that was
+//! already started is skipped, since the write clears conflicting paths
+//! itself (see `VFS::write`).
+
+use std::collections::BTreeSet;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::future::LocalBoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::FutureExt;
+use futures::Stream;
+use futures::StreamExt;
+use manifest::DiffEntry;
+use progress_model::ProgressBar;
+use progress_model::Registry;
+use storemodel::ReadFileContents;
+use types::RepoPath;
+use types::RepoPathBuf;
+use vfs::AsyncVfsWriter;
+
+use crate::space::StreamSpaceCheck;
+use crate::windows_paths;
+use crate::Action;
+use crate::ActionMap;
+use crate::Checkout;
+use crate::CheckoutError;
+use crate::CheckoutPlan;
+use crate::CheckoutStats;
+use crate::PlanSummary;
+use crate::UpdateContentAction;
+use crate::WindowsPathError;
+use crate::VFS_BATCH_SIZE;
+
+const DEFAULT_FETCH_BATCH_SIZE: usize = 1000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct DiffStreamOptions {
+    /// Maximum number of files fetched from the store in one request.
+    pub fetch_batch_size: usize,
+}
+
+impl Default for DiffStreamOptions {
+    fn default() -> Self {
+        Self {
+            fetch_batch_size: DEFAULT_FETCH_BATCH_SIZE,
+        }
+    }
+}
+
+enum Work {
+    Remove(Vec<RepoPathBuf>),
+    Fetch(Vec<UpdateContentAction>),
+    SetExec(RepoPathBuf, bool),
+}
+
+/// Actions received from the diff that were not started yet, and the paths
+/// of started ones that later actions may conflict with.
+#[derive(Default)]
+struct Pending {
+    remove: Vec<RepoPathBuf>,
+    fetch: Vec<UpdateContentAction>,
+    set_exec: Vec<(RepoPathBuf, bool)>,
+    /// Writes waiting for a conflicting removal to complete.
+    blocked: Vec<UpdateContentAction>,
+    /// Removals not yet completed, including ones in `remove`.
+    removing: BTreeSet<RepoPathBuf>,
+    /// Paths of writes started, or in `fetch`.
+    writing: BTreeSet<RepoPathBuf>,
+    /// Whether a removal completed since `blocked` was last checked.
+    unblock: bool,
+}
+
+impl Pending {
+    /// Queues the action for `entry`. Returns false if it was skipped.
+    fn add(&mut self, entry: DiffEntry) -> bool {
+        let path = entry.path;
+        match Action::from_diff_type(entry.diff_type) {
+            None => false,
+            Some(Action::Remove) => {
+                if conflicts(&self.writing, &path) {
+                    return false;
+                }
+                self.removing.insert(path.clone());
+                self.remove.push(path);
+                true
+            }
+            Some(Action::UpdateExec(set_x_flag)) => {
+                self.set_exec.push((path, set_x_flag));
+                true
+            }
+            Some(Action::Update(up)) => {
+                let action = UpdateContentAction::new(path, up.to, up.from.is_none());
+                if conflicts(&self.removing, &action.path) {
+                    self.blocked.push(action);
+                } else {
+                    self.writing.insert(action.path.clone());
+                    self.fetch.push(action);
+                }
+                true
+            }
+        }
+    }
+
+    fn removed(&mut self, paths: &[RepoPathBuf]) {
+        for path in paths {
+            self.removing.remove(path);
+        }
+        self.unblock = true;
+    }
+
+    /// Work that can be started now. Partial fetch batches are only started
+    /// if `diff_done`.
+    fn take_ready(&mut self, diff_done: bool, fetch_batch_size: usize) -> Vec<Work> {
+        if std::mem::take(&mut self.unblock) && !self.blocked.is_empty() {
+            let (blocked, ready): (Vec<_>, Vec<_>) = std::mem::take(&mut self.blocked)
+                .into_iter()
+                .partition(|action| conflicts(&self.removing, &action.path));
+            self.blocked = blocked;
+            for action in ready {
+                self.writing.insert(action.path.clone());
+                self.fetch.push(action);
+            }
+        }
+
+        let mut work = Vec::new();
+        while !self.remove.is_empty() {
+            let len = self.remove.len().min(VFS_BATCH_SIZE);
+            work.push(Work::Remove(self.remove.drain(..len).collect()));
+        }
+        work.extend(
+            self.set_exec
+                .drain(..)
+                .map(|(path, flag)| Work::SetExec(path, flag)),
+        );
+        while self.fetch.len() >= fetch_batch_size || (diff_done && !self.fetch.is_empty()) {
+            let len = self.fetch.len().min(fetch_batch_size);
+            work.push(Work::Fetch(self.fetch.drain(..len).collect()));
+        }
+        work
+    }
+}
+
+/// Whether `path`, or one of its parents, or something below it is in
+/// `paths`.
+fn conflicts(paths: &BTreeSet<RepoPathBuf>, path: &RepoPath) -> bool {
+    if path.parents().chain(Some(path)).any(|p| paths.contains(p)) {
+        return true;
+    }
+    // Paths are ordered by components, so anything below `path` directly
+    // follows it.
+    paths
+        .range::<RepoPath, _>((Bound::Excluded(path), Bound::Unbounded))
+        .next()
+        .map_or(false, |next| next.parents().any(|p| p == path))
+}
+
+impl Checkout {
+    /// Builds a plan from a diff produced asynchronously. Same as
+    /// `plan_action_map(ActionMap::from_diff(..))`.
+    pub async fn plan_diff_stream(
+        &self,
+        diff: impl Stream<Item = Result<DiffEntry>>,
+    ) -> Result<CheckoutPlan> {
+        let map = ActionMap::from_diff_stream(diff).await?;
+        Ok(self.plan_action_map(map))
+    }
+
+    /// Applies `diff` to the working copy as its entries arrive, instead of
+    /// planning the whole checkout first. The resulting working copy is the
+    /// same as with `plan_diff_stream` followed by `apply_store`.
+    ///
+    /// Case normalization and priority paths need the full plan, so they are
+    /// not applied. Failures of `diff` are reported as `FetchFailed`. Paths
+    /// Windows can't write, and with `with_space_check` the space needed,
+    /// are checked as entries arrive, so they may fail the checkout after
+    /// part of the diff was applied.
+    pub async fn apply_diff_stream(
+        &self,
+        diff: impl Stream<Item = Result<DiffEntry>>,
+        store: &dyn ReadFileContents<Error = anyhow::Error>,
+        options: DiffStreamOptions,
+    ) -> Result<CheckoutStats, CheckoutError> {
+        let stats = CheckoutStats::new(self);
+        let result = self.apply_diff_entries(diff, store, options, &stats).await;
+        self.hooks.finish(&stats, result).await?;
+        Ok(stats)
+    }
+
+    async fn apply_diff_entries(
+        &self,
+        diff: impl Stream<Item = Result<DiffEntry>>,
+        store: &dyn ReadFileContents<Error = anyhow::Error>,
+        options: DiffStreamOptions,
+        stats_ref: &CheckoutStats,
+    ) -> Result<(), CheckoutError> {
+        // The size of the diff isn't known, and removals and writes overlap,
+        // so both pre hooks run first, with empty summaries.
+        let summary = PlanSummary::default();
+        self.hooks.pre_remove(&summary).await?;
+        self.hooks.pre_update(&summary).await?;
+        let bar = &ProgressBar::new("Updating", 0, "files");
+        Registry::main().register_progress_bar(bar);
+        let async_vfs = &self.async_vfs();
+        let fetch_batch_size = options.fetch_batch_size.max(1);
+        let mut space_check = StreamSpaceCheck::new(self)?;
+
+        let mut diff = Box::pin(diff);
+        let mut diff_done = false;
+        let mut pending = Pending::default();
+        let mut running: FuturesUnordered<
+            LocalBoxFuture<'_, Result<Option<Vec<RepoPathBuf>>, CheckoutError>>,
+        > = FuturesUnordered::new();
+
+        loop {
+            for work in pending.take_ready(diff_done, fetch_batch_size) {
+                running.push(self.run_diff_work(work, store, async_vfs, stats_ref, bar));
+            }
+            if diff_done && running.is_empty() {
+                break;
+            }
+
+            tokio::select! {
+                entry = diff.next(), if !diff_done && running.len() < self.concurrency => {
+                    match entry {
+                        None => diff_done = true,
+                        Some(entry) => {
+                            let entry = entry.map_err(|source| CheckoutError::FetchFailed {
+                                key: None,
+                                source,
+                            })?;
+                            bar.increase_total(1);
+                            let action = Action::from_diff_type(entry.diff_type);
+                            if let Some(Action::Update(up)) = action {
+                                self.check_windows_path(&entry.path, stats_ref)?;
+                                if let Some(space_check) = &mut space_check {
+                                    space_check.add(&up.to.hgid)?;
+                                }
+                            }
+                            if !pending.add(entry) {
+                                bar.increase_position(1);
+                            }
+                        }
+                    }
+                }
+                Some(done) = running.next(), if !running.is_empty() => {
+                    if let Some(removed) = done? {
+                        pending.removed(&removed);
+                    }
+                }
+            }
+        }
+
+        drop(running);
+        self.hooks.post_remove(&stats_ref.applied()).await
+    }
+
+    /// Same as `CheckoutPlan::check_windows_paths`, for a path written by
+    /// `apply_diff_stream`.
+    fn check_windows_path(
+        &self,
+        path: &RepoPathBuf,
+        stats: &CheckoutStats,
+    ) -> Result<(), WindowsPathError> {
+        let problems = windows_paths::find_path_problems(self.vfs.root(), std::iter::once(path));
+        if problems.is_empty() {
+            return Ok(());
+        }
+        let result = windows_paths::check_path_problems(&problems, self.allow_reserved_names);
+        stats.path_problems.lock().extend(problems);
+        result
+    }
+
+    /// Runs `work`. Returns the removed paths for removals.
+    fn run_diff_work<'a>(
+        &'a self,
+        work: Work,
+        store: &'a dyn ReadFileContents<Error = anyhow::Error>,
+        async_vfs: &'a AsyncVfsWriter,
+        stats: &'a CheckoutStats,
+        bar: &'a Arc<ProgressBar>,
+    ) -> LocalBoxFuture<'a, Result<Option<Vec<RepoPathBuf>>, CheckoutError>> {
+        async move {
+            match work {
+                Work::Remove(paths) => {
+                    CheckoutPlan::remove_files(async_vfs, stats, paths.clone(), bar).await?;
+                    Ok(Some(paths))
+                }
+                Work::Fetch(actions) => {
+                    CheckoutPlan::fetch_and_write(
+                        store,
+                        actions.iter(),
+                        async_vfs,
+                        stats,
+                        None,
+                        self,
+                        bar,
+                    )
+                    .await?;
+                    Ok(None)
+                }
+                Work::SetExec(path, flag) => {
+                    CheckoutPlan::set_exec_on_file(async_vfs, stats, &path, flag, bar).await?;
+                    Ok(None)
+                }
+            }
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use futures::stream;
+    use futures::stream::BoxStream;
+    use manifest::FileMetadata;
+    use manifest_tree::testutil::make_tree_manifest_from_meta;
+    use manifest_tree::testutil::TestStore;
+    use manifest_tree::Diff;
+    use minibytes::Bytes;
+    use parking_lot::Mutex;
+    use pathmatcher::AlwaysMatcher;
+    use tempfile::TempDir;
+    use types::HgId;
+    use types::Key;
+    use vfs::VFS;
+    use walkdir::WalkDir;
+
+    use super::*;
+    use crate::type_to_flag;
+    use crate::AppliedStats;
+    use crate::CheckoutHooks;
+    use crate::PathProblem;
+    use crate::PathProblemKind;
+    use crate::SpaceCheck;
+    use crate::SyncSpawner;
+
+    /// Records how many diff entries were produced at the time of each fetch.
+    struct RecordingStore {
+        produced: Arc<AtomicUsize>,
+        fetches: Mutex<Vec<usize>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ReadFileContents for RecordingStore {
+        type Error = anyhow::Error;
+
+        async fn read_file_contents(&self, keys: Vec<Key>) -> BoxStream<Result<(Bytes, Key)>> {
+            self.fetches
+                .lock()
+                .push(self.produced.load(Ordering::SeqCst));
+            stream::iter(keys)
+                .map(|key| Ok((key.hgid.to_string().into_bytes().into(), key)))
+                .boxed()
+        }
+
+        async fn read_rename_metadata(
+            &self,
+            _keys: Vec<Key>,
+        ) -> BoxStream<Result<(Key, Option<Key>), Self::Error>> {
+            stream::empty().boxed()
+        }
+    }
+
+    fn rp(p: &str) -> RepoPathBuf {
+        RepoPathBuf::from_string(p.to_string()).unwrap()
+    }
+
+    fn hgid(p: u8) -> HgId {
+        HgId::from_byte_array([p; HgId::len()])
+    }
+
+    fn read_tree(root: &Path) -> BTreeMap<String, Vec<u8>> {
+        WalkDir::new(root)
+            .into_iter()
+            .map(|e| e.unwrap())
+            .filter(|e| !e.file_type().is_dir())
+            .map(|e| {
+                let path = e.path().strip_prefix(root).unwrap();
+                let path = path.to_str().unwrap().replace('\\', "/");
+                (path, std::fs::read(e.path()).unwrap())
+            })
+            .collect()
+    }
+
+    fn from_tree() -> Vec<(RepoPathBuf, FileMetadata)> {
+        let mut tree = vec![
+            // Turns into a directory.
+            (rp("a"), FileMetadata::regular(hgid(1))),
+            // Turns into a file.
+            (rp("b/c"), FileMetadata::regular(hgid(2))),
+            (rp("b/d/e"), FileMetadata::regular(hgid(3))),
+            (rp("f"), FileMetadata::regular(hgid(4))),
+            (rp("g"), FileMetadata::regular(hgid(5))),
+        ];
+        for i in 0..20 {
+            tree.push((rp(&format!("old/{}", i)), FileMetadata::regular(hgid(6))));
+        }
+        tree
+    }
+
+    fn to_tree() -> Vec<(RepoPathBuf, FileMetadata)> {
+        let mut tree = vec![
+            (rp("a/x"), FileMetadata::regular(hgid(1))),
+            (rp("b"), FileMetadata::regular(hgid(2))),
+            (rp("f"), FileMetadata::regular(hgid(7))),
+            (rp("g"), FileMetadata::executable(hgid(5))),
+        ];
+        for i in 0..20 {
+            tree.push((
+                rp(&format!("new/{}", i)),
+                FileMetadata::regular(hgid(i + 10)),
+            ));
+        }
+        tree
+    }
+
+    /// Writes `from`, and returns the diff entries to get to `to`.
+    fn setup(
+        root: &Path,
+        from: &[(RepoPathBuf, FileMetadata)],
+        to: &[(RepoPathBuf, FileMetadata)],
+    ) -> Result<Vec<DiffEntry>> {
+        let vfs = VFS::new(root.to_path_buf())?;
+        for (path, meta) in from {
+            let data = meta.hgid.to_string().into_bytes();
+            vfs.write(path, &data, type_to_flag(&meta.file_type))?;
+        }
+        let store = Arc::new(TestStore::new());
+        let left = make_tree_manifest_from_meta(store.clone(), from.iter().cloned());
+        let right = make_tree_manifest_from_meta(store, to.iter().cloned());
+        let matcher = AlwaysMatcher::new();
+        Diff::new(&left, &right, &matcher)?.collect()
+    }
+
+    /// A diff stream producing an entry every `delay`.
+    fn slow_stream(
+        entries: Vec<DiffEntry>,
+        delay: Duration,
+        produced: Arc<AtomicUsize>,
+    ) -> impl Stream<Item = Result<DiffEntry>> {
+        stream::iter(entries).then(move |entry| {
+            let produced = produced.clone();
+            async move {
+                tokio::time::sleep(delay).await;
+                produced.fetch_add(1, Ordering::SeqCst);
+                Ok(entry)
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_plan_diff_stream() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let entries = setup(tempdir.path(), &from_tree(), &to_tree())?;
+        let expected = ActionMap::from_diff(entries.clone().into_iter().map(Ok))?;
+        let actual = ActionMap::from_diff_stream(stream::iter(entries).map(Ok)).await?;
+        assert_eq!(actual, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_apply_diff_stream() -> Result<()> {
+        let (from, to) = (from_tree(), to_tree());
+
+        // Reference: plan the whole diff, then apply it.
+        let expected_dir = TempDir::new()?;
+        let entries = setup(expected_dir.path(), &from, &to)?;
+        let total = entries.len();
+        let checkout = Checkout::default_config(VFS::new(expected_dir.path().to_path_buf())?);
+        let plan = checkout
+            .plan_diff_stream(stream::iter(entries).map(Ok))
+            .await?;
+        let store = RecordingStore {
+            produced: Default::default(),
+            fetches: Default::default(),
+        };
+        plan.apply_store(&store).await?;
+
+        let tempdir = TempDir::new()?;
+        let entries = setup(tempdir.path(), &from, &to)?;
+        let produced = Arc::new(AtomicUsize::new(0));
+        let store = RecordingStore {
+            produced: produced.clone(),
+            fetches: Default::default(),
+        };
+        let diff = slow_stream(entries, Duration::from_millis(5), produced);
+        let checkout = Checkout::default_config(VFS::new(tempdir.path().to_path_buf())?);
+        let options = DiffStreamOptions {
+            fetch_batch_size: 4,
+        };
+        let stats = checkout.apply_diff_stream(diff, &store, options).await?;
+
+        let fetches = store.fetches.lock();
+        assert!(fetches.len() > 1);
+        assert!(fetches[0] < total, "first fetch waited for the whole diff");
+        assert_eq!(stats.updated.load(Ordering::Relaxed), to.len() - 1);
+        assert_eq!(stats.meta_updated.load(Ordering::Relaxed), 1);
+        assert_eq!(read_tree(tempdir.path()), read_tree(expected_dir.path()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_apply_diff_stream_space_check() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let entries = setup(tempdir.path(), &from_tree(), &to_tree())?;
+        let store = RecordingStore {
+            produced: Default::default(),
+            fetches: Default::default(),
+        };
+        // Room for 5 files of 100 bytes, out of the 23 written.
+        let check = SpaceCheck::with_sizes(Arc::new(|_| Some(100)))
+            .with_margin(0)
+            .with_free_space_probe(Arc::new(|_| Ok(500)));
+        let checkout = Checkout::default_config(VFS::new(tempdir.path().to_path_buf())?)
+            .with_space_check(check.clone());
+        let result = checkout
+            .apply_diff_stream(
+                stream::iter(entries.clone()).map(Ok),
+                &store,
+                Default::default(),
+            )
+            .await;
+        match result {
+            Err(CheckoutError::InsufficientSpace(err)) => {
+                assert_eq!(err.estimate.bytes_written, 600)
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+
+        let checkout = Checkout::default_config(VFS::new(tempdir.path().to_path_buf())?)
+            .with_space_check(check.with_force(true));
+        checkout
+            .apply_diff_stream(stream::iter(entries).map(Ok), &store, Default::default())
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_apply_diff_stream_windows_paths() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let to = vec![
+            (rp("src/aux.c"), FileMetadata::regular(hgid(1))),
+            (rp("src/main.c"), FileMetadata::regular(hgid(2))),
+        ];
+        let entries = setup(tempdir.path(), &[], &to)?;
+        let store = RecordingStore {
+            produced: Default::default(),
+            fetches: Default::default(),
+        };
+        let checkout = Checkout::default_config(VFS::new(tempdir.path().to_path_buf())?);
+        let result = checkout
+            .apply_diff_stream(stream::iter(entries).map(Ok), &store, Default::default())
+            .await;
+        if cfg!(windows) {
+            assert!(matches!(result, Err(CheckoutError::PathProblems(_))));
+        } else {
+            assert_eq!(
+                result?.path_problems(),
+                vec![PathProblem {
+                    path: rp("src/aux.c"),
+                    kind: PathProblemKind::ReservedName("aux.c".to_string()),
+                }]
+            );
+        }
+        Ok(())
+    }
+
+    /// Records the names of the hooks called.
+    #[derive(Default)]
+    struct RecordingHook {
+        calls: Mutex<Vec<&'static str>>,
+    }
+
+    #[async_trait::async_trait]
+    impl CheckoutHooks for RecordingHook {
+        async fn pre_remove(&self, _plan: &PlanSummary) -> Result<()> {
+            self.calls.lock().push("pre_remove");
+            Ok(())
+        }
+
+        async fn post_remove(&self, _stats: &AppliedStats) -> Result<()> {
+            self.calls.lock().push("post_remove");
+            Ok(())
+        }
+
+        async fn pre_update(&self, _plan: &PlanSummary) -> Result<()> {
+            self.calls.lock().push("pre_update");
+            Ok(())
+        }
+
+        async fn post_apply(
+            &self,
+            _stats: &AppliedStats,
+            _outcome: Result<(), &CheckoutError>,
+        ) -> Result<()> {
+            self.calls.lock().push("post_apply");
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_diff_stream_hooks() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let entries = setup(tempdir.path(), &from_tree(), &to_tree())?;
+        let store = RecordingStore {
+            produced: Default::default(),
+            fetches: Default::default(),
+        };
+        let hook = Arc::new(RecordingHook::default());
+        let checkout = Checkout::default_config(VFS::new(tempdir.path().to_path_buf())?)
+            .with_hook(hook.clone());
+        checkout
+            .apply_diff_stream(stream::iter(entries).map(Ok), &store, Default::default())
+            .await?;
+        assert_eq!(
+            *hook.calls.lock(),
+            vec!["pre_remove", "pre_update", "post_remove", "post_apply"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_diff_stream_blocking_spawner() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let entries = setup(tempdir.path(), &from_tree(), &to_tree())?;
+        let store = RecordingStore {
+            produced: Default::default(),
+            fetches: Default::default(),
+        };
+        // Without a tokio runtime, using its blocking pool would panic.
+        let checkout = Checkout::default_config(VFS::new(tempdir.path().to_path_buf())?)
+            .with_blocking_spawner(Arc::new(SyncSpawner));
+        let diff = stream::iter(entries).map(Ok);
+        futures::executor::block_on(checkout.apply_diff_stream(diff, &store, Default::default()))?;
+        let files = read_tree(tempdir.path());
+        assert_eq!(files.len(), to_tree().len());
+        assert_eq!(files["b"], hgid(2).to_string().into_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let paths: BTreeSet<_> = [rp("a"), rp("b/c/d"), rp("e-f")].into_iter().collect();
+        assert!(conflicts(&paths, &rp("a")));
+        assert!(conflicts(&paths, &rp("a/x")));
+        assert!(conflicts(&paths, &rp("b")));
+        assert!(conflicts(&paths, &rp("b/c")));
+        assert!(conflicts(&paths, &rp("b/c/d/e")));
+        assert!(!conflicts(&paths, &rp("b/x")));
+        assert!(!conflicts(&paths, &rp("e")));
+        assert!(!conflicts(&paths, &rp("ab")));
+    }
+}
@@ -0,0 +1,245 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Counts of the changes of a plan by kind and by top-level directory, to
+//! describe a checkout to the user before applying it.
+
+use std::collections::HashMap;
+
+use manifest::FileType;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::Action;
+use crate::ActionMap;
+
+/// Top-level directories listed in `DiffSummary::top_dirs`.
+pub(crate) const DEFAULT_TOP_DIRS: usize = 10;
+
+/// The changes of a plan, see `CheckoutPlan::diff_summary`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffSummary {
+    /// Files that only exist in the destination.
+    pub added: usize,
+    /// Files that only exist in the source.
+    pub removed: usize,
+    /// Files whose content changes, possibly along with the exec flag.
+    pub content_changed: usize,
+    /// Files where only the exec flag changes.
+    pub flag_changed: usize,
+    /// Files that become or stop being symlinks, whether their content
+    /// changes or not.
+    pub type_changed: usize,
+    /// Files written as symlinks.
+    pub symlinks: usize,
+    /// Files written as executables, or made executable.
+    pub executables: usize,
+    /// The top-level directories with the most changed files, most changed
+    /// first. Files at the root are counted under an empty name.
+    pub top_dirs: Vec<DirSummary>,
+    /// Changed files in top-level directories not in `top_dirs`.
+    pub other_dirs_files: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirSummary {
+    pub dir: String,
+    pub files: usize,
+}
+
+impl DiffSummary {
+    pub(crate) fn from_actions(actions: &ActionMap, top_dirs: usize) -> Self {
+        let mut summary = DiffSummary::default();
+        let mut dirs: HashMap<&str, usize> = HashMap::new();
+        for (path, action) in actions.iter() {
+            match action {
+                Action::Remove => summary.removed += 1,
+                Action::UpdateExec(set_x_flag) => {
+                    summary.flag_changed += 1;
+                    if *set_x_flag {
+                        summary.executables += 1;
+                    }
+                }
+                Action::Update(up) => {
+                    match up.from {
+                        None => summary.added += 1,
+                        Some(from) if is_type_change(from.file_type, up.to.file_type) => {
+                            summary.type_changed += 1
+                        }
+                        Some(_) => summary.content_changed += 1,
+                    }
+                    match up.to.file_type {
+                        FileType::Symlink => summary.symlinks += 1,
+                        FileType::Executable => summary.executables += 1,
+                        FileType::Regular | FileType::GitSubmodule => {}
+                    }
+                }
+            }
+            let dir = match path.as_str().split_once('/') {
+                Some((dir, _)) => dir,
+                None => "",
+            };
+            *dirs.entry(dir).or_default() += 1;
+        }
+
+        let mut dirs: Vec<_> = dirs.into_iter().collect();
+        dirs.sort_by(|(a_dir, a), (b_dir, b)| b.cmp(a).then_with(|| a_dir.cmp(b_dir)));
+        for (i, (dir, files)) in dirs.into_iter().enumerate() {
+            if i < top_dirs {
+                summary.top_dirs.push(DirSummary {
+                    dir: dir.to_string(),
+                    files,
+                });
+            } else {
+                summary.other_dirs_files += files;
+            }
+        }
+        summary
+    }
+
+    /// Number of files changed.
+    pub fn total(&self) -> usize {
+        self.added + self.removed + self.content_changed + self.flag_changed + self.type_changed
+    }
+}
+
+/// Whether a file changing from `from` to `to` changes kind, as opposed to
+/// only its exec flag.
+fn is_type_change(from: FileType, to: FileType) -> bool {
+    from != to && (from == FileType::Symlink || to == FileType::Symlink)
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use manifest::DiffEntry;
+    use manifest::DiffType;
+    use manifest::FileMetadata;
+    use types::testutil::generate_repo_paths;
+    use types::HgId;
+    use types::RepoPathBuf;
+
+    use super::*;
+
+    fn rp(p: &str) -> RepoPathBuf {
+        RepoPathBuf::from_string(p.to_string()).unwrap()
+    }
+
+    fn hgid(p: u8) -> HgId {
+        let mut r = HgId::default().into_byte_array();
+        r[0] = p;
+        HgId::from_byte_array(r)
+    }
+
+    fn changed(path: &str, old: FileMetadata, new: FileMetadata) -> DiffEntry {
+        DiffEntry::new(rp(path), DiffType::Changed(old, new))
+    }
+
+    #[test]
+    fn test_summary() -> Result<()> {
+        let diff = vec![
+            DiffEntry::new(
+                rp("a/new"),
+                DiffType::RightOnly(FileMetadata::regular(hgid(1))),
+            ),
+            DiffEntry::new(
+                rp("a/link"),
+                DiffType::RightOnly(FileMetadata::symlink(hgid(1))),
+            ),
+            DiffEntry::new(
+                rp("b/old"),
+                DiffType::LeftOnly(FileMetadata::regular(hgid(1))),
+            ),
+            changed(
+                "a/edit",
+                FileMetadata::regular(hgid(1)),
+                FileMetadata::regular(hgid(2)),
+            ),
+            // Content and flag change.
+            changed(
+                "c/tool",
+                FileMetadata::regular(hgid(1)),
+                FileMetadata::executable(hgid(2)),
+            ),
+            changed(
+                "b/chmod",
+                FileMetadata::regular(hgid(1)),
+                FileMetadata::executable(hgid(1)),
+            ),
+            changed(
+                "b/unchmod",
+                FileMetadata::executable(hgid(1)),
+                FileMetadata::regular(hgid(1)),
+            ),
+            // Same content, but not a flag change.
+            changed(
+                "to_link",
+                FileMetadata::regular(hgid(1)),
+                FileMetadata::symlink(hgid(1)),
+            ),
+            changed(
+                "a/from_link",
+                FileMetadata::symlink(hgid(1)),
+                FileMetadata::executable(hgid(2)),
+            ),
+        ];
+        let actions = ActionMap::from_diff(diff.into_iter().map(Ok))?;
+
+        let summary = DiffSummary::from_actions(&actions, 2);
+        assert_eq!(
+            summary,
+            DiffSummary {
+                added: 2,
+                removed: 1,
+                content_changed: 2,
+                flag_changed: 2,
+                type_changed: 2,
+                symlinks: 2,
+                executables: 3,
+                top_dirs: vec![
+                    DirSummary {
+                        dir: "a".to_string(),
+                        files: 4,
+                    },
+                    DirSummary {
+                        dir: "b".to_string(),
+                        files: 3,
+                    },
+                ],
+                other_dirs_files: 2,
+            }
+        );
+        assert_eq!(summary.total(), 9);
+
+        let summary = DiffSummary::from_actions(&actions, DEFAULT_TOP_DIRS);
+        let dirs: Vec<_> = summary.top_dirs.iter().map(|d| d.dir.as_str()).collect();
+        assert_eq!(dirs, vec!["a", "b", "", "c"]);
+        assert_eq!(summary.other_dirs_files, 0);
+
+        let json = serde_json::to_string(&summary)?;
+        assert_eq!(serde_json::from_str::<DiffSummary>(&json)?, summary);
+        Ok(())
+    }
+
+    #[test]
+    fn test_summary_bounded() -> Result<()> {
+        let paths = generate_repo_paths(200, &mut quickcheck::Gen::new(5));
+        let diff = paths.iter().map(|p| {
+            Ok(DiffEntry::new(
+                p.clone(),
+                DiffType::LeftOnly(Default::default()),
+            ))
+        });
+        let actions = ActionMap::from_diff(diff)?;
+        let summary = DiffSummary::from_actions(&actions, 3);
+        assert!(summary.top_dirs.len() <= 3);
+        let listed: usize = summary.top_dirs.iter().map(|d| d.files).sum();
+        assert_eq!(listed + summary.other_dirs_files, actions.len());
+        assert_eq!(summary.removed, actions.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use futures::future;
+use futures::stream;
+use futures::StreamExt;
+use futures::TryStreamExt;
+use minibytes::Bytes;
+use serde::Serialize;
+use types::RepoPath;
+use types::RepoPathBuf;
+use vfs::AsyncVfsWriter;
+use vfs::UpdateFlag;
+
+/// Files of a directory written by one `AsyncVfsWriter::write_dir`.
+const FILES_PER_WRITE: usize = 16;
+
+/// Writing the files of a batch grouped by directory, so each directory is
+/// audited and created once, and its files are written relative to it where
+/// the `VFS` supports it. See `Checkout::with_dir_batching`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct DirBatching {
+    /// Directories of a batch written at once.
+    pub dirs: usize,
+    /// Chunks of the files of a directory written at once. Files are written
+    /// in chunks of 16.
+    pub files_per_dir: usize,
+}
+
+impl Default for DirBatching {
+    fn default() -> Self {
+        Self {
+            dirs: 4,
+            files_per_dir: 2,
+        }
+    }
+}
+
+/// Writes `files`, returning the number of bytes written. Errors name the
+/// failed path with a `BatchFailure`.
+pub(crate) async fn write_by_dir(
+    async_vfs: &AsyncVfsWriter,
+    files: impl IntoIterator<Item = (RepoPathBuf, Bytes, UpdateFlag)>,
+    batching: DirBatching,
+) -> Result<usize> {
+    let mut dirs: BTreeMap<RepoPathBuf, Vec<_>> = BTreeMap::new();
+    for (path, data, flag) in files {
+        let dir = path
+            .parent()
+            .unwrap_or_else(|| RepoPath::empty())
+            .to_owned();
+        dirs.entry(dir).or_default().push((path, data, flag));
+    }
+    stream::iter(dirs)
+        .map(|(dir, files)| write_dir(async_vfs, dir, files, batching.files_per_dir))
+        .buffer_unordered(batching.dirs.max(1))
+        .try_fold(0, |total, written| future::ok(total + written))
+        .await
+}
+
+async fn write_dir(
+    async_vfs: &AsyncVfsWriter,
+    dir: RepoPathBuf,
+    mut files: Vec<(RepoPathBuf, Bytes, UpdateFlag)>,
+    concurrency: usize,
+) -> Result<usize> {
+    let mut chunks = Vec::with_capacity(files.len() / FILES_PER_WRITE + 1);
+    while files.len() > FILES_PER_WRITE {
+        let rest = files.split_off(FILES_PER_WRITE);
+        chunks.push(std::mem::replace(&mut files, rest));
+    }
+    chunks.push(files);
+    stream::iter(chunks)
+        .map(|chunk| async_vfs.write_dir(dir.clone(), chunk))
+        .buffer_unordered(concurrency.max(1))
+        .try_fold(0, |total, written| future::ok(total + written))
+        .await
+}
@@ -0,0 +1,371 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Errors of applying a checkout plan.
+//!
+//! Store failures (`FetchFailed`, `KeyNotFound`) may succeed on retry, while
+//! working copy failures (`WriteFailed`, `RemoveFailed`, `MetaUpdateFailed`)
+//! usually need the user to act, see `is_disk_full` and `is_permission`.
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use serde::Serialize;
+use thiserror::Error;
+use types::Key;
+use types::RepoPathBuf;
+
+use crate::CaseCollisionError;
+use crate::HookPhase;
+use crate::InsufficientSpace;
+use crate::WindowsPathError;
+
+#[derive(Debug, Error)]
+pub enum CheckoutError {
+    /// The store failed to return file content. `key` is set if the store
+    /// attached the failed `Key` as context of the error.
+    #[error("Failed to fetch {}: {source}", fetched(.key))]
+    FetchFailed {
+        key: Option<Key>,
+        source: anyhow::Error,
+    },
+
+    #[error("Failed to write {path}: {source}")]
+    WriteFailed {
+        path: RepoPathBuf,
+        source: anyhow::Error,
+    },
+
+    #[error("Failed to remove {path}: {source}")]
+    RemoveFailed {
+        path: RepoPathBuf,
+        source: anyhow::Error,
+    },
+
+    /// Failed to update the exec flag of `path`.
+    #[error("Failed to update exec on {path}: {source}")]
+    MetaUpdateFailed {
+        path: RepoPathBuf,
+        source: anyhow::Error,
+    },
+
+    /// Failed to stat `path` after writing it, to collect its metadata.
+    #[error("Failed to stat {path} after checkout: {source}")]
+    StatFailed {
+        path: RepoPathBuf,
+        source: anyhow::Error,
+    },
+
+    /// Some files still failed to fetch after `rounds` retries of only
+    /// the failed keys.
+    #[error(
+        "Failed to fetch {} files after {rounds} retries, including {}",
+        .failures.len(),
+        first_failure(.failures)
+    )]
+    FetchRetriesExhausted {
+        rounds: usize,
+        failures: Vec<FetchFailure>,
+    },
+
+    /// Failed to save or restore the ACL and capabilities of `path`, with
+    /// `Checkout::with_preserved_xattrs` in strict mode.
+    #[error("Failed to preserve ACL and capabilities of {path}: {source}")]
+    XattrFailed {
+        path: RepoPathBuf,
+        source: anyhow::Error,
+    },
+
+    /// A hook added with `Checkout::with_hook` failed.
+    #[error("Checkout {phase} hook failed: {source}")]
+    HookFailed {
+        phase: HookPhase,
+        source: anyhow::Error,
+    },
+
+    /// The store returned content for a key that was not requested.
+    #[error("Storage returned unknown key {key}")]
+    KeyNotFound { key: Key },
+
+    /// Files deleted locally are changed by the checkout, with
+    /// `LocallyDeletedPolicy::Abort`.
+    #[error(
+        "{} files deleted locally are changed by the checkout:\n {}",
+        .paths.len(),
+        list_paths(.paths)
+    )]
+    LocallyDeleted { paths: Vec<RepoPathBuf> },
+
+    /// The checkout was stopped before completing. `stats` counts the
+    /// actions applied until then.
+    #[error("Checkout aborted after {stats}")]
+    Aborted { stats: AppliedStats },
+
+    /// The plan can't be applied as it is. This is a bug.
+    #[error("Checkout plan is inconsistent: {detail}")]
+    PlanInconsistent { detail: String },
+
+    #[error(transparent)]
+    PathProblems(#[from] WindowsPathError),
+
+    #[error(transparent)]
+    CaseCollision(#[from] CaseCollisionError),
+
+    /// There isn't enough free space for the plan, see
+    /// `Checkout::with_space_check`.
+    #[error(transparent)]
+    InsufficientSpace(#[from] InsufficientSpace),
+
+    #[error("Failed to normalize case: {source}")]
+    CaseNormalizationFailed { source: anyhow::Error },
+
+    /// Failed to stage content in, or read it back from, the staging
+    /// directory `dir`, see `CheckoutPlan::stage`.
+    #[error("Failed to stage content in {dir:?}: {source}")]
+    StagingFailed { dir: PathBuf, source: anyhow::Error },
+
+    /// Failed to record the state to restore in the spill directory `dir`,
+    /// see `CheckoutPlan::capture_undo`.
+    #[error("Failed to capture undo state in {dir:?}: {source}")]
+    UndoCaptureFailed { dir: PathBuf, source: anyhow::Error },
+
+    /// A blocking task of the checkout panicked or was dropped without
+    /// running, see `Checkout::with_blocking_spawner`.
+    #[error("Checkout task failed: {0}")]
+    TaskFailed(anyhow::Error),
+}
+
+fn fetched(key: &Option<Key>) -> String {
+    match key {
+        Some(key) => key.to_string(),
+        None => "from store".to_string(),
+    }
+}
+
+/// The first paths of `paths`, one per line.
+fn list_paths(paths: &[RepoPathBuf]) -> String {
+    let mut list = paths
+        .iter()
+        .take(5)
+        .map(|p| p.as_str())
+        .collect::<Vec<_>>()
+        .join("\n ");
+    if paths.len() > 5 {
+        list.push_str("\n ...");
+    }
+    list
+}
+
+fn first_failure(failures: &[FetchFailure]) -> String {
+    match failures.first() {
+        Some(failure) => failure.to_string(),
+        None => "none".to_string(),
+    }
+}
+
+/// A key the store failed to return, or did not return at all.
+#[derive(Debug)]
+pub struct FetchFailure {
+    pub key: Key,
+    pub source: anyhow::Error,
+}
+
+impl fmt::Display for FetchFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.source)
+    }
+}
+
+/// Number of actions applied by a checkout.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct AppliedStats {
+    pub removed: usize,
+    pub updated: usize,
+    pub meta_updated: usize,
+}
+
+impl fmt::Display for AppliedStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} removed, {} updated, {} exec changed",
+            self.removed, self.updated, self.meta_updated
+        )
+    }
+}
+
+/// What caused a `CheckoutError`, see `CheckoutError::class`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureClass {
+    /// The disk is full or the quota exceeded, or would have been.
+    DiskFull,
+    PermissionDenied,
+    /// The store does not have the content of some files.
+    StoreNotFound,
+    /// Other failures of the store.
+    Store,
+    /// Other failures to change the working copy.
+    WorkingCopy,
+    /// The plan can't be applied to this working copy: paths Windows can't
+    /// write, paths differing only by case, or files deleted locally.
+    Conflict,
+    Hook,
+    Aborted,
+    /// A bug of the checkout.
+    Internal,
+}
+
+impl CheckoutError {
+    /// The working copy path the error is about, if any.
+    pub fn path(&self) -> Option<&RepoPathBuf> {
+        match self {
+            CheckoutError::WriteFailed { path, .. }
+            | CheckoutError::RemoveFailed { path, .. }
+            | CheckoutError::MetaUpdateFailed { path, .. }
+            | CheckoutError::StatFailed { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    /// The underlying `io::Error`, if the error was caused by one.
+    pub fn io_error(&self) -> Option<&io::Error> {
+        let source = match self {
+            CheckoutError::FetchFailed { source, .. }
+            | CheckoutError::WriteFailed { source, .. }
+            | CheckoutError::RemoveFailed { source, .. }
+            | CheckoutError::MetaUpdateFailed { source, .. }
+            | CheckoutError::StatFailed { source, .. }
+            | CheckoutError::CaseNormalizationFailed { source }
+            | CheckoutError::StagingFailed { source, .. }
+            | CheckoutError::UndoCaptureFailed { source, .. } => source,
+            _ => return None,
+        };
+        source.chain().find_map(|e| e.downcast_ref::<io::Error>())
+    }
+
+    /// Whether the error is caused by a full disk or exceeded quota, or the
+    /// disk would have been full, see `Checkout::with_space_check`.
+    pub fn is_disk_full(&self) -> bool {
+        matches!(self, CheckoutError::InsufficientSpace(_))
+            || self.io_error().map_or(false, is_disk_full)
+    }
+
+    /// Whether the error is caused by missing permissions.
+    pub fn is_permission(&self) -> bool {
+        self.io_error().map_or(false, is_permission)
+    }
+
+    /// Classifies the error, e.g. for `CheckoutFailureReport::failure_bundle`.
+    pub fn class(&self) -> FailureClass {
+        if self.is_disk_full() {
+            return FailureClass::DiskFull;
+        }
+        if self.is_permission() {
+            return FailureClass::PermissionDenied;
+        }
+        match self {
+            CheckoutError::FetchFailed { source, .. } if is_not_found(source) => {
+                FailureClass::StoreNotFound
+            }
+            CheckoutError::FetchRetriesExhausted { failures, .. }
+                if failures.iter().all(|f| is_not_found(&f.source)) =>
+            {
+                FailureClass::StoreNotFound
+            }
+            CheckoutError::FetchFailed { .. }
+            | CheckoutError::FetchRetriesExhausted { .. }
+            | CheckoutError::KeyNotFound { .. } => FailureClass::Store,
+            CheckoutError::WriteFailed { .. }
+            | CheckoutError::RemoveFailed { .. }
+            | CheckoutError::MetaUpdateFailed { .. }
+            | CheckoutError::StatFailed { .. }
+            | CheckoutError::XattrFailed { .. }
+            | CheckoutError::InsufficientSpace(_)
+            | CheckoutError::CaseNormalizationFailed { .. }
+            | CheckoutError::StagingFailed { .. }
+            | CheckoutError::UndoCaptureFailed { .. } => FailureClass::WorkingCopy,
+            CheckoutError::LocallyDeleted { .. }
+            | CheckoutError::PathProblems(_)
+            | CheckoutError::CaseCollision(_) => FailureClass::Conflict,
+            CheckoutError::HookFailed { .. } => FailureClass::Hook,
+            CheckoutError::Aborted { .. } => FailureClass::Aborted,
+            CheckoutError::PlanInconsistent { .. } | CheckoutError::TaskFailed(_) => {
+                FailureClass::Internal
+            }
+        }
+    }
+}
+
+/// Whether `err` is caused by a `NotFound` `io::Error`.
+fn is_not_found(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| e.kind() == io::ErrorKind::NotFound)
+}
+
+#[cfg(unix)]
+const DISK_FULL_ERRORS: &[i32] = &[
+    28, // ENOSPC
+    #[cfg(target_os = "linux")]
+    122, // EDQUOT
+    #[cfg(target_os = "macos")]
+    69, // EDQUOT
+];
+#[cfg(windows)]
+const DISK_FULL_ERRORS: &[i32] = &[
+    39,  // ERROR_HANDLE_DISK_FULL
+    112, // ERROR_DISK_FULL
+];
+
+/// Whether `err` means the disk is full or the quota is exceeded.
+pub fn is_disk_full(err: &io::Error) -> bool {
+    err.raw_os_error()
+        .map_or(false, |code| DISK_FULL_ERRORS.contains(&code))
+}
+
+/// Whether `err` means the access was denied.
+pub fn is_permission(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::PermissionDenied
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Context;
+
+    use super::*;
+
+    fn write_failed(err: io::Error) -> CheckoutError {
+        let source = Err::<(), _>(err).context("Can't write").unwrap_err();
+        CheckoutError::WriteFailed {
+            path: RepoPathBuf::from_string("a/b".to_string()).unwrap(),
+            source,
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_is_disk_full() {
+        let err = write_failed(io::Error::from_raw_os_error(28));
+        assert!(err.is_disk_full());
+        assert!(!err.is_permission());
+    }
+
+    #[test]
+    fn test_is_permission() {
+        let err = write_failed(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(err.is_permission());
+        assert!(!err.is_disk_full());
+        assert_eq!(err.path().map(|p| p.as_str()), Some("a/b"));
+
+        let err = CheckoutError::PlanInconsistent {
+            detail: "test".to_string(),
+        };
+        assert!(err.io_error().is_none());
+    }
+}
@@ -0,0 +1,214 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::fs::Metadata;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use parking_lot::Mutex;
+use tracing::warn;
+use treestate::filestate::FileStateV2;
+use treestate::filestate::StateFlags;
+use types::RepoPath;
+use types::RepoPathBuf;
+use vfs::VFS;
+
+use crate::spawner::run_blocking;
+use crate::spawner::BlockingSpawner;
+use crate::CheckoutError;
+
+/// On-disk state of a file written by checkout, as needed to record it in
+/// the treestate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileStateMetadata {
+    pub size: u64,
+    pub mtime: SystemTime,
+    pub mode: u32,
+}
+
+impl FileStateMetadata {
+    pub fn from_metadata(meta: &Metadata) -> Result<Self> {
+        #[cfg(unix)]
+        let mode = std::os::unix::fs::PermissionsExt::mode(&meta.permissions());
+        #[cfg(windows)]
+        let mode = 0o644; // todo figure this out
+        Ok(Self {
+            size: meta.len(),
+            mtime: meta.modified()?,
+            mode,
+        })
+    }
+
+    /// Treestate entry for a clean file present in the first parent.
+    pub fn to_file_state(&self, path: &RepoPath) -> Result<FileStateV2> {
+        let mtime = self.mtime.duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+        Ok(FileStateV2 {
+            mode: self.mode,
+            size: truncate_u64("size", path, self.size),
+            mtime: truncate_u64("mtime", path, mtime),
+            state: StateFlags::EXIST_P1 | StateFlags::EXIST_NEXT,
+            copied: None,
+        })
+    }
+}
+
+pub(crate) fn truncate_u64(f: &str, path: &RepoPath, v: u64) -> i32 {
+    const RANGE_MASK: u64 = 0x7FFFFFFF;
+    let truncated = v & RANGE_MASK;
+    if truncated != v {
+        warn!("{} for {} is truncated {}=>{}", f, path, v, truncated);
+    }
+    truncated as i32
+}
+
+/// Collects the metadata of every file touched by a checkout. Removed files
+/// are recorded with `None`.
+pub(crate) struct FileMetadataCollector {
+    vfs: VFS,
+    spawner: Arc<dyn BlockingSpawner>,
+    files: Mutex<Vec<(RepoPathBuf, Option<FileStateMetadata>)>>,
+}
+
+impl FileMetadataCollector {
+    pub(crate) fn new(vfs: VFS, spawner: Arc<dyn BlockingSpawner>) -> Self {
+        Self {
+            vfs,
+            spawner,
+            files: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Stats `paths` after they were written or had their mode changed.
+    pub(crate) async fn record_written(
+        &self,
+        paths: Vec<RepoPathBuf>,
+    ) -> Result<(), CheckoutError> {
+        let vfs = self.vfs.clone();
+        let files = run_blocking(&*self.spawner, move || -> Result<Vec<_>, CheckoutError> {
+            paths
+                .into_iter()
+                .map(|path| {
+                    let meta = match vfs
+                        .metadata(&path)
+                        .and_then(|m| FileStateMetadata::from_metadata(&m))
+                    {
+                        Ok(meta) => meta,
+                        Err(source) => return Err(CheckoutError::StatFailed { path, source }),
+                    };
+                    Ok((path, Some(meta)))
+                })
+                .collect()
+        })
+        .await??;
+        self.files.lock().extend(files);
+        Ok(())
+    }
+
+    pub(crate) fn record_removed(&self, paths: &[RepoPathBuf]) {
+        self.files
+            .lock()
+            .extend(paths.iter().map(|path| (path.clone(), None)));
+    }
+
+    pub(crate) fn take(&self) -> Vec<(RepoPathBuf, Option<FileStateMetadata>)> {
+        std::mem::take(&mut *self.files.lock())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use futures::stream;
+    use futures::stream::BoxStream;
+    use futures::StreamExt;
+    use manifest::FileMetadata;
+    use manifest::FileType;
+    use minibytes::Bytes;
+    use storemodel::ReadFileContents;
+    use tempfile::TempDir;
+    use types::HgId;
+    use types::Key;
+
+    use super::*;
+    use crate::actions::UpdateAction;
+    use crate::Action;
+    use crate::ActionMap;
+    use crate::Checkout;
+
+    struct ContentStore;
+
+    #[async_trait::async_trait]
+    impl ReadFileContents for ContentStore {
+        type Error = anyhow::Error;
+
+        async fn read_file_contents(&self, keys: Vec<Key>) -> BoxStream<Result<(Bytes, Key)>> {
+            stream::iter(keys)
+                .map(|key| Ok((key.hgid.to_string().into_bytes().into(), key)))
+                .boxed()
+        }
+
+        async fn read_rename_metadata(
+            &self,
+            _keys: Vec<Key>,
+        ) -> BoxStream<Result<(Key, Option<Key>), Self::Error>> {
+            stream::empty().boxed()
+        }
+    }
+
+    fn rp(p: &str) -> RepoPathBuf {
+        RepoPathBuf::from_string(p.to_string()).unwrap()
+    }
+
+    fn update(id: u8, file_type: FileType) -> Action {
+        let meta = FileMetadata::new(HgId::from_byte_array([id; HgId::len()]), file_type);
+        Action::Update(UpdateAction::new(None, meta))
+    }
+
+    #[tokio::test]
+    async fn test_collect_file_metadata() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        for path in ["old", "dir/exec"] {
+            vfs.write(&rp(path), b"old", vfs::UpdateFlag::Regular)?;
+        }
+
+        let mut map = ActionMap::empty();
+        map.insert(rp("a"), update(1, FileType::Regular));
+        map.insert(rp("dir/b"), update(2, FileType::Executable));
+        map.insert(rp("dir/exec"), Action::UpdateExec(true));
+        map.insert(rp("old"), Action::Remove);
+
+        let plan = Checkout::default_config(vfs.clone())
+            .with_file_metadata(true)
+            .plan_action_map(map);
+        let stats = plan.apply_store(&ContentStore).await?;
+        let files: BTreeMap<_, _> = stats.take_file_metadata().unwrap().into_iter().collect();
+
+        assert_eq!(files.len(), 4);
+        assert_eq!(files[&rp("old")], None);
+        for path in ["a", "dir/b", "dir/exec"] {
+            let expected = FileStateMetadata::from_metadata(&vfs.metadata(&rp(path))?)?;
+            assert_eq!(files[&rp(path)].as_ref(), Some(&expected));
+        }
+        assert_eq!(files[&rp("a")].as_ref().unwrap().size, 40);
+        assert_eq!(files[&rp("dir/exec")].as_ref().unwrap().size, 3);
+        #[cfg(unix)]
+        {
+            assert_eq!(files[&rp("a")].as_ref().unwrap().mode & 0o111, 0);
+            assert_ne!(files[&rp("dir/b")].as_ref().unwrap().mode & 0o111, 0);
+            assert_ne!(files[&rp("dir/exec")].as_ref().unwrap().mode & 0o111, 0);
+        }
+
+        // Not collected by default.
+        let plan = Checkout::default_config(vfs).plan_action_map(ActionMap::empty());
+        let stats = plan.apply_store(&ContentStore).await?;
+        assert!(stats.take_file_metadata().is_none());
+        Ok(())
+    }
+}
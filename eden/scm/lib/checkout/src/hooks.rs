@@ -0,0 +1,172 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::AppliedStats;
+use crate::CheckoutError;
+use crate::CheckoutStats;
+
+/// Code run at the phase boundaries of `CheckoutPlan::apply_store`, see
+/// `Checkout::with_hook`. Every method does nothing by default.
+///
+/// `Checkout::apply_diff_stream` removes and writes files at the same time,
+/// so it runs both pre hooks first, with empty summaries, and `post_remove`
+/// once the whole diff is applied.
+#[async_trait]
+pub trait CheckoutHooks: Send + Sync {
+    /// Before files are removed, and before on-disk names are fixed up to
+    /// the plan's casing.
+    async fn pre_remove(&self, _plan: &PlanSummary) -> Result<()> {
+        Ok(())
+    }
+
+    /// After all removals.
+    async fn post_remove(&self, _stats: &AppliedStats) -> Result<()> {
+        Ok(())
+    }
+
+    /// Before any content is fetched or written, and exec bits are updated.
+    async fn pre_update(&self, _plan: &PlanSummary) -> Result<()> {
+        Ok(())
+    }
+
+    /// After the checkout finished, whether it succeeded or not. Not called
+    /// if an earlier hook aborted the checkout.
+    async fn post_apply(
+        &self,
+        _stats: &AppliedStats,
+        _outcome: Result<(), &CheckoutError>,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Counts of the actions of a plan, and the bytes they write when known,
+/// given to hooks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PlanSummary {
+    pub remove: usize,
+    /// Files to write, excluding those already written by an interrupted
+    /// checkout.
+    pub update_content: usize,
+    /// Files among `update_content` that do not exist yet.
+    pub new_files: usize,
+    /// Files that only need their exec bit updated.
+    pub update_meta: usize,
+    /// Size of the content of `update_content`, if the sizes are known from
+    /// `Checkout::with_space_check`. Files of unknown size are not counted.
+    pub update_bytes: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookPhase {
+    PreRemove,
+    PostRemove,
+    PreUpdate,
+    PostApply,
+}
+
+impl fmt::Display for HookPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HookPhase::PreRemove => "pre_remove",
+            HookPhase::PostRemove => "post_remove",
+            HookPhase::PreUpdate => "pre_update",
+            HookPhase::PostApply => "post_apply",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The hooks of a `Checkout`, run in registration order.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    hooks: Vec<Arc<dyn CheckoutHooks>>,
+    /// Log hook errors instead of failing the checkout.
+    pub(crate) warn_only: bool,
+}
+
+impl Hooks {
+    pub(crate) fn add(&mut self, hook: Arc<dyn CheckoutHooks>) {
+        self.hooks.push(hook);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub(crate) async fn pre_remove(&self, plan: &PlanSummary) -> Result<(), CheckoutError> {
+        for hook in &self.hooks {
+            self.check(HookPhase::PreRemove, hook.pre_remove(plan).await)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn post_remove(&self, stats: &AppliedStats) -> Result<(), CheckoutError> {
+        for hook in &self.hooks {
+            self.check(HookPhase::PostRemove, hook.post_remove(stats).await)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn pre_update(&self, plan: &PlanSummary) -> Result<(), CheckoutError> {
+        for hook in &self.hooks {
+            self.check(HookPhase::PreUpdate, hook.pre_update(plan).await)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn post_apply(
+        &self,
+        stats: &AppliedStats,
+        outcome: Result<(), &CheckoutError>,
+    ) -> Result<(), CheckoutError> {
+        for hook in &self.hooks {
+            self.check(HookPhase::PostApply, hook.post_apply(stats, outcome).await)?;
+        }
+        Ok(())
+    }
+
+    /// Runs `post_apply` with the outcome of a checkout, unless a hook
+    /// aborted it. The checkout's error is returned over the hooks' errors.
+    pub(crate) async fn finish(
+        &self,
+        stats: &CheckoutStats,
+        result: Result<(), CheckoutError>,
+    ) -> Result<(), CheckoutError> {
+        if self.is_empty() {
+            return result;
+        }
+        if let Err(CheckoutError::HookFailed { .. }) = result {
+            return result;
+        }
+        let outcome = result.as_ref().map(|_| ());
+        let hooked = self.post_apply(&stats.applied(), outcome).await;
+        if let (Err(_), Err(e)) = (&result, &hooked) {
+            warn!("Ignoring hook error after failed checkout: {}", e);
+        }
+        result.and(hooked)
+    }
+
+    fn check(&self, phase: HookPhase, result: Result<()>) -> Result<(), CheckoutError> {
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) if self.warn_only => {
+                warn!("Checkout {} hook failed: {:#}", phase, e);
+                Ok(())
+            }
+            Err(source) => Err(CheckoutError::HookFailed { phase, source }),
+        }
+    }
+}
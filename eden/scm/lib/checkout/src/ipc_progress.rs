@@ -0,0 +1,340 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Reports checkout progress over a [`NodeIpc`] channel, e.g. to ISL.
+//!
+//! While the checkout is running, a `checkout_progress` message is sent every
+//! `interval`. Once it completes, a single `checkout_done` or
+//! `checkout_failed` message is sent.
+//!
+//! Sending blocks until the peer reads, so messages are sent from a thread
+//! of their own, never from the checkout's tasks. A peer that stops reading
+//! misses progress messages, and the result is waited for at most
+//! `RESULT_SEND_TIMEOUT`.
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::channel::oneshot;
+use nodeipc::NodeIpc;
+use parking_lot::Condvar;
+use parking_lot::Mutex;
+use parking_lot::MutexGuard;
+use serde_json::json;
+use serde_json::Value;
+use storemodel::ReadFileContents;
+use tracing::warn;
+
+use crate::CheckoutError;
+use crate::CheckoutPlan;
+use crate::CheckoutStats;
+
+/// How long the result message is waited for once the checkout completes.
+const RESULT_SEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl CheckoutPlan {
+    /// Same as `apply_store`, but reports progress and the final result on
+    /// `ipc`. Failing to send on `ipc` is logged and does not fail the
+    /// checkout.
+    pub async fn apply_stream_with_ipc_progress(
+        &self,
+        store: &dyn ReadFileContents<Error = anyhow::Error>,
+        ipc: Arc<NodeIpc>,
+        interval: Duration,
+    ) -> Result<CheckoutStats, CheckoutError> {
+        let total_actions = self.total_actions();
+        let stats = Arc::new(CheckoutStats::new(&self.checkout));
+
+        let reporter = Arc::new(Reporter::default());
+        let (sent_tx, sent_rx) = oneshot::channel();
+        // Not a blocking task: it is left behind while the peer does not
+        // read, and must not delay the shutdown of the runtime.
+        std::thread::spawn({
+            let stats = Arc::downgrade(&stats);
+            let reporter = reporter.clone();
+            move || {
+                reporter.run(&ipc, &stats, total_actions, interval);
+                let _ = sent_tx.send(());
+            }
+        });
+
+        let result = self.apply_store_with_stats(store, &stats).await;
+
+        let message = match &result {
+            Ok(()) => done_message(&stats, total_actions),
+            Err(e) => json!({
+                "type": "checkout_failed",
+                "error": format!("{:#}", e),
+            }),
+        };
+        // No progress message is sent after this one.
+        reporter.finish(message);
+        if tokio::time::timeout(RESULT_SEND_TIMEOUT, sent_rx)
+            .await
+            .is_err()
+        {
+            warn!("Timed out sending checkout result over IPC");
+        }
+
+        result.map(|()| {
+            Arc::try_unwrap(stats)
+                .ok()
+                .expect("reporter should only hold stats while not finished")
+        })
+    }
+}
+
+#[derive(Default)]
+struct Reporter {
+    /// The result message, once the checkout completed.
+    result: Mutex<Option<Value>>,
+    cond: Condvar,
+}
+
+impl Reporter {
+    /// Sends progress every `interval`, then the result message. `stats`
+    /// are only upgraded with `result` locked, so they are released once
+    /// `finish` returns.
+    fn run(&self, ipc: &NodeIpc, stats: &Weak<CheckoutStats>, total: usize, interval: Duration) {
+        let mut result = self.result.lock();
+        loop {
+            if let Some(message) = result.take() {
+                drop(result);
+                send(ipc, message);
+                return;
+            }
+            let message = match stats.upgrade() {
+                Some(stats) => progress_message(&stats, total),
+                None => return,
+            };
+            MutexGuard::unlocked(&mut result, || send(ipc, message));
+            if result.is_none() {
+                self.cond.wait_for(&mut result, interval);
+            }
+        }
+    }
+
+    fn finish(&self, message: Value) {
+        *self.result.lock() = Some(message);
+        self.cond.notify_all();
+    }
+}
+
+fn progress_message(stats: &CheckoutStats, total_actions: usize) -> Value {
+    json!({
+        "type": "checkout_progress",
+        "updated": stats.updated.load(Ordering::Relaxed),
+        "removed": stats.removed.load(Ordering::Relaxed),
+        "written_bytes": stats.written_bytes.load(Ordering::Relaxed),
+        "total_actions": total_actions,
+    })
+}
+
+fn done_message(stats: &CheckoutStats, total_actions: usize) -> Value {
+    json!({
+        "type": "checkout_done",
+        "updated": stats.updated.load(Ordering::Relaxed),
+        "removed": stats.removed.load(Ordering::Relaxed),
+        "meta_updated": stats.meta_updated.load(Ordering::Relaxed),
+        "written_bytes": stats.written_bytes.load(Ordering::Relaxed),
+        "total_actions": total_actions,
+    })
+}
+
+fn send(ipc: &NodeIpc, message: Value) {
+    if let Err(e) = ipc.send(message) {
+        warn!("Failed to send checkout progress over IPC: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::anyhow;
+    use filedescriptor::IntoRawFileDescriptor;
+    use futures::stream;
+    use futures::stream::BoxStream;
+    use futures::StreamExt;
+    use manifest::FileMetadata;
+    use minibytes::Bytes;
+    use tempfile::TempDir;
+    use types::HgId;
+    use types::Key;
+    use types::RepoPathBuf;
+    use vfs::VFS;
+
+    use super::*;
+    use crate::actions::UpdateAction;
+    use crate::Action;
+    use crate::ActionMap;
+    use crate::Checkout;
+
+    /// Returns file contents one at a time, with a delay before each.
+    struct SlowStore {
+        delay: Duration,
+        fail: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl ReadFileContents for SlowStore {
+        type Error = anyhow::Error;
+
+        async fn read_file_contents(&self, keys: Vec<Key>) -> BoxStream<Result<(Bytes, Key)>> {
+            let delay = self.delay;
+            let fail = self.fail;
+            stream::iter(keys)
+                .then(move |key| async move {
+                    tokio::time::sleep(delay).await;
+                    if fail {
+                        return Err(anyhow!("store unavailable"));
+                    }
+                    Ok((key.hgid.to_string().into_bytes().into(), key))
+                })
+                .boxed()
+        }
+
+        async fn read_rename_metadata(
+            &self,
+            _keys: Vec<Key>,
+        ) -> BoxStream<Result<(Key, Option<Key>), Self::Error>> {
+            stream::empty().boxed()
+        }
+    }
+
+    fn plan(tempdir: &TempDir, count: u8) -> Result<CheckoutPlan> {
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        let mut map = ActionMap::empty();
+        for i in 0..count {
+            let path = RepoPathBuf::from_string(format!("file{}", i))?;
+            let meta = FileMetadata::regular(HgId::from_byte_array([i + 1; HgId::len()]));
+            map.insert(path, Action::Update(UpdateAction::new(None, meta)));
+        }
+        Ok(Checkout::default_config(vfs).plan_action_map(map))
+    }
+
+    fn ipc_pair() -> (Arc<NodeIpc>, NodeIpc) {
+        let (a, b) = filedescriptor::socketpair().unwrap();
+        let a = NodeIpc::from_raw_file_descriptor(a.into_raw_file_descriptor()).unwrap();
+        let b = NodeIpc::from_raw_file_descriptor(b.into_raw_file_descriptor()).unwrap();
+        (Arc::new(a), b)
+    }
+
+    fn recv_all(ipc: &NodeIpc) -> Vec<Value> {
+        let mut messages = Vec::new();
+        while let Some(message) = ipc.recv::<Value>().unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn test_progress_and_done() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let plan = plan(&tempdir, 5)?;
+        let (ipc, peer) = ipc_pair();
+        let store = SlowStore {
+            delay: Duration::from_millis(20),
+            fail: false,
+        };
+
+        let stats = plan
+            .apply_stream_with_ipc_progress(&store, ipc, Duration::from_millis(5))
+            .await?;
+        assert_eq!(stats.updated.load(Ordering::Relaxed), 5);
+
+        let messages = recv_all(&peer);
+        let (last, progress) = messages.split_last().unwrap();
+        assert!(!progress.is_empty());
+        for message in progress {
+            assert_eq!(message["type"], "checkout_progress");
+            assert_eq!(message["total_actions"], 5);
+            assert!(message["updated"].as_u64().unwrap() <= 5);
+        }
+        assert_eq!(last["type"], "checkout_done");
+        assert_eq!(last["updated"], 5);
+        assert_eq!(last["removed"], 0);
+        assert_eq!(last["total_actions"], 5);
+        assert_eq!(last["written_bytes"], 5 * 40);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let plan = plan(&tempdir, 3)?;
+        let (ipc, peer) = ipc_pair();
+        let store = SlowStore {
+            delay: Duration::from_millis(20),
+            fail: true,
+        };
+
+        let result = plan
+            .apply_stream_with_ipc_progress(&store, ipc, Duration::from_millis(5))
+            .await;
+        assert!(matches!(result, Err(CheckoutError::FetchFailed { .. })));
+
+        let messages = recv_all(&peer);
+        let terminal: Vec<_> = messages
+            .iter()
+            .filter(|m| m["type"] != "checkout_progress")
+            .collect();
+        assert_eq!(terminal.len(), 1);
+        assert_eq!(messages.last().unwrap()["type"], "checkout_failed");
+        assert!(terminal[0]["error"]
+            .as_str()
+            .unwrap()
+            .contains("store unavailable"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_failure_does_not_fail_checkout() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let plan = plan(&tempdir, 2)?;
+        let (ipc, peer) = ipc_pair();
+        drop(peer);
+        let store = SlowStore {
+            delay: Duration::from_millis(5),
+            fail: false,
+        };
+
+        let stats = plan
+            .apply_stream_with_ipc_progress(&store, ipc, Duration::from_millis(1))
+            .await?;
+        assert_eq!(stats.updated.load(Ordering::Relaxed), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_peer_not_reading() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let plan = plan(&tempdir, 2)?;
+        let (ipc, peer) = ipc_pair();
+        // Blocks sending until the peer reads, which it never does.
+        let blocked = std::thread::spawn({
+            let ipc = ipc.clone();
+            move || ipc.send("x".repeat(16 << 20))
+        });
+        let store = SlowStore {
+            delay: Duration::from_millis(5),
+            fail: false,
+        };
+
+        let stats = tokio::time::timeout(
+            RESULT_SEND_TIMEOUT * 5,
+            plan.apply_stream_with_ipc_progress(&store, ipc, Duration::from_millis(1)),
+        )
+        .await??;
+        assert_eq!(stats.updated.load(Ordering::Relaxed), 2);
+
+        drop(peer);
+        assert!(blocked.join().unwrap().is_err());
+        Ok(())
+    }
+}
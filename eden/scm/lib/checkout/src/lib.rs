@@ -6,8 +6,10 @@
  */
 
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt;
 use std::fs::File;
+use std::future::Future;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::io::Write;
@@ -15,14 +17,16 @@ use std::path::Path;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 use std::time::SystemTime;
 
 use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::format_err;
-use anyhow::Context;
 use anyhow::Result;
 use async_runtime::try_block_unless_interrupted as block_on;
+use futures::future;
 use futures::stream;
 use futures::try_join;
 use futures::Stream;
@@ -63,22 +67,114 @@ use workingcopy::workingcopy::WorkingCopy;
 
 #[allow(dead_code)]
 mod actions;
+mod canonical;
+mod case_normalization;
 pub mod clone;
 #[allow(dead_code)]
 mod conflict;
+mod content_filter;
+mod diff_stream;
+mod diff_summary;
+mod dir_batches;
+mod errors;
+mod file_metadata;
+mod hooks;
+#[cfg(feature = "ipc-progress")]
+mod ipc_progress;
+mod locally_deleted;
+mod memory;
 #[allow(dead_code)]
 mod merge;
+mod notify;
+#[cfg(all(test, unix))]
+mod plan_fuzz;
+mod priority;
+pub mod progress;
+mod report;
+mod space;
+mod spawner;
+mod staging;
+mod undo;
+mod windows_paths;
+mod xattrs;
 
 pub use actions::Action;
 pub use actions::ActionMap;
+pub use case_normalization::CaseCollisionError;
+pub use case_normalization::CaseNormalization;
+use configmodel::convert::ByteCount;
 use configmodel::Config;
 use configmodel::ConfigExt;
 pub use conflict::Conflict;
+pub use content_filter::ContentFilter;
+use content_filter::ContentFiltering;
+pub use content_filter::FilterOutcome;
+pub use diff_stream::DiffStreamOptions;
+pub use diff_summary::DiffSummary;
+pub use diff_summary::DirSummary;
+pub use dir_batches::DirBatching;
+pub use errors::is_disk_full;
+pub use errors::is_permission;
+pub use errors::AppliedStats;
+pub use errors::CheckoutError;
+pub use errors::FailureClass;
+pub use errors::FetchFailure;
+use file_metadata::FileMetadataCollector;
+pub use file_metadata::FileStateMetadata;
+pub use hooks::CheckoutHooks;
+pub use hooks::HookPhase;
+use hooks::Hooks;
+pub use hooks::PlanSummary;
+pub use locally_deleted::LocallyDeletedPolicy;
+use memory::MemoryBudget;
+pub use memory::MemoryLimits;
 pub use merge::Merge;
 pub use merge::MergeResult;
+pub use notify::JsonLinesNotifier;
+pub use notify::NoopNotifier;
+pub use notify::NotificationBatch;
+use notify::OutcomeRecorder;
+pub use notify::PathOutcome;
+pub use notify::WorkingCopyNotifier;
+use priority::PriorityPaths;
+pub use report::CheckoutFailureReport;
+pub use report::EffectiveOptions;
+pub use report::FailedAction;
+pub use report::FailedActionKind;
+pub use report::FailureBundle;
+pub use report::FailureBundleOptions;
+pub use report::FailureCause;
+pub use report::FailureEnvironment;
+pub use report::FailureTiming;
+pub use space::FileSizes;
+pub use space::FreeSpaceProbe;
+pub use space::InsufficientSpace;
+pub use space::SpaceCheck;
+pub use space::SpaceEstimate;
+use spawner::run_blocking;
+pub use spawner::BlockingSpawner;
+pub use spawner::BlockingTask;
+pub use spawner::SyncSpawner;
+pub use spawner::ThreadPoolSpawner;
+pub use spawner::TokioSpawner;
+pub use staging::find_stale_staging;
+use staging::ContentSource;
+pub use staging::StagedCheckout;
+pub use staging::StaleStaging;
 use status::FileStatus;
 use status::Status;
-use tokio::runtime::Handle;
+pub use undo::ReverseMetadata;
+pub use undo::UndoOptions;
+pub use undo::UndoOverflow;
+pub use undo::UndoPlan;
+use vfs::BatchFailure;
+use vfs::FileModePolicy;
+pub use windows_paths::PathProblem;
+pub use windows_paths::PathProblemKind;
+pub use windows_paths::WindowsPathError;
+use xattrs::XattrPaths;
+use xattrs::XattrPreserver;
+pub use xattrs::XattrStats;
 
 const VFS_BATCH_SIZE: usize = 100;
 
@@ -93,6 +189,11 @@ pub struct CheckoutPlan {
     filtered_update_content: Vec<UpdateContentAction>,
     /// Files that only need X flag updated.
     update_meta: Vec<UpdateMetaAction>,
+    diff_summary: DiffSummary,
+    /// Files deleted locally that are written again, sorted.
+    restored: Vec<RepoPathBuf>,
+    /// Files deleted locally that are left deleted, sorted.
+    kept_deleted: Vec<RepoPathBuf>,
     progress: Option<Mutex<CheckoutProgress>>,
     checkout: Checkout,
 }
@@ -132,15 +233,161 @@ pub struct CheckoutStats {
     updated: AtomicUsize,
     meta_updated: AtomicUsize,
     written_bytes: AtomicUsize,
+    /// Existing entries renamed (or removed) to match the plan's casing.
+    case_renamed: AtomicUsize,
+    /// Time from the start of the checkout until all priority paths were
+    /// written.
+    priority_complete: Mutex<Option<Duration>>,
+    /// Set if `Checkout::with_file_metadata` is enabled.
+    file_metadata: Option<FileMetadataCollector>,
+    /// Paths written that Windows can't write as-is.
+    path_problems: Mutex<Vec<PathProblem>>,
+    /// Keys fetched again after failing, counted once per retry.
+    retried_keys: AtomicUsize,
+    retry_rounds: AtomicUsize,
+    /// Set if `Checkout::with_preserved_xattrs` is enabled.
+    xattrs: Option<XattrPreserver>,
+    /// Fetched content not written yet.
+    memory: MemoryBudget,
+    /// See `CheckoutPlan::with_locally_deleted`.
+    restored: AtomicUsize,
+    kept_deleted: Mutex<Vec<RepoPathBuf>>,
+    /// Set if `Checkout::with_notifier` is set.
+    outcomes: Option<OutcomeRecorder>,
+    warnings: Mutex<Vec<String>>,
+    /// Set if `Checkout::with_content_filter` is set.
+    content_filter: Option<ContentFiltering>,
+}
+
+impl CheckoutStats {
+    fn new(checkout: &Checkout) -> Self {
+        Self {
+            file_metadata: checkout.collect_file_metadata.then(|| {
+                FileMetadataCollector::new(checkout.vfs.clone(), checkout.shared_spawner())
+            }),
+            xattrs: checkout.preserve_xattrs.clone().map(|paths| {
+                XattrPreserver::new(checkout.vfs.clone(), checkout.shared_spawner(), paths)
+            }),
+            memory: MemoryBudget::new(checkout.memory_limits),
+            outcomes: checkout.notifier.is_some().then(OutcomeRecorder::default),
+            content_filter: checkout.content_filter.clone().map(|filter| {
+                ContentFiltering::new(
+                    filter,
+                    checkout.shared_spawner(),
+                    checkout.report_unfiltered_bytes,
+                )
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// The metadata of files touched by the checkout, if
+    /// `Checkout::with_file_metadata` is enabled. Removed files have `None`.
+    /// Subsequent calls return an empty list.
+    pub fn take_file_metadata(&self) -> Option<Vec<(RepoPathBuf, Option<FileStateMetadata>)>> {
+        self.file_metadata.as_ref().map(|c| c.take())
+    }
+
+    /// How long it took to write all files matching
+    /// `Checkout::with_priority_paths`, or `None` if there were none.
+    pub fn time_to_priority_complete(&self) -> Option<Duration> {
+        *self.priority_complete.lock()
+    }
+
+    /// Paths written by the checkout that Windows can't write as-is, found
+    /// on every platform. See `PathProblemKind`.
+    pub fn path_problems(&self) -> Vec<PathProblem> {
+        self.path_problems.lock().clone()
+    }
+
+    /// Number of keys fetched again because fetching them failed. A key
+    /// retried twice is counted twice.
+    pub fn retried_keys(&self) -> usize {
+        self.retried_keys.load(Ordering::Relaxed)
+    }
+
+    /// Number of rounds of fetching failed keys again.
+    pub fn retry_rounds(&self) -> usize {
+        self.retry_rounds.load(Ordering::Relaxed)
+    }
+
+    /// Files whose ACL and capabilities were preserved, if
+    /// `Checkout::with_preserved_xattrs` is enabled.
+    pub fn xattr_stats(&self) -> Option<XattrStats> {
+        self.xattrs.as_ref().map(|x| x.stats())
+    }
+
+    /// The most bytes of fetched content held at once before being written,
+    /// see `Checkout::with_memory_limits`.
+    pub fn peak_bytes_in_flight(&self) -> usize {
+        self.memory.peak()
+    }
+
+    /// Number of files deleted locally that were written again, see
+    /// `CheckoutPlan::with_locally_deleted`.
+    pub fn restored(&self) -> usize {
+        self.restored.load(Ordering::Relaxed)
+    }
+
+    /// Files deleted locally that the checkout left deleted, see
+    /// `LocallyDeletedPolicy::KeepDeleted`.
+    pub fn kept_deleted(&self) -> Vec<RepoPathBuf> {
+        self.kept_deleted.lock().clone()
+    }
+
+    /// Problems that did not fail the checkout, like failing to notify the
+    /// notifier set with `Checkout::with_notifier`.
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.lock().clone()
+    }
+
+    /// Bytes written before `Checkout::with_content_filter` filtered them,
+    /// if it was asked to report them. Otherwise comparable with the written
+    /// bytes, which are counted after filtering.
+    pub fn unfiltered_bytes(&self) -> Option<usize> {
+        self.content_filter
+            .as_ref()
+            .and_then(|filtering| filtering.unfiltered_bytes())
+    }
+
+    fn record_outcome(&self, paths: impl IntoIterator<Item = RepoPathBuf>, outcome: PathOutcome) {
+        if let Some(outcomes) = &self.outcomes {
+            outcomes.record(paths, outcome);
+        }
+    }
+
+    pub(crate) fn applied(&self) -> AppliedStats {
+        AppliedStats {
+            removed: self.removed.load(Ordering::Relaxed),
+            updated: self.updated.load(Ordering::Relaxed),
+            meta_updated: self.meta_updated.load(Ordering::Relaxed),
+        }
+    }
 }
 
 const DEFAULT_CONCURRENCY: usize = 16;
+const DEFAULT_FETCH_RETRIES: usize = 2;
 const MAX_CHECK_UNKNOWN: usize = 5000;
 
 #[derive(Clone)]
 pub struct Checkout {
     vfs: VFS,
     concurrency: usize,
+    case_normalization: CaseNormalization,
+    priority_paths: PriorityPaths,
+    collect_file_metadata: bool,
+    allow_reserved_names: bool,
+    fetch_retries: usize,
+    preserve_xattrs: Option<XattrPaths>,
+    hooks: Hooks,
+    memory_limits: MemoryLimits,
+    space_check: Option<SpaceCheck>,
+    dir_batching: Option<DirBatching>,
+    spawner: Option<Arc<dyn BlockingSpawner>>,
+    deterministic: bool,
+    notifier: Option<Arc<dyn WorkingCopyNotifier>>,
+    content_filter: Option<Arc<dyn ContentFilter>>,
+    report_unfiltered_bytes: bool,
 }
 
 impl Checkout {
@@ -148,6 +395,21 @@ impl Checkout {
         Self {
             vfs,
             concurrency: DEFAULT_CONCURRENCY,
+            case_normalization: CaseNormalization::default(),
+            priority_paths: PriorityPaths::default(),
+            collect_file_metadata: false,
+            allow_reserved_names: false,
+            fetch_retries: DEFAULT_FETCH_RETRIES,
+            preserve_xattrs: None,
+            hooks: Hooks::default(),
+            memory_limits: MemoryLimits::default(),
+            space_check: None,
+            dir_batching: None,
+            spawner: None,
+            deterministic: false,
+            notifier: None,
+            content_filter: None,
+            report_unfiltered_bytes: false,
         }
     }
 
@@ -156,7 +418,265 @@ impl Checkout {
             .get_opt("nativecheckout", "concurrency")
             .map_err(|e| format_err!("Failed to parse nativecheckout.concurrency: {}", e))?;
         let concurrency = concurrency.unwrap_or(DEFAULT_CONCURRENCY);
-        Ok(Self { vfs, concurrency })
+        let case_normalization = config
+            .get_opt::<bool>("nativecheckout", "casenormalization")
+            .map_err(|e| format_err!("Failed to parse nativecheckout.casenormalization: {}", e))?;
+        let case_normalization = match case_normalization {
+            None => CaseNormalization::Auto,
+            Some(true) => CaseNormalization::Always,
+            Some(false) => CaseNormalization::Never,
+        };
+        let allow_reserved_names = config
+            .get_opt::<bool>("nativecheckout", "allowreservednames")
+            .map_err(|e| format_err!("Failed to parse nativecheckout.allowreservednames: {}", e))?
+            .unwrap_or_default();
+        let fetch_retries = config
+            .get_opt("nativecheckout", "fetchretries")
+            .map_err(|e| format_err!("Failed to parse nativecheckout.fetchretries: {}", e))?
+            .unwrap_or(DEFAULT_FETCH_RETRIES);
+        let mut memory_limits = MemoryLimits::default();
+        if let Some(max) = config
+            .get_opt::<ByteCount>("nativecheckout", "maxbytesinflight")
+            .map_err(|e| format_err!("Failed to parse nativecheckout.maxbytesinflight: {}", e))?
+        {
+            memory_limits.max_bytes_in_flight = max.value() as usize;
+        }
+        if let Some(threshold) = config
+            .get_opt::<ByteCount>("nativecheckout", "smallfilethreshold")
+            .map_err(|e| format_err!("Failed to parse nativecheckout.smallfilethreshold: {}", e))?
+        {
+            memory_limits.small_file_threshold = threshold.value() as usize;
+        }
+        if let Some(percent) = config
+            .get_opt("nativecheckout", "fastlanepercent")
+            .map_err(|e| format_err!("Failed to parse nativecheckout.fastlanepercent: {}", e))?
+        {
+            memory_limits.fast_lane_percent = percent;
+        }
+        let dir_batching = config
+            .get_opt::<bool>("nativecheckout", "dirbatching")
+            .map_err(|e| format_err!("Failed to parse nativecheckout.dirbatching: {}", e))?
+            .unwrap_or_default()
+            .then(DirBatching::default);
+        let vfs = if allow_reserved_names {
+            vfs.with_reserved_names(true)
+        } else {
+            vfs
+        };
+        Ok(Self {
+            vfs,
+            concurrency,
+            case_normalization,
+            priority_paths: PriorityPaths::default(),
+            collect_file_metadata: false,
+            allow_reserved_names,
+            fetch_retries,
+            preserve_xattrs: None,
+            hooks: Hooks::default(),
+            memory_limits,
+            space_check: None,
+            dir_batching,
+            spawner: None,
+            deterministic: false,
+            notifier: None,
+            content_filter: None,
+            report_unfiltered_bytes: false,
+        })
+    }
+
+    /// Overrides the number of filesystem operations run concurrently.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Overrides whether to fix up on-disk names that differ from the plan
+    /// only by case. By default, this is done on case-insensitive filesystems.
+    pub fn with_case_normalization(mut self, case_normalization: CaseNormalization) -> Self {
+        self.case_normalization = case_normalization;
+        self
+    }
+
+    /// Write files matching `paths` before any other file. Each entry is
+    /// either a file or a directory, which matches all files below it.
+    /// Removals and exec bit updates are not affected.
+    pub fn with_priority_paths(mut self, paths: Vec<RepoPathBuf>) -> Self {
+        self.priority_paths = PriorityPaths::new(paths);
+        self
+    }
+
+    /// Record the size, mtime and mode of every written file, and the paths
+    /// of removed files, in the returned `CheckoutStats`, so the caller can
+    /// update the treestate without statting files again. Each file is
+    /// statted once after it is written. The list is kept in memory until
+    /// the checkout finishes, which is about a hundred bytes per file.
+    pub fn with_file_metadata(mut self, collect: bool) -> Self {
+        self.collect_file_metadata = collect;
+        self
+    }
+
+    /// On Windows, write names that would otherwise be written somewhere
+    /// else, like `aux.c` or `foo.`, through extended-length (`\\?\`) paths
+    /// instead of failing the checkout. The filesystem may still reject
+    /// them, and many tools can't open such files.
+    pub fn with_reserved_names(mut self, allow: bool) -> Self {
+        self.vfs = self.vfs.with_reserved_names(allow);
+        self.allow_reserved_names = allow;
+        self
+    }
+
+    /// How the permissions of the files written are chosen. By default, files
+    /// overwritten keep their mode but for the exec bits, see
+    /// `FileModePolicy::PreserveExisting`.
+    pub fn with_file_mode_policy(mut self, policy: FileModePolicy) -> Self {
+        self.vfs = self.vfs.with_file_mode_policy(policy);
+        self
+    }
+
+    /// When fetching some files fails, fetch only those again, up to
+    /// `retries` times, after the others were fetched. Only errors that
+    /// carry the failed `Key` as context, and keys the store did not return,
+    /// are retried. With 0, the checkout fails on the first fetch error.
+    ///
+    /// Keys the store did not return fail the checkout with
+    /// `CheckoutError::FetchRetriesExhausted` once retries are exhausted,
+    /// including with 0 retries, instead of leaving their files unwritten.
+    pub fn with_fetch_retries(mut self, retries: usize) -> Self {
+        self.fetch_retries = retries;
+        self
+    }
+
+    /// Before overwriting files matching `matcher`, save their POSIX ACL and
+    /// file capabilities (see `vfs::PRESERVED_XATTRS`), and restore them
+    /// after the new content is written. Only supported on Linux; elsewhere
+    /// this does nothing. Failures are logged and counted in
+    /// `CheckoutStats::xattr_stats`, unless `strict`, which fails the
+    /// checkout instead, and fails right away on other platforms.
+    pub fn with_preserved_xattrs(
+        mut self,
+        matcher: Arc<dyn Matcher + Sync + Send>,
+        strict: bool,
+    ) -> Result<Self> {
+        if !vfs::XATTRS_SUPPORTED {
+            if strict {
+                bail!("Preserving ACLs and capabilities is only supported on Linux");
+            }
+            return Ok(self);
+        }
+        self.preserve_xattrs = Some(XattrPaths { matcher, strict });
+        Ok(self)
+    }
+
+    /// Run `hook` at the phase boundaries of the checkout, after the hooks
+    /// added before it. A hook error fails the checkout before its next
+    /// phase, unless `with_hook_errors_as_warnings` is set.
+    pub fn with_hook(mut self, hook: Arc<dyn CheckoutHooks>) -> Self {
+        self.hooks.add(hook);
+        self
+    }
+
+    /// Log hook errors instead of failing the checkout.
+    pub fn with_hook_errors_as_warnings(mut self, warn_only: bool) -> Self {
+        self.hooks.warn_only = warn_only;
+        self
+    }
+
+    /// Bounds the fetched content held in memory until it is written, in
+    /// addition to `concurrency`, which bounds the number of batches.
+    pub fn with_memory_limits(mut self, limits: MemoryLimits) -> Self {
+        self.memory_limits = limits;
+        self
+    }
+
+    /// Before applying or staging a plan from the store, check that the
+    /// working copy's filesystem has room for the files it writes, net of
+    /// the files it removes or overwrites. Fails with `InsufficientSpace`
+    /// before anything is written if not, unless `SpaceCheck::with_force`.
+    pub fn with_space_check(mut self, check: SpaceCheck) -> Self {
+        self.space_check = Some(check);
+        self
+    }
+
+    /// Groups fetched content by directory when written, see `DirBatching`.
+    /// With `None`, the default, each file is audited and written by path.
+    /// Enabled by `nativecheckout.dirbatching`.
+    pub fn with_dir_batching(mut self, dir_batching: Option<DirBatching>) -> Self {
+        self.dir_batching = dir_batching;
+        self
+    }
+
+    /// Runs the blocking filesystem work of the checkout with `spawner`,
+    /// including writing, removing and changing the exec bit of files,
+    /// instead of on the blocking pool of the tokio runtime and on threads
+    /// of the checkout's own. See `SyncSpawner` and `ThreadPoolSpawner`.
+    pub fn with_blocking_spawner(mut self, spawner: Arc<dyn BlockingSpawner>) -> Self {
+        self.spawner = Some(spawner);
+        self
+    }
+
+    /// Applies plans in a deterministic order, so that filesystem snapshots
+    /// of checkouts can be compared across machines: files are removed,
+    /// then written, then have their exec bit changed, one batch at a time
+    /// and in the order of `CheckoutPlan::normalize`, whatever order the
+    /// store returns content in. Priority paths are still written first.
+    ///
+    /// This is much slower, as content is fetched one batch at a time, and
+    /// disables `with_dir_batching`. Content placed from a `StagedCheckout`
+    /// is not ordered.
+    pub fn with_deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Tells `notifier` which paths were written, removed or had their exec
+    /// bit changed once the plan is applied, and before the `post_apply`
+    /// hooks, whether the checkout succeeded or not. Paths of the writes
+    /// and removals that failed the checkout are reported as failed.
+    ///
+    /// Notifier errors do not fail the checkout, they are logged and listed
+    /// in `CheckoutStats::warnings`.
+    pub fn with_notifier(mut self, notifier: Arc<dyn WorkingCopyNotifier>) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Write regular and executable files through `filter`, like converting
+    /// line endings. Symlinks are written as is. Content is still fetched
+    /// and staged unfiltered, so stores verifying fetched content against its
+    /// hash check the content in the repository, not the filtered content.
+    /// Untracked files are compared with the filtered content. The written
+    /// bytes of `CheckoutStats` are counted after filtering, and also before
+    /// if `report_unfiltered_bytes`, see `CheckoutStats::unfiltered_bytes`.
+    pub fn with_content_filter(
+        mut self,
+        filter: Arc<dyn ContentFilter>,
+        report_unfiltered_bytes: bool,
+    ) -> Self {
+        self.content_filter = Some(filter);
+        self.report_unfiltered_bytes = report_unfiltered_bytes;
+        self
+    }
+
+    pub(crate) fn spawner(&self) -> &dyn BlockingSpawner {
+        self.spawner.as_deref().unwrap_or(&TokioSpawner)
+    }
+
+    /// Writer of the working copy, running on the spawner of
+    /// `with_blocking_spawner` if set.
+    pub(crate) fn async_vfs(&self) -> AsyncVfsWriter {
+        match &self.spawner {
+            Some(spawner) => AsyncVfsWriter::with_executor(
+                self.vfs.clone(),
+                spawner::vfs_executor(spawner.clone()),
+            ),
+            None => AsyncVfsWriter::spawn_new(self.vfs.clone(), 16),
+        }
+    }
+
+    fn shared_spawner(&self) -> Arc<dyn BlockingSpawner> {
+        self.spawner
+            .clone()
+            .unwrap_or_else(|| Arc::new(TokioSpawner))
     }
 
     pub fn plan_action_map(&self, map: ActionMap) -> CheckoutPlan {
@@ -166,6 +686,7 @@ impl Checkout {
 
 impl CheckoutPlan {
     fn from_action_map(checkout: Checkout, map: ActionMap) -> Self {
+        let diff_summary = DiffSummary::from_actions(&map, diff_summary::DEFAULT_TOP_DIRS);
         let mut remove = vec![];
         let mut update_content = vec![];
         let mut update_meta = vec![];
@@ -186,6 +707,9 @@ impl CheckoutPlan {
             update_content,
             filtered_update_content,
             update_meta,
+            diff_summary,
+            restored: Vec::new(),
+            kept_deleted: Vec::new(),
             progress: None,
             checkout,
         }
@@ -226,74 +750,534 @@ impl CheckoutPlan {
     pub async fn apply_store(
         &self,
         store: &dyn ReadFileContents<Error = anyhow::Error>,
-    ) -> Result<CheckoutStats> {
+    ) -> Result<CheckoutStats, CheckoutError> {
+        let stats = CheckoutStats::new(&self.checkout);
+        self.apply_store_with_stats(store, &stats).await?;
+        Ok(stats)
+    }
+
+    /// Stats to pass to `apply_store_with_stats`.
+    pub fn new_stats(&self) -> CheckoutStats {
+        CheckoutStats::new(&self.checkout)
+    }
+
+    /// Same as `apply_store`, but updates the caller's `stats` as the checkout
+    /// progresses so they can be observed while it is running. On failure,
+    /// `failure_report` makes a `CheckoutFailureReport` of the error.
+    pub async fn apply_store_with_stats(
+        &self,
+        store: &dyn ReadFileContents<Error = anyhow::Error>,
+        stats_ref: &CheckoutStats,
+    ) -> Result<(), CheckoutError> {
+        self.check_space().await?;
+        self.apply_with_stats(ContentSource::Store(store), stats_ref)
+            .await
+    }
+
+    /// Applies the plan, taking content from `source`.
+    async fn apply_with_stats(
+        &self,
+        source: ContentSource<'_>,
+        stats_ref: &CheckoutStats,
+    ) -> Result<(), CheckoutError> {
+        let result = self.apply_phases(source, stats_ref).await;
+        self.notify(stats_ref).await;
+        self.checkout.hooks.finish(stats_ref, result).await
+    }
+
+    /// Sends the outcomes recorded in `stats_ref` to the notifier, if any.
+    async fn notify(&self, stats_ref: &CheckoutStats) {
+        let (notifier, outcomes) = match (&self.checkout.notifier, &stats_ref.outcomes) {
+            (Some(notifier), Some(outcomes)) => (notifier, outcomes),
+            _ => return,
+        };
+        for batch in outcomes.take_batches() {
+            let notifier = notifier.clone();
+            let result = run_blocking(self.checkout.spawner(), move || notifier.notify(batch))
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|r| r);
+            if let Err(e) = result {
+                let warning = format!("Failed to notify of checkout changes: {:#}", e);
+                warn!("{}", warning);
+                stats_ref.warnings.lock().push(warning);
+                return;
+            }
+        }
+    }
+
+    async fn apply_phases(
+        &self,
+        source: ContentSource<'_>,
+        stats_ref: &CheckoutStats,
+    ) -> Result<(), CheckoutError> {
+        let start = Instant::now();
         let vfs = &self.checkout.vfs;
+        self.check_windows_paths(stats_ref)?;
+        *stats_ref.kept_deleted.lock() = self.kept_deleted.clone();
         debug!(
             "Skipping checking out {} files since they're already written",
             self.update_content.len() - self.filtered_update_content.len()
         );
-        let total = self.filtered_update_content.len() + self.remove.len() + self.update_meta.len();
+        let total = self.total_actions();
         let bar = &ProgressBar::new("Updating", total as u64, "files");
         Registry::main().register_progress_bar(bar);
-        let async_vfs = &AsyncVfsWriter::spawn_new(vfs.clone(), 16);
-        let stats = CheckoutStats::default();
-        let stats_ref = &stats;
+        let async_vfs = &self.checkout.async_vfs();
+        let hooks = &self.checkout.hooks;
+        let summary = if hooks.is_empty() {
+            PlanSummary::default()
+        } else {
+            self.summary()
+        };
+        hooks.pre_remove(&summary).await?;
+
+        if self.checkout.case_normalization.enabled(vfs) {
+            let renamed = self.normalize_case().await?;
+            stats_ref.case_renamed.fetch_add(renamed, Ordering::Relaxed);
+        }
 
-        let remove_files = stream::iter(self.remove.clone().into_iter())
+        let deterministic = self.checkout.deterministic;
+        let concurrency = if deterministic {
+            1
+        } else {
+            self.checkout.concurrency
+        };
+        let mut remove = self.remove.clone();
+        if deterministic {
+            remove.sort();
+        }
+        let remove_files = stream::iter(remove.into_iter())
             .chunks(VFS_BATCH_SIZE)
             .map(|paths| Self::remove_files(async_vfs, stats_ref, paths, bar));
-        let remove_files = remove_files.buffer_unordered(self.checkout.concurrency);
+        let remove_files = remove_files.buffer_unordered(concurrency);
 
         Self::process_work_stream(remove_files).await?;
+        hooks.post_remove(&stats_ref.applied()).await?;
+        hooks.pre_update(&summary).await?;
+
+        let update_content = async {
+            if self.checkout.priority_paths.is_empty() {
+                let actions = self.filtered_update_content.iter();
+                return self
+                    .apply_update_content(source, actions, async_vfs, stats_ref, bar)
+                    .await;
+            }
+            let (priority, rest): (Vec<_>, Vec<_>) = self
+                .filtered_update_content
+                .iter()
+                .partition(|u| self.checkout.priority_paths.matches(&u.path));
+            self.apply_update_content(source, priority.into_iter(), async_vfs, stats_ref, bar)
+                .await?;
+            *stats_ref.priority_complete.lock() = Some(start.elapsed());
+            self.apply_update_content(source, rest.into_iter(), async_vfs, stats_ref, bar)
+                .await
+        };
+
+        let mut update_meta: Vec<_> = self.update_meta.iter().collect();
+        if deterministic {
+            update_meta.sort_by(|a, b| canonical::cmp_update_meta(a, b));
+        }
+        let update_meta = stream::iter(update_meta).map(|action| {
+            Self::set_exec_on_file(async_vfs, stats_ref, &action.path, action.set_x_flag, bar)
+        });
+        let update_meta = update_meta.buffer_unordered(concurrency);
+
+        let update_meta = Self::process_work_stream(update_meta);
+
+        if deterministic {
+            update_content.await?;
+            update_meta.await?;
+        } else {
+            try_join!(update_content, update_meta)?;
+        }
+        stats_ref
+            .restored
+            .store(self.restored.len(), Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Counts of the actions left to apply.
+    pub fn summary(&self) -> PlanSummary {
+        let update_bytes = self.checkout.space_check.as_ref().map(|check| {
+            let ids = self.filtered_update_content.iter().map(|u| &u.content_hgid);
+            check.content_bytes(ids).0
+        });
+        PlanSummary {
+            remove: self.remove.len(),
+            update_content: self.filtered_update_content.len(),
+            new_files: self
+                .filtered_update_content
+                .iter()
+                .filter(|u| u.new_file)
+                .count(),
+            update_meta: self.update_meta.len(),
+            update_bytes,
+        }
+    }
+
+    /// Counts of the changes of this plan by kind and top-level directory,
+    /// e.g. to confirm the checkout with the user. Unlike `summary`, files
+    /// already written by an interrupted checkout are included. Empty for
+    /// the plans of `capture_undo`, which aren't made from a diff.
+    pub fn diff_summary(&self) -> &DiffSummary {
+        &self.diff_summary
+    }
+
+    /// Lists the paths this plan writes that Windows can't write as-is.
+    pub fn windows_path_problems(&self) -> Vec<PathProblem> {
+        windows_paths::find_path_problems(
+            self.checkout.vfs.root(),
+            self.update_content.iter().map(|u| &u.path),
+        )
+    }
+
+    /// Fails on Windows if the plan writes names that would be written
+    /// somewhere else, before anything is written. Elsewhere, only records
+    /// them in `stats`.
+    fn check_windows_paths(&self, stats: &CheckoutStats) -> Result<(), WindowsPathError> {
+        let problems = self.windows_path_problems();
+        if let Some(first) = problems.first() {
+            debug!(
+                "{} paths can't be written as-is on Windows, including {}: {}",
+                problems.len(),
+                first.path,
+                first.kind
+            );
+        }
+        let result =
+            windows_paths::check_path_problems(&problems, self.checkout.allow_reserved_names);
+        *stats.path_problems.lock() = problems;
+        result
+    }
 
-        let actions: HashMap<_, _> = self
+    /// Renames existing entries that only differ from paths to be written by
+    /// case. Fails if the plan itself writes paths that only differ by case.
+    async fn normalize_case(&self) -> Result<usize, CheckoutError> {
+        case_normalization::check_case_collisions(
+            self.update_content
+                .iter()
+                .map(|u| &u.path)
+                .chain(self.update_meta.iter().map(|u| &u.path)),
+        )?;
+
+        let vfs = self.checkout.vfs.clone();
+        let targets: Vec<_> = self
             .filtered_update_content
             .iter()
-            .map(|u| (u.make_key(), u.clone()))
+            .map(|u| u.path.clone())
+            .chain(self.update_meta.iter().map(|u| u.path.clone()))
             .collect();
-        let keys: Vec<_> = actions.keys().cloned().collect();
+        let remove: HashSet<_> = self.remove.iter().cloned().collect();
+        run_blocking(self.checkout.spawner(), move || {
+            case_normalization::normalize_case(&vfs, &targets, &remove)
+        })
+        .await?
+        .map_err(|source| CheckoutError::CaseNormalizationFailed { source })
+    }
 
-        let data_stream = store.read_file_contents(keys).await;
+    /// Fetches the content for `actions` from `source` and writes it out.
+    async fn apply_update_content<'a>(
+        &self,
+        source: ContentSource<'_>,
+        actions: impl Iterator<Item = &'a UpdateContentAction>,
+        async_vfs: &AsyncVfsWriter,
+        stats_ref: &CheckoutStats,
+        bar: &Arc<ProgressBar>,
+    ) -> Result<(), CheckoutError> {
+        let store = match source {
+            ContentSource::Store(store) => store,
+            ContentSource::Staged(staged) => {
+                return self.place_staged(staged, actions, stats_ref, bar).await;
+            }
+            ContentSource::Mixed { staged, store } => {
+                let (placed, fetched): (Vec<_>, Vec<_>) =
+                    actions.partition(|u| staged.contains(&u.content_hgid));
+                self.place_staged(staged, placed.into_iter(), stats_ref, bar)
+                    .await?;
+                return Self::fetch_and_write(
+                    store,
+                    fetched.into_iter(),
+                    async_vfs,
+                    stats_ref,
+                    self.progress.as_ref(),
+                    &self.checkout,
+                    bar,
+                )
+                .await;
+            }
+        };
+        Self::fetch_and_write(
+            store,
+            actions,
+            async_vfs,
+            stats_ref,
+            self.progress.as_ref(),
+            &self.checkout,
+            bar,
+        )
+        .await
+    }
 
-        let update_content = data_stream.map(|result| -> Result<_> {
-            let (data, key) = result?;
-            let action = actions
-                .get(&key)
-                .ok_or_else(|| format_err!("Storage returned unknown key {}", key))?;
-            let path = action.path.clone();
-            let flag = type_to_flag(&action.file_type);
-            Ok((path, action.content_hgid, data, flag))
-        });
+    /// Same as `apply_update_content`, without needing a plan.
+    async fn fetch_and_write<'a>(
+        store: &dyn ReadFileContents<Error = anyhow::Error>,
+        actions: impl Iterator<Item = &'a UpdateContentAction>,
+        async_vfs: &AsyncVfsWriter,
+        stats_ref: &CheckoutStats,
+        progress_ref: Option<&Mutex<CheckoutProgress>>,
+        checkout: &Checkout,
+        bar: &Arc<ProgressBar>,
+    ) -> Result<(), CheckoutError> {
+        if checkout.deterministic {
+            return Self::fetch_and_write_in_order(
+                store,
+                actions,
+                async_vfs,
+                stats_ref,
+                progress_ref,
+                checkout,
+                bar,
+            )
+            .await;
+        }
+        let actions: HashMap<_, _> = actions.map(|u| (u.make_key(), u.clone())).collect();
+        let mut keys: Vec<_> = actions.keys().cloned().collect();
+        if checkout.dir_batching.is_some() {
+            // Stores mostly return content in the order it was requested, so
+            // siblings end up in the same batches.
+            keys.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+        }
 
-        let progress_ref = self.progress.as_ref();
-        let update_content = update_content
-            .chunks(VFS_BATCH_SIZE)
-            .map(|actions| async move {
-                let actions: Result<Vec<_>, _> = actions.into_iter().collect();
-                Self::write_files(async_vfs, stats_ref, actions?, progress_ref, bar).await
-            });
+        let actions = &actions;
+        Self::retry_failed_fetches(keys, stats_ref, checkout, move |keys| {
+            Self::fetch_and_write_keys(
+                store,
+                actions,
+                keys,
+                async_vfs,
+                stats_ref,
+                progress_ref,
+                checkout,
+                bar,
+            )
+        })
+        .await
+    }
+
+    /// Calls `fetch` with `keys`, then again with the keys it returns as
+    /// failed, up to `Checkout::with_fetch_retries` times.
+    async fn retry_failed_fetches<F, Fut>(
+        mut keys: Vec<Key>,
+        stats_ref: &CheckoutStats,
+        checkout: &Checkout,
+        mut fetch: F,
+    ) -> Result<(), CheckoutError>
+    where
+        F: FnMut(Vec<Key>) -> Fut,
+        Fut: Future<Output = Result<Vec<FetchFailure>, CheckoutError>>,
+    {
+        for round in 0..=checkout.fetch_retries {
+            if round > 0 {
+                debug!("Fetching {} failed files again", keys.len());
+                stats_ref.retry_rounds.fetch_add(1, Ordering::Relaxed);
+                stats_ref
+                    .retried_keys
+                    .fetch_add(keys.len(), Ordering::Relaxed);
+            }
+            let failures = fetch(keys).await?;
+            if failures.is_empty() {
+                return Ok(());
+            }
+            if round == checkout.fetch_retries {
+                return Err(CheckoutError::FetchRetriesExhausted {
+                    rounds: round,
+                    failures,
+                });
+            }
+            keys = failures.into_iter().map(|f| f.key).collect();
+        }
+        unreachable!("the last round returns")
+    }
+
+    /// Same as `fetch_and_write`, but fetches and writes one batch at a
+    /// time, writing files in path order whatever order the store returns
+    /// them in. See `Checkout::with_deterministic`.
+    async fn fetch_and_write_in_order<'a>(
+        store: &dyn ReadFileContents<Error = anyhow::Error>,
+        actions: impl Iterator<Item = &'a UpdateContentAction>,
+        async_vfs: &AsyncVfsWriter,
+        stats_ref: &CheckoutStats,
+        progress_ref: Option<&Mutex<CheckoutProgress>>,
+        checkout: &Checkout,
+        bar: &Arc<ProgressBar>,
+    ) -> Result<(), CheckoutError> {
+        let mut actions: Vec<_> = actions.collect();
+        actions.sort_by(|a, b| canonical::cmp_update_content(a, b));
+        for batch in actions.chunks(VFS_BATCH_SIZE) {
+            let mut contents = Self::fetch_batch(store, batch, stats_ref, checkout).await?;
+            let files: Vec<_> = batch
+                .iter()
+                .filter_map(|action| {
+                    let data = contents.remove(&action.make_key())?;
+                    let flag = type_to_flag(&action.file_type);
+                    Some((action.path.clone(), action.content_hgid, data, flag))
+                })
+                .collect();
+            if files.is_empty() {
+                continue;
+            }
+            Self::write_files(async_vfs, stats_ref, files, None, progress_ref, bar).await?;
+        }
+        Ok(())
+    }
 
-        let update_content = update_content.buffer_unordered(self.checkout.concurrency);
+    /// Fetches the content for `batch`, fetching failed keys again like
+    /// `fetch_and_write`.
+    async fn fetch_batch(
+        store: &dyn ReadFileContents<Error = anyhow::Error>,
+        batch: &[&UpdateContentAction],
+        stats_ref: &CheckoutStats,
+        checkout: &Checkout,
+    ) -> Result<HashMap<Key, Bytes>, CheckoutError> {
+        let keys: Vec<_> = batch.iter().map(|u| u.make_key()).collect();
+        let contents = Mutex::new(HashMap::new());
+        let contents_ref = &contents;
+        Self::retry_failed_fetches(keys, stats_ref, checkout, move |keys| async move {
+            let (fetched, failures) = Self::fetch_keys(store, keys, checkout).await?;
+            contents_ref.lock().extend(fetched);
+            Ok(failures)
+        })
+        .await?;
+        Ok(contents.into_inner())
+    }
 
-        let update_meta = stream::iter(self.update_meta.iter()).map(|action| {
-            Self::set_exec_on_file(async_vfs, stats_ref, &action.path, action.set_x_flag, bar)
+    /// Fetches the content of `keys`. Keys the store did not return, and if
+    /// retries are enabled, fetch errors for a known key, are returned
+    /// instead of failing.
+    async fn fetch_keys(
+        store: &dyn ReadFileContents<Error = anyhow::Error>,
+        keys: Vec<Key>,
+        checkout: &Checkout,
+    ) -> Result<(HashMap<Key, Bytes>, Vec<FetchFailure>), CheckoutError> {
+        let retry = checkout.fetch_retries > 0;
+        let mut contents = HashMap::new();
+        let mut failures = Vec::new();
+        let mut data_stream = store.read_file_contents(keys.clone()).await;
+        while let Some(result) = data_stream.next().await {
+            match result {
+                Ok((data, key)) => {
+                    if !keys.contains(&key) {
+                        return Err(CheckoutError::KeyNotFound { key });
+                    }
+                    contents.insert(key, data);
+                }
+                Err(source) => match source.downcast_ref::<Key>().cloned() {
+                    Some(key) if retry => failures.push(FetchFailure { key, source }),
+                    key => return Err(CheckoutError::FetchFailed { key, source }),
+                },
+            }
+        }
+        for key in &keys {
+            if !contents.contains_key(key) && !failures.iter().any(|f| &f.key == key) {
+                failures.push(FetchFailure {
+                    key: key.clone(),
+                    source: anyhow!("not returned by the store"),
+                });
+            }
+        }
+        Ok((contents, failures))
+    }
+
+    /// Fetches `keys` and writes the content for their `actions`. Keys the
+    /// store did not return, and if retries are enabled, fetch errors for a
+    /// known key, are returned instead of failing.
+    #[allow(clippy::too_many_arguments)]
+    async fn fetch_and_write_keys(
+        store: &dyn ReadFileContents<Error = anyhow::Error>,
+        actions: &HashMap<Key, UpdateContentAction>,
+        keys: Vec<Key>,
+        async_vfs: &AsyncVfsWriter,
+        stats_ref: &CheckoutStats,
+        progress_ref: Option<&Mutex<CheckoutProgress>>,
+        checkout: &Checkout,
+        bar: &Arc<ProgressBar>,
+    ) -> Result<Vec<FetchFailure>, CheckoutError> {
+        let retry = checkout.fetch_retries > 0;
+        let not_returned: Mutex<HashSet<Key>> = Mutex::new(keys.iter().cloned().collect());
+        let failures = Mutex::new(Vec::new());
+
+        let data_stream = store.read_file_contents(keys).await;
+
+        let update_content = data_stream.filter_map(|result| {
+            let item = match result {
+                Ok((data, key)) => match actions.get(&key) {
+                    Some(action) => {
+                        not_returned.lock().remove(&key);
+                        let flag = type_to_flag(&action.file_type);
+                        Some(Ok((action.path.clone(), action.content_hgid, data, flag)))
+                    }
+                    None => Some(Err(CheckoutError::KeyNotFound { key })),
+                },
+                Err(source) => match source.downcast_ref::<Key>().cloned() {
+                    Some(key) if retry => {
+                        not_returned.lock().remove(&key);
+                        failures.lock().push(FetchFailure { key, source });
+                        None
+                    }
+                    key => Some(Err(CheckoutError::FetchFailed { key, source })),
+                },
+            };
+            future::ready(item)
         });
-        let update_meta = update_meta.buffer_unordered(self.checkout.concurrency);
 
-        let update_content = Self::process_work_stream(update_content);
-        let update_meta = Self::process_work_stream(update_meta);
+        let update_content =
+            memory::budgeted_batches(update_content, &stats_ref.memory, VFS_BATCH_SIZE, |item| {
+                item.as_ref().map_or(0, |(_, _, data, _)| data.len())
+            })
+            .map(|(actions, permit)| async move {
+                // Released once written, or if writing fails.
+                let _permit = permit;
+                let actions: Result<Vec<_>, _> = actions.into_iter().collect();
+                Self::write_files(
+                    async_vfs,
+                    stats_ref,
+                    actions?,
+                    checkout.dir_batching,
+                    progress_ref,
+                    bar,
+                )
+                .await
+            });
 
-        try_join!(update_content, update_meta)?;
+        let update_content = update_content.buffer_unordered(checkout.concurrency);
+        Self::process_work_stream(update_content).await?;
+
+        let mut failures = failures.into_inner();
+        failures.extend(
+            not_returned
+                .into_inner()
+                .into_iter()
+                .map(|key| FetchFailure {
+                    key,
+                    source: anyhow!("not returned by the store"),
+                }),
+        );
+        Ok(failures)
+    }
 
-        Ok(stats)
+    /// Number of actions `apply_store` will perform.
+    fn total_actions(&self) -> usize {
+        self.filtered_update_content.len() + self.remove.len() + self.update_meta.len()
     }
 
+    /// Same as `apply_store`. Errors are `CheckoutError`s.
     #[instrument(skip_all, err)]
     pub fn blocking_apply_store(
         &self,
         store: &dyn ReadFileContents<Error = anyhow::Error>,
     ) -> Result<CheckoutStats> {
-        block_on(self.apply_store(store))
+        block_on(async { Ok(self.apply_store(store).await?) })
     }
 
     pub async fn apply_store_dry_run(
@@ -317,6 +1301,10 @@ impl CheckoutPlan {
     pub fn check_conflicts(&self, status: &Status) -> Vec<&RepoPath> {
         let mut conflicts = vec![];
         for file in self.all_files() {
+            // Overwriting them was chosen in with_locally_deleted.
+            if self.restored.binary_search(file).is_ok() {
+                continue;
+            }
             // Unknown files are handled separately in check_unknown_files
             if !matches!(status.status(file), None | Some(FileStatus::Unknown)) {
                 conflicts.push(file.as_repo_path());
@@ -334,6 +1322,7 @@ impl CheckoutPlan {
     ) -> Result<Vec<RepoPathBuf>> {
         let vfs = &self.checkout.vfs;
         let mut check_content = vec![];
+        let mut flags = HashMap::new();
 
         let new_files: Vec<_> = self.new_file_actions().collect();
 
@@ -382,6 +1371,7 @@ impl CheckoutPlan {
                     ),
                 };
                 let key = Key::new(file.clone(), hgid);
+                flags.insert(file.clone(), type_to_flag(&file_action.file_type));
                 check_content.push(key);
             }
         }
@@ -395,16 +1385,29 @@ impl CheckoutPlan {
             return Ok(unknowns);
         }
 
+        let flags = Arc::new(flags);
         let check_content = store
             .read_file_contents(check_content)
             .await
             .chunks(VFS_BATCH_SIZE)
             .map(|v| {
                 let vfs = vfs.clone();
-                Handle::current().spawn_blocking(move || -> Result<Vec<RepoPathBuf>> {
-                    let v: std::result::Result<Vec<_>, _> = v.into_iter().collect();
-                    Self::check_content(&vfs, v?)
-                })
+                let flags = flags.clone();
+                let filter = self.checkout.content_filter.clone();
+                run_blocking(
+                    self.checkout.spawner(),
+                    move || -> Result<Vec<RepoPathBuf>> {
+                        let v: std::result::Result<Vec<_>, _> = v.into_iter().collect();
+                        let v = v?
+                            .into_iter()
+                            .map(|(data, key)| {
+                                let flag = flags.get(&key.path).copied();
+                                (data, key, flag.unwrap_or(UpdateFlag::Regular))
+                            })
+                            .collect();
+                        Self::check_content(&vfs, v, filter.as_deref())
+                    },
+                )
             })
             .buffer_unordered(self.checkout.concurrency)
             .map(|r| r?);
@@ -415,9 +1418,9 @@ impl CheckoutPlan {
     }
 
     /// Drains stream returning error if one of futures fail
-    async fn process_work_stream<S: Stream<Item = Result<()>> + Unpin>(
+    async fn process_work_stream<E, S: Stream<Item = Result<(), E>> + Unpin>(
         mut stream: S,
-    ) -> Result<()> {
+    ) -> Result<(), E> {
         while let Some(result) = stream.next().await {
             result?;
         }
@@ -434,11 +1437,21 @@ impl CheckoutPlan {
         Ok(r)
     }
 
-    fn check_content(vfs: &VFS, files: Vec<(Bytes, Key)>) -> Result<Vec<RepoPathBuf>> {
+    /// Paths of `files` whose content on disk is not what the checkout
+    /// would write, which is the content after the content filter.
+    fn check_content(
+        vfs: &VFS,
+        files: Vec<(Bytes, Key, UpdateFlag)>,
+        filter: Option<&dyn ContentFilter>,
+    ) -> Result<Vec<RepoPathBuf>> {
         let mut result = vec![];
-        for file in files {
-            let path = &file.1.path;
-            match Self::check_file(vfs, file.0, path) {
+        for (data, key, flag) in files {
+            let path = &key.path;
+            let expected = match filter {
+                Some(filter) => content_filter::filter_content(filter, path, data, flag),
+                None => Ok(data),
+            };
+            match expected.and_then(|expected| Self::check_file(vfs, expected, path)) {
                 Err(err) => {
                     warn!("Can not check {}: {}", path, err);
                     result.push(path.clone())
@@ -463,32 +1476,101 @@ impl CheckoutPlan {
         async_vfs: &AsyncVfsWriter,
         stats: &CheckoutStats,
         actions: Vec<(RepoPathBuf, HgId, Bytes, UpdateFlag)>,
+        dir_batching: Option<DirBatching>,
         progress: Option<&Mutex<CheckoutProgress>>,
         bar: &Arc<ProgressBar>,
-    ) -> Result<()> {
-        let count = actions.len();
+    ) -> Result<(), CheckoutError> {
+        let files = actions
+            .iter()
+            .map(|(path, hgid, _, flag)| (path.clone(), hgid.clone(), *flag))
+            .collect();
+        let actions: Vec<_> = actions
+            .into_iter()
+            .map(|(path, _, content, flag)| (path, content, flag))
+            .collect();
+        let write = async {
+            let unfiltered: usize = actions.iter().map(|(_, content, _)| content.len()).sum();
+            let actions = match &stats.content_filter {
+                Some(filtering) => filtering.filter_batch(actions).await?,
+                None => actions,
+            };
+            let written = match dir_batching {
+                Some(batching) => dir_batches::write_by_dir(async_vfs, actions, batching).await,
+                None => async_vfs.write_batch(actions).await,
+            }?;
+            if let Some(filtering) = &stats.content_filter {
+                filtering.record_written(unfiltered);
+            }
+            Ok(written)
+        };
+        Self::write_files_with(stats, files, write, progress, bar).await
+    }
 
-        let first_file = actions
-            .get(0)
-            .expect("Cant have empty actions in write_files")
-            .0
-            .to_string();
-        bar.set_message(first_file);
+    /// Runs `write`, which writes `files` and returns the number of bytes
+    /// written, with the bookkeeping of `write_files` around it. Errors of
+    /// `write` name the failed path with a `BatchFailure`.
+    async fn write_files_with(
+        stats: &CheckoutStats,
+        files: Vec<(RepoPathBuf, HgId, UpdateFlag)>,
+        write: impl Future<Output = Result<usize>>,
+        progress: Option<&Mutex<CheckoutProgress>>,
+        bar: &Arc<ProgressBar>,
+    ) -> Result<(), CheckoutError> {
+        let count = files.len();
+
+        let first_file = match files.get(0) {
+            Some((path, ..)) => path.clone(),
+            None => {
+                return Err(CheckoutError::PlanInconsistent {
+                    detail: "no actions in write_files".to_string(),
+                });
+            }
+        };
+        bar.set_message(first_file.to_string());
 
-        let paths: Vec<_> = actions
+        let paths: Vec<_> = files
             .iter()
-            .map(|(path, hgid, _, _)| (hgid.clone(), path.as_repo_path().to_owned()))
+            .map(|(path, hgid, _)| (hgid.clone(), path.as_repo_path().to_owned()))
             .collect();
-        let actions = actions
-            .into_iter()
-            .map(|(path, _, content, flag)| (path, content, flag));
-        let w = async_vfs.write_batch(actions).await?;
+        let saved_xattrs = match &stats.xattrs {
+            Some(xattrs) => {
+                let files = files
+                    .iter()
+                    .map(|(path, _, flag)| (path.clone(), *flag))
+                    .collect();
+                xattrs.save(files).await?
+            }
+            None => Vec::new(),
+        };
+        let written = write.await;
+        let outcome = match written {
+            Ok(_) => PathOutcome::Written,
+            Err(_) => PathOutcome::Failed,
+        };
+        stats.record_outcome(paths.iter().map(|(_, path)| path.clone()), outcome);
+        let w = written.map_err(|source| CheckoutError::WriteFailed {
+            path: failed_path(&source).unwrap_or(first_file),
+            source,
+        })?;
         stats.updated.fetch_add(count, Ordering::Relaxed);
         stats.written_bytes.fetch_add(w, Ordering::Relaxed);
 
+        if let Some(xattrs) = &stats.xattrs {
+            xattrs.restore(saved_xattrs).await?;
+        }
+
+        if let Some(file_metadata) = &stats.file_metadata {
+            let written = paths.iter().map(|(_, path)| path.clone()).collect();
+            file_metadata.record_written(written).await?;
+        }
+
         if let Some(progress) = progress {
             progress.lock().record_writes(paths);
-            fail::fail_point!("checkout-post-progress", |_| { bail!("oh no!") });
+            fail::fail_point!("checkout-post-progress", |_| {
+                Err(CheckoutError::Aborted {
+                    stats: stats.applied(),
+                })
+            });
         }
         bar.increase_position(count as u64);
 
@@ -500,9 +1582,25 @@ impl CheckoutPlan {
         stats: &CheckoutStats,
         paths: Vec<RepoPathBuf>,
         bar: &Arc<ProgressBar>,
-    ) -> Result<()> {
+    ) -> Result<(), CheckoutError> {
         let count = paths.len();
-        async_vfs.remove_batch(paths).await?;
+        if let Some(file_metadata) = &stats.file_metadata {
+            file_metadata.record_removed(&paths);
+        }
+        let first_path = paths.first().cloned().unwrap_or_default();
+        let recorded = stats.outcomes.is_some().then(|| paths.clone());
+        let removed = async_vfs.remove_batch(paths).await;
+        if let Some(recorded) = recorded {
+            let outcome = match removed {
+                Ok(_) => PathOutcome::Removed,
+                Err(_) => PathOutcome::Failed,
+            };
+            stats.record_outcome(recorded, outcome);
+        }
+        removed.map_err(|source| CheckoutError::RemoveFailed {
+            path: failed_path(&source).unwrap_or(first_path),
+            source,
+        })?;
         stats.removed.fetch_add(count, Ordering::Relaxed);
         bar.increase_position(count as u64);
         Ok(())
@@ -514,12 +1612,21 @@ impl CheckoutPlan {
         path: &RepoPath,
         flag: bool,
         bar: &Arc<ProgressBar>,
-    ) -> Result<()> {
-        async_vfs
-            .set_executable(path.to_owned(), flag)
-            .await
-            .context(format!("Updating exec on {}", path))?;
+    ) -> Result<(), CheckoutError> {
+        let set = async_vfs.set_executable(path.to_owned(), flag).await;
+        let outcome = match set {
+            Ok(_) => PathOutcome::MetaChanged,
+            Err(_) => PathOutcome::Failed,
+        };
+        stats.record_outcome([path.to_owned()], outcome);
+        set.map_err(|source| CheckoutError::MetaUpdateFailed {
+            path: path.to_owned(),
+            source,
+        })?;
         stats.meta_updated.fetch_add(1, Ordering::Relaxed);
+        if let Some(file_metadata) = &stats.file_metadata {
+            file_metadata.record_written(vec![path.to_owned()]).await?;
+        }
         bar.increase_position(1);
         Ok(())
     }
@@ -568,6 +1675,9 @@ impl CheckoutPlan {
             update_content: vec![],
             filtered_update_content: vec![],
             update_meta: vec![],
+            diff_summary: Default::default(),
+            restored: Vec::new(),
+            kept_deleted: Vec::new(),
             progress: None,
             checkout: Checkout::default_config(vfs),
         }
@@ -706,6 +1816,11 @@ impl CheckoutProgress {
     }
 }
 
+/// The path a batch of `AsyncVfsWriter` failed at.
+fn failed_path(err: &anyhow::Error) -> Option<RepoPathBuf> {
+    err.downcast_ref::<BatchFailure>().map(|f| f.path.clone())
+}
+
 fn type_to_flag(ft: &FileType) -> UpdateFlag {
     match ft {
         FileType::Regular => UpdateFlag::Regular,
@@ -751,6 +1866,7 @@ mod test {
     use std::collections::HashMap;
     use std::fs::create_dir;
     use std::path::Path;
+    use std::path::PathBuf;
 
     use anyhow::ensure;
     use anyhow::Context;
@@ -832,20 +1948,1372 @@ mod test {
         Ok(())
     }
 
-    fn generate_trees(tree_size: usize, count: usize) -> Vec<Vec<(RepoPathBuf, FileMetadata)>> {
-        let mut result = vec![];
-        let mut gen = Gen::new(5);
-        let paths = generate_repo_paths(tree_size * count, &mut gen);
+    fn update_regular(id: u8) -> Action {
+        Action::Update(actions::UpdateAction::new(
+            None,
+            FileMetadata::regular(hgid(id)),
+        ))
+    }
 
-        for i in 0..count {
-            let mut tree = vec![];
-            for idx in 0..tree_size {
-                let meta = FileMetadata::arbitrary(&mut gen);
-                let path = paths.get(i * tree_size / 2 + idx).unwrap().clone();
-                tree.push((path, meta));
-            }
-            result.push(tree)
-        }
+    #[cfg(not(windows))]
+    #[tokio::test]
+    async fn test_windows_path_problems_reported() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        let mut map = ActionMap::empty();
+        map.insert(rp("src/aux.c"), update_regular(1));
+        map.insert(rp("src/main.c"), update_regular(2));
+
+        // Only reported outside Windows.
+        let plan = Checkout::default_config(vfs.clone()).plan_action_map(map);
+        let stats = plan.apply_store(&DummyFileContentStore).await?;
+        assert_eq!(
+            stats.path_problems(),
+            vec![PathProblem {
+                path: rp("src/aux.c"),
+                kind: PathProblemKind::ReservedName("aux.c".to_string()),
+            }]
+        );
+        assert_eq!(vfs.read(&rp("src/aux.c"))?.as_ref(), hgid_file(&hgid(1)));
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_windows_extended_length_paths() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        let long = rp(&format!("{}/{}", "d".repeat(150), "f".repeat(150)));
+        let reserved = rp("src/aux.c");
+        let mut map = ActionMap::empty();
+        map.insert(long.clone(), update_regular(1));
+        map.insert(reserved.clone(), update_regular(2));
+
+        // Reserved names fail the whole plan before anything is written.
+        let plan = Checkout::default_config(vfs.clone()).plan_action_map(map.clone());
+        let err = match plan.apply_store(&DummyFileContentStore).await {
+            Err(CheckoutError::PathProblems(err)) => err,
+            other => panic!("expected path problems, got {:?}", other.err()),
+        };
+        assert_eq!(err.problems.len(), 1);
+        assert_eq!(err.problems[0].path, reserved);
+        assert!(vfs.metadata(&long).is_err());
+
+        let plan = Checkout::default_config(vfs.clone())
+            .with_reserved_names(true)
+            .plan_action_map(map);
+        plan.apply_store(&DummyFileContentStore).await?;
+
+        // Only filesystem calls use the extended-length form.
+        let vfs = vfs.with_reserved_names(true);
+        assert!(!vfs.join(&long).to_str().unwrap().starts_with(r"\\?\"));
+        assert_eq!(vfs.read(&long)?.as_ref(), hgid_file(&hgid(1)));
+        assert_eq!(vfs.read(&reserved)?.as_ref(), hgid_file(&hgid(2)));
+        Ok(())
+    }
+
+    /// A plan applying `action` to `path`, which can't be written since it
+    /// is in `.hg`.
+    fn invalid_path_plan(vfs: VFS, action: Action) -> CheckoutPlan {
+        let mut map = ActionMap::empty();
+        map.insert(rp(".hg/x"), action);
+        Checkout::default_config(vfs).plan_action_map(map)
+    }
+
+    #[tokio::test]
+    async fn test_fetch_failed() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        let mut map = ActionMap::empty();
+        map.insert(rp("a"), update_regular(1));
+        let plan = Checkout::default_config(vfs.clone())
+            .with_fetch_retries(0)
+            .plan_action_map(map.clone());
+
+        match plan.apply_store(&FailingStore::Unavailable).await {
+            Err(CheckoutError::FetchFailed { key, source }) => {
+                assert_eq!(key, Some(Key::new(rp("a"), hgid(1))));
+                assert_eq!(source.root_cause().to_string(), "store unavailable");
+            }
+            other => panic!("expected FetchFailed, got {:?}", other.err()),
+        }
+
+        let plan = Checkout::default_config(vfs.clone()).plan_action_map(map.clone());
+        match plan.apply_store(&FailingStore::Unavailable).await {
+            Err(CheckoutError::FetchRetriesExhausted { rounds, failures }) => {
+                assert_eq!(rounds, DEFAULT_FETCH_RETRIES);
+                assert_eq!(failures.len(), 1);
+                assert_eq!(failures[0].key, Key::new(rp("a"), hgid(1)));
+                assert_eq!(
+                    failures[0].source.root_cause().to_string(),
+                    "store unavailable"
+                );
+            }
+            other => panic!("expected FetchRetriesExhausted, got {:?}", other.err()),
+        }
+
+        // Keys that are not returned fail the checkout, even without retries.
+        for retries in [0, DEFAULT_FETCH_RETRIES] {
+            let plan = Checkout::default_config(vfs.clone())
+                .with_fetch_retries(retries)
+                .plan_action_map(map.clone());
+            match plan.apply_store(&FailingStore::Missing).await {
+                Err(CheckoutError::FetchRetriesExhausted { rounds, failures }) => {
+                    assert_eq!(rounds, retries);
+                    assert_eq!(failures.len(), 1);
+                    assert_eq!(failures[0].key, Key::new(rp("a"), hgid(1)));
+                }
+                other => panic!("expected FetchRetriesExhausted, got {:?}", other.err()),
+            }
+            assert!(vfs.metadata(&rp("a")).is_err());
+        }
+
+        let plan = Checkout::default_config(vfs).plan_action_map(map);
+        match plan.apply_store(&FailingStore::WrongKey).await {
+            Err(CheckoutError::KeyNotFound { key }) => assert_eq!(key.path, rp("other")),
+            other => panic!("expected KeyNotFound, got {:?}", other.err()),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_retry() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        let mut files = Vec::new();
+        let mut map = ActionMap::empty();
+        for i in 1..=100u8 {
+            let path = rp(&format!("dir{}/file{}", i % 7, i));
+            map.insert(path.clone(), update_regular(i));
+            files.push((path, FileMetadata::regular(hgid(i))));
+        }
+        let plan = Checkout::default_config(vfs).plan_action_map(map);
+
+        let store = FlakyStore::default();
+        let stats = plan.apply_store(&store).await?;
+        assert_fs(tempdir.path(), &files)?;
+        assert_eq!(stats.updated.load(Ordering::Relaxed), 100);
+        assert_eq!(stats.retry_rounds(), 1);
+        // 10 failed and 7 were not returned.
+        assert_eq!(stats.retried_keys(), 17);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_limits() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        let mut map = ActionMap::empty();
+        for i in 1..=40u8 {
+            map.insert(rp(&format!("file{}", i)), update_regular(i));
+        }
+        let limits = MemoryLimits {
+            max_bytes_in_flight: 100 << 20,
+            small_file_threshold: 1 << 20,
+            fast_lane_percent: 25,
+        };
+        let plan = Checkout::default_config(vfs)
+            .with_memory_limits(limits)
+            .plan_action_map(map);
+
+        let store = SizedStore::default();
+        let stats = plan.apply_store(&store).await?;
+        assert_eq!(stats.updated.load(Ordering::Relaxed), 40);
+        let peak = stats.peak_bytes_in_flight();
+        assert!(peak >= SizedStore::LARGE, "peak {}", peak);
+        assert!(
+            peak <= limits.max_bytes_in_flight + SizedStore::LARGE,
+            "peak {}",
+            peak
+        );
+        for i in 1..=40u8 {
+            let written = std::fs::read(tempdir.path().join(format!("file{}", i)))?;
+            ensure!(
+                written[..] == store.content(&hgid(i))[..],
+                "file{} was not written correctly",
+                i
+            );
+        }
+        Ok(())
+    }
+
+    fn space_check(sizes: &[(u8, u64)], available: u64) -> SpaceCheck {
+        let sizes: HashMap<HgId, u64> = sizes.iter().map(|(id, size)| (hgid(*id), *size)).collect();
+        SpaceCheck::with_sizes(Arc::new(move |id| sizes.get(id).copied()))
+            .with_margin(100)
+            .with_free_space_probe(Arc::new(move |_| Ok(available)))
+    }
+
+    #[tokio::test]
+    async fn test_space_check() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        vfs.write(&rp("a"), &[0; 10], UpdateFlag::Regular)?;
+        let mut map = ActionMap::empty();
+        map.insert(rp("a"), update_regular(2));
+        map.insert(rp("b"), update_regular(3));
+
+        let check = space_check(&[(2, 1000)], 500);
+        let plan = Checkout::default_config(vfs.clone())
+            .with_space_check(check.clone())
+            .plan_action_map(map.clone());
+        let err = match plan.apply_store(&DummyFileContentStore).await {
+            Err(CheckoutError::InsufficientSpace(err)) => err,
+            other => bail!("unexpected result {:?}", other.map(|_| ())),
+        };
+        assert_eq!(
+            err.estimate,
+            SpaceEstimate {
+                bytes_written: 1000,
+                bytes_freed: 10,
+                available: 500,
+                margin: 100,
+                unknown_sizes: 1,
+            }
+        );
+        assert_eq!(err.estimate.required(), 1100);
+        assert!(err.to_string().contains("1 files of unknown size"));
+        assert_eq!(std::fs::read(tempdir.path().join("a"))?, vec![0; 10]);
+        assert!(!tempdir.path().join("b").exists());
+
+        let plan = Checkout::default_config(vfs)
+            .with_space_check(check.with_force(true))
+            .plan_action_map(map);
+        plan.apply_store(&DummyFileContentStore).await?;
+        assert_fs(
+            tempdir.path(),
+            &[
+                (rp("a"), FileMetadata::regular(hgid(2))),
+                (rp("b"), FileMetadata::regular(hgid(3))),
+            ],
+        )?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_space_check_ignores_freed() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        vfs.write(&rp("big"), &[0; 10000], UpdateFlag::Regular)?;
+        vfs.write(&rp("old"), &[0; 5000], UpdateFlag::Regular)?;
+        let mut map = ActionMap::empty();
+        map.insert(rp("big"), update_regular(1));
+        map.insert(rp("old"), Action::Remove);
+        map.insert(rp("new"), update_regular(2));
+
+        // Overwriting and removing files frees more than is written, but the
+        // new content is written first, so it must fit as well.
+        let plan = Checkout::default_config(vfs.clone())
+            .with_space_check(space_check(&[(1, 3000), (2, 1000)], 4000))
+            .plan_action_map(map.clone());
+        let estimate = plan
+            .estimate_space(&space_check(&[(1, 3000), (2, 1000)], 4000))
+            .await?
+            .context("no estimate")?;
+        assert_eq!(estimate.bytes_written, 4000);
+        assert_eq!(estimate.bytes_freed, 15000);
+        assert_eq!(estimate.required(), 4100);
+        assert!(!estimate.is_sufficient());
+        assert!(matches!(
+            plan.apply_store(&DummyFileContentStore).await,
+            Err(CheckoutError::InsufficientSpace(_))
+        ));
+        assert!(tempdir.path().join("old").exists());
+
+        let plan = Checkout::default_config(vfs)
+            .with_space_check(space_check(&[(1, 3000), (2, 1000)], 4100))
+            .plan_action_map(map);
+        plan.apply_store(&DummyFileContentStore).await?;
+        assert!(!tempdir.path().join("old").exists());
+        assert_fs(
+            tempdir.path(),
+            &[
+                (rp("big"), FileMetadata::regular(hgid(1))),
+                (rp("new"), FileMetadata::regular(hgid(2))),
+            ],
+        )?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_working_copy_failures() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+
+        let plan = invalid_path_plan(vfs.clone(), update_regular(1));
+        match plan.apply_store(&DummyFileContentStore).await {
+            Err(CheckoutError::WriteFailed { path, .. }) => assert_eq!(path, rp(".hg/x")),
+            other => panic!("expected WriteFailed, got {:?}", other.err()),
+        }
+
+        let plan = invalid_path_plan(vfs.clone(), Action::Remove);
+        match plan.apply_store(&DummyFileContentStore).await {
+            Err(CheckoutError::RemoveFailed { path, .. }) => assert_eq!(path, rp(".hg/x")),
+            other => panic!("expected RemoveFailed, got {:?}", other.err()),
+        }
+
+        let plan = invalid_path_plan(vfs, Action::UpdateExec(true));
+        match plan.apply_store(&DummyFileContentStore).await {
+            Err(err @ CheckoutError::MetaUpdateFailed { .. }) => {
+                assert_eq!(err.path(), Some(&rp(".hg/x")));
+                assert!(!err.is_disk_full());
+            }
+            other => panic!("expected MetaUpdateFailed, got {:?}", other.err()),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_aborted() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let working_path = tempdir.path().join("workingdir");
+        create_dir(&working_path)?;
+        let vfs = VFS::new(working_path)?;
+        let mut map = ActionMap::empty();
+        map.insert(rp("a"), update_regular(1));
+        let mut plan = Checkout::default_config(vfs).plan_action_map(map);
+        plan.add_progress(&tempdir.path().join("updateprogress"))?;
+
+        let scenario = fail::FailScenario::setup();
+        fail::cfg("checkout-post-progress", "return").unwrap();
+        let result = plan.apply_store(&DummyFileContentStore).await;
+        scenario.teardown();
+
+        match result {
+            Err(CheckoutError::Aborted { stats }) => assert_eq!(stats.updated, 1),
+            other => panic!("expected Aborted, got {:?}", other.err()),
+        }
+        Ok(())
+    }
+
+    /// Records the hook calls, and fails `pre_update` if `fail_pre_update`.
+    #[derive(Default)]
+    struct RecordingHook {
+        calls: Mutex<Vec<String>>,
+        fail_pre_update: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl CheckoutHooks for RecordingHook {
+        async fn pre_remove(&self, plan: &PlanSummary) -> Result<()> {
+            self.calls.lock().push(format!("pre_remove {:?}", plan));
+            Ok(())
+        }
+
+        async fn post_remove(&self, stats: &AppliedStats) -> Result<()> {
+            self.calls.lock().push(format!("post_remove {}", stats));
+            Ok(())
+        }
+
+        async fn pre_update(&self, _plan: &PlanSummary) -> Result<()> {
+            self.calls.lock().push("pre_update".to_string());
+            if self.fail_pre_update {
+                bail!("not now");
+            }
+            Ok(())
+        }
+
+        async fn post_apply(
+            &self,
+            stats: &AppliedStats,
+            outcome: Result<(), &CheckoutError>,
+        ) -> Result<()> {
+            let calls = &mut self.calls.lock();
+            calls.push(format!("post_apply {} {}", stats, outcome.is_ok()));
+            Ok(())
+        }
+    }
+
+    /// Counts calls of `read_file_contents`, and the keys fetched.
+    #[derive(Default)]
+    struct CountingStore {
+        fetches: AtomicUsize,
+        keys: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl ReadFileContents for CountingStore {
+        type Error = anyhow::Error;
+
+        async fn read_file_contents(&self, keys: Vec<Key>) -> BoxStream<Result<(Bytes, Key)>> {
+            self.fetches.fetch_add(1, Ordering::Relaxed);
+            self.keys.fetch_add(keys.len(), Ordering::Relaxed);
+            DummyFileContentStore.read_file_contents(keys).await
+        }
+
+        async fn read_rename_metadata(
+            &self,
+            _keys: Vec<Key>,
+        ) -> BoxStream<Result<(Key, Option<Key>), Self::Error>> {
+            stream::empty().boxed()
+        }
+    }
+
+    fn hooks_plan(vfs: &VFS, hook: Arc<RecordingHook>) -> Result<CheckoutPlan> {
+        vfs.write(&rp("old"), b"old", UpdateFlag::Regular)?;
+        vfs.write(&rp("b"), b"b", UpdateFlag::Regular)?;
+        let mut map = ActionMap::empty();
+        map.insert(rp("old"), Action::Remove);
+        map.insert(rp("a"), update_regular(1));
+        map.insert(
+            rp("b"),
+            Action::Update(actions::UpdateAction::new(
+                Some(FileMetadata::regular(hgid(3))),
+                FileMetadata::regular(hgid(2)),
+            )),
+        );
+        Ok(Checkout::default_config(vfs.clone())
+            .with_hook(hook)
+            .with_space_check(space_check(&[(1, 10), (2, 20)], u64::MAX))
+            .plan_action_map(map))
+    }
+
+    #[tokio::test]
+    async fn test_hooks() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        let hook = Arc::new(RecordingHook::default());
+        let plan = hooks_plan(&vfs, hook.clone())?;
+
+        let store = CountingStore::default();
+        plan.apply_store(&store).await?;
+        assert_eq!(
+            *hook.calls.lock(),
+            vec![
+                "pre_remove PlanSummary { remove: 1, update_content: 2, new_files: 1, update_meta: 0, update_bytes: Some(30) }",
+                "post_remove 1 removed, 0 updated, 0 exec changed",
+                "pre_update",
+                "post_apply 1 removed, 2 updated, 0 exec changed true",
+            ]
+        );
+        assert!(store.fetches.load(Ordering::Relaxed) > 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hook_failed() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        let hook = Arc::new(RecordingHook {
+            fail_pre_update: true,
+            ..Default::default()
+        });
+        let plan = hooks_plan(&vfs, hook.clone())?;
+
+        let store = CountingStore::default();
+        match plan.apply_store(&store).await {
+            Err(CheckoutError::HookFailed { phase, .. }) => {
+                assert_eq!(phase, HookPhase::PreUpdate)
+            }
+            other => panic!("expected HookFailed, got {:?}", other.err()),
+        }
+        // Nothing was fetched or written, and post_apply was not called.
+        assert_eq!(store.fetches.load(Ordering::Relaxed), 0);
+        assert!(!tempdir.path().join("a").exists());
+        assert_eq!(hook.calls.lock().len(), 3);
+
+        // Errors can be ignored.
+        let hook = Arc::new(RecordingHook {
+            fail_pre_update: true,
+            ..Default::default()
+        });
+        let plan = Checkout::default_config(vfs.clone())
+            .with_hook(hook.clone())
+            .with_hook_errors_as_warnings(true)
+            .plan_action_map(ActionMap::empty());
+        plan.apply_store(&store).await?;
+        assert_eq!(hook.calls.lock().len(), 4);
+        Ok(())
+    }
+
+    /// Records the batches it is notified of, and the hook calls made
+    /// before each, failing if `fail`.
+    #[derive(Default)]
+    struct RecordingNotifier {
+        batches: Mutex<Vec<NotificationBatch>>,
+        hook: Option<Arc<RecordingHook>>,
+        fail: bool,
+    }
+
+    impl WorkingCopyNotifier for RecordingNotifier {
+        fn notify(&self, changes: NotificationBatch) -> Result<()> {
+            if let Some(hook) = &self.hook {
+                hook.calls.lock().push("notify".to_string());
+            }
+            self.batches.lock().push(changes);
+            if self.fail {
+                bail!("watcher is gone");
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_notifier() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        vfs.write(&rp("x"), b"x", UpdateFlag::Regular)?;
+        let hook = Arc::new(RecordingHook::default());
+        let notifier = Arc::new(RecordingNotifier {
+            hook: Some(hook.clone()),
+            ..Default::default()
+        });
+        vfs.write(&rp("old"), b"old", UpdateFlag::Regular)?;
+        vfs.write(&rp("b"), b"b", UpdateFlag::Regular)?;
+        let mut map = ActionMap::empty();
+        map.insert(rp("old"), Action::Remove);
+        map.insert(rp("a"), update_regular(1));
+        map.insert(rp("b"), update_regular(2));
+        map.insert(rp("x"), Action::UpdateExec(true));
+        let plan = Checkout::default_config(vfs.clone())
+            .with_hook(hook.clone())
+            .with_notifier(notifier.clone())
+            .plan_action_map(map);
+
+        let stats = plan.apply_store(&DummyFileContentStore).await?;
+        assert!(stats.warnings().is_empty());
+        assert_eq!(
+            *notifier.batches.lock(),
+            vec![NotificationBatch {
+                changes: vec![
+                    (rp("a"), PathOutcome::Written),
+                    (rp("b"), PathOutcome::Written),
+                    (rp("old"), PathOutcome::Removed),
+                    (rp("x"), PathOutcome::MetaChanged),
+                ],
+                last: true,
+            }]
+        );
+        // Notified after all changes, before post_apply.
+        let calls = hook.calls.lock();
+        assert_eq!(calls[calls.len() - 2], "notify");
+        assert!(calls[calls.len() - 1].starts_with("post_apply"));
+        drop(calls);
+
+        // The paths of a failed write are notified as failed, and notifier
+        // errors are only warnings.
+        let notifier = Arc::new(RecordingNotifier {
+            fail: true,
+            ..Default::default()
+        });
+        let mut map = ActionMap::empty();
+        map.insert(rp(".hg/x"), update_regular(1));
+        let plan = Checkout::default_config(vfs.clone())
+            .with_notifier(notifier.clone())
+            .plan_action_map(map);
+        match plan.apply_store(&DummyFileContentStore).await {
+            Err(CheckoutError::WriteFailed { path, .. }) => assert_eq!(path, rp(".hg/x")),
+            other => panic!("expected WriteFailed, got {:?}", other.err()),
+        }
+        assert_eq!(
+            notifier.batches.lock()[0].changes,
+            vec![(rp(".hg/x"), PathOutcome::Failed)]
+        );
+
+        let mut map = ActionMap::empty();
+        map.insert(rp("c"), update_regular(1));
+        let plan = Checkout::default_config(vfs.clone())
+            .with_notifier(notifier)
+            .plan_action_map(map);
+        let stats = plan.apply_store(&DummyFileContentStore).await?;
+        assert_eq!(
+            stats.warnings(),
+            vec!["Failed to notify of checkout changes: watcher is gone"]
+        );
+        Ok(())
+    }
+
+    /// A plan removing `old`, updating `b`, and writing `a` and `c`, which
+    /// have the same content.
+    fn staging_plan(vfs: &VFS) -> Result<CheckoutPlan> {
+        vfs.write(&rp("old"), b"old", UpdateFlag::Regular)?;
+        vfs.write(&rp("b"), b"b", UpdateFlag::Regular)?;
+        let mut map = ActionMap::empty();
+        map.insert(rp("old"), Action::Remove);
+        map.insert(rp("a"), update_regular(1));
+        map.insert(
+            rp("b"),
+            Action::Update(actions::UpdateAction::new(
+                Some(FileMetadata::regular(hgid(3))),
+                FileMetadata::regular(hgid(2)),
+            )),
+        );
+        map.insert(rp("dir/c"), update_regular(1));
+        Ok(Checkout::default_config(vfs.clone()).plan_action_map(map))
+    }
+
+    #[tokio::test]
+    async fn test_staged_checkout() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let root = tempdir.path().join("wc");
+        create_dir(&root)?;
+        let vfs = VFS::new(root)?;
+        let staging_dir = tempdir.path().join("staging");
+        let plan = staging_plan(&vfs)?;
+        // Replaced by a directory when committing.
+        vfs.write(&rp("dir"), b"a", UpdateFlag::Symlink)?;
+
+        let store = CountingStore::default();
+        let staged = plan.stage(&store, &staging_dir).await?;
+        // Shared content is staged once.
+        assert_eq!(store.keys.load(Ordering::Relaxed), 2);
+        assert_eq!(std::fs::read_dir(&staging_dir)?.count(), 3);
+        // The working copy is untouched.
+        assert_eq!(vfs.read(&rp("old"))?, b"old");
+        assert_eq!(vfs.read(&rp("b"))?, b"b");
+        assert!(!vfs.join(&rp("a")).exists());
+
+        let stats = staged.commit().await?;
+        assert_eq!(
+            stats.applied(),
+            AppliedStats {
+                removed: 1,
+                updated: 3,
+                meta_updated: 0,
+            }
+        );
+        assert!(!vfs.join(&rp("old")).exists());
+        assert_eq!(vfs.read(&rp("a"))?, hgid_file(&hgid(1)));
+        assert_eq!(vfs.read(&rp("dir/c"))?, hgid_file(&hgid(1)));
+        assert!(std::fs::symlink_metadata(vfs.join(&rp("dir")))?.is_dir());
+        assert_eq!(vfs.read(&rp("b"))?, hgid_file(&hgid(2)));
+        assert!(!staging_dir.exists());
+        // Committing does not fetch anything.
+        assert_eq!(store.fetches.load(Ordering::Relaxed), 1);
+        assert_eq!(store.keys.load(Ordering::Relaxed), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_staged_checkout_abandoned() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let root = tempdir.path().join("wc");
+        create_dir(&root)?;
+        let vfs = VFS::new(root)?;
+        let staging_dir = tempdir.path().join("staging");
+
+        let staged = staging_plan(&vfs)?
+            .stage(&DummyFileContentStore, &staging_dir)
+            .await?;
+        assert!(find_stale_staging(&staging_dir)?.is_some());
+        staged.abandon()?;
+        assert!(!staging_dir.exists());
+        assert!(find_stale_staging(&staging_dir)?.is_none());
+
+        // Staged content that changed is not written.
+        let staged = staging_plan(&vfs)?
+            .stage(&DummyFileContentStore, &staging_dir)
+            .await?;
+        std::fs::write(staging_dir.join(hgid(1).to_hex()), b"x")?;
+        let err = staged.commit().await.unwrap_err();
+        assert!(
+            matches!(err, CheckoutError::StagingFailed { .. }),
+            "{}",
+            err
+        );
+        assert_eq!(vfs.read(&rp("old"))?, b"old");
+        assert_eq!(vfs.read(&rp("b"))?, b"b");
+
+        // It is left behind, and blocks staging until it is removed.
+        let err = staging_plan(&vfs)?
+            .stage(&DummyFileContentStore, &staging_dir)
+            .await
+            .err();
+        assert!(matches!(err, Some(CheckoutError::StagingFailed { .. })));
+        let stale = find_stale_staging(&staging_dir)?.context("stale staging")?;
+        assert_eq!(stale.pid, Some(std::process::id()));
+        stale.remove()?;
+        assert!(!staging_dir.exists());
+        Ok(())
+    }
+
+    /// Every file under `root`, with its content, or target for symlinks,
+    /// and whether it is a symlink or executable.
+    #[cfg(unix)]
+    fn snapshot(root: &Path) -> Result<HashMap<PathBuf, (Vec<u8>, bool, bool)>> {
+        use std::os::unix::fs::PermissionsExt;
+        let mut files = HashMap::new();
+        for entry in WalkDir::new(root) {
+            let entry = entry?;
+            let file_type = entry.file_type();
+            if file_type.is_dir() {
+                continue;
+            }
+            let path = entry.path().strip_prefix(root)?.to_path_buf();
+            let file = if file_type.is_symlink() {
+                let target = std::fs::read_link(entry.path())?;
+                (
+                    target.to_string_lossy().into_owned().into_bytes(),
+                    true,
+                    false,
+                )
+            } else {
+                let exec = entry.metadata()?.permissions().mode() & 0o111 != 0;
+                (std::fs::read(entry.path())?, false, exec)
+            };
+            files.insert(path, file);
+        }
+        Ok(files)
+    }
+
+    /// A plan removing `dir/b`, overwriting `a`, `big` and `link`, adding
+    /// `new`, and clearing the exec bit of `x`.
+    #[cfg(unix)]
+    fn undo_plan(vfs: &VFS) -> Result<CheckoutPlan> {
+        vfs.write(&rp("a"), b"a", UpdateFlag::Regular)?;
+        vfs.write(&rp("x"), b"x", UpdateFlag::Executable)?;
+        vfs.write(&rp("dir/b"), b"b", UpdateFlag::Executable)?;
+        vfs.write(&rp("link"), b"a", UpdateFlag::Symlink)?;
+        vfs.write(&rp("big"), &hgid_file(&hgid(9)), UpdateFlag::Regular)?;
+        let mut map = ActionMap::empty();
+        map.insert(rp("dir/b"), Action::Remove);
+        map.insert(rp("x"), Action::UpdateExec(false));
+        for (path, id) in [("a", 1), ("big", 2), ("link", 3)] {
+            map.insert(
+                rp(path),
+                Action::Update(actions::UpdateAction::new(
+                    Some(FileMetadata::regular(hgid(8))),
+                    FileMetadata::regular(hgid(id)),
+                )),
+            );
+        }
+        map.insert(rp("new"), update_regular(4));
+        Ok(Checkout::default_config(vfs.clone()).plan_action_map(map))
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_undo() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let root = tempdir.path().join("wc");
+        create_dir(&root)?;
+        let vfs = VFS::new(root.clone())?;
+        let spill_dir = tempdir.path().join("undo");
+        let plan = undo_plan(&vfs)?;
+        let before = snapshot(&root)?;
+
+        let big_size = hgid_file(&hgid(9)).len() as u64;
+        let reverse: ReverseMetadata =
+            Arc::new(|path: &RepoPath| (path.as_str() == "big").then(|| hgid(9)));
+        let options = UndoOptions::new(spill_dir.clone())
+            .with_max_file_bytes(big_size - 1)
+            .with_reverse_metadata(reverse);
+        let (_, undo) = plan
+            .apply_store_with_undo(&DummyFileContentStore, &options)
+            .await
+            .map_err(|(e, _)| e)?;
+        assert!(undo.unrestorable().is_empty());
+        // `a` and `link` have the same content, which is spilled once.
+        assert_eq!(std::fs::read_dir(&spill_dir)?.count(), 3);
+        assert_ne!(snapshot(&root)?, before);
+        assert!(!vfs.join(&rp("dir")).exists());
+
+        let store = CountingStore::default();
+        let stats = undo.apply(&store).await?;
+        assert_eq!(
+            stats.applied(),
+            AppliedStats {
+                removed: 1,
+                updated: 4,
+                meta_updated: 1,
+            }
+        );
+        assert_eq!(snapshot(&root)?, before);
+        assert!(!spill_dir.exists());
+        // Only `big` was fetched.
+        assert_eq!(store.keys.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_undo_overflow() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let root = tempdir.path().join("wc");
+        create_dir(&root)?;
+        let vfs = VFS::new(root.clone())?;
+        let spill_dir = tempdir.path().join("undo");
+        let plan = undo_plan(&vfs)?;
+        let before = snapshot(&root)?;
+
+        // Fails before changing anything.
+        let options = UndoOptions::new(spill_dir.clone()).with_max_spill_bytes(1);
+        let (err, undo) = plan
+            .apply_store_with_undo(&DummyFileContentStore, &options)
+            .await
+            .err()
+            .unwrap();
+        assert!(undo.is_none());
+        assert!(
+            matches!(err, CheckoutError::UndoCaptureFailed { .. }),
+            "{}",
+            err
+        );
+        assert_eq!(snapshot(&root)?, before);
+        assert!(!spill_dir.exists());
+
+        // Files that don't fit are not restored.
+        let options = options.with_overflow(UndoOverflow::MetadataOnly);
+        let (_, undo) = plan
+            .apply_store_with_undo(&DummyFileContentStore, &options)
+            .await
+            .map_err(|(e, _)| e)?;
+        // Only `dir/b` fits.
+        let mut unrestorable = undo.unrestorable().to_vec();
+        unrestorable.sort();
+        assert_eq!(unrestorable, vec![rp("a"), rp("big"), rp("link")]);
+        undo.apply(&DummyFileContentStore).await?;
+        let after = snapshot(&root)?;
+        assert_eq!(after[Path::new("dir/b")], before[Path::new("dir/b")]);
+        assert_eq!(after[Path::new("x")], before[Path::new("x")]);
+        assert_eq!(after[Path::new("a")], (hgid_file(&hgid(1)), false, false));
+        assert!(!after.contains_key(Path::new("new")));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_undo_failed_checkout() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let root = tempdir.path().join("wc");
+        create_dir(&root)?;
+        let vfs = VFS::new(root.clone())?;
+        let spill_dir = tempdir.path().join("undo");
+        let plan = undo_plan(&vfs)?;
+        let before = snapshot(&root)?;
+
+        // The undo plan is returned with the error, and rolls back what was
+        // applied before fetching failed.
+        let options = UndoOptions::new(spill_dir.clone());
+        let undo = match plan
+            .apply_store_with_undo(&FailingStore::Unavailable, &options)
+            .await
+        {
+            Err((CheckoutError::FetchRetriesExhausted { .. }, Some(undo))) => undo,
+            Err((e, _)) => panic!("expected FetchRetriesExhausted with undo, got {:?}", e),
+            Ok(_) => panic!("expected FetchRetriesExhausted, got success"),
+        };
+        assert!(!vfs.join(&rp("dir")).exists());
+        undo.apply(&DummyFileContentStore).await?;
+        assert_eq!(snapshot(&root)?, before);
+        assert!(!spill_dir.exists());
+        Ok(())
+    }
+
+    /// Checks out `to` over `from` in a new working copy with `vfs_fn` and
+    /// `dir_batching`, returning the resulting files and the bytes written.
+    #[cfg(unix)]
+    async fn checkout_snapshot(
+        from: &[(RepoPathBuf, FileMetadata)],
+        to: &[(RepoPathBuf, FileMetadata)],
+        vfs_fn: impl Fn(VFS) -> VFS,
+        dir_batching: Option<DirBatching>,
+    ) -> Result<(HashMap<PathBuf, (Vec<u8>, bool, bool)>, usize)> {
+        let tempdir = tempfile::tempdir()?;
+        let root = tempdir.path().join("wc");
+        create_dir(&root)?;
+        let vfs = vfs_fn(VFS::new(root.clone())?);
+        roll_out_fs(&vfs, from)?;
+        // Checked out over a symlinked directory, which must not be written
+        // through.
+        let outside = tempdir.path().join("outside");
+        create_dir(&outside)?;
+        if !root.join("linked").exists() {
+            std::os::unix::fs::symlink(&outside, root.join("linked"))?;
+        }
+
+        let store = Arc::new(TestStore::new());
+        let matcher = AlwaysMatcher::new();
+        let left_tree = make_tree_manifest_from_meta(store.clone(), from.iter().cloned());
+        let right_tree = make_tree_manifest_from_meta(store, to.iter().cloned());
+        let diff = Diff::new(&left_tree, &right_tree, &matcher)?;
+        let plan = Checkout::default_config(vfs)
+            .with_dir_batching(dir_batching)
+            .plan_action_map(ActionMap::from_diff(diff)?);
+        let stats = plan.apply_store(&DummyFileContentStore).await?;
+        ensure!(
+            std::fs::read_dir(&outside)?.next().is_none(),
+            "wrote through a symlink"
+        );
+        Ok((
+            snapshot(&root)?,
+            stats.written_bytes.load(Ordering::Relaxed),
+        ))
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_dir_batching() -> Result<()> {
+        let mut trees = generate_trees(100, 3);
+        let mut wide = Vec::new();
+        for i in 0..40u8 {
+            wide.push((rp(&format!("wide/{}", i)), FileMetadata::regular(hgid(i))));
+        }
+        wide.push((rp("wide/sub/x"), FileMetadata::executable(hgid(1))));
+        wide.push((rp("linked/a"), FileMetadata::symlink(hgid(2))));
+        wide.push((rp("top"), FileMetadata::regular(hgid(3))));
+        trees.push(wide);
+        trees.push(vec![(rp("wide"), FileMetadata::regular(hgid(4)))]);
+
+        let batching = DirBatching {
+            dirs: 2,
+            files_per_dir: 3,
+        };
+        for from in trees.iter() {
+            for to in trees.iter() {
+                let expected = checkout_snapshot(from, to, |vfs| vfs, None).await?;
+                let batched = checkout_snapshot(from, to, |vfs| vfs, Some(batching)).await?;
+                assert_eq!(batched, expected);
+                // Without the dirfd capability.
+                let fallback =
+                    checkout_snapshot(from, to, |vfs| vfs.with_dir_opener(None), Some(batching))
+                        .await?;
+                assert_eq!(fallback, expected);
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts the tasks run with `inner`.
+    struct CountingSpawner<S> {
+        inner: S,
+        tasks: AtomicUsize,
+    }
+
+    impl<S: BlockingSpawner> BlockingSpawner for CountingSpawner<S> {
+        fn spawn(&self, task: BlockingTask) -> future::BoxFuture<'static, Result<()>> {
+            self.tasks.fetch_add(1, Ordering::Relaxed);
+            self.inner.spawn(task)
+        }
+    }
+
+    /// Checks out `to` over `from` in a new working copy, running blocking
+    /// work with `spawner` if set, and returns the resulting files.
+    #[cfg(unix)]
+    async fn checkout_with_spawner(
+        from: &[(RepoPathBuf, FileMetadata)],
+        to: &[(RepoPathBuf, FileMetadata)],
+        spawner: Option<Arc<dyn BlockingSpawner>>,
+    ) -> Result<HashMap<PathBuf, (Vec<u8>, bool, bool)>> {
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        roll_out_fs(&vfs, from)?;
+
+        let store = Arc::new(TestStore::new());
+        let matcher = AlwaysMatcher::new();
+        let left_tree = make_tree_manifest_from_meta(store.clone(), from.iter().cloned());
+        let right_tree = make_tree_manifest_from_meta(store, to.iter().cloned());
+        let diff = Diff::new(&left_tree, &right_tree, &matcher)?;
+        let mut checkout = Checkout::default_config(vfs).with_dir_batching(None);
+        if let Some(spawner) = spawner {
+            checkout = checkout.with_blocking_spawner(spawner);
+        }
+        let plan = checkout.plan_action_map(ActionMap::from_diff(diff)?);
+        plan.apply_store(&DummyFileContentStore).await?;
+        snapshot(tempdir.path())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_blocking_spawner() -> Result<()> {
+        let from = [
+            (rp("a"), FileMetadata::regular(hgid(1))),
+            (rp("b"), FileMetadata::regular(hgid(2))),
+            (rp("d/e"), FileMetadata::regular(hgid(3))),
+        ];
+        let to = [
+            (rp("a"), FileMetadata::regular(hgid(4))),
+            (rp("c"), FileMetadata::regular(hgid(5))),
+            (rp("d/e"), FileMetadata::executable(hgid(3))),
+        ];
+        let expected =
+            tokio::runtime::Runtime::new()?.block_on(checkout_with_spawner(&from, &to, None))?;
+
+        // Without a tokio runtime, using its blocking pool would panic.
+        let sync = Arc::new(CountingSpawner {
+            inner: SyncSpawner,
+            tasks: AtomicUsize::new(0),
+        });
+        let files =
+            futures::executor::block_on(checkout_with_spawner(&from, &to, Some(sync.clone())))?;
+        assert_eq!(files, expected);
+
+        let pool = Arc::new(CountingSpawner {
+            inner: ThreadPoolSpawner::new(2),
+            tasks: AtomicUsize::new(0),
+        });
+        let files =
+            futures::executor::block_on(checkout_with_spawner(&from, &to, Some(pool.clone())))?;
+        assert_eq!(files, expected);
+
+        // A batch of removals, a batch of writes and an exec bit update.
+        assert_eq!(sync.tasks.load(Ordering::Relaxed), 3);
+        assert_eq!(pool.tasks.load(Ordering::Relaxed), 3);
+        Ok(())
+    }
+
+    /// Returns content in the reverse order of the keys.
+    struct ReversingStore;
+
+    #[async_trait::async_trait]
+    impl ReadFileContents for ReversingStore {
+        type Error = anyhow::Error;
+
+        async fn read_file_contents(&self, keys: Vec<Key>) -> BoxStream<Result<(Bytes, Key)>> {
+            stream::iter(keys.into_iter().rev())
+                .map(|key| Ok((hgid_file(&key.hgid).into(), key)))
+                .boxed()
+        }
+
+        async fn read_rename_metadata(
+            &self,
+            _keys: Vec<Key>,
+        ) -> BoxStream<Result<(Key, Option<Key>), Self::Error>> {
+            stream::empty().boxed()
+        }
+    }
+
+    /// Checks out `to` over `from` in a new working copy in deterministic
+    /// mode, returning the paths touched in the order they were touched.
+    async fn deterministic_order(
+        from: &[(RepoPathBuf, FileMetadata)],
+        to: &[(RepoPathBuf, FileMetadata)],
+        store: &dyn ReadFileContents<Error = anyhow::Error>,
+    ) -> Result<(Vec<RepoPathBuf>, CheckoutPlan)> {
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        roll_out_fs(&vfs, from)?;
+
+        let tree_store = Arc::new(TestStore::new());
+        let matcher = AlwaysMatcher::new();
+        let left_tree = make_tree_manifest_from_meta(tree_store.clone(), from.iter().cloned());
+        let right_tree = make_tree_manifest_from_meta(tree_store, to.iter().cloned());
+        let diff = Diff::new(&left_tree, &right_tree, &matcher)?;
+        let plan = Checkout::default_config(vfs)
+            .with_file_metadata(true)
+            .with_deterministic(true)
+            .plan_action_map(ActionMap::from_diff(diff)?);
+        let stats = plan.apply_store(store).await?;
+        let files = stats.take_file_metadata().unwrap_or_default();
+        Ok((files.into_iter().map(|(path, _)| path).collect(), plan))
+    }
+
+    #[tokio::test]
+    async fn test_deterministic() -> Result<()> {
+        let mut from = Vec::new();
+        let mut to = Vec::new();
+        for i in 0..250usize {
+            let path = rp(&format!("d{}/f{}", i % 7, i));
+            let id = hgid(i as u8);
+            from.push((path.clone(), FileMetadata::regular(id)));
+            match i % 5 {
+                0 => {}
+                1 => to.push((path, FileMetadata::regular(hgid((i + 1) as u8)))),
+                2 => to.push((path, FileMetadata::executable(id))),
+                _ => to.push((path, FileMetadata::regular(id))),
+            }
+        }
+        for j in 0..120usize {
+            let path = rp(&format!("n{}/g{}", j % 3, j));
+            to.push((path, FileMetadata::regular(hgid(j as u8))));
+        }
+
+        let (first, mut plan) = deterministic_order(&from, &to, &DummyFileContentStore).await?;
+        let (second, _) = deterministic_order(&from, &to, &ReversingStore).await?;
+        assert_eq!(first, second);
+
+        // Removals, then content updates, then exec bit updates, each in
+        // path order.
+        plan.normalize();
+        let expected: Vec<_> = plan
+            .removed_files()
+            .chain(plan.updated_content_files())
+            .chain(plan.updated_meta_files())
+            .cloned()
+            .collect();
+        assert_eq!(expected.len(), 50 + 170 + 50);
+        assert_eq!(first, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_checkout_manifests() -> Result<()> {
+        use pathmatcher::TreeMatcher;
+
+        let from = [
+            (rp("A"), FileMetadata::regular(hgid(1))),
+            (rp("dir/B"), FileMetadata::regular(hgid(1))),
+            (rp("dir/C"), FileMetadata::regular(hgid(1))),
+            (rp("other/D"), FileMetadata::regular(hgid(1))),
+        ];
+        let to = [
+            (rp("A"), FileMetadata::regular(hgid(2))),
+            (rp("dir/B"), FileMetadata::executable(hgid(1))),
+            (rp("dir/C"), FileMetadata::regular(hgid(1))),
+            (rp("dir/E"), FileMetadata::regular(hgid(3))),
+        ];
+        let store = Arc::new(TestStore::new());
+        let current = make_tree_manifest_from_meta(store.clone(), from.iter().cloned());
+        let target = make_tree_manifest_from_meta(store, to.iter().cloned());
+        let file_len = hgid_file(&hgid(1)).len();
+
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        roll_out_fs(&vfs, &from)?;
+        let report = checkout_manifests(
+            vfs,
+            &current,
+            &target,
+            &DummyFileContentStore,
+            CheckoutOptions::default(),
+        )
+        .await?;
+        assert_fs(tempdir.path(), &to)?;
+        assert_eq!(
+            report.plan,
+            PlanSummary {
+                remove: 1,
+                update_content: 2,
+                new_files: 1,
+                update_meta: 1,
+                update_bytes: None,
+            }
+        );
+        assert_eq!(
+            report.applied,
+            AppliedStats {
+                removed: 1,
+                updated: 2,
+                meta_updated: 1,
+            }
+        );
+        assert_eq!(report.written_bytes, 2 * file_len);
+        assert_eq!(report.diff_summary.total(), 4);
+        assert!(report.stats.is_some());
+
+        // A dry run only fetches.
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        roll_out_fs(&vfs, &from)?;
+        let options = CheckoutOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let report =
+            checkout_manifests(vfs, &current, &target, &DummyFileContentStore, options).await?;
+        assert_fs(tempdir.path(), &from)?;
+        assert_eq!(report.applied, AppliedStats::default());
+        assert_eq!(report.fetched_files, 2);
+        assert_eq!(report.fetched_bytes, 2 * file_len as u64);
+        assert!(report.stats.is_none());
+
+        // Files outside of the sparse matcher are left alone.
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        roll_out_fs(&vfs, &from)?;
+        let options = CheckoutOptions {
+            sparse_matcher: Some(Arc::new(TreeMatcher::from_rules(["dir/**"].iter(), true)?)),
+            ..Default::default()
+        };
+        let report =
+            checkout_manifests(vfs, &current, &target, &DummyFileContentStore, options).await?;
+        let mut expected = to[1..].to_vec();
+        expected.push(from[0].clone());
+        expected.push(from[3].clone());
+        assert_fs(tempdir.path(), &expected)?;
+        assert_eq!(
+            report.applied,
+            AppliedStats {
+                removed: 0,
+                updated: 1,
+                meta_updated: 1,
+            }
+        );
+
+        // Files are written with the file mode policy.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let tempdir = tempfile::tempdir()?;
+            let vfs = VFS::new(tempdir.path().to_path_buf())?;
+            roll_out_fs(&vfs, &from)?;
+            let options = CheckoutOptions {
+                file_mode_policy: FileModePolicy::Fixed {
+                    regular: 0o640,
+                    executable: 0o750,
+                },
+                ..Default::default()
+            };
+            checkout_manifests(vfs, &current, &target, &DummyFileContentStore, options).await?;
+            let mode = |path: &str| -> Result<u32> {
+                let metadata = std::fs::metadata(tempdir.path().join(path))?;
+                Ok(metadata.permissions().mode() & 0o777)
+            };
+            assert_eq!(mode("A")?, 0o640);
+            assert_eq!(mode("dir/B")?, 0o750);
+            assert_eq!(mode("dir/E")?, 0o640);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_locally_deleted() -> Result<()> {
+        use status::StatusBuilder;
+
+        let from = [
+            (rp("A"), FileMetadata::regular(hgid(1))),
+            (rp("B"), FileMetadata::regular(hgid(1))),
+            (rp("C"), FileMetadata::regular(hgid(1))),
+        ];
+        // A and B are deleted locally. The content of A changes, only the
+        // flags of B do.
+        let to = [
+            (rp("A"), FileMetadata::regular(hgid(2))),
+            (rp("B"), FileMetadata::executable(hgid(1))),
+            (rp("C"), FileMetadata::regular(hgid(3))),
+        ];
+        let deleted = vec![rp("A"), rp("B")];
+        let status = StatusBuilder::new().deleted(deleted.clone()).build();
+        let store = Arc::new(TestStore::new());
+        let matcher = AlwaysMatcher::new();
+        let current = make_tree_manifest_from_meta(store.clone(), from.iter().cloned());
+        let target = make_tree_manifest_from_meta(store, to.iter().cloned());
+        let plan = |vfs: &VFS| -> Result<CheckoutPlan> {
+            let diff = Diff::new(&current, &target, &matcher)?;
+            Ok(Checkout::default_config(vfs.clone())
+                .with_file_metadata(true)
+                .plan_action_map(ActionMap::from_diff(diff)?))
+        };
+        let working_copy = || -> Result<(TempDir, VFS)> {
+            let tempdir = tempfile::tempdir()?;
+            let vfs = VFS::new(tempdir.path().to_path_buf())?;
+            roll_out_fs(&vfs, &from)?;
+            for path in &deleted {
+                vfs.remove(path)?;
+            }
+            Ok((tempdir, vfs))
+        };
+        let recorded = |stats: &CheckoutStats| {
+            let mut paths: Vec<_> = stats
+                .take_file_metadata()
+                .unwrap()
+                .into_iter()
+                .map(|(path, meta)| {
+                    assert!(meta.is_some(), "{} recorded as removed", path);
+                    path
+                })
+                .collect();
+            paths.sort();
+            paths
+        };
+
+        let (_tempdir, vfs) = working_copy()?;
+        assert_eq!(plan(&vfs)?.check_conflicts(&status).len(), 2);
+
+        // Restored files are written, including the one whose flags only
+        // changed.
+        let (tempdir, vfs) = working_copy()?;
+        let plan_restore =
+            plan(&vfs)?.with_locally_deleted(&deleted, LocallyDeletedPolicy::Restore, &target)?;
+        assert_eq!(plan_restore.restored_files(), &deleted[..]);
+        assert!(plan_restore.check_conflicts(&status).is_empty());
+        let stats = plan_restore.apply_store(&DummyFileContentStore).await?;
+        assert_fs(tempdir.path(), &to)?;
+        assert_eq!(stats.restored(), 2);
+        assert!(stats.kept_deleted().is_empty());
+        assert_eq!(
+            stats.applied(),
+            AppliedStats {
+                removed: 0,
+                updated: 3,
+                meta_updated: 0,
+            }
+        );
+        assert_eq!(recorded(&stats), vec![rp("A"), rp("B"), rp("C")]);
+
+        // Kept files are neither written nor recorded.
+        let (tempdir, vfs) = working_copy()?;
+        let plan_keep = plan(&vfs)?.with_locally_deleted(
+            &deleted,
+            LocallyDeletedPolicy::KeepDeleted,
+            &target,
+        )?;
+        assert_eq!(plan_keep.kept_deleted_files(), &deleted[..]);
+        assert!(plan_keep.check_conflicts(&status).is_empty());
+        let stats = plan_keep.apply_store(&DummyFileContentStore).await?;
+        assert_fs(tempdir.path(), &to[2..])?;
+        assert_eq!(stats.restored(), 0);
+        assert_eq!(stats.kept_deleted(), deleted);
+        assert_eq!(
+            stats.applied(),
+            AppliedStats {
+                removed: 0,
+                updated: 1,
+                meta_updated: 0,
+            }
+        );
+        assert_eq!(recorded(&stats), vec![rp("C")]);
+
+        // Aborting lists the files.
+        let (tempdir, vfs) = working_copy()?;
+        match plan(&vfs)?.with_locally_deleted(&deleted, LocallyDeletedPolicy::Abort, &target) {
+            Ok(_) => bail!("checkout of locally deleted files was not aborted"),
+            Err(e) => {
+                assert!(e.to_string().contains("2 files deleted locally"), "{}", e);
+                match e.downcast_ref::<CheckoutError>() {
+                    Some(CheckoutError::LocallyDeleted { paths }) => assert_eq!(paths, &deleted),
+                    _ => return Err(e),
+                }
+            }
+        }
+        assert_fs(tempdir.path(), &from[2..])?;
+
+        // Files not changed by the plan are ignored.
+        let (_tempdir, vfs) = working_copy()?;
+        let plan_other =
+            plan(&vfs)?.with_locally_deleted(&[rp("D")], LocallyDeletedPolicy::Abort, &target)?;
+        assert!(plan_other.restored_files().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_plan_inconsistent() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        let async_vfs = AsyncVfsWriter::spawn_new(vfs, 1);
+        let bar = ProgressBar::new("Updating", 0, "files");
+
+        let result = CheckoutPlan::write_files(
+            &async_vfs,
+            &CheckoutStats::default(),
+            vec![],
+            None,
+            None,
+            &bar,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(CheckoutError::PlanInconsistent { .. })
+        ));
+        Ok(())
+    }
+
+    fn generate_trees(tree_size: usize, count: usize) -> Vec<Vec<(RepoPathBuf, FileMetadata)>> {
+        let mut result = vec![];
+        let mut gen = Gen::new(5);
+        let paths = generate_repo_paths(tree_size * count, &mut gen);
+
+        for i in 0..count {
+            let mut tree = vec![];
+            for idx in 0..tree_size {
+                let meta = FileMetadata::arbitrary(&mut gen);
+                let path = paths.get(i * tree_size / 2 + idx).unwrap().clone();
+                tree.push((path, meta));
+            }
+            result.push(tree)
+        }
         result
     }
 
@@ -1058,6 +3526,129 @@ mod test {
         }
     }
 
+    enum FailingStore {
+        /// Fails every key.
+        Unavailable,
+        /// Returns content for a path that was not requested.
+        WrongKey,
+        /// Returns nothing.
+        Missing,
+    }
+
+    #[async_trait::async_trait]
+    impl ReadFileContents for FailingStore {
+        type Error = anyhow::Error;
+
+        async fn read_file_contents(&self, keys: Vec<Key>) -> BoxStream<Result<(Bytes, Key)>> {
+            if matches!(self, FailingStore::Missing) {
+                return stream::empty().boxed();
+            }
+            let wrong_key = matches!(self, FailingStore::WrongKey);
+            stream::iter(keys)
+                .map(move |key| {
+                    if wrong_key {
+                        let content = hgid_file(&key.hgid).into();
+                        Ok((content, Key::new(rp("other"), key.hgid)))
+                    } else {
+                        Err(anyhow!("store unavailable").context(key))
+                    }
+                })
+                .boxed()
+        }
+
+        async fn read_rename_metadata(
+            &self,
+            _keys: Vec<Key>,
+        ) -> BoxStream<Result<(Key, Option<Key>), Self::Error>> {
+            stream::empty().boxed()
+        }
+    }
+
+    /// Returns 64MB of content for every tenth key, and 1KB for the others.
+    /// Large contents share their buffer.
+    struct SizedStore {
+        large: Bytes,
+    }
+
+    impl SizedStore {
+        const LARGE: usize = 64 << 20;
+
+        fn content(&self, hgid: &HgId) -> Bytes {
+            let n = hgid.as_ref()[0];
+            if n % 10 == 0 {
+                self.large.clone()
+            } else {
+                vec![n; 1024].into()
+            }
+        }
+    }
+
+    impl Default for SizedStore {
+        fn default() -> Self {
+            Self {
+                large: vec![7; Self::LARGE].into(),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ReadFileContents for SizedStore {
+        type Error = anyhow::Error;
+
+        async fn read_file_contents(&self, keys: Vec<Key>) -> BoxStream<Result<(Bytes, Key)>> {
+            let results: Vec<_> = keys
+                .into_iter()
+                .map(|key| Ok((self.content(&key.hgid), key)))
+                .collect();
+            stream::iter(results).boxed()
+        }
+
+        async fn read_rename_metadata(
+            &self,
+            _keys: Vec<Key>,
+        ) -> BoxStream<Result<(Key, Option<Key>), Self::Error>> {
+            stream::empty().boxed()
+        }
+    }
+
+    /// The first time a key is requested, fails it if the first byte of its
+    /// hgid is a multiple of 10, and skips it if that byte is 7 modulo 15.
+    #[derive(Default)]
+    struct FlakyStore {
+        requested: Mutex<HashSet<Key>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ReadFileContents for FlakyStore {
+        type Error = anyhow::Error;
+
+        async fn read_file_contents(&self, keys: Vec<Key>) -> BoxStream<Result<(Bytes, Key)>> {
+            let mut requested = self.requested.lock();
+            let results: Vec<_> = keys
+                .into_iter()
+                .filter_map(|key| {
+                    let first = requested.insert(key.clone());
+                    let n = key.hgid.as_ref()[0];
+                    if first && n % 10 == 0 {
+                        Some(Err(anyhow!("transient failure").context(key)))
+                    } else if first && n % 15 == 7 {
+                        None
+                    } else {
+                        Some(Ok((hgid_file(&key.hgid).into(), key)))
+                    }
+                })
+                .collect();
+            stream::iter(results).boxed()
+        }
+
+        async fn read_rename_metadata(
+            &self,
+            _keys: Vec<Key>,
+        ) -> BoxStream<Result<(Key, Option<Key>), Self::Error>> {
+            stream::empty().boxed()
+        }
+    }
+
     fn hgid_file(hgid: &HgId) -> Vec<u8> {
         hgid.to_string().into_bytes()
     }
@@ -1086,35 +3677,7 @@ impl fmt::Display for CheckoutPlan {
 }
 
 pub fn file_state(vfs: &VFS, path: &RepoPath) -> Result<FileStateV2> {
-    let meta = vfs.metadata(path)?;
-    #[cfg(unix)]
-    let mode = std::os::unix::fs::PermissionsExt::mode(&meta.permissions());
-    #[cfg(windows)]
-    let mode = 0o644; // todo figure this out
-    let mtime = meta
-        .modified()?
-        .duration_since(SystemTime::UNIX_EPOCH)?
-        .as_secs();
-    let mtime = truncate_u64("mtime", path, mtime);
-    let size = meta.len();
-    let size = truncate_u64("size", path, size);
-    let state = StateFlags::EXIST_P1 | StateFlags::EXIST_NEXT;
-    Ok(FileStateV2 {
-        mode,
-        size,
-        mtime,
-        state,
-        copied: None,
-    })
-}
-
-fn truncate_u64(f: &str, path: &RepoPath, v: u64) -> i32 {
-    const RANGE_MASK: u64 = 0x7FFFFFFF;
-    let truncated = v & RANGE_MASK;
-    if truncated != v {
-        warn!("{} for {} is truncated {}=>{}", f, path, v, truncated);
-    }
-    truncated as i32
+    FileStateMetadata::from_metadata(&vfs.metadata(path)?)?.to_file_state(path)
 }
 
 pub fn checkout(
@@ -1167,16 +3730,161 @@ pub fn checkout(
     }
 
     // 3. Execute the plan
-    block_on(plan.apply_store(&repo.file_store()?))?;
+    let stats = plan.blocking_apply_store(&repo.file_store()?)?;
+    let files = stats
+        .take_file_metadata()
+        .ok_or_else(|| anyhow!("checkout did not collect file metadata"))?;
 
     // 4. Update the treestate parents, dirstate
     wc.set_parents(&mut [target_commit].iter())?;
-    record_updates(&plan, &wc.vfs(), &mut wc.treestate().lock())?;
+    record_updates(files, &mut wc.treestate().lock())?;
     dirstate::flush(wc.vfs().root(), &mut wc.treestate().lock(), repo.locker())?;
 
     Ok(plan.stats())
 }
 
+/// Options of `checkout_manifests`. The defaults are those of
+/// `Checkout::default_config`.
+#[derive(Clone)]
+pub struct CheckoutOptions {
+    /// Only check out files matching this, e.g. the sparse profile. Other
+    /// files are left alone.
+    pub sparse_matcher: Option<Arc<dyn Matcher + Sync + Send>>,
+    /// Fetch the content the checkout would write, without changing the
+    /// working copy.
+    pub dry_run: bool,
+    /// See `Checkout::with_concurrency`.
+    pub concurrency: usize,
+    /// See `Checkout::with_case_normalization`.
+    pub case_normalization: CaseNormalization,
+    /// See `Checkout::with_fetch_retries`.
+    pub fetch_retries: usize,
+    /// See `Checkout::with_file_metadata`.
+    pub file_metadata: bool,
+    /// See `Checkout::with_deterministic`.
+    pub deterministic: bool,
+    /// See `Checkout::with_file_mode_policy`.
+    pub file_mode_policy: FileModePolicy,
+    /// See `Checkout::with_notifier`. Not notified for a dry run.
+    pub notifier: Option<Arc<dyn WorkingCopyNotifier>>,
+    /// See `Checkout::with_content_filter`.
+    pub content_filter: Option<Arc<dyn ContentFilter>>,
+    pub report_unfiltered_bytes: bool,
+}
+
+impl Default for CheckoutOptions {
+    fn default() -> Self {
+        Self {
+            sparse_matcher: None,
+            dry_run: false,
+            concurrency: DEFAULT_CONCURRENCY,
+            case_normalization: CaseNormalization::default(),
+            fetch_retries: DEFAULT_FETCH_RETRIES,
+            file_metadata: false,
+            deterministic: false,
+            file_mode_policy: FileModePolicy::default(),
+            notifier: None,
+            content_filter: None,
+            report_unfiltered_bytes: false,
+        }
+    }
+}
+
+/// What `checkout_manifests` did.
+pub struct CheckoutReport {
+    /// The changes between the manifests, within the sparse matcher.
+    pub diff_summary: DiffSummary,
+    /// The actions planned.
+    pub plan: PlanSummary,
+    /// The actions applied. Zero for a dry run.
+    pub applied: AppliedStats,
+    pub written_bytes: usize,
+    /// Files and bytes fetched by a dry run.
+    pub fetched_files: usize,
+    pub fetched_bytes: u64,
+    /// Paths written, or for a dry run to be written, that Windows can't
+    /// write as-is.
+    pub path_problems: Vec<PathProblem>,
+    /// Existing entries renamed to match the casing of the plan.
+    pub case_renamed: usize,
+    /// Time spent diffing the manifests and planning.
+    pub plan_time: Duration,
+    /// Time spent applying the plan, or fetching for a dry run.
+    pub apply_time: Duration,
+    /// See `CheckoutStats::warnings`.
+    pub warnings: Vec<String>,
+    /// All the statistics of the checkout, `None` for a dry run.
+    pub stats: Option<CheckoutStats>,
+}
+
+/// Checks out `target_manifest` in `vfs`, which has `current_manifest`
+/// checked out: diffs the manifests, plans the checkout and applies it with
+/// content from `store`.
+///
+/// Unlike `checkout`, the working copy is not checked for local changes and
+/// the treestate is not updated. For more control, use `Checkout` and
+/// `CheckoutPlan` directly.
+pub async fn checkout_manifests(
+    vfs: VFS,
+    current_manifest: &TreeManifest,
+    target_manifest: &TreeManifest,
+    store: &dyn ReadFileContents<Error = anyhow::Error>,
+    options: CheckoutOptions,
+) -> Result<CheckoutReport> {
+    let start = Instant::now();
+    let matcher: ArcMatcher = options
+        .sparse_matcher
+        .unwrap_or_else(|| Arc::new(AlwaysMatcher::new()));
+    let diff = Diff::new(current_manifest, target_manifest, &matcher)?;
+    let mut checkout = Checkout::default_config(vfs);
+    if let Some(notifier) = options.notifier {
+        checkout = checkout.with_notifier(notifier);
+    }
+    if let Some(filter) = options.content_filter {
+        checkout = checkout.with_content_filter(filter, options.report_unfiltered_bytes);
+    }
+    let plan = checkout
+        .with_concurrency(options.concurrency)
+        .with_case_normalization(options.case_normalization)
+        .with_fetch_retries(options.fetch_retries)
+        .with_file_metadata(options.file_metadata)
+        .with_deterministic(options.deterministic)
+        .with_file_mode_policy(options.file_mode_policy)
+        .plan_action_map(ActionMap::from_diff(diff)?);
+    let mut report = CheckoutReport {
+        diff_summary: plan.diff_summary().clone(),
+        plan: plan.summary(),
+        applied: AppliedStats::default(),
+        written_bytes: 0,
+        fetched_files: 0,
+        fetched_bytes: 0,
+        path_problems: Vec::new(),
+        case_renamed: 0,
+        plan_time: start.elapsed(),
+        apply_time: Duration::ZERO,
+        warnings: Vec::new(),
+        stats: None,
+    };
+
+    let start = Instant::now();
+    if options.dry_run {
+        let (files, bytes) = plan.apply_store_dry_run(store).await?;
+        report.fetched_files = files;
+        report.fetched_bytes = bytes;
+        report.path_problems = plan.windows_path_problems();
+    } else {
+        let stats = plan.apply_store(store).await?;
+        report.applied = stats.applied();
+        report.written_bytes = stats.written_bytes.load(Ordering::Relaxed);
+        report.path_problems = stats.path_problems();
+        report.case_renamed = stats.case_renamed.load(Ordering::Relaxed);
+        report.warnings = stats.warnings();
+        report.stats = Some(stats);
+    }
+    report.apply_time = start.elapsed();
+    Ok(report)
+}
+
 fn create_sparse_matchers(
     repo: &mut Repo,
     vfs: &VFS,
@@ -1242,7 +3950,7 @@ fn create_plan(
         actions =
             actions.with_sparse_profile_change(old_sparse, new_sparse, current_mf, target_mf)?;
     }
-    let checkout = Checkout::from_config(vfs.clone(), &config)?;
+    let checkout = Checkout::from_config(vfs.clone(), &config)?.with_file_metadata(true);
     let plan = checkout.plan_action_map(actions);
     // if let Some(progress_path) = progress_path {
     //     plan.add_progress(progress_path.as_path()).map_pyerr(py)?;
@@ -1251,20 +3959,19 @@ fn create_plan(
     Ok(plan)
 }
 
-fn record_updates(plan: &CheckoutPlan, vfs: &VFS, treestate: &mut TreeState) -> Result<()> {
-    let bar = ProgressBar::register_new("recording", plan.all_files().count() as u64, "files");
-
-    for removed in plan.removed_files() {
-        treestate.remove(removed)?;
-        bar.increase_position(1);
-    }
+fn record_updates(
+    files: Vec<(RepoPathBuf, Option<FileStateMetadata>)>,
+    treestate: &mut TreeState,
+) -> Result<()> {
+    let bar = ProgressBar::register_new("recording", files.len() as u64, "files");
 
-    for updated in plan
-        .updated_content_files()
-        .chain(plan.updated_meta_files())
-    {
-        let fstate = file_state(vfs, updated)?;
-        treestate.insert(updated, &fstate)?;
+    for (path, meta) in files {
+        match meta {
+            None => {
+                treestate.remove(&path)?;
+            }
+            Some(meta) => treestate.insert(&path, &meta.to_file_state(&path)?)?,
+        }
         bar.increase_position(1);
     }
 
@@ -0,0 +1,107 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Files deleted locally, without committing, that the checkout changes,
+//! see `CheckoutPlan::with_locally_deleted`.
+
+use std::collections::HashSet;
+use std::mem;
+
+use anyhow::Result;
+use manifest::Manifest;
+use types::RepoPathBuf;
+
+use crate::CheckoutError;
+use crate::CheckoutPlan;
+use crate::UpdateContentAction;
+
+/// What to do with files deleted locally that the target commit changes,
+/// in content or only in flags.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LocallyDeletedPolicy {
+    /// Write the target version of the files, counted by
+    /// `CheckoutStats::restored`. Plans do this by default.
+    #[default]
+    Restore,
+    /// Leave the files deleted, listed by `CheckoutStats::kept_deleted`.
+    /// Their metadata is not collected, so they stay tracked and show as
+    /// deleted after the checkout.
+    KeepDeleted,
+    /// Fail with `CheckoutError::LocallyDeleted`, listing the files.
+    Abort,
+}
+
+impl CheckoutPlan {
+    /// Applies `policy` to the files this plan changes that are in
+    /// `deleted`, usually `Status::deleted`. Restored files are no longer
+    /// reported by `check_conflicts`, and the ones whose flags only changed
+    /// are written with their content in `target`, the manifest checked
+    /// out.
+    pub fn with_locally_deleted<'a>(
+        mut self,
+        deleted: impl IntoIterator<Item = &'a RepoPathBuf>,
+        policy: LocallyDeletedPolicy,
+        target: &impl Manifest,
+    ) -> Result<Self> {
+        let deleted: HashSet<_> = deleted.into_iter().collect();
+        let mut affected: Vec<_> = self
+            .update_content
+            .iter()
+            .map(|u| &u.path)
+            .chain(self.update_meta.iter().map(|u| &u.path))
+            .filter(|path| deleted.contains(path))
+            .cloned()
+            .collect();
+        if affected.is_empty() {
+            return Ok(self);
+        }
+        affected.sort();
+
+        match policy {
+            LocallyDeletedPolicy::Restore => {
+                let (restored, update_meta): (Vec<_>, Vec<_>) = mem::take(&mut self.update_meta)
+                    .into_iter()
+                    .partition(|u| deleted.contains(&u.path));
+                self.update_meta = update_meta;
+                for action in restored {
+                    let meta = target.get_file(&action.path)?.ok_or_else(|| {
+                        CheckoutError::PlanInconsistent {
+                            detail: format!("{} is not in the target manifest", action.path),
+                        }
+                    })?;
+                    let update = UpdateContentAction::new(action.path, meta, false);
+                    self.update_content.push(update.clone());
+                    self.filtered_update_content.push(update);
+                }
+                self.restored = affected;
+            }
+            LocallyDeletedPolicy::KeepDeleted => {
+                self.update_content.retain(|u| !deleted.contains(&u.path));
+                self.filtered_update_content
+                    .retain(|u| !deleted.contains(&u.path));
+                self.update_meta.retain(|u| !deleted.contains(&u.path));
+                self.kept_deleted = affected;
+            }
+            LocallyDeletedPolicy::Abort => {
+                return Err(CheckoutError::LocallyDeleted { paths: affected }.into());
+            }
+        }
+        Ok(self)
+    }
+
+    /// Files deleted locally this plan writes again, see
+    /// `with_locally_deleted`.
+    pub fn restored_files(&self) -> &[RepoPathBuf] {
+        &self.restored
+    }
+
+    /// Files deleted locally this plan leaves deleted, see
+    /// `with_locally_deleted`.
+    pub fn kept_deleted_files(&self) -> &[RepoPathBuf] {
+        &self.kept_deleted
+    }
+}
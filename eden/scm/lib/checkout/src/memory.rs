@@ -0,0 +1,250 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use futures::stream;
+use futures::Stream;
+use futures::StreamExt;
+use serde::Serialize;
+use tokio::sync::Notify;
+
+/// Limits on the fetched content a checkout holds in memory before writing
+/// it, see `Checkout::with_memory_limits`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct MemoryLimits {
+    /// Fetching pauses while this many bytes were fetched but not written.
+    /// Sizes are only known once fetched, so this can be exceeded by one
+    /// file. A file is always fetched while nothing is in flight, so a
+    /// limit of 0 fetches one file at a time.
+    pub max_bytes_in_flight: usize,
+    /// Files up to this size are small, and can use the fast lane.
+    pub small_file_threshold: usize,
+    /// Percentage of `max_bytes_in_flight` kept for small files: once the
+    /// rest is used, fetching continues only until a large file arrives.
+    pub fast_lane_percent: u8,
+}
+
+impl Default for MemoryLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes_in_flight: 1 << 30,
+            small_file_threshold: 1 << 20,
+            fast_lane_percent: 25,
+        }
+    }
+}
+
+/// Bytes of fetched content held by a checkout.
+#[derive(Default)]
+pub(crate) struct MemoryBudget {
+    limits: MemoryLimits,
+    in_flight: AtomicUsize,
+    peak: AtomicUsize,
+    /// Set when a large file was fetched through the fast lane, until the
+    /// bytes in flight fit outside of it again.
+    fast_lane_closed: AtomicBool,
+    released: Notify,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(limits: MemoryLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    /// Bytes in flight above which only the fast lane is open.
+    fn shared_limit(&self) -> usize {
+        let percent = self.limits.fast_lane_percent.min(100) as usize;
+        self.limits.max_bytes_in_flight / 100 * (100 - percent)
+    }
+
+    fn may_fetch(&self) -> bool {
+        let in_flight = self.in_flight.load(Ordering::Acquire);
+        // Nothing would release bytes to wait for.
+        if in_flight == 0 || in_flight < self.shared_limit() {
+            self.fast_lane_closed.store(false, Ordering::Relaxed);
+            return true;
+        }
+        in_flight < self.limits.max_bytes_in_flight
+            && !self.fast_lane_closed.load(Ordering::Relaxed)
+    }
+
+    /// Waits until the next file may be fetched.
+    async fn wait(&self) {
+        loop {
+            let released = self.released.notified();
+            if self.may_fetch() {
+                return;
+            }
+            released.await;
+        }
+    }
+
+    /// Accounts for a fetched file of `size` bytes.
+    fn acquire(&self, size: usize) -> MemoryPermit<'_> {
+        let before = self.in_flight.fetch_add(size, Ordering::AcqRel);
+        self.peak.fetch_max(before + size, Ordering::Relaxed);
+        if before >= self.shared_limit() && size > self.limits.small_file_threshold {
+            self.fast_lane_closed.store(true, Ordering::Relaxed);
+        }
+        MemoryPermit { budget: self, size }
+    }
+
+    fn is_small(&self, size: usize) -> bool {
+        size <= self.limits.small_file_threshold
+    }
+}
+
+/// Bytes of fetched files, released once they are written or dropped.
+pub(crate) struct MemoryPermit<'a> {
+    budget: &'a MemoryBudget,
+    size: usize,
+}
+
+impl MemoryPermit<'_> {
+    fn merge(&mut self, other: MemoryPermit<'_>) {
+        self.size += other.size;
+        // `other` no longer releases anything.
+        std::mem::forget(other);
+    }
+}
+
+impl Drop for MemoryPermit<'_> {
+    fn drop(&mut self) {
+        if self.size > 0 {
+            self.budget.in_flight.fetch_sub(self.size, Ordering::AcqRel);
+            self.budget.released.notify_one();
+        }
+    }
+}
+
+/// Groups the items of `items` in batches of up to `batch_size`, accounting
+/// the bytes of each item, as returned by `size`, in `budget` until the
+/// batch's permit is dropped. Polling `items` pauses while the budget is
+/// used; the partial batch is returned first so it can be written. Large
+/// files are returned in batches of their own, so small files are not
+/// written after them.
+pub(crate) fn budgeted_batches<'a, T: 'a>(
+    items: impl Stream<Item = T> + Send + 'a,
+    budget: &'a MemoryBudget,
+    batch_size: usize,
+    size: fn(&T) -> usize,
+) -> impl Stream<Item = (Vec<T>, MemoryPermit<'a>)> + 'a
+where
+    T: Send,
+{
+    let state = (items.boxed(), None::<(T, MemoryPermit<'a>)>, false);
+    stream::unfold(state, move |(mut items, mut large, mut done)| async move {
+        let mut batch = Vec::new();
+        let mut permit = budget.acquire(0);
+        if let Some((item, large_permit)) = large.take() {
+            return Some(((vec![item], large_permit), (items, None, done)));
+        }
+        while !done && batch.len() < batch_size {
+            if !budget.may_fetch() {
+                if !batch.is_empty() {
+                    break;
+                }
+                budget.wait().await;
+            }
+            match items.next().await {
+                None => done = true,
+                Some(item) => {
+                    let item_size = size(&item);
+                    let item_permit = budget.acquire(item_size);
+                    if budget.is_small(item_size) {
+                        batch.push(item);
+                        permit.merge(item_permit);
+                    } else if batch.is_empty() {
+                        return Some(((vec![item], item_permit), (items, None, done)));
+                    } else {
+                        large = Some((item, item_permit));
+                        break;
+                    }
+                }
+            }
+        }
+        if batch.is_empty() {
+            None
+        } else {
+            Some(((batch, permit), (items, large, done)))
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_budgeted_batches() {
+        let budget = MemoryBudget::new(MemoryLimits {
+            max_bytes_in_flight: 1000,
+            small_file_threshold: 10,
+            fast_lane_percent: 20,
+        });
+        let sizes = vec![5, 5, 500, 5, 700, 5, 5];
+        let batches: Vec<_> = budgeted_batches(stream::iter(sizes), &budget, 3, |s| *s)
+            .map(|(batch, _permit)| batch)
+            .collect()
+            .await;
+        assert_eq!(
+            batches,
+            vec![vec![5, 5], vec![500], vec![5], vec![700], vec![5, 5]]
+        );
+        assert_eq!(budget.in_flight.load(Ordering::Relaxed), 0);
+        assert_eq!(budget.peak(), 705);
+    }
+
+    #[tokio::test]
+    async fn test_fast_lane() {
+        let budget = MemoryBudget::new(MemoryLimits {
+            max_bytes_in_flight: 1000,
+            small_file_threshold: 10,
+            fast_lane_percent: 20,
+        });
+        let first = budget.acquire(900);
+        // The shared part is used, small files may still be fetched.
+        assert!(budget.may_fetch());
+        let small = budget.acquire(10);
+        assert!(budget.may_fetch());
+        // A large file closes the fast lane.
+        let large = budget.acquire(50);
+        assert!(!budget.may_fetch());
+        drop(small);
+        assert!(!budget.may_fetch());
+        drop(large);
+        assert!(!budget.may_fetch());
+        drop(first);
+        assert!(budget.may_fetch());
+        assert_eq!(budget.peak(), 960);
+    }
+
+    #[tokio::test]
+    async fn test_zero_limit() {
+        let budget = MemoryBudget::new(MemoryLimits {
+            max_bytes_in_flight: 0,
+            ..Default::default()
+        });
+        let sizes = vec![5, 5, 500];
+        let batches: Vec<_> = budgeted_batches(stream::iter(sizes), &budget, 3, |s| *s)
+            .map(|(batch, _permit)| batch)
+            .collect()
+            .await;
+        assert_eq!(batches, vec![vec![5], vec![5], vec![500]]);
+        assert_eq!(budget.peak(), 500);
+    }
+}
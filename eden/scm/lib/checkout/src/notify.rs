@@ -0,0 +1,208 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Telling whatever watches the working copy, like a virtualized filesystem
+//! or watchman, which paths a checkout changed, so it does not need to scan
+//! for them. See `Checkout::with_notifier`.
+
+use std::io::Write;
+
+use anyhow::Result;
+use parking_lot::Mutex;
+use serde::Serialize;
+use types::RepoPathBuf;
+
+/// Maximum number of changes in a `NotificationBatch`.
+pub(crate) const NOTIFICATION_BATCH_SIZE: usize = 1000;
+
+/// What a checkout did to a path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathOutcome {
+    /// The content was written, and the file type set.
+    Written,
+    Removed,
+    /// Only the exec bit was changed.
+    MetaChanged,
+    /// The path was in a batch of writes or removals that failed, or its
+    /// exec bit could not be changed. It may or may not have changed.
+    Failed,
+}
+
+/// Some of the paths changed by a checkout, sorted by path.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NotificationBatch {
+    pub changes: Vec<(RepoPathBuf, PathOutcome)>,
+    /// Whether this is the last batch of the checkout.
+    pub last: bool,
+}
+
+/// Told about the paths a checkout changed once it no longer touches the
+/// filesystem, whether it succeeded or not.
+pub trait WorkingCopyNotifier: Send + Sync {
+    /// Called for each batch in turn, from a blocking task. Errors are
+    /// reported as warnings of the checkout, and the remaining batches are
+    /// not sent.
+    fn notify(&self, changes: NotificationBatch) -> Result<()>;
+}
+
+/// Ignores notifications.
+pub struct NoopNotifier;
+
+impl WorkingCopyNotifier for NoopNotifier {
+    fn notify(&self, _changes: NotificationBatch) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes each batch as a line of JSON, with the files in the format of
+/// watchman subscription results:
+///
+/// ```json
+/// {"files":[{"name":"a/b","exists":true,"outcome":"written"}],"last":true}
+/// ```
+///
+/// `exists` is left out for failed paths.
+pub struct JsonLinesNotifier<W> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> JsonLinesNotifier<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner()
+    }
+}
+
+#[derive(Serialize)]
+struct JsonBatch<'a> {
+    files: Vec<JsonFile<'a>>,
+    last: bool,
+}
+
+#[derive(Serialize)]
+struct JsonFile<'a> {
+    name: &'a RepoPathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exists: Option<bool>,
+    outcome: PathOutcome,
+}
+
+impl<W: Write + Send> WorkingCopyNotifier for JsonLinesNotifier<W> {
+    fn notify(&self, changes: NotificationBatch) -> Result<()> {
+        let files = changes
+            .changes
+            .iter()
+            .map(|(path, outcome)| JsonFile {
+                name: path,
+                exists: match outcome {
+                    PathOutcome::Written | PathOutcome::MetaChanged => Some(true),
+                    PathOutcome::Removed => Some(false),
+                    PathOutcome::Failed => None,
+                },
+                outcome: *outcome,
+            })
+            .collect();
+        let batch = JsonBatch {
+            files,
+            last: changes.last,
+        };
+        let mut line = serde_json::to_vec(&batch)?;
+        line.push(b'\n');
+        let mut writer = self.writer.lock();
+        writer.write_all(&line)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The outcomes recorded by a checkout with a notifier.
+#[derive(Default)]
+pub(crate) struct OutcomeRecorder {
+    changes: Mutex<Vec<(RepoPathBuf, PathOutcome)>>,
+}
+
+impl OutcomeRecorder {
+    pub(crate) fn record(
+        &self,
+        paths: impl IntoIterator<Item = RepoPathBuf>,
+        outcome: PathOutcome,
+    ) {
+        self.changes
+            .lock()
+            .extend(paths.into_iter().map(|path| (path, outcome)));
+    }
+
+    /// The recorded outcomes in batches, emptying the recorder.
+    pub(crate) fn take_batches(&self) -> Vec<NotificationBatch> {
+        let mut changes = std::mem::take(&mut *self.changes.lock());
+        changes.sort();
+        let mut batches: Vec<_> = changes
+            .chunks(NOTIFICATION_BATCH_SIZE)
+            .map(|changes| NotificationBatch {
+                changes: changes.to_vec(),
+                last: false,
+            })
+            .collect();
+        if let Some(batch) = batches.last_mut() {
+            batch.last = true;
+        }
+        batches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_lines() -> Result<()> {
+        let notifier = JsonLinesNotifier::new(Vec::new());
+        let path = |p: &str| RepoPathBuf::from_string(p.to_string()).unwrap();
+        notifier.notify(NotificationBatch {
+            changes: vec![
+                (path("a"), PathOutcome::Written),
+                (path("b/c"), PathOutcome::Removed),
+                (path("d"), PathOutcome::Failed),
+            ],
+            last: true,
+        })?;
+        let output = String::from_utf8(notifier.into_inner())?;
+        assert_eq!(
+            output,
+            concat!(
+                r#"{"files":[{"name":"a","exists":true,"outcome":"written"},"#,
+                r#"{"name":"b/c","exists":false,"outcome":"removed"},"#,
+                r#"{"name":"d","outcome":"failed"}],"last":true}"#,
+                "\n"
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_batches() {
+        let recorder = OutcomeRecorder::default();
+        let paths: Vec<_> = (0..NOTIFICATION_BATCH_SIZE + 1)
+            .rev()
+            .map(|i| RepoPathBuf::from_string(format!("f{:05}", i)).unwrap())
+            .collect();
+        recorder.record(paths, PathOutcome::Written);
+        let batches = recorder.take_batches();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].changes.len(), NOTIFICATION_BATCH_SIZE);
+        assert!(!batches[0].last);
+        assert_eq!(batches[1].changes[0].0.as_str(), "f01000");
+        assert!(batches[1].last);
+        assert!(recorder.take_batches().is_empty());
+    }
+}
@@ -0,0 +1,545 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Property tests of checkout: pairs of random trees are checked out in a
+//! temporary directory with various options, and the working copy compared
+//! with the target tree, content and file types included.
+//!
+//! Cases are generated from a seed, printed on failure along with the
+//! smallest failing case found. The same cases run every time, unless
+//! `CHECKOUT_FUZZ_SEED` is set, e.g. to the printed seed to run them again,
+//! and `CHECKOUT_FUZZ_CASES` to run more of them.
+
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+use futures::stream;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use manifest::DiffEntry;
+use manifest::FileMetadata;
+use manifest::FileType;
+use manifest_tree::testutil::make_tree_manifest_from_meta;
+use manifest_tree::testutil::TestStore;
+use manifest_tree::Diff;
+use minibytes::Bytes;
+use parking_lot::Mutex;
+use pathmatcher::AlwaysMatcher;
+use storemodel::ReadFileContents;
+use types::HgId;
+use types::Key;
+use types::RepoPathBuf;
+use vfs::VFS;
+
+use crate::type_to_flag;
+use crate::ActionMap;
+use crate::CaseNormalization;
+use crate::Checkout;
+use crate::CheckoutError;
+use crate::CheckoutPlan;
+use crate::DirBatching;
+
+/// Seed of the first case, so that runs are reproducible unless
+/// `CHECKOUT_FUZZ_SEED` asks for other cases.
+const DEFAULT_SEED: u64 = 0x5eed;
+const DEFAULT_CASES: u64 = 32;
+
+/// Path components of generated trees.
+const NAMES: &[&str] = &["a", "b", "c"];
+/// Path components of generated trees with names differing only by case.
+const CASE_NAMES: &[&str] = &["a", "b", "c", "A", "B"];
+/// Number of distinct file contents, small so that files share content.
+const CONTENTS: usize = 4;
+
+type Tree = BTreeMap<RepoPathBuf, FileMetadata>;
+
+/// SplitMix64, so that cases only depend on their seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct GenOptions {
+    /// Generate names differing only by case.
+    case_collisions: bool,
+}
+
+/// A checkout from `source` to `target`.
+#[derive(Clone, Debug)]
+struct Case {
+    source: Tree,
+    target: Tree,
+}
+
+impl Case {
+    fn generate(seed: u64, options: GenOptions) -> Self {
+        let mut rng = Rng(seed);
+        let names = if options.case_collisions {
+            CASE_NAMES
+        } else {
+            NAMES
+        };
+        let source = gen_tree(&mut rng, names);
+        let target = gen_target(&mut rng, names, &source);
+        Self { source, target }
+    }
+
+    fn diff(&self) -> Result<Vec<DiffEntry>> {
+        let store = Arc::new(TestStore::new());
+        let matcher = AlwaysMatcher::new();
+        let left = make_tree_manifest_from_meta(store.clone(), self.source.clone());
+        let right = make_tree_manifest_from_meta(store, self.target.clone());
+        let diff = Diff::new(&left, &right, &matcher)?;
+        diff.collect()
+    }
+
+    fn plan(&self, checkout: Checkout) -> Result<CheckoutPlan> {
+        let map = ActionMap::from_diff(self.diff()?.into_iter().map(Ok))?;
+        Ok(checkout.plan_action_map(map))
+    }
+
+    /// The cases with one file less in one of the trees.
+    fn shrink(&self) -> Vec<Case> {
+        let without = |tree: &Tree, path: &RepoPathBuf| {
+            let mut tree = tree.clone();
+            tree.remove(path);
+            tree
+        };
+        let smaller_sources = self.source.keys().map(|path| Case {
+            source: without(&self.source, path),
+            target: self.target.clone(),
+        });
+        let smaller_targets = self.target.keys().map(|path| Case {
+            source: self.source.clone(),
+            target: without(&self.target, path),
+        });
+        smaller_sources.chain(smaller_targets).collect()
+    }
+
+    /// The trees, the diff and the plan of the case.
+    fn describe(&self) -> Result<String> {
+        let tempdir = tempfile::tempdir()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        let plan = self.plan(Checkout::default_config(vfs))?;
+        let mut description = self.to_string();
+        description.push_str("diff:\n");
+        for entry in self.diff()? {
+            description.push_str(&format!("  {:?}\n", entry));
+        }
+        description.push_str(&format!("plan:\n{}", plan));
+        Ok(description)
+    }
+}
+
+impl fmt::Display for Case {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, tree) in [("source", &self.source), ("target", &self.target)] {
+            writeln!(f, "{}:", name)?;
+            for (path, meta) in tree {
+                writeln!(
+                    f,
+                    "  {} {:?} {}",
+                    path,
+                    meta.file_type,
+                    meta.hgid.as_ref()[0]
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn gen_path(rng: &mut Rng, names: &[&str]) -> RepoPathBuf {
+    let depth = 1 + rng.below(3);
+    let components: Vec<_> = (0..depth).map(|_| names[rng.below(names.len())]).collect();
+    RepoPathBuf::from_string(components.join("/")).unwrap()
+}
+
+fn gen_meta(rng: &mut Rng) -> FileMetadata {
+    let hgid = content_id(1 + rng.below(CONTENTS) as u8);
+    match rng.below(4) {
+        0 => FileMetadata::executable(hgid),
+        1 => FileMetadata::symlink(hgid),
+        _ => FileMetadata::regular(hgid),
+    }
+}
+
+/// Adds `path` to `tree`, unless it is a directory of the tree or one of
+/// its parents is a file.
+fn insert(tree: &mut Tree, path: RepoPathBuf, meta: FileMetadata) {
+    let is_dir = tree
+        .keys()
+        .any(|file| file.parents().any(|dir| dir == path.as_repo_path()));
+    let under_file = path.parents().any(|dir| tree.contains_key(dir));
+    if !is_dir && !under_file {
+        tree.insert(path, meta);
+    }
+}
+
+fn gen_tree(rng: &mut Rng, names: &[&str]) -> Tree {
+    let mut tree = Tree::new();
+    for _ in 0..1 + rng.below(8) {
+        let path = gen_path(rng, names);
+        let meta = gen_meta(rng);
+        insert(&mut tree, path, meta);
+    }
+    tree
+}
+
+/// A tree with some of the files of `source` changed, removed, or replaced
+/// by a directory, and some new files, possibly replacing directories.
+fn gen_target(rng: &mut Rng, names: &[&str], source: &Tree) -> Tree {
+    let mut target = Tree::new();
+    for (path, meta) in source {
+        match rng.below(6) {
+            0 => {}
+            1 => {
+                let meta = gen_meta(rng);
+                insert(&mut target, path.clone(), meta);
+            }
+            2 => {
+                let name = names[rng.below(names.len())];
+                let child = RepoPathBuf::from_string(format!("{}/{}", path, name)).unwrap();
+                let meta = gen_meta(rng);
+                insert(&mut target, child, meta);
+            }
+            _ => insert(&mut target, path.clone(), *meta),
+        }
+    }
+    for _ in 0..rng.below(4) {
+        let path = gen_path(rng, names);
+        let meta = gen_meta(rng);
+        insert(&mut target, path, meta);
+    }
+    target
+}
+
+fn content_id(n: u8) -> HgId {
+    let mut id = HgId::default().into_byte_array();
+    id[0] = n;
+    HgId::from_byte_array(id)
+}
+
+fn content(hgid: &HgId) -> String {
+    hgid.to_string()
+}
+
+/// Serves the content of `content_id`s, failing the first fetch of every
+/// other one if `flaky`.
+#[derive(Default)]
+struct FuzzStore {
+    flaky: bool,
+    fetched: Mutex<HashSet<Key>>,
+}
+
+#[async_trait::async_trait]
+impl ReadFileContents for FuzzStore {
+    type Error = anyhow::Error;
+
+    async fn read_file_contents(&self, keys: Vec<Key>) -> BoxStream<Result<(Bytes, Key)>> {
+        let mut fetched = self.fetched.lock();
+        let results: Vec<_> = keys
+            .into_iter()
+            .map(|key| {
+                let first = fetched.insert(key.clone());
+                if self.flaky && first && key.hgid.as_ref()[0] % 2 == 0 {
+                    Err(anyhow!("transient failure").context(key))
+                } else {
+                    Ok((content(&key.hgid).into_bytes().into(), key))
+                }
+            })
+            .collect();
+        stream::iter(results).boxed()
+    }
+
+    async fn read_rename_metadata(
+        &self,
+        _keys: Vec<Key>,
+    ) -> BoxStream<Result<(Key, Option<Key>), Self::Error>> {
+        stream::empty().boxed()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ApplyOptions {
+    concurrency: usize,
+    dir_batching: bool,
+    /// Fail the first fetch of some keys, which the checkout retries.
+    flaky_store: bool,
+    /// Stage the content first, fetching shared content once, then rename
+    /// it into place.
+    staged: bool,
+    case_normalization: CaseNormalization,
+}
+
+impl ApplyOptions {
+    fn matrix(case_normalization: CaseNormalization) -> Vec<Run> {
+        let mut runs = Vec::new();
+        for concurrency in [1, 16] {
+            for dir_batching in [false, true] {
+                for flaky_store in [false, true] {
+                    for staged in [false, true] {
+                        runs.push(Run::Apply(ApplyOptions {
+                            concurrency,
+                            dir_batching,
+                            flaky_store,
+                            staged,
+                            case_normalization,
+                        }));
+                    }
+                }
+            }
+        }
+        runs
+    }
+
+    fn checkout(&self, vfs: VFS) -> Checkout {
+        let dir_batching = self.dir_batching.then(DirBatching::default);
+        Checkout::default_config(vfs)
+            .with_concurrency(self.concurrency)
+            .with_dir_batching(dir_batching)
+            .with_case_normalization(self.case_normalization)
+    }
+}
+
+/// How a case is checked out.
+#[derive(Clone, Copy, Debug)]
+enum Run {
+    Apply(ApplyOptions),
+    /// Abort the checkout after the given number of write batches, then
+    /// check out again, resuming from the progress file.
+    Resume {
+        interrupt_after: usize,
+    },
+}
+
+/// The files of a working copy.
+#[derive(Debug, PartialEq, Eq)]
+enum Entry {
+    File { content: String, exec: bool },
+    Symlink(String),
+}
+
+impl Entry {
+    fn expected(meta: &FileMetadata) -> Self {
+        let content = content(&meta.hgid);
+        match meta.file_type {
+            FileType::Regular => Entry::File {
+                content,
+                exec: false,
+            },
+            FileType::Executable => Entry::File {
+                content,
+                exec: true,
+            },
+            FileType::Symlink => Entry::Symlink(content),
+            FileType::GitSubmodule => unreachable!("not generated"),
+        }
+    }
+}
+
+/// The files under `dir`, by path relative to the working copy. Fails on
+/// empty directories, which checkout removes.
+fn snapshot(dir: &Path, prefix: &str, files: &mut BTreeMap<String, Entry>) -> Result<()> {
+    let mut empty = true;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        empty = false;
+        let path = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            let target = fs::read_link(entry.path())?;
+            files.insert(path, Entry::Symlink(target.to_string_lossy().into_owned()));
+        } else if file_type.is_dir() {
+            snapshot(&entry.path(), &format!("{}/", path), files)?;
+        } else {
+            let exec = entry.metadata()?.permissions().mode() & 0o111 != 0;
+            let content = String::from_utf8_lossy(&fs::read(entry.path())?).into_owned();
+            files.insert(path, Entry::File { content, exec });
+        }
+    }
+    ensure!(!empty || prefix.is_empty(), "Empty directory {}", prefix);
+    Ok(())
+}
+
+/// Fails unless the working copy at `root` has exactly the files of
+/// `target`.
+fn compare(root: &Path, target: &Tree) -> Result<()> {
+    let mut actual = BTreeMap::new();
+    snapshot(root, "", &mut actual)?;
+    for (path, meta) in target {
+        let expected = Entry::expected(meta);
+        match actual.remove(&path.to_string()) {
+            Some(entry) if entry == expected => {}
+            Some(entry) => bail!("{} is {:?}, expected {:?}", path, entry, expected),
+            None => bail!("{} is missing", path),
+        }
+    }
+    if let Some(path) = actual.keys().next() {
+        bail!("Unexpected file {}", path);
+    }
+    Ok(())
+}
+
+async fn run(case: &Case, run: Run) -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let root = tempdir.path().join("workingdir");
+    fs::create_dir(&root)?;
+    let vfs = VFS::new(root.clone())?;
+    for (path, meta) in &case.source {
+        let data = content(&meta.hgid);
+        vfs.write(path, data.as_bytes(), type_to_flag(&meta.file_type))?;
+    }
+
+    match run {
+        Run::Apply(options) => {
+            let store = FuzzStore {
+                flaky: options.flaky_store,
+                ..Default::default()
+            };
+            let plan = case.plan(options.checkout(vfs))?;
+            if options.staged {
+                let staging_dir = tempdir.path().join("staging");
+                let staged = plan
+                    .stage(&store, &staging_dir)
+                    .await
+                    .context("Staging failed")?;
+                staged.commit().await.context("Staged checkout failed")?;
+            } else {
+                plan.apply_store(&store).await.context("Checkout failed")?;
+            }
+        }
+        Run::Resume { interrupt_after } => {
+            let progress = tempdir.path().join("updateprogress");
+            let checkout = Checkout::default_config(vfs);
+            let mut plan = case.plan(checkout.clone())?;
+            plan.add_progress(&progress)?;
+
+            let actions = match interrupt_after {
+                0 => "return".to_string(),
+                n => format!("{}*off->return", n),
+            };
+            let scenario = fail::FailScenario::setup();
+            fail::cfg("checkout-post-progress", &actions).unwrap();
+            let result = plan.apply_store(&FuzzStore::default()).await;
+            scenario.teardown();
+            match result {
+                Ok(_) | Err(CheckoutError::Aborted { .. }) => {}
+                Err(e) => return Err(e).context("Interrupted checkout failed"),
+            }
+
+            let mut plan = case.plan(checkout)?;
+            plan.add_progress(&progress)?;
+            plan.apply_store(&FuzzStore::default())
+                .await
+                .context("Resumed checkout failed")?;
+        }
+    }
+
+    compare(&root, &case.target)
+}
+
+/// Removes files from the trees of `case` as long as `run` still fails.
+async fn minimize(mut case: Case, run: Run) -> Case {
+    loop {
+        let mut smaller = None;
+        for candidate in case.shrink() {
+            if self::run(&candidate, run).await.is_err() {
+                smaller = Some(candidate);
+                break;
+            }
+        }
+        match smaller {
+            Some(smaller) => case = smaller,
+            None => return case,
+        }
+    }
+}
+
+fn env_u64(name: &str) -> Option<u64> {
+    std::env::var(name).ok().and_then(|v| v.parse().ok())
+}
+
+/// Checks out generated cases with each of `runs`.
+async fn fuzz(options: GenOptions, runs: &[Run]) -> Result<()> {
+    let first_seed = env_u64("CHECKOUT_FUZZ_SEED").unwrap_or(DEFAULT_SEED);
+    let cases = env_u64("CHECKOUT_FUZZ_CASES").unwrap_or(DEFAULT_CASES);
+    for i in 0..cases {
+        let seed = first_seed.wrapping_add(i);
+        let case = Case::generate(seed, options);
+        for &r in runs {
+            if let Err(e) = run(&case, r).await {
+                let minimized = minimize(case.clone(), r).await;
+                eprintln!("===");
+                eprintln!("Checkout {:?} failed: {:?}", r, e);
+                eprintln!("Case of seed {} (first seed {})", seed, first_seed);
+                eprintln!("Minimized case:");
+                match minimized.describe() {
+                    Ok(description) => eprint!("{}", description),
+                    Err(e) => eprintln!("{}(failed to plan: {})", minimized, e),
+                }
+                eprintln!("===");
+                bail!(
+                    "Checkout fuzzing failed, rerun with CHECKOUT_FUZZ_SEED={}",
+                    first_seed
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fuzz_checkout() -> Result<()> {
+    let runs = ApplyOptions::matrix(CaseNormalization::Auto);
+    fuzz(GenOptions::default(), &runs).await
+}
+
+#[tokio::test]
+async fn test_fuzz_case_collisions() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    if !VFS::new(tempdir.path().to_path_buf())?.case_sensitive() {
+        return Ok(());
+    }
+    let options = GenOptions {
+        case_collisions: true,
+    };
+    let runs = ApplyOptions::matrix(CaseNormalization::Never);
+    fuzz(options, &runs).await
+}
+
+#[tokio::test]
+async fn test_fuzz_resume() -> Result<()> {
+    let runs = [
+        Run::Resume { interrupt_after: 0 },
+        Run::Resume { interrupt_after: 1 },
+    ];
+    fuzz(GenOptions::default(), &runs).await
+}
@@ -0,0 +1,190 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use types::RepoPath;
+use types::RepoPathBuf;
+
+/// Paths whose content should be written before anything else, e.g. files
+/// open in an editor. Each hint matches itself and everything below it.
+#[derive(Clone, Default)]
+pub(crate) struct PriorityPaths {
+    /// Sorted and deduplicated.
+    hints: Vec<RepoPathBuf>,
+}
+
+impl PriorityPaths {
+    pub(crate) fn new(mut hints: Vec<RepoPathBuf>) -> Self {
+        hints.sort();
+        hints.dedup();
+        Self { hints }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hints.is_empty()
+    }
+
+    /// Whether `path` or one of its parent directories is a hint.
+    pub(crate) fn matches(&self, path: &RepoPath) -> bool {
+        path.parents().skip(1).chain(Some(path)).any(|p| {
+            self.hints
+                .binary_search_by(|h| h.as_repo_path().cmp(p))
+                .is_ok()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::collections::BTreeSet;
+    use std::path::Path;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use futures::stream;
+    use futures::stream::BoxStream;
+    use futures::StreamExt;
+    use manifest::FileMetadata;
+    use minibytes::Bytes;
+    use parking_lot::Mutex;
+    use storemodel::ReadFileContents;
+    use tempfile::TempDir;
+    use types::HgId;
+    use types::Key;
+    use vfs::VFS;
+    use walkdir::WalkDir;
+
+    use super::*;
+    use crate::actions::UpdateAction;
+    use crate::Action;
+    use crate::ActionMap;
+    use crate::Checkout;
+
+    /// Records, for every fetch, the requested paths and the files that were
+    /// already on disk at the time.
+    struct RecordingStore {
+        root: PathBuf,
+        delay: Duration,
+        fetches: Mutex<Vec<(BTreeSet<String>, BTreeSet<String>)>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ReadFileContents for RecordingStore {
+        type Error = anyhow::Error;
+
+        async fn read_file_contents(&self, keys: Vec<Key>) -> BoxStream<Result<(Bytes, Key)>> {
+            let requested = keys.iter().map(|k| k.path.to_string()).collect();
+            let on_disk = read_tree(&self.root).into_keys().collect();
+            self.fetches.lock().push((requested, on_disk));
+
+            let delay = self.delay;
+            stream::iter(keys)
+                .then(move |key| async move {
+                    tokio::time::sleep(delay).await;
+                    Ok((key.hgid.to_string().into_bytes().into(), key))
+                })
+                .boxed()
+        }
+
+        async fn read_rename_metadata(
+            &self,
+            _keys: Vec<Key>,
+        ) -> BoxStream<Result<(Key, Option<Key>), Self::Error>> {
+            stream::empty().boxed()
+        }
+    }
+
+    fn rp(p: &str) -> RepoPathBuf {
+        RepoPathBuf::from_string(p.to_string()).unwrap()
+    }
+
+    fn read_tree(root: &Path) -> BTreeMap<String, Vec<u8>> {
+        WalkDir::new(root)
+            .into_iter()
+            .map(|e| e.unwrap())
+            .filter(|e| e.file_type().is_file())
+            .map(|e| {
+                let path = e.path().strip_prefix(root).unwrap();
+                let path = path.to_str().unwrap().replace('\\', "/");
+                (path, std::fs::read(e.path()).unwrap())
+            })
+            .collect()
+    }
+
+    const FILES: &[&str] = &[
+        "README",
+        "docs/x.md",
+        "src/a.rs",
+        "src/b.rs",
+        "src-old/c.rs",
+    ];
+
+    async fn checkout(priority_paths: Vec<RepoPathBuf>) -> Result<(TempDir, RecordingStore)> {
+        let tempdir = TempDir::new()?;
+        let mut map = ActionMap::empty();
+        for (i, path) in FILES.iter().enumerate() {
+            let meta = FileMetadata::regular(HgId::from_byte_array([i as u8 + 1; HgId::len()]));
+            map.insert(rp(path), Action::Update(UpdateAction::new(None, meta)));
+        }
+        let store = RecordingStore {
+            root: tempdir.path().to_path_buf(),
+            delay: Duration::from_millis(10),
+            fetches: Mutex::new(Vec::new()),
+        };
+
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        let plan = Checkout::default_config(vfs)
+            .with_priority_paths(priority_paths.clone())
+            .plan_action_map(map);
+        let stats = plan.apply_store(&store).await?;
+        assert_eq!(
+            stats.time_to_priority_complete().is_some(),
+            !priority_paths.is_empty()
+        );
+        Ok((tempdir, store))
+    }
+
+    #[test]
+    fn test_matches() {
+        let paths = PriorityPaths::new(vec![rp("a"), rp("a-b"), rp("c/d.txt"), rp("a")]);
+        assert!(paths.matches(&rp("a")));
+        assert!(paths.matches(&rp("a/x")));
+        assert!(paths.matches(&rp("a/x/y")));
+        assert!(paths.matches(&rp("a-b/x")));
+        assert!(paths.matches(&rp("c/d.txt")));
+        assert!(!paths.matches(&rp("ab")));
+        assert!(!paths.matches(&rp("c")));
+        assert!(!paths.matches(&rp("c/e.txt")));
+        assert!(!PriorityPaths::default().matches(&rp("a")));
+    }
+
+    #[tokio::test]
+    async fn test_priority_first() -> Result<()> {
+        let (tempdir, store) = checkout(vec![rp("src"), rp("README")]).await?;
+
+        let priority: BTreeSet<_> = ["README", "src/a.rs", "src/b.rs"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let fetches = store.fetches.lock();
+        assert_eq!(fetches.len(), 2);
+        let (requested, on_disk) = &fetches[0];
+        assert_eq!(requested, &priority);
+        assert!(on_disk.is_empty());
+        // Everything else is only fetched, and so written, once all priority
+        // paths are on disk.
+        let (requested, on_disk) = &fetches[1];
+        assert!(requested.is_disjoint(&priority));
+        assert_eq!(requested.len(), FILES.len() - priority.len());
+        assert_eq!(on_disk, &priority);
+
+        let (expected, _) = checkout(vec![]).await?;
+        assert_eq!(read_tree(tempdir.path()), read_tree(expected.path()));
+        Ok(())
+    }
+}
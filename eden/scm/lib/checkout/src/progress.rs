@@ -0,0 +1,332 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Shows the progress of a checkout with the progress bars rendered by the
+//! CLI.
+//!
+//! [`attach`] registers a "files" bar, counting removed, written and
+//! exec-flag-updated files, and a "bytes" bar counting written bytes. The
+//! bars are set from the counters of `CheckoutStats` by
+//! [`CheckoutProgress::update`] or [`CheckoutProgress::async_update`], so the
+//! checkout itself does no extra work per file.
+
+use std::future::Future;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::time::Duration;
+
+use progress_model::ProgressBar;
+use progress_model::Registry;
+use storemodel::ReadFileContents;
+
+use crate::CheckoutError;
+use crate::CheckoutPlan;
+use crate::CheckoutStats;
+use crate::PlanSummary;
+
+/// Where [`attach`] registers its bars.
+pub trait ProgressRegistry {
+    fn register_progress_bar(&self, bar: &Arc<ProgressBar>);
+}
+
+impl ProgressRegistry for Registry {
+    fn register_progress_bar(&self, bar: &Arc<ProgressBar>) {
+        Registry::register_progress_bar(self, bar)
+    }
+}
+
+/// What the bars count up to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProgressTotals {
+    /// Files removed, written, or with their exec flag updated.
+    pub actions: usize,
+    /// Bytes to write, if known. Otherwise the bytes bar has no total.
+    pub bytes: Option<u64>,
+}
+
+impl From<&PlanSummary> for ProgressTotals {
+    fn from(summary: &PlanSummary) -> Self {
+        Self {
+            actions: summary.remove + summary.update_content + summary.update_meta,
+            bytes: None,
+        }
+    }
+}
+
+struct Bars {
+    stats: Weak<CheckoutStats>,
+    files: Arc<ProgressBar>,
+    bytes: Arc<ProgressBar>,
+    /// Set once the files bar shows the priority paths were written.
+    priority_reported: AtomicBool,
+}
+
+impl Bars {
+    fn update(&self) {
+        let stats = match self.stats.upgrade() {
+            Some(stats) => stats,
+            None => return,
+        };
+        // The counters only grow, so the bars never go back.
+        let files = stats.removed.load(Ordering::Relaxed)
+            + stats.updated.load(Ordering::Relaxed)
+            + stats.meta_updated.load(Ordering::Relaxed);
+        self.files.set_position(files as u64);
+        self.bytes
+            .set_position(stats.written_bytes.load(Ordering::Relaxed) as u64);
+        if let Some(elapsed) = stats.time_to_priority_complete() {
+            if !self.priority_reported.swap(true, Ordering::Relaxed) {
+                self.files
+                    .set_message(format!("priority paths written in {:.1?}", elapsed));
+            }
+        }
+    }
+}
+
+/// The bars of a checkout, see [`attach`]. Dropping it before
+/// [`CheckoutProgress::finish`], e.g. when the checkout is cancelled,
+/// abandons the bars.
+pub struct CheckoutProgress {
+    bars: Option<Arc<Bars>>,
+}
+
+/// Register the bars of a checkout updating `stats` with `registry`.
+pub fn attach(
+    stats: &Arc<CheckoutStats>,
+    totals: ProgressTotals,
+    registry: &dyn ProgressRegistry,
+) -> CheckoutProgress {
+    let files = ProgressBar::new("Updating", totals.actions as u64, "files");
+    let bytes = ProgressBar::new("Writing", totals.bytes.unwrap_or(0), "bytes");
+    registry.register_progress_bar(&files);
+    registry.register_progress_bar(&bytes);
+    CheckoutProgress {
+        bars: Some(Arc::new(Bars {
+            stats: Arc::downgrade(stats),
+            files,
+            bytes,
+            priority_reported: AtomicBool::new(false),
+        })),
+    }
+}
+
+impl CheckoutProgress {
+    /// Set the bars from the current counters.
+    pub fn update(&self) {
+        if let Some(bars) = &self.bars {
+            bars.update();
+        }
+    }
+
+    /// Update the bars every `interval`. The future ends once the bars are
+    /// finished or abandoned.
+    pub fn async_update(&self, interval: Duration) -> impl Future<Output = ()> + Send + 'static {
+        let bars = self.bars.as_ref().map_or_else(Weak::new, Arc::downgrade);
+        async move {
+            while let Some(bars) = bars.upgrade() {
+                bars.update();
+                drop(bars);
+                tokio::time::sleep(interval).await;
+            }
+        }
+    }
+
+    /// Finalize the bars with the result of the checkout. On success the
+    /// files bar is complete, on failure the bars keep their last position.
+    pub fn finish<T>(mut self, result: &Result<T, CheckoutError>) {
+        if let Some(bars) = self.bars.take() {
+            bars.update();
+            match result {
+                Ok(_) => {
+                    let (_, total) = bars.files.position_total();
+                    bars.files.set_position(total);
+                    let (written, total) = bars.bytes.position_total();
+                    if total == 0 {
+                        bars.bytes.set_total(written);
+                    }
+                }
+                Err(e) => bars.files.set_message(format!("failed: {}", e)),
+            }
+        }
+    }
+}
+
+impl Drop for CheckoutProgress {
+    fn drop(&mut self) {
+        if let Some(bars) = self.bars.take() {
+            bars.files.set_message("cancelled".to_string());
+        }
+    }
+}
+
+impl CheckoutPlan {
+    /// Same as `apply_store`, but shows progress bars registered with
+    /// `registry`, updated every `interval`.
+    pub async fn apply_store_with_progress(
+        &self,
+        store: &dyn ReadFileContents<Error = anyhow::Error>,
+        registry: &dyn ProgressRegistry,
+        interval: Duration,
+    ) -> Result<CheckoutStats, CheckoutError> {
+        let stats = Arc::new(CheckoutStats::new(&self.checkout));
+        let progress = attach(&stats, ProgressTotals::from(&self.summary()), registry);
+        let updater = tokio::spawn(progress.async_update(interval));
+
+        let result = self.apply_store_with_stats(store, &stats).await;
+        progress.finish(&result);
+        // The updater only holds weak references, and ends once finished.
+        let _ = updater.await;
+
+        result.map(|()| {
+            Arc::try_unwrap(stats)
+                .ok()
+                .expect("progress bars should only hold weak references to stats")
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use parking_lot::Mutex;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeRegistry {
+        bars: Mutex<Vec<Arc<ProgressBar>>>,
+    }
+
+    impl ProgressRegistry for FakeRegistry {
+        fn register_progress_bar(&self, bar: &Arc<ProgressBar>) {
+            self.bars.lock().push(bar.clone());
+        }
+    }
+
+    impl FakeRegistry {
+        fn bars(&self) -> Vec<(String, u64, u64, Option<String>)> {
+            self.bars
+                .lock()
+                .iter()
+                .map(|bar| {
+                    let (pos, total) = bar.position_total();
+                    let message = bar.message().map(|m| m.to_string());
+                    (bar.topic().to_string(), pos, total, message)
+                })
+                .collect()
+        }
+    }
+
+    /// Updates the counters like a checkout writing `files` files of
+    /// `size` bytes, checking the bars after each file.
+    fn fake_checkout(
+        stats: &CheckoutStats,
+        progress: &CheckoutProgress,
+        registry: &FakeRegistry,
+        files: usize,
+        size: usize,
+    ) {
+        let mut last = (0, 0);
+        for _ in 0..files {
+            stats.updated.fetch_add(1, Ordering::Relaxed);
+            stats.written_bytes.fetch_add(size, Ordering::Relaxed);
+            progress.update();
+            let bars = registry.bars();
+            let current = (bars[0].1, bars[1].1);
+            assert!(current.0 > last.0 && current.1 > last.1);
+            last = current;
+        }
+    }
+
+    #[test]
+    fn test_progress_success() {
+        let stats = Arc::new(CheckoutStats::default());
+        let registry = FakeRegistry::default();
+        let totals = ProgressTotals {
+            actions: 5,
+            bytes: None,
+        };
+        let progress = attach(&stats, totals, &registry);
+        assert_eq!(
+            registry.bars(),
+            vec![
+                ("Updating".to_string(), 0, 5, None),
+                ("Writing".to_string(), 0, 0, None),
+            ]
+        );
+
+        fake_checkout(&stats, &progress, &registry, 4, 10);
+        stats.removed.fetch_add(1, Ordering::Relaxed);
+        *stats.priority_complete.lock() = Some(Duration::from_millis(100));
+        progress.update();
+        let bars = registry.bars();
+        assert_eq!(bars[0].1, 5);
+        assert_eq!(
+            bars[0].3.as_deref(),
+            Some("priority paths written in 100.0ms")
+        );
+
+        progress.finish(&Ok(()));
+        let bars = registry.bars();
+        assert_eq!(bars[0].1, 5);
+        assert_eq!(bars[1], ("Writing".to_string(), 40, 40, None));
+    }
+
+    #[test]
+    fn test_progress_failure() {
+        let stats = Arc::new(CheckoutStats::default());
+        let registry = FakeRegistry::default();
+        let totals = ProgressTotals {
+            actions: 5,
+            bytes: Some(50),
+        };
+        let progress = attach(&stats, totals, &registry);
+        fake_checkout(&stats, &progress, &registry, 2, 10);
+
+        let result: Result<(), _> = Err(CheckoutError::PlanInconsistent {
+            detail: "bad plan".to_string(),
+        });
+        progress.finish(&result);
+        assert_eq!(
+            registry.bars(),
+            vec![
+                (
+                    "Updating".to_string(),
+                    2,
+                    5,
+                    Some("failed: Checkout plan is inconsistent: bad plan".to_string())
+                ),
+                ("Writing".to_string(), 20, 50, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_progress_cancelled() {
+        let stats = Arc::new(CheckoutStats::default());
+        let registry = FakeRegistry::default();
+        let progress = attach(&stats, ProgressTotals::default(), &registry);
+        drop(progress);
+        assert_eq!(registry.bars()[0].3.as_deref(), Some("cancelled"));
+    }
+
+    #[tokio::test]
+    async fn test_async_update() {
+        let stats = Arc::new(CheckoutStats::default());
+        let registry = FakeRegistry::default();
+        let progress = attach(&stats, ProgressTotals::default(), &registry);
+        let updater = tokio::spawn(progress.async_update(Duration::from_millis(1)));
+
+        stats.updated.fetch_add(3, Ordering::Relaxed);
+        while registry.bars()[0].1 < 3 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        progress.finish(&Ok(()));
+        updater.await.unwrap();
+    }
+}
@@ -0,0 +1,671 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Reports of failed checkouts, for users to attach to support requests.
+//! See `CheckoutPlan::failure_report`.
+//!
+//! A `FailureBundle` never includes file contents, and paths are cut after
+//! `FailureBundleOptions::path_depth` components, in error messages too.
+
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use serde::Serialize;
+use types::HgId;
+use types::RepoPathBuf;
+
+use crate::AppliedStats;
+use crate::CaseNormalization;
+use crate::Checkout;
+use crate::CheckoutError;
+use crate::CheckoutPlan;
+use crate::CheckoutStats;
+use crate::DirBatching;
+use crate::FailureClass;
+use crate::MemoryLimits;
+use crate::PlanSummary;
+
+/// A failed checkout, with what is needed to investigate it.
+#[derive(Debug)]
+pub struct CheckoutFailureReport {
+    error: CheckoutError,
+    plan: PlanSummary,
+    applied: AppliedStats,
+    written_bytes: usize,
+    elapsed: Duration,
+    options: EffectiveOptions,
+    environment: FailureEnvironment,
+    root: PathBuf,
+    bundle_options: FailureBundleOptions,
+}
+
+/// How a `FailureBundle` is made, see `CheckoutFailureReport::with_bundle_options`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FailureBundleOptions {
+    /// Components of paths kept, the others being replaced by `...`.
+    pub path_depth: usize,
+    /// Failed actions are left out until the bundle, as JSON, fits in this
+    /// many bytes. The rest of the bundle is always kept.
+    pub max_bytes: usize,
+}
+
+impl Default for FailureBundleOptions {
+    fn default() -> Self {
+        Self {
+            path_depth: 2,
+            max_bytes: 1 << 20,
+        }
+    }
+}
+
+/// The facts about a failed checkout that support asks for, see
+/// `CheckoutFailureReport::failure_bundle`.
+#[derive(Clone, Debug, Serialize)]
+pub struct FailureBundle {
+    pub class: FailureClass,
+    /// The error and its causes, outermost first.
+    pub causes: Vec<FailureCause>,
+    /// The actions the plan had to apply.
+    pub plan: PlanSummary,
+    /// The actions applied before the failure.
+    pub applied: AppliedStats,
+    pub failed_actions: Vec<FailedAction>,
+    /// Failed actions left out to fit in `FailureBundleOptions::max_bytes`.
+    pub omitted_actions: usize,
+    pub environment: FailureEnvironment,
+    pub options: EffectiveOptions,
+    pub timing: FailureTiming,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FailureCause {
+    pub message: String,
+    /// Set if the cause is an `io::Error`.
+    pub io_kind: Option<String>,
+    pub os_error: Option<i32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailedActionKind {
+    Fetch,
+    Write,
+    Remove,
+    UpdateExec,
+    Stat,
+    Xattr,
+    /// A path the plan can't apply to this working copy.
+    Conflict,
+}
+
+/// An action of the plan that failed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FailedAction {
+    pub kind: FailedActionKind,
+    pub path: Option<String>,
+    /// The content fetched, for `FailedActionKind::Fetch`.
+    pub hgid: Option<String>,
+    pub io_kind: Option<String>,
+    pub os_error: Option<i32>,
+}
+
+/// The working copy a checkout failed on, as found at the failure.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FailureEnvironment {
+    /// `std::env::consts::OS`.
+    pub platform: String,
+    /// Filesystem type of the working copy root, if it could be told.
+    pub filesystem: Option<String>,
+    pub free_bytes: Option<u64>,
+    pub case_sensitive: bool,
+}
+
+/// The options of a `Checkout`, as set with its `with_*` methods.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct EffectiveOptions {
+    pub concurrency: usize,
+    pub case_normalization: CaseNormalization,
+    pub priority_paths: bool,
+    pub file_metadata: bool,
+    pub reserved_names: bool,
+    pub fetch_retries: usize,
+    pub preserved_xattrs: bool,
+    pub hooks: bool,
+    pub hook_errors_as_warnings: bool,
+    pub memory_limits: MemoryLimits,
+    pub space_check: bool,
+    pub dir_batching: Option<DirBatching>,
+    pub blocking_spawner: bool,
+    pub deterministic: bool,
+    pub notifier: bool,
+    pub content_filter: bool,
+}
+
+/// How far and fast a checkout went before failing.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FailureTiming {
+    pub elapsed_ms: u64,
+    pub bytes_written: usize,
+    /// Actions applied per second.
+    pub actions_per_sec: f64,
+    pub bytes_per_sec: f64,
+}
+
+impl CheckoutPlan {
+    /// A report of `error`, which failed applying this plan after `elapsed`,
+    /// with `stats` updated until then. The environment is looked at now.
+    pub fn failure_report(
+        &self,
+        error: CheckoutError,
+        stats: &CheckoutStats,
+        elapsed: Duration,
+    ) -> CheckoutFailureReport {
+        let vfs = &self.checkout.vfs;
+        let environment = FailureEnvironment {
+            platform: std::env::consts::OS.to_string(),
+            filesystem: fsinfo::fstype(vfs.root()).ok().map(|t| t.to_string()),
+            free_bytes: fs2::available_space(vfs.root()).ok(),
+            case_sensitive: vfs.case_sensitive(),
+        };
+        CheckoutFailureReport {
+            error,
+            plan: self.summary(),
+            applied: stats.applied(),
+            written_bytes: stats
+                .written_bytes
+                .load(std::sync::atomic::Ordering::Relaxed),
+            elapsed,
+            options: EffectiveOptions::new(&self.checkout),
+            environment,
+            root: vfs.root().to_path_buf(),
+            bundle_options: FailureBundleOptions::default(),
+        }
+    }
+}
+
+impl EffectiveOptions {
+    fn new(checkout: &Checkout) -> Self {
+        Self {
+            concurrency: checkout.concurrency,
+            case_normalization: checkout.case_normalization,
+            priority_paths: !checkout.priority_paths.is_empty(),
+            file_metadata: checkout.collect_file_metadata,
+            reserved_names: checkout.allow_reserved_names,
+            fetch_retries: checkout.fetch_retries,
+            preserved_xattrs: checkout.preserve_xattrs.is_some(),
+            hooks: !checkout.hooks.is_empty(),
+            hook_errors_as_warnings: checkout.hooks.warn_only,
+            memory_limits: checkout.memory_limits,
+            space_check: checkout.space_check.is_some(),
+            dir_batching: checkout.dir_batching,
+            blocking_spawner: checkout.spawner.is_some(),
+            deterministic: checkout.deterministic,
+            notifier: checkout.notifier.is_some(),
+            content_filter: checkout.content_filter.is_some(),
+        }
+    }
+}
+
+impl CheckoutFailureReport {
+    pub fn error(&self) -> &CheckoutError {
+        &self.error
+    }
+
+    pub fn into_error(self) -> CheckoutError {
+        self.error
+    }
+
+    pub fn with_bundle_options(mut self, options: FailureBundleOptions) -> Self {
+        self.bundle_options = options;
+        self
+    }
+
+    /// The report, with paths redacted and without file contents, to send
+    /// to support.
+    pub fn failure_bundle(&self) -> FailureBundle {
+        let depth = self.bundle_options.path_depth;
+        let actions = failed_actions(&self.error);
+        let redactor = Redactor::new(&self.root, actions.iter().filter_map(|a| a.path), depth);
+
+        let mut causes = Vec::new();
+        let mut cause: Option<&(dyn Error + 'static)> = Some(&self.error);
+        while let Some(err) = cause {
+            let io_error = err.downcast_ref::<io::Error>();
+            causes.push(FailureCause {
+                message: redactor.redact(&err.to_string()),
+                io_kind: io_error.map(|e| format!("{:?}", e.kind())),
+                os_error: io_error.and_then(|e| e.raw_os_error()),
+            });
+            cause = err.source();
+        }
+
+        let failed_actions = actions
+            .into_iter()
+            .map(|a| FailedAction {
+                kind: a.kind,
+                path: a.path.map(|p| redact_path(p.as_str(), depth)),
+                hgid: a.hgid.map(|id| id.to_hex()),
+                io_kind: a.io_error.map(|e| format!("{:?}", e.kind())),
+                os_error: a.io_error.and_then(|e| e.raw_os_error()),
+            })
+            .collect();
+
+        let secs = self.elapsed.as_secs_f64();
+        let applied = self.applied.removed + self.applied.updated + self.applied.meta_updated;
+        let rate = |n: usize| if secs > 0.0 { n as f64 / secs } else { 0.0 };
+        let mut bundle = FailureBundle {
+            class: self.error.class(),
+            causes,
+            plan: self.plan,
+            applied: self.applied,
+            failed_actions,
+            omitted_actions: 0,
+            environment: self.environment.clone(),
+            options: self.options.clone(),
+            timing: FailureTiming {
+                elapsed_ms: self.elapsed.as_millis() as u64,
+                bytes_written: self.written_bytes,
+                actions_per_sec: rate(applied),
+                bytes_per_sec: rate(self.written_bytes),
+            },
+        };
+        bundle.truncate(self.bundle_options.max_bytes);
+        bundle
+    }
+}
+
+impl FailureBundle {
+    /// Writes the bundle to `path`, as JSON.
+    pub fn write_to(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec(self)?;
+        std::fs::write(path, json)
+            .with_context(|| format!("Failed to write checkout failure bundle to {:?}", path))
+    }
+
+    /// Leaves out failed actions, last first, until the JSON of the bundle
+    /// fits in `max_bytes`.
+    fn truncate(&mut self, max_bytes: usize) {
+        let mut size = json_len(self);
+        while size > max_bytes && !self.failed_actions.is_empty() {
+            let mut excess = (size - max_bytes) as isize;
+            while excess > 0 {
+                match self.failed_actions.pop() {
+                    // With the comma separating it.
+                    Some(action) => excess -= json_len(&action) as isize + 1,
+                    None => break,
+                }
+                self.omitted_actions += 1;
+            }
+            // The count of omissions may have grown longer.
+            size = json_len(self);
+        }
+    }
+}
+
+fn json_len(value: &impl Serialize) -> usize {
+    serde_json::to_vec(value).map_or(0, |json| json.len())
+}
+
+/// A failed action, before redaction.
+struct Failed<'a> {
+    kind: FailedActionKind,
+    path: Option<&'a RepoPathBuf>,
+    hgid: Option<HgId>,
+    io_error: Option<&'a io::Error>,
+}
+
+impl<'a> Failed<'a> {
+    fn new(kind: FailedActionKind, path: &'a RepoPathBuf) -> Self {
+        Self {
+            kind,
+            path: Some(path),
+            hgid: None,
+            io_error: None,
+        }
+    }
+
+    fn caused_by(mut self, source: &'a anyhow::Error) -> Self {
+        self.io_error = source.chain().find_map(|e| e.downcast_ref::<io::Error>());
+        self
+    }
+}
+
+fn failed_actions(error: &CheckoutError) -> Vec<Failed<'_>> {
+    match error {
+        CheckoutError::FetchFailed { key, source } => vec![Failed {
+            kind: FailedActionKind::Fetch,
+            path: key.as_ref().map(|k| &k.path),
+            hgid: key.as_ref().map(|k| k.hgid),
+            io_error: None,
+        }
+        .caused_by(source)],
+        CheckoutError::FetchRetriesExhausted { failures, .. } => failures
+            .iter()
+            .map(|f| Failed {
+                hgid: Some(f.key.hgid),
+                ..Failed::new(FailedActionKind::Fetch, &f.key.path).caused_by(&f.source)
+            })
+            .collect(),
+        CheckoutError::KeyNotFound { key } => vec![Failed {
+            hgid: Some(key.hgid),
+            ..Failed::new(FailedActionKind::Fetch, &key.path)
+        }],
+        CheckoutError::WriteFailed { path, source } => {
+            vec![Failed::new(FailedActionKind::Write, path).caused_by(source)]
+        }
+        CheckoutError::RemoveFailed { path, source } => {
+            vec![Failed::new(FailedActionKind::Remove, path).caused_by(source)]
+        }
+        CheckoutError::MetaUpdateFailed { path, source } => {
+            vec![Failed::new(FailedActionKind::UpdateExec, path).caused_by(source)]
+        }
+        CheckoutError::StatFailed { path, source } => {
+            vec![Failed::new(FailedActionKind::Stat, path).caused_by(source)]
+        }
+        CheckoutError::XattrFailed { path, source } => {
+            vec![Failed::new(FailedActionKind::Xattr, path).caused_by(source)]
+        }
+        CheckoutError::LocallyDeleted { paths } => paths
+            .iter()
+            .map(|p| Failed::new(FailedActionKind::Conflict, p))
+            .collect(),
+        CheckoutError::PathProblems(err) => err
+            .problems
+            .iter()
+            .map(|p| Failed::new(FailedActionKind::Conflict, &p.path))
+            .collect(),
+        CheckoutError::CaseCollision(err) => err
+            .pairs
+            .iter()
+            .flat_map(|(a, b)| {
+                [
+                    Failed::new(FailedActionKind::Conflict, a),
+                    Failed::new(FailedActionKind::Conflict, b),
+                ]
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// `path` with the components after the first `depth` replaced by `...`.
+fn redact_path(path: &str, depth: usize) -> String {
+    let components: Vec<_> = path.split('/').collect();
+    if components.len() <= depth {
+        return path.to_string();
+    }
+    let mut kept = components[..depth].to_vec();
+    kept.push("...");
+    kept.join("/")
+}
+
+/// Redacts the paths of the failed actions, and the working copy root, in
+/// error messages.
+struct Redactor {
+    /// Longest first, so that a path is not partly replaced as a prefix of
+    /// another.
+    replacements: Vec<(String, String)>,
+}
+
+impl Redactor {
+    fn new<'a>(root: &Path, paths: impl Iterator<Item = &'a RepoPathBuf>, depth: usize) -> Self {
+        let mut replacements: Vec<_> = paths
+            .map(|p| (p.to_string(), redact_path(p.as_str(), depth)))
+            .filter(|(path, redacted)| path != redacted)
+            .collect();
+        let root = root.display().to_string();
+        if !root.is_empty() {
+            replacements.push((root, "<root>".to_string()));
+        }
+        replacements.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+        replacements.dedup();
+        Self { replacements }
+    }
+
+    fn redact(&self, message: &str) -> String {
+        let mut message = message.to_string();
+        for (from, to) in &self.replacements {
+            message = message.replace(from, to);
+        }
+        message
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::anyhow;
+    use futures::stream;
+    use futures::stream::BoxStream;
+    use futures::StreamExt;
+    use manifest::FileMetadata;
+    use minibytes::Bytes;
+    use storemodel::ReadFileContents;
+    use tempfile::TempDir;
+    use types::Key;
+    use vfs::VFS;
+
+    use super::*;
+    use crate::actions::UpdateAction;
+    use crate::Action;
+    use crate::ActionMap;
+    use crate::FetchFailure;
+
+    /// Has none of the content.
+    struct EmptyStore;
+
+    #[async_trait::async_trait]
+    impl ReadFileContents for EmptyStore {
+        type Error = anyhow::Error;
+
+        async fn read_file_contents(&self, keys: Vec<Key>) -> BoxStream<Result<(Bytes, Key)>> {
+            stream::iter(keys)
+                .map(|key| {
+                    let err = io::Error::new(io::ErrorKind::NotFound, "no such blob");
+                    Err(anyhow::Error::new(err).context(key))
+                })
+                .boxed()
+        }
+
+        async fn read_rename_metadata(
+            &self,
+            _keys: Vec<Key>,
+        ) -> BoxStream<Result<(Key, Option<Key>), Self::Error>> {
+            stream::empty().boxed()
+        }
+    }
+
+    fn rp(p: &str) -> RepoPathBuf {
+        RepoPathBuf::from_string(p.to_string()).unwrap()
+    }
+
+    fn hgid(n: u8) -> HgId {
+        HgId::from_byte_array([n; HgId::len()])
+    }
+
+    fn plan(tempdir: &TempDir, paths: &[&str]) -> Result<CheckoutPlan> {
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        let mut map = ActionMap::empty();
+        for (i, path) in paths.iter().enumerate() {
+            let meta = FileMetadata::regular(hgid(i as u8 + 1));
+            map.insert(rp(path), Action::Update(UpdateAction::new(None, meta)));
+        }
+        Ok(Checkout::default_config(vfs).plan_action_map(map))
+    }
+
+    fn io_failure(err: io::Error) -> anyhow::Error {
+        anyhow::Error::new(err).context("Can't write")
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_disk_full() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let plan = plan(&tempdir, &["dir/sub/secret.txt"])?;
+        let error = CheckoutError::WriteFailed {
+            path: rp("dir/sub/secret.txt"),
+            source: io_failure(io::Error::from_raw_os_error(28)),
+        };
+        let report = plan.failure_report(error, &CheckoutStats::default(), Duration::from_secs(2));
+        let bundle = report.failure_bundle();
+
+        assert_eq!(bundle.class, FailureClass::DiskFull);
+        assert_eq!(
+            bundle.failed_actions,
+            vec![FailedAction {
+                kind: FailedActionKind::Write,
+                path: Some("dir/sub/...".to_string()),
+                hgid: None,
+                io_kind: Some(format!("{:?}", io::Error::from_raw_os_error(28).kind())),
+                os_error: Some(28),
+            }]
+        );
+        assert_eq!(bundle.causes.len(), 3);
+        assert!(bundle.causes[0]
+            .message
+            .starts_with("Failed to write dir/sub/...: "));
+        assert_eq!(bundle.causes[1].message, "Can't write");
+        assert_eq!(bundle.causes[2].os_error, Some(28));
+        let json = serde_json::to_string(&bundle)?;
+        assert!(!json.contains("secret"), "{}", json);
+
+        assert_eq!(bundle.plan.update_content, 1);
+        assert_eq!(bundle.timing.elapsed_ms, 2000);
+        assert_eq!(bundle.environment.platform, std::env::consts::OS);
+        assert_eq!(
+            bundle.environment.case_sensitive,
+            plan.checkout.vfs.case_sensitive()
+        );
+        assert_eq!(bundle.options.fetch_retries, plan.checkout.fetch_retries);
+
+        // Deep enough to keep the whole path.
+        let bundle = report
+            .with_bundle_options(FailureBundleOptions {
+                path_depth: 3,
+                ..Default::default()
+            })
+            .failure_bundle();
+        assert_eq!(
+            bundle.failed_actions[0].path.as_deref(),
+            Some("dir/sub/secret.txt")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_permission_denied() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let plan = plan(&tempdir, &["a/b/c"])?;
+        let root = tempdir.path().join("a/b/c");
+        let err = io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("can't remove {}", root.display()),
+        );
+        let error = CheckoutError::RemoveFailed {
+            path: rp("a/b/c"),
+            source: io_failure(err),
+        };
+        let bundle = plan
+            .failure_report(error, &CheckoutStats::default(), Duration::ZERO)
+            .with_bundle_options(FailureBundleOptions {
+                path_depth: 1,
+                ..Default::default()
+            })
+            .failure_bundle();
+
+        assert_eq!(bundle.class, FailureClass::PermissionDenied);
+        let action = &bundle.failed_actions[0];
+        assert_eq!(action.kind, FailedActionKind::Remove);
+        assert_eq!(action.path.as_deref(), Some("a/..."));
+        assert_eq!(action.io_kind.as_deref(), Some("PermissionDenied"));
+        // The working copy root and the path are redacted in messages.
+        let last = bundle.causes.last().unwrap();
+        assert_eq!(last.message, "can't remove <root>/a/...");
+        assert_eq!(bundle.timing.actions_per_sec, 0.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_not_found() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let plan = plan(&tempdir, &["x/y/one", "x/y/two"])?;
+        let stats = plan.new_stats();
+        let error = match plan.apply_store_with_stats(&EmptyStore, &stats).await {
+            Err(error) => error,
+            Ok(()) => panic!("the store has no content"),
+        };
+        let report = plan.failure_report(error, &stats, Duration::ZERO);
+        assert!(matches!(
+            report.error(),
+            CheckoutError::FetchRetriesExhausted { .. }
+        ));
+        let bundle = report.failure_bundle();
+
+        assert_eq!(bundle.class, FailureClass::StoreNotFound);
+        let mut actions = bundle.failed_actions.clone();
+        actions.sort_by(|a, b| a.hgid.cmp(&b.hgid));
+        assert_eq!(actions.len(), 2);
+        for (action, id) in actions.iter().zip([1, 2]) {
+            assert_eq!(action.kind, FailedActionKind::Fetch);
+            assert_eq!(action.path.as_deref(), Some("x/y/..."));
+            assert_eq!(action.hgid, Some(hgid(id).to_hex()));
+            assert_eq!(action.io_kind.as_deref(), Some("NotFound"));
+        }
+        assert_eq!(bundle.applied, AppliedStats::default());
+
+        // Store failures that are not about missing content.
+        let error = CheckoutError::FetchFailed {
+            key: None,
+            source: anyhow!("store unavailable"),
+        };
+        let report = plan.failure_report(error, &CheckoutStats::default(), Duration::ZERO);
+        assert_eq!(report.failure_bundle().class, FailureClass::Store);
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_cap() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let plan = plan(&tempdir, &[])?;
+        let failures = (0..1000)
+            .map(|i| FetchFailure {
+                key: Key::new(rp(&format!("dir/file{}", i)), hgid(1)),
+                source: anyhow!("store unavailable"),
+            })
+            .collect();
+        let error = CheckoutError::FetchRetriesExhausted {
+            rounds: 2,
+            failures,
+        };
+        let report = plan
+            .failure_report(error, &CheckoutStats::default(), Duration::ZERO)
+            .with_bundle_options(FailureBundleOptions {
+                max_bytes: 8192,
+                ..Default::default()
+            });
+        let bundle = report.failure_bundle();
+
+        assert!(bundle.omitted_actions > 0);
+        assert!(!bundle.failed_actions.is_empty());
+        assert_eq!(bundle.failed_actions.len() + bundle.omitted_actions, 1000);
+
+        let path = tempdir.path().join("bundle.json");
+        bundle.write_to(&path)?;
+        let json = std::fs::read(&path)?;
+        assert!(json.len() <= 8192, "{} bytes", json.len());
+        // Close to the cap.
+        assert!(json.len() > 8192 - 200, "{} bytes", json.len());
+        let value: serde_json::Value = serde_json::from_slice(&json)?;
+        assert_eq!(value["class"], "store");
+        assert_eq!(value["omitted_actions"], bundle.omitted_actions);
+        Ok(())
+    }
+}
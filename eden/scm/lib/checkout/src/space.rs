@@ -0,0 +1,354 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Checking that the working copy's filesystem has room for a plan before
+//! applying it, see `Checkout::with_space_check`.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use futures::stream;
+use futures::StreamExt;
+use thiserror::Error;
+use tracing::debug;
+use tracing::warn;
+use types::HgId;
+use types::RepoPathBuf;
+use vfs::VFS;
+
+use crate::spawner::run_blocking;
+use crate::Checkout;
+use crate::CheckoutError;
+use crate::CheckoutPlan;
+use crate::VFS_BATCH_SIZE;
+
+/// Returns the size of the file content `HgId`, if known without fetching
+/// it, e.g. from store metadata.
+pub type FileSizes = Arc<dyn Fn(&HgId) -> Option<u64> + Send + Sync>;
+
+/// Returns the bytes available to the current user on the filesystem of the
+/// given path.
+pub type FreeSpaceProbe = Arc<dyn Fn(&Path) -> io::Result<u64> + Send + Sync>;
+
+const DEFAULT_MARGIN: u64 = 100 << 20;
+
+/// How to check that a plan fits on disk, see `Checkout::with_space_check`.
+#[derive(Clone)]
+pub struct SpaceCheck {
+    sizes: SizeSource,
+    margin: u64,
+    force: bool,
+    probe: FreeSpaceProbe,
+}
+
+#[derive(Clone)]
+enum SizeSource {
+    PerFile(FileSizes),
+    Total(u64),
+}
+
+impl SpaceCheck {
+    /// Takes the size of each file written from `sizes`. Files it doesn't
+    /// know are counted in `SpaceEstimate::unknown_sizes`.
+    pub fn with_sizes(sizes: FileSizes) -> Self {
+        Self::new(SizeSource::PerFile(sizes))
+    }
+
+    /// Uses `bytes` as the total size of the files written, as computed by
+    /// the caller.
+    pub fn with_total_bytes(bytes: u64) -> Self {
+        Self::new(SizeSource::Total(bytes))
+    }
+
+    fn new(sizes: SizeSource) -> Self {
+        Self {
+            sizes,
+            margin: DEFAULT_MARGIN,
+            force: false,
+            probe: Arc::new(|path| fs2::available_space(path)),
+        }
+    }
+
+    /// Bytes to keep free in addition to what the plan needs. Defaults to
+    /// 100MB.
+    pub fn with_margin(mut self, margin: u64) -> Self {
+        self.margin = margin;
+        self
+    }
+
+    /// Only log a warning instead of failing when there isn't enough space.
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Overrides how the free space is found, statvfs or
+    /// GetDiskFreeSpaceEx by default.
+    pub fn with_free_space_probe(mut self, probe: FreeSpaceProbe) -> Self {
+        self.probe = probe;
+        self
+    }
+}
+
+/// The disk space a plan needs, compared to the space available.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpaceEstimate {
+    /// Size of the files written.
+    pub bytes_written: u64,
+    /// Size of the files removed or overwritten, as found on disk. Files
+    /// with other hard links are not counted, since they stay on disk. Only
+    /// reported: new content is written before the old files are gone.
+    pub bytes_freed: u64,
+    pub available: u64,
+    pub margin: u64,
+    /// Files written whose size is not known, and so not counted in
+    /// `bytes_written`.
+    pub unknown_sizes: usize,
+}
+
+impl SpaceEstimate {
+    /// Bytes that must be available to apply the plan, including the
+    /// margin. Files are written to a staging area, or next to the file
+    /// they replace, before the old content is removed, so space freed by
+    /// the plan does not count.
+    pub fn required(&self) -> u64 {
+        self.bytes_written.saturating_add(self.margin)
+    }
+
+    pub fn is_sufficient(&self) -> bool {
+        self.required() <= self.available
+    }
+}
+
+/// The filesystem does not have room for a plan, see
+/// `Checkout::with_space_check`.
+#[derive(Debug, Error)]
+pub struct InsufficientSpace {
+    pub estimate: SpaceEstimate,
+}
+
+impl fmt::Display for InsufficientSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let estimate = &self.estimate;
+        write!(
+            f,
+            "checkout needs {} bytes of free space but only {} are available",
+            estimate.required(),
+            estimate.available
+        )?;
+        write!(
+            f,
+            " ({} written, {} freed, {} margin)",
+            estimate.bytes_written, estimate.bytes_freed, estimate.margin
+        )?;
+        if estimate.unknown_sizes > 0 {
+            write!(
+                f,
+                ", not counting {} files of unknown size",
+                estimate.unknown_sizes
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl CheckoutPlan {
+    /// Estimates the disk space needed to apply this plan according to
+    /// `check`, without changing the working copy. The files removed or
+    /// overwritten are statted, up to `concurrency` batches at once.
+    ///
+    /// Returns `None` if the free space can't be found.
+    pub async fn estimate_space(
+        &self,
+        check: &SpaceCheck,
+    ) -> Result<Option<SpaceEstimate>, CheckoutError> {
+        let vfs = &self.checkout.vfs;
+        let available = match (check.probe)(vfs.root()) {
+            Ok(available) => available,
+            Err(e) => {
+                warn!("Can not get free space of {:?}: {}", vfs.root(), e);
+                return Ok(None);
+            }
+        };
+        let (bytes_written, unknown_sizes) = check.content_bytes(
+            self.filtered_update_content
+                .iter()
+                .map(|action| &action.content_hgid),
+        );
+        let paths = self
+            .remove
+            .iter()
+            .chain(self.filtered_update_content.iter().map(|u| &u.path))
+            .cloned();
+        let spawner = self.checkout.spawner();
+        let mut freed = stream::iter(paths)
+            .chunks(VFS_BATCH_SIZE)
+            .map(|paths| {
+                let vfs = vfs.clone();
+                run_blocking(spawner, move || freed_bytes(&vfs, &paths))
+            })
+            .buffer_unordered(self.checkout.concurrency);
+        let mut bytes_freed = 0u64;
+        while let Some(freed) = freed.next().await {
+            bytes_freed = bytes_freed.saturating_add(freed?);
+        }
+        Ok(Some(SpaceEstimate {
+            bytes_written,
+            bytes_freed,
+            available,
+            margin: check.margin,
+            unknown_sizes,
+        }))
+    }
+
+    /// Fails if `Checkout::with_space_check` is set and there isn't enough
+    /// space for the plan, unless forced.
+    pub(crate) async fn check_space(&self) -> Result<(), CheckoutError> {
+        let check = match &self.checkout.space_check {
+            Some(check) => check,
+            None => return Ok(()),
+        };
+        let estimate = match self.estimate_space(check).await? {
+            Some(estimate) => estimate,
+            None => return Ok(()),
+        };
+        debug!("Disk space estimate: {:?}", estimate);
+        check.verify(estimate)?;
+        Ok(())
+    }
+}
+
+impl SpaceCheck {
+    /// Total size of the contents `ids`, and how many of them have an
+    /// unknown size and are not counted.
+    pub(crate) fn content_bytes<'a>(&self, ids: impl Iterator<Item = &'a HgId>) -> (u64, usize) {
+        let sizes = match &self.sizes {
+            SizeSource::Total(bytes) => return (*bytes, 0),
+            SizeSource::PerFile(sizes) => sizes,
+        };
+        let mut total = 0u64;
+        let mut unknown_sizes = 0;
+        for id in ids {
+            match sizes(id) {
+                Some(size) => total = total.saturating_add(size),
+                None => unknown_sizes += 1,
+            }
+        }
+        (total, unknown_sizes)
+    }
+
+    /// Fails if `estimate` does not fit, unless forced. Returns whether it
+    /// fits.
+    fn verify(&self, estimate: SpaceEstimate) -> Result<bool, CheckoutError> {
+        if estimate.is_sufficient() {
+            return Ok(true);
+        }
+        let error = InsufficientSpace { estimate };
+        if self.force {
+            warn!("Continuing checkout anyway: {}", error);
+            return Ok(false);
+        }
+        Err(error.into())
+    }
+}
+
+/// `Checkout::with_space_check` for `Checkout::apply_diff_stream`, where the
+/// files written are only known as the diff arrives. The free space is
+/// found once, and each file is counted before it is written, so running
+/// out of space fails the checkout part way instead of before it starts.
+pub(crate) struct StreamSpaceCheck<'a> {
+    check: &'a SpaceCheck,
+    estimate: SpaceEstimate,
+    /// Set once a forced check warned, so it is not repeated for every file.
+    exceeded: bool,
+}
+
+impl StreamSpaceCheck<'_> {
+    /// Starts checking the space used by `checkout`, if it has a space
+    /// check and the free space can be found. Sizes from
+    /// `SpaceCheck::with_total_bytes` are checked right away.
+    pub(crate) fn new(checkout: &Checkout) -> Result<Option<StreamSpaceCheck<'_>>, CheckoutError> {
+        let check = match &checkout.space_check {
+            Some(check) => check,
+            None => return Ok(None),
+        };
+        let root = checkout.vfs.root();
+        let available = match (check.probe)(root) {
+            Ok(available) => available,
+            Err(e) => {
+                warn!("Can not get free space of {:?}: {}", root, e);
+                return Ok(None);
+            }
+        };
+        let bytes_written = match &check.sizes {
+            SizeSource::Total(bytes) => *bytes,
+            SizeSource::PerFile(_) => 0,
+        };
+        let estimate = SpaceEstimate {
+            bytes_written,
+            bytes_freed: 0,
+            available,
+            margin: check.margin,
+            unknown_sizes: 0,
+        };
+        let exceeded = !check.verify(estimate)?;
+        Ok(Some(StreamSpaceCheck {
+            check,
+            estimate,
+            exceeded,
+        }))
+    }
+
+    /// Counts the file content `id`, about to be written. Fails if the
+    /// files counted so far don't fit, unless forced.
+    pub(crate) fn add(&mut self, id: &HgId) -> Result<(), CheckoutError> {
+        let sizes = match &self.check.sizes {
+            SizeSource::PerFile(sizes) => sizes,
+            SizeSource::Total(_) => return Ok(()),
+        };
+        match sizes(id) {
+            Some(size) => {
+                self.estimate.bytes_written = self.estimate.bytes_written.saturating_add(size)
+            }
+            None => self.estimate.unknown_sizes += 1,
+        }
+        if !self.exceeded {
+            self.exceeded = !self.check.verify(self.estimate)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sum of the sizes of the files and symlinks at `paths`. Missing paths and
+/// paths that can't be statted count as 0.
+fn freed_bytes(vfs: &VFS, paths: &[RepoPathBuf]) -> u64 {
+    let mut total = 0u64;
+    for path in paths {
+        let metadata = match vfs.metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if metadata.is_dir() || has_other_links(&metadata) {
+            continue;
+        }
+        total = total.saturating_add(metadata.len());
+    }
+    total
+}
+
+#[cfg(unix)]
+fn has_other_links(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink() > 1
+}
+
+#[cfg(not(unix))]
+fn has_other_links(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
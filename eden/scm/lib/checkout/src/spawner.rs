@@ -0,0 +1,144 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::panic::catch_unwind;
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use futures::channel::oneshot;
+use futures::future;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use parking_lot::Mutex;
+use tokio::runtime::Handle;
+use vfs::VfsExecutor;
+
+use crate::CheckoutError;
+
+/// A blocking task of a checkout, like writing a batch of files.
+pub type BlockingTask = Box<dyn FnOnce() + Send>;
+
+/// Runs the blocking filesystem work of a checkout, see
+/// `Checkout::with_blocking_spawner`.
+pub trait BlockingSpawner: Send + Sync {
+    /// Runs `task`, returning a future that resolves once it ran. The task
+    /// must run even if the future is dropped or never polled. Fails if the
+    /// task panicked or was dropped without running.
+    fn spawn(&self, task: BlockingTask) -> BoxFuture<'static, Result<()>>;
+}
+
+/// Runs tasks on the blocking pool of the current tokio runtime. This is the
+/// default.
+pub struct TokioSpawner;
+
+impl BlockingSpawner for TokioSpawner {
+    fn spawn(&self, task: BlockingTask) -> BoxFuture<'static, Result<()>> {
+        let handle = Handle::current().spawn_blocking(task);
+        async move { Ok(handle.await?) }.boxed()
+    }
+}
+
+/// Runs tasks on the calling thread, before `spawn` returns, so a checkout
+/// uses no thread of its own. Tasks block the executor polling the checkout.
+pub struct SyncSpawner;
+
+impl BlockingSpawner for SyncSpawner {
+    fn spawn(&self, task: BlockingTask) -> BoxFuture<'static, Result<()>> {
+        future::ready(run_task(task)).boxed()
+    }
+}
+
+/// Runs tasks on a fixed number of threads owned by the spawner, in the
+/// order they were spawned. Dropping it waits for queued tasks to complete.
+pub struct ThreadPoolSpawner {
+    sender: Mutex<Option<mpsc::Sender<BlockingTask>>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPoolSpawner {
+    pub fn new(threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<BlockingTask>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handles = (0..threads.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || {
+                    while let Some(task) = next_task(&receiver) {
+                        task();
+                    }
+                })
+            })
+            .collect();
+        Self {
+            sender: Mutex::new(Some(sender)),
+            handles,
+        }
+    }
+}
+
+impl BlockingSpawner for ThreadPoolSpawner {
+    fn spawn(&self, task: BlockingTask) -> BoxFuture<'static, Result<()>> {
+        let (tx, rx) = oneshot::channel();
+        let task: BlockingTask = Box::new(move || {
+            tx.send(run_task(task)).ok();
+        });
+        if let Some(sender) = self.sender.lock().as_ref() {
+            sender.send(task).ok();
+        }
+        async move {
+            rx.await
+                .unwrap_or_else(|_| Err(anyhow!("blocking task dropped without running")))
+        }
+        .boxed()
+    }
+}
+
+impl Drop for ThreadPoolSpawner {
+    fn drop(&mut self) {
+        self.sender.lock().take();
+        for handle in self.handles.drain(..) {
+            handle.join().ok();
+        }
+    }
+}
+
+/// The next task of a `ThreadPoolSpawner`, releasing the lock before it
+/// runs.
+fn next_task(receiver: &Mutex<mpsc::Receiver<BlockingTask>>) -> Option<BlockingTask> {
+    receiver.lock().recv().ok()
+}
+
+/// Runs `task`, failing instead of unwinding if it panics.
+fn run_task(task: BlockingTask) -> Result<()> {
+    catch_unwind(AssertUnwindSafe(task)).map_err(|_| anyhow!("blocking task panicked"))
+}
+
+/// Runs `f` with `spawner` and returns its result.
+pub(crate) async fn run_blocking<T: Send + 'static>(
+    spawner: &dyn BlockingSpawner,
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, CheckoutError> {
+    let (tx, rx) = oneshot::channel();
+    spawner
+        .spawn(Box::new(move || {
+            tx.send(f()).ok();
+        }))
+        .await
+        .map_err(CheckoutError::TaskFailed)?;
+    rx.await
+        .map_err(|_| CheckoutError::TaskFailed(anyhow!("blocking task did not return")))
+}
+
+/// Runs the actions of an `AsyncVfsWriter` with `spawner`.
+pub(crate) fn vfs_executor(spawner: Arc<dyn BlockingSpawner>) -> VfsExecutor {
+    Arc::new(move |task| spawner.spawn(task))
+}
@@ -0,0 +1,432 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Checkout in two phases: `CheckoutPlan::stage` fetches all content to a
+//! staging directory without touching the working copy, then
+//! `StagedCheckout::commit` applies the plan from it, which is much shorter
+//! than fetching, and can wait for a confirmation.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use futures::stream;
+use futures::StreamExt;
+use minibytes::Bytes;
+use parking_lot::Mutex;
+use progress_model::ProgressBar;
+use storemodel::ReadFileContents;
+use tracing::warn;
+use types::HgId;
+use types::Key;
+use types::RepoPathBuf;
+use vfs::BatchFailure;
+use vfs::UpdateFlag;
+use vfs::VFS;
+
+use crate::content_filter::filter_content;
+use crate::spawner::run_blocking;
+use crate::type_to_flag;
+use crate::CheckoutError;
+use crate::CheckoutPlan;
+use crate::CheckoutStats;
+use crate::ContentFilter;
+use crate::UpdateContentAction;
+use crate::VFS_BATCH_SIZE;
+
+/// Created first in a staging directory, containing the id of the process
+/// staging content.
+const OWNER_FILE: &str = "owner";
+
+/// Where `CheckoutPlan::apply_phases` takes file content from.
+#[derive(Clone, Copy)]
+pub(crate) enum ContentSource<'a> {
+    Store(&'a dyn ReadFileContents<Error = anyhow::Error>),
+    Staged(&'a StagedContent),
+    /// Staged content if there is some for the hgid, the store otherwise.
+    /// Used by `UndoPlan`.
+    Mixed {
+        staged: &'a StagedContent,
+        store: &'a dyn ReadFileContents<Error = anyhow::Error>,
+    },
+}
+
+/// Content fetched by `CheckoutPlan::stage`, one file per hgid.
+pub(crate) struct StagedContent {
+    dir: PathBuf,
+    /// Size of each staged file.
+    sizes: HashMap<HgId, u64>,
+    /// Number of paths not written yet, per hgid. The last one gets the
+    /// staged file itself, the others a copy.
+    remaining: Mutex<HashMap<HgId, usize>>,
+}
+
+/// A checkout plan with all its content staged, see `CheckoutPlan::stage`.
+pub struct StagedCheckout {
+    plan: CheckoutPlan,
+    content: StagedContent,
+}
+
+/// A staging directory left by a checkout that neither committed nor
+/// abandoned it, see `find_stale_staging`.
+#[derive(Debug)]
+pub struct StaleStaging {
+    pub dir: PathBuf,
+    /// The process that created it, if it got to record it.
+    pub pid: Option<u32>,
+}
+
+impl CheckoutPlan {
+    /// Fetches the content this plan writes to `staging_dir`, without
+    /// changing the working copy. Content used by several files is fetched
+    /// and stored once. `staging_dir` must not exist, and should be on the
+    /// working copy's filesystem, e.g. in the repo's metadata directory, so
+    /// that files can be renamed into place.
+    ///
+    /// Fails before fetching anything if `Checkout::with_space_check` finds
+    /// there isn't enough space.
+    ///
+    /// The staging directory is removed if staging fails. If the process
+    /// dies instead, it is left behind, see `find_stale_staging`.
+    pub async fn stage(
+        self,
+        store: &dyn ReadFileContents<Error = anyhow::Error>,
+        staging_dir: &Path,
+    ) -> Result<StagedCheckout, CheckoutError> {
+        self.check_space().await?;
+        let dir = staging_dir.to_path_buf();
+        if dir.exists() {
+            return Err(staging_failed(
+                &dir,
+                anyhow!("the staging directory already exists"),
+            ));
+        }
+        create_staging_dir(&dir).map_err(|source| staging_failed(&dir, source))?;
+        let sizes = match self.fetch_to_staging(store, &dir).await {
+            Ok(sizes) => sizes,
+            Err(e) => {
+                if let Err(remove_error) = fs::remove_dir_all(&dir) {
+                    warn!("Can not remove {:?}: {}", dir, remove_error);
+                }
+                return Err(e);
+            }
+        };
+        let content = StagedContent::new(dir, sizes, &self.filtered_update_content);
+        Ok(StagedCheckout {
+            plan: self,
+            content,
+        })
+    }
+
+    /// Fetches the content of `filtered_update_content` to `dir`, once per
+    /// hgid. Returns the size of each staged file.
+    async fn fetch_to_staging(
+        &self,
+        store: &dyn ReadFileContents<Error = anyhow::Error>,
+        dir: &Path,
+    ) -> Result<HashMap<HgId, u64>, CheckoutError> {
+        let mut requested = HashMap::new();
+        for action in &self.filtered_update_content {
+            requested
+                .entry(action.content_hgid)
+                .or_insert_with(|| action.make_key());
+        }
+        let keys = requested.values().cloned().collect();
+        let bar = ProgressBar::register_new("Staging", requested.len() as u64, "files");
+
+        let spawner = self.checkout.spawner();
+        let mut staged = store
+            .read_file_contents(keys)
+            .await
+            .map(|result| async move {
+                let (data, key) = result.map_err(|source| CheckoutError::FetchFailed {
+                    key: source.downcast_ref::<Key>().cloned(),
+                    source,
+                })?;
+                let path = blob_path(dir, &key.hgid);
+                let size = run_blocking(spawner, move || write_blob(&path, &data))
+                    .await?
+                    .map_err(|source| staging_failed(dir, source))?;
+                Ok::<_, CheckoutError>((key, size))
+            })
+            .buffer_unordered(self.checkout.concurrency);
+
+        let mut sizes = HashMap::new();
+        while let Some(result) = staged.next().await {
+            let (key, size) = result?;
+            if requested.remove(&key.hgid).is_none() {
+                return Err(CheckoutError::KeyNotFound { key });
+            }
+            sizes.insert(key.hgid, size);
+            bar.increase_position(1);
+        }
+        if let Some(key) = requested.into_values().next() {
+            return Err(CheckoutError::FetchFailed {
+                key: Some(key),
+                source: anyhow!("not returned by the store"),
+            });
+        }
+        Ok(sizes)
+    }
+
+    /// Writes the staged content for `actions`. Files sharing content get
+    /// copies of the staged file, except the last one, which gets the file
+    /// itself. Copies are made first, as the file is moved afterwards.
+    pub(crate) async fn place_staged<'a>(
+        &self,
+        staged: &StagedContent,
+        actions: impl Iterator<Item = &'a UpdateContentAction>,
+        stats: &CheckoutStats,
+        bar: &Arc<ProgressBar>,
+    ) -> Result<(), CheckoutError> {
+        let mut copies = Vec::new();
+        let mut renames = Vec::new();
+        {
+            let mut remaining = staged.remaining.lock();
+            for action in actions {
+                let file = (
+                    action.path.clone(),
+                    action.content_hgid,
+                    type_to_flag(&action.file_type),
+                );
+                match remaining.get_mut(&action.content_hgid) {
+                    Some(count) if *count > 1 => {
+                        *count -= 1;
+                        copies.push(file);
+                    }
+                    Some(_) => {
+                        remaining.remove(&action.content_hgid);
+                        renames.push(file);
+                    }
+                    None => {
+                        return Err(CheckoutError::PlanInconsistent {
+                            detail: format!("{} was not staged", action.path),
+                        });
+                    }
+                }
+            }
+        }
+
+        let progress = self.progress.as_ref();
+        let spawner = self.checkout.spawner();
+        for (files, rename) in [(copies, false), (renames, true)] {
+            let batches = stream::iter(files)
+                .chunks(VFS_BATCH_SIZE)
+                .map(|files| {
+                    let vfs = self.checkout.vfs.clone();
+                    let dir = staged.dir.clone();
+                    let batch = files.clone();
+                    let filtering = stats.content_filter.as_ref();
+                    let filter = filtering.map(|filtering| filtering.filter().clone());
+                    let place = async move {
+                        let (written, unfiltered) = run_blocking(spawner, move || {
+                            place_batch(&vfs, &dir, batch, rename, filter.as_deref())
+                        })
+                        .await??;
+                        if let Some(filtering) = filtering {
+                            filtering.record_written(unfiltered);
+                        }
+                        Ok(written)
+                    };
+                    Self::write_files_with(stats, files, place, progress, bar)
+                })
+                .buffer_unordered(self.checkout.concurrency);
+            Self::process_work_stream(batches).await?;
+        }
+        Ok(())
+    }
+}
+
+impl StagedCheckout {
+    /// The plan the content was staged for.
+    pub fn plan(&self) -> &CheckoutPlan {
+        &self.plan
+    }
+
+    pub fn staging_dir(&self) -> &Path {
+        &self.content.dir
+    }
+
+    /// Applies the plan as `CheckoutPlan::apply_store` would, but writes the
+    /// staged content instead of fetching it, by renaming staged files into
+    /// place when possible. Fails before changing the working copy if staged
+    /// files are missing or changed size.
+    ///
+    /// The staging directory is removed once the checkout succeeds. If it
+    /// fails, the directory is left for `find_stale_staging`, as some of its
+    /// files may have been moved.
+    pub async fn commit(self) -> Result<CheckoutStats, CheckoutError> {
+        self.content.verify()?;
+        let stats = CheckoutStats::new(&self.plan.checkout);
+        self.plan
+            .apply_with_stats(ContentSource::Staged(&self.content), &stats)
+            .await?;
+        self.content.remove()?;
+        Ok(stats)
+    }
+
+    /// Removes the staging directory, leaving the working copy as it is.
+    pub fn abandon(self) -> Result<(), CheckoutError> {
+        self.content.remove()
+    }
+}
+
+impl StagedContent {
+    /// Content staged in `dir`, with the size of each file, to be written
+    /// by `actions`. Actions for hgids that were not staged are ignored.
+    pub(crate) fn new(
+        dir: PathBuf,
+        sizes: HashMap<HgId, u64>,
+        actions: &[UpdateContentAction],
+    ) -> Self {
+        let mut remaining = HashMap::new();
+        for action in actions {
+            if sizes.contains_key(&action.content_hgid) {
+                *remaining.entry(action.content_hgid).or_default() += 1;
+            }
+        }
+        Self {
+            dir,
+            sizes,
+            remaining: Mutex::new(remaining),
+        }
+    }
+
+    pub(crate) fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub(crate) fn contains(&self, hgid: &HgId) -> bool {
+        self.sizes.contains_key(hgid)
+    }
+
+    /// Checks that every staged file is still there, with its size.
+    pub(crate) fn verify(&self) -> Result<(), CheckoutError> {
+        for (hgid, size) in &self.sizes {
+            let path = blob_path(&self.dir, hgid);
+            let result = fs::metadata(&path)
+                .with_context(|| format!("Can't stat {:?}", path))
+                .and_then(|metadata| {
+                    if metadata.len() != *size {
+                        bail!(
+                            "{:?} has {} bytes, {} were staged",
+                            path,
+                            metadata.len(),
+                            size
+                        );
+                    }
+                    Ok(())
+                });
+            result.map_err(|source| staging_failed(&self.dir, source))?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn remove(&self) -> Result<(), CheckoutError> {
+        fs::remove_dir_all(&self.dir).map_err(|e| staging_failed(&self.dir, e.into()))
+    }
+}
+
+impl StaleStaging {
+    pub fn remove(self) -> Result<(), CheckoutError> {
+        fs::remove_dir_all(&self.dir).map_err(|e| staging_failed(&self.dir, e.into()))
+    }
+}
+
+/// Finds a staging directory at `staging_dir` that was left behind, by a
+/// process that died between `CheckoutPlan::stage` and `commit` or
+/// `abandon`, or by a failed `commit`. A directory in use by a running
+/// checkout looks the same, so this must be called with the working copy
+/// lock held.
+pub fn find_stale_staging(staging_dir: &Path) -> Result<Option<StaleStaging>, CheckoutError> {
+    if !staging_dir.exists() {
+        return Ok(None);
+    }
+    let pid = match fs::read_to_string(staging_dir.join(OWNER_FILE)) {
+        Ok(pid) => pid.trim().parse().ok(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(staging_failed(staging_dir, e.into())),
+    };
+    Ok(Some(StaleStaging {
+        dir: staging_dir.to_path_buf(),
+        pid,
+    }))
+}
+
+pub(crate) fn create_staging_dir(dir: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = dir.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Fails if another checkout created it in the meantime.
+    fs::create_dir(dir)?;
+    fs::write(dir.join(OWNER_FILE), std::process::id().to_string())?;
+    Ok(())
+}
+
+pub(crate) fn blob_path(dir: &Path, hgid: &HgId) -> PathBuf {
+    dir.join(hgid.to_hex())
+}
+
+/// Writes `data` to `path`, through a temporary file so that `path` never
+/// has partial content.
+pub(crate) fn write_blob(path: &Path, data: &[u8]) -> anyhow::Result<u64> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data).with_context(|| format!("Can't write {:?}", tmp))?;
+    let size = fs::metadata(&tmp)?.len();
+    if size != data.len() as u64 {
+        bail!("{:?} has {} bytes, {} were written", tmp, size, data.len());
+    }
+    fs::rename(&tmp, path).with_context(|| format!("Can't rename {:?}", tmp))?;
+    Ok(size)
+}
+
+/// Writes staged files in place, moving them if `rename`, copying them
+/// otherwise. Files `filter` wants are always copied, through the filter.
+/// Returns the number of bytes written, and before filtering.
+fn place_batch(
+    vfs: &VFS,
+    dir: &Path,
+    files: Vec<(RepoPathBuf, HgId, UpdateFlag)>,
+    rename: bool,
+    filter: Option<&dyn ContentFilter>,
+) -> anyhow::Result<(usize, usize)> {
+    let mut written = 0;
+    let mut unfiltered = 0;
+    for (path, hgid, flag) in files {
+        let source = blob_path(dir, &hgid);
+        let filter = filter.filter(|f| !matches!(flag, UpdateFlag::Symlink) && f.wants(&path));
+        let result = match filter {
+            None if rename => vfs.rename_into(&path, &source, flag).map(|w| (w, w)),
+            _ => fs::read(&source)
+                .with_context(|| format!("Can't read {:?}", source))
+                .and_then(|content| {
+                    let size = content.len();
+                    let content: Bytes = match filter {
+                        Some(filter) => filter_content(filter, &path, content.into(), flag)?,
+                        None => content.into(),
+                    };
+                    Ok((vfs.write(&path, &content, flag)?, size))
+                }),
+        };
+        let (w, u) = result.context(BatchFailure { path })?;
+        written += w;
+        unfiltered += u;
+    }
+    Ok((written, unfiltered))
+}
+
+pub(crate) fn staging_failed(dir: &Path, source: anyhow::Error) -> CheckoutError {
+    CheckoutError::StagingFailed {
+        dir: dir.to_path_buf(),
+        source,
+    }
+}
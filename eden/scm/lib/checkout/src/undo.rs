@@ -0,0 +1,379 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Undo plans: `CheckoutPlan::capture_undo` records the state of the files a
+//! plan changes before it is applied, and returns an `UndoPlan` restoring
+//! that state. Small files are copied to a spill directory, so rolling back
+//! does not need manifests or the network for them.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use manifest::FileMetadata;
+use manifest::FileType;
+use storemodel::ReadFileContents;
+use tracing::warn;
+use types::HgId;
+use types::Parents;
+use types::RepoPath;
+use types::RepoPathBuf;
+use vfs::VFS;
+
+use crate::spawner::run_blocking;
+use crate::staging::blob_path;
+use crate::staging::create_staging_dir;
+use crate::staging::write_blob;
+use crate::staging::ContentSource;
+use crate::staging::StagedContent;
+use crate::xattrs::is_executable;
+use crate::CheckoutError;
+use crate::CheckoutPlan;
+use crate::CheckoutStats;
+use crate::UpdateContentAction;
+use crate::UpdateMetaAction;
+
+const DEFAULT_MAX_SPILL_BYTES: u64 = 256 << 20;
+const DEFAULT_MAX_FILE_BYTES: u64 = 1 << 20;
+
+/// The hgid of the content a file had before the checkout, if known. Used
+/// for files whose content is not spilled.
+pub type ReverseMetadata = Arc<dyn Fn(&RepoPath) -> Option<HgId> + Send + Sync>;
+
+/// What `CheckoutPlan::capture_undo` does once the spill directory is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UndoOverflow {
+    /// Fail before changing the working copy.
+    Fail,
+    /// Record the remaining files by hgid only, with a warning.
+    MetadataOnly,
+}
+
+/// How `CheckoutPlan::capture_undo` records the previous state of files.
+#[derive(Clone)]
+pub struct UndoOptions {
+    spill_dir: PathBuf,
+    max_spill_bytes: u64,
+    max_file_bytes: u64,
+    overflow: UndoOverflow,
+    reverse_metadata: Option<ReverseMetadata>,
+}
+
+/// Restores the files changed by a checkout, see `CheckoutPlan::capture_undo`.
+pub struct UndoPlan {
+    plan: CheckoutPlan,
+    content: StagedContent,
+    unrestorable: Vec<RepoPathBuf>,
+}
+
+impl UndoOptions {
+    /// Spill content to `spill_dir`, which must not exist. Like a staging
+    /// directory, it should be on the working copy's filesystem.
+    pub fn new(spill_dir: PathBuf) -> Self {
+        Self {
+            spill_dir,
+            max_spill_bytes: DEFAULT_MAX_SPILL_BYTES,
+            max_file_bytes: DEFAULT_MAX_FILE_BYTES,
+            overflow: UndoOverflow::Fail,
+            reverse_metadata: None,
+        }
+    }
+
+    /// Spill at most `max` bytes in total, see `with_overflow`.
+    pub fn with_max_spill_bytes(mut self, max: u64) -> Self {
+        self.max_spill_bytes = max;
+        self
+    }
+
+    /// Record files larger than `max` bytes by hgid instead of spilling
+    /// them, see `with_reverse_metadata`.
+    pub fn with_max_file_bytes(mut self, max: u64) -> Self {
+        self.max_file_bytes = max;
+        self
+    }
+
+    pub fn with_overflow(mut self, overflow: UndoOverflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Look up the previous hgid of files that are not spilled with
+    /// `reverse_metadata`. Without it, or if it returns `None`, they can't
+    /// be restored, see `UndoPlan::unrestorable`.
+    pub fn with_reverse_metadata(mut self, reverse_metadata: ReverseMetadata) -> Self {
+        self.reverse_metadata = Some(reverse_metadata);
+        self
+    }
+}
+
+impl CheckoutPlan {
+    /// Records the state of the files this plan removes, overwrites, or
+    /// changes the exec bit of, and returns a plan restoring it, see
+    /// `UndoOptions`. Nothing in the working copy is changed. The spill
+    /// directory is removed if this fails.
+    pub async fn capture_undo(&self, options: &UndoOptions) -> Result<UndoPlan, CheckoutError> {
+        let dir = options.spill_dir.clone();
+        if dir.exists() {
+            return Err(undo_failed(
+                &dir,
+                anyhow!("the spill directory already exists"),
+            ));
+        }
+        let vfs = self.checkout.vfs.clone();
+        let files = PlannedFiles {
+            removed: self.remove.clone(),
+            updated: self
+                .filtered_update_content
+                .iter()
+                .map(|u| u.path.clone())
+                .collect(),
+            meta_updated: self.update_meta.iter().map(|u| u.path.clone()).collect(),
+        };
+        let captured = {
+            let options = options.clone();
+            run_blocking(self.checkout.spawner(), move || {
+                create_staging_dir(&options.spill_dir)?;
+                capture(&vfs, &options, files)
+            })
+            .await?
+        };
+        let captured = match captured {
+            Ok(captured) => captured,
+            Err(source) => {
+                if let Err(remove_error) = fs::remove_dir_all(&dir) {
+                    warn!("Can not remove {:?}: {}", dir, remove_error);
+                }
+                return Err(undo_failed(&dir, source));
+            }
+        };
+
+        let plan = CheckoutPlan {
+            remove: captured.remove,
+            filtered_update_content: captured.update_content.clone(),
+            update_content: captured.update_content,
+            update_meta: captured.update_meta,
+            diff_summary: Default::default(),
+            restored: Vec::new(),
+            kept_deleted: Vec::new(),
+            progress: None,
+            checkout: self.checkout.clone(),
+        };
+        let content = StagedContent::new(dir, captured.sizes, &plan.filtered_update_content);
+        Ok(UndoPlan {
+            plan,
+            content,
+            unrestorable: captured.unrestorable,
+        })
+    }
+
+    /// Same as `apply_store`, after `capture_undo`. If applying fails, the
+    /// error comes with the undo plan, to roll back the failed checkout. It
+    /// is `None` if capturing failed, before the working copy was changed.
+    pub async fn apply_store_with_undo(
+        &self,
+        store: &dyn ReadFileContents<Error = anyhow::Error>,
+        options: &UndoOptions,
+    ) -> Result<(CheckoutStats, UndoPlan), (CheckoutError, Option<UndoPlan>)> {
+        let undo = self.capture_undo(options).await.map_err(|e| (e, None))?;
+        match self.apply_store(store).await {
+            Ok(stats) => Ok((stats, undo)),
+            Err(e) => Err((e, Some(undo))),
+        }
+    }
+}
+
+impl UndoPlan {
+    /// The plan restoring the previous state.
+    pub fn plan(&self) -> &CheckoutPlan {
+        &self.plan
+    }
+
+    pub fn spill_dir(&self) -> &Path {
+        self.content.dir()
+    }
+
+    /// Files changed by the checkout that this plan leaves as they are, as
+    /// their content was neither spilled nor known by hgid.
+    pub fn unrestorable(&self) -> &[RepoPathBuf] {
+        &self.unrestorable
+    }
+
+    /// Restores the previous state with the normal checkout machinery.
+    /// Spilled content is moved into place; content recorded by hgid is
+    /// fetched from `store`. Fails before changing the working copy if
+    /// spilled files are missing or changed size.
+    ///
+    /// The spill directory is removed once this succeeds.
+    pub async fn apply(
+        self,
+        store: &dyn ReadFileContents<Error = anyhow::Error>,
+    ) -> Result<CheckoutStats, CheckoutError> {
+        self.content.verify()?;
+        let stats = CheckoutStats::new(&self.plan.checkout);
+        let source = ContentSource::Mixed {
+            staged: &self.content,
+            store,
+        };
+        self.plan.apply_with_stats(source, &stats).await?;
+        self.content.remove()?;
+        Ok(stats)
+    }
+
+    /// Removes the spill directory, giving up on rolling back.
+    pub fn discard(self) -> Result<(), CheckoutError> {
+        self.content.remove()
+    }
+}
+
+/// Paths changed by a plan, by kind of change.
+struct PlannedFiles {
+    removed: Vec<RepoPathBuf>,
+    updated: Vec<RepoPathBuf>,
+    meta_updated: Vec<RepoPathBuf>,
+}
+
+/// The actions of an undo plan, and the content spilled for them.
+#[derive(Default)]
+struct Captured {
+    remove: Vec<RepoPathBuf>,
+    update_content: Vec<UpdateContentAction>,
+    update_meta: Vec<UpdateMetaAction>,
+    sizes: HashMap<HgId, u64>,
+    unrestorable: Vec<RepoPathBuf>,
+}
+
+/// The state of a file before the checkout.
+enum Previous {
+    /// Nothing, or a directory, which is restored through its files.
+    Absent,
+    File(FileMetadata),
+    Unrestorable,
+}
+
+struct Spiller<'a> {
+    vfs: &'a VFS,
+    options: &'a UndoOptions,
+    sizes: HashMap<HgId, u64>,
+    spilled_bytes: u64,
+    overflowed: bool,
+}
+
+fn capture(vfs: &VFS, options: &UndoOptions, files: PlannedFiles) -> anyhow::Result<Captured> {
+    let mut spiller = Spiller {
+        vfs,
+        options,
+        sizes: HashMap::new(),
+        spilled_bytes: 0,
+        overflowed: false,
+    };
+    let mut captured = Captured::default();
+    let removed = files.removed.into_iter().map(|path| (path, true));
+    let updated = files.updated.into_iter().map(|path| (path, false));
+    for (path, removed) in removed.chain(updated) {
+        match spiller.previous(&path)? {
+            Previous::Absent if removed => {}
+            Previous::Absent => captured.remove.push(path),
+            Previous::File(meta) => captured
+                .update_content
+                .push(UpdateContentAction::new(path, meta, removed)),
+            Previous::Unrestorable => captured.unrestorable.push(path),
+        }
+    }
+    for path in files.meta_updated {
+        match vfs.metadata(&path) {
+            Ok(metadata) if metadata.is_file() => {
+                let set_x_flag = is_executable(vfs, &path)?;
+                captured
+                    .update_meta
+                    .push(UpdateMetaAction { path, set_x_flag });
+            }
+            Ok(_) => {}
+            Err(e) if is_not_found(&e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    captured.sizes = spiller.sizes;
+    Ok(captured)
+}
+
+impl Spiller<'_> {
+    fn previous(&mut self, path: &RepoPath) -> anyhow::Result<Previous> {
+        let metadata = match self.vfs.metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if is_not_found(&e) => return Ok(Previous::Absent),
+            Err(e) => return Err(e),
+        };
+        if metadata.is_dir() {
+            return Ok(Previous::Absent);
+        }
+        let file_type = if metadata.is_symlink() {
+            FileType::Symlink
+        } else if is_executable(self.vfs, path)? {
+            FileType::Executable
+        } else {
+            FileType::Regular
+        };
+
+        let size = metadata.len();
+        if size <= self.options.max_file_bytes {
+            if self.spilled_bytes + size <= self.options.max_spill_bytes {
+                let hgid = self.spill(path)?;
+                return Ok(Previous::File(FileMetadata::new(hgid, file_type)));
+            }
+            if self.options.overflow == UndoOverflow::Fail {
+                bail!(
+                    "spilling {} would exceed {} bytes",
+                    path,
+                    self.options.max_spill_bytes
+                );
+            }
+            if !self.overflowed {
+                warn!(
+                    "Undo spill directory is full, recording {} and later files by hgid only",
+                    path
+                );
+                self.overflowed = true;
+            }
+        }
+
+        let reverse_metadata = self.options.reverse_metadata.as_ref();
+        Ok(match reverse_metadata.and_then(|f| f(path)) {
+            Some(hgid) => Previous::File(FileMetadata::new(hgid, file_type)),
+            None => Previous::Unrestorable,
+        })
+    }
+
+    /// Copies the content of `path`, or the target of a symlink, to the
+    /// spill directory, once per content. Returns the id it is stored as.
+    fn spill(&mut self, path: &RepoPath) -> anyhow::Result<HgId> {
+        let data = self.vfs.read(path)?;
+        let hgid = HgId::from_content(&data, Parents::None);
+        if !self.sizes.contains_key(&hgid) {
+            let size = write_blob(&blob_path(&self.options.spill_dir, &hgid), &data)?;
+            self.sizes.insert(hgid, size);
+            self.spilled_bytes += size;
+        }
+        Ok(hgid)
+    }
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .map_or(false, |e| e.kind() == io::ErrorKind::NotFound)
+}
+
+fn undo_failed(dir: &Path, source: anyhow::Error) -> CheckoutError {
+    CheckoutError::UndoCaptureFailed {
+        dir: dir.to_path_buf(),
+        source,
+    }
+}
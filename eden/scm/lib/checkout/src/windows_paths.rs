@@ -0,0 +1,213 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Finds paths in a checkout plan that Windows can't write as-is.
+//!
+//! Names like `aux.c` or `foo.` are rewritten by the classic Win32 APIs, so
+//! writing them would write somewhere else. On Windows, the plan fails before
+//! anything is written if it contains such names, unless checkout is allowed
+//! to write them through extended-length paths. Long paths are always written
+//! through extended-length paths, so they are only reported. On other
+//! platforms, problems are only reported, to help linting cross-platform
+//! repos.
+
+use std::fmt;
+use std::path::Path;
+
+use types::RepoPathBuf;
+use vfs::windows_name_issue;
+use vfs::WindowsNameIssue;
+use vfs::WINDOWS_MAX_PATH;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PathProblemKind {
+    /// The component is a DOS device name, like `con` or `aux.c`.
+    ReservedName(String),
+    /// The component ends with a dot or a space.
+    TrailingDotOrSpace(String),
+    /// The path is at least `WINDOWS_MAX_PATH` UTF-16 units long once joined
+    /// to the working copy root. Some tools can't open such files.
+    TooLong(usize),
+}
+
+impl PathProblemKind {
+    /// Whether this fails the checkout on Windows, unless reserved names
+    /// are allowed.
+    pub fn is_name_problem(&self) -> bool {
+        !matches!(self, PathProblemKind::TooLong(_))
+    }
+}
+
+impl fmt::Display for PathProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathProblemKind::ReservedName(name) => write!(f, "reserved name {:?}", name),
+            PathProblemKind::TrailingDotOrSpace(name) => {
+                write!(f, "trailing dot or space in {:?}", name)
+            }
+            PathProblemKind::TooLong(len) => write!(f, "{} characters long", len),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathProblem {
+    pub path: RepoPathBuf,
+    pub kind: PathProblemKind,
+}
+
+/// The checkout plan writes names Windows would write somewhere else.
+#[derive(Debug)]
+pub struct WindowsPathError {
+    pub problems: Vec<PathProblem>,
+}
+
+impl fmt::Display for WindowsPathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "checkout would write paths Windows can't represent:")?;
+        for problem in &self.problems {
+            write!(f, " ({}: {})", problem.path, problem.kind)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for WindowsPathError {}
+
+/// Returns the problems of every path in `paths`, in order. A path has at
+/// most one name problem, for its first problematic component.
+pub(crate) fn find_path_problems<'a>(
+    root: &Path,
+    paths: impl Iterator<Item = &'a RepoPathBuf>,
+) -> Vec<PathProblem> {
+    // The separator after the root.
+    let root_len = root.to_string_lossy().encode_utf16().count() + 1;
+    let mut problems = Vec::new();
+    for path in paths {
+        let name_problem = path.components().find_map(|c| {
+            let name = c.as_str().to_string();
+            windows_name_issue(c.as_str()).map(|issue| match issue {
+                WindowsNameIssue::ReservedName => PathProblemKind::ReservedName(name),
+                WindowsNameIssue::TrailingDotOrSpace => PathProblemKind::TrailingDotOrSpace(name),
+            })
+        });
+        if let Some(kind) = name_problem {
+            problems.push(PathProblem {
+                path: path.clone(),
+                kind,
+            });
+        }
+        let len = root_len + path.as_str().encode_utf16().count();
+        if len >= WINDOWS_MAX_PATH {
+            problems.push(PathProblem {
+                path: path.clone(),
+                kind: PathProblemKind::TooLong(len),
+            });
+        }
+    }
+    problems
+}
+
+/// Fails with every name problem in `problems`, if they can't be written.
+pub(crate) fn check_path_problems(
+    problems: &[PathProblem],
+    allow_reserved_names: bool,
+) -> Result<(), WindowsPathError> {
+    if !cfg!(windows) || allow_reserved_names {
+        return Ok(());
+    }
+    let problems: Vec<_> = problems
+        .iter()
+        .filter(|p| p.kind.is_name_problem())
+        .cloned()
+        .collect();
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(WindowsPathError { problems })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rp(p: &str) -> RepoPathBuf {
+        RepoPathBuf::from_string(p.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_find_path_problems() {
+        let long = format!("dir/{}", "x".repeat(300));
+        let paths = vec![
+            rp("src/main.rs"),
+            rp("src/aux.c"),
+            rp("CON/readme"),
+            rp("docs/notes."),
+            rp("trailing /space"),
+            rp("nul"),
+            rp(&long),
+            rp("console.txt"),
+        ];
+        let problems = find_path_problems(Path::new("/repo"), paths.iter());
+        let expected = vec![
+            (
+                "src/aux.c",
+                PathProblemKind::ReservedName("aux.c".to_string()),
+            ),
+            (
+                "CON/readme",
+                PathProblemKind::ReservedName("CON".to_string()),
+            ),
+            (
+                "docs/notes.",
+                PathProblemKind::TrailingDotOrSpace("notes.".to_string()),
+            ),
+            (
+                "trailing /space",
+                PathProblemKind::TrailingDotOrSpace("trailing ".to_string()),
+            ),
+            ("nul", PathProblemKind::ReservedName("nul".to_string())),
+            (long.as_str(), PathProblemKind::TooLong(310)),
+        ];
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(path, kind)| PathProblem {
+                path: rp(path),
+                kind,
+            })
+            .collect();
+        assert_eq!(problems, expected);
+
+        // The root counts towards the length.
+        let path = rp(&"y".repeat(250));
+        assert!(find_path_problems(Path::new("/r"), [&path].into_iter()).is_empty());
+        assert_eq!(
+            find_path_problems(Path::new("/longer/root"), [&path].into_iter())[0].kind,
+            PathProblemKind::TooLong(263)
+        );
+    }
+
+    #[test]
+    fn test_check_path_problems() {
+        let problems = find_path_problems(
+            Path::new("/repo"),
+            [rp("a/aux.c"), rp(&"z".repeat(300))].iter(),
+        );
+        assert_eq!(problems.len(), 2);
+        assert!(check_path_problems(&problems, true).is_ok());
+        let result = check_path_problems(&problems, false);
+        if cfg!(windows) {
+            // Long paths are written with extended-length paths.
+            let err = result.unwrap_err();
+            assert_eq!(err.problems, problems[..1]);
+            assert!(err.to_string().contains("a/aux.c"));
+        } else {
+            assert!(result.is_ok());
+        }
+    }
+}
@@ -0,0 +1,295 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use pathmatcher::Matcher;
+use tracing::warn;
+use types::RepoPath;
+use types::RepoPathBuf;
+use vfs::SavedXattrs;
+use vfs::UpdateFlag;
+use vfs::VFS;
+
+use crate::spawner::run_blocking;
+use crate::spawner::BlockingSpawner;
+use crate::CheckoutError;
+
+/// Files whose POSIX ACL and capabilities are preserved, see
+/// `Checkout::with_preserved_xattrs`.
+#[derive(Clone)]
+pub(crate) struct XattrPaths {
+    pub(crate) matcher: Arc<dyn Matcher + Sync + Send>,
+    pub(crate) strict: bool,
+}
+
+/// Outcome of preserving extended attributes during a checkout.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct XattrStats {
+    /// Files whose attributes were restored after being overwritten.
+    pub restored: usize,
+    /// Files whose attributes could not be read before being overwritten.
+    pub capture_failures: usize,
+    /// Files whose attributes could not be restored.
+    pub apply_failures: usize,
+}
+
+/// Saves the attributes of matching files before they are overwritten, and
+/// restores them after.
+pub(crate) struct XattrPreserver {
+    vfs: VFS,
+    spawner: Arc<dyn BlockingSpawner>,
+    paths: XattrPaths,
+    restored: AtomicUsize,
+    capture_failures: AtomicUsize,
+    apply_failures: AtomicUsize,
+}
+
+/// Attributes of the files of a batch, with the flag they are written with.
+pub(crate) type SavedBatch = Vec<(RepoPathBuf, UpdateFlag, SavedXattrs)>;
+
+impl XattrPreserver {
+    pub(crate) fn new(vfs: VFS, spawner: Arc<dyn BlockingSpawner>, paths: XattrPaths) -> Self {
+        Self {
+            vfs,
+            spawner,
+            paths,
+            restored: AtomicUsize::new(0),
+            capture_failures: AtomicUsize::new(0),
+            apply_failures: AtomicUsize::new(0),
+        }
+    }
+
+    /// Saves the attributes of the files of `files` that match. Files without
+    /// attributes, including new files, are left out.
+    pub(crate) async fn save(
+        &self,
+        files: Vec<(RepoPathBuf, UpdateFlag)>,
+    ) -> Result<SavedBatch, CheckoutError> {
+        let files: Vec<_> = files
+            .into_iter()
+            // Symlinks can't have these attributes.
+            .filter(|(_, flag)| !matches!(flag, UpdateFlag::Symlink))
+            .filter(|(path, _)| self.paths.matcher.matches_file(path).unwrap_or(false))
+            .collect();
+        if files.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let vfs = self.vfs.clone();
+        let strict = self.paths.strict;
+        let (saved, failures) =
+            run_blocking(&*self.spawner, move || -> Result<_, CheckoutError> {
+                let mut saved = Vec::new();
+                let mut failures = 0;
+                for (path, flag) in files {
+                    match vfs.save_xattrs(&path) {
+                        Ok(attrs) if attrs.is_empty() => {}
+                        Ok(attrs) => saved.push((path, flag, attrs)),
+                        Err(source) if strict => {
+                            return Err(CheckoutError::XattrFailed { path, source });
+                        }
+                        Err(e) => {
+                            warn!("Can not save ACL and capabilities of {}: {:#}", path, e);
+                            failures += 1;
+                        }
+                    }
+                }
+                Ok((saved, failures))
+            })
+            .await??;
+        self.capture_failures.fetch_add(failures, Ordering::Relaxed);
+        Ok(saved)
+    }
+
+    /// Restores attributes saved by `save`, after the files were written.
+    pub(crate) async fn restore(&self, saved: SavedBatch) -> Result<(), CheckoutError> {
+        if saved.is_empty() {
+            return Ok(());
+        }
+
+        let vfs = self.vfs.clone();
+        let strict = self.paths.strict;
+        let (restored, failures) =
+            run_blocking(&*self.spawner, move || -> Result<_, CheckoutError> {
+                let mut restored = 0;
+                let mut failures = 0;
+                for (path, flag, attrs) in saved {
+                    match restore_file(&vfs, &path, flag, &attrs) {
+                        Ok(()) => restored += 1,
+                        Err(source) if strict => {
+                            return Err(CheckoutError::XattrFailed { path, source });
+                        }
+                        Err(e) => {
+                            warn!("Can not restore ACL and capabilities of {}: {:#}", path, e);
+                            failures += 1;
+                        }
+                    }
+                }
+                Ok((restored, failures))
+            })
+            .await??;
+        self.restored.fetch_add(restored, Ordering::Relaxed);
+        self.apply_failures.fetch_add(failures, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub(crate) fn stats(&self) -> XattrStats {
+        XattrStats {
+            restored: self.restored.load(Ordering::Relaxed),
+            capture_failures: self.capture_failures.load(Ordering::Relaxed),
+            apply_failures: self.apply_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn restore_file(
+    vfs: &VFS,
+    path: &RepoPath,
+    flag: UpdateFlag,
+    attrs: &SavedXattrs,
+) -> anyhow::Result<()> {
+    vfs.restore_xattrs(path, attrs)?;
+    // The ACL carries the permission bits. Set the exec bit again if the
+    // old ACL did not match it.
+    let exec = matches!(flag, UpdateFlag::Executable);
+    if is_executable(vfs, path)? != exec {
+        vfs.set_executable(path, exec)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn is_executable(vfs: &VFS, path: &RepoPath) -> anyhow::Result<bool> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Ok(vfs.metadata(path)?.permissions().mode() & 0o100 != 0)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_executable(_vfs: &VFS, _path: &RepoPath) -> anyhow::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use anyhow::Result;
+    use futures::stream;
+    use futures::stream::BoxStream;
+    use futures::StreamExt;
+    use manifest::FileMetadata;
+    use manifest::FileType;
+    use minibytes::Bytes;
+    use pathmatcher::AlwaysMatcher;
+    use storemodel::ReadFileContents;
+    use tempfile::TempDir;
+    use types::HgId;
+    use types::Key;
+
+    use super::*;
+    use crate::actions::UpdateAction;
+    use crate::Action;
+    use crate::ActionMap;
+    use crate::Checkout;
+
+    const ACL: &str = "system.posix_acl_access";
+
+    struct ContentStore;
+
+    #[async_trait::async_trait]
+    impl ReadFileContents for ContentStore {
+        type Error = anyhow::Error;
+
+        async fn read_file_contents(&self, keys: Vec<Key>) -> BoxStream<Result<(Bytes, Key)>> {
+            stream::iter(keys)
+                .map(|key| Ok((key.hgid.to_string().into_bytes().into(), key)))
+                .boxed()
+        }
+
+        async fn read_rename_metadata(
+            &self,
+            _keys: Vec<Key>,
+        ) -> BoxStream<Result<(Key, Option<Key>), Self::Error>> {
+            stream::empty().boxed()
+        }
+    }
+
+    fn rp(p: &str) -> RepoPathBuf {
+        RepoPathBuf::from_string(p.to_string()).unwrap()
+    }
+
+    /// Entries (tag, perm, id) of an access ACL in the xattr format.
+    fn acl_entries(acl: &[u8]) -> Vec<(u16, u16, u32)> {
+        acl[4..]
+            .chunks(8)
+            .map(|e| {
+                (
+                    u16::from_le_bytes([e[0], e[1]]),
+                    u16::from_le_bytes([e[2], e[3]]),
+                    u32::from_le_bytes([e[4], e[5], e[6], e[7]]),
+                )
+            })
+            .collect()
+    }
+
+    /// An ACL of a 0644 file that also lets user 12345 read it.
+    fn acl() -> Vec<u8> {
+        const UNDEFINED_ID: u32 = u32::MAX;
+        // POSIX_ACL_XATTR_VERSION
+        let mut acl = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in [
+            (0x01u16, 6u16, UNDEFINED_ID), // ACL_USER_OBJ
+            (0x02, 4, 12345),              // ACL_USER
+            (0x04, 4, UNDEFINED_ID),       // ACL_GROUP_OBJ
+            (0x10, 4, UNDEFINED_ID),       // ACL_MASK
+            (0x20, 4, UNDEFINED_ID),       // ACL_OTHER
+        ] {
+            acl.extend(tag.to_le_bytes());
+            acl.extend(perm.to_le_bytes());
+            acl.extend(id.to_le_bytes());
+        }
+        acl
+    }
+
+    #[tokio::test]
+    async fn test_preserve_acl() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let vfs = VFS::new(tempdir.path().to_path_buf())?;
+        let tool = rp("bin/tool");
+        vfs.write(&tool, b"old", UpdateFlag::Regular)?;
+
+        let mut attrs = SavedXattrs::default();
+        attrs.insert(ACL, acl());
+        if vfs.restore_xattrs(&tool, &attrs).is_err() {
+            // The filesystem of the temporary directory may not support ACLs.
+            return Ok(());
+        }
+
+        let mut map = ActionMap::empty();
+        let meta = FileMetadata::new(HgId::from_byte_array([1; HgId::len()]), FileType::Regular);
+        map.insert(tool.clone(), Action::Update(UpdateAction::new(None, meta)));
+        let plan = Checkout::default_config(vfs.clone())
+            .with_preserved_xattrs(Arc::new(AlwaysMatcher::new()), true)?
+            .plan_action_map(map);
+        let stats = plan.apply_store(&ContentStore).await?;
+
+        assert_eq!(vfs.read(&tool)?.len(), 40);
+        let saved = vfs.save_xattrs(&tool)?;
+        let entries = acl_entries(saved.get(ACL).expect("ACL was preserved"));
+        assert!(entries.contains(&(0x02, 4, 12345)));
+        assert_eq!(
+            stats.xattr_stats(),
+            Some(XattrStats {
+                restored: 1,
+                ..Default::default()
+            })
+        );
+        Ok(())
+    }
+}
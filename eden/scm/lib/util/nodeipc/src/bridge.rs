@@ -0,0 +1,337 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Bridge between `NodeIpc` and tokio channels, for async code structured
+//! around channels.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::mux::Waited;
+use crate::NodeIpc;
+
+/// How often the receiving pump checks whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Messages received but not taken from the `Receiver` yet. Once full, the
+/// receiving pump stops reading from the channel.
+const INBOUND_BUFFER: usize = 16;
+
+/// Errors of the pumps of `NodeIpc::into_channel_pair`.
+#[derive(Debug, thiserror::Error)]
+pub enum IpcBridgeError {
+    /// A received message could not be deserialized. It was skipped.
+    #[error("cannot deserialize received message: {0:#}")]
+    Deserialize(anyhow::Error),
+    /// A message could not be serialized. It was not sent.
+    #[error("cannot serialize message: {0:#}")]
+    Serialize(anyhow::Error),
+    /// Reading from the channel failed. Nothing more is received.
+    #[error("cannot receive: {0:#}")]
+    Recv(anyhow::Error),
+    /// Writing to the channel failed. Nothing more is sent.
+    #[error("cannot send: {0:#}")]
+    Send(anyhow::Error),
+}
+
+#[derive(Default)]
+struct BridgeState {
+    stop: AtomicBool,
+    shutdown: Notify,
+    errors: Mutex<Vec<IpcBridgeError>>,
+}
+
+impl BridgeState {
+    fn record(&self, error: IpcBridgeError) {
+        tracing::warn!("NodeIpc bridge: {}", error);
+        self.errors.lock().unwrap().push(error);
+    }
+}
+
+/// Controls the pumps of `NodeIpc::into_channel_pair`.
+pub struct IpcBridgeHandle {
+    state: Arc<BridgeState>,
+    send_pump: JoinHandle<()>,
+    recv_pump: JoinHandle<()>,
+}
+
+impl IpcBridgeHandle {
+    /// Errors since the last call. Pumps keep running after errors about a
+    /// single message.
+    pub fn take_errors(&self) -> Vec<IpcBridgeError> {
+        std::mem::take(&mut *self.state.errors.lock().unwrap())
+    }
+
+    /// Stops the pumps, after sending messages already in the `Sender`'s
+    /// buffer, and waits for them. Messages sent afterwards fail.
+    pub async fn shutdown(self) -> Vec<IpcBridgeError> {
+        self.state.stop.store(true, Ordering::Release);
+        self.state.shutdown.notify_one();
+        self.join().await
+    }
+
+    /// Waits for the pumps to stop, which they do once both channel halves
+    /// are dropped, or the peer closes the channel. Returns the errors not
+    /// taken yet.
+    pub async fn join(self) -> Vec<IpcBridgeError> {
+        for pump in [self.send_pump, self.recv_pump] {
+            if let Err(e) = pump.await {
+                if e.is_panic() {
+                    std::panic::resume_unwind(e.into_panic());
+                }
+            }
+        }
+        std::mem::take(&mut *self.state.errors.lock().unwrap())
+    }
+}
+
+impl NodeIpc {
+    /// Exposes the channel as a pair of tokio channels: `T`s sent on the
+    /// `Sender` are sent to the peer, and messages from the peer are
+    /// deserialized as `U`s received on the `Receiver`. Sending waits while
+    /// `outbound_buffer` messages are waiting to be written.
+    ///
+    /// Messages that can't be (de)serialized are skipped and reported by
+    /// `IpcBridgeHandle::take_errors`. Multiplexed streams are unaffected.
+    /// Must be called within a tokio runtime.
+    pub fn into_channel_pair<T, U>(
+        self: Arc<Self>,
+        outbound_buffer: usize,
+    ) -> (mpsc::Sender<T>, mpsc::Receiver<U>, IpcBridgeHandle)
+    where
+        T: Serialize + Send + 'static,
+        U: DeserializeOwned + Send + 'static,
+    {
+        let state = Arc::new(BridgeState::default());
+        let (out_tx, out_rx) = mpsc::channel(outbound_buffer);
+        let (in_tx, in_rx) = mpsc::channel(INBOUND_BUFFER);
+        let send_pump = tokio::spawn(send_pump(self.clone(), out_rx, state.clone()));
+        let recv_pump = {
+            let state = state.clone();
+            tokio::task::spawn_blocking(move || recv_pump(&self, in_tx, &state))
+        };
+        let handle = IpcBridgeHandle {
+            state,
+            send_pump,
+            recv_pump,
+        };
+        (out_tx, in_rx, handle)
+    }
+}
+
+async fn send_pump<T: Serialize + Send + 'static>(
+    ipc: Arc<NodeIpc>,
+    mut rx: mpsc::Receiver<T>,
+    state: Arc<BridgeState>,
+) {
+    let mut shutting_down = false;
+    loop {
+        let first = if shutting_down {
+            rx.recv().await
+        } else {
+            tokio::select! {
+                message = rx.recv() => message,
+                _ = state.shutdown.notified() => {
+                    // Only messages already buffered are received from now on.
+                    rx.close();
+                    shutting_down = true;
+                    continue;
+                }
+            }
+        };
+        let mut batch = match first {
+            Some(message) => vec![message],
+            None => return,
+        };
+        while let Ok(message) = rx.try_recv() {
+            batch.push(message);
+        }
+        let ipc = ipc.clone();
+        let state = state.clone();
+        let sent = tokio::task::spawn_blocking(move || send_batch(&ipc, batch, &state)).await;
+        if !matches!(sent, Ok(true)) {
+            return;
+        }
+    }
+}
+
+/// Returns `false` if the channel is broken.
+fn send_batch<T: Serialize>(ipc: &NodeIpc, batch: Vec<T>, state: &BridgeState) -> bool {
+    for message in batch {
+        if let Err(e) = ipc.send(message) {
+            if e.downcast_ref::<serde_json::Error>().is_some() {
+                state.record(IpcBridgeError::Serialize(e));
+            } else {
+                state.record(IpcBridgeError::Send(e));
+                return false;
+            }
+        }
+    }
+    true
+}
+
+fn recv_pump<U: DeserializeOwned>(ipc: &NodeIpc, tx: mpsc::Sender<U>, state: &BridgeState) {
+    while !state.stop.load(Ordering::Acquire) && !tx.is_closed() {
+        let deadline = Instant::now() + POLL_INTERVAL;
+        let line = match ipc.demux.recv_plain_line_matching(ipc, deadline, |_| true) {
+            Ok(Waited::Ready(Some(line))) => line,
+            Ok(Waited::Ready(None)) => return,
+            Ok(Waited::TimedOut) => continue,
+            Err(e) => {
+                state.record(IpcBridgeError::Recv(e));
+                return;
+            }
+        };
+        match serde_json::from_str(&line) {
+            Ok(message) => {
+                if !forward(&tx, message, state) {
+                    return;
+                }
+            }
+            Err(e) => {
+                ipc.counters.deserialize_error();
+                let e = anyhow::Error::new(e).context(format!(
+                    "when deserializing {:?} to {}",
+                    line.trim_end(),
+                    std::any::type_name::<U>()
+                ));
+                state.record(IpcBridgeError::Deserialize(e));
+            }
+        }
+    }
+}
+
+/// Waits for room in the `Receiver`'s buffer, unless the pumps are stopped.
+/// Returns `false` if `message` was not forwarded.
+fn forward<U>(tx: &mpsc::Sender<U>, message: U, state: &BridgeState) -> bool {
+    let runtime = tokio::runtime::Handle::current();
+    loop {
+        match runtime.block_on(tokio::time::timeout(POLL_INTERVAL, tx.reserve())) {
+            Ok(Ok(permit)) => {
+                permit.send(message);
+                return true;
+            }
+            Ok(Err(_closed)) => return false,
+            Err(_elapsed) if state.stop.load(Ordering::Acquire) => return false,
+            Err(_elapsed) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::testutil::ipc_pair;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Ping {
+        n: usize,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Pong {
+        n: usize,
+        reply: String,
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_round_trip() {
+        let (a, b) = ipc_pair();
+        let (a_tx, mut a_rx, a_handle) = Arc::new(a).into_channel_pair::<Ping, Pong>(4);
+        let (b_tx, mut b_rx, b_handle) = Arc::new(b).into_channel_pair::<Pong, Ping>(4);
+
+        let count = 100;
+        let server = tokio::spawn(async move {
+            while let Some(Ping { n }) = b_rx.recv().await {
+                let reply = format!("pong {}", n);
+                b_tx.send(Pong { n, reply }).await.unwrap();
+            }
+        });
+        let client = tokio::spawn(async move {
+            for n in 0..count {
+                a_tx.send(Ping { n }).await.unwrap();
+            }
+        });
+        for n in 0..count {
+            let pong = a_rx.recv().await.unwrap();
+            assert_eq!(pong.n, n);
+            assert_eq!(pong.reply, format!("pong {}", n));
+        }
+        client.await.unwrap();
+
+        assert!(a_handle.shutdown().await.is_empty());
+        // The peer closed the channel.
+        server.await.unwrap();
+        assert!(b_handle.join().await.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_backpressure() {
+        let (a, b) = ipc_pair();
+        let (tx, _rx, handle) = Arc::new(a).into_channel_pair::<String, String>(2);
+
+        // Nothing reads `b`, so the socket buffer fills up, then the
+        // outbound buffer, then sending waits.
+        let big = "x".repeat(64 * 1024);
+        let mut sent = 0;
+        while tokio::time::timeout(Duration::from_millis(500), tx.send(big.clone()))
+            .await
+            .is_ok()
+        {
+            sent += 1;
+            assert!(sent < 1000, "sending never waited");
+        }
+        assert_eq!(tx.capacity(), 0);
+
+        // Reading `b` unblocks sending.
+        let reader = std::thread::spawn(move || {
+            let mut received = 0;
+            while let Ok(Some(message)) = b.recv::<String>() {
+                assert_eq!(message.len(), 64 * 1024);
+                received += 1;
+            }
+            received
+        });
+        tx.send(big.clone()).await.unwrap();
+        tx.send(big).await.unwrap();
+
+        // Shutting down sends what was buffered, then closes the channel.
+        assert!(handle.shutdown().await.is_empty());
+        assert_eq!(reader.join().unwrap(), sent + 2);
+        assert!(tx.send(String::new()).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_deserialize_error() {
+        let (a, b) = ipc_pair();
+        let (tx, mut rx, handle) = Arc::new(a).into_channel_pair::<Ping, Ping>(2);
+        b.send("not a ping").unwrap();
+        b.send(Ping { n: 1 }).unwrap();
+
+        assert_eq!(rx.recv().await, Some(Ping { n: 1 }));
+        let errors = handle.take_errors();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], IpcBridgeError::Deserialize(_)));
+
+        // Dropping both halves stops the pumps.
+        drop(tx);
+        drop(rx);
+        assert!(handle.join().await.is_empty());
+    }
+}
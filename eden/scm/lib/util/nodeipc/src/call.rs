@@ -0,0 +1,594 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Blocking request/response calls with a deadline.
+//!
+//! A call is a plain message `{"__nodeipc_call_id": id, "request": ...}`.
+//! The peer answers with `{"__nodeipc_call_id": id, "response": ...}`, for
+//! example using [`CallRequest::reply`]. Responses are matched by id, so
+//! other plain messages, and late responses to abandoned attempts, do not
+//! confuse the caller.
+//!
+//! Calls started with `NodeIpc::start_call` can be cancelled, see
+//! `NodeIpc::serve_calls`. A cancel is `{"__nodeipc_call_id": id, "type":
+//! "cancel"}`. A request that was cancelled, or whose handler failed, is
+//! answered with `{"__nodeipc_call_id": id, "failure": ...}` instead of a
+//! response.
+
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::de::DeserializeOwned;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::mux::Waited;
+use crate::nodeipc::NodeIpc;
+
+/// Serialized calls and responses start with this.
+const CALL_PREFIX: &str = "{\"__nodeipc_call_id\":";
+
+/// Call ids are unique within the process so a reconnected channel never
+/// sees a reused id.
+static NEXT_CALL_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A request sent by `NodeIpc::call_with_timeout`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CallRequest<T> {
+    // Must be the first field so the serialized form starts with `CALL_PREFIX`.
+    #[serde(rename = "__nodeipc_call_id")]
+    pub id: u64,
+    pub request: T,
+}
+
+/// The response to a `CallRequest` with the same id.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CallResponse<T> {
+    #[serde(rename = "__nodeipc_call_id")]
+    pub id: u64,
+    pub response: T,
+}
+
+/// Sent by `PendingCall::cancel`. Never passed to handlers.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct CallCancel {
+    #[serde(rename = "__nodeipc_call_id")]
+    pub(crate) id: u64,
+    #[serde(rename = "type")]
+    pub(crate) kind: CallControl,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CallControl {
+    Cancel,
+}
+
+/// Sent instead of a `CallResponse` by `NodeIpc::serve_calls`.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct CallFailure {
+    #[serde(rename = "__nodeipc_call_id")]
+    pub(crate) id: u64,
+    pub(crate) failure: Failure,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Failure {
+    Cancelled,
+    Error(String),
+}
+
+/// A call message received by the serving side.
+pub(crate) enum Incoming {
+    Request(u64),
+    Cancel(u64),
+}
+
+impl<T> CallRequest<T> {
+    /// Send the response to this request.
+    pub fn reply(&self, ipc: &NodeIpc, response: impl Serialize) -> anyhow::Result<()> {
+        ipc.send(CallResponse {
+            id: self.id,
+            response,
+        })
+    }
+}
+
+/// What `NodeIpc::call_with_timeout` does if the peer closed the channel or
+/// did not respond in time.
+#[derive(Default)]
+pub struct RetryConfig<'a> {
+    retry: bool,
+    reconnect: Option<Box<dyn FnMut() -> anyhow::Result<Arc<NodeIpc>> + 'a>>,
+}
+
+impl<'a> RetryConfig<'a> {
+    /// Do not retry.
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// Retry once on the same channel.
+    pub fn once() -> Self {
+        Self {
+            retry: true,
+            reconnect: None,
+        }
+    }
+
+    /// Retry once on a fresh channel obtained from `reconnect`. Keep a clone
+    /// of the returned `NodeIpc` to continue using it after the call.
+    pub fn with_reconnect(reconnect: impl FnMut() -> anyhow::Result<Arc<NodeIpc>> + 'a) -> Self {
+        Self {
+            retry: true,
+            reconnect: Some(Box::new(reconnect)),
+        }
+    }
+}
+
+/// Error from `NodeIpc::call_with_timeout`. Tells which phase of the call
+/// failed.
+#[derive(Debug, Error)]
+pub enum NodeIpcError {
+    #[error("NodeIpc call failed when encoding the request")]
+    Encode(#[source] serde_json::Error),
+
+    #[error("NodeIpc call failed when sending the request")]
+    Send(#[source] anyhow::Error),
+
+    #[error("NodeIpc call timed out after {0:?} waiting for the response")]
+    Timeout(Duration),
+
+    #[error("NodeIpc peer closed the channel before responding")]
+    PeerClosed,
+
+    #[error("NodeIpc call failed when receiving the response")]
+    Recv(#[source] anyhow::Error),
+
+    #[error("NodeIpc call failed when decoding the response")]
+    Decode(#[source] serde_json::Error),
+
+    /// The request was cancelled with `PendingCall::cancel` before it was
+    /// handled.
+    #[error("NodeIpc call was cancelled")]
+    Cancelled,
+
+    /// The handler of `NodeIpc::serve_calls` failed.
+    #[error("NodeIpc peer failed to handle the call: {0}")]
+    Remote(String),
+
+    /// The peer exceeded the inbound limits of a channel with the
+    /// `Disconnect` policy, see `NodeIpc::set_inbound_limits`. Also returned
+    /// by `recv`.
+    #[error("NodeIpc peer violated the protocol: {0}")]
+    ProtocolViolation(String),
+
+    #[error("NodeIpc call failed when reconnecting after: {previous}")]
+    Reconnect {
+        previous: Box<NodeIpcError>,
+        #[source]
+        source: anyhow::Error,
+    },
+}
+
+impl NodeIpcError {
+    fn is_retryable(&self) -> bool {
+        matches!(self, Self::Timeout(_) | Self::PeerClosed)
+    }
+}
+
+impl NodeIpc {
+    /// Send `req` and wait up to `timeout` for the response. Blocking.
+    ///
+    /// If the peer closed the channel or did not respond in time, `retry`
+    /// decides whether to try once more, with the same `timeout`.
+    ///
+    /// The peer receives a `CallRequest` and should answer with a
+    /// `CallResponse`. Other plain messages received while waiting are left
+    /// for `recv`.
+    pub fn call_with_timeout<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        req: Req,
+        timeout: Duration,
+        mut retry: RetryConfig,
+    ) -> Result<Resp, NodeIpcError> {
+        let request = serde_json::to_value(req).map_err(|e| {
+            self.counters.serialize_error();
+            NodeIpcError::Encode(e)
+        })?;
+        let mut ids = Vec::new();
+        let response = match self.call_once(&request, timeout, &mut ids) {
+            Err(e) if retry.retry && e.is_retryable() => {
+                tracing::debug!("retrying NodeIpc call: {}", e);
+                match retry.reconnect.as_mut() {
+                    None => self.call_once(&request, timeout, &mut ids),
+                    Some(reconnect) => match reconnect() {
+                        Ok(ipc) => {
+                            let mut retry_ids = Vec::new();
+                            let result = ipc.call_once(&request, timeout, &mut retry_ids);
+                            ipc.abandon_calls(&retry_ids);
+                            result
+                        }
+                        Err(source) => Err(NodeIpcError::Reconnect {
+                            previous: Box::new(e),
+                            source,
+                        }),
+                    },
+                }
+            }
+            result => result,
+        };
+        // Late replies must not be left for `recv`.
+        self.abandon_calls(&ids);
+        let response = response?;
+        serde_json::from_value(response).map_err(|e| {
+            self.counters.deserialize_error();
+            NodeIpcError::Decode(e)
+        })
+    }
+
+    /// Make a single attempt. `ids` are the ids of earlier attempts on this
+    /// channel that were not replied. Late responses to them are discarded.
+    /// The id of this attempt is added to them unless it is replied.
+    fn call_once(
+        &self,
+        request: &Value,
+        timeout: Duration,
+        ids: &mut Vec<u64>,
+    ) -> Result<Value, NodeIpcError> {
+        let deadline = Instant::now() + timeout;
+        let id = NEXT_CALL_ID.fetch_add(1, Ordering::AcqRel);
+        ids.push(id);
+        self.send_request(id, request)?;
+        let result = self.wait_reply(id, ids, deadline, timeout);
+        if is_reply(&result) {
+            ids.pop();
+        }
+        result
+    }
+
+    /// Discard the replies to the calls `ids`, queued or arriving later.
+    fn abandon_calls(&self, ids: &[u64]) {
+        for id in ids {
+            self.demux.abandon_call(self, *id);
+        }
+    }
+
+    fn send_request(&self, id: u64, request: &Value) -> Result<(), NodeIpcError> {
+        self.send(CallRequest { id, request }).map_err(|e| {
+            if is_closed(&e) {
+                NodeIpcError::PeerClosed
+            } else {
+                NodeIpcError::Send(e)
+            }
+        })
+    }
+
+    /// Wait for the reply to `id` until `deadline`. Late replies to the
+    /// other `ids` are discarded.
+    fn wait_reply(
+        &self,
+        id: u64,
+        ids: &[u64],
+        deadline: Instant,
+        timeout: Duration,
+    ) -> Result<Value, NodeIpcError> {
+        loop {
+            let waited = self.demux.recv_plain_line_matching(self, deadline, |line| {
+                reply_id(line).map_or(false, |reply| reply == id || ids.contains(&reply))
+            });
+            let line = match waited {
+                Ok(Waited::Ready(Some(line))) => line,
+                Ok(Waited::Ready(None)) => return Err(NodeIpcError::PeerClosed),
+                Ok(Waited::TimedOut) => return Err(NodeIpcError::Timeout(timeout)),
+                Err(e) if is_closed(&e) => return Err(NodeIpcError::PeerClosed),
+                Err(e) => return Err(NodeIpcError::Recv(e)),
+            };
+            let (reply, result) = decode_reply(&line)?;
+            if reply == id {
+                return result;
+            }
+        }
+    }
+
+    /// Send `req` without waiting for the response. Unlike with
+    /// `call_with_timeout`, the request can be cancelled while waiting.
+    pub fn start_call(&self, req: impl Serialize) -> Result<PendingCall<'_>, NodeIpcError> {
+        let request = serde_json::to_value(req).map_err(|e| {
+            self.counters.serialize_error();
+            NodeIpcError::Encode(e)
+        })?;
+        let id = NEXT_CALL_ID.fetch_add(1, Ordering::AcqRel);
+        self.send_request(id, &request)?;
+        Ok(PendingCall {
+            ipc: self,
+            id,
+            replied: AtomicBool::new(false),
+        })
+    }
+}
+
+/// A request sent by `NodeIpc::start_call`.
+///
+/// Dropping it before the reply was received discards the reply, even if it
+/// arrives later.
+pub struct PendingCall<'a> {
+    ipc: &'a NodeIpc,
+    id: u64,
+    replied: AtomicBool,
+}
+
+impl PendingCall<'_> {
+    /// The id of the `CallRequest`.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Ask the peer to cancel the request. Can be called while another
+    /// thread is in `wait`. If the request is still being handled by
+    /// `NodeIpc::serve_calls`, `wait` returns `NodeIpcError::Cancelled`.
+    /// Otherwise the peer ignores the cancel.
+    pub fn cancel(&self) -> anyhow::Result<()> {
+        self.ipc.send(CallCancel {
+            id: self.id,
+            kind: CallControl::Cancel,
+        })
+    }
+
+    /// Wait up to `timeout` for the reply. Can be called again after
+    /// `NodeIpcError::Timeout`.
+    pub fn wait<Resp: DeserializeOwned>(&self, timeout: Duration) -> Result<Resp, NodeIpcError> {
+        if self.replied.load(Ordering::Acquire) {
+            return Err(NodeIpcError::Recv(anyhow::format_err!(
+                "NodeIpc call {} was already replied",
+                self.id
+            )));
+        }
+        let deadline = Instant::now() + timeout;
+        let result = self.ipc.wait_reply(self.id, &[], deadline, timeout);
+        if is_reply(&result) {
+            self.replied.store(true, Ordering::Release);
+        }
+        serde_json::from_value(result?).map_err(|e| {
+            self.ipc.counters.deserialize_error();
+            NodeIpcError::Decode(e)
+        })
+    }
+}
+
+impl Drop for PendingCall<'_> {
+    fn drop(&mut self) {
+        if !*self.replied.get_mut() {
+            self.ipc.demux.abandon_call(self.ipc, self.id);
+        }
+    }
+}
+
+/// Whether `result` of `wait_reply` is the peer's reply, as opposed to a
+/// failure to receive it.
+fn is_reply(result: &Result<Value, NodeIpcError>) -> bool {
+    matches!(
+        result,
+        Ok(_) | Err(NodeIpcError::Cancelled) | Err(NodeIpcError::Remote(_))
+    )
+}
+
+/// The id of a serialized `CallResponse` or `CallFailure`, or `None` if
+/// `line` is neither.
+pub(crate) fn reply_id(line: &str) -> Option<u64> {
+    if !line.starts_with(CALL_PREFIX) {
+        return None;
+    }
+    if let Ok(response) = serde_json::from_str::<CallResponse<IgnoredAny>>(line) {
+        return Some(response.id);
+    }
+    let failure: CallFailure = serde_json::from_str(line).ok()?;
+    Some(failure.id)
+}
+
+/// Decode a line accepted by `reply_id` into its id and outcome.
+fn decode_reply(line: &str) -> Result<(u64, Result<Value, NodeIpcError>), NodeIpcError> {
+    if let Ok(response) = serde_json::from_str::<CallResponse<Value>>(line) {
+        return Ok((response.id, Ok(response.response)));
+    }
+    let failure: CallFailure = serde_json::from_str(line).map_err(NodeIpcError::Decode)?;
+    let error = match failure.failure {
+        Failure::Cancelled => NodeIpcError::Cancelled,
+        Failure::Error(message) => NodeIpcError::Remote(message),
+    };
+    Ok((failure.id, Err(error)))
+}
+
+/// Whether `line` is a serialized `CallRequest` or `CallCancel`.
+pub(crate) fn incoming(line: &str) -> Option<Incoming> {
+    if !line.starts_with(CALL_PREFIX) {
+        return None;
+    }
+    if let Ok(request) = serde_json::from_str::<CallRequest<IgnoredAny>>(line) {
+        return Some(Incoming::Request(request.id));
+    }
+    let cancel: CallCancel = serde_json::from_str(line).ok()?;
+    Some(Incoming::Cancel(cancel.id))
+}
+
+pub(crate) fn is_closed(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| {
+        if let Some(NodeIpcError::PeerClosed) = e.downcast_ref::<NodeIpcError>() {
+            return true;
+        }
+        e.downcast_ref::<io::Error>().map_or(false, |e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::UnexpectedEof
+            )
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::thread;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::testutil::ipc_pair;
+
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    /// Spawn a peer that ignores the first request and answers the second.
+    fn spawn_flaky_peer(peer: NodeIpc) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let _ignored: Option<CallRequest<Value>> = peer.recv().unwrap();
+            if let Ok(Some(req)) = peer.recv::<CallRequest<Value>>() {
+                peer.send("unrelated").unwrap();
+                let n = req.request["n"].as_u64().unwrap();
+                req.reply(&peer, json!({"n": n + 1})).unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn test_retry_once() {
+        let (a, b) = ipc_pair();
+        let peer = spawn_flaky_peer(b);
+
+        let start = Instant::now();
+        let response: Value = a
+            .call_with_timeout(json!({"n": 1}), TIMEOUT, RetryConfig::once())
+            .unwrap();
+        assert_eq!(response, json!({"n": 2}));
+        assert!(start.elapsed() < TIMEOUT * 2 + Duration::from_secs(1));
+        // Plain messages received while waiting are left for `recv`.
+        let plain: String = a.recv().unwrap().unwrap();
+        assert_eq!(plain, "unrelated");
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_no_retry() {
+        let (a, b) = ipc_pair();
+        let peer = spawn_flaky_peer(b);
+
+        let start = Instant::now();
+        let result: Result<Value, _> =
+            a.call_with_timeout(json!({"n": 1}), TIMEOUT, RetryConfig::none());
+        let elapsed = start.elapsed();
+        assert!(matches!(result, Err(NodeIpcError::Timeout(t)) if t == TIMEOUT));
+        assert!(elapsed >= TIMEOUT);
+        assert!(elapsed < TIMEOUT + Duration::from_secs(1));
+
+        drop(a);
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let (a, b) = ipc_pair();
+        drop(b);
+
+        let mut reconnected = None;
+        let result: Value = a
+            .call_with_timeout(
+                json!({"n": 10}),
+                TIMEOUT,
+                RetryConfig::with_reconnect(|| {
+                    let (a2, b2) = ipc_pair();
+                    thread::spawn(move || {
+                        let req: CallRequest<Value> = b2.recv().unwrap().unwrap();
+                        req.reply(&b2, req.request["n"].as_u64().unwrap() * 2)
+                            .unwrap();
+                        // Keep the channel open until the caller is done.
+                        let _: Option<Value> = b2.recv().unwrap_or(None);
+                    });
+                    let a2 = Arc::new(a2);
+                    reconnected = Some(a2.clone());
+                    Ok(a2)
+                }),
+            )
+            .unwrap();
+        assert_eq!(result, json!(20));
+        assert!(reconnected.is_some());
+
+        let (a, b) = ipc_pair();
+        drop(b);
+        let result: Result<Value, _> = a.call_with_timeout(
+            json!({}),
+            TIMEOUT,
+            RetryConfig::with_reconnect(|| anyhow::bail!("daemon is not running")),
+        );
+        match result {
+            Err(NodeIpcError::Reconnect { previous, .. }) => {
+                assert!(matches!(*previous, NodeIpcError::PeerClosed));
+            }
+            _ => panic!("expected a reconnect error"),
+        }
+    }
+
+    #[test]
+    fn test_abandoned() {
+        let (a, b) = ipc_pair();
+        let peer = thread::spawn(move || {
+            let req: CallRequest<Value> = b.recv().unwrap().unwrap();
+            let cancel: Value = b.recv().unwrap().unwrap();
+            assert_eq!(
+                cancel,
+                json!({"__nodeipc_call_id": req.id, "type": "cancel"})
+            );
+            // Replies after the requester gave up.
+            req.reply(&b, "late").unwrap();
+            b.send("plain").unwrap();
+        });
+
+        let pending = a.start_call(json!({})).unwrap();
+        pending.cancel().unwrap();
+        drop(pending);
+        // The late reply is discarded, not left for `recv`.
+        let plain: String = a.recv().unwrap().unwrap();
+        assert_eq!(plain, "plain");
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_late_reply_after_timeout() {
+        let (a, b) = ipc_pair();
+        let (timed_out_tx, timed_out_rx) = std::sync::mpsc::channel();
+        let peer = thread::spawn(move || {
+            let req: CallRequest<Value> = b.recv().unwrap().unwrap();
+            let reply = format!("{}\n", json!({"__nodeipc_call_id": req.id, "response": 1}));
+            let frame = crate::nodeipc::frame(reply.as_bytes());
+            // Half of the reply does not keep the caller past its deadline.
+            let (head, tail) = frame.split_at(frame.len() / 2);
+            b.w.lock().unwrap().write_all(head).unwrap();
+            timed_out_rx.recv().unwrap();
+            b.w.lock().unwrap().write_all(tail).unwrap();
+            b.send("plain").unwrap();
+        });
+
+        let start = Instant::now();
+        let result: Result<Value, _> = a.call_with_timeout(json!({}), TIMEOUT, RetryConfig::none());
+        assert!(matches!(result, Err(NodeIpcError::Timeout(_))));
+        assert!(start.elapsed() < TIMEOUT + Duration::from_secs(1));
+        timed_out_tx.send(()).unwrap();
+        // The rest of the reply is read, and discarded.
+        let plain: String = a.recv().unwrap().unwrap();
+        assert_eq!(plain, "plain");
+        peer.join().unwrap();
+    }
+}
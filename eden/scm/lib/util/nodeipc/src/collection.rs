@@ -0,0 +1,390 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Sending large collections as a sequence of smaller messages.
+//!
+//! A collection is sent as `{"__nodeipc_collection": {tag, kind: "begin"}}`,
+//! then `{"__nodeipc_collection": {tag, kind: "chunk"}, "items": [...]}`
+//! messages, then `{"__nodeipc_collection": {tag, kind: "end", count}}`. The
+//! end message has an `error` if the sender failed to produce all items.
+//!
+//! The receiver only consumes messages with the tag it asked for, so plain
+//! messages and collections with other tags can be sent in between.
+
+use std::convert::Infallible;
+use std::marker::PhantomData;
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::nodeipc::NodeIpc;
+
+/// Serialized collection messages start with this.
+const COLLECTION_PREFIX: &str = "{\"__nodeipc_collection\":";
+
+#[derive(Serialize, Deserialize)]
+struct CollectionMessage<T> {
+    // Must be the first field so the serialized form starts with `COLLECTION_PREFIX`.
+    #[serde(rename = "__nodeipc_collection")]
+    header: Header,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    items: Option<T>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    tag: String,
+    kind: Kind,
+    /// Number of items sent. Only set on `End`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    count: Option<u64>,
+    /// Why the sender stopped early. Only set on `End`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Begin,
+    Chunk,
+    End,
+}
+
+impl Header {
+    fn new(tag: &str, kind: Kind) -> Self {
+        Self {
+            tag: tag.to_string(),
+            kind,
+            count: None,
+            error: None,
+        }
+    }
+}
+
+impl NodeIpc {
+    /// Send the items of `iter` as the collection `tag`, in messages of up to
+    /// `chunk_size` items. The peer receives them with `recv_iter`. Returns
+    /// the number of items sent.
+    pub fn send_iter<T: Serialize>(
+        &self,
+        tag: &str,
+        iter: impl Iterator<Item = T>,
+        chunk_size: usize,
+    ) -> anyhow::Result<u64> {
+        self.send_try_iter(tag, iter.map(Ok::<T, Infallible>), chunk_size)
+    }
+
+    /// Like `send_iter`, but producing an item may fail. On the first error,
+    /// the collection is ended with the error so the peer's `recv_iter` fails
+    /// too, and the error is returned.
+    pub fn send_try_iter<T: Serialize, E: Into<anyhow::Error>>(
+        &self,
+        tag: &str,
+        iter: impl Iterator<Item = Result<T, E>>,
+        chunk_size: usize,
+    ) -> anyhow::Result<u64> {
+        let chunk_size = chunk_size.max(1);
+        self.send(CollectionMessage::<()> {
+            header: Header::new(tag, Kind::Begin),
+            items: None,
+        })?;
+
+        let mut count = 0;
+        let mut chunk = Vec::with_capacity(chunk_size);
+        let mut failure = None;
+        for item in iter {
+            let value = item.map_err(Into::into).and_then(|item| {
+                serde_json::to_value(item)
+                    .map_err(|e| {
+                        self.counters.serialize_error();
+                        e
+                    })
+                    .context("in NodeIpc::send_iter, when converting item to JSON")
+            });
+            match value {
+                Ok(value) => chunk.push(value),
+                Err(e) => {
+                    failure = Some(e);
+                    break;
+                }
+            }
+            if chunk.len() >= chunk_size {
+                count += chunk.len() as u64;
+                self.send_chunk(tag, &mut chunk)?;
+            }
+        }
+        if !chunk.is_empty() {
+            count += chunk.len() as u64;
+            self.send_chunk(tag, &mut chunk)?;
+        }
+
+        let mut header = Header::new(tag, Kind::End);
+        header.count = Some(count);
+        header.error = failure.as_ref().map(|e| format!("{:#}", e));
+        self.send(CollectionMessage::<()> {
+            header,
+            items: None,
+        })?;
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(count),
+        }
+    }
+
+    fn send_chunk(&self, tag: &str, chunk: &mut Vec<Value>) -> anyhow::Result<()> {
+        self.send(CollectionMessage {
+            header: Header::new(tag, Kind::Chunk),
+            items: Some(&chunk[..]),
+        })?;
+        chunk.clear();
+        Ok(())
+    }
+
+    /// Receive the collection `tag` sent by the peer's `send_iter`. Items are
+    /// yielded as their chunks arrive. Other messages stay queued for `recv`
+    /// or other `recv_iter`s.
+    ///
+    /// The iterator yields an error, then stops, if the channel is closed
+    /// before the end of the collection, if fewer or more items than the
+    /// sender counted were received, or if the sender failed.
+    pub fn recv_iter<T: DeserializeOwned>(
+        &self,
+        tag: &str,
+    ) -> impl Iterator<Item = anyhow::Result<T>> + '_ {
+        CollectionIter {
+            ipc: self,
+            tag: tag.to_string(),
+            started: false,
+            done: false,
+            received: 0,
+            items: Vec::new().into_iter(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+struct CollectionIter<'a, T> {
+    ipc: &'a NodeIpc,
+    tag: String,
+    started: bool,
+    done: bool,
+    /// Number of items received so far, yielded or not.
+    received: u64,
+    /// Items of the last chunk not yielded yet.
+    items: std::vec::IntoIter<Value>,
+    phantom: PhantomData<fn() -> T>,
+}
+
+impl<'a, T: DeserializeOwned> CollectionIter<'a, T> {
+    /// Receive the next message of the collection. Returns the items of a
+    /// chunk, or `None` at the end.
+    fn recv_message(&mut self) -> anyhow::Result<Option<Vec<Value>>> {
+        let tag = self.tag.as_str();
+        let line = self
+            .ipc
+            .demux
+            .recv_plain_line_where(self.ipc, |line| message_tag(line).as_deref() == Some(tag))
+            .context("in NodeIpc::recv_iter, when reading line from file descriptor")?;
+        let line = match line {
+            Some(line) => line,
+            None => anyhow::bail!(
+                "NodeIpc collection {:?} was truncated: the channel was closed after {} items",
+                tag,
+                self.received
+            ),
+        };
+        let message: CollectionMessage<Vec<Value>> = serde_json::from_str(&line)
+            .map_err(|e| {
+                self.ipc.counters.deserialize_error();
+                e
+            })
+            .context("in NodeIpc::recv_iter, when parsing collection message")?;
+        let header = message.header;
+
+        match (self.started, header.kind) {
+            (false, Kind::Begin) => {
+                self.started = true;
+                Ok(Some(Vec::new()))
+            }
+            (false, kind) => anyhow::bail!(
+                "NodeIpc collection {:?} did not begin before {:?}",
+                tag,
+                kind
+            ),
+            (true, Kind::Begin) => anyhow::bail!(
+                "NodeIpc collection {:?} was truncated: it began again after {} items",
+                tag,
+                self.received
+            ),
+            (true, Kind::Chunk) => {
+                let items = message.items.unwrap_or_default();
+                self.received += items.len() as u64;
+                Ok(Some(items))
+            }
+            (true, Kind::End) => {
+                if let Some(error) = header.error {
+                    anyhow::bail!(
+                        "NodeIpc collection {:?} failed on the sending side after {} items: {}",
+                        tag,
+                        self.received,
+                        error
+                    );
+                }
+                let count = header.count.unwrap_or_default();
+                anyhow::ensure!(
+                    count == self.received,
+                    "NodeIpc collection {:?} was truncated: {} items were sent, {} received",
+                    tag,
+                    count,
+                    self.received
+                );
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl<'a, T: DeserializeOwned> Iterator for CollectionIter<'a, T> {
+    type Item = anyhow::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(value) = self.items.next() {
+                let result = serde_json::from_value(value).map_err(|e| {
+                    self.ipc.counters.deserialize_error();
+                    e
+                });
+                return Some(result.with_context(|| {
+                    format!(
+                        "in NodeIpc::recv_iter, when deserializing to {}",
+                        std::any::type_name::<T>(),
+                    )
+                }));
+            }
+            if self.done {
+                return None;
+            }
+            match self.recv_message() {
+                Ok(Some(items)) => self.items = items.into_iter(),
+                Ok(None) => self.done = true,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// The tag of a serialized `CollectionMessage`, or `None` if `line` is not one.
+fn message_tag(line: &str) -> Option<String> {
+    if !line.starts_with(COLLECTION_PREFIX) {
+        return None;
+    }
+    let message: CollectionMessage<IgnoredAny> = serde_json::from_str(line).ok()?;
+    Some(message.header.tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::testutil::ipc_pair;
+
+    fn send_message(
+        ipc: &NodeIpc,
+        tag: &str,
+        kind: Kind,
+        items: Option<Value>,
+        count: Option<u64>,
+    ) {
+        let mut header = Header::new(tag, kind);
+        header.count = count;
+        ipc.send(CollectionMessage { header, items }).unwrap();
+    }
+
+    #[test]
+    fn test_send_recv_iter() -> anyhow::Result<()> {
+        let (a, b) = ipc_pair();
+        let sender = thread::spawn(move || {
+            let items = (0..10000u32).inspect(|&i| {
+                if i == 5000 {
+                    a.send("unrelated").unwrap();
+                }
+            });
+            a.send_iter("paths", items, 100).unwrap()
+        });
+
+        let items: Vec<u32> = b.recv_iter("paths").collect::<anyhow::Result<_>>()?;
+        assert_eq!(items, (0..10000).collect::<Vec<_>>());
+        assert_eq!(sender.join().unwrap(), 10000);
+        // The unrelated message is left for `recv`.
+        assert_eq!(b.recv::<String>()?.as_deref(), Some("unrelated"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_end() -> anyhow::Result<()> {
+        let (a, b) = ipc_pair();
+        send_message(&a, "paths", Kind::Begin, None, None);
+        send_message(&a, "paths", Kind::Chunk, Some(json!([1, 2])), None);
+        drop(a);
+
+        let mut iter = b.recv_iter::<u32>("paths");
+        assert_eq!(iter.next().unwrap()?, 1);
+        assert_eq!(iter.next().unwrap()?, 2);
+        let err = iter.next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{}", err);
+        assert!(iter.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_mismatch() -> anyhow::Result<()> {
+        let (a, b) = ipc_pair();
+        send_message(&a, "paths", Kind::Begin, None, None);
+        send_message(&a, "paths", Kind::Chunk, Some(json!([1, 2])), None);
+        send_message(&a, "paths", Kind::End, None, Some(3));
+
+        let result: anyhow::Result<Vec<u32>> = b.recv_iter("paths").collect();
+        let err = result.unwrap_err();
+        assert!(
+            err.to_string().contains("3 items were sent, 2 received"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_failure() -> anyhow::Result<()> {
+        let (a, b) = ipc_pair();
+        let items = vec![Ok(1), Ok(2), Err(anyhow::anyhow!("source failed")), Ok(3)];
+        let result = a.send_try_iter("paths", items.into_iter(), 1);
+        assert!(result.is_err());
+        // Collections with other tags are not affected.
+        a.send_iter("other", [4, 5].into_iter(), 10)?;
+
+        let other: Vec<u32> = b.recv_iter("other").collect::<anyhow::Result<_>>()?;
+        assert_eq!(other, vec![4, 5]);
+        let received: Vec<_> = b.recv_iter::<u32>("paths").collect();
+        assert_eq!(received.len(), 3);
+        assert_eq!(received[0].as_ref().unwrap(), &1);
+        assert_eq!(received[1].as_ref().unwrap(), &2);
+        let err = received[2].as_ref().unwrap_err();
+        assert!(err.to_string().contains("source failed"), "{}", err);
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Per-message compression of large messages.
+//!
+//! A compressed message is sent in place of the JSON line as
+//! `COMPRESSED_MARKER`, a format byte, and the compressed line. JSON text
+//! never starts with a NUL byte, so a peer that did not enable compression
+//! fails with [`CompressionError::NotEnabled`] instead of parsing garbage.
+//!
+//! Compressed messages are decompressed up to a maximum size, so that a
+//! small message can't expand to exhaust the memory of the receiver.
+//!
+//! Compression needs the frame headers to delimit the binary payload, so it
+//! is disabled in libuv compatibility mode, where the peer expects plain
+//! JSON lines.
+
+use std::io;
+use std::io::Read;
+
+use anyhow::Context;
+use thiserror::Error;
+
+use crate::nodeipc::NodeIpc;
+
+/// First byte of a compressed message.
+const COMPRESSED_MARKER: u8 = 0;
+
+/// Format byte of zstd compressed messages.
+const FORMAT_ZSTD: u8 = 1;
+
+/// zstd level. Messages are compressed on the sending thread, so favor
+/// speed.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Default maximum size of a decompressed message.
+pub(crate) const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 256 << 20;
+
+#[derive(Debug, Error)]
+pub enum CompressionError {
+    #[error("NodeIpc received a compressed message, but compression is not enabled")]
+    NotEnabled,
+
+    #[error("NodeIpc received a message compressed with unknown format {0}")]
+    UnknownFormat(u8),
+
+    #[error("NodeIpc received a compressed message larger than {0} bytes")]
+    TooLarge(usize),
+}
+
+/// Compress `line` if it is at least `threshold` bytes long.
+pub(crate) fn compress(line: &str, threshold: usize) -> io::Result<Option<Vec<u8>>> {
+    if line.len() < threshold {
+        return Ok(None);
+    }
+    let mut payload = vec![COMPRESSED_MARKER, FORMAT_ZSTD];
+    zstd::stream::copy_encode(line.as_bytes(), &mut payload, ZSTD_LEVEL)?;
+    Ok(Some(payload))
+}
+
+pub(crate) fn is_compressed(payload: &[u8]) -> bool {
+    payload.first() == Some(&COMPRESSED_MARKER)
+}
+
+/// Decompress a payload accepted by `is_compressed` back to its line, if it
+/// is at most `max_size` bytes long.
+pub(crate) fn decompress(payload: &[u8], max_size: usize) -> anyhow::Result<String> {
+    let format = payload.get(1).copied().unwrap_or_default();
+    if format != FORMAT_ZSTD {
+        return Err(CompressionError::UnknownFormat(format).into());
+    }
+    let decoder = zstd::stream::Decoder::new(&payload[2..])
+        .context("in NodeIpc::recv, when decompressing message")?;
+    let mut line = Vec::new();
+    // Read one byte more than allowed to tell a message of exactly
+    // `max_size` bytes from a larger one.
+    decoder
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut line)
+        .context("in NodeIpc::recv, when decompressing message")?;
+    if line.len() > max_size {
+        return Err(CompressionError::TooLarge(max_size).into());
+    }
+    String::from_utf8(line).context("in NodeIpc::recv, when decompressing message")
+}
+
+impl NodeIpc {
+    /// Compress messages of at least `threshold` bytes, including the
+    /// trailing newline. The peer must enable compression too. Smaller
+    /// messages are sent as they are.
+    ///
+    /// Ignored in libuv compatibility mode.
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.compression_threshold = Some(threshold);
+        self
+    }
+
+    /// Reject compressed messages larger than `max_size` bytes once
+    /// decompressed, 256MB by default.
+    pub fn with_max_decompressed_size(mut self, max_size: usize) -> Self {
+        self.max_decompressed_size = max_size;
+        self
+    }
+
+    /// Whether messages are compressed, see `with_compression`.
+    pub(crate) fn compression_threshold(&self) -> Option<usize> {
+        if self.libuv_compat {
+            None
+        } else {
+            self.compression_threshold
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use filedescriptor::IntoRawFileDescriptor;
+    use serde::Deserialize;
+    use serde::Serialize;
+
+    use super::*;
+    use crate::testutil::ipc_pair;
+    use crate::IpcStats;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+    struct Entry {
+        path: String,
+        status: String,
+    }
+
+    fn status(len: usize) -> Vec<Entry> {
+        (0..len)
+            .map(|i| Entry {
+                path: format!("some/directory/file{}.txt", i % 100),
+                status: "modified".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_large_message() {
+        let (a, b) = ipc_pair();
+        let (a, b) = (a.with_compression(1024), b.with_compression(1024));
+        let message = status(100_000);
+        let len = serde_json::to_string(&message).unwrap().len() as u64 + 1;
+        assert!(len > 5_000_000);
+
+        let sender = {
+            let message = message.clone();
+            thread::spawn(move || {
+                a.send(message).unwrap();
+                a
+            })
+        };
+        let received: Vec<Entry> = b.recv().unwrap().unwrap();
+        assert_eq!(received, message);
+
+        let a_stats = sender.join().unwrap().stats();
+        assert_eq!(a_stats.compressed_sent, 1);
+        assert_eq!(a_stats.compression_bytes_before, len);
+        assert_eq!(a_stats.compression_bytes_after, a_stats.bytes_sent);
+        assert!(a_stats.bytes_sent < len / 50, "{:?}", a_stats);
+        let b_stats = b.stats();
+        assert_eq!(b_stats.compressed_received, 1);
+        assert_eq!(b_stats.bytes_received, a_stats.bytes_sent);
+    }
+
+    #[test]
+    fn test_threshold() {
+        let (a, b) = ipc_pair();
+        let (a, b) = (a.with_compression(1024), b.with_compression(1024));
+        let small = status(5);
+        a.send(&small).unwrap();
+        let received: Vec<Entry> = b.recv().unwrap().unwrap();
+        assert_eq!(received, small);
+
+        let len = serde_json::to_string(&small).unwrap().len() as u64 + 1;
+        assert_eq!(
+            a.stats(),
+            IpcStats {
+                messages_sent: 1,
+                bytes_sent: len,
+                last_send_duration: a.stats().last_send_duration,
+                ..Default::default()
+            }
+        );
+        assert_eq!(b.stats().compressed_received, 0);
+    }
+
+    #[test]
+    fn test_not_enabled() {
+        let (a, b) = ipc_pair();
+        let a = a.with_compression(0);
+        a.send("compressed").unwrap();
+        let err = b.recv::<String>().unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<CompressionError>(),
+                Some(CompressionError::NotEnabled)
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_too_large() {
+        let (a, b) = ipc_pair();
+        let a = a.with_compression(0);
+        let b = b.with_compression(0).with_max_decompressed_size(1 << 20);
+        let line = "x".repeat(1 << 20);
+        a.send(&line[..(1 << 20) - 3]).unwrap();
+        // The quotes and newline make it exactly 1MB.
+        assert_eq!(b.recv::<String>().unwrap().unwrap().len(), (1 << 20) - 3);
+
+        a.send(&line).unwrap();
+        assert!(a.stats().bytes_sent < 1 << 12);
+        let err = b.recv::<String>().unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<CompressionError>(),
+                Some(CompressionError::TooLarge(1_048_576))
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_libuv_compat() {
+        let (a, b) = filedescriptor::socketpair().unwrap();
+        let a = NodeIpc::from_raw_file_descriptor(a.into_raw_file_descriptor()).unwrap();
+        let b = NodeIpc::from_raw_file_descriptor(b.into_raw_file_descriptor()).unwrap();
+        let (a, b) = (
+            a.with_compression(0).with_libuv_compat(),
+            b.with_libuv_compat(),
+        );
+
+        // Sent as a plain JSON line.
+        a.send("hello").unwrap();
+        assert_eq!(b.recv::<String>().unwrap().unwrap(), "hello");
+        assert_eq!(a.stats().compressed_sent, 0);
+    }
+}
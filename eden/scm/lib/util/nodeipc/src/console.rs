@@ -0,0 +1,373 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Terminal attributes of the stdio adopted by `NodeIpc::recv_stdio`.
+//!
+//! Code that caches terminal attributes (pager, progress bars) needs to
+//! re-initialize when the stdio is replaced. `recv_stdio` returns the
+//! attributes of the adopted stdio, and calls the hook registered with
+//! `set_stdio_change_hook`. On Unix, the hook is also called on `SIGWINCH`
+//! while an adopted terminal is active.
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use filedescriptor::RawFileDescriptor;
+
+type StdioChangeHook = Arc<dyn Fn(&AdoptedStdioInfo) + Send + Sync>;
+
+static HOOK: Mutex<Option<StdioChangeHook>> = Mutex::new(None);
+
+/// The stdio adopted by the last `recv_stdio`, if one of them is a terminal.
+#[cfg(unix)]
+static ADOPTED: Mutex<Option<[RawFileDescriptor; 3]>> = Mutex::new(None);
+
+/// Terminal attributes of stdin, stdout and stderr after `recv_stdio`.
+#[derive(Clone, Copy, Debug)]
+pub struct AdoptedStdioInfo {
+    pub stdin: TerminalInfo,
+    pub stdout: TerminalInfo,
+    pub stderr: TerminalInfo,
+}
+
+/// Terminal attributes of a single stream.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TerminalInfo {
+    /// Whether the stream is a terminal (a console on Windows).
+    pub is_tty: bool,
+    /// The window size, if the stream is a terminal that reports it. On
+    /// Windows, only output streams do.
+    pub size: Option<TerminalSize>,
+    pub mode: Option<TerminalMode>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TerminalSize {
+    pub columns: u16,
+    pub rows: u16,
+}
+
+/// The termios settings on Unix, or the console mode on Windows.
+#[derive(Clone, Copy)]
+pub struct TerminalMode {
+    #[cfg(unix)]
+    termios: libc::termios,
+    #[cfg(windows)]
+    console_mode: u32,
+}
+
+impl TerminalMode {
+    /// Whether input is line-buffered ("cooked"), as opposed to raw.
+    /// On Windows, only meaningful for stdin.
+    pub fn is_canonical(&self) -> bool {
+        #[cfg(unix)]
+        return self.termios.c_lflag & libc::ICANON != 0;
+        #[cfg(windows)]
+        return self.console_mode & winapi::um::wincon::ENABLE_LINE_INPUT != 0;
+    }
+
+    /// Whether input is echoed. On Windows, only meaningful for stdin.
+    pub fn is_echo(&self) -> bool {
+        #[cfg(unix)]
+        return self.termios.c_lflag & libc::ECHO != 0;
+        #[cfg(windows)]
+        return self.console_mode & winapi::um::wincon::ENABLE_ECHO_INPUT != 0;
+    }
+
+    #[cfg(unix)]
+    pub fn termios(&self) -> &libc::termios {
+        &self.termios
+    }
+
+    #[cfg(windows)]
+    pub fn console_mode(&self) -> u32 {
+        self.console_mode
+    }
+}
+
+impl fmt::Debug for TerminalMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TerminalMode")
+            .field("canonical", &self.is_canonical())
+            .field("echo", &self.is_echo())
+            .finish()
+    }
+}
+
+impl AdoptedStdioInfo {
+    fn is_tty(&self) -> bool {
+        self.stdin.is_tty || self.stdout.is_tty || self.stderr.is_tty
+    }
+}
+
+/// Call `hook` after `recv_stdio` replaced the stdio, and on Unix, when the
+/// adopted terminal is resized. Replaces the previous hook.
+///
+/// The hook is called from the thread calling `recv_stdio`, or for resizes,
+/// from a dedicated thread.
+pub fn set_stdio_change_hook(hook: impl Fn(&AdoptedStdioInfo) + Send + Sync + 'static) {
+    *HOOK.lock().unwrap() = Some(Arc::new(hook));
+}
+
+/// Record `fds` as the adopted stdio, and notify the hook of their
+/// attributes.
+pub(crate) fn adopted(fds: [RawFileDescriptor; 3]) -> AdoptedStdioInfo {
+    let info = query(fds);
+    #[cfg(unix)]
+    {
+        *ADOPTED.lock().unwrap() = info.is_tty().then_some(fds);
+        if info.is_tty() {
+            if let Err(e) = sigwinch::install() {
+                tracing::warn!("cannot watch terminal size changes: {:?}", e);
+            }
+        }
+    }
+    fire(&info);
+    info
+}
+
+fn fire(info: &AdoptedStdioInfo) {
+    // Do not hold the lock while running the hook, which might replace it.
+    let hook = HOOK.lock().unwrap().clone();
+    if let Some(hook) = hook {
+        hook(info);
+    }
+}
+
+fn query(fds: [RawFileDescriptor; 3]) -> AdoptedStdioInfo {
+    AdoptedStdioInfo {
+        stdin: terminal_info(fds[0]),
+        stdout: terminal_info(fds[1]),
+        stderr: terminal_info(fds[2]),
+    }
+}
+
+#[cfg(unix)]
+fn terminal_info(fd: RawFileDescriptor) -> TerminalInfo {
+    use std::mem;
+
+    if unsafe { libc::isatty(fd) } != 1 {
+        return TerminalInfo::default();
+    }
+    let mut winsize: libc::winsize = unsafe { mem::zeroed() };
+    let size =
+        (unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut winsize) } == 0).then(|| TerminalSize {
+            columns: winsize.ws_col,
+            rows: winsize.ws_row,
+        });
+    let mut termios: libc::termios = unsafe { mem::zeroed() };
+    let mode =
+        (unsafe { libc::tcgetattr(fd, &mut termios) } == 0).then(|| TerminalMode { termios });
+    TerminalInfo {
+        is_tty: true,
+        size,
+        mode,
+    }
+}
+
+#[cfg(windows)]
+fn terminal_info(handle: RawFileDescriptor) -> TerminalInfo {
+    use std::mem;
+
+    use winapi::um::consoleapi::GetConsoleMode;
+    use winapi::um::wincon::GetConsoleScreenBufferInfo;
+
+    let mut console_mode = 0;
+    if handle.is_null() || unsafe { GetConsoleMode(handle as _, &mut console_mode) } == 0 {
+        return TerminalInfo::default();
+    }
+    // Only output handles have a screen buffer.
+    let mut buffer_info = unsafe { mem::zeroed() };
+    let size =
+        (unsafe { GetConsoleScreenBufferInfo(handle as _, &mut buffer_info) } != 0).then(|| {
+            let window = buffer_info.srWindow;
+            TerminalSize {
+                columns: (window.Right - window.Left + 1) as u16,
+                rows: (window.Bottom - window.Top + 1) as u16,
+            }
+        });
+    TerminalInfo {
+        is_tty: true,
+        size,
+        mode: Some(TerminalMode { console_mode }),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn terminal_info(_fd: RawFileDescriptor) -> TerminalInfo {
+    TerminalInfo::default()
+}
+
+/// Re-reads the attributes of the adopted stdio when the terminal is
+/// resized. The signal handler writes to a pipe, read by a thread that
+/// calls the hook.
+#[cfg(unix)]
+mod sigwinch {
+    use std::io;
+    use std::mem;
+    use std::sync::atomic::AtomicI32;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+
+    use once_cell::sync::OnceCell;
+
+    use super::fire;
+    use super::query;
+    use super::ADOPTED;
+
+    static INSTALLED: OnceCell<()> = OnceCell::new();
+    static PIPE_WRITE: AtomicI32 = AtomicI32::new(-1);
+    /// The handler replaced by ours, called from ours if it is a function.
+    static PREVIOUS: AtomicUsize = AtomicUsize::new(libc::SIG_DFL);
+
+    pub(super) fn install() -> io::Result<()> {
+        INSTALLED.get_or_try_init(|| {
+            let mut fds = [-1; 2];
+            if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            for fd in fds {
+                unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            }
+            // Do not block the handler if the thread is behind.
+            unsafe { libc::fcntl(fds[1], libc::F_SETFL, libc::O_NONBLOCK) };
+            let read_fd = fds[0];
+            thread::Builder::new()
+                .name("nodeipc-sigwinch".to_string())
+                .spawn(move || watch(read_fd))?;
+            PIPE_WRITE.store(fds[1], Ordering::Release);
+
+            let mut action: libc::sigaction = unsafe { mem::zeroed() };
+            action.sa_sigaction = on_sigwinch as extern "C" fn(libc::c_int) as usize;
+            action.sa_flags = libc::SA_RESTART;
+            let mut previous: libc::sigaction = unsafe { mem::zeroed() };
+            if unsafe { libc::sigaction(libc::SIGWINCH, &action, &mut previous) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Handlers taking a `siginfo_t` can't be called with just the
+            // signal number.
+            if previous.sa_flags & libc::SA_SIGINFO == 0 {
+                PREVIOUS.store(previous.sa_sigaction, Ordering::Release);
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    extern "C" fn on_sigwinch(signal: libc::c_int) {
+        let fd = PIPE_WRITE.load(Ordering::Acquire);
+        if fd >= 0 {
+            let byte = 0u8;
+            unsafe { libc::write(fd, &byte as *const u8 as *const _, 1) };
+        }
+        let previous = PREVIOUS.load(Ordering::Acquire);
+        if previous != libc::SIG_DFL && previous != libc::SIG_IGN {
+            let previous: extern "C" fn(libc::c_int) = unsafe { mem::transmute(previous) };
+            previous(signal);
+        }
+    }
+
+    fn watch(read_fd: libc::c_int) {
+        let mut buf = [0u8; 64];
+        loop {
+            let n = unsafe { libc::read(read_fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+            if n < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            if n <= 0 {
+                return;
+            }
+            let fds = match *ADOPTED.lock().unwrap() {
+                Some(fds) => fds,
+                None => continue,
+            };
+            let info = query(fds);
+            if !info.is_tty() {
+                // The adopted terminal is gone.
+                *ADOPTED.lock().unwrap() = None;
+                continue;
+            }
+            fire(&info);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::ptr;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn set_size(fd: libc::c_int, columns: u16, rows: u16) {
+        let winsize = libc::winsize {
+            ws_row: rows,
+            ws_col: columns,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        assert_eq!(unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &winsize) }, 0);
+    }
+
+    #[test]
+    fn test_adopted_pty() {
+        let (mut master, mut slave) = (-1, -1);
+        let ret = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        assert_eq!(ret, 0);
+        set_size(master, 120, 40);
+
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        set_stdio_change_hook(move |info| {
+            let _ = tx.lock().unwrap().send(info.stdout.size);
+        });
+
+        let info = adopted([slave, slave, slave]);
+        assert!(info.stdout.is_tty);
+        assert!(info.stdin.mode.unwrap().is_canonical());
+        let size = TerminalSize {
+            columns: 120,
+            rows: 40,
+        };
+        assert_eq!(info.stdout.size, Some(size));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(10)), Ok(Some(size)));
+
+        // Resizes are noticed while the pty is adopted.
+        set_size(master, 80, 24);
+        unsafe { libc::raise(libc::SIGWINCH) };
+        let size = TerminalSize {
+            columns: 80,
+            rows: 24,
+        };
+        assert_eq!(rx.recv_timeout(Duration::from_secs(10)), Ok(Some(size)));
+
+        // Files are not terminals.
+        let file = tempfile::tempfile().unwrap();
+        let fd = std::os::unix::io::AsRawFd::as_raw_fd(&file);
+        let info = adopted([fd, fd, fd]);
+        assert!(!info.stdout.is_tty);
+        assert_eq!(info.stdout.size, None);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(10)), Ok(None));
+
+        *HOOK.lock().unwrap() = None;
+        unsafe {
+            libc::close(master);
+            libc::close(slave);
+        }
+    }
+}
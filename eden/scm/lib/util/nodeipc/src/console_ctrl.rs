@@ -0,0 +1,243 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Console control events of the console adopted by `NodeIpc::recv_stdio`
+//! on Windows.
+//!
+//! Once `recv_stdio` attached to the sender's console, Ctrl-C, Ctrl-Break
+//! and closing the console are delivered to this process as console control
+//! events. They are passed to the hook registered with
+//! `set_console_ctrl_hook`, and sent as
+//! `{"__nodeipc_console_ctrl": event}` on the channel registered with
+//! `set_console_ctrl_channel`, so the sender can coordinate the shutdown.
+//! Without either, the events are handled by default, which terminates the
+//! process.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::nodeipc::NodeIpc;
+
+type ConsoleCtrlHook = Arc<dyn Fn(ConsoleCtrlEvent) + Send + Sync>;
+
+static HOOK: Mutex<Option<ConsoleCtrlHook>> = Mutex::new(None);
+static CHANNEL: Mutex<Option<Arc<NodeIpc>>> = Mutex::new(None);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsoleCtrlEvent {
+    CtrlC,
+    CtrlBreak,
+    /// The console is being closed. The process is terminated once the hook
+    /// returns.
+    Close,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ConsoleCtrlMessage {
+    #[serde(rename = "__nodeipc_console_ctrl")]
+    event: ConsoleCtrlEvent,
+}
+
+impl ConsoleCtrlEvent {
+    /// The event sent by the adopting process, if `message` is one.
+    pub fn from_message(message: &Value) -> Option<Self> {
+        message.get("__nodeipc_console_ctrl")?;
+        ConsoleCtrlMessage::deserialize(message)
+            .ok()
+            .map(|m| m.event)
+    }
+}
+
+/// Call `hook` on console control events. Replaces the previous hook.
+///
+/// The hook is called from a thread started by Windows for the event.
+pub fn set_console_ctrl_hook(hook: impl Fn(ConsoleCtrlEvent) + Send + Sync + 'static) {
+    *HOOK.lock().unwrap() = Some(Arc::new(hook));
+}
+
+/// Send console control events on `ipc`, typically the channel stdio was
+/// received on. `None` stops sending them.
+pub fn set_console_ctrl_channel(ipc: Option<Arc<NodeIpc>>) {
+    *CHANNEL.lock().unwrap() = ipc;
+}
+
+/// Pass `event` to the hook and the channel. Returns whether any of them
+/// handled it.
+#[cfg(any(windows, test))]
+fn dispatch(event: ConsoleCtrlEvent) -> bool {
+    // Do not hold the locks while running the hook, which might replace it.
+    let hook = HOOK.lock().unwrap().clone();
+    let channel = CHANNEL.lock().unwrap().clone();
+    if let Some(channel) = &channel {
+        if let Err(e) = channel.send(ConsoleCtrlMessage { event }) {
+            tracing::warn!("cannot forward console control event: {:?}", e);
+        }
+    }
+    if let Some(hook) = &hook {
+        hook(event);
+    }
+    hook.is_some() || channel.is_some()
+}
+
+#[cfg(windows)]
+pub(crate) use windows::install;
+
+#[cfg(windows)]
+mod windows {
+    use std::io;
+
+    use once_cell::sync::OnceCell;
+    use winapi::shared::minwindef::BOOL;
+    use winapi::shared::minwindef::DWORD;
+    use winapi::shared::minwindef::FALSE;
+    use winapi::shared::minwindef::TRUE;
+    use winapi::um::consoleapi::SetConsoleCtrlHandler;
+    use winapi::um::wincon::CTRL_BREAK_EVENT;
+    use winapi::um::wincon::CTRL_CLOSE_EVENT;
+    use winapi::um::wincon::CTRL_C_EVENT;
+
+    use super::dispatch;
+    use super::ConsoleCtrlEvent;
+
+    static INSTALLED: OnceCell<()> = OnceCell::new();
+
+    /// Install the handler, once per process. It stays installed across
+    /// consoles.
+    pub(crate) fn install() -> io::Result<()> {
+        INSTALLED.get_or_try_init(|| {
+            // A process started with Ctrl-C disabled would otherwise
+            // ignore it in the adopted console.
+            unsafe { SetConsoleCtrlHandler(None, FALSE) };
+            if unsafe { SetConsoleCtrlHandler(Some(on_ctrl), TRUE) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    unsafe extern "system" fn on_ctrl(ctrl_type: DWORD) -> BOOL {
+        let event = match ctrl_type {
+            CTRL_C_EVENT => ConsoleCtrlEvent::CtrlC,
+            CTRL_BREAK_EVENT => ConsoleCtrlEvent::CtrlBreak,
+            CTRL_CLOSE_EVENT => ConsoleCtrlEvent::Close,
+            _ => return FALSE,
+        };
+        if dispatch(event) {
+            TRUE
+        } else {
+            FALSE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testutil::ipc_pair;
+
+    #[test]
+    fn test_dispatch() {
+        let (ipc, peer) = ipc_pair();
+        assert!(!dispatch(ConsoleCtrlEvent::CtrlC));
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        set_console_ctrl_hook({
+            let events = events.clone();
+            move |event| events.lock().unwrap().push(event)
+        });
+        set_console_ctrl_channel(Some(Arc::new(ipc)));
+        assert!(dispatch(ConsoleCtrlEvent::CtrlBreak));
+        assert_eq!(*events.lock().unwrap(), [ConsoleCtrlEvent::CtrlBreak]);
+
+        let message: Value = peer.recv().unwrap().unwrap();
+        assert_eq!(message, json!({"__nodeipc_console_ctrl": "ctrl_break"}));
+        assert_eq!(
+            ConsoleCtrlEvent::from_message(&message),
+            Some(ConsoleCtrlEvent::CtrlBreak)
+        );
+        assert_eq!(ConsoleCtrlEvent::from_message(&json!({"a": 1})), None);
+
+        set_console_ctrl_channel(None);
+        *HOOK.lock().unwrap() = None;
+    }
+
+    /// Run by `test_ctrl_break` in a child process sharing its console.
+    #[cfg(windows)]
+    #[test]
+    fn console_ctrl_child() {
+        use std::io::Write;
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        if std::env::var_os(CHILD_ENV).is_none() {
+            return;
+        }
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        set_console_ctrl_hook(move |event| {
+            let _ = tx.lock().unwrap().send(event);
+        });
+        install().unwrap();
+        println!("ready");
+        std::io::stdout().flush().unwrap();
+        let event = rx.recv_timeout(Duration::from_secs(30)).unwrap();
+        println!("event: {:?}", event);
+    }
+
+    #[cfg(windows)]
+    const CHILD_ENV: &str = "NODEIPC_TEST_CONSOLE_CTRL_CHILD";
+
+    #[cfg(windows)]
+    #[test]
+    fn test_ctrl_break() {
+        use std::io::BufRead;
+        use std::io::BufReader;
+        use std::io::Read;
+        use std::os::windows::process::CommandExt;
+        use std::process::Command;
+        use std::process::Stdio;
+
+        use winapi::um::winbase::CREATE_NEW_PROCESS_GROUP;
+        use winapi::um::wincon::GenerateConsoleCtrlEvent;
+        use winapi::um::wincon::CTRL_BREAK_EVENT;
+
+        // In its own process group, so that only the child gets the event.
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "console_ctrl::tests::console_ctrl_child",
+                "--nocapture",
+            ])
+            .env(CHILD_ENV, "1")
+            .creation_flags(CREATE_NEW_PROCESS_GROUP)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        while !line.starts_with("ready") {
+            line.clear();
+            assert_ne!(stdout.read_line(&mut line).unwrap(), 0, "child exited");
+        }
+
+        let ok = unsafe { GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, child.id()) };
+        assert_ne!(ok, 0);
+        let mut rest = String::new();
+        stdout.read_to_string(&mut rest).unwrap();
+        // The child survived the event, and exits normally.
+        assert!(child.wait().unwrap().success());
+        assert!(rest.contains("event: CtrlBreak"), "{}", rest);
+    }
+}
@@ -0,0 +1,492 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Per-message encryption, for channels forwarded between machines.
+//!
+//! `NodeIpc::with_encryption` starts with a handshake. Each side sends a
+//! hello with a random salt, and derives a key per direction from the
+//! pre-shared key and both salts with HKDF-SHA256. Each side then sends a
+//! key confirmation, encrypted like the messages after it, which a peer with
+//! another pre-shared key fails to decrypt. A peer without encryption
+//! answers a hello it receives with a refusal, so the handshake fails
+//! without waiting for the timeout.
+//!
+//! An encrypted message is sent as `ENCRYPTED_MARKER`, a format byte, a
+//! counter, and the message sealed with ChaCha20-Poly1305. The counter is
+//! the nonce, and the header is authenticated, so messages that were
+//! tampered with, truncated, reordered or replayed are rejected with
+//! `NodeIpcError::ProtocolViolation`. The message is compressed before it
+//! is encrypted, see `with_compression`.
+//!
+//! Like compression, encryption needs the frame headers, so it is not
+//! supported in libuv compatibility mode. File descriptors can't be sent on
+//! encrypted channels.
+
+use std::io::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Context;
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::aead::Payload;
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::Key;
+use chacha20poly1305::KeyInit;
+use chacha20poly1305::Nonce;
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+use thiserror::Error;
+
+use crate::call::NodeIpcError;
+use crate::mux::Waited;
+use crate::nodeipc::frame;
+use crate::nodeipc::read_frame_until;
+use crate::nodeipc::NodeIpc;
+
+/// First byte of encrypted messages and hellos. Shared with compressed
+/// messages, which have another format byte.
+const ENCRYPTED_MARKER: u8 = 0;
+
+/// Format byte of the handshake hello.
+const FORMAT_HELLO: u8 = 0x10;
+
+/// Format byte of messages sealed with ChaCha20-Poly1305.
+const FORMAT_SEALED: u8 = 0x11;
+
+/// Format byte of the answer of a peer without encryption to a hello.
+const FORMAT_REFUSED: u8 = 0x12;
+
+/// Version of the handshake, sent in the hello.
+const HANDSHAKE_VERSION: u8 = 1;
+
+const SALT_LEN: usize = 32;
+
+/// Marker, format, and counter.
+const SEALED_HEADER_LEN: usize = 2 + std::mem::size_of::<u64>();
+
+/// Sealed with counter 0, by both sides, to confirm they have the same key.
+const KEY_CONFIRMATION: &[u8] = b"nodeipc key confirmation";
+
+/// How long to wait for the handshake of the peer.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub enum EncryptionError {
+    #[error("NodeIpc received an encrypted message, but encryption is not enabled")]
+    NotEnabled,
+
+    #[error("NodeIpc peer did not enable encryption")]
+    PeerNotEncrypted,
+
+    #[error("NodeIpc peer did not send its encryption handshake within {0:?}")]
+    HandshakeTimeout(Duration),
+
+    #[error("NodeIpc peer uses encryption handshake version {0}")]
+    UnknownVersion(u8),
+
+    #[error("NodeIpc peer has a different pre-shared key")]
+    KeyMismatch,
+
+    #[error("NodeIpc encryption is not supported in libuv compatibility mode")]
+    LibuvCompat,
+
+    #[error("NodeIpc cannot send or receive file descriptors on an encrypted channel")]
+    SendFdRefused,
+}
+
+/// Whether `payload` is a hello or an encrypted message.
+pub(crate) fn is_encrypted(payload: &[u8]) -> bool {
+    payload.first() == Some(&ENCRYPTED_MARKER)
+        && matches!(payload.get(1), Some(&FORMAT_HELLO) | Some(&FORMAT_SEALED))
+}
+
+/// Whether `payload` is a hello.
+pub(crate) fn is_hello(payload: &[u8]) -> bool {
+    payload.starts_with(&[ENCRYPTED_MARKER, FORMAT_HELLO])
+}
+
+/// Keys and counters of an encrypted channel.
+pub(crate) struct Encryption {
+    send: ChaCha20Poly1305,
+    recv: ChaCha20Poly1305,
+    send_counter: AtomicU64,
+    recv_counter: AtomicU64,
+}
+
+impl Encryption {
+    /// Derive the keys of the side that sent the hello with `salt`. The key
+    /// of each direction depends on the salt of its sender.
+    fn derive(psk: &[u8], salt: &[u8; SALT_LEN], peer_salt: &[u8; SALT_LEN]) -> Self {
+        let (first, second) = if salt < peer_salt {
+            (salt, peer_salt)
+        } else {
+            (peer_salt, salt)
+        };
+        let hkdf = Hkdf::<Sha256>::new(Some(&[&first[..], &second[..]].concat()), psk);
+        let key = |sender_salt: &[u8]| {
+            let mut key = Key::default();
+            hkdf.expand_multi_info(&[&b"nodeipc message key "[..], sender_salt], &mut key)
+                .expect("32 bytes is a valid length for HKDF-SHA256");
+            ChaCha20Poly1305::new(&key)
+        };
+        Self {
+            send: key(salt),
+            recv: key(peer_salt),
+            send_counter: AtomicU64::new(0),
+            recv_counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Encrypt the next message sent.
+    pub(crate) fn seal(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let counter = self.send_counter.fetch_add(1, Ordering::Relaxed);
+        let mut payload = Vec::with_capacity(SEALED_HEADER_LEN + data.len() + 16);
+        payload.extend_from_slice(&[ENCRYPTED_MARKER, FORMAT_SEALED]);
+        payload.extend_from_slice(&counter.to_be_bytes());
+        let sealed = self
+            .send
+            .encrypt(
+                &nonce(counter),
+                Payload {
+                    msg: data,
+                    aad: &payload,
+                },
+            )
+            .map_err(|_| anyhow::format_err!("in NodeIpc::send, when encrypting message"))?;
+        payload.extend_from_slice(&sealed);
+        Ok(payload)
+    }
+
+    /// Decrypt the next message received. Messages must be received in the
+    /// order they were sent, once.
+    pub(crate) fn open(&self, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if payload.len() < SEALED_HEADER_LEN
+            || payload[0] != ENCRYPTED_MARKER
+            || payload[1] != FORMAT_SEALED
+        {
+            return Err(EncryptionError::PeerNotEncrypted.into());
+        }
+        let (header, sealed) = payload.split_at(SEALED_HEADER_LEN);
+        let counter = u64::from_be_bytes(header[2..].try_into().unwrap());
+        let expected = self.recv_counter.load(Ordering::Relaxed);
+        if counter != expected {
+            return Err(NodeIpcError::ProtocolViolation(format!(
+                "sent encrypted message {} instead of {}",
+                counter, expected
+            ))
+            .into());
+        }
+        let data = self
+            .recv
+            .decrypt(
+                &nonce(counter),
+                Payload {
+                    msg: sealed,
+                    aad: header,
+                },
+            )
+            .map_err(|_| {
+                NodeIpcError::ProtocolViolation(format!(
+                    "sent encrypted message {} that failed authentication",
+                    counter
+                ))
+            })?;
+        self.recv_counter.store(expected + 1, Ordering::Relaxed);
+        Ok(data)
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+impl NodeIpc {
+    /// Encrypt messages with keys derived from `psk`, the pre-shared key,
+    /// after a handshake with the peer. The peer must call `with_encryption`
+    /// too, at the same time, with the same key. Fails if it does not, or
+    /// uses another key.
+    ///
+    /// Messages received out of order, replayed, or tampered with fail with
+    /// `NodeIpcError::ProtocolViolation`. `send_fd_vec` and `recv_fd_vec`
+    /// fail with `EncryptionError::SendFdRefused`.
+    ///
+    /// Not supported in libuv compatibility mode.
+    pub fn with_encryption(self, psk: &[u8]) -> anyhow::Result<Self> {
+        self.with_encryption_within(psk, HANDSHAKE_TIMEOUT)
+    }
+
+    /// Like `with_encryption`, failing if the handshake is not done within
+    /// `timeout`.
+    fn with_encryption_within(mut self, psk: &[u8], timeout: Duration) -> anyhow::Result<Self> {
+        let deadline = Instant::now() + timeout;
+        if self.libuv_compat {
+            return Err(EncryptionError::LibuvCompat.into());
+        }
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let mut hello = vec![ENCRYPTED_MARKER, FORMAT_HELLO, HANDSHAKE_VERSION];
+        hello.extend_from_slice(&salt);
+        self.write_handshake(&hello)?;
+
+        let peer_hello = self.read_handshake(deadline, timeout)?;
+        let peer_salt: [u8; SALT_LEN] = match peer_hello.as_slice() {
+            [ENCRYPTED_MARKER, FORMAT_HELLO, HANDSHAKE_VERSION, salt @ ..] => salt
+                .try_into()
+                .map_err(|_| NodeIpcError::ProtocolViolation("sent a bad hello".to_string()))?,
+            [ENCRYPTED_MARKER, FORMAT_HELLO, version, ..] => {
+                return Err(EncryptionError::UnknownVersion(*version).into());
+            }
+            _ => return Err(EncryptionError::PeerNotEncrypted.into()),
+        };
+        if peer_salt == salt {
+            // Our own hello, reflected.
+            return Err(NodeIpcError::ProtocolViolation("sent our own hello".to_string()).into());
+        }
+
+        let encryption = Encryption::derive(psk, &salt, &peer_salt);
+        self.write_handshake(&encryption.seal(KEY_CONFIRMATION)?)?;
+        let confirmation = self.read_handshake(deadline, timeout)?;
+        match encryption.open(&confirmation) {
+            Ok(data) if data == KEY_CONFIRMATION => {}
+            _ => return Err(EncryptionError::KeyMismatch.into()),
+        }
+        self.encryption = Some(encryption);
+        Ok(self)
+    }
+
+    fn write_handshake(&self, payload: &[u8]) -> anyhow::Result<()> {
+        let mut w = self.w.lock().unwrap();
+        w.write_all(&frame(payload))
+            .context("in NodeIpc::with_encryption, when sending handshake")?;
+        Ok(())
+    }
+
+    /// Reads a handshake frame, failing if it is not complete at `deadline`,
+    /// `timeout` after the handshake started.
+    fn read_handshake(&self, deadline: Instant, timeout: Duration) -> anyhow::Result<Vec<u8>> {
+        let mut r = self.r.lock().unwrap();
+        let partial = &mut *self.partial_read.lock().unwrap();
+        match read_frame_until(r.get_mut(), partial, Some(deadline))
+            .context("in NodeIpc::with_encryption, when receiving handshake")?
+        {
+            Waited::Ready(Some(payload)) => Ok(payload),
+            Waited::Ready(None) => Err(NodeIpcError::PeerClosed.into()),
+            Waited::TimedOut => Err(EncryptionError::HandshakeTimeout(timeout).into()),
+        }
+    }
+
+    /// Answer the hello of a peer when encryption is not enabled. Errors are
+    /// ignored: the peer fails its handshake either way.
+    pub(crate) fn refuse_hello(&self) {
+        let mut w = self.w.lock().unwrap();
+        let _ = w.write_all(&frame(&[ENCRYPTED_MARKER, FORMAT_REFUSED]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::testutil::ipc_pair;
+
+    const PSK: &[u8] = b"correct horse battery staple";
+
+    /// Runs the handshake on both ends of a channel, with `psk_a` and
+    /// `psk_b`.
+    fn encrypted_pair(
+        psk_a: &'static [u8],
+        psk_b: &'static [u8],
+    ) -> (anyhow::Result<NodeIpc>, anyhow::Result<NodeIpc>) {
+        let (a, b) = ipc_pair();
+        let a = thread::spawn(move || a.with_encryption(psk_a));
+        let b = b.with_encryption(psk_b);
+        (a.join().unwrap(), b)
+    }
+
+    fn assert_violation(err: anyhow::Error) {
+        assert!(
+            matches!(
+                err.downcast_ref::<NodeIpcError>(),
+                Some(NodeIpcError::ProtocolViolation(_))
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    /// Writes `payloads` as they are to `ipc`'s peer.
+    fn write_raw(ipc: &NodeIpc, payloads: &[&[u8]]) {
+        let mut w = ipc.w.lock().unwrap();
+        for payload in payloads {
+            w.write_all(&frame(payload)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (a, b) = encrypted_pair(PSK, PSK);
+        let (a, b) = (a.unwrap(), b.unwrap());
+        let b = b.with_compression(64);
+        let a = a.with_compression(64);
+
+        a.send("src/secret/path.rs").unwrap();
+        assert_eq!(b.recv::<String>().unwrap().unwrap(), "src/secret/path.rs");
+        let long = "commit message ".repeat(100);
+        b.send(&long).unwrap();
+        b.send(1).unwrap();
+        assert_eq!(a.recv::<String>().unwrap().unwrap(), long);
+        assert_eq!(a.recv::<u32>().unwrap().unwrap(), 1);
+        assert_eq!(b.stats().compressed_sent, 1);
+
+        let err = a.send_fd_vec(&[]).unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<EncryptionError>(),
+                Some(EncryptionError::SendFdRefused)
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_key_mismatch() {
+        let (a, b) = encrypted_pair(PSK, b"wrong");
+        for result in [a, b] {
+            let err = result.err().unwrap();
+            assert!(
+                matches!(
+                    err.downcast_ref::<EncryptionError>(),
+                    Some(EncryptionError::KeyMismatch)
+                ),
+                "{:?}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_one_sided() {
+        let (a, b) = ipc_pair();
+        let start = Instant::now();
+        let a = thread::spawn(move || a.with_encryption(PSK));
+        let err = b.recv::<String>().unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<EncryptionError>(),
+                Some(EncryptionError::NotEnabled)
+            ),
+            "{:?}",
+            err
+        );
+        // The peer refused the hello, so `a` fails without waiting for the
+        // handshake timeout, while `b` is still open.
+        let err = a.join().unwrap().err().unwrap();
+        assert!(
+            matches!(
+                err.downcast_ref::<EncryptionError>(),
+                Some(EncryptionError::PeerNotEncrypted)
+            ),
+            "{:?}",
+            err
+        );
+        assert!(start.elapsed() < HANDSHAKE_TIMEOUT);
+        drop(b);
+
+        // The peer sends plain messages.
+        let (a, b) = ipc_pair();
+        b.send("hello").unwrap();
+        let err = a.with_encryption(PSK).err().unwrap();
+        assert!(
+            matches!(
+                err.downcast_ref::<EncryptionError>(),
+                Some(EncryptionError::PeerNotEncrypted)
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_partial_handshake() {
+        let (a, b) = ipc_pair();
+        let hello = frame(&[ENCRYPTED_MARKER, FORMAT_HELLO, HANDSHAKE_VERSION]);
+        b.w.lock()
+            .unwrap()
+            .write_all(&hello[..hello.len() - 1])
+            .unwrap();
+
+        // The peer stops in the middle of its hello.
+        let timeout = Duration::from_millis(100);
+        let start = Instant::now();
+        let err = a.with_encryption_within(PSK, timeout).err().unwrap();
+        assert!(
+            matches!(
+                err.downcast_ref::<EncryptionError>(),
+                Some(EncryptionError::HandshakeTimeout(_))
+            ),
+            "{:?}",
+            err
+        );
+        assert!(start.elapsed() < HANDSHAKE_TIMEOUT);
+        drop(b);
+    }
+
+    #[test]
+    fn test_tamper() {
+        let (a, b) = encrypted_pair(PSK, PSK);
+        let (a, b) = (a.unwrap(), b.unwrap());
+        let mut sealed = a.encryption.as_ref().unwrap().seal(b"\"one\"\n").unwrap();
+        *sealed.last_mut().unwrap() ^= 1;
+        write_raw(&a, &[&sealed]);
+        assert_violation(b.recv::<String>().unwrap_err());
+
+        // Truncated.
+        let (a, b) = encrypted_pair(PSK, PSK);
+        let (a, b) = (a.unwrap(), b.unwrap());
+        let sealed = a.encryption.as_ref().unwrap().seal(b"\"one\"\n").unwrap();
+        write_raw(&a, &[&sealed[..sealed.len() - 1]]);
+        assert_violation(b.recv::<String>().unwrap_err());
+
+        // Plain messages are not accepted either.
+        write_raw(&a, &[b"\"two\"\n"]);
+        let err = b.recv::<String>().unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<EncryptionError>(),
+                Some(EncryptionError::PeerNotEncrypted)
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_replay() {
+        let (a, b) = encrypted_pair(PSK, PSK);
+        let (a, b) = (a.unwrap(), b.unwrap());
+        let encryption = a.encryption.as_ref().unwrap();
+        let one = encryption.seal(b"\"one\"\n").unwrap();
+        write_raw(&a, &[&one, &one]);
+        assert_eq!(b.recv::<String>().unwrap().unwrap(), "one");
+        assert_violation(b.recv::<String>().unwrap_err());
+
+        // Reordered.
+        let two = encryption.seal(b"\"two\"\n").unwrap();
+        let three = encryption.seal(b"\"three\"\n").unwrap();
+        write_raw(&a, &[&three, &two]);
+        assert_violation(b.recv::<String>().unwrap_err());
+        assert_eq!(b.recv::<String>().unwrap().unwrap(), "two");
+    }
+}
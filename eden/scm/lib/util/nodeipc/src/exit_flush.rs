@@ -0,0 +1,243 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Flushing channels before the process exits, so messages sent right
+//! before exiting, like final status updates, reach the peer.
+//!
+//! Messages are written straight to the file descriptor, so a message can
+//! only be lost if another thread is still writing it when the process
+//! exits, or, on Windows, if the pipe is closed before the peer read it.
+//! Flushing waits for both, but never longer than a deadline, so a wedged
+//! peer cannot block the exit.
+
+use std::sync::Arc;
+use std::sync::TryLockError;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use filedescriptor::RawFileDescriptor;
+use once_cell::sync::OnceCell;
+
+use crate::nodeipc::NodeIpc;
+use crate::panic_report;
+use crate::singleton::IPC;
+
+/// How long `register_exit_flush` waits for the channels at exit.
+const EXIT_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often locks held by other threads are retried.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+static REGISTERED: OnceCell<()> = OnceCell::new();
+
+impl NodeIpc {
+    /// Wait until messages being sent by other threads are written, and on
+    /// Windows until the peer read them, for at most `timeout`. Returns
+    /// `false` if they were not in time.
+    pub fn flush(&self, timeout: Duration) -> bool {
+        self.flush_until(Instant::now() + timeout)
+    }
+
+    fn flush_until(&self, deadline: Instant) -> bool {
+        let fd = loop {
+            match self.w.try_lock() {
+                Ok(w) => break w.fd(),
+                // The thread writing panicked, the rest of its message is
+                // lost anyway.
+                Err(TryLockError::Poisoned(w)) => break w.into_inner().fd(),
+                Err(TryLockError::WouldBlock) => {
+                    if Instant::now() >= deadline {
+                        return false;
+                    }
+                    thread::sleep(LOCK_RETRY_INTERVAL);
+                }
+            }
+        };
+        match fd {
+            Some(fd) => flush_fd(fd, deadline.saturating_duration_since(Instant::now())),
+            // Loopback channels hold what was written until it is read.
+            None => true,
+        }
+    }
+}
+
+/// Flush the channels of this process: the one returned by `get_singleton`
+/// and the one panics are reported on, within `timeout` overall. Returns
+/// `false` if any was not flushed in time.
+///
+/// Channels are not closed, so this can be called several times, and while
+/// other threads still use them.
+pub fn flush_all_channels(timeout: Duration) -> bool {
+    flush_channels(&channels(), Instant::now() + timeout)
+}
+
+/// Flush the channels with `flush_all_channels` when the process exits
+/// normally: returning from `main` or calling `std::process::exit`. Panics
+/// are reported and flushed by `install_panic_reporter`.
+///
+/// Registering again has no effect.
+pub fn register_exit_flush() {
+    REGISTERED.get_or_init(|| {
+        // Registration only fails if the C runtime is out of memory, then
+        // exits are not flushed.
+        unsafe { libc::atexit(flush_at_exit) };
+    });
+}
+
+extern "C" fn flush_at_exit() {
+    // Unwinding out of an `extern "C"` function aborts.
+    let _ = std::panic::catch_unwind(|| flush_all_channels(EXIT_FLUSH_TIMEOUT));
+}
+
+fn channels() -> Vec<Arc<NodeIpc>> {
+    let mut channels = Vec::new();
+    // Do not initialize the singleton, nothing was sent on it. Another
+    // thread initializing it at exit is not waited for.
+    if let Ok(ipc) = IPC.try_read() {
+        if let Some(Some(ipc)) = &*ipc {
+            channels.push(ipc.clone());
+        }
+    }
+    if let Some(ipc) = panic_report::channel() {
+        if !channels.iter().any(|c| Arc::ptr_eq(c, &ipc)) {
+            channels.push(ipc);
+        }
+    }
+    channels
+}
+
+pub(crate) fn flush_channels(channels: &[Arc<NodeIpc>], deadline: Instant) -> bool {
+    // Keep flushing the others after one timed out, they might still make
+    // it.
+    channels
+        .iter()
+        .fold(true, |flushed, ipc| ipc.flush_until(deadline) && flushed)
+}
+
+/// Wait until the peer read everything written to `fd`.
+#[cfg(windows)]
+fn flush_fd(fd: RawFileDescriptor, timeout: Duration) -> bool {
+    use std::sync::mpsc;
+
+    use winapi::um::fileapi::FlushFileBuffers;
+
+    // This blocks until the peer reads, so it runs on its own thread, left
+    // behind if the peer does not.
+    let handle = fd as usize;
+    let (tx, rx) = mpsc::sync_channel(1);
+    let spawned = thread::Builder::new()
+        .name("nodeipc-flush".to_string())
+        .spawn(move || {
+            // Fails for sockets, which keep unread data after the process
+            // exits.
+            unsafe { FlushFileBuffers(handle as _) };
+            let _ = tx.send(());
+        });
+    spawned.is_ok() && rx.recv_timeout(timeout).is_ok()
+}
+
+/// The data written to `fd` is kept by the OS after the process exits.
+#[cfg(not(windows))]
+fn flush_fd(_fd: RawFileDescriptor, _timeout: Duration) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::ipc_pair;
+
+    #[test]
+    fn test_flush_wedged_peer() {
+        let (a, b) = ipc_pair();
+        let a = Arc::new(a);
+        assert!(flush_channels(&[a.clone()], Instant::now()));
+
+        // Larger than the socket buffer, and never read by `b`.
+        let writer = thread::spawn({
+            let a = a.clone();
+            move || a.send("x".repeat(16 << 20))
+        });
+        while a.w.try_lock().is_ok() {
+            thread::sleep(LOCK_RETRY_INTERVAL);
+        }
+
+        let start = Instant::now();
+        assert!(!a.flush(Duration::from_millis(100)));
+        assert!(!flush_channels(
+            &[a.clone(), a.clone()],
+            Instant::now() + Duration::from_millis(100)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        drop(b);
+        assert!(writer.join().unwrap().is_err());
+        assert!(a.flush(Duration::from_millis(100)));
+    }
+
+    #[cfg(unix)]
+    const CHILD_ENV: &str = "NODEIPC_TEST_EXIT_FLUSH_CHILD";
+
+    /// Messages sent by `exit_flush_child`, besides the large one.
+    #[cfg(unix)]
+    const MESSAGES: usize = 10;
+
+    /// Large enough to block until the parent reads.
+    #[cfg(unix)]
+    const LARGE_LEN: usize = 4 << 20;
+
+    /// Run by `test_exit_flush` in a child process. Exits while another
+    /// thread is still sending a message.
+    #[cfg(unix)]
+    #[test]
+    fn exit_flush_child() {
+        let mode = match std::env::var(CHILD_ENV) {
+            Ok(mode) => mode,
+            Err(_) => return,
+        };
+        let ipc = crate::get_singleton().unwrap();
+        for i in 0..MESSAGES {
+            ipc.send(i).unwrap();
+        }
+        thread::spawn({
+            let ipc = ipc.clone();
+            move || ipc.send("x".repeat(LARGE_LEN))
+        });
+        while ipc.w.try_lock().is_ok() {
+            thread::sleep(LOCK_RETRY_INTERVAL);
+        }
+        match mode.as_str() {
+            "explicit" => assert!(flush_all_channels(Duration::from_secs(10))),
+            _ => {
+                register_exit_flush();
+                register_exit_flush();
+            }
+        }
+        std::process::exit(0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exit_flush() {
+        use crate::testutil::spawn_child_test;
+
+        for mode in ["explicit", "atexit"] {
+            let (mut child, ipc) =
+                spawn_child_test("exit_flush::tests::exit_flush_child", &[(CHILD_ENV, mode)]);
+            // The child exits while the large message is being written.
+            thread::sleep(Duration::from_millis(200));
+            for i in 0..MESSAGES {
+                assert_eq!(ipc.recv::<usize>().unwrap(), Some(i));
+            }
+            let large = ipc.recv::<String>().unwrap().unwrap();
+            assert_eq!(large.len(), LARGE_LEN, "{}", mode);
+            assert_eq!(ipc.recv::<String>().unwrap(), None);
+            assert!(child.wait().unwrap().success());
+        }
+    }
+}
@@ -0,0 +1,411 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Sending file descriptors by path, where `SCM_RIGHTS` is not permitted.
+//!
+//! Some sandboxes reject `sendmsg` with `SCM_RIGHTS`, even though both
+//! processes can open the same files. With `NodeIpc::with_fd_path_fallback`,
+//! `send_fd_vec` then sends `FD_PATH_MARKER` in place of the control message,
+//! followed by a regular message describing how to reopen each fd. Regular
+//! files and character devices, like ptys, are reopened by path, and
+//! regular files are seeked to the sender's offset. Pipes and sockets cannot
+//! be reopened, and are reported by `PartialTransfer`.
+//!
+//! A reopened file is a new open file description: unlike fds sent via
+//! `SCM_RIGHTS`, it does not share the offset with the sender's fd.
+
+use thiserror::Error;
+
+use crate::nodeipc::NodeIpc;
+
+/// Sent instead of the `b'\n'` that carries the `SCM_RIGHTS` control
+/// message, to tell the receiver that fd paths follow.
+#[cfg(unix)]
+pub(crate) const FD_PATH_MARKER: u8 = b'p';
+
+/// Some file descriptors could not be sent by path, see
+/// `NodeIpc::send_fd_vec_with_report`. The message was still sent, and the
+/// receiver gets -1 in place of these file descriptors.
+#[derive(Debug, Error)]
+#[error("NodeIpc could not send file descriptors {unrepresentable:?} by path")]
+pub struct PartialTransfer {
+    /// Indexes of the file descriptors in the sent list, and why they could
+    /// not be sent.
+    pub unrepresentable: Vec<(usize, String)>,
+}
+
+impl NodeIpc {
+    /// When sending file descriptors is not permitted, for example by a
+    /// sandbox, send how to reopen them by path instead. See
+    /// `PartialTransfer` for file descriptors that cannot be reopened.
+    ///
+    /// Only used on Unix. Receivers always accept fds sent by path.
+    pub fn with_fd_path_fallback(mut self) -> Self {
+        self.fd_path_fallback = true;
+        self
+    }
+}
+
+#[cfg(unix)]
+pub(crate) use self::unix::*;
+
+#[cfg(unix)]
+mod unix {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+    use std::io::Write;
+    use std::mem::ManuallyDrop;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::io::IntoRawFd;
+    use std::path::PathBuf;
+
+    use anyhow::Context;
+    use filedescriptor::RawFileDescriptor;
+    use serde::Deserialize;
+    use serde::Serialize;
+
+    use super::PartialTransfer;
+    use super::FD_PATH_MARKER;
+    use crate::nodeipc::NodeIpc;
+    use crate::sendfd::SendFdPayload;
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct FdPathPayload {
+        /// `None` for file descriptors that cannot be reopened.
+        fds: Vec<Option<FdPath>>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct FdPath {
+        path: PathBuf,
+        /// Access mode and `O_APPEND`, from `fcntl(F_GETFL)`.
+        flags: i32,
+        /// Offset of regular files.
+        offset: Option<u64>,
+        /// Identify the file, so a different file at the same path is
+        /// rejected.
+        dev: u64,
+        ino: u64,
+    }
+
+    /// Whether `sendmsg` failed because passing file descriptors is not
+    /// permitted, rather than because of the channel.
+    pub(crate) fn is_sendfd_blocked(err: &io::Error) -> bool {
+        matches!(
+            err.raw_os_error(),
+            Some(libc::EPERM | libc::EACCES | libc::EINVAL | libc::EOPNOTSUPP)
+        )
+    }
+
+    impl NodeIpc {
+        /// Send how to reopen `fds` by path, after `sendmsg` with them
+        /// failed. Returns the file descriptors that could not be sent.
+        pub(crate) fn send_fd_paths(
+            &self,
+            fds: &[RawFileDescriptor],
+        ) -> anyhow::Result<Option<PartialTransfer>> {
+            let mut unrepresentable = Vec::new();
+            let fds: Vec<Option<FdPath>> = fds
+                .iter()
+                .enumerate()
+                .map(|(i, &fd)| match FdPath::describe(fd) {
+                    Ok(fd_path) => Some(fd_path),
+                    Err(reason) => {
+                        unrepresentable.push((i, reason));
+                        None
+                    }
+                })
+                .collect();
+            tracing::debug!("NodeIpc sending fds by path: {:?}", &fds);
+
+            let mut w = self.w.lock().unwrap();
+            w.write_all(&[FD_PATH_MARKER])
+                .context("in NodeIpc::send_fd_vec, when sending fd paths")?;
+            drop(w);
+            self.send(FdPathPayload { fds })?;
+
+            if unrepresentable.is_empty() {
+                Ok(None)
+            } else {
+                Ok(Some(PartialTransfer { unrepresentable }))
+            }
+        }
+
+        /// The other end of `send_fd_paths`, after receiving `FD_PATH_MARKER`.
+        pub(crate) fn recv_fd_paths(&self) -> anyhow::Result<SendFdPayload> {
+            let payload: FdPathPayload = match self.recv()? {
+                Some(payload) => payload,
+                None => anyhow::bail!("Unexpected EOF when receiving fd paths"),
+            };
+            let mut files = Vec::with_capacity(payload.fds.len());
+            for fd_path in payload.fds {
+                // On error, `files` closes the fds reopened so far.
+                files.push(fd_path.map(|p| p.reopen()).transpose()?);
+            }
+            let raw_fds = files
+                .into_iter()
+                .map(|file| file.map_or(-1, |file| file.into_raw_fd()))
+                .collect();
+            Ok(SendFdPayload { raw_fds })
+        }
+    }
+
+    impl FdPath {
+        /// Describe how to reopen `fd`, or why it cannot be reopened.
+        fn describe(fd: RawFileDescriptor) -> Result<Self, String> {
+            // Borrow `fd` without closing it.
+            let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+            let metadata = file.metadata().map_err(|e| e.to_string())?;
+            let file_type = metadata.file_type();
+            let (path, offset) = if file_type.is_file() {
+                let offset = (&*file).stream_position().map_err(|e| e.to_string())?;
+                (fd_path(fd)?, Some(offset))
+            } else if file_type.is_char_device() {
+                (tty_path(fd).map_or_else(|| fd_path(fd), Ok)?, None)
+            } else if file_type.is_fifo() {
+                return Err("pipe".to_string());
+            } else if file_type.is_socket() {
+                return Err("socket".to_string());
+            } else {
+                return Err(format!("unsupported file type {:?}", file_type));
+            };
+
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if flags < 0 {
+                return Err(io::Error::last_os_error().to_string());
+            }
+            Ok(Self {
+                path,
+                flags: flags & (libc::O_ACCMODE | libc::O_APPEND),
+                offset,
+                dev: metadata.dev(),
+                ino: metadata.ino(),
+            })
+        }
+
+        /// Open the described file, making sure it is the same file.
+        fn reopen(self) -> anyhow::Result<File> {
+            let c_path = CString::new(self.path.as_os_str().as_bytes())?;
+            // Like fds received via SCM_RIGHTS, the fd is not close-on-exec.
+            let fd = unsafe { libc::open(c_path.as_ptr(), self.flags | libc::O_NOCTTY) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error())
+                    .with_context(|| format!("Failed to reopen {}", self.path.display()));
+            }
+            let mut file = unsafe { File::from_raw_fd(fd) };
+            let metadata = file.metadata()?;
+            anyhow::ensure!(
+                (metadata.dev(), metadata.ino()) == (self.dev, self.ino),
+                "{} is not the file the sender has open",
+                self.path.display()
+            );
+            if let Some(offset) = self.offset {
+                file.seek(SeekFrom::Start(offset))
+                    .with_context(|| format!("Failed to seek {}", self.path.display()))?;
+            }
+            Ok(file)
+        }
+    }
+
+    fn tty_path(fd: RawFileDescriptor) -> Option<PathBuf> {
+        let mut buf = [0u8; libc::PATH_MAX as usize];
+        let ret = unsafe { libc::ttyname_r(fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+        if ret != 0 {
+            return None;
+        }
+        let len = buf.iter().position(|&b| b == 0)?;
+        Some(PathBuf::from(std::ffi::OsStr::from_bytes(&buf[..len])))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn fd_path(fd: RawFileDescriptor) -> Result<PathBuf, String> {
+        let path =
+            std::fs::read_link(format!("/proc/self/fd/{}", fd)).map_err(|e| e.to_string())?;
+        if path.as_os_str().as_bytes().ends_with(b" (deleted)") {
+            return Err("deleted file".to_string());
+        }
+        Ok(path)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn fd_path(fd: RawFileDescriptor) -> Result<PathBuf, String> {
+        let mut buf = [0u8; libc::PATH_MAX as usize];
+        let ret = unsafe { libc::fcntl(fd, libc::F_GETPATH, buf.as_mut_ptr()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error().to_string());
+        }
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        Ok(PathBuf::from(std::ffi::OsStr::from_bytes(&buf[..len])))
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    fn fd_path(_fd: RawFileDescriptor) -> Result<PathBuf, String> {
+        Err("path of file descriptors is unknown on this platform".to_string())
+    }
+
+    #[cfg(test)]
+    thread_local! {
+        static INJECTED_ERRNO: std::cell::Cell<Option<i32>> = std::cell::Cell::new(None);
+    }
+
+    /// Make the next `sendmsg` with fds on this thread fail with `errno`.
+    #[cfg(test)]
+    pub(crate) fn inject_sendmsg_error(errno: i32) {
+        INJECTED_ERRNO.with(|e| e.set(Some(errno)));
+    }
+
+    /// The error set by `inject_sendmsg_error`.
+    #[cfg(test)]
+    pub(crate) fn take_injected_sendmsg_error() -> Option<io::Error> {
+        INJECTED_ERRNO.with(|e| e.take().map(io::Error::from_raw_os_error))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+    use std::fs::File;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+    use std::io::Write;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::io::FromRawFd;
+
+    use super::*;
+    use crate::testutil::ipc_pair;
+
+    /// Files standing in for stdin, stdout and stderr, each at a different
+    /// offset.
+    fn stdio_files(dir: &std::path::Path) -> Vec<File> {
+        (0..3)
+            .map(|i| {
+                let path = dir.join(format!("stdio{}", i));
+                let mut file = fs::OpenOptions::new()
+                    .create(true)
+                    .read(true)
+                    .write(true)
+                    .open(path)
+                    .unwrap();
+                file.write_all(b"0123456789").unwrap();
+                file.seek(SeekFrom::Start(i * 3)).unwrap();
+                file
+            })
+            .collect()
+    }
+
+    fn same_file(a: &File, b: &File) -> bool {
+        let (a, b) = (a.metadata().unwrap(), b.metadata().unwrap());
+        (a.dev(), a.ino()) == (b.dev(), b.ino())
+    }
+
+    #[test]
+    fn test_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = stdio_files(dir.path());
+        let fds: Vec<_> = files.iter().map(|f| f.as_raw_fd()).collect();
+        let (a, b) = ipc_pair();
+        let a = a.with_fd_path_fallback();
+
+        inject_sendmsg_error(libc::EPERM);
+        a.send_fd_vec(&fds).unwrap();
+        let payload = b.recv_fd_vec().unwrap();
+        assert_eq!(payload.raw_fds.len(), 3);
+        for (i, (&raw_fd, file)) in payload.raw_fds.iter().zip(&files).enumerate() {
+            assert!(!fds.contains(&raw_fd));
+            let mut received = unsafe { File::from_raw_fd(raw_fd) };
+            assert!(same_file(&received, file));
+            assert_eq!(received.stream_position().unwrap(), i as u64 * 3);
+        }
+
+        // Messages still work after the fallback.
+        a.send("after").unwrap();
+        assert_eq!(b.recv::<String>().unwrap().unwrap(), "after");
+    }
+
+    #[test]
+    fn test_partial_transfer() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = stdio_files(dir.path());
+        let (socket, _peer) = filedescriptor::socketpair().unwrap();
+        let fds = [files[0].as_raw_fd(), socket.as_raw_fd()];
+        let (a, b) = ipc_pair();
+        let a = a.with_fd_path_fallback();
+
+        inject_sendmsg_error(libc::EINVAL);
+        let partial = a.send_fd_vec_with_report(&fds).unwrap().unwrap();
+        assert_eq!(partial.unrepresentable, [(1, "socket".to_string())]);
+
+        let payload = b.recv_fd_vec().unwrap();
+        assert_eq!(payload.raw_fds[1], -1);
+        let received = unsafe { File::from_raw_fd(payload.raw_fds[0]) };
+        assert!(same_file(&received, &files[0]));
+
+        // The fds that could be sent are, whether or not they all are.
+        inject_sendmsg_error(libc::EINVAL);
+        a.send_fd_vec(&fds).unwrap();
+        let payload = b.recv_fd_vec().unwrap();
+        assert_eq!(payload.raw_fds.len(), 2);
+        drop(unsafe { File::from_raw_fd(payload.raw_fds[0]) });
+    }
+
+    #[test]
+    fn test_optional_fds_left_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = stdio_files(dir.path());
+        let (socket, _peer) = filedescriptor::socketpair().unwrap();
+        let fds = [files[0].as_raw_fd(), socket.as_raw_fd()];
+        let (a, b) = ipc_pair();
+        let a = a.with_fd_path_fallback();
+
+        // Like the singleton sent after the stdio by `send_stdio`.
+        inject_sendmsg_error(libc::EPERM);
+        let partial = a.send_labeled_fd_vec(&fds, None, 1).unwrap();
+        assert!(partial.is_none());
+        let payload = b.recv_fd_vec().unwrap();
+        assert_eq!(payload.raw_fds.len(), 1);
+        let received = unsafe { File::from_raw_fd(payload.raw_fds[0]) };
+        assert!(same_file(&received, &files[0]));
+    }
+
+    #[test]
+    fn test_replaced_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = stdio_files(dir.path());
+        let (a, b) = ipc_pair();
+        let a = a.with_fd_path_fallback();
+
+        inject_sendmsg_error(libc::EPERM);
+        a.send_fd_vec(&[files[0].as_raw_fd()]).unwrap();
+        // Another file takes the path before the receiver reopens it.
+        fs::rename(dir.path().join("stdio1"), dir.path().join("stdio0")).unwrap();
+        let err = b.recv_fd_vec().unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("is not the file the sender has open"),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_fallback_not_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = stdio_files(dir.path());
+        let (a, _b) = ipc_pair();
+
+        inject_sendmsg_error(libc::EPERM);
+        let err = a.send_fd_vec(&[files[0].as_raw_fd()]).unwrap_err();
+        assert!(err.downcast_ref::<PartialTransfer>().is_none());
+    }
+}
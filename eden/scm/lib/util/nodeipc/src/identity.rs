@@ -0,0 +1,299 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Identity of the process on the other end of a channel, as told by the
+//! OS, so that daemons can decide whether to serve a client.
+//!
+//! The identity is never taken from the messages of the peer, which could
+//! claim anything, like the pid in a `SendFdPayload`.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use filedescriptor::AsRawFileDescriptor;
+
+use crate::loopback::Writer;
+use crate::nodeipc::NodeIpc;
+
+/// Who is on the other end of a `NodeIpc`, see `NodeIpc::peer_identity`.
+/// Fields are `None` if the OS could not tell.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerIdentity {
+    pub pid: Option<u32>,
+    /// Always `None` on Windows.
+    pub uid: Option<u32>,
+    /// Always `None` on Windows.
+    pub gid: Option<u32>,
+    /// The executable of `pid`, resolved when the identity is first asked
+    /// for, so the process may have exec'd or exited meanwhile.
+    pub executable: Option<PathBuf>,
+}
+
+/// Decides whether a client accepted by a `NodeIpcListener` is served, see
+/// `NodeIpcListener::authorize`.
+pub type AuthorizePolicy = Arc<dyn Fn(&PeerIdentity) -> bool + Send + Sync>;
+
+impl NodeIpc {
+    /// The identity of the peer process, from the socket or named pipe of
+    /// the channel. It is looked up once, on the first call.
+    ///
+    /// On Linux, this uses `SO_PEERCRED` and `/proc/<pid>/exe`, on macOS
+    /// `LOCAL_PEERCRED` and `LOCAL_PEEREPID`, and on Windows the pid of the
+    /// named pipe client, or server for clients. Other Unix platforms only
+    /// get the uid and gid. Loopback channels have the current process as
+    /// their peer.
+    ///
+    /// Fails if the channel is not a socket or named pipe, e.g. a regular
+    /// pipe.
+    pub fn peer_identity(&self) -> anyhow::Result<PeerIdentity> {
+        let identity = self.peer_identity.get_or_try_init(|| {
+            let fd = match &*self.w.lock().unwrap() {
+                Writer::Fd(fd) => fd.as_raw_file_descriptor(),
+                Writer::Loopback(_) => return Ok(sys::current_identity()),
+            };
+            sys::peer_identity(fd).context("in NodeIpc::peer_identity")
+        })?;
+        Ok(identity.clone())
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::io;
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+    use std::mem;
+    use std::process;
+
+    use filedescriptor::RawFileDescriptor;
+
+    use super::PeerIdentity;
+
+    pub(crate) fn current_identity() -> PeerIdentity {
+        PeerIdentity {
+            pid: Some(process::id()),
+            uid: Some(unsafe { libc::getuid() }),
+            gid: Some(unsafe { libc::getgid() }),
+            executable: std::env::current_exe().ok(),
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) fn peer_identity(fd: RawFileDescriptor) -> io::Result<PeerIdentity> {
+        let mut cred: libc::ucred = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The pid is 0 if the peer is in another pid namespace.
+        let pid = (cred.pid > 0).then_some(cred.pid as u32);
+        Ok(PeerIdentity {
+            pid,
+            uid: Some(cred.uid),
+            gid: Some(cred.gid),
+            executable: pid.and_then(|pid| std::fs::read_link(format!("/proc/{}/exe", pid)).ok()),
+        })
+    }
+
+    /// From `sys/un.h`.
+    #[cfg(target_os = "macos")]
+    const SOL_LOCAL: libc::c_int = 0;
+    #[cfg(target_os = "macos")]
+    const LOCAL_PEERCRED: libc::c_int = 0x001;
+    #[cfg(target_os = "macos")]
+    const LOCAL_PEEREPID: libc::c_int = 0x003;
+
+    /// `struct xucred` of `sys/ucred.h`.
+    #[cfg(target_os = "macos")]
+    #[repr(C)]
+    struct Xucred {
+        cr_version: libc::c_uint,
+        cr_uid: libc::uid_t,
+        cr_ngroups: libc::c_short,
+        cr_groups: [libc::gid_t; 16],
+    }
+
+    #[cfg(target_os = "macos")]
+    pub(crate) fn peer_identity(fd: RawFileDescriptor) -> io::Result<PeerIdentity> {
+        let mut cred: Xucred = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<Xucred>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                SOL_LOCAL,
+                LOCAL_PEERCRED,
+                &mut cred as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut pid: libc::pid_t = 0;
+        let mut len = mem::size_of::<libc::pid_t>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                SOL_LOCAL,
+                LOCAL_PEEREPID,
+                &mut pid as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        let pid = (ret == 0 && pid > 0).then_some(pid as u32);
+        Ok(PeerIdentity {
+            pid,
+            uid: Some(cred.cr_uid),
+            gid: (cred.cr_ngroups > 0).then_some(cred.cr_groups[0]),
+            executable: pid.and_then(executable),
+        })
+    }
+
+    #[cfg(target_os = "macos")]
+    fn executable(pid: u32) -> Option<std::path::PathBuf> {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut buf = vec![0u8; libc::PROC_PIDPATHINFO_MAXSIZE as usize];
+        let len = unsafe {
+            libc::proc_pidpath(
+                pid as libc::c_int,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len() as u32,
+            )
+        };
+        if len <= 0 {
+            return None;
+        }
+        Some(OsStr::from_bytes(&buf[..len as usize]).into())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+    pub(crate) fn peer_identity(fd: RawFileDescriptor) -> io::Result<PeerIdentity> {
+        let mut uid: libc::uid_t = 0;
+        let mut gid: libc::gid_t = 0;
+        if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PeerIdentity {
+            uid: Some(uid),
+            gid: Some(gid),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::ffi::OsString;
+    use std::io;
+    use std::os::windows::ffi::OsStringExt;
+    use std::path::PathBuf;
+    use std::process;
+
+    use filedescriptor::RawFileDescriptor;
+    use winapi::shared::minwindef::DWORD;
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::OpenProcess;
+    use winapi::um::winbase::GetNamedPipeClientProcessId;
+    use winapi::um::winbase::GetNamedPipeServerProcessId;
+    use winapi::um::winbase::QueryFullProcessImageNameW;
+    use winapi::um::winnt::HANDLE;
+    use winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION;
+
+    use super::PeerIdentity;
+
+    pub(crate) fn current_identity() -> PeerIdentity {
+        PeerIdentity {
+            pid: Some(process::id()),
+            executable: std::env::current_exe().ok(),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn peer_identity(fd: RawFileDescriptor) -> io::Result<PeerIdentity> {
+        let handle = fd as HANDLE;
+        let mut pid: DWORD = 0;
+        if unsafe { GetNamedPipeClientProcessId(handle, &mut pid) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // On the client end, the client is us.
+        if pid == process::id() {
+            let mut server: DWORD = 0;
+            if unsafe { GetNamedPipeServerProcessId(handle, &mut server) } != 0 {
+                pid = server;
+            }
+        }
+        Ok(PeerIdentity {
+            pid: Some(pid),
+            executable: executable(pid),
+            ..Default::default()
+        })
+    }
+
+    fn executable(pid: DWORD) -> Option<PathBuf> {
+        let process = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+        if process.is_null() {
+            return None;
+        }
+        let mut buf = vec![0u16; 32768];
+        let mut len = buf.len() as DWORD;
+        let ok = unsafe { QueryFullProcessImageNameW(process, 0, buf.as_mut_ptr(), &mut len) };
+        unsafe { CloseHandle(process) };
+        if ok == 0 {
+            return None;
+        }
+        Some(OsString::from_wide(&buf[..len as usize]).into())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::process;
+
+    use filedescriptor::IntoRawFileDescriptor;
+    use filedescriptor::Pipe;
+
+    use super::*;
+    use crate::testutil::ipc_pair;
+
+    #[test]
+    fn test_socketpair_identity() {
+        let (a, _b) = ipc_pair();
+        let identity = a.peer_identity().unwrap();
+        assert_eq!(identity.uid, Some(unsafe { libc::getuid() }));
+        assert_eq!(identity.gid, Some(unsafe { libc::getgid() }));
+        if cfg!(any(target_os = "linux", target_os = "macos")) {
+            assert_eq!(identity.pid, Some(process::id()));
+            assert_eq!(
+                identity.executable.map(|p| p.canonicalize().unwrap()),
+                Some(std::env::current_exe().unwrap().canonicalize().unwrap())
+            );
+        }
+        // Looked up once.
+        assert_eq!(a.peer_identity().unwrap(), a.peer_identity().unwrap());
+
+        let (loopback, _peer) = NodeIpc::new_loopback_pair();
+        assert_eq!(loopback.peer_identity().unwrap().pid, Some(process::id()));
+    }
+
+    #[test]
+    fn test_not_a_socket() {
+        let pipe = Pipe::new().unwrap();
+        let ipc = NodeIpc::from_raw_file_descriptor(pipe.write.into_raw_file_descriptor()).unwrap();
+        assert!(ipc.peer_identity().is_err());
+    }
+}
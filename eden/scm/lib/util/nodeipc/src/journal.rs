@@ -0,0 +1,230 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Optional journal of recently sent messages, so a peer that reconnects,
+//! like a reloaded ISL page, can catch up on what it missed.
+//!
+//! Only messages matching a filter are retained, in a ring bounded by both
+//! message count and bytes. Replayed messages have a top-level `"replayed":
+//! true` field, so they can be told apart from live traffic.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::nodeipc::NodeIpc;
+
+/// Decides whether a sent message is retained, see
+/// `NodeIpc::enable_retention`.
+pub type RetainFilter = Arc<dyn Fn(&Value) -> bool + Send + Sync>;
+
+/// Field set on replayed messages.
+const REPLAYED_FIELD: &str = "replayed";
+
+pub(crate) struct Journal {
+    max_messages: usize,
+    max_bytes: usize,
+    filter: RetainFilter,
+    retained: Mutex<Retained>,
+}
+
+#[derive(Default)]
+struct Retained {
+    /// Messages with their serialized length, oldest first.
+    messages: VecDeque<(Value, usize)>,
+    bytes: usize,
+}
+
+impl Journal {
+    /// Retains `message`, sent serialized as `len` bytes, if it is a JSON
+    /// object matching the filter, evicting the oldest messages to stay
+    /// within bounds. Messages larger than `max_bytes` are not retained.
+    pub(crate) fn record<T: Serialize + ?Sized>(&self, message: &T, len: usize) {
+        if len > self.max_bytes || self.max_messages == 0 {
+            return;
+        }
+        // Converted from the message rather than parsed from the line.
+        let value = match serde_json::to_value(message) {
+            Ok(value @ Value::Object(_)) => value,
+            _ => return,
+        };
+        if !(self.filter)(&value) {
+            return;
+        }
+        let mut retained = self.retained.lock().unwrap();
+        while retained.messages.len() >= self.max_messages || retained.bytes + len > self.max_bytes
+        {
+            match retained.messages.pop_front() {
+                Some((_, evicted)) => retained.bytes -= evicted,
+                None => break,
+            }
+        }
+        retained.messages.push_back((value, len));
+        retained.bytes += len;
+    }
+
+    /// The retained messages, oldest first, marked as replayed.
+    fn replayed(&self) -> Vec<Value> {
+        let retained = self.retained.lock().unwrap();
+        retained
+            .messages
+            .iter()
+            .map(|(value, _)| {
+                let mut value = value.clone();
+                if let Value::Object(map) = &mut value {
+                    map.insert(REPLAYED_FIELD.to_string(), Value::Bool(true));
+                }
+                value
+            })
+            .collect()
+    }
+}
+
+impl NodeIpc {
+    /// Retain recently sent messages that are JSON objects matching `filter`,
+    /// up to `max_messages` messages and `max_bytes` serialized bytes, the
+    /// oldest evicted first. See `replay_to`.
+    ///
+    /// Only messages sent with `send` are retained, whether or not they
+    /// could be delivered. Retention can only be enabled once per `NodeIpc`.
+    pub fn enable_retention(
+        &self,
+        max_messages: usize,
+        max_bytes: usize,
+        filter: RetainFilter,
+    ) -> anyhow::Result<()> {
+        let journal = Journal {
+            max_messages,
+            max_bytes,
+            filter,
+            retained: Default::default(),
+        };
+        self.journal
+            .set(journal)
+            .map_err(|_| anyhow::format_err!("NodeIpc retention is already enabled"))
+    }
+
+    /// Send the retained messages to the peer of `target`, which may be
+    /// `self`, oldest first, each with a `"replayed": true` field. Replayed
+    /// messages are not retained again. Returns how many were sent.
+    ///
+    /// Does nothing unless `enable_retention` was called.
+    pub fn replay_to(&self, target: &NodeIpc) -> anyhow::Result<usize> {
+        let messages = match self.journal.get() {
+            Some(journal) => journal.replayed(),
+            None => return Ok(0),
+        };
+        for message in &messages {
+            let mut line = serde_json::to_string(message)?;
+            line.push('\n');
+            target.send_line(line)?;
+        }
+        Ok(messages.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn progress_and_snapshots() -> RetainFilter {
+        Arc::new(|message| {
+            matches!(
+                message.get("type").and_then(|t| t.as_str()),
+                Some("checkout_progress" | "status_snapshot")
+            )
+        })
+    }
+
+    fn recv_all(ipc: &NodeIpc) -> Vec<Value> {
+        let mut messages = Vec::new();
+        while ipc.wait_readable(Default::default()).unwrap() {
+            match ipc.recv().unwrap() {
+                Some(message) => messages.push(message),
+                None => break,
+            }
+        }
+        messages
+    }
+
+    #[test]
+    fn test_replay_after_reconnect() {
+        let (cli, frontend) = NodeIpc::new_loopback_pair();
+        cli.enable_retention(3, 1024, progress_and_snapshots())
+            .unwrap();
+        assert!(cli
+            .enable_retention(3, 1024, progress_and_snapshots())
+            .is_err());
+
+        let sent = [
+            json!({"type": "checkout_progress", "n": 1}),
+            json!({"type": "log", "n": 2}),
+            json!({"type": "status_snapshot", "n": 3}),
+            json!({"type": "checkout_progress", "n": 4}),
+            json!(["checkout_progress", 5]),
+            json!({"type": "log", "n": 6}),
+            json!({"type": "checkout_progress", "n": 7}),
+        ];
+        for message in &sent {
+            cli.send(message).unwrap();
+        }
+        // Live traffic is not marked.
+        assert_eq!(recv_all(&frontend), sent);
+        drop(frontend);
+
+        // The frontend reconnects, and gets the last 3 retained messages.
+        let (cli_side, frontend) = NodeIpc::new_loopback_pair();
+        assert_eq!(cli.replay_to(&cli_side).unwrap(), 3);
+        assert_eq!(
+            recv_all(&frontend),
+            [
+                json!({"type": "status_snapshot", "n": 3, "replayed": true}),
+                json!({"type": "checkout_progress", "n": 4, "replayed": true}),
+                json!({"type": "checkout_progress", "n": 7, "replayed": true}),
+            ]
+        );
+
+        // Replaying does not change what is retained.
+        assert_eq!(cli.replay_to(&cli_side).unwrap(), 3);
+        assert_eq!(recv_all(&frontend).len(), 3);
+    }
+
+    #[test]
+    fn test_byte_bound() {
+        let (a, b) = NodeIpc::new_loopback_pair();
+        let message = |n: usize| json!({"type": "checkout_progress", "n": n});
+        let len = serde_json::to_string(&message(1)).unwrap().len();
+        a.enable_retention(100, len * 2 + 1, progress_and_snapshots())
+            .unwrap();
+
+        for n in 1..=5 {
+            a.send(message(n)).unwrap();
+        }
+        // Too large to retain at all.
+        let large = json!({"type": "status_snapshot", "data": "x".repeat(len * 2)});
+        a.send(&large).unwrap();
+        assert_eq!(recv_all(&b).len(), 6);
+
+        assert_eq!(a.replay_to(&a).unwrap(), 2);
+        let replayed = recv_all(&b);
+        let ns: Vec<_> = replayed.iter().map(|m| m["n"].clone()).collect();
+        assert_eq!(ns, [json!(4), json!(5)]);
+        assert!(replayed.iter().all(|m| m["replayed"] == json!(true)));
+    }
+
+    #[test]
+    fn test_not_enabled() {
+        let (a, _b) = NodeIpc::new_loopback_pair();
+        a.send(json!({"type": "checkout_progress"})).unwrap();
+        assert_eq!(a.replay_to(&a).unwrap(), 0);
+    }
+}
@@ -18,9 +18,83 @@
 //! [1]: https://github.com/nodejs/node/blob/fe514bf960ca1243b71657af662e7df29f5b57cf/lib/internal/child_process/serialization.js#L54
 //! [2]: https://github.com/nodejs/node/commit/db6253f94a7e499b2bacf5998a246c7cd06f7245
 
+#[cfg(feature = "tokio")]
+mod bridge;
+mod call;
+mod collection;
+mod compress;
+mod console;
+mod console_ctrl;
+mod encrypt;
+mod exit_flush;
+mod fdpath;
+mod identity;
+mod journal;
+mod listener;
+mod loopback;
+mod mux;
 pub(crate) mod nodeipc;
+mod panic_report;
+mod peer;
+mod ratelimit;
+mod reconnect;
 mod sendfd;
+mod serve;
 pub(crate) mod singleton;
+mod stats;
+#[cfg(test)]
+pub(crate) mod testutil;
+mod trace;
+mod typed;
+#[cfg(feature = "ws-bridge")]
+mod ws_bridge;
 
+#[cfg(feature = "tokio")]
+pub use self::bridge::IpcBridgeError;
+#[cfg(feature = "tokio")]
+pub use self::bridge::IpcBridgeHandle;
+pub use self::call::CallRequest;
+pub use self::call::CallResponse;
+pub use self::call::NodeIpcError;
+pub use self::call::PendingCall;
+pub use self::call::RetryConfig;
+pub use self::compress::CompressionError;
+pub use self::console::set_stdio_change_hook;
+pub use self::console::AdoptedStdioInfo;
+pub use self::console::TerminalInfo;
+pub use self::console::TerminalMode;
+pub use self::console::TerminalSize;
+pub use self::console_ctrl::set_console_ctrl_channel;
+pub use self::console_ctrl::set_console_ctrl_hook;
+pub use self::console_ctrl::ConsoleCtrlEvent;
+pub use self::encrypt::EncryptionError;
+pub use self::exit_flush::flush_all_channels;
+pub use self::exit_flush::register_exit_flush;
+pub use self::fdpath::PartialTransfer;
+pub use self::identity::AuthorizePolicy;
+pub use self::identity::PeerIdentity;
+pub use self::journal::RetainFilter;
+pub use self::listener::NodeIpcListener;
+pub use self::mux::IpcStream;
 pub use self::nodeipc::NodeIpc;
+pub use self::panic_report::install_panic_reporter;
+pub use self::ratelimit::InboundLimitPolicy;
+pub use self::reconnect::Backoff;
+pub use self::reconnect::Connector;
+pub use self::reconnect::ReconnectingIpc;
+pub use self::sendfd::FdInheritance;
+pub use self::serve::CancelToken;
 pub use self::singleton::get_singleton;
+pub use self::stats::channel_stats;
+pub use self::stats::ChannelStats;
+pub use self::stats::IpcStats;
+pub use self::trace::read_trace;
+pub use self::trace::TraceDirection;
+pub use self::trace::TraceRecord;
+pub use self::typed::check_protocol;
+pub use self::typed::protocol_schema;
+pub use self::typed::IpcProtocol;
+pub use self::typed::ProtocolError;
+pub use self::typed::TypedChannel;
+#[cfg(feature = "ws-bridge")]
+pub use self::ws_bridge::bridge_to_websocket;
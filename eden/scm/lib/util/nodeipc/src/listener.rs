@@ -0,0 +1,628 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Accepting `NodeIpc` channels from several clients over time, e.g. for a
+//! daemon serving CLI invocations. Clients connect to a Unix domain socket,
+//! or a named pipe on Windows, with `NodeIpc::connect`.
+
+use std::path::Path;
+
+use crate::AuthorizePolicy;
+use crate::NodeIpc;
+
+/// Listens for `NodeIpc` clients. Each accepted client gets its own
+/// `NodeIpc`, without libuv compatibility unless `with_libuv_compat` is set.
+pub struct NodeIpcListener {
+    inner: sys::Listener,
+    libuv_compat: bool,
+    policy: Option<AuthorizePolicy>,
+}
+
+impl NodeIpcListener {
+    /// Listens at `path`. On Unix, this creates a socket only accessible by
+    /// the current user. An existing socket at `path` is replaced if nobody
+    /// accepts connections on it anymore, and fails the bind otherwise. The
+    /// listener of such a socket sees the probe as a client closing right
+    /// away. The socket is removed when the listener is dropped.
+    ///
+    /// On Windows, `path` names a named pipe: `\\.\pipe\<name>` is used as
+    /// is, other paths are turned into a pipe name.
+    pub fn bind(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self::new(sys::Listener::bind(path)?))
+    }
+
+    /// Listens on the socket passed by systemd socket activation, if the
+    /// process was started that way, and clears the environment variables
+    /// describing it. Always `None` on Windows.
+    pub fn bind_systemd_activation() -> anyhow::Result<Option<Self>> {
+        Ok(sys::Listener::from_systemd()?.map(Self::new))
+    }
+
+    fn new(inner: sys::Listener) -> Self {
+        Self {
+            inner,
+            libuv_compat: false,
+            policy: None,
+        }
+    }
+
+    /// Enable libuv pipe compatibility on accepted clients, see
+    /// `NodeIpc::with_libuv_compat`.
+    pub fn with_libuv_compat(mut self) -> Self {
+        self.libuv_compat = true;
+        self
+    }
+
+    /// Only accept clients whose `NodeIpc::peer_identity` passes `policy`.
+    /// Other clients, and clients whose identity can't be told, are
+    /// disconnected before anything they sent is read.
+    pub fn authorize(mut self, policy: AuthorizePolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Waits for the next client.
+    pub fn accept(&self) -> anyhow::Result<NodeIpc> {
+        loop {
+            let ipc = self.inner.accept()?;
+            if let Some(ipc) = self.configure(ipc) {
+                return Ok(ipc);
+            }
+        }
+    }
+
+    /// Waits for the next client without blocking the runtime. On Windows,
+    /// this blocks the worker thread instead, so it needs the multi-threaded
+    /// runtime.
+    #[cfg(feature = "tokio")]
+    pub async fn accept_async(&self) -> anyhow::Result<NodeIpc> {
+        loop {
+            let ipc = self.inner.accept_async().await?;
+            if let Some(ipc) = self.configure(ipc) {
+                return Ok(ipc);
+            }
+        }
+    }
+
+    /// The accepted client, or `None` if it was not authorized.
+    fn configure(&self, ipc: NodeIpc) -> Option<NodeIpc> {
+        if let Some(policy) = &self.policy {
+            match ipc.peer_identity() {
+                Ok(identity) if policy(&identity) => {}
+                Ok(identity) => {
+                    tracing::debug!("NodeIpcListener rejected client {:?}", identity);
+                    return None;
+                }
+                Err(e) => {
+                    tracing::warn!("NodeIpcListener rejected unidentified client: {:#}", e);
+                    return None;
+                }
+            }
+        }
+        if self.libuv_compat {
+            Some(ipc.with_libuv_compat())
+        } else {
+            Some(ipc)
+        }
+    }
+}
+
+impl NodeIpc {
+    /// Connects to a `NodeIpcListener` bound to `path`.
+    pub fn connect(path: &Path) -> anyhow::Result<Self> {
+        sys::connect(path)
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::io::RawFd;
+    use std::os::unix::net::UnixListener;
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+    use std::path::PathBuf;
+    use std::process;
+
+    use anyhow::bail;
+    use anyhow::Context;
+    use filedescriptor::pollfd;
+    use filedescriptor::POLLIN;
+
+    use crate::NodeIpc;
+
+    /// The first fd passed by systemd socket activation.
+    const SD_LISTEN_FDS_START: RawFd = 3;
+
+    pub(crate) struct Listener {
+        // Non-blocking, so it can be waited on by tokio too.
+        listener: UnixListener,
+        // The socket file created by `bind`, with its device and inode, so
+        // a socket that replaced it is not removed.
+        owned: Option<(PathBuf, u64, u64)>,
+    }
+
+    impl Listener {
+        pub(crate) fn bind(path: &Path) -> anyhow::Result<Self> {
+            remove_stale_socket(path)?;
+            let listener = UnixListener::bind(path)
+                .with_context(|| format!("in NodeIpcListener::bind, binding {:?}", path))?;
+            let owned = (|| -> io::Result<_> {
+                fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+                listener.set_nonblocking(true)?;
+                let metadata = fs::symlink_metadata(path)?;
+                Ok((path.to_path_buf(), metadata.dev(), metadata.ino()))
+            })();
+            match owned {
+                Ok(owned) => Ok(Self {
+                    listener,
+                    owned: Some(owned),
+                }),
+                Err(e) => {
+                    let _ = fs::remove_file(path);
+                    Err(e).with_context(|| format!("in NodeIpcListener::bind, securing {:?}", path))
+                }
+            }
+        }
+
+        pub(crate) fn from_systemd() -> anyhow::Result<Option<Self>> {
+            let pid = match env::var("LISTEN_PID") {
+                Ok(pid) => pid,
+                Err(_) => return Ok(None),
+            };
+            if pid.parse::<u32>().ok() != Some(process::id()) {
+                return Ok(None);
+            }
+            let count: usize = env::var("LISTEN_FDS")
+                .context("in NodeIpcListener::bind_systemd_activation, reading LISTEN_FDS")?
+                .parse()
+                .context("in NodeIpcListener::bind_systemd_activation, parsing LISTEN_FDS")?;
+            for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+                env::remove_var(name);
+            }
+            match count {
+                0 => return Ok(None),
+                1 => {}
+                _ => bail!("systemd passed {} sockets, expected one", count),
+            }
+            let fd = SD_LISTEN_FDS_START;
+            let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+            if unsafe { libc::fstat(fd, &mut stat) } != 0
+                || stat.st_mode & libc::S_IFMT != libc::S_IFSOCK
+            {
+                bail!("fd {} passed by systemd is not a socket", fd);
+            }
+            // systemd does not set close-on-exec.
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            let listener = unsafe { UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Ok(Some(Self {
+                listener,
+                owned: None,
+            }))
+        }
+
+        pub(crate) fn accept(&self) -> anyhow::Result<NodeIpc> {
+            loop {
+                match self.listener.accept() {
+                    Ok((stream, _)) => return into_ipc(stream),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        let mut fds = [pollfd {
+                            fd: self.listener.as_raw_fd(),
+                            events: POLLIN,
+                            revents: 0,
+                        }];
+                        filedescriptor::poll(&mut fds, None)
+                            .context("in NodeIpcListener::accept, when polling")?;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e).context("in NodeIpcListener::accept"),
+                }
+            }
+        }
+
+        #[cfg(feature = "tokio")]
+        pub(crate) async fn accept_async(&self) -> anyhow::Result<NodeIpc> {
+            use tokio::io::unix::AsyncFd;
+            use tokio::io::Interest;
+
+            let fd = AsyncFd::with_interest(self.listener.as_raw_fd(), Interest::READABLE)?;
+            loop {
+                let mut guard = fd.readable().await?;
+                match guard.try_io(|_| self.listener.accept()) {
+                    Ok(Ok((stream, _))) => return into_ipc(stream),
+                    Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Ok(Err(e)) => return Err(e).context("in NodeIpcListener::accept_async"),
+                    Err(_would_block) => {}
+                }
+            }
+        }
+    }
+
+    impl Drop for Listener {
+        fn drop(&mut self) {
+            if let Some((path, dev, ino)) = &self.owned {
+                let same = fs::symlink_metadata(path).map_or(false, |metadata| {
+                    metadata.dev() == *dev && metadata.ino() == *ino
+                });
+                if same {
+                    let _ = fs::remove_file(path);
+                }
+            }
+        }
+    }
+
+    /// Removes the socket at `path` if nobody accepts connections on it.
+    fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("checking {:?}", path)),
+        };
+        if !metadata.file_type().is_socket() {
+            bail!("{:?} exists and is not a socket", path);
+        }
+        match UnixStream::connect(path) {
+            Ok(_) => Err(io::Error::from(io::ErrorKind::AddrInUse))
+                .with_context(|| format!("another process is listening on {:?}", path)),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                tracing::debug!("removing stale socket {:?}", path);
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        Err(e).with_context(|| format!("removing stale socket {:?}", path))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("probing {:?}", path)),
+        }
+    }
+
+    fn into_ipc(stream: UnixStream) -> anyhow::Result<NodeIpc> {
+        // Accepted sockets inherit non-blocking mode on some platforms.
+        stream.set_nonblocking(false)?;
+        NodeIpc::from_raw_file_descriptor(stream.into_raw_fd())
+    }
+
+    pub(crate) fn connect(path: &Path) -> anyhow::Result<NodeIpc> {
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("in NodeIpc::connect, connecting to {:?}", path))?;
+        NodeIpc::from_raw_file_descriptor(stream.into_raw_fd())
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::ffi::OsStr;
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+    use std::ptr;
+    use std::sync::Mutex;
+
+    use anyhow::Context;
+    use winapi::shared::minwindef::DWORD;
+    use winapi::shared::winerror::ERROR_ACCESS_DENIED;
+    use winapi::shared::winerror::ERROR_PIPE_BUSY;
+    use winapi::shared::winerror::ERROR_PIPE_CONNECTED;
+    use winapi::um::fileapi::CreateFileW;
+    use winapi::um::fileapi::OPEN_EXISTING;
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+    use winapi::um::namedpipeapi::ConnectNamedPipe;
+    use winapi::um::namedpipeapi::WaitNamedPipeW;
+    use winapi::um::winbase::CreateNamedPipeW;
+    use winapi::um::winbase::FILE_FLAG_FIRST_PIPE_INSTANCE;
+    use winapi::um::winbase::PIPE_ACCESS_DUPLEX;
+    use winapi::um::winbase::PIPE_READMODE_BYTE;
+    use winapi::um::winbase::PIPE_REJECT_REMOTE_CLIENTS;
+    use winapi::um::winbase::PIPE_TYPE_BYTE;
+    use winapi::um::winbase::PIPE_UNLIMITED_INSTANCES;
+    use winapi::um::winbase::PIPE_WAIT;
+    use winapi::um::winnt::GENERIC_READ;
+    use winapi::um::winnt::GENERIC_WRITE;
+    use winapi::um::winnt::HANDLE;
+
+    use crate::NodeIpc;
+
+    const PIPE_PREFIX: &str = r"\\.\pipe\";
+    const BUFFER_SIZE: DWORD = 65536;
+    const CONNECT_TIMEOUT_MS: DWORD = 5000;
+
+    struct Handle(HANDLE);
+
+    // The handle is only used under the `Mutex` of `Listener`.
+    unsafe impl Send for Handle {}
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+
+    /// Named pipes have an instance per client. The listener keeps the
+    /// instance the next client connects to.
+    pub(crate) struct Listener {
+        name: Vec<u16>,
+        next: Mutex<Handle>,
+    }
+
+    impl Listener {
+        pub(crate) fn bind(path: &Path) -> anyhow::Result<Self> {
+            let name = pipe_name(path);
+            // Pipes disappear with their last handle, so there is nothing
+            // stale to clean up. Another listener makes this fail.
+            let first = create_instance(&name, true).map_err(|e| {
+                if e.raw_os_error() == Some(ERROR_ACCESS_DENIED as i32) {
+                    io::Error::from(io::ErrorKind::AddrInUse)
+                } else {
+                    e
+                }
+            });
+            let first = first.with_context(|| format!("in NodeIpcListener::bind, {:?}", path))?;
+            Ok(Self {
+                name,
+                next: Mutex::new(first),
+            })
+        }
+
+        pub(crate) fn from_systemd() -> anyhow::Result<Option<Self>> {
+            Ok(None)
+        }
+
+        pub(crate) fn accept(&self) -> anyhow::Result<NodeIpc> {
+            let mut next = self.next.lock().unwrap();
+            let ok = unsafe { ConnectNamedPipe(next.0, ptr::null_mut()) };
+            if ok == 0 {
+                let e = io::Error::last_os_error();
+                if e.raw_os_error() != Some(ERROR_PIPE_CONNECTED as i32) {
+                    return Err(e).context("in NodeIpcListener::accept");
+                }
+            }
+            let instance = create_instance(&self.name, false)
+                .context("in NodeIpcListener::accept, creating the next pipe instance")?;
+            let connected = std::mem::replace(&mut *next, instance);
+            let handle = connected.0;
+            std::mem::forget(connected);
+            NodeIpc::from_raw_file_descriptor(handle as _)
+        }
+
+        #[cfg(feature = "tokio")]
+        pub(crate) async fn accept_async(&self) -> anyhow::Result<NodeIpc> {
+            tokio::task::block_in_place(|| self.accept())
+        }
+    }
+
+    fn pipe_name(path: &Path) -> Vec<u16> {
+        let path = path.to_string_lossy();
+        let name = if path.starts_with(PIPE_PREFIX) {
+            path.into_owned()
+        } else {
+            let name: String = path
+                .chars()
+                .map(|c| {
+                    if matches!(c, '\\' | '/' | ':') {
+                        '-'
+                    } else {
+                        c
+                    }
+                })
+                .collect();
+            format!("{}{}", PIPE_PREFIX, name)
+        };
+        OsStr::new(&name).encode_wide().chain(Some(0)).collect()
+    }
+
+    fn create_instance(name: &[u16], first: bool) -> io::Result<Handle> {
+        let mut open_mode = PIPE_ACCESS_DUPLEX;
+        if first {
+            open_mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+        }
+        let handle = unsafe {
+            CreateNamedPipeW(
+                name.as_ptr(),
+                open_mode,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                BUFFER_SIZE,
+                BUFFER_SIZE,
+                0,
+                // The default security only gives write access to the
+                // creator, administrators and LocalSystem.
+                ptr::null_mut(),
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Handle(handle))
+    }
+
+    pub(crate) fn connect(path: &Path) -> anyhow::Result<NodeIpc> {
+        let name = pipe_name(path);
+        loop {
+            let handle = unsafe {
+                CreateFileW(
+                    name.as_ptr(),
+                    GENERIC_READ | GENERIC_WRITE,
+                    0,
+                    ptr::null_mut(),
+                    OPEN_EXISTING,
+                    0,
+                    ptr::null_mut(),
+                )
+            };
+            if handle != INVALID_HANDLE_VALUE {
+                return NodeIpc::from_raw_file_descriptor(handle as _);
+            }
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(ERROR_PIPE_BUSY as i32) {
+                return Err(e)
+                    .with_context(|| format!("in NodeIpc::connect, connecting to {:?}", path));
+            }
+            // All instances are connected. Wait for the listener to create
+            // another one.
+            if unsafe { WaitNamedPipeW(name.as_ptr(), CONNECT_TIMEOUT_MS) } == 0 {
+                return Err(io::Error::last_os_error())
+                    .with_context(|| format!("in NodeIpc::connect, waiting for {:?}", path));
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixListener;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::thread;
+
+    use serde_json::json;
+    use serde_json::Value;
+
+    use super::*;
+
+    /// Serves `clients` clients one after another, replying to each message
+    /// with the client's number.
+    fn serve(listener: NodeIpcListener, clients: usize) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            for client in 0..clients {
+                let ipc = listener.accept().unwrap();
+                while let Some(message) = ipc.recv::<Value>().unwrap() {
+                    ipc.send(json!({"client": client, "echo": message}))
+                        .unwrap();
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_sequential_clients() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("daemon.sock");
+        let listener = NodeIpcListener::bind(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let server = serve(listener, 3);
+
+        for client in 0..3 {
+            let path = path.clone();
+            thread::spawn(move || {
+                let ipc = NodeIpc::connect(&path).unwrap();
+                for i in 0..2 {
+                    ipc.send(json!(i)).unwrap();
+                    let reply: Value = ipc.recv().unwrap().unwrap();
+                    assert_eq!(reply, json!({"client": client, "echo": i}));
+                }
+            })
+            .join()
+            .unwrap();
+        }
+        server.join().unwrap();
+        // The listener removed its socket.
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_stale_socket_takeover() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("daemon.sock");
+
+        // A listener that died without removing its socket.
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let listener = NodeIpcListener::bind(&path).unwrap();
+        // A live listener is not taken over. Its first client is the probe.
+        assert!(NodeIpcListener::bind(&path).is_err());
+
+        let server = serve(listener, 2);
+        let ipc = NodeIpc::connect(&path).unwrap();
+        ipc.send("hello").unwrap();
+        let reply: Value = ipc.recv().unwrap().unwrap();
+        assert_eq!(reply, json!({"client": 1, "echo": "hello"}));
+        drop(ipc);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_not_a_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"data").unwrap();
+        assert!(NodeIpcListener::bind(&path).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_authorize() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("daemon.sock");
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        // Rejects the first client.
+        let policy: AuthorizePolicy = Arc::new({
+            let seen = seen.clone();
+            move |identity| {
+                let mut seen = seen.lock().unwrap();
+                seen.push(identity.clone());
+                seen.len() > 1
+            }
+        });
+        let listener = NodeIpcListener::bind(&path).unwrap().authorize(policy);
+        let server = thread::spawn(move || {
+            let ipc = listener.accept().unwrap();
+            ipc.recv::<Value>().unwrap()
+        });
+
+        let rejected = NodeIpc::connect(&path).unwrap();
+        let _ = rejected.send("from the rejected client");
+        assert!(!matches!(rejected.recv::<Value>(), Ok(Some(_))));
+        let accepted = NodeIpc::connect(&path).unwrap();
+        accepted.send("from the accepted client").unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            Some(json!("from the accepted client"))
+        );
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[0].uid, Some(unsafe { libc::getuid() }));
+        if cfg!(any(target_os = "linux", target_os = "macos")) {
+            assert_eq!(seen[0].pid, Some(std::process::id()));
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_accept_async() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("daemon.sock");
+        let listener = NodeIpcListener::bind(&path).unwrap();
+
+        let client = thread::spawn({
+            let path = path.clone();
+            move || {
+                let ipc = NodeIpc::connect(&path).unwrap();
+                ipc.send("hello").unwrap();
+            }
+        });
+        let ipc = listener.accept_async().await.unwrap();
+        let message: String = ipc.recv().unwrap().unwrap();
+        assert_eq!(message, "hello");
+        client.join().unwrap();
+    }
+}
@@ -0,0 +1,443 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! In-process channels, see `NodeIpc::new_loopback_pair`.
+//!
+//! `NodeIpc` reads from a `Reader` and writes to a `Writer`: either the same
+//! duplex file descriptor, or the ends of an in-memory `Channel`. Messages
+//! are framed the same way on both. Unlike a socket, a `Channel` buffers
+//! without limit, so sends never block, and fds sent on it are duplicated
+//! instead of passing through the kernel.
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::mem::ManuallyDrop;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
+
+use filedescriptor::AsRawFileDescriptor;
+use filedescriptor::FileDescriptor;
+#[cfg(unix)]
+use filedescriptor::FromRawFileDescriptor;
+use filedescriptor::RawFileDescriptor;
+
+use crate::nodeipc::NodeIpc;
+
+impl NodeIpc {
+    /// Two `NodeIpc`s connected in memory, for tests, or to run both sides of
+    /// a protocol in one process. They do not involve the singleton.
+    ///
+    /// They behave like the ends of a socketpair: `send_fd_vec` works, and
+    /// gives the receiver its own duplicates of the fds. When one end is
+    /// dropped, the other receives `None` once it read everything sent
+    /// before, and fails to send. Sending never blocks.
+    pub fn new_loopback_pair() -> (NodeIpc, NodeIpc) {
+        let channel = Arc::new(Channel::default());
+        let end = |end| {
+            let reader = LoopbackReader {
+                channel: channel.clone(),
+                end,
+            };
+            let writer = LoopbackWriter {
+                channel: channel.clone(),
+                end,
+            };
+            NodeIpc::from_transport(Reader::Loopback(reader), Writer::Loopback(writer))
+        };
+        (end(0), end(1))
+    }
+}
+
+/// Where `NodeIpc` writes.
+pub(crate) enum Writer {
+    /// Shares the fd of `Reader::Fd`, which closes it.
+    Fd(ManuallyDrop<FileDescriptor>),
+    Loopback(LoopbackWriter),
+}
+
+/// Where `NodeIpc` reads.
+pub(crate) enum Reader {
+    Fd(FileDescriptor),
+    Loopback(LoopbackReader),
+}
+
+impl Writer {
+    /// The file descriptor written to, if there is one.
+    pub(crate) fn fd(&self) -> Option<RawFileDescriptor> {
+        match self {
+            Writer::Fd(fd) => Some(fd.as_raw_file_descriptor()),
+            Writer::Loopback(_) => None,
+        }
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Writer::Fd(fd) => fd.write(buf),
+            Writer::Loopback(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Fd(fd) => fd.flush(),
+            Writer::Loopback(_) => Ok(()),
+        }
+    }
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Reader::Fd(fd) => fd.read(buf),
+            Reader::Loopback(r) => r.read(buf),
+        }
+    }
+}
+
+/// Both directions between ends 0 and 1. Direction `i` is written by end
+/// `i`, and read by the other end.
+#[derive(Default)]
+pub(crate) struct Channel {
+    directions: Mutex<[Direction; 2]>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct Direction {
+    data: VecDeque<u8>,
+    /// Bytes read and written so far, positions in the stream.
+    read: u64,
+    written: u64,
+    /// Fds sent by `send_fds`, with the position of the byte sent along.
+    /// Like the kernel does, a batch is discarded if its byte is read by a
+    /// plain `read`.
+    #[cfg(unix)]
+    fds: VecDeque<(u64, Vec<FileDescriptor>)>,
+    /// The writing end was dropped or shut down: reads return EOF once
+    /// `data` is drained.
+    write_closed: bool,
+    /// The reading end was dropped or shut down: writes fail, and so do
+    /// reads.
+    read_closed: bool,
+}
+
+impl Direction {
+    fn is_readable(&self) -> bool {
+        !self.data.is_empty() || self.write_closed || self.read_closed
+    }
+
+    fn is_eof(&self) -> bool {
+        self.read_closed || self.data.is_empty()
+    }
+
+    /// Discard fds sent along with bytes already read.
+    fn discard_stale_fds(&mut self) {
+        #[cfg(unix)]
+        while self.fds.front().map_or(false, |(pos, _)| *pos < self.read) {
+            self.fds.pop_front();
+        }
+    }
+}
+
+impl Channel {
+    /// Wait until direction `dir` is readable: it has data, or one of its
+    /// ends was closed. Returns `None` if `timeout` passed first.
+    fn wait_readable(
+        &self,
+        dir: usize,
+        timeout: Option<Duration>,
+    ) -> Option<MutexGuard<'_, [Direction; 2]>> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut directions = self.directions.lock().unwrap();
+        while !directions[dir].is_readable() {
+            directions = match deadline {
+                None => self.changed.wait(directions).unwrap(),
+                Some(deadline) => {
+                    let timeout = deadline.checked_duration_since(Instant::now())?;
+                    self.changed.wait_timeout(directions, timeout).unwrap().0
+                }
+            };
+        }
+        Some(directions)
+    }
+
+    /// Close both directions for both ends, like shutting down a socket.
+    pub(crate) fn shutdown(&self) {
+        let mut directions = self.directions.lock().unwrap();
+        for direction in directions.iter_mut() {
+            direction.write_closed = true;
+            direction.read_closed = true;
+        }
+        self.changed.notify_all();
+    }
+}
+
+pub(crate) struct LoopbackWriter {
+    pub(crate) channel: Arc<Channel>,
+    end: usize,
+}
+
+pub(crate) struct LoopbackReader {
+    channel: Arc<Channel>,
+    end: usize,
+}
+
+impl LoopbackWriter {
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut directions = self.channel.directions.lock().unwrap();
+        let direction = &mut directions[self.end];
+        if direction.write_closed || direction.read_closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        direction.data.extend(buf);
+        direction.written += buf.len() as u64;
+        self.channel.changed.notify_all();
+        Ok(buf.len())
+    }
+
+    /// Send duplicates of `fds`, along with a byte of data, like
+    /// `SCM_RIGHTS`. Fails like `sendmsg` if an fd is invalid.
+    #[cfg(unix)]
+    pub(crate) fn send_fds(&self, fds: &[RawFileDescriptor]) -> io::Result<()> {
+        let mut dups = Vec::with_capacity(fds.len());
+        for &fd in fds {
+            let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+            if dup < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            dups.push(unsafe { FileDescriptor::from_raw_file_descriptor(dup) });
+        }
+        let mut directions = self.channel.directions.lock().unwrap();
+        let direction = &mut directions[self.end];
+        if direction.write_closed || direction.read_closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let pos = direction.written;
+        direction.fds.push_back((pos, dups));
+        direction.data.push_back(b'\n');
+        direction.written += 1;
+        self.channel.changed.notify_all();
+        Ok(())
+    }
+}
+
+impl LoopbackReader {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut directions = self.channel.wait_readable(1 - self.end, None).unwrap();
+        let direction = &mut directions[1 - self.end];
+        if direction.is_eof() {
+            return Ok(0);
+        }
+        let len = buf.len().min(direction.data.len());
+        for (b, d) in buf.iter_mut().zip(direction.data.drain(..len)) {
+            *b = d;
+        }
+        direction.read += len as u64;
+        direction.discard_stale_fds();
+        Ok(len)
+    }
+
+    /// Wait until there is something to read, or `timeout` passes. Returns
+    /// `false` on timeout. EOF counts as readable.
+    pub(crate) fn wait_readable(&self, timeout: Duration) -> bool {
+        self.channel
+            .wait_readable(1 - self.end, Some(timeout))
+            .is_some()
+    }
+
+    /// Receive the fds sent by `send_fds`, consuming the byte sent along.
+    /// The caller owns them. Returns no fds at EOF, or if the next byte was
+    /// not sent along with fds.
+    #[cfg(unix)]
+    pub(crate) fn recv_fds(&self) -> Vec<RawFileDescriptor> {
+        use filedescriptor::IntoRawFileDescriptor;
+
+        let mut directions = self.channel.wait_readable(1 - self.end, None).unwrap();
+        let direction = &mut directions[1 - self.end];
+        if direction.is_eof() {
+            return Vec::new();
+        }
+        direction.data.pop_front();
+        let pos = direction.read;
+        direction.read += 1;
+        let fds = match direction.fds.front() {
+            Some((fds_pos, _)) if *fds_pos == pos => direction.fds.pop_front().unwrap().1,
+            _ => Vec::new(),
+        };
+        direction.discard_stale_fds();
+        fds.into_iter()
+            .map(|fd| fd.into_raw_file_descriptor())
+            .collect()
+    }
+}
+
+impl Drop for LoopbackWriter {
+    fn drop(&mut self) {
+        let mut directions = self.channel.directions.lock().unwrap();
+        directions[self.end].write_closed = true;
+        self.channel.changed.notify_all();
+    }
+}
+
+impl Drop for LoopbackReader {
+    fn drop(&mut self) {
+        let mut directions = self.channel.directions.lock().unwrap();
+        let direction = &mut directions[1 - self.end];
+        direction.read_closed = true;
+        direction.data.clear();
+        #[cfg(unix)]
+        direction.fds.clear();
+        self.channel.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use serde_json::json;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::call::CallRequest;
+    use crate::call::RetryConfig;
+
+    #[test]
+    fn test_round_trip() {
+        let (a, b) = NodeIpc::new_loopback_pair();
+        a.send(json!({"n": 1})).unwrap();
+        a.send("two").unwrap();
+        b.send(3).unwrap();
+        assert_eq!(b.recv::<Value>().unwrap(), Some(json!({"n": 1})));
+        assert_eq!(b.recv::<String>().unwrap().as_deref(), Some("two"));
+        assert_eq!(a.recv::<u32>().unwrap(), Some(3));
+
+        // Messages sent before closing are still received.
+        a.send("last").unwrap();
+        drop(a);
+        assert_eq!(b.recv::<String>().unwrap().as_deref(), Some("last"));
+        assert!(b.recv::<Value>().unwrap().is_none());
+        assert!(b.recv::<Value>().unwrap().is_none());
+        assert!(b.send("closed").is_err());
+    }
+
+    #[test]
+    fn test_large_message() {
+        // Larger than socket buffers: sending does not wait for the peer.
+        let message = "x".repeat(16 << 20);
+        let (a, b) = NodeIpc::new_loopback_pair();
+        a.send(&message).unwrap();
+        assert_eq!(b.recv::<String>().unwrap().unwrap(), message);
+
+        let (a, b) = NodeIpc::new_loopback_pair();
+        let (a, b) = (a.with_compression(1024), b.with_compression(1024));
+        a.send(&message).unwrap();
+        assert_eq!(b.recv::<String>().unwrap().unwrap(), message);
+        assert_eq!(a.stats().compressed_sent, 1);
+    }
+
+    #[test]
+    fn test_streams_and_calls() -> anyhow::Result<()> {
+        let (a, b) = NodeIpc::new_loopback_pair();
+        let (a, b) = (Arc::new(a), Arc::new(b));
+
+        let stream = a.open_stream();
+        stream.send("subscribe")?;
+        let accepted = b.accept_stream()?.unwrap();
+        assert_eq!(accepted.recv::<String>()?.as_deref(), Some("subscribe"));
+        for i in 0..3 {
+            accepted.send(i)?;
+        }
+        drop(accepted);
+        for i in 0..3 {
+            assert_eq!(stream.recv::<u32>()?, Some(i));
+        }
+        assert!(stream.recv::<u32>()?.is_none());
+
+        let peer = thread::spawn({
+            let b = b.clone();
+            move || {
+                let req: CallRequest<Value> = b.recv().unwrap().unwrap();
+                let n = req.request["n"].as_u64().unwrap();
+                req.reply(&b, json!({"n": n + 1})).unwrap();
+            }
+        });
+        let response: Value = a.call_with_timeout(
+            json!({"n": 1}),
+            Duration::from_secs(10),
+            RetryConfig::none(),
+        )?;
+        assert_eq!(response, json!({"n": 2}));
+        peer.join().unwrap();
+
+        // Nothing to read: waiting times out.
+        assert!(!a.wait_readable(Duration::from_millis(10))?);
+        drop(b);
+        assert!(a.wait_readable(Duration::from_secs(10))?);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send_fd_vec() {
+        use std::fs::File;
+        use std::io::Seek;
+        use std::os::unix::fs::MetadataExt;
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::io::FromRawFd;
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"0123456789").unwrap();
+        let (a, b) = NodeIpc::new_loopback_pair();
+        a.send("before").unwrap();
+        a.send_fd_vec(&[file.as_raw_fd(), file.as_raw_fd()])
+            .unwrap();
+        a.send("after").unwrap();
+        assert!(a.send_fd_vec(&[-1]).is_err());
+
+        assert_eq!(b.recv::<String>().unwrap().as_deref(), Some("before"));
+        let payload = b.recv_fd_vec().unwrap();
+        assert_eq!(payload.raw_fds.len(), 2);
+        for &raw_fd in &payload.raw_fds {
+            assert_ne!(raw_fd, file.as_raw_fd());
+            let mut received = unsafe { File::from_raw_fd(raw_fd) };
+            let (m1, m2) = (received.metadata().unwrap(), file.metadata().unwrap());
+            assert_eq!((m1.dev(), m1.ino()), (m2.dev(), m2.ino()));
+            // Duplicates share the file position.
+            assert_eq!(received.stream_position().unwrap(), 10);
+        }
+        assert_eq!(b.recv::<String>().unwrap().as_deref(), Some("after"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send_recv_stdio() {
+        let stat = |fd| {
+            let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+            assert_eq!(unsafe { libc::fstat(fd, &mut stat) }, 0);
+            (stat.st_dev, stat.st_ino)
+        };
+        let before: Vec<_> = (0..3).map(stat).collect();
+
+        let (a, b) = NodeIpc::new_loopback_pair();
+        a.send_stdio().unwrap();
+        b.recv_stdio().unwrap();
+
+        let after: Vec<_> = (0..3).map(stat).collect();
+        assert_eq!(before, after);
+        a.send("still works").unwrap();
+        assert_eq!(b.recv::<String>().unwrap().as_deref(), Some("still works"));
+    }
+}
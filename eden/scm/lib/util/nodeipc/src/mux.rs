@@ -0,0 +1,596 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Multiplexing independent streams over a single `NodeIpc` channel.
+//!
+//! Messages sent on an [`IpcStream`] are wrapped as
+//! `{"__nodeipc_stream": {stream_id, seq, initiator, fin}, "payload": ...}`.
+//! Plain messages (`NodeIpc::send`) are not wrapped and can be used alongside
+//! streams. The receiving side routes incoming lines to per-stream buffers,
+//! so a `recv` on one stream (or a plain `recv`) never consumes messages that
+//! belong to others.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::call::reply_id;
+use crate::nodeipc::NodeIpc;
+
+/// Serialized envelopes start with this. Used to tell them apart from plain
+/// messages without parsing the JSON.
+const ENVELOPE_PREFIX: &str = "{\"__nodeipc_stream\":";
+
+/// Maximum messages buffered per stream. A stream receiving more than this
+/// without being drained is considered stalled.
+const STREAM_QUEUE_LIMIT: usize = 4096;
+
+/// Maximum peer-opened streams remembered as dropped locally. A message for
+/// a stream forgotten earlier is taken as opening a new one, which is never
+/// accepted if the peer never sends one itself.
+const DROPPED_STREAMS_LIMIT: usize = 4096;
+
+/// Abandoned calls are forgotten after this long, as their reply may never
+/// come. A reply arriving later is left for `NodeIpc::recv`.
+const ABANDONED_CALL_TTL: Duration = Duration::from_secs(600);
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    // Must be the first field so the serialized form starts with `ENVELOPE_PREFIX`.
+    #[serde(rename = "__nodeipc_stream")]
+    header: Header,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    stream_id: u64,
+    seq: u64,
+    /// Whether the sender opened the stream.
+    initiator: bool,
+    #[serde(default)]
+    fin: bool,
+}
+
+/// Whether `line` is a serialized envelope ending its stream.
+pub(crate) fn is_stream_fin(line: &str) -> bool {
+    #[derive(Deserialize)]
+    struct HeaderOnly {
+        #[serde(rename = "__nodeipc_stream")]
+        header: Header,
+    }
+    line.starts_with(ENVELOPE_PREFIX)
+        && serde_json::from_str::<HeaderOnly>(line).map_or(false, |e| e.header.fin)
+}
+
+/// Streams opened by either side share the same id space. `local` tells
+/// who opened the stream.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub(crate) struct StreamKey {
+    id: u64,
+    local: bool,
+}
+
+#[derive(Default)]
+struct StreamBuffer {
+    next_seq: u64,
+    /// `None` is a FIN.
+    pending: BTreeMap<u64, Option<Value>>,
+    stalled: bool,
+    finished: bool,
+}
+
+#[derive(Default)]
+struct DemuxState {
+    /// Plain lines not yet consumed by `NodeIpc::recv`.
+    plain: VecDeque<String>,
+    streams: HashMap<StreamKey, StreamBuffer>,
+    /// Streams opened by the peer, not yet returned by `accept_stream`.
+    incoming: VecDeque<StreamKey>,
+    /// Peer-opened streams that were dropped locally, until the peer ends
+    /// them. `dropped_order` has the same streams, oldest first, to forget
+    /// them beyond `DROPPED_STREAMS_LIMIT`.
+    dropped: HashSet<StreamKey>,
+    dropped_order: VecDeque<StreamKey>,
+    /// Calls whose reply is no longer awaited, see `PendingCall`, with when
+    /// they were abandoned.
+    abandoned_calls: HashMap<u64, Instant>,
+    /// Whether a thread is reading from the file descriptor.
+    reading: bool,
+    eof: bool,
+}
+
+/// Outcome of waiting with a deadline.
+pub(crate) enum Waited<T> {
+    /// `None` on EOF.
+    Ready(Option<T>),
+    TimedOut,
+}
+
+/// Routes incoming lines to plain or per-stream queues.
+///
+/// Only one thread reads from the file descriptor at a time. Other receivers
+/// wait on the condvar and check their queues after each routed line.
+#[derive(Default)]
+pub(crate) struct Demux {
+    state: Mutex<DemuxState>,
+    cond: Condvar,
+    next_stream_id: AtomicU64,
+}
+
+impl DemuxState {
+    fn route(&mut self, line: String) -> anyhow::Result<()> {
+        if !self.abandoned_calls.is_empty() {
+            if let Some(id) = reply_id(&line) {
+                if self.abandoned_calls.remove(&id).is_some() {
+                    return Ok(());
+                }
+            }
+        }
+        if !line.starts_with(ENVELOPE_PREFIX) {
+            self.plain.push_back(line);
+            return Ok(());
+        }
+
+        let envelope: Envelope = serde_json::from_str(&line)
+            .context("in NodeIpc::recv, when parsing stream envelope")?;
+        let header = envelope.header;
+        let key = StreamKey {
+            id: header.stream_id,
+            local: !header.initiator,
+        };
+        if !self.streams.contains_key(&key) {
+            if key.local || self.dropped.contains(&key) {
+                // The stream was closed locally. Drop the message.
+                if header.fin && self.dropped.remove(&key) {
+                    // Nothing else will be sent on it.
+                    self.dropped_order.retain(|dropped| *dropped != key);
+                }
+                return Ok(());
+            }
+            self.streams.insert(key, StreamBuffer::default());
+            self.incoming.push_back(key);
+        }
+
+        let buffer = self.streams.get_mut(&key).unwrap();
+        if buffer.pending.len() >= STREAM_QUEUE_LIMIT {
+            buffer.stalled = true;
+            return Ok(());
+        }
+        let payload = if header.fin {
+            None
+        } else {
+            Some(envelope.payload.unwrap_or(Value::Null))
+        };
+        buffer.pending.insert(header.seq, payload);
+        Ok(())
+    }
+
+    fn pop_plain(&mut self) -> Option<anyhow::Result<Option<String>>> {
+        self.plain.pop_front().map(|line| Ok(Some(line)))
+    }
+
+    fn pop_plain_matching(
+        &mut self,
+        matches: &mut impl FnMut(&str) -> bool,
+    ) -> Option<anyhow::Result<Option<String>>> {
+        let index = self.plain.iter().position(|line| matches(line))?;
+        self.plain.remove(index).map(|line| Ok(Some(line)))
+    }
+
+    fn pop_stream(&mut self, key: StreamKey) -> Option<anyhow::Result<Option<Value>>> {
+        let buffer = match self.streams.get_mut(&key) {
+            None => return Some(Ok(None)),
+            Some(buffer) => buffer,
+        };
+        if buffer.finished {
+            return Some(Ok(None));
+        }
+        if buffer.stalled {
+            return Some(Err(anyhow::format_err!(
+                "NodeIpc stream {} stalled: more than {} messages were not received",
+                key.id,
+                STREAM_QUEUE_LIMIT
+            )));
+        }
+        let next_seq = buffer.next_seq;
+        let payload = buffer.pending.remove(&next_seq)?;
+        buffer.next_seq += 1;
+        if payload.is_none() {
+            buffer.finished = true;
+        }
+        Some(Ok(payload))
+    }
+
+    fn pop_incoming(&mut self) -> Option<anyhow::Result<Option<StreamKey>>> {
+        self.incoming.pop_front().map(|key| Ok(Some(key)))
+    }
+
+    /// Drop the messages the peer sends on `key` from now on, until its FIN.
+    fn drop_stream(&mut self, key: StreamKey) {
+        if !self.dropped.insert(key) {
+            return;
+        }
+        self.dropped_order.push_back(key);
+        while self.dropped_order.len() > DROPPED_STREAMS_LIMIT {
+            if let Some(oldest) = self.dropped_order.pop_front() {
+                self.dropped.remove(&oldest);
+            }
+        }
+    }
+
+    /// Update the queue depths reported by `NodeIpc::stats`.
+    fn record_queued(&self, ipc: &NodeIpc) {
+        let stream = self.streams.values().map(|b| b.pending.len()).sum();
+        ipc.counters.set_queued(self.plain.len(), stream);
+    }
+}
+
+impl Demux {
+    /// Wait until `pop` produces a value, reading and routing lines as needed.
+    /// Returns `None` on EOF, after queued values are consumed.
+    fn wait_for<T>(
+        &self,
+        ipc: &NodeIpc,
+        pop: impl FnMut(&mut DemuxState) -> Option<anyhow::Result<Option<T>>>,
+    ) -> anyhow::Result<Option<T>> {
+        match self.wait_until(ipc, None, pop)? {
+            Waited::Ready(value) => Ok(value),
+            Waited::TimedOut => unreachable!("no deadline was set"),
+        }
+    }
+
+    /// Like `wait_for`, but gives up at `deadline`.
+    fn wait_until<T>(
+        &self,
+        ipc: &NodeIpc,
+        deadline: Option<Instant>,
+        mut pop: impl FnMut(&mut DemuxState) -> Option<anyhow::Result<Option<T>>>,
+    ) -> anyhow::Result<Waited<T>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(result) = pop(&mut *state) {
+                state.record_queued(ipc);
+                return result.map(Waited::Ready);
+            }
+            if state.eof {
+                return Ok(Waited::Ready(None));
+            }
+            let remaining = match deadline {
+                None => None,
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) if !remaining.is_zero() => Some(remaining),
+                    _ => return Ok(Waited::TimedOut),
+                },
+            };
+            if state.reading {
+                state = match remaining {
+                    None => self.cond.wait(state).unwrap(),
+                    Some(remaining) => self.cond.wait_timeout(state, remaining).unwrap().0,
+                };
+                continue;
+            }
+
+            state.reading = true;
+            drop(state);
+            let line = ipc.recv_line_until(deadline);
+            state = self.state.lock().unwrap();
+            state.reading = false;
+            let routed = match line {
+                Ok(Waited::Ready(Some(line))) => state.route(line),
+                Ok(Waited::Ready(None)) => {
+                    state.eof = true;
+                    Ok(())
+                }
+                Ok(Waited::TimedOut) => Ok(()),
+                Err(e) => Err(e),
+            };
+            state.record_queued(ipc);
+            self.cond.notify_all();
+            routed?;
+        }
+    }
+
+    /// Receive a plain (non-multiplexed) line.
+    pub(crate) fn recv_plain_line(&self, ipc: &NodeIpc) -> anyhow::Result<Option<String>> {
+        self.wait_for(ipc, |state| state.pop_plain())
+    }
+
+    /// Receive the first plain line for which `matches` returns true. Other
+    /// plain lines stay queued for `recv_plain_line`.
+    pub(crate) fn recv_plain_line_where(
+        &self,
+        ipc: &NodeIpc,
+        mut matches: impl FnMut(&str) -> bool,
+    ) -> anyhow::Result<Option<String>> {
+        self.wait_for(ipc, |state| state.pop_plain_matching(&mut matches))
+    }
+
+    /// Receive the first plain line for which `matches` returns true, before
+    /// `deadline`. Other plain lines stay queued for `recv_plain_line`.
+    pub(crate) fn recv_plain_line_matching(
+        &self,
+        ipc: &NodeIpc,
+        deadline: Instant,
+        mut matches: impl FnMut(&str) -> bool,
+    ) -> anyhow::Result<Waited<String>> {
+        self.wait_until(ipc, Some(deadline), |state| {
+            state.pop_plain_matching(&mut matches)
+        })
+    }
+
+    /// Discard the reply to the call `id`, whether it is queued or arrives
+    /// later.
+    pub(crate) fn abandon_call(&self, ipc: &NodeIpc, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        match state
+            .plain
+            .iter()
+            .position(|line| reply_id(line) == Some(id))
+        {
+            Some(index) => {
+                state.plain.remove(index);
+            }
+            None => {
+                let now = Instant::now();
+                state
+                    .abandoned_calls
+                    .retain(|_, abandoned| now.duration_since(*abandoned) < ABANDONED_CALL_TTL);
+                state.abandoned_calls.insert(id, now);
+            }
+        }
+        state.record_queued(ipc);
+    }
+}
+
+impl NodeIpc {
+    /// Open a new stream. Messages sent on the stream are delivered to the
+    /// peer's stream returned by `accept_stream`.
+    pub fn open_stream(self: &Arc<Self>) -> IpcStream {
+        let id = self.demux.next_stream_id.fetch_add(1, Ordering::AcqRel);
+        let key = StreamKey { id, local: true };
+        let mut state = self.demux.state.lock().unwrap();
+        state.streams.insert(key, StreamBuffer::default());
+        drop(state);
+        IpcStream::new(self.clone(), key)
+    }
+
+    /// Wait for the peer to open a stream. A stream is "opened" when its
+    /// first message arrives. Returns `None` if the channel was closed.
+    pub fn accept_stream(self: &Arc<Self>) -> anyhow::Result<Option<IpcStream>> {
+        let key = self.demux.wait_for(self, |state| state.pop_incoming())?;
+        Ok(key.map(|key| IpcStream::new(self.clone(), key)))
+    }
+}
+
+/// A virtual stream over a `NodeIpc` channel.
+///
+/// `close` (or drop) sends a FIN so the peer's `recv` returns `None`.
+pub struct IpcStream {
+    ipc: Arc<NodeIpc>,
+    key: StreamKey,
+    next_seq: AtomicU64,
+    closed: AtomicBool,
+}
+
+impl IpcStream {
+    fn new(ipc: Arc<NodeIpc>, key: StreamKey) -> Self {
+        Self {
+            ipc,
+            key,
+            next_seq: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// The stream id. Unique among streams opened by the same side.
+    pub fn id(&self) -> u64 {
+        self.key.id
+    }
+
+    /// Send a message on this stream.
+    pub fn send(&self, message: impl Serialize) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.closed.load(Ordering::Acquire),
+            "NodeIpc stream {} was closed",
+            self.key.id
+        );
+        let payload = serde_json::to_value(message)
+            .map_err(|e| {
+                self.ipc.counters.serialize_error();
+                e
+            })
+            .context("in IpcStream::send, when converting message to JSON")?;
+        self.send_envelope(Some(payload), false)
+    }
+
+    /// Receive a message sent to this stream. Block if there are no new
+    /// messages. Returns `None` if the peer closed the stream, or the
+    /// channel was closed.
+    pub fn recv<V: DeserializeOwned>(&self) -> anyhow::Result<Option<V>> {
+        let key = self.key;
+        let value = match self
+            .ipc
+            .demux
+            .wait_for(&self.ipc, |state| state.pop_stream(key))?
+        {
+            None => return Ok(None),
+            Some(value) => value,
+        };
+        let result = serde_json::from_value(value).map_err(|e| {
+            self.ipc.counters.deserialize_error();
+            e
+        });
+        let result = result.with_context(|| {
+            format!(
+                "in IpcStream::recv, when deserializing to {}",
+                std::any::type_name::<V>(),
+            )
+        })?;
+        Ok(Some(result))
+    }
+
+    /// Close the sending side of the stream. The peer's `recv` returns
+    /// `None` after receiving messages sent before the close.
+    pub fn close(&self) -> anyhow::Result<()> {
+        if self.closed.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        self.send_envelope(None, true)
+    }
+
+    fn send_envelope(&self, payload: Option<Value>, fin: bool) -> anyhow::Result<()> {
+        let envelope = Envelope {
+            header: Header {
+                stream_id: self.key.id,
+                seq: self.next_seq.fetch_add(1, Ordering::AcqRel),
+                initiator: self.key.local,
+                fin,
+            },
+            payload,
+        };
+        self.ipc.send(envelope)
+    }
+}
+
+impl Drop for IpcStream {
+    fn drop(&mut self) {
+        let _ = self.close();
+        let mut state = self.ipc.demux.state.lock().unwrap();
+        // Whether the peer's FIN was received, read or not.
+        let ended = state.streams.remove(&self.key).map_or(false, |buffer| {
+            buffer.finished || buffer.pending.values().any(Option::is_none)
+        });
+        state.record_queued(&self.ipc);
+        if !self.key.local && !ended {
+            state.drop_stream(self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testutil::ipc_pair;
+
+    #[test]
+    fn test_interleaved_streams() -> anyhow::Result<()> {
+        let (a, b) = ipc_pair();
+        let (a, b) = (Arc::new(a), Arc::new(b));
+
+        let streams: Vec<IpcStream> = (0..3).map(|_| a.open_stream()).collect();
+        for i in 0..5 {
+            for (s, stream) in streams.iter().enumerate() {
+                stream.send(json!([s, i]))?;
+            }
+            a.send(json!(["plain", i]))?;
+        }
+        for stream in &streams {
+            stream.close()?;
+        }
+
+        for s in 0..3 {
+            let stream = b.accept_stream()?.unwrap();
+            let mut received = Vec::new();
+            while let Some(value) = stream.recv::<Value>()? {
+                received.push(value);
+            }
+            let expected: Vec<Value> = (0..5).map(|i| json!([s, i])).collect();
+            assert_eq!(received, expected);
+            // Further recv after FIN keeps returning None.
+            assert!(stream.recv::<Value>()?.is_none());
+        }
+
+        for i in 0..5 {
+            assert_eq!(b.recv::<Value>()?, Some(json!(["plain", i])));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fin_on_drop() -> anyhow::Result<()> {
+        let (a, b) = ipc_pair();
+        let (a, b) = (Arc::new(a), Arc::new(b));
+
+        let stream = a.open_stream();
+        stream.send("hello")?;
+        drop(stream);
+
+        let stream = b.accept_stream()?.unwrap();
+        assert_eq!(stream.recv::<String>()?.as_deref(), Some("hello"));
+        assert!(stream.recv::<String>()?.is_none());
+
+        // Replies on a stream accepted by the peer arrive on the opener's stream.
+        let stream2 = a.open_stream();
+        stream2.send(1)?;
+        let accepted = b.accept_stream()?.unwrap();
+        assert_eq!(accepted.recv::<u32>()?, Some(1));
+        accepted.send(2)?;
+        assert_eq!(stream2.recv::<u32>()?, Some(2));
+
+        drop(a);
+        drop(stream2);
+        assert!(accepted.recv::<u32>()?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dropped_streams() -> anyhow::Result<()> {
+        let (a, b) = ipc_pair();
+        let (a, b) = (Arc::new(a), Arc::new(b));
+
+        let stream = a.open_stream();
+        stream.send(1)?;
+        drop(b.accept_stream()?.unwrap());
+        assert_eq!(b.demux.state.lock().unwrap().dropped.len(), 1);
+
+        // Messages after the drop are discarded, and the FIN forgets the
+        // stream.
+        stream.send(2)?;
+        drop(stream);
+        a.send("plain")?;
+        assert_eq!(b.recv::<String>()?.as_deref(), Some("plain"));
+        assert!(b.demux.state.lock().unwrap().dropped.is_empty());
+
+        // A stream whose FIN was received is not remembered.
+        drop(a.open_stream());
+        drop(b.accept_stream()?.unwrap());
+        assert!(b.demux.state.lock().unwrap().dropped.is_empty());
+
+        // At most `DROPPED_STREAMS_LIMIT` streams are remembered.
+        let peer_key = |id| StreamKey { id, local: false };
+        let mut state = DemuxState::default();
+        for id in 0..DROPPED_STREAMS_LIMIT as u64 + 10 {
+            state.drop_stream(peer_key(id));
+        }
+        assert_eq!(state.dropped.len(), DROPPED_STREAMS_LIMIT);
+        assert_eq!(state.dropped_order.len(), DROPPED_STREAMS_LIMIT);
+        assert!(!state.dropped.contains(&peer_key(9)));
+        assert!(state.dropped.contains(&peer_key(10)));
+        Ok(())
+    }
+}
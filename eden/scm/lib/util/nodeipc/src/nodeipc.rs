@@ -13,16 +13,41 @@ use std::io::BufRead;
 use std::io::Read;
 use std::io::Write;
 use std::mem::ManuallyDrop;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::Context;
+use filedescriptor::pollfd;
+use filedescriptor::AsRawFileDescriptor;
 use filedescriptor::FileDescriptor;
 use filedescriptor::FromRawFileDescriptor;
 use filedescriptor::IntoRawSocketDescriptor;
 use filedescriptor::RawFileDescriptor;
+use filedescriptor::POLLIN;
+use once_cell::sync::OnceCell;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
+use crate::call::NodeIpcError;
+use crate::compress;
+use crate::compress::CompressionError;
+use crate::encrypt;
+use crate::encrypt::Encryption;
+use crate::encrypt::EncryptionError;
+use crate::identity::PeerIdentity;
+use crate::journal::Journal;
+use crate::loopback::Reader;
+use crate::loopback::Writer;
+use crate::mux::Demux;
+use crate::mux::Waited;
+use crate::ratelimit::InboundLimiter;
+use crate::stats::IpcCounters;
+use crate::trace::TraceDirection;
+use crate::trace::Tracer;
+
 // 0, 1, 2, ..., file descriptor used by libc (or msvcrt, ucrt).
 //
 // This is different from RawFileDescriptor, which is
@@ -40,13 +65,41 @@ pub type LibcFd = libc::c_int;
 pub struct NodeIpc {
     // Mutex is used so the static singleton is easier to use
     // (send and recv do not take &mut self).
-    // `r` and `w` share a same file descriptor. `FileDescriptor` closes the underlying
-    // fd on drop. Use `ManuallyDrop` to avoid duplicated closing.
-    pub(crate) w: Mutex<ManuallyDrop<FileDescriptor>>,
-    pub(crate) r: Mutex<io::BufReader<FileDescriptor>>,
+    // `r` and `w` share a same file descriptor, or are the ends of a loopback
+    // channel. `FileDescriptor` closes the underlying fd on drop. Use
+    // `ManuallyDrop` to avoid duplicated closing.
+    pub(crate) w: Mutex<Writer>,
+    pub(crate) r: Mutex<io::BufReader<Reader>>,
     // Whether compatible with libuv.
     // If true, on Windows, we'll add extra frame headers per message.
     pub(crate) libuv_compat: bool,
+    // Routes incoming messages to plain `recv` or multiplexed streams.
+    pub(crate) demux: Demux,
+    // Records traffic if set. See `enable_trace`.
+    pub(crate) tracer: OnceCell<Tracer>,
+    // Retains sent messages for replay if set. See `enable_retention`.
+    pub(crate) journal: OnceCell<Journal>,
+    // Traffic counters. See `stats`.
+    pub(crate) counters: Arc<IpcCounters>,
+    // Set when a watched peer exits. See `watch_peer`.
+    pub(crate) peer_dead: AtomicBool,
+    // Messages at least this long are compressed. See `with_compression`.
+    pub(crate) compression_threshold: Option<usize>,
+    // Larger decompressed messages are rejected. See
+    // `with_max_decompressed_size`.
+    pub(crate) max_decompressed_size: usize,
+    // Send fds by path if sending them is not permitted. See
+    // `with_fd_path_fallback`.
+    pub(crate) fd_path_fallback: bool,
+    // See `peer_identity`.
+    pub(crate) peer_identity: OnceCell<PeerIdentity>,
+    // See `set_inbound_limits`.
+    pub(crate) inbound_limiter: Mutex<Option<InboundLimiter>>,
+    // Keys and counters of an encrypted channel. See `with_encryption`.
+    pub(crate) encryption: Option<Encryption>,
+    // The start of a message whose read timed out. Only used with `r`
+    // locked. See `recv_line_until`.
+    pub(crate) partial_read: Mutex<Vec<u8>>,
 }
 
 impl NodeIpc {
@@ -103,11 +156,44 @@ impl NodeIpc {
         let get_fd = || unsafe { FileDescriptor::from_raw_file_descriptor(raw_file_descriptor) };
         let fd = get_fd();
 
-        let r = Mutex::new(io::BufReader::new(fd));
-        let w = Mutex::new(ManuallyDrop::new(get_fd()));
+        let r = Reader::Fd(fd);
+        let w = Writer::Fd(ManuallyDrop::new(get_fd()));
+        Ok(Self::from_transport(r, w))
+    }
+
+    pub(crate) fn from_transport(r: Reader, w: Writer) -> Self {
+        let r = Mutex::new(io::BufReader::new(r));
+        let w = Mutex::new(w);
         let libuv_compat = false;
-        let ipc = Self { r, w, libuv_compat };
-        Ok(ipc)
+        let demux = Demux::default();
+        let tracer = OnceCell::new();
+        let journal = OnceCell::new();
+        let counters = IpcCounters::register();
+        let peer_dead = AtomicBool::new(false);
+        let compression_threshold = None;
+        let max_decompressed_size = compress::DEFAULT_MAX_DECOMPRESSED_SIZE;
+        let fd_path_fallback = false;
+        let peer_identity = OnceCell::new();
+        let inbound_limiter = Mutex::new(None);
+        let encryption = None;
+        let partial_read = Mutex::new(Vec::new());
+        Self {
+            r,
+            w,
+            libuv_compat,
+            demux,
+            tracer,
+            journal,
+            counters,
+            peer_dead,
+            compression_threshold,
+            max_decompressed_size,
+            fd_path_fallback,
+            peer_identity,
+            inbound_limiter,
+            encryption,
+            partial_read,
+        }
     }
 
     /// Initialize `NodeIpc` from a socket-ish. A socket-ish provides the raw socket
@@ -131,7 +217,14 @@ impl NodeIpc {
     /// and the other side is not receiving the message.
     pub fn send(&self, message: impl Serialize) -> anyhow::Result<()> {
         let mut line = serde_json::to_string(&message)
+            .map_err(|e| {
+                self.counters.serialize_error();
+                e
+            })
             .context("in NodeIpc::send, when converting message to JSON")?;
+        if let Some(journal) = self.journal.get() {
+            journal.record(&message, line.len());
+        }
         line.push('\n');
         self.send_line(line)
     }
@@ -140,13 +233,18 @@ impl NodeIpc {
     /// messages. Returns `None` if the other side has closed the channel.
     pub fn recv<V: DeserializeOwned>(&self) -> anyhow::Result<Option<V>> {
         let line = match self
-            .recv_line()
+            .demux
+            .recv_plain_line(self)
             .context("in NodeIpc::recv, when reading line from file descriptor")?
         {
             None => return Ok(None),
             Some(line) => line,
         };
-        let result = serde_json::from_str(&line).with_context(|| {
+        let result = serde_json::from_str(&line).map_err(|e| {
+            self.counters.deserialize_error();
+            e
+        });
+        let result = result.with_context(|| {
             format!(
                 "in NodeIpc::recv, when deserializing {} to {}",
                 FmtString(line.trim_end()),
@@ -158,65 +256,235 @@ impl NodeIpc {
 
     /// Send a line. Blocking. The line should include the ending '\n'.
     #[inline(never)]
-    fn send_line(&self, line: String) -> anyhow::Result<()> {
+    pub(crate) fn send_line(&self, line: String) -> anyhow::Result<()> {
+        if self.is_peer_dead() {
+            return Err(NodeIpcError::PeerClosed.into());
+        }
+        let compressed = match self.compression_threshold() {
+            Some(threshold) => compress::compress(&line, threshold)
+                .context("in NodeIpc::send, when compressing message")?,
+            None => None,
+        };
+        let data = compressed.as_deref().unwrap_or(line.as_bytes());
         let mut w = self.w.lock().unwrap();
 
+        // Sealed under the lock, so that messages are sent in the order of
+        // their counters.
+        let sealed = self.encryption.as_ref().map(|e| e.seal(data)).transpose()?;
+        let compressed_len = data.len();
+        let data = sealed.as_deref().unwrap_or(data);
+
         let payload = if cfg!(windows) || !self.libuv_compat {
-            // Emulate libuv pipe frame header on Windows, or if libuv_compat is false.
-            // The header provides a hint about the payload size, which can be useful
-            // to prevent over-read that loses special control messages from sendmsg().
-            // See https://github.com/libuv/libuv/blob/e1143f12657444c750e47ab3e1fb70ae6a030620/src/win/pipe.c#L1745-L1752
-            let mut header = UvPipeWin32FrameHeader::default();
-            let len = line.len();
-            if len > 0 {
-                assert!(len <= u32::MAX as usize);
-                const UV__IPC_FRAME_HAS_DATA: u32 = 1;
-                header.flags |= UV__IPC_FRAME_HAS_DATA;
-                header.data_length = len as u32;
-            }
-            let header: [u8; std::mem::size_of::<UvPipeWin32FrameHeader>()] =
-                unsafe { std::mem::transmute(header) };
-            let mut payload = Vec::with_capacity(header.len() + len);
-            payload.extend_from_slice(&header);
-            payload.extend_from_slice(line.as_bytes());
-            Cow::Owned(payload)
+            Cow::Owned(frame(data))
         } else {
-            Cow::Borrowed(line.as_bytes())
+            Cow::Borrowed(data)
         };
 
+        let start = Instant::now();
         w.write_all(payload.as_ref()).with_context(|| {
             format!(
                 "in NodeIpc::send, when sending message {}",
                 FmtString(line.trim_end())
             )
-        })
+        })?;
+        self.counters.sent(data.len(), start.elapsed());
+        if compressed.is_some() {
+            self.counters.compressed(line.len(), compressed_len);
+        }
+        if let Some(tracer) = self.tracer.get() {
+            tracer.message(TraceDirection::Send, &line);
+        }
+        Ok(())
     }
 
     /// Receive a line. Blocking. The line would include the ending '\n'.
+    /// Lines over the inbound limits are not returned, see
+    /// `set_inbound_limits`.
     #[inline(never)]
-    fn recv_line(&self) -> anyhow::Result<Option<String>> {
+    pub(crate) fn recv_line(&self) -> anyhow::Result<Option<String>> {
+        match self.recv_line_until(None)? {
+            Waited::Ready(line) => Ok(line),
+            Waited::TimedOut => unreachable!("no deadline was set"),
+        }
+    }
+
+    /// Like `recv_line`, but gives up at `deadline`, even in the middle of a
+    /// message. The part of the message read is kept for the next call.
+    pub(crate) fn recv_line_until(
+        &self,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<Waited<String>> {
+        loop {
+            if !self.delay_inbound(deadline) {
+                return Ok(Waited::TimedOut);
+            }
+            let line = match self.recv_line_untraced(deadline) {
+                Ok(Waited::TimedOut) => return Ok(Waited::TimedOut),
+                Ok(Waited::Ready(line)) => Ok(line),
+                Err(e) => Err(e),
+            };
+            let line = self.check_peer(line)?;
+            let line = line.map(|(line, len)| {
+                self.counters.received(len);
+                line
+            });
+            if let Some(line) = line.as_ref() {
+                if !self.admit_inbound(line)? {
+                    continue;
+                }
+            }
+            if let (Some(tracer), Some(line)) = (self.tracer.get(), line.as_ref()) {
+                tracer.message(TraceDirection::Recv, line);
+            }
+            return Ok(Waited::Ready(line));
+        }
+    }
+
+    /// Wait until there is something to read, or `timeout` passes.
+    /// Returns `false` on timeout. EOF counts as readable.
+    ///
+    /// On Windows, this only works if the underlying handle is a socket.
+    pub(crate) fn wait_readable(&self, timeout: Duration) -> anyhow::Result<bool> {
+        let r = self.r.lock().unwrap();
+        if !r.buffer().is_empty() {
+            return Ok(true);
+        }
+        poll_readable(r.get_ref(), timeout)
+    }
+
+    /// Returns the line, and how many bytes were read for it.
+    fn recv_line_untraced(
+        &self,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<Waited<(String, usize)>> {
         let mut r = self.r.lock().unwrap();
+        let partial = &mut *self.partial_read.lock().unwrap();
         if cfg!(windows) || !self.libuv_compat {
             // Use unbuffered read to avoid over reading.
             assert!(r.buffer().is_empty());
-            let r = r.get_mut();
-            let mut libuv_pipe_frame_header = [0u8; std::mem::size_of::<UvPipeWin32FrameHeader>()];
-            r.read_exact(&mut libuv_pipe_frame_header)
-                .context("in NodeIpc::recv, when reading frame header")?;
-            let header: UvPipeWin32FrameHeader =
-                unsafe { std::mem::transmute(libuv_pipe_frame_header) };
-            let size = header.data_length as usize;
-            if size == 0 {
-                return Ok(None);
+            let mut buf = match read_frame_until(r.get_mut(), partial, deadline)? {
+                Waited::TimedOut => return Ok(Waited::TimedOut),
+                Waited::Ready(None) => return Ok(Waited::Ready(None)),
+                Waited::Ready(Some(buf)) => buf,
+            };
+            let size = buf.len();
+            match &self.encryption {
+                Some(encryption) => buf = encryption.open(&buf)?,
+                None if encrypt::is_encrypted(&buf) => {
+                    if encrypt::is_hello(&buf) {
+                        self.refuse_hello();
+                    }
+                    return Err(EncryptionError::NotEnabled.into());
+                }
+                None => {}
+            }
+            if compress::is_compressed(&buf) {
+                if self.compression_threshold().is_none() {
+                    return Err(CompressionError::NotEnabled.into());
+                }
+                self.counters.decompressed();
+                return Ok(Waited::Ready(Some((
+                    compress::decompress(&buf, self.max_decompressed_size)?,
+                    size,
+                ))));
             }
-            let mut buf = vec![0u8; size];
-            r.read_exact(&mut buf).context("in NodeIpc::recv")?;
             let line = String::from_utf8(buf).context("in NodeIpc::recv")?;
-            return Ok(Some(line));
+            return Ok(Waited::Ready(Some((line, size))));
+        }
+        if !read_line_until(&mut r, partial, deadline).context("in NodeIpc::recv")? {
+            return Ok(Waited::TimedOut);
+        }
+        let line = std::mem::take(partial);
+        if line.is_empty() {
+            return Ok(Waited::Ready(None));
+        }
+        let n = line.len();
+        let line = String::from_utf8(line).context("in NodeIpc::recv")?;
+        Ok(Waited::Ready(Some((line, n))))
+    }
+}
+
+/// Wait until `r` has something to read, or `timeout` passes. Returns
+/// `false` on timeout. EOF counts as readable.
+fn poll_readable(r: &Reader, timeout: Duration) -> anyhow::Result<bool> {
+    let fd = match r {
+        Reader::Fd(fd) => fd.as_raw_file_descriptor(),
+        Reader::Loopback(r) => return Ok(r.wait_readable(timeout)),
+    };
+    let mut fds = [pollfd {
+        fd: fd as _,
+        events: POLLIN,
+        revents: 0,
+    }];
+    let ready = filedescriptor::poll(&mut fds, Some(timeout))
+        .context("in NodeIpc::recv, when polling file descriptor")?;
+    Ok(ready > 0)
+}
+
+/// Wait until `r` has something to read. Returns `false` if `deadline`
+/// passed first. Returns right away without a deadline, reads then block.
+fn wait_for_data(r: &Reader, deadline: Option<Instant>) -> anyhow::Result<bool> {
+    match deadline {
+        None => Ok(true),
+        Some(deadline) => poll_readable(r, deadline.saturating_duration_since(Instant::now())),
+    }
+}
+
+/// Read from `r` until `buf` is `len` bytes long. Returns `false` if
+/// `deadline` passed first, with what was read so far in `buf`.
+fn fill_until(
+    r: &mut Reader,
+    buf: &mut Vec<u8>,
+    len: usize,
+    deadline: Option<Instant>,
+) -> anyhow::Result<bool> {
+    while buf.len() < len {
+        if !wait_for_data(r, deadline)? {
+            return Ok(false);
+        }
+        let start = buf.len();
+        buf.resize(len, 0);
+        let result = r.read(&mut buf[start..]);
+        buf.truncate(start + result.as_ref().map_or(0, |n| *n));
+        match result {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
+
+/// Read from `r` up to the next newline, included, or EOF, appending to
+/// `line`. Returns `false` if `deadline` passed first, with what was read so
+/// far in `line`.
+fn read_line_until(
+    r: &mut io::BufReader<Reader>,
+    line: &mut Vec<u8>,
+    deadline: Option<Instant>,
+) -> anyhow::Result<bool> {
+    loop {
+        if r.buffer().is_empty() && !wait_for_data(r.get_ref(), deadline)? {
+            return Ok(false);
+        }
+        let available = match r.fill_buf() {
+            Ok(available) => available,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if available.is_empty() {
+            return Ok(true);
+        }
+        let (len, done) = match available.iter().position(|&b| b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (available.len(), false),
+        };
+        line.extend_from_slice(&available[..len]);
+        r.consume(len);
+        if done {
+            return Ok(true);
         }
-        let mut line = String::new();
-        let n = r.read_line(&mut line).context("in NodeIpc::recv")?;
-        if n == 0 { Ok(None) } else { Ok(Some(line)) }
     }
 }
 
@@ -262,6 +530,56 @@ impl<'a> fmt::Display for FmtString<'a> {
     }
 }
 
+/// Prefix `data` with a frame header.
+///
+/// Emulate libuv pipe frame header on Windows, or if libuv_compat is false.
+/// The header provides a hint about the payload size, which can be useful
+/// to prevent over-read that loses special control messages from sendmsg().
+/// See https://github.com/libuv/libuv/blob/e1143f12657444c750e47ab3e1fb70ae6a030620/src/win/pipe.c#L1745-L1752
+pub(crate) fn frame(data: &[u8]) -> Vec<u8> {
+    let mut header = UvPipeWin32FrameHeader::default();
+    let len = data.len();
+    if len > 0 {
+        assert!(len <= u32::MAX as usize);
+        const UV__IPC_FRAME_HAS_DATA: u32 = 1;
+        header.flags |= UV__IPC_FRAME_HAS_DATA;
+        header.data_length = len as u32;
+    }
+    let header: [u8; std::mem::size_of::<UvPipeWin32FrameHeader>()] =
+        unsafe { std::mem::transmute(header) };
+    let mut payload = Vec::with_capacity(header.len() + len);
+    payload.extend_from_slice(&header);
+    payload.extend_from_slice(data);
+    payload
+}
+
+/// Read the payload of a frame written by `frame`, giving up at `deadline`.
+/// Returns `None` for an empty frame. The bytes of the frame read so far are
+/// kept in `partial`, to continue from on the next call. Unbuffered, to
+/// avoid over reading.
+pub(crate) fn read_frame_until(
+    r: &mut Reader,
+    partial: &mut Vec<u8>,
+    deadline: Option<Instant>,
+) -> anyhow::Result<Waited<Vec<u8>>> {
+    const HEADER_LEN: usize = std::mem::size_of::<UvPipeWin32FrameHeader>();
+    if !fill_until(r, partial, HEADER_LEN, deadline)
+        .context("in NodeIpc::recv, when reading frame header")?
+    {
+        return Ok(Waited::TimedOut);
+    }
+    let mut libuv_pipe_frame_header = [0u8; HEADER_LEN];
+    libuv_pipe_frame_header.copy_from_slice(&partial[..HEADER_LEN]);
+    let header: UvPipeWin32FrameHeader = unsafe { std::mem::transmute(libuv_pipe_frame_header) };
+    let size = header.data_length as usize;
+    if !fill_until(r, partial, HEADER_LEN + size, deadline).context("in NodeIpc::recv")? {
+        return Ok(Waited::TimedOut);
+    }
+    let buf = partial.split_off(HEADER_LEN);
+    partial.clear();
+    Ok(Waited::Ready(if size == 0 { None } else { Some(buf) }))
+}
+
 // See https://github.com/libuv/libuv/blob/e1143f12657444c750e47ab3e1fb70ae6a030620/src/win/pipe.c#L74-L79
 #[repr(C)]
 #[derive(Default)]
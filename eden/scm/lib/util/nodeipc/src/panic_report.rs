@@ -0,0 +1,225 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Reporting panics to the other side of the channel before the process
+//! exits, so it can show what went wrong instead of only seeing the channel
+//! close.
+//!
+//! The report is a `{"type": "panic", ...}` message, see `PanicMessage`.
+
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::backtrace::BacktraceStatus;
+use std::panic;
+use std::panic::PanicInfo;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+
+use crate::nodeipc::NodeIpc;
+use crate::singleton::get_singleton;
+
+/// How long a panic waits for its report to be sent.
+const SEND_TIMEOUT: Duration = Duration::from_millis(200);
+
+static INSTALLED: OnceCell<()> = OnceCell::new();
+static CHANNEL: Mutex<Option<Arc<NodeIpc>>> = Mutex::new(None);
+// Set while a report is being sent. Panics meanwhile, including of the
+// sending thread, are not reported.
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize)]
+struct PanicMessage {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    message: String,
+    location: Option<String>,
+    /// Only captured if enabled by `RUST_BACKTRACE`.
+    backtrace: Option<String>,
+    thread: Option<String>,
+    version: &'static str,
+}
+
+/// Report panics on `channel`, or on the channel of `get_singleton` if it is
+/// `None`, then run the previous panic hook.
+///
+/// The hook is installed once. Later calls only replace the channel. Sending
+/// the report is best effort: errors are ignored, and the panic does not wait
+/// for more than 200ms for it to be sent and flushed (see `NodeIpc::flush`).
+pub fn install_panic_reporter(channel: Option<Arc<NodeIpc>>) {
+    *CHANNEL.lock().unwrap() = channel.or_else(get_singleton);
+    INSTALLED.get_or_init(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            report(info);
+            previous(info);
+        }));
+    });
+}
+
+/// The channel panics are reported on, unless it is being replaced.
+pub(crate) fn channel() -> Option<Arc<NodeIpc>> {
+    CHANNEL.try_lock().ok()?.clone()
+}
+
+fn report(info: &PanicInfo) {
+    // The channel lock might be held by the panicking thread.
+    let channel = match CHANNEL.try_lock() {
+        Ok(channel) => channel.clone(),
+        Err(_) => return,
+    };
+    let channel = match channel {
+        Some(channel) => channel,
+        None => return,
+    };
+    if REPORTING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    // Everything is formatted here, the sending thread only writes the
+    // message.
+    let message = panic_message(info);
+    let deadline = Instant::now() + SEND_TIMEOUT;
+    let (tx, rx) = mpsc::sync_channel(1);
+    let spawned = thread::Builder::new()
+        .name("nodeipc-panic-report".to_string())
+        .spawn(move || {
+            if channel.send(message).is_ok() {
+                channel.flush(deadline.saturating_duration_since(Instant::now()));
+            }
+            let _ = tx.send(());
+        });
+    // The write might block on a full pipe, or on the write lock held by the
+    // panicking thread.
+    if spawned.is_ok() {
+        let _ = rx.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+    }
+    REPORTING.store(false, Ordering::SeqCst);
+}
+
+fn panic_message(info: &PanicInfo) -> PanicMessage {
+    let home = home_dir();
+    let redact = |s: String| match &home {
+        Some(home) => redact_home(&s, home),
+        None => s,
+    };
+    let backtrace = Backtrace::capture();
+    let backtrace = match backtrace.status() {
+        BacktraceStatus::Captured => Some(redact(backtrace.to_string())),
+        _ => None,
+    };
+    PanicMessage {
+        kind: "panic",
+        message: redact(payload_str(info.payload()).to_string()),
+        location: info.location().map(|l| redact(l.to_string())),
+        backtrace,
+        thread: thread::current().name().map(|s| s.to_string()),
+        version: version::VERSION,
+    }
+}
+
+fn payload_str(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+fn home_dir() -> Option<String> {
+    let name = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+    let home = std::env::var(name).ok()?;
+    let home = home.trim_end_matches(['/', '\\']);
+    if home.is_empty() {
+        None
+    } else {
+        Some(home.to_string())
+    }
+}
+
+/// Replace the home directory `home` in paths of `s` by `~`.
+fn redact_home(s: &str, home: &str) -> String {
+    let mut redacted = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find(home) {
+        let after = &rest[i + home.len()..];
+        // Only whole path components: "/home/foo" is not in "/home/foobar".
+        let whole = after.is_empty() || after.starts_with(['/', '\\']);
+        redacted.push_str(&rest[..i]);
+        redacted.push_str(if whole { "~" } else { home });
+        rest = after;
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_home() {
+        let home = "/home/alice";
+        assert_eq!(
+            redact_home("at /home/alice/src/main.rs:1:2 and /home/alice", home),
+            "at ~/src/main.rs:1:2 and ~"
+        );
+        assert_eq!(redact_home("/home/alicex/a.rs", home), "/home/alicex/a.rs");
+        assert_eq!(redact_home("no paths", home), "no paths");
+    }
+
+    #[cfg(unix)]
+    const CHILD_ENV: &str = "NODEIPC_TEST_PANIC_REPORT_CHILD";
+
+    /// Run by `test_panic_report` in a child process, with `NODE_CHANNEL_FD`
+    /// set to its end of a socketpair.
+    #[cfg(unix)]
+    #[test]
+    fn panic_report_child() {
+        if std::env::var_os(CHILD_ENV).is_none() {
+            return;
+        }
+        install_panic_reporter(None);
+        // Idempotent.
+        install_panic_reporter(None);
+        panic!("reported panic");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_panic_report() {
+        use serde_json::Value;
+
+        use crate::testutil::spawn_child_test;
+
+        let (mut child, ipc) = spawn_child_test(
+            "panic_report::tests::panic_report_child",
+            &[(CHILD_ENV, "1"), ("RUST_BACKTRACE", "1")],
+        );
+        assert!(!child.wait().unwrap().success());
+
+        let message: Value = ipc.recv().unwrap().unwrap();
+        assert_eq!(message["type"], "panic");
+        assert_eq!(message["message"], "reported panic");
+        assert_eq!(message["version"], version::VERSION);
+        assert_eq!(message["thread"], "panic_report::tests::panic_report_child");
+        let location = message["location"].as_str().unwrap();
+        assert!(location.contains("panic_report.rs"), "{}", location);
+        assert!(message["backtrace"].is_string());
+        // Only one report, then EOF.
+        assert!(ipc.recv::<Value>().unwrap().is_none());
+    }
+}
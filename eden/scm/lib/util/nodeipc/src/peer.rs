@@ -0,0 +1,439 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Monitoring of the peer process.
+//!
+//! If the peer dies without closing the channel (for example, the file
+//! descriptor was inherited by one of its children), `recv` can block for a
+//! long time. `NodeIpc::watch_peer` watches the peer pid, and shuts down the
+//! channel when it exits, so blocked receivers return `PeerClosed` promptly.
+
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+use filedescriptor::AsRawFileDescriptor;
+use filedescriptor::RawFileDescriptor;
+
+use crate::call::NodeIpcError;
+use crate::loopback::Channel;
+use crate::loopback::Writer;
+use crate::nodeipc::NodeIpc;
+
+/// How often the monitor checks whether the `NodeIpc` was dropped, or whether
+/// the peer exists when there is no way to wait for its exit.
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+impl NodeIpc {
+    /// Watch process `pid`, the peer of this channel. When it exits, the
+    /// channel is shut down and marked as peer-dead: blocked and later
+    /// `recv`s and `send`s fail with `NodeIpcError::PeerClosed`.
+    ///
+    /// The pid is usually known by whoever spawned the peer, or else told
+    /// by `peer_identity`. On Windows, it is also in the `SendFdPayload`
+    /// received from the peer, which should not be trusted.
+    ///
+    /// Processes that exited but were not reaped yet are noticed on Windows,
+    /// macOS and Linux 5.3+, but not on other Unix platforms.
+    pub fn watch_peer(self: &Arc<Self>, pid: u32) -> anyhow::Result<()> {
+        self.watch_peer_with_callback(pid, |_pid| {})
+    }
+
+    /// Like `watch_peer`, and calls `on_exit` with the pid after the channel
+    /// was shut down. `on_exit` is called from the monitor thread.
+    pub fn watch_peer_with_callback(
+        self: &Arc<Self>,
+        pid: u32,
+        on_exit: impl FnOnce(u32) + Send + 'static,
+    ) -> anyhow::Result<()> {
+        let waiter = ExitWaiter::new(pid)
+            .with_context(|| format!("in NodeIpc::watch_peer, when watching pid {pid}"))?;
+        let target = self.shutdown_target();
+        // Do not keep the channel alive. The fd is only used while the
+        // channel is, so it cannot be closed and reused meanwhile.
+        let ipc = Arc::downgrade(self);
+        thread::Builder::new()
+            .name(format!("nodeipc-peer-{pid}"))
+            .spawn(move || {
+                loop {
+                    match waiter.wait(CHECK_INTERVAL) {
+                        Ok(true) => break,
+                        Ok(false) if ipc.strong_count() == 0 => return,
+                        Ok(false) => {}
+                        Err(e) => {
+                            tracing::warn!("NodeIpc stopped watching pid {}: {}", pid, e);
+                            return;
+                        }
+                    }
+                }
+                if let Some(ipc) = ipc.upgrade() {
+                    tracing::debug!("NodeIpc peer {} exited", pid);
+                    ipc.mark_peer_dead(target);
+                    on_exit(pid);
+                }
+            })
+            .context("in NodeIpc::watch_peer, when spawning the monitor thread")?;
+        Ok(())
+    }
+
+    /// Whether a watched peer exited. See `watch_peer`.
+    pub fn is_peer_dead(&self) -> bool {
+        self.peer_dead.load(Ordering::Acquire)
+    }
+
+    /// Mark the peer as dead and shut down the channel, which wakes up
+    /// threads blocked reading from it.
+    fn mark_peer_dead(&self, target: ShutdownTarget) {
+        self.peer_dead.store(true, Ordering::Release);
+        target.shutdown();
+    }
+
+    /// Shut down the channel. Blocked and later reads see EOF, and the peer
+    /// sees the channel closed.
+    pub(crate) fn shutdown_channel(&self) {
+        self.shutdown_target().shutdown();
+    }
+
+    fn shutdown_target(&self) -> ShutdownTarget {
+        match &*self.w.lock().unwrap() {
+            Writer::Fd(fd) => ShutdownTarget::Fd(fd.as_raw_file_descriptor() as usize),
+            Writer::Loopback(w) => ShutdownTarget::Loopback(w.channel.clone()),
+        }
+    }
+
+    /// Replace the outcome of a read that ended the channel by `PeerClosed`,
+    /// if the peer is dead.
+    pub(crate) fn check_peer<T>(
+        &self,
+        result: anyhow::Result<Option<T>>,
+    ) -> anyhow::Result<Option<T>> {
+        match result {
+            Ok(Some(value)) => Ok(Some(value)),
+            _ if self.is_peer_dead() => Err(NodeIpcError::PeerClosed.into()),
+            result => result,
+        }
+    }
+}
+
+/// What `mark_peer_dead` shuts down. The fd is a `usize` to be `Send`.
+enum ShutdownTarget {
+    Fd(usize),
+    Loopback(Arc<Channel>),
+}
+
+impl ShutdownTarget {
+    fn shutdown(self) {
+        match self {
+            ShutdownTarget::Fd(fd) => {
+                if let Err(e) = shutdown(fd as RawFileDescriptor) {
+                    tracing::debug!("NodeIpc failed to shut down the channel: {}", e);
+                }
+            }
+            ShutdownTarget::Loopback(channel) => channel.shutdown(),
+        }
+    }
+}
+
+/// Shut down both directions of a socket.
+fn shutdown(fd: RawFileDescriptor) -> io::Result<()> {
+    #[cfg(unix)]
+    let ret = unsafe { libc::shutdown(fd, libc::SHUT_RDWR) };
+
+    // Only works if the handle is a socket.
+    #[cfg(windows)]
+    let ret = unsafe { winapi::um::winsock2::shutdown(fd as _, winapi::um::winsock2::SD_BOTH) };
+
+    #[cfg(not(any(unix, windows)))]
+    let ret = {
+        let _ = fd;
+        0
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Waits for a process to exit.
+enum ExitWaiter {
+    /// The process had exited already.
+    Exited,
+
+    #[cfg(target_os = "linux")]
+    PidFd(filedescriptor::FileDescriptor),
+
+    #[cfg(target_os = "macos")]
+    Kqueue(filedescriptor::FileDescriptor),
+
+    #[cfg(windows)]
+    Handle(ProcessHandle),
+
+    /// Check whether the process exists periodically. Unlike the others,
+    /// this does not notice processes that exited but were not reaped.
+    #[cfg(unix)]
+    Poll(u32),
+}
+
+impl ExitWaiter {
+    fn new(pid: u32) -> io::Result<Self> {
+        #[cfg(target_os = "linux")]
+        {
+            use filedescriptor::FromRawFileDescriptor;
+
+            let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+            if fd >= 0 {
+                let fd =
+                    unsafe { filedescriptor::FileDescriptor::from_raw_file_descriptor(fd as _) };
+                return Ok(Self::PidFd(fd));
+            }
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ESRCH) => Ok(Self::Exited),
+                // Older kernels.
+                Some(libc::ENOSYS) => Ok(Self::Poll(pid)),
+                _ => Err(err),
+            };
+        }
+
+        #[cfg(target_os = "macos")]
+        {
+            use filedescriptor::FromRawFileDescriptor;
+
+            let kq = unsafe { libc::kqueue() };
+            if kq < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let kq = unsafe { filedescriptor::FileDescriptor::from_raw_file_descriptor(kq) };
+            let event = libc::kevent {
+                ident: pid as _,
+                filter: libc::EVFILT_PROC,
+                flags: libc::EV_ADD | libc::EV_ONESHOT,
+                fflags: libc::NOTE_EXIT,
+                data: 0,
+                udata: std::ptr::null_mut(),
+            };
+            let ret = unsafe {
+                libc::kevent(
+                    kq.as_raw_file_descriptor(),
+                    &event,
+                    1,
+                    std::ptr::null_mut(),
+                    0,
+                    std::ptr::null(),
+                )
+            };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                return match err.raw_os_error() {
+                    Some(libc::ESRCH) => Ok(Self::Exited),
+                    _ => Err(err),
+                };
+            }
+            return Ok(Self::Kqueue(kq));
+        }
+
+        #[cfg(windows)]
+        {
+            use winapi::shared::winerror::ERROR_INVALID_PARAMETER;
+            use winapi::um::processthreadsapi::OpenProcess;
+            use winapi::um::winnt::SYNCHRONIZE;
+
+            let handle = unsafe { OpenProcess(SYNCHRONIZE, 0, pid) };
+            if handle.is_null() {
+                let err = io::Error::last_os_error();
+                return match err.raw_os_error() {
+                    Some(code) if code as u32 == ERROR_INVALID_PARAMETER => Ok(Self::Exited),
+                    _ => Err(err),
+                };
+            }
+            return Ok(Self::Handle(ProcessHandle(handle)));
+        }
+
+        #[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+        {
+            return Ok(Self::Poll(pid));
+        }
+
+        #[allow(unreachable_code)]
+        {
+            let _ = pid;
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "platform is not supported for watching processes",
+            ))
+        }
+    }
+
+    /// Wait up to `timeout` for the process to exit. Returns `false` on
+    /// timeout.
+    fn wait(&self, timeout: Duration) -> io::Result<bool> {
+        match self {
+            Self::Exited => Ok(true),
+
+            #[cfg(target_os = "linux")]
+            Self::PidFd(fd) => {
+                let mut fds = [filedescriptor::pollfd {
+                    fd: fd.as_raw_file_descriptor(),
+                    events: filedescriptor::POLLIN,
+                    revents: 0,
+                }];
+                let ready = filedescriptor::poll(&mut fds, Some(timeout))
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                Ok(ready > 0)
+            }
+
+            #[cfg(target_os = "macos")]
+            Self::Kqueue(kq) => {
+                let timeout = libc::timespec {
+                    tv_sec: timeout.as_secs() as _,
+                    tv_nsec: timeout.subsec_nanos() as _,
+                };
+                let mut event: libc::kevent = unsafe { std::mem::zeroed() };
+                let ret = unsafe {
+                    libc::kevent(
+                        kq.as_raw_file_descriptor(),
+                        std::ptr::null(),
+                        0,
+                        &mut event,
+                        1,
+                        &timeout,
+                    )
+                };
+                match ret {
+                    ret if ret < 0 => {
+                        let err = io::Error::last_os_error();
+                        match err.kind() {
+                            io::ErrorKind::Interrupted => Ok(false),
+                            _ => Err(err),
+                        }
+                    }
+                    ret => Ok(ret > 0),
+                }
+            }
+
+            #[cfg(windows)]
+            Self::Handle(handle) => {
+                use winapi::shared::winerror::WAIT_TIMEOUT;
+                use winapi::um::synchapi::WaitForSingleObject;
+                use winapi::um::winbase::WAIT_OBJECT_0;
+
+                let millis = timeout.as_millis().min(u32::MAX as u128) as u32;
+                match unsafe { WaitForSingleObject(handle.0, millis) } {
+                    WAIT_OBJECT_0 => Ok(true),
+                    WAIT_TIMEOUT => Ok(false),
+                    _ => Err(io::Error::last_os_error()),
+                }
+            }
+
+            #[cfg(unix)]
+            Self::Poll(pid) => {
+                thread::sleep(timeout.min(CHECK_INTERVAL));
+                let ret = unsafe { libc::kill(*pid as libc::pid_t, 0) };
+                Ok(ret != 0 && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH))
+            }
+        }
+    }
+}
+
+/// A process handle, closed on drop.
+#[cfg(windows)]
+struct ProcessHandle(winapi::um::winnt::HANDLE);
+
+// The handle is only used to wait for the process.
+#[cfg(windows)]
+unsafe impl Send for ProcessHandle {}
+
+#[cfg(windows)]
+impl Drop for ProcessHandle {
+    fn drop(&mut self) {
+        unsafe { winapi::um::handleapi::CloseHandle(self.0) };
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+    use std::process::Command;
+    use std::process::Stdio;
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    use filedescriptor::IntoRawFileDescriptor;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::call::is_closed;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn test_recv_after_peer_killed() {
+        let (a, b) = filedescriptor::socketpair().unwrap();
+        // Keep a copy of the child's end, like a grandchild inheriting it
+        // would. So killing the child does not close the channel.
+        let _held = b.try_clone().unwrap();
+        let child_end = unsafe { File::from_raw_fd(b.into_raw_file_descriptor()) };
+        let mut child = Command::new("sleep")
+            .arg("60")
+            .stdin(Stdio::from(child_end))
+            .spawn()
+            .unwrap();
+        let pid = child.id();
+
+        let ipc =
+            Arc::new(NodeIpc::from_raw_file_descriptor(a.into_raw_file_descriptor()).unwrap());
+        let (exited_tx, exited_rx) = mpsc::channel();
+        ipc.watch_peer_with_callback(pid, move |pid| exited_tx.send(pid).unwrap())
+            .unwrap();
+        assert!(!ipc.is_peer_dead());
+
+        let (recv_tx, recv_rx) = mpsc::channel();
+        let receiver = {
+            let ipc = ipc.clone();
+            thread::spawn(move || recv_tx.send(ipc.recv::<Value>()).unwrap())
+        };
+
+        let start = Instant::now();
+        child.kill().unwrap();
+        let err = recv_rx.recv_timeout(TIMEOUT).unwrap().unwrap_err();
+        assert!(start.elapsed() < TIMEOUT);
+        assert!(err
+            .chain()
+            .any(|e| matches!(e.downcast_ref(), Some(NodeIpcError::PeerClosed))));
+        assert!(is_closed(&err));
+        assert_eq!(exited_rx.recv_timeout(TIMEOUT).unwrap(), pid);
+        assert!(ipc.is_peer_dead());
+
+        // Later calls fail right away.
+        assert!(is_closed(&ipc.recv::<Value>().unwrap_err()));
+        assert!(is_closed(&ipc.send("x").unwrap_err()));
+
+        receiver.join().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_watch_exited_peer() {
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+
+        let (a, _b) = filedescriptor::socketpair().unwrap();
+        let ipc =
+            Arc::new(NodeIpc::from_raw_file_descriptor(a.into_raw_file_descriptor()).unwrap());
+        let (exited_tx, exited_rx) = mpsc::channel();
+        ipc.watch_peer_with_callback(pid, move |pid| exited_tx.send(pid).unwrap())
+            .unwrap();
+        assert_eq!(exited_rx.recv_timeout(TIMEOUT).unwrap(), pid);
+        assert!(is_closed(&ipc.recv::<Value>().unwrap_err()));
+    }
+}
@@ -0,0 +1,394 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Inbound rate limiting, so a peer sending messages faster than they are
+//! handled cannot grow the receive queues or starve the receiving thread.
+//!
+//! Limits apply to every message read from the channel: plain messages,
+//! stream messages and call responses. They are not set by default. With the
+//! `Drop` and `Disconnect` policies, call responses, call cancels and the
+//! ends of streams over the limits are still received: dropping them would
+//! leave a caller, a handler or a stream reader waiting forever.
+
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::call::incoming;
+use crate::call::reply_id;
+use crate::call::Incoming;
+use crate::call::NodeIpcError;
+use crate::mux::is_stream_fin;
+use crate::nodeipc::NodeIpc;
+
+/// What happens to messages received over the inbound limits, see
+/// `NodeIpc::set_inbound_limits`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InboundLimitPolicy {
+    /// Wait before reading more. The unread messages stay in the OS buffer,
+    /// and the peer blocks sending once it is full.
+    Delay,
+    /// Read and discard the messages over the limits.
+    Drop,
+    /// Shut down the channel when a message is over the limits. The receiver
+    /// gets `NodeIpcError::ProtocolViolation`, and the peer sees the channel
+    /// closed.
+    Disconnect,
+}
+
+/// The inbound limits of a channel.
+pub(crate) struct InboundLimiter {
+    bucket: TokenBucket,
+    policy: InboundLimitPolicy,
+}
+
+impl InboundLimiter {
+    /// A limiter with the same limits, for messages from another source.
+    pub(crate) fn same_limits(&self) -> Self {
+        Self {
+            bucket: TokenBucket::new_f64(self.bucket.rate, self.bucket.burst),
+            policy: self.policy,
+        }
+    }
+
+    pub(crate) fn policy(&self) -> InboundLimitPolicy {
+        self.policy
+    }
+
+    /// Take a token for a message received `now`, or tell how long until
+    /// one is available.
+    pub(crate) fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.bucket.take(now)
+    }
+}
+
+/// Whether `line` is a message that the `Drop` and `Disconnect` policies let
+/// through over the limits.
+pub(crate) fn is_exempt(line: &str) -> bool {
+    reply_id(line).is_some()
+        || matches!(incoming(line), Some(Incoming::Cancel(_)))
+        || is_stream_fin(line)
+}
+
+/// Holds up to `burst` tokens, refilled at `rate` per second. A message
+/// takes one.
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, burst: u32) -> Self {
+        Self::new_f64(rate as f64, burst.max(1) as f64)
+    }
+
+    fn new_f64(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Take a token, or tell how long until one is available.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+impl NodeIpc {
+    /// Limit the messages received to `msgs_per_sec` on average, allowing
+    /// bursts of up to `burst` messages. `policy` decides what happens to the
+    /// messages over the limits. Replaces the previous limits.
+    ///
+    /// Fails if `msgs_per_sec` is 0.
+    pub fn set_inbound_limits(
+        &self,
+        msgs_per_sec: u32,
+        burst: u32,
+        policy: InboundLimitPolicy,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(msgs_per_sec > 0, "inbound limits must allow some messages");
+        *self.inbound_limiter.lock().unwrap() = Some(InboundLimiter {
+            bucket: TokenBucket::new(msgs_per_sec, burst),
+            policy,
+        });
+        Ok(())
+    }
+
+    /// A copy of the inbound limits, with its own tokens.
+    pub(crate) fn copy_inbound_limits(&self) -> Option<InboundLimiter> {
+        self.inbound_limiter
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(InboundLimiter::same_limits)
+    }
+
+    /// Remove the inbound limits.
+    pub fn clear_inbound_limits(&self) {
+        *self.inbound_limiter.lock().unwrap() = None;
+    }
+
+    /// With the `Delay` policy, wait until a message can be received.
+    /// Returns `false` if that is after `deadline`, having waited until then.
+    pub(crate) fn delay_inbound(&self, deadline: Option<Instant>) -> bool {
+        loop {
+            let wait = match &mut *self.inbound_limiter.lock().unwrap() {
+                Some(limiter) if limiter.policy == InboundLimitPolicy::Delay => {
+                    match limiter.bucket.take(Instant::now()) {
+                        Ok(()) => return true,
+                        Err(wait) => wait,
+                    }
+                }
+                _ => return true,
+            };
+            if let Some(deadline) = deadline {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left < wait {
+                    thread::sleep(left);
+                    self.counters.inbound_delayed(left);
+                    return false;
+                }
+            }
+            thread::sleep(wait);
+            self.counters.inbound_delayed(wait);
+        }
+    }
+
+    /// With the `Drop` and `Disconnect` policies, check the received `line`
+    /// against the limits. Returns `false` if it should be discarded.
+    pub(crate) fn admit_inbound(&self, line: &str) -> anyhow::Result<bool> {
+        let policy = match &mut *self.inbound_limiter.lock().unwrap() {
+            Some(limiter) if limiter.policy != InboundLimitPolicy::Delay => {
+                match limiter.bucket.take(Instant::now()) {
+                    Ok(()) => return Ok(true),
+                    Err(_) => limiter.policy,
+                }
+            }
+            _ => return Ok(true),
+        };
+        if is_exempt(line) {
+            return Ok(true);
+        }
+        if policy == InboundLimitPolicy::Drop {
+            self.counters.inbound_dropped();
+            return Ok(false);
+        }
+        tracing::warn!("NodeIpc peer exceeded the inbound limits, disconnecting");
+        self.counters.inbound_disconnected();
+        self.shutdown_channel();
+        Err(NodeIpcError::ProtocolViolation("exceeded the inbound message rate".to_string()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::call::is_closed;
+    use crate::testutil::ipc_pair;
+    use crate::CallRequest;
+    use crate::RetryConfig;
+
+    const FLOOD: usize = 10000;
+
+    /// Send `FLOOD` messages from another thread, counting the messages
+    /// sent, until the channel is closed.
+    fn flood(ipc: NodeIpc) -> (Arc<AtomicUsize>, thread::JoinHandle<()>) {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let counter = sent.clone();
+        // Large enough for the messages not to fit in the OS buffer.
+        let message = "x".repeat(200);
+        let handle = thread::spawn(move || {
+            for _ in 0..FLOOD {
+                if ipc.send(&message).is_err() {
+                    break;
+                }
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        (sent, handle)
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(10, 2);
+        let now = bucket.refilled;
+        assert_eq!(bucket.take(now), Ok(()));
+        assert_eq!(bucket.take(now), Ok(()));
+        assert_eq!(bucket.take(now), Err(Duration::from_millis(100)));
+        assert_eq!(bucket.take(now + Duration::from_millis(100)), Ok(()));
+        // Refills up to the burst.
+        let later = now + Duration::from_secs(10);
+        assert_eq!(bucket.take(later), Ok(()));
+        assert_eq!(bucket.take(later), Ok(()));
+        assert!(bucket.take(later).is_err());
+    }
+
+    #[test]
+    fn test_delay() {
+        let (a, b) = ipc_pair();
+        b.set_inbound_limits(100, 10, InboundLimitPolicy::Delay).unwrap();
+        let (sent, sender) = flood(a);
+
+        let start = Instant::now();
+        for _ in 0..60 {
+            let _: String = b.recv().unwrap().unwrap();
+        }
+        // The 50 messages over the burst took at least 0.5s.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(30), "{:?}", elapsed);
+        assert!(b.stats().inbound_delayed >= Duration::from_millis(400));
+        // The sender is blocked by the full OS buffer.
+        assert!(sent.load(Ordering::Relaxed) < FLOOD);
+        assert_eq!(b.stats().inbound_dropped, 0);
+
+        drop(b);
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn test_drop() {
+        let (a, b) = ipc_pair();
+        b.set_inbound_limits(100, 10, InboundLimitPolicy::Drop).unwrap();
+        let (sent, sender) = flood(a);
+
+        let start = Instant::now();
+        let mut received = 0;
+        loop {
+            match b.recv::<String>() {
+                Ok(Some(_)) => received += 1,
+                Ok(None) => break,
+                Err(e) if is_closed(&e) => break,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        sender.join().unwrap();
+        let elapsed = start.elapsed();
+
+        assert_eq!(sent.load(Ordering::Relaxed), FLOOD);
+        let stats = b.stats();
+        assert_eq!(stats.inbound_dropped as usize, FLOOD - received);
+        assert!(received >= 10);
+        assert!(received as f64 <= 11.0 + elapsed.as_secs_f64() * 100.0);
+        assert_eq!(stats.inbound_delayed, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let (a, b) = ipc_pair();
+        b.set_inbound_limits(100, 10, InboundLimitPolicy::Disconnect).unwrap();
+        let (sent, sender) = flood(a);
+
+        let start = Instant::now();
+        let mut received = 0;
+        let err = loop {
+            match b.recv::<String>() {
+                Ok(Some(_)) => received += 1,
+                Ok(None) => panic!("the channel was closed by the peer"),
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(NodeIpcError::ProtocolViolation(_))
+        ));
+        assert!((10..20).contains(&received), "{}", received);
+
+        // The sender sees the channel closed promptly.
+        sender.join().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(sent.load(Ordering::Relaxed) < FLOOD);
+        assert_eq!(b.stats().inbound_disconnects, 1);
+        assert!(!matches!(b.recv::<String>(), Ok(Some(_))));
+    }
+
+    #[test]
+    fn test_exempt_replies() {
+        let (a, b) = ipc_pair();
+        a.set_inbound_limits(1, 1, InboundLimitPolicy::Drop).unwrap();
+        let peer = thread::spawn(move || {
+            let req: CallRequest<Value> = b.recv().unwrap().unwrap();
+            for i in 0..5 {
+                b.send(i).unwrap();
+            }
+            req.reply(&b, "done").unwrap();
+        });
+
+        // The reply is over the limits, but not dropped.
+        let response: String = a
+            .call_with_timeout(json!({}), Duration::from_secs(10), RetryConfig::none())
+            .unwrap();
+        assert_eq!(response, "done");
+        assert_eq!(a.recv::<u32>().unwrap(), Some(0));
+        assert_eq!(a.stats().inbound_dropped, 4);
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_delay_deadline() {
+        let (a, b) = ipc_pair();
+        a.set_inbound_limits(1, 1, InboundLimitPolicy::Delay).unwrap();
+        let peer = thread::spawn(move || {
+            b.send("first").unwrap();
+            let req: CallRequest<Value> = b.recv().unwrap().unwrap();
+            req.reply(&b, "late").unwrap();
+        });
+        assert_eq!(a.recv::<String>().unwrap().as_deref(), Some("first"));
+
+        // Waiting for the next token does not keep the caller past its
+        // deadline.
+        let start = Instant::now();
+        let timeout = Duration::from_millis(100);
+        let result: Result<String, _> =
+            a.call_with_timeout(json!({}), timeout, RetryConfig::none());
+        assert!(matches!(result, Err(NodeIpcError::Timeout(_))));
+        assert!(start.elapsed() < Duration::from_millis(900));
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_zero_rate() {
+        let (a, _b) = ipc_pair();
+        assert!(a
+            .set_inbound_limits(0, 10, InboundLimitPolicy::Drop)
+            .is_err());
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let (a, b) = ipc_pair();
+        b.set_inbound_limits(1, 1, InboundLimitPolicy::Drop).unwrap();
+        b.clear_inbound_limits();
+        for i in 0..100 {
+            a.send(i).unwrap();
+        }
+        for i in 0..100 {
+            assert_eq!(b.recv::<u32>().unwrap(), Some(i));
+        }
+        assert_eq!(b.stats().inbound_dropped, 0);
+    }
+}
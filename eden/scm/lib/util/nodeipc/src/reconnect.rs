@@ -0,0 +1,409 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! A `NodeIpc` handle that reconnects when the peer goes away.
+//!
+//! When an operation finds the channel closed, the channel is dropped, a new
+//! one is obtained from the connector (with exponential backoff), and only
+//! that operation is replayed on it. Nothing else is replayed: messages sent
+//! before the closure was noticed may have been lost, and messages the old
+//! peer sent but were not received are gone. Use `with_on_reconnect` to
+//! re-establish session state on the new channel.
+
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::call::is_closed;
+use crate::NodeIpc;
+use crate::NodeIpcError;
+use crate::RetryConfig;
+
+/// Creates a new channel to the peer, e.g. by connecting to a daemon.
+pub type Connector = Arc<dyn Fn() -> anyhow::Result<NodeIpc> + Send + Sync>;
+
+type OnReconnect = Box<dyn Fn(&NodeIpc) -> anyhow::Result<()> + Send + Sync>;
+
+/// How `ReconnectingIpc` retries connecting.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    /// Delay before the second attempt. The first attempt is immediate.
+    pub initial: Duration,
+    /// Upper bound of the delay, before jitter.
+    pub max: Duration,
+    /// Growth of the delay after each failed attempt.
+    pub multiplier: f64,
+    /// The delay is randomly changed by up to this fraction of itself.
+    pub jitter: f64,
+    /// Number of connection attempts before giving up.
+    pub max_attempts: usize,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(50),
+            max: Duration::from_secs(5),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: 10,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay before attempt `attempt`, starting at 1 for the second one.
+    fn delay(&self, attempt: usize) -> Duration {
+        let exp = self
+            .multiplier
+            .powi(attempt.saturating_sub(1).min(i32::MAX as usize) as i32);
+        let base = self.initial.as_secs_f64() * exp;
+        let base = base.min(self.max.as_secs_f64());
+        let jitter = if self.jitter > 0.0 {
+            rand::thread_rng().gen_range(-self.jitter..=self.jitter)
+        } else {
+            0.0
+        };
+        Duration::from_secs_f64((base * (1.0 + jitter)).max(0.0))
+    }
+}
+
+struct State {
+    ipc: Arc<NodeIpc>,
+    /// Incremented on every reconnection.
+    generation: u64,
+    /// Whether a thread is reconnecting. Others wait for it.
+    reconnecting: bool,
+}
+
+/// Wraps a `NodeIpc` and transparently replaces it when the peer closes the
+/// channel, for example when a daemon restarts. See the module
+/// documentation for what is and isn't replayed.
+pub struct ReconnectingIpc {
+    connector: Connector,
+    backoff: Backoff,
+    on_reconnect: Option<OnReconnect>,
+    state: Mutex<State>,
+    reconnected: Condvar,
+}
+
+impl ReconnectingIpc {
+    /// Connect using `connector`, retrying according to `backoff`.
+    pub fn new(connector: Connector, backoff: Backoff) -> Result<Self, NodeIpcError> {
+        let ipc = connect(&connector, &backoff, None, NodeIpcError::PeerClosed)?;
+        Ok(Self {
+            connector,
+            backoff,
+            on_reconnect: None,
+            state: Mutex::new(State {
+                ipc: Arc::new(ipc),
+                generation: 0,
+                reconnecting: false,
+            }),
+            reconnected: Condvar::new(),
+        })
+    }
+
+    /// Call `on_reconnect` with every new channel before it is used. If it
+    /// fails, the connection attempt counts as failed.
+    pub fn with_on_reconnect(
+        mut self,
+        on_reconnect: impl Fn(&NodeIpc) -> anyhow::Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.on_reconnect = Some(Box::new(on_reconnect));
+        self
+    }
+
+    /// The current channel. It is not replaced while in use, so operations
+    /// on it fail once the peer is gone.
+    pub fn current(&self) -> Arc<NodeIpc> {
+        self.state.lock().unwrap().ipc.clone()
+    }
+
+    /// Send a message, reconnecting if the channel was closed.
+    pub fn send(&self, message: impl Serialize) -> Result<(), NodeIpcError> {
+        let message = serde_json::to_value(message).map_err(NodeIpcError::Encode)?;
+        self.with_reconnect(|ipc| {
+            ipc.send(&message).map_err(|e| {
+                if is_closed(&e) {
+                    NodeIpcError::PeerClosed
+                } else {
+                    NodeIpcError::Send(e)
+                }
+            })
+        })
+    }
+
+    /// Receive a message, reconnecting and receiving from the new channel if
+    /// the channel was closed.
+    pub fn recv<V: DeserializeOwned>(&self) -> Result<V, NodeIpcError> {
+        let value = self.with_reconnect(|ipc| match ipc.recv::<Value>() {
+            Ok(Some(value)) => Ok(value),
+            Ok(None) => Err(NodeIpcError::PeerClosed),
+            Err(e) if is_closed(&e) => Err(NodeIpcError::PeerClosed),
+            Err(e) => Err(NodeIpcError::Recv(e)),
+        })?;
+        serde_json::from_value(value).map_err(NodeIpcError::Decode)
+    }
+
+    /// Like `NodeIpc::call_with_timeout`, but replays the call on a new
+    /// channel if the channel was closed. Timeouts are not retried.
+    pub fn call_with_timeout<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        req: Req,
+        timeout: Duration,
+    ) -> Result<Resp, NodeIpcError> {
+        let request = serde_json::to_value(req).map_err(NodeIpcError::Encode)?;
+        let response: Value = self
+            .with_reconnect(|ipc| ipc.call_with_timeout(&request, timeout, RetryConfig::none()))?;
+        serde_json::from_value(response).map_err(NodeIpcError::Decode)
+    }
+
+    /// Run `op` on the current channel. If the peer closed it, reconnect and
+    /// run `op` again, up to `Backoff::max_attempts` times.
+    fn with_reconnect<T>(
+        &self,
+        op: impl Fn(&NodeIpc) -> Result<T, NodeIpcError>,
+    ) -> Result<T, NodeIpcError> {
+        let (mut ipc, mut generation) = {
+            let state = self.state.lock().unwrap();
+            (state.ipc.clone(), state.generation)
+        };
+        let mut replays = 0;
+        loop {
+            match op(&ipc) {
+                Err(NodeIpcError::PeerClosed) if replays < self.backoff.max_attempts => {
+                    replays += 1;
+                    tracing::debug!("NodeIpc peer closed, reconnecting");
+                    (ipc, generation) = self.reconnect(generation)?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Replace the channel of `generation`. If another thread is already
+    /// replacing it, wait for it and use its channel instead.
+    fn reconnect(&self, generation: u64) -> Result<(Arc<NodeIpc>, u64), NodeIpcError> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.generation != generation {
+                return Ok((state.ipc.clone(), state.generation));
+            }
+            if !state.reconnecting {
+                break;
+            }
+            state = self.reconnected.wait(state).unwrap();
+        }
+        state.reconnecting = true;
+        drop(state);
+
+        let result = connect(
+            &self.connector,
+            &self.backoff,
+            self.on_reconnect.as_ref(),
+            NodeIpcError::PeerClosed,
+        );
+
+        let mut state = self.state.lock().unwrap();
+        state.reconnecting = false;
+        let result = result.map(|ipc| {
+            state.ipc = Arc::new(ipc);
+            state.generation += 1;
+            (state.ipc.clone(), state.generation)
+        });
+        drop(state);
+        self.reconnected.notify_all();
+        result
+    }
+}
+
+/// Connect, retrying according to `backoff`. `previous` is the error that
+/// caused the reconnection.
+fn connect(
+    connector: &Connector,
+    backoff: &Backoff,
+    on_reconnect: Option<&OnReconnect>,
+    previous: NodeIpcError,
+) -> Result<NodeIpc, NodeIpcError> {
+    let mut attempt = 0;
+    loop {
+        let result = connector().and_then(|ipc| {
+            if let Some(on_reconnect) = on_reconnect {
+                on_reconnect(&ipc)?;
+            }
+            Ok(ipc)
+        });
+        attempt += 1;
+        match result {
+            Ok(ipc) => return Ok(ipc),
+            Err(e) if attempt >= backoff.max_attempts.max(1) => {
+                return Err(NodeIpcError::Reconnect {
+                    previous: Box::new(previous),
+                    source: e,
+                });
+            }
+            Err(e) => {
+                let delay = backoff.delay(attempt);
+                tracing::debug!("NodeIpc connect failed, retrying in {:?}: {:#}", delay, e);
+                thread::sleep(delay);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::testutil::ipc_pair;
+    use crate::CallRequest;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// A "daemon" that can be stopped and started. Each connection is served
+    /// by a thread that doubles `n` in calls and exits on `{"exit": true}`.
+    #[derive(Default)]
+    struct Server {
+        up: AtomicBool,
+        connections: AtomicUsize,
+    }
+
+    impl Server {
+        fn connector(self: &Arc<Self>) -> Connector {
+            let server = self.clone();
+            Arc::new(move || {
+                if !server.up.load(Ordering::SeqCst) {
+                    anyhow::bail!("daemon is not running");
+                }
+                server.connections.fetch_add(1, Ordering::SeqCst);
+                let (client, peer) = ipc_pair();
+                thread::spawn(move || {
+                    while let Ok(Some(message)) = peer.recv::<Value>() {
+                        if message.get("exit").is_some() {
+                            break;
+                        }
+                        let req: CallRequest<Value> = serde_json::from_value(message).unwrap();
+                        let n = req.request["n"].as_u64().unwrap();
+                        let _ = req.reply(&peer, n * 2);
+                    }
+                });
+                Ok(client)
+            })
+        }
+
+        /// Stop the daemon, closing the current connection.
+        fn kill(&self, ipc: &ReconnectingIpc) {
+            self.up.store(false, Ordering::SeqCst);
+            ipc.current().send(json!({"exit": true})).unwrap();
+        }
+
+        /// Start the daemon after `delay`.
+        fn restart_after(self: &Arc<Self>, delay: Duration) -> thread::JoinHandle<()> {
+            let server = self.clone();
+            thread::spawn(move || {
+                thread::sleep(delay);
+                server.up.store(true, Ordering::SeqCst);
+            })
+        }
+    }
+
+    fn fast_backoff(max_attempts: usize) -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(5),
+            max: Duration::from_millis(20),
+            max_attempts,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_reconnect_after_restart() {
+        let server = Arc::new(Server::default());
+        server.up.store(true, Ordering::SeqCst);
+        let reconnects = Arc::new(AtomicUsize::new(0));
+        let ipc = ReconnectingIpc::new(server.connector(), fast_backoff(100))
+            .unwrap()
+            .with_on_reconnect({
+                let reconnects = reconnects.clone();
+                move |_| {
+                    reconnects.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }
+            });
+
+        let n: u64 = ipc.call_with_timeout(json!({"n": 1}), TIMEOUT).unwrap();
+        assert_eq!(n, 2);
+
+        server.kill(&ipc);
+        let restart = server.restart_after(Duration::from_millis(100));
+        let n: u64 = ipc.call_with_timeout(json!({"n": 2}), TIMEOUT).unwrap();
+        assert_eq!(n, 4);
+        restart.join().unwrap();
+        assert_eq!(reconnects.load(Ordering::SeqCst), 1);
+        assert_eq!(server.connections.load(Ordering::SeqCst), 2);
+
+        let n: u64 = ipc.call_with_timeout(json!({"n": 3}), TIMEOUT).unwrap();
+        assert_eq!(n, 6);
+        assert_eq!(reconnects.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_concurrent_callers_coalesce() {
+        let server = Arc::new(Server::default());
+        server.up.store(true, Ordering::SeqCst);
+        let ipc = Arc::new(ReconnectingIpc::new(server.connector(), fast_backoff(100)).unwrap());
+
+        server.kill(&ipc);
+        let restart = server.restart_after(Duration::from_millis(100));
+        let callers: Vec<_> = (0..4)
+            .map(|i| {
+                let ipc = ipc.clone();
+                thread::spawn(move || {
+                    let n: u64 = ipc.call_with_timeout(json!({"n": i}), TIMEOUT).unwrap();
+                    assert_eq!(n, i * 2);
+                })
+            })
+            .collect();
+        for caller in callers {
+            caller.join().unwrap();
+        }
+        restart.join().unwrap();
+        assert_eq!(server.connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_retry_budget() {
+        let server = Arc::new(Server::default());
+        let result = ReconnectingIpc::new(server.connector(), fast_backoff(3));
+        assert!(matches!(result, Err(NodeIpcError::Reconnect { .. })));
+
+        server.up.store(true, Ordering::SeqCst);
+        let ipc = ReconnectingIpc::new(server.connector(), fast_backoff(3)).unwrap();
+        server.kill(&ipc);
+        let result: Result<u64, _> = ipc.call_with_timeout(json!({"n": 1}), TIMEOUT);
+        match result {
+            Err(NodeIpcError::Reconnect { previous, .. }) => {
+                assert!(matches!(*previous, NodeIpcError::PeerClosed));
+            }
+            _ => panic!("expected a reconnect error"),
+        }
+        assert_eq!(server.connections.load(Ordering::SeqCst), 1);
+    }
+}
@@ -8,13 +8,25 @@
 use std::sync::Arc;
 
 use anyhow::Context;
+#[cfg(unix)]
 use filedescriptor::AsRawFileDescriptor;
 use filedescriptor::RawFileDescriptor;
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::console;
+use crate::console::AdoptedStdioInfo;
+use crate::encrypt::EncryptionError;
+#[cfg(unix)]
+use crate::fdpath;
+use crate::fdpath::PartialTransfer;
+#[cfg(unix)]
+use crate::loopback::Reader;
+#[cfg(unix)]
+use crate::loopback::Writer;
 use crate::nodeipc::NodeIpc;
 use crate::singleton::IPC;
+use crate::trace::TraceDirection;
 
 impl NodeIpc {
     /// Send a list of fd (or HANDLE on Windows).
@@ -23,7 +35,61 @@ impl NodeIpc {
     /// Note: if the other side is nodejs, it will not understand this special
     /// message. Nodejs has a different implementation. You can use
     /// `subprocess.send(message, sendHandle)` between nodejs processes.
+    ///
+    /// If fds were sent by path (see `with_fd_path_fallback`), and some
+    /// could not be, the others are still sent and a warning is logged. See
+    /// `send_fd_vec_with_report` to handle that case.
     pub fn send_fd_vec(&self, fds: &[RawFileDescriptor]) -> anyhow::Result<()> {
+        if let Some(partial) = self.send_fd_vec_with_report(fds)? {
+            tracing::warn!("{}", partial);
+        }
+        Ok(())
+    }
+
+    /// `send_fd_vec`, returning the fds that could not be sent by path. An
+    /// error means that nothing was sent.
+    pub fn send_fd_vec_with_report(
+        &self,
+        fds: &[RawFileDescriptor],
+    ) -> anyhow::Result<Option<PartialTransfer>> {
+        self.send_labeled_fd_vec(fds, None, 0)
+    }
+
+    /// `send_fd_vec_with_report` with optional labels describing the fds
+    /// for tracing. The last `optional` fds are left out, rather than
+    /// reported, if the fds are sent by path and they can't be.
+    pub(crate) fn send_labeled_fd_vec(
+        &self,
+        fds: &[RawFileDescriptor],
+        purposes: Option<&[&str]>,
+        optional: usize,
+    ) -> anyhow::Result<Option<PartialTransfer>> {
+        // Labeled fds, like stdio and the singleton, are meant to be
+        // inheritable.
+        #[cfg(all(unix, debug_assertions))]
+        if purposes.is_none() {
+            for &fd in fds.iter().filter(|&&fd| looks_like_leaked_pipe(fd)) {
+                tracing::warn!(
+                    "NodeIpc sending fd {} which is a pipe or socket without FD_CLOEXEC",
+                    fd
+                );
+            }
+        }
+        let partial = self.send_fd_vec_untraced(fds, optional)?;
+        self.counters.fd_sent();
+        if let Some(tracer) = self.tracer.get() {
+            tracer.fds(TraceDirection::SendFds, fds.len(), purposes);
+        }
+        Ok(partial)
+    }
+
+    /// Returns the fds that could not be sent by path, if they were sent by
+    /// path.
+    fn send_fd_vec_untraced(
+        &self,
+        fds: &[RawFileDescriptor],
+        optional: usize,
+    ) -> anyhow::Result<Option<PartialTransfer>> {
         self.check_sendfd_compatibility()?;
 
         #[cfg(windows)]
@@ -45,7 +111,8 @@ impl NodeIpc {
                 pid: std::process::id(),
                 raw_fds: sendable_fds,
             };
-            return self.send(payload);
+            let _ = optional;
+            return self.send(payload).map(|()| None);
         }
 
         #[cfg(unix)]
@@ -66,15 +133,25 @@ impl NodeIpc {
             unsafe { libc::memcpy(cmsg_data as *mut _, fds.as_ptr() as *const _, fds_byte_size) };
 
             let w = self.w.lock().unwrap();
-            let socket_fd = w.as_raw_file_descriptor();
-            let ret = unsafe { libc::sendmsg(socket_fd, &hdr, 0) };
-            if ret < 0 {
-                return Err(std::io::Error::last_os_error())
-                    .with_context(|| format!("Failed to sendmsg with fds {:?}", &fds));
+            let socket_fd = match &*w {
+                Writer::Fd(fd) => fd.as_raw_file_descriptor(),
+                Writer::Loopback(w) => {
+                    w.send_fds(fds)
+                        .with_context(|| format!("Failed to send fds {:?}", &fds))?;
+                    return Ok(None);
+                }
+            };
+            let ret = sendmsg(socket_fd, &hdr);
+            drop((cmsgs, opaque, w));
+            if let Err(err) = ret {
+                if self.fd_path_fallback && fdpath::is_sendfd_blocked(&err) {
+                    tracing::debug!("NodeIpc sending fds by path after sendmsg error: {}", err);
+                    return self.send_fd_paths(&fds[..fds.len() - optional]);
+                }
+                return Err(err).with_context(|| format!("Failed to sendmsg with fds {:?}", &fds));
             }
-            drop((cmsgs, opaque));
 
-            return Ok(());
+            return Ok(None);
         }
 
         #[allow(unreachable_code)]
@@ -91,7 +168,37 @@ impl NodeIpc {
     ///
     /// On POSIX systems, at most 32 fds can be received once.
     /// See `MAX_FD_COUNT` below.
+    ///
+    /// Fds sent by path are reopened, and are -1 if the sender could not
+    /// send them. Fails if a path no longer refers to the sender's file.
+    ///
+    /// The received fds are not inherited by child processes, see
+    /// `recv_fd_vec_with_inheritance` to keep some inheritable.
     pub fn recv_fd_vec(&self) -> anyhow::Result<SendFdPayload> {
+        self.recv_fd_vec_with_inheritance(&[])
+    }
+
+    /// `recv_fd_vec`, with the inheritance of the received fds given by
+    /// `inheritance`, in order. Fds past the end of `inheritance` are
+    /// `FdInheritance::Private`.
+    pub fn recv_fd_vec_with_inheritance(
+        &self,
+        inheritance: &[FdInheritance],
+    ) -> anyhow::Result<SendFdPayload> {
+        let payload = self.recv_fd_vec_untraced()?;
+        for (i, &fd) in payload.raw_fds.iter().enumerate() {
+            let inheritance = inheritance.get(i).copied().unwrap_or_default();
+            set_inheritance(fd, inheritance)
+                .with_context(|| format!("Failed to set inheritance of received fd {:?}", fd))?;
+        }
+        self.counters.fd_received();
+        if let Some(tracer) = self.tracer.get() {
+            tracer.fds(TraceDirection::RecvFds, payload.raw_fds.len(), None);
+        }
+        Ok(payload)
+    }
+
+    fn recv_fd_vec_untraced(&self) -> anyhow::Result<SendFdPayload> {
         self.check_sendfd_compatibility()?;
 
         #[cfg(windows)]
@@ -181,12 +288,26 @@ impl NodeIpc {
 
             let r = self.r.lock().unwrap();
             assert!(r.buffer().is_empty());
-            let socket_fd = r.get_ref().as_raw_file_descriptor();
+            let socket_fd = match r.get_ref() {
+                Reader::Fd(fd) => fd.as_raw_file_descriptor(),
+                Reader::Loopback(r) => {
+                    let raw_fds = r.recv_fds();
+                    return Ok(SendFdPayload { raw_fds });
+                }
+            };
 
-            let ret = libc::recvmsg(socket_fd, &mut hdr, 0);
+            // Close the received fds on exec right away where supported,
+            // `recv_fd_vec_with_inheritance` sets their flags either way.
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            let flags = libc::MSG_CMSG_CLOEXEC;
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            let flags = 0;
+            let ret = libc::recvmsg(socket_fd, &mut hdr, flags);
             if ret < 0 {
                 return Err(std::io::Error::last_os_error()).context("Failed to recvmsg");
             }
+            // The dummy byte, or `FD_PATH_MARKER` if fds are sent by path.
+            let marker = *((*hdr.msg_iov).iov_base as *const u8);
 
             let mut received_fds = Vec::<RawFileDescriptor>::new();
             let mut cmsg = libc::CMSG_FIRSTHDR(&hdr);
@@ -205,7 +326,11 @@ impl NodeIpc {
                 }
                 cmsg = libc::CMSG_NXTHDR(&hdr, cmsg);
             }
-            drop((cmsgs, opaque));
+            drop((cmsgs, opaque, r));
+
+            if ret == 1 && marker == fdpath::FD_PATH_MARKER && received_fds.is_empty() {
+                return self.recv_fd_paths();
+            }
 
             let payload = SendFdPayload {
                 raw_fds: received_fds,
@@ -222,6 +347,9 @@ impl NodeIpc {
 
     /// Send the stdio and optionally the `NODE_CHANNEL_FD` file descriptor
     /// (the singleton) for the other end to "attach".
+    ///
+    /// The singleton can't be sent by path. It is left out if the stdio is
+    /// sent by path, and stdio that can't be is reported as a warning.
     pub fn send_stdio(&self) -> anyhow::Result<()> {
         let mut fds = Vec::with_capacity(4);
 
@@ -241,27 +369,41 @@ impl NodeIpc {
             fds.extend_from_slice(stdio_constants())
         }
 
+        let mut purposes = vec!["stdin", "stdout", "stderr"];
+
         // Optionally, include the singleton file descriptor.
+        let mut optional = 0;
         if let Some(ipc) = crate::get_singleton() {
-            if let Ok(w) = ipc.w.lock() {
-                fds.push(w.as_raw_file_descriptor());
+            if let Some(fd) = ipc.w.lock().ok().and_then(|w| w.fd()) {
+                fds.push(fd);
+                purposes.push("ipc");
+                optional = 1;
             }
         }
 
-        self.send_fd_vec(&fds)?;
+        if let Some(partial) = self.send_labeled_fd_vec(&fds, Some(&purposes), optional)? {
+            tracing::warn!("{}", partial);
+        }
         Ok(())
     }
 
     /// Replace the stdio using the one sent from the other end.
     /// Update the singleton to match the sender.
     ///
-    /// On Windows, the console might be replaced to the sender's.
-    pub fn recv_stdio(&self) -> anyhow::Result<()> {
-        let payload = self.recv_fd_vec()?;
+    /// On Windows, the console might be replaced to the sender's. Its
+    /// control events are then handled as described in `ConsoleCtrlEvent`.
+    ///
+    /// Return the terminal attributes of the new stdio. They are also passed
+    /// to the hook set by `set_stdio_change_hook`.
+    pub fn recv_stdio(&self) -> anyhow::Result<AdoptedStdioInfo> {
+        // The stdio is inherited by the processes we spawn, but not the
+        // singleton.
+        let payload = self.recv_fd_vec_with_inheritance(&[FdInheritance::Inheritable; 3])?;
 
         // Replace the stdio.
         #[cfg(unix)]
         {
+            // Fds that could not be sent by path are -1.
             for (&received_fd, &std_fd) in payload.raw_fds.iter().zip(stdio_constants()) {
                 if received_fd > 0 && received_fd != std_fd {
                     unsafe {
@@ -284,6 +426,9 @@ impl NodeIpc {
                     AttachConsole(payload.pid)
                 };
             }
+            if let Err(e) = crate::console_ctrl::install() {
+                tracing::warn!("cannot handle console control events: {:?}", e);
+            }
 
             for (&received_handle, &std_constant) in payload.raw_fds.iter().zip(stdio_constants()) {
                 if !received_handle.is_null() {
@@ -292,16 +437,18 @@ impl NodeIpc {
             }
         }
 
-        // Replace the singleton.
+        // Replace the singleton. It cannot be sent by path.
         let mut ipc = IPC.write().unwrap();
-        if let Some(&raw_fd) = payload.raw_fds.get(stdio_constants().len()) {
+        let ipc_fd = payload.raw_fds.get(stdio_constants().len());
+        if let Some(&raw_fd) = ipc_fd.filter(|&&fd| is_present(fd)) {
             let new_ipc = NodeIpc::from_raw_file_descriptor(raw_fd)?.with_libuv_compat();
             *ipc = Some(Some(Arc::new(new_ipc)));
         } else {
             *ipc = Some(None);
         }
+        drop(ipc);
 
-        Ok(())
+        Ok(console::adopted(std_fds()))
     }
 
     fn check_sendfd_compatibility(&self) -> anyhow::Result<()> {
@@ -309,10 +456,106 @@ impl NodeIpc {
             !self.libuv_compat,
             "send_fd_vec() and recv_fd_vec() are incompatible with libuv compatibility."
         );
+        if self.encryption.is_some() {
+            return Err(EncryptionError::SendFdRefused.into());
+        }
         Ok(())
     }
 }
 
+/// Whether `fd` is a received fd, rather than a placeholder for one that
+/// could not be sent by path.
+fn is_present(fd: RawFileDescriptor) -> bool {
+    #[cfg(unix)]
+    {
+        return fd >= 0;
+    }
+
+    #[allow(unreachable_code)]
+    {
+        let _ = fd;
+        true
+    }
+}
+
+/// Whether a received fd (or HANDLE on Windows) is inherited by child
+/// processes, see `NodeIpc::recv_fd_vec_with_inheritance`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FdInheritance {
+    /// Closed on exec (`FD_CLOEXEC`), or not inheritable on Windows.
+    #[default]
+    Private,
+    /// Left open in child processes, like stdio.
+    Inheritable,
+}
+
+/// Sets the inheritance of the received `fd`. Placeholders for fds that
+/// could not be received are left alone.
+fn set_inheritance(fd: RawFileDescriptor, inheritance: FdInheritance) -> std::io::Result<()> {
+    #[cfg(windows)]
+    {
+        use winapi::um::handleapi::SetHandleInformation;
+        use winapi::um::winbase::HANDLE_FLAG_INHERIT;
+        use winapi::um::winnt::HANDLE;
+
+        if fd.is_null() {
+            return Ok(());
+        }
+        let flags = match inheritance {
+            FdInheritance::Private => 0,
+            FdInheritance::Inheritable => HANDLE_FLAG_INHERIT,
+        };
+        if unsafe { SetHandleInformation(fd as HANDLE, HANDLE_FLAG_INHERIT, flags) } == 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    {
+        if !is_present(fd) {
+            return Ok(());
+        }
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let new_flags = match inheritance {
+            FdInheritance::Private => flags | libc::FD_CLOEXEC,
+            FdInheritance::Inheritable => flags & !libc::FD_CLOEXEC,
+        };
+        if new_flags != flags && unsafe { libc::fcntl(fd, libc::F_SETFD, new_flags) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        return Ok(());
+    }
+
+    #[allow(unreachable_code)]
+    {
+        let _ = (fd, inheritance);
+        Ok(())
+    }
+}
+
+/// Whether `fd` is a pipe or socket that child processes would inherit,
+/// other than stdio. Sending one usually means it leaked from where it was
+/// created.
+#[cfg(unix)]
+fn looks_like_leaked_pipe(fd: RawFileDescriptor) -> bool {
+    if fd <= libc::STDERR_FILENO {
+        return false;
+    }
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 || flags & libc::FD_CLOEXEC != 0 {
+        return false;
+    }
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+        return false;
+    }
+    matches!(stat.st_mode & libc::S_IFMT, libc::S_IFIFO | libc::S_IFSOCK)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SendFdPayload {
     #[cfg(windows)]
@@ -400,11 +643,54 @@ fn cmsg_vec_and_msghdr(
     (cmsg_buf, (iov_buf, dummy_iov), hdr)
 }
 
+/// `sendmsg(2)` of `hdr`, without flags.
+#[cfg(all(unix, not(test)))]
+fn sendmsg(socket_fd: RawFileDescriptor, hdr: &libc::msghdr) -> std::io::Result<()> {
+    if unsafe { libc::sendmsg(socket_fd, hdr, 0) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// `sendmsg(2)` of `hdr`, failing with the error set by
+/// `fdpath::inject_sendmsg_error` if any.
+#[cfg(all(unix, test))]
+fn sendmsg(socket_fd: RawFileDescriptor, hdr: &libc::msghdr) -> std::io::Result<()> {
+    if let Some(err) = fdpath::take_injected_sendmsg_error() {
+        return Err(err);
+    }
+    if unsafe { libc::sendmsg(socket_fd, hdr, 0) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
 #[cfg(windows)]
 type StdioConstant = winapi::shared::minwindef::DWORD;
 #[cfg(unix)]
 type StdioConstant = RawFileDescriptor;
 
+/// The current stdin, stdout and stderr.
+fn std_fds() -> [RawFileDescriptor; 3] {
+    #[cfg(windows)]
+    {
+        use winapi::um::processenv::GetStdHandle;
+        use winapi::um::winbase;
+
+        return [
+            winbase::STD_INPUT_HANDLE,
+            winbase::STD_OUTPUT_HANDLE,
+            winbase::STD_ERROR_HANDLE,
+        ]
+        .map(|h| unsafe { GetStdHandle(h) } as RawFileDescriptor);
+    }
+
+    #[cfg(unix)]
+    {
+        return [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO];
+    }
+}
+
 fn stdio_constants() -> &'static [StdioConstant] {
     #[cfg(windows)]
     {
@@ -424,3 +710,79 @@ fn stdio_constants() -> &'static [StdioConstant] {
     #[allow(unreachable_code)]
     &[]
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::process::Command;
+
+    use filedescriptor::AsRawFileDescriptor;
+    use filedescriptor::FileDescriptor;
+    use filedescriptor::FromRawFileDescriptor;
+    use filedescriptor::Pipe;
+
+    use super::*;
+    use crate::testutil::ipc_pair;
+
+    fn is_cloexec(fd: RawFileDescriptor) -> bool {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        assert!(flags >= 0);
+        flags & libc::FD_CLOEXEC != 0
+    }
+
+    /// Whether a child process has `fd` open.
+    fn child_has_fd(fd: RawFileDescriptor) -> bool {
+        Command::new("sh")
+            .arg("-c")
+            .arg(format!("test -e /dev/fd/{}", fd))
+            .status()
+            .unwrap()
+            .success()
+    }
+
+    #[test]
+    fn test_received_fds_not_inherited() {
+        let pipe = Pipe::new().unwrap();
+        let fds = [
+            pipe.read.as_raw_file_descriptor(),
+            pipe.write.as_raw_file_descriptor(),
+        ];
+        for (a, b) in [ipc_pair(), NodeIpc::new_loopback_pair()] {
+            a.send_fd_vec(&fds).unwrap();
+            let received = b.recv_fd_vec().unwrap().raw_fds;
+            assert_eq!(received.len(), 2);
+            for &fd in &received {
+                assert!(is_cloexec(fd));
+                assert!(!child_has_fd(fd));
+                drop(unsafe { FileDescriptor::from_raw_file_descriptor(fd) });
+            }
+
+            // Only the first fd is meant to be inherited.
+            a.send_fd_vec(&fds).unwrap();
+            let received = b
+                .recv_fd_vec_with_inheritance(&[FdInheritance::Inheritable])
+                .unwrap()
+                .raw_fds;
+            assert!(!is_cloexec(received[0]));
+            assert!(child_has_fd(received[0]));
+            assert!(is_cloexec(received[1]));
+            assert!(!child_has_fd(received[1]));
+            for fd in received {
+                drop(unsafe { FileDescriptor::from_raw_file_descriptor(fd) });
+            }
+        }
+    }
+
+    #[test]
+    fn test_looks_like_leaked_pipe() {
+        let pipe = Pipe::new().unwrap();
+        let fd = pipe.read.as_raw_file_descriptor();
+        assert!(!looks_like_leaked_pipe(fd));
+        set_inheritance(fd, FdInheritance::Inheritable).unwrap();
+        assert!(looks_like_leaked_pipe(fd));
+
+        let file = tempfile::tempfile().unwrap();
+        let fd = file.as_raw_file_descriptor();
+        set_inheritance(fd, FdInheritance::Inheritable).unwrap();
+        assert!(!looks_like_leaked_pipe(fd));
+    }
+}
@@ -0,0 +1,331 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Serving calls, with cancellation.
+//!
+//! `NodeIpc::serve_calls` handles `CallRequest`s on up to
+//! `MAX_CALL_WORKERS` threads, and tracks the requests in flight by id. A
+//! cancel sent by `PendingCall::cancel` flips the `CancelToken` of the
+//! handler of the request, which should then return early. The requester
+//! receives `NodeIpcError::Cancelled` whatever the handler returned.
+//! Requests cancelled before a thread picked them up are not handled.
+//! Cancels for requests not in flight are ignored.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::call::incoming;
+use crate::call::is_closed;
+use crate::call::CallFailure;
+use crate::call::CallRequest;
+use crate::call::CallResponse;
+use crate::call::Failure;
+use crate::call::Incoming;
+use crate::nodeipc::NodeIpc;
+
+/// Most requests `serve_calls` handles at once. Other requests wait for a
+/// thread in a queue, cancels are still read meanwhile.
+const MAX_CALL_WORKERS: usize = 32;
+
+/// Flips when the request being handled is cancelled, or the channel is
+/// closed. Cheap to clone.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl CancelToken {
+    /// Whether the request was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        *self.inner.0.lock().unwrap()
+    }
+
+    /// Block until the request is cancelled, or `timeout` elapsed. Returns
+    /// whether it was cancelled.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (cancelled, cond) = &*self.inner;
+        let cancelled = cancelled.lock().unwrap();
+        let (cancelled, _) = cond
+            .wait_timeout_while(cancelled, timeout, |cancelled| !*cancelled)
+            .unwrap();
+        *cancelled
+    }
+
+    fn cancel(&self) {
+        let (cancelled, cond) = &*self.inner;
+        *cancelled.lock().unwrap() = true;
+        cond.notify_all();
+    }
+}
+
+/// Requests received by `serve_calls`, not answered yet.
+#[derive(Default)]
+struct Calls {
+    /// Both the queued requests and the ones being handled.
+    in_flight: HashMap<u64, CancelToken>,
+    queued: VecDeque<(u64, String, CancelToken)>,
+    workers: usize,
+}
+
+impl NodeIpc {
+    /// Handle `CallRequest`s with `handler` until the peer closes the
+    /// channel. Blocking.
+    ///
+    /// Requests are handled on up to `MAX_CALL_WORKERS` threads, so a slow
+    /// handler does not delay the others, or the cancels for it. A failing
+    /// handler is reported to the requester as `NodeIpcError::Remote`.
+    /// Other plain messages are left for `recv`.
+    pub fn serve_calls<Req, Resp>(
+        &self,
+        handler: impl Fn(Req, &CancelToken) -> anyhow::Result<Resp> + Sync,
+    ) -> anyhow::Result<()>
+    where
+        Req: DeserializeOwned,
+        Resp: Serialize,
+    {
+        let calls: Mutex<Calls> = Default::default();
+        let handler = &handler;
+        let calls = &calls;
+        thread::scope(|scope| {
+            let result = loop {
+                let line = match self
+                    .demux
+                    .recv_plain_line_where(self, |line| incoming(line).is_some())
+                {
+                    Ok(Some(line)) => line,
+                    Ok(None) => break Ok(()),
+                    Err(e) if is_closed(&e) => break Ok(()),
+                    Err(e) => break Err(e),
+                };
+                match incoming(&line) {
+                    Some(Incoming::Request(id)) => {
+                        let token = CancelToken::default();
+                        let mut state = calls.lock().unwrap();
+                        state.in_flight.insert(id, token.clone());
+                        state.queued.push_back((id, line, token));
+                        if state.workers < MAX_CALL_WORKERS {
+                            state.workers += 1;
+                            scope.spawn(move || self.call_worker(calls, handler));
+                        }
+                    }
+                    Some(Incoming::Cancel(id)) => {
+                        if let Some(token) = calls.lock().unwrap().in_flight.get(&id) {
+                            token.cancel();
+                        }
+                    }
+                    None => {}
+                }
+            };
+            // Nobody is waiting for the replies anymore.
+            for token in calls.lock().unwrap().in_flight.values() {
+                token.cancel();
+            }
+            result
+        })
+    }
+
+    /// Handle the queued requests of `calls`, until there are none left.
+    fn call_worker<Req, Resp>(
+        &self,
+        calls: &Mutex<Calls>,
+        handler: &(impl Fn(Req, &CancelToken) -> anyhow::Result<Resp> + Sync),
+    ) where
+        Req: DeserializeOwned,
+        Resp: Serialize,
+    {
+        loop {
+            let (id, line, token) = {
+                let mut state = calls.lock().unwrap();
+                match state.queued.pop_front() {
+                    Some(call) => call,
+                    None => {
+                        state.workers -= 1;
+                        return;
+                    }
+                }
+            };
+            let result = (!token.is_cancelled()).then(|| {
+                serde_json::from_str::<CallRequest<Req>>(&line)
+                    .context("in NodeIpc::serve_calls, when decoding the request")
+                    .and_then(|request| handler(request.request, &token))
+            });
+            // Cancels arriving from now on are ignored.
+            calls.lock().unwrap().in_flight.remove(&id);
+            let sent = match result {
+                Some(Ok(response)) if !token.is_cancelled() => {
+                    self.send(CallResponse { id, response })
+                }
+                Some(Err(e)) if !token.is_cancelled() => self.send(CallFailure {
+                    id,
+                    failure: Failure::Error(format!("{:#}", e)),
+                }),
+                _ => self.send(CallFailure {
+                    id,
+                    failure: Failure::Cancelled,
+                }),
+            };
+            if let Err(e) = sent {
+                tracing::debug!("cannot reply to NodeIpc call {}: {:#}", id, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+    use std::time::Instant;
+
+    use serde_json::json;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::testutil::ipc_pair;
+    use crate::NodeIpcError;
+    use crate::RetryConfig;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Serve requests `{"n": n, "wait_ms": ms}`, answering `n` after `ms`
+    /// milliseconds. Handlers poll their token meanwhile, and set
+    /// `cancelled` if it flips.
+    fn spawn_server(peer: NodeIpc, cancelled: Arc<AtomicBool>) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            peer.serve_calls(|request: Value, token| {
+                let wait = Duration::from_millis(request["wait_ms"].as_u64().unwrap());
+                let start = Instant::now();
+                while start.elapsed() < wait {
+                    if token.is_cancelled() {
+                        cancelled.store(true, Ordering::Release);
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(5));
+                }
+                Ok(request["n"].clone())
+            })
+            .unwrap();
+        })
+    }
+
+    #[test]
+    fn test_cancel() {
+        let (a, b) = ipc_pair();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let server = spawn_server(b, cancelled.clone());
+
+        let pending = a.start_call(json!({"n": 1, "wait_ms": 60_000})).unwrap();
+        pending.cancel().unwrap();
+        let result: Result<Value, _> = pending.wait(TIMEOUT);
+        assert!(matches!(result, Err(NodeIpcError::Cancelled)));
+        assert!(cancelled.load(Ordering::Acquire));
+
+        drop(pending);
+        drop(a);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_cancel_after_completion() {
+        let (a, b) = ipc_pair();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let server = spawn_server(b, cancelled.clone());
+
+        let pending = a.start_call(json!({"n": 1, "wait_ms": 0})).unwrap();
+        let response: Value = pending.wait(TIMEOUT).unwrap();
+        assert_eq!(response, json!(1));
+        pending.cancel().unwrap();
+
+        let response: Value = a
+            .call_with_timeout(json!({"n": 2, "wait_ms": 0}), TIMEOUT, RetryConfig::none())
+            .unwrap();
+        assert_eq!(response, json!(2));
+        assert!(!cancelled.load(Ordering::Acquire));
+
+        drop(pending);
+        drop(a);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_cancel_one_of_two() {
+        let (a, b) = ipc_pair();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let server = spawn_server(b, cancelled.clone());
+
+        let first = a.start_call(json!({"n": 1, "wait_ms": 60_000})).unwrap();
+        let second = a.start_call(json!({"n": 2, "wait_ms": 200})).unwrap();
+        first.cancel().unwrap();
+        let result: Result<Value, _> = first.wait(TIMEOUT);
+        assert!(matches!(result, Err(NodeIpcError::Cancelled)));
+        let response: Value = second.wait(TIMEOUT).unwrap();
+        assert_eq!(response, json!(2));
+
+        drop((first, second));
+        drop(a);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_max_workers() {
+        use std::sync::atomic::AtomicUsize;
+
+        let (a, b) = ipc_pair();
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let server = {
+            let (running, peak) = (running.clone(), peak.clone());
+            thread::spawn(move || {
+                b.serve_calls(|n: u64, _| {
+                    let now = running.fetch_add(1, Ordering::AcqRel) + 1;
+                    peak.fetch_max(now, Ordering::AcqRel);
+                    thread::sleep(Duration::from_millis(20));
+                    running.fetch_sub(1, Ordering::AcqRel);
+                    Ok(n)
+                })
+                .unwrap();
+            })
+        };
+
+        let calls = (0..MAX_CALL_WORKERS as u64 * 2)
+            .map(|n| a.start_call(n).unwrap())
+            .collect::<Vec<_>>();
+        for (n, call) in calls.iter().enumerate() {
+            let response: u64 = call.wait(TIMEOUT).unwrap();
+            assert_eq!(response, n as u64);
+        }
+        assert!(peak.load(Ordering::Acquire) <= MAX_CALL_WORKERS);
+
+        drop(calls);
+        drop(a);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_handler_error() {
+        let (a, b) = ipc_pair();
+        let server = thread::spawn(move || {
+            b.serve_calls(|_: Value, _| -> anyhow::Result<()> { anyhow::bail!("no such command") })
+                .unwrap();
+        });
+
+        let result: Result<Value, _> = a.call_with_timeout(json!({}), TIMEOUT, RetryConfig::none());
+        assert!(matches!(result, Err(NodeIpcError::Remote(m)) if m == "no such command"));
+
+        drop(a);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,389 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Traffic counters of `NodeIpc` channels, for diagnostics.
+//!
+//! Every channel registers its counters on creation, so [`channel_stats`]
+//! can list all live channels in the process, including the singleton.
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::nodeipc::NodeIpc;
+use crate::singleton::IPC;
+
+static CHANNELS: Lazy<Mutex<Vec<Weak<IpcCounters>>>> = Lazy::new(Default::default);
+static NEXT_CHANNEL_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A snapshot of the counters of a channel.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpcStats {
+    /// Messages sent, including stream messages and calls.
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    /// Bytes of the sent messages, including the trailing newline but not
+    /// the frame headers. Compressed messages count as their compressed size.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Messages sent compressed, see `NodeIpc::with_compression`.
+    pub compressed_sent: u64,
+    /// Bytes of the compressed sent messages, before and after compression.
+    pub compression_bytes_before: u64,
+    pub compression_bytes_after: u64,
+    /// Compressed messages received.
+    pub compressed_received: u64,
+    /// `send_fd_vec` and `recv_fd_vec` operations.
+    pub fd_sends: u64,
+    pub fd_receives: u64,
+    /// Messages that could not be converted to JSON, so were not sent.
+    pub serialize_errors: u64,
+    /// Received messages that could not be converted from JSON.
+    pub deserialize_errors: u64,
+    /// How long the last send blocked writing to the file descriptor. Long
+    /// blocks mean the peer is not keeping up.
+    pub last_send_duration: Option<Duration>,
+    /// Received plain messages not yet consumed by `recv`.
+    pub queued_plain: u64,
+    /// Received stream messages not yet consumed by `IpcStream::recv`.
+    pub queued_stream: u64,
+    /// How long receiving was delayed by the inbound limits, see
+    /// `NodeIpc::set_inbound_limits`.
+    pub inbound_delayed: Duration,
+    /// Received messages discarded by the inbound limits.
+    pub inbound_dropped: u64,
+    /// Times the channel was shut down by the inbound limits.
+    pub inbound_disconnects: u64,
+}
+
+/// [`IpcStats`] of a live channel, as listed by [`channel_stats`].
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChannelStats {
+    /// Unique among channels of this process.
+    pub id: u64,
+    /// Whether this is the channel returned by `get_singleton`.
+    pub singleton: bool,
+    pub stats: IpcStats,
+}
+
+pub(crate) struct IpcCounters {
+    id: u64,
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    compressed_sent: AtomicU64,
+    compression_bytes_before: AtomicU64,
+    compression_bytes_after: AtomicU64,
+    compressed_received: AtomicU64,
+    fd_sends: AtomicU64,
+    fd_receives: AtomicU64,
+    serialize_errors: AtomicU64,
+    deserialize_errors: AtomicU64,
+    // Microseconds, plus one. 0 means no send completed yet.
+    last_send_us: AtomicU64,
+    queued_plain: AtomicU64,
+    queued_stream: AtomicU64,
+    inbound_delayed_us: AtomicU64,
+    inbound_dropped: AtomicU64,
+    inbound_disconnects: AtomicU64,
+}
+
+impl IpcCounters {
+    /// Create and register counters for a new channel.
+    pub(crate) fn register() -> Arc<Self> {
+        let counters = Arc::new(Self {
+            id: NEXT_CHANNEL_ID.fetch_add(1, Ordering::Relaxed),
+            messages_sent: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            compressed_sent: AtomicU64::new(0),
+            compression_bytes_before: AtomicU64::new(0),
+            compression_bytes_after: AtomicU64::new(0),
+            compressed_received: AtomicU64::new(0),
+            fd_sends: AtomicU64::new(0),
+            fd_receives: AtomicU64::new(0),
+            serialize_errors: AtomicU64::new(0),
+            deserialize_errors: AtomicU64::new(0),
+            last_send_us: AtomicU64::new(0),
+            queued_plain: AtomicU64::new(0),
+            queued_stream: AtomicU64::new(0),
+            inbound_delayed_us: AtomicU64::new(0),
+            inbound_dropped: AtomicU64::new(0),
+            inbound_disconnects: AtomicU64::new(0),
+        });
+        let mut channels = CHANNELS.lock().unwrap();
+        channels.retain(|c| c.strong_count() > 0);
+        channels.push(Arc::downgrade(&counters));
+        counters
+    }
+
+    pub(crate) fn sent(&self, len: usize, duration: Duration) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        let us = duration.as_micros().min(u64::MAX as u128 - 1) as u64;
+        self.last_send_us.store(us + 1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self, len: usize) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Record a sent message that was compressed from `before` to `after`
+    /// bytes. It is also recorded by `sent`.
+    pub(crate) fn compressed(&self, before: usize, after: usize) {
+        self.compressed_sent.fetch_add(1, Ordering::Relaxed);
+        self.compression_bytes_before
+            .fetch_add(before as u64, Ordering::Relaxed);
+        self.compression_bytes_after
+            .fetch_add(after as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn decompressed(&self) {
+        self.compressed_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn fd_sent(&self) {
+        self.fd_sends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn fd_received(&self) {
+        self.fd_receives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn serialize_error(&self) {
+        self.serialize_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn deserialize_error(&self) {
+        self.deserialize_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the current queue depths. Called with the demux lock held, so
+    /// updates are not reordered.
+    pub(crate) fn set_queued(&self, plain: usize, stream: usize) {
+        self.queued_plain.store(plain as u64, Ordering::Relaxed);
+        self.queued_stream.store(stream as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inbound_delayed(&self, duration: Duration) {
+        let us = duration.as_micros().min(u64::MAX as u128) as u64;
+        self.inbound_delayed_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inbound_dropped(&self) {
+        self.inbound_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inbound_disconnected(&self) {
+        self.inbound_disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> IpcStats {
+        let last_send_us = self.last_send_us.load(Ordering::Relaxed);
+        IpcStats {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            compressed_sent: self.compressed_sent.load(Ordering::Relaxed),
+            compression_bytes_before: self.compression_bytes_before.load(Ordering::Relaxed),
+            compression_bytes_after: self.compression_bytes_after.load(Ordering::Relaxed),
+            compressed_received: self.compressed_received.load(Ordering::Relaxed),
+            fd_sends: self.fd_sends.load(Ordering::Relaxed),
+            fd_receives: self.fd_receives.load(Ordering::Relaxed),
+            serialize_errors: self.serialize_errors.load(Ordering::Relaxed),
+            deserialize_errors: self.deserialize_errors.load(Ordering::Relaxed),
+            last_send_duration: last_send_us.checked_sub(1).map(Duration::from_micros),
+            queued_plain: self.queued_plain.load(Ordering::Relaxed),
+            queued_stream: self.queued_stream.load(Ordering::Relaxed),
+            inbound_delayed: Duration::from_micros(self.inbound_delayed_us.load(Ordering::Relaxed)),
+            inbound_dropped: self.inbound_dropped.load(Ordering::Relaxed),
+            inbound_disconnects: self.inbound_disconnects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl NodeIpc {
+    /// A snapshot of the traffic counters of this channel.
+    pub fn stats(&self) -> IpcStats {
+        self.counters.snapshot()
+    }
+
+    /// The id of this channel in [`channel_stats`].
+    pub fn channel_id(&self) -> u64 {
+        self.counters.id
+    }
+}
+
+/// Stats of every live `NodeIpc` channel of this process, ordered by id.
+/// Does not initialize the singleton.
+pub fn channel_stats() -> Vec<ChannelStats> {
+    let singleton_id = match *IPC.read().unwrap() {
+        Some(Some(ref ipc)) => Some(ipc.channel_id()),
+        _ => None,
+    };
+    let channels = CHANNELS.lock().unwrap();
+    channels
+        .iter()
+        .filter_map(|c| c.upgrade())
+        .map(|c| ChannelStats {
+            id: c.id,
+            singleton: Some(c.id) == singleton_id,
+            stats: c.snapshot(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::thread;
+
+    use serde::Serializer;
+    use serde_json::json;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::testutil::ipc_pair;
+
+    /// Fails to serialize, like an oversized message would.
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("message is too large"))
+        }
+    }
+
+    #[test]
+    fn test_counters() {
+        let (a, b) = ipc_pair();
+
+        a.send(json!({"n": 1})).unwrap(); // 8 bytes with the newline.
+        a.send("hello").unwrap(); // 8 bytes.
+        assert!(a.send(Unserializable).is_err());
+        b.send(42).unwrap(); // 3 bytes.
+
+        let _: Value = b.recv().unwrap().unwrap();
+        // Decoding fails after the message is received.
+        assert!(b.recv::<u64>().is_err());
+        let n: u64 = a.recv().unwrap().unwrap();
+        assert_eq!(n, 42);
+
+        let a_stats = a.stats();
+        assert_eq!(
+            a_stats,
+            IpcStats {
+                messages_sent: 2,
+                messages_received: 1,
+                bytes_sent: 16,
+                bytes_received: 3,
+                serialize_errors: 1,
+                last_send_duration: a_stats.last_send_duration,
+                ..Default::default()
+            }
+        );
+        assert!(a_stats.last_send_duration.is_some());
+        let b_stats = b.stats();
+        assert_eq!(
+            b_stats,
+            IpcStats {
+                messages_sent: 1,
+                messages_received: 2,
+                bytes_sent: 3,
+                bytes_received: 16,
+                deserialize_errors: 1,
+                last_send_duration: b_stats.last_send_duration,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fd_counters() {
+        let (a, b) = ipc_pair();
+        a.send_fd_vec(&[libc::STDOUT_FILENO]).unwrap();
+        let payload = b.recv_fd_vec().unwrap();
+        for fd in payload.raw_fds {
+            unsafe { libc::close(fd) };
+        }
+        assert_eq!(a.stats().fd_sends, 1);
+        assert_eq!(b.stats().fd_receives, 1);
+        assert_eq!(a.stats().messages_sent, 0);
+    }
+
+    #[test]
+    fn test_queued() {
+        let (a, b) = ipc_pair();
+        let (a, b) = (Arc::new(a), Arc::new(b));
+        let stream = a.open_stream();
+        stream.send(1).unwrap();
+        stream.send(2).unwrap();
+        a.send("plain").unwrap();
+
+        // Receiving the plain message queues the stream messages before it.
+        let _: String = b.recv().unwrap().unwrap();
+        assert_eq!(b.stats().queued_stream, 2);
+        assert_eq!(b.stats().queued_plain, 0);
+
+        let peer_stream = b.accept_stream().unwrap().unwrap();
+        let _: u64 = peer_stream.recv().unwrap().unwrap();
+        assert_eq!(b.stats().queued_stream, 1);
+    }
+
+    #[test]
+    fn test_concurrent_senders() {
+        let (a, b) = ipc_pair();
+        let a = Arc::new(a);
+        let senders: Vec<_> = (0..4)
+            .map(|_| {
+                let a = a.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        a.send(1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..400 {
+            let _: u64 = b.recv().unwrap().unwrap();
+        }
+        for sender in senders {
+            sender.join().unwrap();
+        }
+        assert_eq!(a.stats().messages_sent, 400);
+        assert_eq!(a.stats().bytes_sent, 800);
+        assert_eq!(b.stats().bytes_received, 800);
+    }
+
+    #[test]
+    fn test_channel_stats() {
+        let (a, b) = ipc_pair();
+        a.send(1).unwrap();
+        let ids: HashMap<u64, IpcStats> = channel_stats()
+            .into_iter()
+            .map(|c| (c.id, c.stats))
+            .collect();
+        assert_eq!(ids[&a.channel_id()].messages_sent, 1);
+        assert_eq!(ids[&b.channel_id()].messages_sent, 0);
+
+        let id = a.channel_id();
+        drop(a);
+        assert!(channel_stats().iter().all(|c| c.id != id));
+    }
+}
@@ -0,0 +1,50 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::process::Child;
+#[cfg(unix)]
+use std::process::Command;
+#[cfg(unix)]
+use std::process::Stdio;
+
+use filedescriptor::IntoRawFileDescriptor;
+
+use crate::NodeIpc;
+
+/// Two connected `NodeIpc`s backed by a socketpair.
+pub(crate) fn ipc_pair() -> (NodeIpc, NodeIpc) {
+    let (a, b) = filedescriptor::socketpair().unwrap();
+    let a = NodeIpc::from_raw_file_descriptor(a.into_raw_file_descriptor()).unwrap();
+    let b = NodeIpc::from_raw_file_descriptor(b.into_raw_file_descriptor()).unwrap();
+    (a, b)
+}
+
+/// Run the test `name` of this binary in a child process, with `envs` set
+/// and `NODE_CHANNEL_FD` set to its end of a socketpair. Returns the child
+/// and our end, compatible with the child's `get_singleton`.
+#[cfg(unix)]
+pub(crate) fn spawn_child_test(name: &str, envs: &[(&str, &str)]) -> (Child, NodeIpc) {
+    let (ours, theirs) = filedescriptor::socketpair().unwrap();
+    let fd = theirs.as_raw_fd();
+    assert_eq!(unsafe { libc::fcntl(fd, libc::F_SETFD, 0) }, 0);
+    let child = Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", name])
+        .envs(envs.iter().copied())
+        .env("NODE_CHANNEL_FD", fd.to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    drop(theirs);
+    let ours = NodeIpc::from_raw_file_descriptor(ours.into_raw_file_descriptor())
+        .unwrap()
+        .with_libuv_compat();
+    (child, ours)
+}
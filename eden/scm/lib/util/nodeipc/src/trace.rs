@@ -0,0 +1,246 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Optional recording of `NodeIpc` traffic to a newline-delimited JSON file
+//! for postmortem debugging.
+//!
+//! Each line in the trace file is a [`TraceRecord`]. Records are flushed one
+//! at a time, so a crash loses at most the record being written.
+
+use std::fs;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::nodeipc::NodeIpc;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceDirection {
+    Send,
+    Recv,
+    SendFds,
+    RecvFds,
+}
+
+/// A line in the trace file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    pub dir: TraceDirection,
+    /// Microseconds since tracing was enabled. Monotonic.
+    pub ts_us: u64,
+    /// Byte length of the message, excluding frame headers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub len: Option<usize>,
+    /// The full message. Absent if redacted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<Value>,
+    /// The top-level "type" field of a redacted message, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_type: Option<String>,
+    /// Number of file descriptors sent or received.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fd_count: Option<usize>,
+    /// What the file descriptors are for, if the sender labeled them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fd_purposes: Option<Vec<String>>,
+}
+
+pub(crate) struct Tracer {
+    out: Mutex<BufWriter<File>>,
+    redact: bool,
+    start: Instant,
+    disabled: AtomicBool,
+}
+
+impl Tracer {
+    fn open(path: &Path, redact: bool) -> anyhow::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening NodeIpc trace file {}", path.display()))?;
+        Ok(Self {
+            out: Mutex::new(BufWriter::new(file)),
+            redact,
+            start: Instant::now(),
+            disabled: AtomicBool::new(false),
+        })
+    }
+
+    pub(crate) fn message(&self, dir: TraceDirection, line: &str) {
+        if self.disabled.load(Ordering::Relaxed) {
+            return;
+        }
+        let line = line.trim_end_matches('\n');
+        let value: Option<Value> = serde_json::from_str(line).ok();
+        let (message, message_type) = if self.redact {
+            let message_type = value
+                .as_ref()
+                .and_then(|v| v.get("type"))
+                .and_then(|t| t.as_str())
+                .map(|t| t.to_string());
+            (None, message_type)
+        } else {
+            // Keep non-JSON lines as strings so they are not lost.
+            (Some(value.unwrap_or_else(|| line.into())), None)
+        };
+        self.write(TraceRecord {
+            dir,
+            ts_us: 0,
+            len: Some(line.len()),
+            message,
+            message_type,
+            fd_count: None,
+            fd_purposes: None,
+        });
+    }
+
+    pub(crate) fn fds(&self, dir: TraceDirection, count: usize, purposes: Option<&[&str]>) {
+        if self.disabled.load(Ordering::Relaxed) {
+            return;
+        }
+        self.write(TraceRecord {
+            dir,
+            ts_us: 0,
+            len: None,
+            message: None,
+            message_type: None,
+            fd_count: Some(count),
+            fd_purposes: purposes.map(|p| p.iter().map(|s| s.to_string()).collect()),
+        });
+    }
+
+    fn write(&self, mut record: TraceRecord) {
+        let mut out = self.out.lock().unwrap();
+        // Take the timestamp with the lock held so records are in order.
+        record.ts_us = self.start.elapsed().as_micros() as u64;
+        let result = (|| -> anyhow::Result<()> {
+            serde_json::to_writer(&mut *out, &record)?;
+            out.write_all(b"\n")?;
+            out.flush()?;
+            Ok(())
+        })();
+        if let Err(e) = result {
+            if !self.disabled.swap(true, Ordering::Relaxed) {
+                tracing::warn!("disabling NodeIpc trace after write error: {:?}", e);
+            }
+        }
+    }
+}
+
+impl NodeIpc {
+    /// Append a record of every message sent or received to the file at
+    /// `path`. If `redact` is true, only the length and top-level "type"
+    /// field of messages are recorded.
+    ///
+    /// Tracing can only be enabled once per `NodeIpc`. Errors writing the
+    /// trace file disable tracing without affecting the IPC channel.
+    pub fn enable_trace(&self, path: &Path, redact: bool) -> anyhow::Result<()> {
+        let tracer = Tracer::open(path, redact)?;
+        self.tracer
+            .set(tracer)
+            .map_err(|_| anyhow::format_err!("NodeIpc trace is already enabled"))
+    }
+}
+
+/// Parse a trace file written by `NodeIpc::enable_trace`. A truncated last
+/// line, as left by a crash, is ignored.
+pub fn read_trace(path: &Path) -> anyhow::Result<Vec<TraceRecord>> {
+    let file = File::open(path)
+        .with_context(|| format!("opening NodeIpc trace file {}", path.display()))?;
+    let mut lines = BufReader::new(file).lines().peekable();
+    let mut records = Vec::new();
+    while let Some(line) = lines.next() {
+        let line = line?;
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(_) if lines.peek().is_none() => break,
+            Err(e) => {
+                return Err(e).with_context(|| format!("parsing NodeIpc trace line {:?}", line));
+            }
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testutil::ipc_pair;
+
+    #[test]
+    fn test_send_recv_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace");
+        let (a, b) = ipc_pair();
+        a.enable_trace(&path, false).unwrap();
+        assert!(a.enable_trace(&path, false).is_err());
+
+        a.send(json!({"type": "ping", "n": 1})).unwrap();
+        b.send(json!({"type": "pong", "n": 2})).unwrap();
+        let _: Value = a.recv().unwrap().unwrap();
+
+        let records = read_trace(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].dir, TraceDirection::Send);
+        assert_eq!(records[0].message, Some(json!({"type": "ping", "n": 1})));
+        let sent = serde_json::to_string(&json!({"type": "ping", "n": 1})).unwrap();
+        assert_eq!(records[0].len, Some(sent.len()));
+        assert_eq!(records[1].dir, TraceDirection::Recv);
+        assert_eq!(records[1].message, Some(json!({"type": "pong", "n": 2})));
+        assert!(records[0].ts_us <= records[1].ts_us);
+    }
+
+    #[test]
+    fn test_redact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace");
+        let (a, _b) = ipc_pair();
+        a.enable_trace(&path, true).unwrap();
+
+        a.send(json!({"type": "secret", "token": "hunter2"}))
+            .unwrap();
+        a.send(json!([1, 2, 3])).unwrap();
+
+        let records = read_trace(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].message, None);
+        assert_eq!(records[0].message_type.as_deref(), Some("secret"));
+        assert_eq!(records[1].message_type, None);
+        assert_eq!(records[1].len, Some("[1,2,3]".len()));
+        assert!(!fs::read_to_string(&path).unwrap().contains("hunter2"));
+    }
+
+    #[test]
+    fn test_truncated_trace() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace");
+        fs::write(
+            &path,
+            "{\"dir\":\"send_fds\",\"ts_us\":1,\"fd_count\":3}\n{\"dir\":\"se",
+        )
+        .unwrap();
+        let records = read_trace(&path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].fd_count, Some(3));
+    }
+}
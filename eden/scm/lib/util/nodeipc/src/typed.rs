@@ -0,0 +1,386 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Versioned messages over a `NodeIpc` channel, so that protocol drift
+//! between the two sides fails loudly instead of silently dropping fields.
+//!
+//! A protocol is an enum implementing [`IpcProtocol`], serialized with
+//! `#[serde(tag = "kind", content = "payload")]`. [`TypedChannel`] sends its
+//! messages as `{"v": VERSION, "kind": ..., "payload": ...}`, and rejects
+//! received messages of another version. In strict mode, the default in
+//! debug builds, it also rejects messages with fields the enum doesn't know.
+//!
+//! [`protocol_schema`] describes the shape of each kind of message, for the
+//! other side to check in its tests, and [`check_protocol`] fails tests when
+//! the shape changes without a version bump.
+
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use anyhow::bail;
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Map;
+use serde_json::Value;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::nodeipc::NodeIpc;
+
+const VERSION_FIELD: &str = "v";
+const KIND_FIELD: &str = "kind";
+
+/// Messages of a versioned protocol.
+pub trait IpcProtocol: Serialize + DeserializeOwned {
+    /// Bump when a kind of message is added, removed or changes shape.
+    const VERSION: u32;
+
+    /// A message of each kind, to describe and check the protocol.
+    fn samples() -> Vec<Self>;
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ProtocolError {
+    #[error("NodeIpc protocol version mismatch: local {local}, remote {remote:?}")]
+    VersionMismatch { local: u32, remote: Option<u32> },
+
+    #[error("NodeIpc message {kind} has unknown fields: {}", .fields.join(", "))]
+    UnknownFields { kind: String, fields: Vec<String> },
+
+    #[error("NodeIpc message is not an object with a kind: {0}")]
+    Malformed(String),
+}
+
+/// A `NodeIpc` channel exchanging the messages of protocol `T`.
+pub struct TypedChannel<T> {
+    ipc: Arc<NodeIpc>,
+    strict: bool,
+    phantom: PhantomData<fn() -> T>,
+}
+
+impl<T: IpcProtocol> TypedChannel<T> {
+    pub fn new(ipc: Arc<NodeIpc>) -> Self {
+        Self {
+            ipc,
+            strict: cfg!(debug_assertions),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Whether messages with unknown fields are rejected with
+    /// `ProtocolError::UnknownFields`, or only logged. Defaults to strict
+    /// in debug builds.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn ipc(&self) -> &Arc<NodeIpc> {
+        &self.ipc
+    }
+
+    pub fn send(&self, message: &T) -> anyhow::Result<()> {
+        self.ipc.send(encode(message)?)
+    }
+
+    /// Receive a message. Block if there are no new messages. Returns
+    /// `None` if the other side has closed the channel.
+    pub fn recv(&self) -> anyhow::Result<Option<T>> {
+        match self.ipc.recv::<Value>()? {
+            Some(value) => Ok(Some(decode(value, self.strict)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn encode<T: IpcProtocol>(message: &T) -> anyhow::Result<Value> {
+    let mut value = serde_json::to_value(message)
+        .context("in TypedChannel::send, when converting message to JSON")?;
+    match value.as_object_mut() {
+        Some(object) if object.contains_key(KIND_FIELD) => {
+            object.insert(VERSION_FIELD.to_string(), T::VERSION.into());
+        }
+        _ => return Err(ProtocolError::Malformed(value.to_string()).into()),
+    }
+    Ok(value)
+}
+
+fn decode<T: IpcProtocol>(mut value: Value, strict: bool) -> anyhow::Result<T> {
+    let object = match value.as_object_mut() {
+        Some(object) => object,
+        None => return Err(ProtocolError::Malformed(value.to_string()).into()),
+    };
+    let remote = object.remove(VERSION_FIELD);
+    let remote = remote.as_ref().and_then(Value::as_u64).map(|v| v as u32);
+    if remote != Some(T::VERSION) {
+        return Err(ProtocolError::VersionMismatch {
+            local: T::VERSION,
+            remote,
+        }
+        .into());
+    }
+    let kind = object.get(KIND_FIELD).and_then(Value::as_str);
+    let kind = match kind.map(str::to_string) {
+        Some(kind) => kind,
+        None => return Err(ProtocolError::Malformed(value.to_string()).into()),
+    };
+    let message: T = serde_json::from_value(value.clone())
+        .with_context(|| format!("in TypedChannel::recv, when deserializing {}", kind))?;
+
+    // Fields unknown to `T` are skipped by serde, and so not serialized back.
+    let known = serde_json::to_value(&message)?;
+    let mut fields = Vec::new();
+    unknown_fields(&value, &known, "", &mut fields);
+    if !fields.is_empty() {
+        fields.sort();
+        let error = ProtocolError::UnknownFields { kind, fields };
+        if strict {
+            return Err(error.into());
+        }
+        warn!("{}", error);
+    }
+    Ok(message)
+}
+
+/// Add the paths of the fields of `received` that are not in `known` to
+/// `fields`.
+fn unknown_fields(received: &Value, known: &Value, prefix: &str, fields: &mut Vec<String>) {
+    match (received, known) {
+        (Value::Object(received), Value::Object(known)) => {
+            for (name, value) in received {
+                let path = if prefix.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}.{}", prefix, name)
+                };
+                match known.get(name) {
+                    Some(known) => unknown_fields(value, known, &path, fields),
+                    None => fields.push(path),
+                }
+            }
+        }
+        (Value::Array(received), Value::Array(known)) => {
+            for (i, (value, known)) in received.iter().zip(known).enumerate() {
+                unknown_fields(value, known, &format!("{}[{}]", prefix, i), fields);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Describe the messages of `T`, from its samples:
+/// `{"version": N, "kinds": {kind: payload}}`, where the payload has the
+/// JSON type of each value ("string", "number", ...) in place of the value.
+/// Kinds without payload are `null`.
+pub fn protocol_schema<T: IpcProtocol>() -> anyhow::Result<Value> {
+    let mut kinds = Map::new();
+    for sample in T::samples() {
+        let mut value = encode(&sample)?;
+        let kind = value[KIND_FIELD].as_str().unwrap_or_default().to_string();
+        let payload = value
+            .as_object_mut()
+            .and_then(|object| object.remove("payload"));
+        kinds.insert(kind, payload.as_ref().map_or(Value::Null, shape));
+    }
+    Ok(serde_json::json!({
+        "version": T::VERSION,
+        "kinds": kinds,
+    }))
+}
+
+fn shape(value: &Value) -> Value {
+    match value {
+        Value::Null => "null".into(),
+        Value::Bool(_) => "boolean".into(),
+        Value::Number(_) => "number".into(),
+        Value::String(_) => "string".into(),
+        Value::Array(items) => Value::Array(items.first().map(shape).into_iter().collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(name, value)| (name.clone(), shape(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// For tests: check that every sample of `T` survives a round trip, and
+/// that `T` still matches `snapshot`, a `protocol_schema` recorded for its
+/// current version. Fails if the protocol changed but `VERSION` didn't.
+pub fn check_protocol<T: IpcProtocol + PartialEq + Debug>(snapshot: &Value) -> anyhow::Result<()> {
+    for sample in T::samples() {
+        let decoded: T = decode(encode(&sample)?, true)?;
+        if decoded != sample {
+            bail!("{:?} was received as {:?}", sample, decoded);
+        }
+    }
+    let schema = protocol_schema::<T>()?;
+    if &schema == snapshot {
+        return Ok(());
+    }
+    let pretty = serde_json::to_string_pretty(&schema)?;
+    if snapshot["version"] == schema["version"] {
+        bail!(
+            "protocol changed without bumping VERSION {}, bump it and update the snapshot to:\n{}",
+            T::VERSION,
+            pretty
+        );
+    }
+    bail!(
+        "VERSION is now {}, update the snapshot to:\n{}",
+        T::VERSION,
+        pretty
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+    use crate::testutil::ipc_pair;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(tag = "kind", content = "payload")]
+    enum Message {
+        Ping { id: u64 },
+        Log { level: String, lines: Vec<String> },
+        Quit,
+    }
+
+    impl IpcProtocol for Message {
+        const VERSION: u32 = 2;
+
+        fn samples() -> Vec<Self> {
+            vec![
+                Message::Ping { id: 1 },
+                Message::Log {
+                    level: "info".to_string(),
+                    lines: vec!["hello".to_string()],
+                },
+                Message::Quit,
+            ]
+        }
+    }
+
+    fn channels() -> (Arc<NodeIpc>, TypedChannel<Message>) {
+        let (a, b) = ipc_pair();
+        (
+            Arc::new(a),
+            TypedChannel::new(Arc::new(b)).with_strict(true),
+        )
+    }
+
+    fn recv_error(channel: &TypedChannel<Message>) -> ProtocolError {
+        let err = channel.recv().unwrap_err();
+        err.downcast::<ProtocolError>().unwrap()
+    }
+
+    #[test]
+    fn test_send_recv() -> anyhow::Result<()> {
+        let (a, b) = ipc_pair();
+        let (a, b) = (Arc::new(a), Arc::new(b));
+        let typed_a = TypedChannel::<Message>::new(a);
+        let typed_b = TypedChannel::<Message>::new(b.clone());
+        typed_a.send(&Message::Ping { id: 1 })?;
+        assert_eq!(
+            b.recv::<Value>()?,
+            Some(json!({"v": 2, "kind": "Ping", "payload": {"id": 1}}))
+        );
+
+        for message in Message::samples() {
+            typed_a.send(&message)?;
+            assert_eq!(typed_b.recv()?, Some(message));
+        }
+        drop(typed_a);
+        assert_eq!(typed_b.recv()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_mismatch() -> anyhow::Result<()> {
+        let (a, b) = channels();
+        a.send(json!({"v": 1, "kind": "Quit"}))?;
+        assert_eq!(
+            recv_error(&b),
+            ProtocolError::VersionMismatch {
+                local: 2,
+                remote: Some(1)
+            }
+        );
+        a.send(json!({"kind": "Quit"}))?;
+        assert_eq!(
+            recv_error(&b),
+            ProtocolError::VersionMismatch {
+                local: 2,
+                remote: None
+            }
+        );
+        a.send(json!(["Quit"]))?;
+        assert!(matches!(recv_error(&b), ProtocolError::Malformed(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_fields() -> anyhow::Result<()> {
+        let renamed = json!({
+            "v": 2,
+            "kind": "Log",
+            "payload": {"level": "info", "lines": [], "message": "renamed"},
+            "extra": true,
+        });
+        let (a, b) = channels();
+        a.send(&renamed)?;
+        assert_eq!(
+            recv_error(&b),
+            ProtocolError::UnknownFields {
+                kind: "Log".to_string(),
+                fields: vec!["extra".to_string(), "payload.message".to_string()],
+            }
+        );
+
+        let b = b.with_strict(false);
+        a.send(&renamed)?;
+        assert_eq!(
+            b.recv()?,
+            Some(Message::Log {
+                level: "info".to_string(),
+                lines: vec![],
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_protocol() -> anyhow::Result<()> {
+        let snapshot = json!({
+            "version": 2,
+            "kinds": {
+                "Ping": {"id": "number"},
+                "Log": {"level": "string", "lines": ["string"]},
+                "Quit": null,
+            },
+        });
+        assert_eq!(protocol_schema::<Message>()?, snapshot);
+        check_protocol::<Message>(&snapshot)?;
+
+        // A kind was added since the snapshot, without a version bump.
+        let mut old = snapshot.clone();
+        old["kinds"].as_object_mut().unwrap().remove("Quit");
+        let err = check_protocol::<Message>(&old).unwrap_err();
+        assert!(err.to_string().contains("without bumping"), "{}", err);
+
+        // The version was bumped, but not the snapshot.
+        old["version"] = 1.into();
+        let err = check_protocol::<Message>(&old).unwrap_err();
+        assert!(err.to_string().contains("update the snapshot"), "{}", err);
+        Ok(())
+    }
+}
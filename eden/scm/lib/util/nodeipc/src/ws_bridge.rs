@@ -0,0 +1,537 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Bridge between `NodeIpc` and a WebSocket, so a browser can talk to the
+//! peer of the channel without a nodejs server in between.
+//!
+//! Each text message from the WebSocket is a JSON message sent on the
+//! channel, and each plain message received from the channel is sent as a
+//! text message, unchanged. Multiplexed streams are not forwarded.
+//!
+//! The inbound limits of the channel, see `NodeIpc::set_inbound_limits`,
+//! apply to the messages from the WebSocket too, counted separately. Pings
+//! are answered by the WebSocket itself. Binary messages are not supported.
+
+use std::io;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::sync::mpsc::TryRecvError;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Context;
+use tungstenite::error::CapacityError;
+use tungstenite::error::ProtocolError;
+use tungstenite::handshake::server::ErrorResponse;
+use tungstenite::handshake::server::Request;
+use tungstenite::handshake::server::Response;
+use tungstenite::http::header::AUTHORIZATION;
+use tungstenite::http::StatusCode;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::Message;
+use tungstenite::WebSocket;
+
+use crate::call::is_closed;
+use crate::ratelimit::is_exempt;
+use crate::ratelimit::InboundLimiter;
+use crate::InboundLimitPolicy;
+use crate::NodeIpc;
+use crate::NodeIpcError;
+
+/// How often the WebSocket side checks for messages from the channel.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How long a client has to send its upgrade request.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a send to the client may block before the bridge gives up on
+/// it, e.g. if it stopped reading.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Messages from the channel waiting to be sent to the WebSocket. Once that
+/// many are waiting, the channel is not read until the client catches up.
+const MAX_PENDING_MESSAGES: usize = 64;
+
+/// How long to wait for the client to acknowledge the WebSocket is closed.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The largest message accepted from the WebSocket. Larger messages close
+/// it.
+const MAX_MESSAGE_SIZE: usize = 16 << 20;
+
+/// Forward messages between `ipc` and the first WebSocket client connecting
+/// to `listener` with `auth_token`, until either side closes. Blocking.
+///
+/// The token is read from an `Authorization: Bearer` header, or from a
+/// `token` query parameter since browsers cannot set headers on WebSockets.
+/// Clients without the token are refused with 401, and the bridge waits for
+/// the next one.
+///
+/// Once the client closes the WebSocket, the channel is shut down. Once the
+/// peer closes the channel, the WebSocket is closed. Returns
+/// `NodeIpcError::ProtocolViolation` if the client sent a binary or too large
+/// message, or exceeded the inbound limits with the `Disconnect` policy.
+pub fn bridge_to_websocket(
+    ipc: Arc<NodeIpc>,
+    listener: TcpListener,
+    auth_token: String,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !auth_token.is_empty(),
+        "in bridge_to_websocket, the token must not be empty"
+    );
+    let ws = accept(&listener, &auth_token)?;
+    let (tx, rx) = mpsc::sync_channel(MAX_PENDING_MESSAGES);
+    let recv_pump = {
+        let ipc = ipc.clone();
+        thread::spawn(move || recv_pump(&ipc, tx))
+    };
+    let result = ws_pump(&ipc, ws, rx);
+    // Ends the receiving pump if the WebSocket ended first.
+    ipc.shutdown_channel();
+    let recv_result = match recv_pump.join() {
+        Ok(result) => result,
+        Err(e) => std::panic::resume_unwind(e),
+    };
+    result.and(recv_result)
+}
+
+/// Accept connections until one has the token.
+fn accept(listener: &TcpListener, auth_token: &str) -> anyhow::Result<WebSocket<TcpStream>> {
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_MESSAGE_SIZE),
+        max_frame_size: Some(MAX_MESSAGE_SIZE),
+        ..Default::default()
+    };
+    loop {
+        let (stream, addr) = listener
+            .accept()
+            .context("in bridge_to_websocket, when accepting a connection")?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let check_token = |request: &Request, response: Response| {
+            if is_authorized(request, auth_token) {
+                Ok(response)
+            } else {
+                let mut response = ErrorResponse::new(Some("invalid token".to_string()));
+                *response.status_mut() = StatusCode::UNAUTHORIZED;
+                Err(response)
+            }
+        };
+        match tungstenite::accept_hdr_with_config(stream, check_token, Some(config)) {
+            Ok(ws) => {
+                ws.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+                ws.get_ref().set_write_timeout(Some(WRITE_TIMEOUT))?;
+                return Ok(ws);
+            }
+            Err(e) => tracing::info!("NodeIpc WebSocket bridge refused {}: {}", addr, e),
+        }
+    }
+}
+
+fn is_authorized(request: &Request, auth_token: &str) -> bool {
+    let from_header = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let from_query = request
+        .uri()
+        .query()
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|param| param.strip_prefix("token="));
+    from_header
+        .into_iter()
+        .chain(from_query)
+        .any(|token| constant_time_eq(token.as_bytes(), auth_token.as_bytes()))
+}
+
+/// Compare without leaking the length of the common prefix.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Sends the plain messages received from `ipc` to `tx`, until the channel
+/// is closed. Blocks while `tx` is full.
+fn recv_pump(ipc: &NodeIpc, tx: mpsc::SyncSender<String>) -> anyhow::Result<()> {
+    loop {
+        let line = match ipc.demux.recv_plain_line(ipc) {
+            Ok(Some(line)) => line,
+            Ok(None) => return Ok(()),
+            Err(e) if is_closed(&e) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if tx.send(line).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Forwards messages from `ws` to `ipc`, and the lines in `rx` to `ws`,
+/// until either side closes.
+fn ws_pump(
+    ipc: &NodeIpc,
+    mut ws: WebSocket<TcpStream>,
+    rx: mpsc::Receiver<String>,
+) -> anyhow::Result<()> {
+    let mut limiter = ipc.copy_inbound_limits();
+    loop {
+        loop {
+            let mut line = match rx.try_recv() {
+                Ok(line) => line,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    close(&mut ws, CloseCode::Normal, "channel closed");
+                    return Ok(());
+                }
+            };
+            if line.ends_with('\n') {
+                line.pop();
+            }
+            match ws.send(Message::Text(line)) {
+                Ok(()) => {}
+                Err(e) if ws_closed(&e) => return Ok(()),
+                Err(tungstenite::Error::Io(e))
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    anyhow::bail!(
+                        "in bridge_to_websocket, the client did not read for {:?}",
+                        WRITE_TIMEOUT
+                    );
+                }
+                Err(e) => {
+                    return Err(e).context("in bridge_to_websocket, when sending to the WebSocket");
+                }
+            }
+        }
+
+        let text = match ws.read() {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Binary(_)) => {
+                close(
+                    &mut ws,
+                    CloseCode::Unsupported,
+                    "binary messages are not supported",
+                );
+                return Err(protocol_violation("sent a binary message").into());
+            }
+            // Pings are answered, and the close acknowledged, by `ws`.
+            Ok(_) => continue,
+            Err(tungstenite::Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue;
+            }
+            Err(tungstenite::Error::Capacity(CapacityError::MessageTooLong { .. })) => {
+                close(&mut ws, CloseCode::Size, "message too long");
+                return Err(protocol_violation("sent a message too long").into());
+            }
+            Err(e) if ws_closed(&e) => return Ok(()),
+            Err(e) => {
+                return Err(e).context("in bridge_to_websocket, when receiving from the WebSocket");
+            }
+        };
+
+        if let Some(limiter) = &mut limiter {
+            match admit(ipc, limiter, &text) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    close(
+                        &mut ws,
+                        CloseCode::Policy,
+                        "exceeded the inbound message rate",
+                    );
+                    return Err(e.into());
+                }
+            }
+        }
+        // Sent as parsed, so the message is a single line.
+        let message: serde_json::Value = match serde_json::from_str(&text) {
+            Ok(message) => message,
+            Err(e) => {
+                ipc.counters.deserialize_error();
+                tracing::warn!("NodeIpc WebSocket bridge skipped invalid JSON: {}", e);
+                continue;
+            }
+        };
+        match ipc.send(message) {
+            Ok(()) => {}
+            Err(e) if is_closed(&e) => {
+                close(&mut ws, CloseCode::Normal, "channel closed");
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Apply `limiter` to a message from the WebSocket, like
+/// `NodeIpc::set_inbound_limits` does to messages from the channel. Returns
+/// `false` if the message should be discarded.
+fn admit(ipc: &NodeIpc, limiter: &mut InboundLimiter, text: &str) -> Result<bool, NodeIpcError> {
+    loop {
+        let wait = match limiter.take(Instant::now()) {
+            Ok(()) => return Ok(true),
+            Err(wait) => wait,
+        };
+        match limiter.policy() {
+            InboundLimitPolicy::Drop | InboundLimitPolicy::Disconnect if is_exempt(text) => {
+                return Ok(true);
+            }
+            // Not reading from the WebSocket blocks the client once the OS
+            // buffer is full.
+            InboundLimitPolicy::Delay => {
+                thread::sleep(wait);
+                ipc.counters.inbound_delayed(wait);
+            }
+            InboundLimitPolicy::Drop => {
+                ipc.counters.inbound_dropped();
+                return Ok(false);
+            }
+            InboundLimitPolicy::Disconnect => {
+                tracing::warn!("NodeIpc WebSocket client exceeded the inbound limits");
+                ipc.counters.inbound_disconnected();
+                return Err(protocol_violation("exceeded the inbound message rate"));
+            }
+        }
+    }
+}
+
+fn protocol_violation(what: &str) -> NodeIpcError {
+    NodeIpcError::ProtocolViolation(format!("WebSocket client {}", what))
+}
+
+/// Close `ws`, and wait for the client to acknowledge it.
+fn close(ws: &mut WebSocket<TcpStream>, code: CloseCode, reason: &'static str) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    if let Err(e) = ws.close(Some(frame)) {
+        tracing::debug!("NodeIpc WebSocket bridge cannot close the WebSocket: {}", e);
+        return;
+    }
+    let deadline = Instant::now() + CLOSE_TIMEOUT;
+    while Instant::now() < deadline {
+        match ws.read() {
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            // `ConnectionClosed` once acknowledged.
+            Err(_) => return,
+        }
+    }
+}
+
+/// Whether `error` means the WebSocket is closed.
+fn ws_closed(error: &tungstenite::Error) -> bool {
+    match error {
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => true,
+        tungstenite::Error::Protocol(
+            ProtocolError::ResetWithoutClosingHandshake | ProtocolError::SendAfterClosing,
+        ) => true,
+        tungstenite::Error::Io(e) => matches!(
+            e.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::UnexpectedEof
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde::Serialize;
+    use serde_json::Value;
+    use tungstenite::client::IntoClientRequest;
+    use tungstenite::stream::MaybeTlsStream;
+
+    use super::*;
+    use crate::testutil::ipc_pair;
+
+    const TOKEN: &str = "secret";
+
+    type Client = WebSocket<MaybeTlsStream<TcpStream>>;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Greeting {
+        name: String,
+        count: u32,
+    }
+
+    /// Bridge `ipc` to a WebSocket on a local port. Returns the URL to
+    /// connect to, without the token.
+    fn spawn_bridge(ipc: Arc<NodeIpc>) -> (String, thread::JoinHandle<anyhow::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let bridge = thread::spawn(move || bridge_to_websocket(ipc, listener, TOKEN.to_string()));
+        (url, bridge)
+    }
+
+    fn connect(url: &str) -> Client {
+        tungstenite::connect(format!("{}?token={}", url, TOKEN))
+            .unwrap()
+            .0
+    }
+
+    /// The next message from the bridge, skipping pings.
+    fn read(client: &mut Client) -> Message {
+        loop {
+            match client.read().unwrap() {
+                Message::Ping(_) | Message::Pong(_) => {}
+                message => return message,
+            }
+        }
+    }
+
+    /// Close `client`, and wait for the bridge to acknowledge it.
+    fn close_client(mut client: Client) {
+        client.close(None).unwrap();
+        while client.read().is_ok() {}
+    }
+
+    fn close_code(message: Message) -> CloseCode {
+        match message {
+            Message::Close(Some(frame)) => frame.code,
+            message => panic!("expected a close frame, got {:?}", message),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (a, b) = ipc_pair();
+        let (url, bridge) = spawn_bridge(Arc::new(a));
+        let mut client = connect(&url);
+
+        let greeting = Greeting {
+            name: "browser".to_string(),
+            count: 1,
+        };
+        let text = serde_json::to_string(&greeting).unwrap();
+        client.send(Message::Text(text)).unwrap();
+        assert_eq!(b.recv::<Greeting>().unwrap(), Some(greeting));
+
+        let greeting = Greeting {
+            name: "cli".to_string(),
+            count: 2,
+        };
+        b.send(&greeting).unwrap();
+        match read(&mut client) {
+            Message::Text(text) => {
+                assert_eq!(serde_json::from_str::<Greeting>(&text).unwrap(), greeting);
+            }
+            message => panic!("expected a text message, got {:?}", message),
+        }
+
+        close_client(client);
+        bridge.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_bad_token() {
+        let (a, b) = ipc_pair();
+        let (url, bridge) = spawn_bridge(Arc::new(a));
+
+        for url in [format!("{}?token=wrong", url), url.clone()] {
+            match tungstenite::connect(url) {
+                Err(tungstenite::Error::Http(response)) => {
+                    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+                }
+                Err(e) => panic!("expected a refused upgrade, got {}", e),
+                Ok(_) => panic!("connected without the token"),
+            }
+        }
+
+        // The bridge still accepts a client with the token, in a header.
+        let mut request = url.into_client_request().unwrap();
+        let header = format!("Bearer {}", TOKEN).parse().unwrap();
+        request.headers_mut().insert(AUTHORIZATION, header);
+        let (mut client, _) = tungstenite::connect(request).unwrap();
+        b.send("hello").unwrap();
+        assert_eq!(read(&mut client), Message::Text("\"hello\"".to_string()));
+
+        close_client(client);
+        bridge.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_websocket_close_ends_bridge() {
+        let (a, b) = ipc_pair();
+        let (url, bridge) = spawn_bridge(Arc::new(a));
+        let client = connect(&url);
+
+        close_client(client);
+        bridge.join().unwrap().unwrap();
+        assert!(matches!(b.recv::<Value>(), Ok(None)));
+    }
+
+    #[test]
+    fn test_channel_close_ends_bridge() {
+        let (a, b) = ipc_pair();
+        let (url, bridge) = spawn_bridge(Arc::new(a));
+        let mut client = connect(&url);
+
+        drop(b);
+        assert_eq!(close_code(read(&mut client)), CloseCode::Normal);
+        while client.read().is_ok() {}
+        bridge.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_binary_rejected() {
+        let (a, b) = ipc_pair();
+        let (url, bridge) = spawn_bridge(Arc::new(a));
+        let mut client = connect(&url);
+
+        client.send(Message::Binary(vec![1, 2, 3])).unwrap();
+        assert_eq!(close_code(read(&mut client)), CloseCode::Unsupported);
+        while client.read().is_ok() {}
+        let error = bridge.join().unwrap().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<NodeIpcError>(),
+            Some(NodeIpcError::ProtocolViolation(_))
+        ));
+        // The channel is shut down too.
+        assert!(matches!(b.recv::<Value>(), Ok(None)));
+    }
+
+    #[test]
+    fn test_inbound_limits() {
+        let (a, b) = ipc_pair();
+        let a = Arc::new(a);
+        a.set_inbound_limits(1, 1, InboundLimitPolicy::Drop)
+            .unwrap();
+        let (url, bridge) = spawn_bridge(a.clone());
+        let mut client = connect(&url);
+
+        for n in 0..3 {
+            client.send(Message::Text(n.to_string())).unwrap();
+        }
+        assert_eq!(b.recv::<u32>().unwrap(), Some(0));
+
+        close_client(client);
+        bridge.join().unwrap().unwrap();
+        assert_eq!(a.stats().inbound_dropped, 2);
+    }
+}
@@ -5,6 +5,10 @@
  * GNU General Public License version 2.
  */
 
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
 
@@ -22,8 +26,19 @@ use crate::VFS;
 pub struct AsyncVfsWriter {
     sender: Option<Sender<WorkItem>>,
     handles: Vec<JoinHandle<()>>,
+    /// Set by `with_executor`, in which case there are no worker threads.
+    executor: Option<(VFS, VfsExecutor)>,
 }
 
+/// Runs a blocking task somewhere, resolving once it ran. Fails if the task
+/// panicked or was dropped without running. See
+/// `AsyncVfsWriter::with_executor`.
+pub type VfsExecutor = Arc<
+    dyn Fn(Box<dyn FnOnce() + Send>) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>
+        + Send
+        + Sync,
+>;
+
 struct WorkItem {
     res: oneshot::Sender<Result<usize>>,
     action: Action,
@@ -34,6 +49,21 @@ enum Action {
     Remove(RepoPathBuf),
     SetExecutable(RepoPathBuf, bool),
     Batch(Vec<Action>),
+    /// Writes of files of a directory, see `VFS::open_dir`.
+    WriteDir(RepoPathBuf, Vec<(RepoPathBuf, Bytes, UpdateFlag)>),
+}
+
+/// Context of the error of a batch, naming the path of the action that
+/// failed. Actions after it were not executed.
+#[derive(Debug)]
+pub struct BatchFailure {
+    pub path: RepoPathBuf,
+}
+
+impl fmt::Display for BatchFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "batch failed at {}", self.path)
+    }
 }
 
 /// Async write interface to `VFS`.
@@ -50,7 +80,21 @@ impl AsyncVfsWriter {
             let vfs = vfs.clone();
             handles.push(thread::spawn(move || async_vfs_worker(vfs, receiver)));
         }
-        Self { sender, handles }
+        Self {
+            sender,
+            handles,
+            executor: None,
+        }
+    }
+
+    /// Runs each action as a task of `executor` instead of on worker threads
+    /// of its own, e.g. to share a thread pool with the caller.
+    pub fn with_executor(vfs: VFS, executor: VfsExecutor) -> Self {
+        Self {
+            sender: None,
+            handles: Vec::new(),
+            executor: Some((vfs, executor)),
+        }
     }
 
     pub async fn write<B: Into<Bytes>>(
@@ -74,6 +118,21 @@ impl AsyncVfsWriter {
         self.submit_action(Action::Batch(batch)).await
     }
 
+    /// Same as `write_batch`, for files of the directory `dir`, which is
+    /// audited and created once for all of them. Files outside of `dir` are
+    /// written by path.
+    pub async fn write_dir<B: Into<Bytes>>(
+        &self,
+        dir: RepoPathBuf,
+        batch: impl IntoIterator<Item = (RepoPathBuf, B, UpdateFlag)>,
+    ) -> Result<usize> {
+        let batch = batch
+            .into_iter()
+            .map(|(path, data, flag)| (path, data.into(), flag))
+            .collect();
+        self.submit_action(Action::WriteDir(dir, batch)).await
+    }
+
     pub async fn remove(&self, path: RepoPathBuf) -> Result<()> {
         self.submit_action(Action::Remove(path)).await.map(|_| ())
     }
@@ -91,8 +150,19 @@ impl AsyncVfsWriter {
 
     async fn submit_action(&self, action: Action) -> Result<usize> {
         let (tx, rx) = oneshot::channel();
-        let wi = WorkItem { action, res: tx };
-        self.sender.as_ref().unwrap().send(wi).ok();
+        match &self.executor {
+            Some((vfs, executor)) => {
+                let vfs = vfs.clone();
+                executor(Box::new(move || {
+                    tx.send(execute_action(&vfs, action)).ok();
+                }))
+                .await?;
+            }
+            None => {
+                let wi = WorkItem { action, res: tx };
+                self.sender.as_ref().unwrap().send(wi).ok();
+            }
+        }
         rx.await?
     }
 }
@@ -118,10 +188,41 @@ fn execute_action(vfs: &VFS, action: Action) -> Result<usize> {
         Action::Batch(batch) => {
             let mut total = 0;
             for action in batch {
-                total += execute_action(vfs, action)?;
+                let path = action.path().map(|p| p.to_owned());
+                total += execute_action(vfs, action).map_err(|e| match path {
+                    Some(path) => e.context(BatchFailure { path }),
+                    None => e,
+                })?;
             }
             Ok(total)
         }
+        Action::WriteDir(dir, batch) => {
+            let first = match batch.first() {
+                Some((path, ..)) => path.clone(),
+                None => return Ok(0),
+            };
+            let writer = vfs
+                .open_dir(&dir)
+                .map_err(|e| e.context(BatchFailure { path: first }))?;
+            let mut total = 0;
+            for (path, data, flag) in batch {
+                total += writer
+                    .write(&path, &data, flag)
+                    .map_err(|e| e.context(BatchFailure { path }))?;
+            }
+            Ok(total)
+        }
+    }
+}
+
+impl Action {
+    fn path(&self) -> Option<&RepoPathBuf> {
+        match self {
+            Action::Write(path, _, _) | Action::Remove(path) | Action::SetExecutable(path, _) => {
+                Some(path)
+            }
+            Action::Batch(_) | Action::WriteDir(..) => None,
+        }
     }
 }
 
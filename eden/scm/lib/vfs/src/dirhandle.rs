@@ -0,0 +1,99 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Writing the files of a directory relative to an open handle of it, so
+//! the path to the directory is resolved once instead of once per file.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Opens directories for `DirHandle`s. See `VFS::with_dir_opener`.
+pub trait DirOpener: Send + Sync {
+    /// Open the directory at `path`. Fails if `path` is a symlink.
+    fn open_dir(&self, path: &Path) -> io::Result<Box<dyn DirHandle>>;
+}
+
+/// An open directory.
+pub trait DirHandle: Send {
+    /// Open the file `name` of the directory for writing, creating or
+    /// truncating it. Fails if it is a symlink.
+    fn create_file(&self, name: &str) -> io::Result<File>;
+
+    /// Create the symlink `name` in the directory pointing to `dest`. Fails
+    /// if `name` exists.
+    fn symlink(&self, name: &str, dest: &Path) -> io::Result<()>;
+}
+
+/// The `DirOpener` of the platform, if it has one.
+pub(crate) fn platform_dir_opener() -> Option<Arc<dyn DirOpener>> {
+    #[cfg(unix)]
+    return Some(Arc::new(unix::UnixDirOpener));
+
+    #[cfg(not(unix))]
+    return None;
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::fs::OpenOptions;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::io::FromRawFd;
+    use std::path::Path;
+
+    use super::DirHandle;
+    use super::DirOpener;
+
+    /// Opens directories with `O_DIRECTORY`, and writes their files with
+    /// `openat` and `symlinkat`.
+    pub(super) struct UnixDirOpener;
+
+    struct UnixDirHandle(File);
+
+    impl DirOpener for UnixDirOpener {
+        fn open_dir(&self, path: &Path) -> io::Result<Box<dyn DirHandle>> {
+            let dir = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC)
+                .open(path)?;
+            Ok(Box::new(UnixDirHandle(dir)))
+        }
+    }
+
+    fn c_string(s: &[u8]) -> io::Result<CString> {
+        CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    impl DirHandle for UnixDirHandle {
+        fn create_file(&self, name: &str) -> io::Result<File> {
+            let name = c_string(name.as_bytes())?;
+            let flags =
+                libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+            let fd = unsafe { libc::openat(self.0.as_raw_fd(), name.as_ptr(), flags, 0o666) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(unsafe { File::from_raw_fd(fd) })
+        }
+
+        fn symlink(&self, name: &str, dest: &Path) -> io::Result<()> {
+            let name = c_string(name.as_bytes())?;
+            let dest = c_string(dest.as_os_str().as_bytes())?;
+            let ret = unsafe { libc::symlinkat(dest.as_ptr(), self.0.as_raw_fd(), name.as_ptr()) };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+}
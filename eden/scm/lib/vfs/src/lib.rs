@@ -6,13 +6,30 @@
  */
 
 mod async_vfs;
+mod dirhandle;
 mod pathauditor;
 mod vfs;
+mod winpath;
+mod xattr;
 
 pub use util::lock::PathLock;
 
 pub use crate::async_vfs::AsyncVfsWriter;
+pub use crate::async_vfs::BatchFailure;
+pub use crate::async_vfs::VfsExecutor;
+pub use crate::dirhandle::DirHandle;
+pub use crate::dirhandle::DirOpener;
 pub use crate::pathauditor::AuditError;
 pub use crate::pathauditor::PathAuditor;
+pub use crate::vfs::DirWriter;
+pub use crate::vfs::FileModePolicy;
 pub use crate::vfs::UpdateFlag;
 pub use crate::vfs::VFS;
+pub use crate::winpath::extended_length_path;
+pub use crate::winpath::has_windows_name_issue;
+pub use crate::winpath::windows_name_issue;
+pub use crate::winpath::WindowsNameIssue;
+pub use crate::winpath::WINDOWS_MAX_PATH;
+pub use crate::xattr::SavedXattrs;
+pub use crate::xattr::PRESERVED_XATTRS;
+pub use crate::xattr::XATTRS_SUPPORTED;
@@ -16,6 +16,9 @@ use once_cell::sync::Lazy;
 use types::RepoPath;
 use types::RepoPathBuf;
 
+use crate::winpath::extended_length_path;
+use crate::winpath::has_windows_name_issue;
+
 /// Audit repositories path to make sure that it is safe to write/remove through them.
 ///
 /// This uses caching internally to avoid the heavy cost of querying the OS for each directory in
@@ -25,6 +28,7 @@ use types::RepoPathBuf;
 pub struct PathAuditor {
     root: PathBuf,
     audited: DashMap<RepoPathBuf, ()>,
+    allow_reserved_names: bool,
 }
 
 #[cfg(not(windows))]
@@ -55,14 +59,37 @@ impl PathAuditor {
     pub fn new(root: impl AsRef<Path>) -> Self {
         let audited = Default::default();
         let root = root.as_ref().to_owned();
-        Self { root, audited }
+        Self {
+            root,
+            audited,
+            allow_reserved_names: false,
+        }
+    }
+
+    /// Allow names Windows mishandles, like `aux.c` or `foo.`, and access
+    /// them through extended-length paths. See `WindowsNameIssue`.
+    pub fn with_reserved_names(mut self, allow: bool) -> Self {
+        self.allow_reserved_names = allow;
+        self
+    }
+
+    pub fn allows_reserved_names(&self) -> bool {
+        self.allow_reserved_names
+    }
+
+    /// The path of `path` on disk, without auditing it. On Windows, it is in
+    /// the extended-length form if it is too long, or if reserved names are
+    /// allowed and it contains one.
+    pub fn full_path(&self, path: &RepoPath) -> PathBuf {
+        let force = self.allow_reserved_names && has_windows_name_issue(path);
+        extended_length_path(self.root.join(path.as_str()), force)
     }
 
     /// Slow path, query the filesystem for unsupported path. Namely, writing through a symlink
     /// outside of the repo is not supported.
     /// XXX: more checks
     fn audit_fs(&self, path: &RepoPath) -> Result<(), AuditError> {
-        let full_path = self.root.join(path.as_str());
+        let full_path = self.full_path(path);
 
         // XXX: Maybe filter by specific errors?
         if let Ok(metadata) = symlink_metadata(&full_path) {
@@ -76,46 +103,58 @@ impl PathAuditor {
 
     /// Make sure that it is safe to write/remove `path` from the repo.
     pub fn audit(&self, path: &RepoPath) -> Result<PathBuf> {
-        audit_invalid_components(path.as_str())
+        audit_invalid_components(path.as_str(), self.allow_reserved_names)
             .with_context(|| format!("Invalid component in \"{}\"", path))?;
 
-        let mut needs_recording_index = std::usize::MAX;
-        for (i, parent) in path.reverse_parents().enumerate() {
+        self.audit_dirs(path.reverse_parents())?;
+        Ok(self.full_path(path))
+    }
+
+    /// Same as `audit`, for writing or removing in the directory `dir`: `dir`
+    /// itself must not be a symlink either. Files in `dir` can be written
+    /// after checking their name with `audit_name`.
+    pub fn audit_dir(&self, dir: &RepoPath) -> Result<PathBuf> {
+        if !dir.is_empty() {
+            audit_invalid_components(dir.as_str(), self.allow_reserved_names)
+                .with_context(|| format!("Invalid component in \"{}\"", dir))?;
+        }
+        self.audit_dirs(std::iter::once(dir).chain(dir.reverse_parents()))?;
+        Ok(self.full_path(dir))
+    }
+
+    /// Checks a file name in a directory audited by `audit_dir`.
+    pub fn audit_name(&self, name: &str) -> Result<(), AuditError> {
+        audit_invalid_components(name, self.allow_reserved_names)
+    }
+
+    /// Checks `dirs`, deepest first, stopping at the first one that was
+    /// already audited.
+    fn audit_dirs<'a>(&self, dirs: impl Iterator<Item = &'a RepoPath>) -> Result<()> {
+        let mut unaudited = Vec::new();
+        for dir in dirs {
             // First fast check w/ read lock
-            if !self.audited.contains_key(parent) {
-                // If fast check failed, do the stat syscall.
-                self.audit_fs(parent)
-                    .with_context(|| format!("Can't audit path \"{}\"", parent))?;
-
-                // If it passes the audit, we can't record them as audited just yet, since a parent
-                // may still fail the audit. Later we'll loop through and record successful audits.
-                needs_recording_index = i;
-            } else {
-                // path.parents() yields the results in deepest-first order, so if we hit a path
-                // that has been audited, we know all the future ones have been audited and we can
-                // bail early.
+            if self.audited.contains_key(dir) {
+                // The directories are yielded deepest-first, so if we hit one that has been
+                // audited, we know all the next ones have been audited and we can bail early.
                 break;
             }
+            // If fast check failed, do the stat syscall.
+            self.audit_fs(dir)
+                .with_context(|| format!("Can't audit path \"{}\"", dir))?;
+            // If it passes the audit, we can't record it as audited just yet, since a parent may
+            // still fail the audit.
+            unaudited.push(dir);
         }
-
-        if needs_recording_index != std::usize::MAX {
-            for (i, parent) in path.reverse_parents().enumerate() {
-                self.audited.entry(parent.to_owned()).or_default();
-                if needs_recording_index == i {
-                    break;
-                }
-            }
+        for dir in unaudited {
+            self.audited.entry(dir.to_owned()).or_default();
         }
-
-        let mut filepath = self.root.to_owned();
-        filepath.push(path.as_str());
-        Ok(filepath)
+        Ok(())
     }
 }
 
 /// Checks that shortnames (e.g. `SL~1`) are not a component on Windows and that files don't end in
-/// a dot (e.g. `sigh....`)
-fn valid_windows_component(component: &str) -> bool {
+/// a dot (e.g. `sigh....`), unless `allow_trailing_dot` is set.
+fn valid_windows_component(component: &str, allow_trailing_dot: bool) -> bool {
     if cfg!(not(windows)) {
         return true;
     }
@@ -124,7 +163,7 @@ fn valid_windows_component(component: &str) -> bool {
             return false;
         }
     }
-    !component.ends_with('.')
+    allow_trailing_dot || !component.ends_with('.')
 }
 
 /// Makes sure that the path does not contain any of the following components:
@@ -133,15 +172,18 @@ fn valid_windows_component(component: &str) -> bool {
 /// - `..`, double dot, unix parent directory
 /// - `.sl` or `.hg`,
 /// It also checks that no trailing dots are part of the component and checks that shortnames
-/// on Windows are valid.
-fn audit_invalid_components(path: &str) -> Result<(), AuditError> {
+/// on Windows are valid. Trailing dots are allowed with `allow_reserved_names`.
+fn audit_invalid_components(path: &str, allow_reserved_names: bool) -> Result<(), AuditError> {
     let path = if cfg!(not(windows)) {
         path.to_owned()
     } else {
         path.to_lowercase()
     };
     for s in path.split(SEPARATORS) {
-        if s.is_empty() || INVALID_COMPONENTS.contains(&s) || !valid_windows_component(s) {
+        if s.is_empty()
+            || INVALID_COMPONENTS.contains(&s)
+            || !valid_windows_component(s, allow_reserved_names)
+        {
             return Err(AuditError::InvalidComponent(s.to_owned()));
         }
     }
@@ -175,10 +217,10 @@ mod tests {
 
     #[test]
     fn test_audit_invalid_components() -> Result<()> {
-        assert!(audit_invalid_components("a/../b").is_err());
-        assert!(audit_invalid_components("a/./b").is_err());
-        assert!(audit_invalid_components("a/.sl/b").is_err());
-        assert!(audit_invalid_components("a/.hg/b").is_err());
+        assert!(audit_invalid_components("a/../b", false).is_err());
+        assert!(audit_invalid_components("a/./b", false).is_err());
+        assert!(audit_invalid_components("a/.sl/b", false).is_err());
+        assert!(audit_invalid_components("a/.hg/b", false).is_err());
         Ok(())
     }
 
@@ -222,6 +264,32 @@ mod tests {
         Ok(())
     }
 
+    #[cfg(not(windows))]
+    #[test]
+    fn test_audit_dir() -> Result<()> {
+        let root = TempDir::new()?;
+        let other = TempDir::new()?;
+
+        let auditor = PathAuditor::new(&root);
+        std::os::unix::fs::symlink(&other, root.as_ref().join("a"))?;
+        create_dir_all(root.as_ref().join("b"))?;
+
+        // Unlike `audit`, the directory itself is checked.
+        let dir = RepoPath::from_str("a")?;
+        assert!(auditor.audit(dir).is_ok());
+        assert!(auditor.audit_dir(dir).is_err());
+        assert!(auditor.audit_dir(RepoPath::from_str("a/c")?).is_err());
+        assert!(auditor.audit_dir(RepoPath::from_str("b/.hg")?).is_err());
+        assert_eq!(
+            auditor.audit_dir(RepoPath::from_str("b")?)?,
+            root.as_ref().join("b")
+        );
+        assert!(auditor.audit_name("c").is_ok());
+        assert!(auditor.audit_name("..").is_err());
+
+        Ok(())
+    }
+
     #[cfg(not(windows))]
     #[test]
     fn test_audit_caching() -> Result<()> {
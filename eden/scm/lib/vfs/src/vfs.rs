@@ -34,9 +34,14 @@ use fsinfo::fstype;
 use fsinfo::FsType;
 use minibytes::Bytes;
 use types::RepoPath;
+use types::RepoPathBuf;
 use util::path::remove_file;
 
+use crate::dirhandle::platform_dir_opener;
+use crate::dirhandle::DirHandle;
+use crate::dirhandle::DirOpener;
 use crate::pathauditor::PathAuditor;
+use crate::xattr::SavedXattrs;
 
 #[derive(Clone)]
 pub struct VFS {
@@ -49,6 +54,8 @@ struct Inner {
     supports_symlinks: bool,
     supports_executables: bool,
     case_sensitive: bool,
+    dir_opener: Option<Arc<dyn DirOpener>>,
+    file_mode_policy: FileModePolicy,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -58,6 +65,58 @@ pub enum UpdateFlag {
     Executable,
 }
 
+/// How the permissions of written files are chosen, see
+/// `VFS::with_file_mode_policy`. Only the permission bits are set. On
+/// Windows, this only decides the read-only attribute, see `Fixed`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FileModePolicy {
+    /// 0o666, or 0o777 for executables, masked by the umask of the process.
+    HonorUmask,
+    /// These modes, whatever the umask. On Windows, files without the owner
+    /// write bit are made read-only.
+    Fixed { regular: u32, executable: u32 },
+    /// Overwritten files keep their mode, but for the executable bits, which
+    /// follow the file type where the file is readable. New files are
+    /// written as with `HonorUmask`.
+    #[default]
+    PreserveExisting,
+}
+
+impl FileModePolicy {
+    /// The mode to create files with. The umask still applies, so the mode
+    /// from `mode` is set afterwards.
+    #[cfg(unix)]
+    fn create_mode(&self, exec: bool) -> u32 {
+        match (self, exec) {
+            (FileModePolicy::Fixed { regular, .. }, false) => *regular,
+            (FileModePolicy::Fixed { executable, .. }, true) => *executable,
+            (_, false) => 0o666,
+            (_, true) => 0o777,
+        }
+    }
+
+    /// The mode of a file written with `exec`, which has mode `current`:
+    /// the mode of the file it overwrites, or the one it was created with.
+    #[cfg(unix)]
+    fn mode(&self, current: u32, exec: bool) -> u32 {
+        match self {
+            FileModePolicy::HonorUmask => util::file::apply_umask(self.create_mode(exec)),
+            FileModePolicy::Fixed { .. } => self.create_mode(exec),
+            FileModePolicy::PreserveExisting => VFS::update_mode(current & 0o7777, exec),
+        }
+    }
+
+    /// Whether files written with `exec` are made read-only.
+    #[cfg(windows)]
+    fn readonly(&self, exec: bool) -> bool {
+        match (self, exec) {
+            (FileModePolicy::Fixed { regular, .. }, false) => regular & 0o200 == 0,
+            (FileModePolicy::Fixed { executable, .. }, true) => executable & 0o200 == 0,
+            _ => false,
+        }
+    }
+}
+
 impl VFS {
     pub fn new(root: PathBuf) -> Result<Self> {
         let auditor = PathAuditor::new(&root);
@@ -74,10 +133,75 @@ impl VFS {
                 supports_symlinks,
                 supports_executables,
                 case_sensitive,
+                dir_opener: platform_dir_opener(),
+                file_mode_policy: FileModePolicy::default(),
             }),
         })
     }
 
+    /// Allow writing names Windows mishandles, like `aux.c` or `foo.`,
+    /// through extended-length paths, where the filesystem permits. Without
+    /// this, trailing dots are rejected on Windows, and reserved names are
+    /// passed to the classic Win32 APIs.
+    pub fn with_reserved_names(self, allow: bool) -> Self {
+        let inner = &self.inner;
+        Self {
+            inner: Arc::new(Inner {
+                root: inner.root.clone(),
+                auditor: PathAuditor::new(&inner.root).with_reserved_names(allow),
+                supports_symlinks: inner.supports_symlinks,
+                supports_executables: inner.supports_executables,
+                case_sensitive: inner.case_sensitive,
+                dir_opener: inner.dir_opener.clone(),
+                file_mode_policy: inner.file_mode_policy,
+            }),
+        }
+    }
+
+    /// Overrides how `open_dir` opens directories. With `None`, the files of
+    /// a directory are written by path, like `write` does.
+    pub fn with_dir_opener(self, dir_opener: Option<Arc<dyn DirOpener>>) -> Self {
+        let inner = &self.inner;
+        Self {
+            inner: Arc::new(Inner {
+                root: inner.root.clone(),
+                auditor: PathAuditor::new(&inner.root)
+                    .with_reserved_names(inner.auditor.allows_reserved_names()),
+                supports_symlinks: inner.supports_symlinks,
+                supports_executables: inner.supports_executables,
+                case_sensitive: inner.case_sensitive,
+                dir_opener,
+                file_mode_policy: inner.file_mode_policy,
+            }),
+        }
+    }
+
+    /// Overrides how the permissions of written files are chosen. The
+    /// default is `FileModePolicy::PreserveExisting`.
+    pub fn with_file_mode_policy(self, file_mode_policy: FileModePolicy) -> Self {
+        // Read the umask now, as reading it briefly clears it, which files
+        // written concurrently would be affected by.
+        #[cfg(unix)]
+        util::file::apply_umask(0);
+        let inner = &self.inner;
+        Self {
+            inner: Arc::new(Inner {
+                root: inner.root.clone(),
+                auditor: PathAuditor::new(&inner.root)
+                    .with_reserved_names(inner.auditor.allows_reserved_names()),
+                supports_symlinks: inner.supports_symlinks,
+                supports_executables: inner.supports_executables,
+                case_sensitive: inner.case_sensitive,
+                dir_opener: inner.dir_opener.clone(),
+                file_mode_policy,
+            }),
+        }
+    }
+
+    pub fn file_mode_policy(&self) -> FileModePolicy {
+        self.inner.file_mode_policy
+    }
+
     pub fn root(&self) -> &Path {
         &self.inner.root
     }
@@ -90,10 +214,17 @@ impl VFS {
         self.inner.root.join(path.as_str())
     }
 
+    /// Same as `join`, for passing to filesystem calls. On Windows, long
+    /// paths, and paths with reserved names when they are allowed, are in
+    /// the extended-length form, which other tools may not accept.
+    pub(crate) fn fs_path(&self, path: &RepoPath) -> PathBuf {
+        self.inner.auditor.full_path(path)
+    }
+
     pub fn metadata(&self, path: &RepoPath) -> Result<Metadata> {
         tracing::trace!(?path, "fetching metadata");
 
-        self.join(path).symlink_metadata().map_err(|e| {
+        self.fs_path(path).symlink_metadata().map_err(|e| {
             // If `path` contains a directory that doesn't actually exist on disk, it surfaces as a
             // NotADirectory error. This error type is unstable and can't actually be matched on.
             // See https://github.com/rust-lang/rust/issues/86442
@@ -121,14 +252,13 @@ impl VFS {
     ///
     /// This is a slow operation, and should not be called before attempting to create `path`.
     fn clear_conflicts(&self, repo_path: &RepoPath) -> Result<()> {
-        let full_path = self.join(repo_path);
+        let full_path = self.fs_path(repo_path);
 
         // Walk down our ancestors, removing the first regular file or symlink
         // we find. We have the invariant that path_buf contains no symlinks
         // since we remove the top most symlink we come across.
-        let mut path_buf = self.inner.root.clone();
-        for part in repo_path.components() {
-            path_buf.push(part.as_str());
+        for prefix in repo_path.parents().skip(1).chain(Some(repo_path)) {
+            let path_buf = self.fs_path(prefix);
 
             let metadata = match symlink_metadata(&path_buf) {
                 Ok(metadata) => metadata,
@@ -157,12 +287,34 @@ impl VFS {
         Ok(())
     }
 
-    fn write_mode(
-        &self,
-        filepath: &Path,
-        content: &[u8],
-        #[allow(unused_variables)] exec: bool,
-    ) -> Result<usize> {
+    /// Same as `clear_conflicts`, to create the directory `dir`. A directory
+    /// at `dir` is kept.
+    fn clear_dir_conflicts(&self, dir: &RepoPath) -> Result<()> {
+        for prefix in dir.parents().skip(1).chain(Some(dir)) {
+            let path_buf = self.fs_path(prefix);
+
+            let metadata = match symlink_metadata(&path_buf) {
+                Ok(metadata) => metadata,
+                Err(err) if err.kind() == ErrorKind::NotFound => break,
+                Err(err) => bail!("error lstating {:?} in clear_conflicts: {}", path_buf, err),
+            };
+
+            let file_type = metadata.file_type();
+            if file_type.is_file() || file_type.is_symlink() {
+                remove_file(&path_buf)
+                    .with_context(|| format!("Can't remove file {:?}", path_buf))?;
+                break;
+            }
+        }
+
+        let dir = self.fs_path(dir);
+        create_dir_all(&dir).with_context(|| format!("Can't create directory {:?}", dir))?;
+
+        Ok(())
+    }
+
+    fn write_mode(&self, filepath: &Path, content: &[u8], exec: bool) -> Result<usize> {
+        let policy = self.inner.file_mode_policy;
         let mut options = OpenOptions::new();
         options.write(true).create(true).truncate(true);
 
@@ -170,15 +322,37 @@ impl VFS {
         {
             use std::os::unix::fs::OpenOptionsExt;
             options.custom_flags(libc::O_NOFOLLOW);
+            options.mode(policy.create_mode(exec));
         }
 
-        let mut f = options.open(filepath)?;
+        #[cfg(windows)]
+        let f = match options.open(filepath) {
+            // Files made read-only by `FileModePolicy::Fixed` are overwritten
+            // all the same.
+            Err(e) if e.kind() == ErrorKind::PermissionDenied && clear_readonly(filepath)? => {
+                options.open(filepath)?
+            }
+            result => result?,
+        };
+        #[cfg(unix)]
+        let f = options.open(filepath)?;
+        Self::write_open_file(f, filepath, content, exec, policy)
+    }
 
+    /// The part of `write_mode` after opening the file. The permissions are
+    /// set through the open file, not by path.
+    fn write_open_file(
+        mut f: File,
+        filepath: &Path,
+        content: &[u8],
+        #[allow(unused_variables)] exec: bool,
+        #[allow(unused_variables)] policy: FileModePolicy,
+    ) -> Result<usize> {
         #[cfg(unix)]
         {
             let metadata = f.metadata()?;
             let mut permissions = metadata.permissions();
-            let mode = Self::update_mode(permissions.mode(), exec);
+            let mode = policy.mode(permissions.mode(), exec);
             permissions.set_mode(mode);
             f.set_permissions(permissions)
                 .with_context(|| format!("Failed to set permissions on {:?}", filepath))?;
@@ -186,6 +360,14 @@ impl VFS {
 
         f.write_all(content)
             .with_context(|| format!("Can't write to {:?}", filepath))?;
+
+        #[cfg(windows)]
+        if policy.readonly(exec) {
+            let mut permissions = f.metadata()?.permissions();
+            permissions.set_readonly(true);
+            f.set_permissions(permissions)
+                .with_context(|| format!("Failed to set permissions on {:?}", filepath))?;
+        }
         Ok(content.len())
     }
 
@@ -205,7 +387,11 @@ impl VFS {
 
     #[cfg(unix)]
     fn set_exec(&self, filepath: &Path, flag: bool) -> Result<()> {
-        let mode = if flag { 0o755 } else { 0o644 };
+        let current = symlink_metadata(filepath)
+            .with_context(|| format!("Can't stat {:?} to update its exec flag", filepath))?
+            .permissions()
+            .mode();
+        let mode = self.inner.file_mode_policy.mode(current, flag);
         let perms = Permissions::from_mode(mode);
         set_permissions(filepath, perms)
             .with_context(|| format!("Can't update exec flag({}) on {:?}", flag, filepath))?;
@@ -284,6 +470,120 @@ impl VFS {
         }
     }
 
+    /// Prepare writing files in the directory `dir`: audit it, and create it
+    /// if needed, once for all of them. The files are then written relative
+    /// to the open directory if the platform supports it, see
+    /// `with_dir_opener`.
+    pub fn open_dir(&self, dir: &RepoPath) -> Result<DirWriter<'_>> {
+        let dirpath = match self.existing_dir(dir) {
+            Ok(dirpath) => dirpath,
+            Err(e) => {
+                self.clear_dir_conflicts(dir).with_context(|| {
+                    format!("Can't clear conflicts after handling error \"{:?}\"", e)
+                })?;
+                self.existing_dir(dir)?
+            }
+        };
+        let handle = match &self.inner.dir_opener {
+            Some(opener) => match opener.open_dir(&dirpath) {
+                Ok(handle) => Some(handle),
+                Err(e) => {
+                    tracing::debug!(?dirpath, "writing files by path: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+        Ok(DirWriter {
+            vfs: self,
+            dir: dir.to_owned(),
+            handle,
+        })
+    }
+
+    /// Audits `dir`, and checks that it is a directory.
+    fn existing_dir(&self, dir: &RepoPath) -> Result<PathBuf> {
+        let dirpath = self
+            .inner
+            .auditor
+            .audit_dir(dir)
+            .with_context(|| format!("Can't write into {}", dir))?;
+        ensure!(
+            symlink_metadata(&dirpath)?.is_dir(),
+            "{:?} is not a directory",
+            dirpath
+        );
+        Ok(dirpath)
+    }
+
+    pub fn supports_dir_handles(&self) -> bool {
+        self.inner.dir_opener.is_some()
+    }
+
+    /// Move the file at `source`, which is outside of the working copy, to
+    /// `path`, as if `write` wrote its content there. Falls back to copying
+    /// when it can't be renamed, e.g. across filesystems. `source` is gone
+    /// afterwards either way.
+    pub fn rename_into(&self, path: &RepoPath, source: &Path, flag: UpdateFlag) -> Result<usize> {
+        if let UpdateFlag::Symlink = flag {
+            return self.copy_into(path, source, flag);
+        }
+        let metadata = fs::metadata(source)
+            .with_context(|| format!("Can't stat {:?} to rename it", source))?;
+        // Same as `write`: conflicts, like a symlinked ancestor failing the
+        // audit, are only cleared if the fast path fails.
+        let renamed = self
+            .rename_inner(path, source, &metadata, flag)
+            .or_else(|e| {
+                self.clear_conflicts(path).with_context(|| {
+                    format!("Can't clear conflicts after handling error \"{:?}\"", e)
+                })?;
+                self.rename_inner(path, source, &metadata, flag)
+            });
+        match renamed {
+            Ok(()) => Ok(metadata.len() as usize),
+            Err(_) => self.copy_into(path, source, flag),
+        }
+    }
+
+    fn rename_inner(
+        &self,
+        path: &RepoPath,
+        source: &Path,
+        #[allow(unused_variables)] metadata: &Metadata,
+        #[allow(unused_variables)] flag: UpdateFlag,
+    ) -> Result<()> {
+        let filepath = self
+            .inner
+            .auditor
+            .audit(path)
+            .with_context(|| format!("Can't write into {}", path))?;
+
+        #[cfg(unix)]
+        {
+            let mut permissions = metadata.permissions();
+            let exec = matches!(flag, UpdateFlag::Executable);
+            // The mode of the file overwritten, for `PreserveExisting`.
+            let current = match symlink_metadata(&filepath) {
+                Ok(existing) if existing.is_file() => existing.permissions().mode(),
+                _ => permissions.mode(),
+            };
+            permissions.set_mode(self.inner.file_mode_policy.mode(current, exec));
+            set_permissions(source, permissions)
+                .with_context(|| format!("Failed to set permissions on {:?}", source))?;
+        }
+
+        fs::rename(source, &filepath)
+            .with_context(|| format!("Can't rename {:?} to {:?}", source, filepath))
+    }
+
+    fn copy_into(&self, path: &RepoPath, source: &Path, flag: UpdateFlag) -> Result<usize> {
+        let content = fs::read(source).with_context(|| format!("Can't read {:?}", source))?;
+        let written = self.write(path, &content, flag)?;
+        remove_file(source).with_context(|| format!("Can't remove {:?}", source))?;
+        Ok(written)
+    }
+
     pub fn set_executable(&self, path: &RepoPath, flag: bool) -> Result<()> {
         let filepath = self
             .inner
@@ -294,23 +594,33 @@ impl VFS {
         self.set_exec(&filepath, flag)
     }
 
+    /// Read the `PRESERVED_XATTRS` of the file at `path`, to restore them
+    /// with `restore_xattrs` after rewriting it. A missing file has none.
+    /// Only supported on Linux, see `XATTRS_SUPPORTED`.
+    pub fn save_xattrs(&self, path: &RepoPath) -> Result<SavedXattrs> {
+        let filepath = self.inner.auditor.audit(path)?;
+        crate::xattr::save(&filepath)
+    }
+
+    /// Set the attributes read by `save_xattrs` on the file at `path`.
+    pub fn restore_xattrs(&self, path: &RepoPath, saved: &SavedXattrs) -> Result<()> {
+        let filepath = self.inner.auditor.audit(path)?;
+        crate::xattr::restore(&filepath, saved)
+    }
+
     /// Remove the file at `path`.
     ///
     /// If file does not exist, returns without an error
     ///
     /// The parent directories of this file will be removed recursively if they are empty.
     pub fn remove(&self, path: &RepoPath) -> Result<()> {
-        let mut filepath = self.inner.auditor.audit(path)?;
+        let filepath = self.inner.auditor.audit(path)?;
         self.remove_keep_path(&filepath)?;
 
         // Mercurial doesn't track empty directories, remove them
         // recursively.
-        loop {
-            if !filepath.pop() || filepath == self.inner.root {
-                break;
-            }
-
-            if remove_dir(&filepath).is_err() {
+        for parent in path.reverse_parents() {
+            if parent.is_empty() || remove_dir(self.fs_path(parent)).is_err() {
                 break;
             }
         }
@@ -361,6 +671,79 @@ impl VFS {
     }
 }
 
+/// Writes the files of a directory, see `VFS::open_dir`.
+pub struct DirWriter<'a> {
+    vfs: &'a VFS,
+    dir: RepoPathBuf,
+    /// `None` if the files are written by path.
+    handle: Option<Box<dyn DirHandle>>,
+}
+
+impl DirWriter<'_> {
+    /// Same as `VFS::write`. Paths outside of the directory are written by
+    /// path.
+    pub fn write(&self, path: &RepoPath, data: &[u8], flag: UpdateFlag) -> Result<usize> {
+        let (handle, name) = match (&self.handle, path.split_last_component()) {
+            (Some(handle), Some((dir, name))) if dir == self.dir.as_repo_path() => {
+                (handle, name.as_str())
+            }
+            _ => return self.vfs.write(path, data, flag),
+        };
+        // The directory was audited when it was opened.
+        self.vfs
+            .inner
+            .auditor
+            .audit_name(name)
+            .with_context(|| format!("Can't write into {}", path))?;
+        match self.write_in_dir(handle.as_ref(), name, path, data, flag) {
+            // Conflicts, like a directory or a symlink in the way, are cleared
+            // by `write`. Other errors would most likely happen again.
+            Err(e) if is_conflict(&e) => self.vfs.write(path, data, flag),
+            result => result,
+        }
+    }
+
+    fn write_in_dir(
+        &self,
+        handle: &dyn DirHandle,
+        name: &str,
+        path: &RepoPath,
+        data: &[u8],
+        flag: UpdateFlag,
+    ) -> Result<usize> {
+        let filepath = self.vfs.fs_path(path);
+        match flag {
+            UpdateFlag::Regular | UpdateFlag::Executable => {
+                let exec = matches!(flag, UpdateFlag::Executable);
+                let file = handle.create_file(name)?;
+                let policy = self.vfs.inner.file_mode_policy;
+                VFS::write_open_file(file, &filepath, data, exec, policy)
+            }
+            UpdateFlag::Symlink if cfg!(unix) && self.vfs.supports_symlinks() => {
+                handle.symlink(name, Path::new(std::str::from_utf8(data)?))?;
+                // Same as `write_symlink`.
+                Ok(filepath.as_os_str().len())
+            }
+            UpdateFlag::Symlink => self.vfs.write(path, data, flag),
+        }
+    }
+}
+
+/// Whether `err` was caused by something in the way of the file written by
+/// `DirWriter`: a directory, a symlink, or a file where a directory was
+/// expected.
+fn is_conflict(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| match e.raw_os_error() {
+            #[cfg(unix)]
+            Some(libc::ELOOP) | Some(libc::EISDIR) | Some(libc::ENOTDIR) | Some(libc::EEXIST) => {
+                true
+            }
+            _ => false,
+        })
+}
+
 #[cfg(unix)]
 #[cfg(test)]
 mod unix_tests {
@@ -419,6 +802,134 @@ mod unix_tests {
         assert_eq!(0, metadata.permissions().mode() & 0o111)
     }
 
+    #[test]
+    fn test_rename_into() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir(tmp.path().join("wc")).unwrap();
+        let vfs = VFS::new(tmp.path().join("wc")).unwrap();
+        let staged = tmp.path().join("staged");
+
+        // Replaces a symlinked ancestor, and sets the exec bit.
+        vfs.write(RepoPath::from_str("a").unwrap(), b"x", UpdateFlag::Symlink)
+            .unwrap();
+        let path = RepoPath::from_str("a/b").unwrap();
+        fs::write(&staged, b"abc").unwrap();
+        let written = vfs.rename_into(path, &staged, UpdateFlag::Executable);
+        assert_eq!(written.unwrap(), 3);
+        assert!(!staged.exists());
+        assert_eq!(vfs.read(path).unwrap(), b"abc");
+        let metadata = fs::symlink_metadata(vfs.join(path)).unwrap();
+        assert_eq!(0o111, metadata.permissions().mode() & 0o111);
+
+        fs::write(&staged, b"target").unwrap();
+        vfs.rename_into(path, &staged, UpdateFlag::Symlink).unwrap();
+        assert!(!staged.exists());
+        assert_eq!(vfs.read(path).unwrap(), b"target");
+    }
+
+    #[test]
+    fn test_open_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir(tmp.path().join("wc")).unwrap();
+        let outside = tmp.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        let vfs = VFS::new(tmp.path().join("wc")).unwrap();
+        assert!(vfs.supports_dir_handles());
+
+        // A symlinked directory is replaced rather than written through.
+        std::os::unix::fs::symlink(&outside, vfs.join(RepoPath::from_str("a").unwrap())).unwrap();
+        let dir = vfs.open_dir(RepoPath::from_str("a/b").unwrap()).unwrap();
+        assert!(dir.handle.is_some());
+
+        let file = RepoPath::from_str("a/b/file").unwrap();
+        let exec = RepoPath::from_str("a/b/exec").unwrap();
+        let link = RepoPath::from_str("a/b/link").unwrap();
+        let conflict = RepoPath::from_str("a/b/conflict").unwrap();
+        fs::create_dir_all(vfs.join(conflict).join("x")).unwrap();
+        assert_eq!(dir.write(file, b"abc", UpdateFlag::Regular).unwrap(), 3);
+        assert_eq!(dir.write(exec, b"abcd", UpdateFlag::Executable).unwrap(), 4);
+        assert_eq!(
+            dir.write(link, b"file", UpdateFlag::Symlink).unwrap(),
+            vfs.join(link).as_os_str().len()
+        );
+        // Replaced by the slow path.
+        dir.write(link, b"exec", UpdateFlag::Symlink).unwrap();
+        dir.write(conflict, b"x", UpdateFlag::Regular).unwrap();
+        assert!(dir
+            .write(
+                RepoPath::from_str("a/b/.hg").unwrap(),
+                b"",
+                UpdateFlag::Regular
+            )
+            .is_err());
+
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+        assert_eq!(vfs.read(file).unwrap(), b"abc");
+        assert_eq!(vfs.read(link).unwrap(), b"exec");
+        assert_eq!(vfs.read(conflict).unwrap(), b"x");
+        let mode = |path| {
+            fs::symlink_metadata(vfs.join(path))
+                .unwrap()
+                .permissions()
+                .mode()
+        };
+        assert_eq!(mode(file) & 0o111, 0);
+        assert_eq!(mode(exec) & 0o111, 0o111);
+
+        // Without a `DirOpener`, files are written by path.
+        let vfs = vfs.with_dir_opener(None);
+        assert!(!vfs.supports_dir_handles());
+        let dir = vfs.open_dir(RepoPath::from_str("c").unwrap()).unwrap();
+        assert!(dir.handle.is_none());
+        let file = RepoPath::from_str("c/file").unwrap();
+        dir.write(file, b"abc", UpdateFlag::Regular).unwrap();
+        assert_eq!(vfs.read(file).unwrap(), b"abc");
+    }
+
+    /// Fails to create files as if the disk was full.
+    struct FullDisk;
+
+    impl DirOpener for FullDisk {
+        fn open_dir(&self, _path: &Path) -> io::Result<Box<dyn DirHandle>> {
+            Ok(Box::new(FullDisk))
+        }
+    }
+
+    impl DirHandle for FullDisk {
+        fn create_file(&self, _name: &str) -> io::Result<File> {
+            Err(io::Error::from_raw_os_error(libc::ENOSPC))
+        }
+
+        fn symlink(&self, _name: &str, _dest: &Path) -> io::Result<()> {
+            Err(io::Error::from_raw_os_error(libc::ENOSPC))
+        }
+    }
+
+    #[test]
+    fn test_open_dir_disk_full() {
+        let tmp = tempfile::tempdir().unwrap();
+        let vfs = VFS::new(tmp.path().to_path_buf())
+            .unwrap()
+            .with_dir_opener(Some(Arc::new(FullDisk)));
+        let dir = vfs.open_dir(RepoPath::from_str("a").unwrap()).unwrap();
+        let file = RepoPath::from_str("a/file").unwrap();
+
+        // Not retried by path.
+        let err = dir.write(file, b"abc", UpdateFlag::Regular).unwrap_err();
+        assert!(!is_conflict(&err));
+        let raw = err
+            .root_cause()
+            .downcast_ref::<io::Error>()
+            .unwrap()
+            .raw_os_error();
+        assert_eq!(raw, Some(libc::ENOSPC));
+        assert!(!vfs.join(file).exists());
+    }
+
     #[test]
     fn test_update_mode() {
         assert_eq!(0o644, VFS::update_mode(0o644, false));
@@ -427,6 +938,111 @@ mod unix_tests {
         assert_eq!(0o755, VFS::update_mode(0o644, true));
         assert_eq!(0o644, VFS::update_mode(0o755, false));
     }
+
+    fn file_mode(vfs: &VFS, path: &RepoPath) -> u32 {
+        let metadata = fs::symlink_metadata(vfs.join(path)).unwrap();
+        metadata.permissions().mode() & 0o7777
+    }
+
+    /// Writes `path` with mode `mode`, bypassing the policy.
+    fn write_with_mode(vfs: &VFS, path: &RepoPath, mode: u32) {
+        fs::write(vfs.join(path), b"old").unwrap();
+        fs::set_permissions(vfs.join(path), Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn test_file_mode_honor_umask() {
+        let tmp = tempfile::tempdir().unwrap();
+        let vfs = VFS::new(tmp.path().to_path_buf())
+            .unwrap()
+            .with_file_mode_policy(FileModePolicy::HonorUmask);
+        let regular = util::file::apply_umask(0o666);
+        let executable = util::file::apply_umask(0o777);
+
+        let file = RepoPath::from_str("file").unwrap();
+        let exec = RepoPath::from_str("exec").unwrap();
+        vfs.write(file, b"abc", UpdateFlag::Regular).unwrap();
+        vfs.write(exec, b"abc", UpdateFlag::Executable).unwrap();
+        assert_eq!(file_mode(&vfs, file), regular);
+        assert_eq!(file_mode(&vfs, exec), executable);
+
+        // The mode of overwritten files is not kept.
+        write_with_mode(&vfs, file, 0o600);
+        vfs.write(file, b"abc", UpdateFlag::Regular).unwrap();
+        assert_eq!(file_mode(&vfs, file), regular);
+        vfs.set_executable(file, true).unwrap();
+        assert_eq!(file_mode(&vfs, file), executable);
+    }
+
+    #[test]
+    fn test_file_mode_fixed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let policy = FileModePolicy::Fixed {
+            regular: 0o640,
+            executable: 0o750,
+        };
+        let vfs = VFS::new(tmp.path().to_path_buf())
+            .unwrap()
+            .with_file_mode_policy(policy);
+
+        let file = RepoPath::from_str("file").unwrap();
+        let exec = RepoPath::from_str("exec").unwrap();
+        vfs.write(file, b"abc", UpdateFlag::Regular).unwrap();
+        vfs.write(exec, b"abc", UpdateFlag::Executable).unwrap();
+        assert_eq!(file_mode(&vfs, file), 0o640);
+        assert_eq!(file_mode(&vfs, exec), 0o750);
+
+        write_with_mode(&vfs, file, 0o604);
+        vfs.write(file, b"abc", UpdateFlag::Regular).unwrap();
+        assert_eq!(file_mode(&vfs, file), 0o640);
+        vfs.set_executable(file, true).unwrap();
+        assert_eq!(file_mode(&vfs, file), 0o750);
+        vfs.set_executable(file, false).unwrap();
+        assert_eq!(file_mode(&vfs, file), 0o640);
+
+        // Files written through a directory handle, or staged and renamed,
+        // follow the policy too.
+        let dir = vfs.open_dir(RepoPath::from_str("d/e").unwrap()).unwrap();
+        let in_dir = RepoPath::from_str("d/e/file").unwrap();
+        dir.write(in_dir, b"abc", UpdateFlag::Executable).unwrap();
+        assert_eq!(file_mode(&vfs, in_dir), 0o750);
+        let staged = tmp.path().join("staged");
+        fs::write(&staged, b"abc").unwrap();
+        vfs.rename_into(exec, &staged, UpdateFlag::Regular).unwrap();
+        assert_eq!(file_mode(&vfs, exec), 0o640);
+    }
+
+    #[test]
+    fn test_file_mode_preserve_existing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let vfs = VFS::new(tmp.path().to_path_buf()).unwrap();
+        assert_eq!(vfs.file_mode_policy(), FileModePolicy::PreserveExisting);
+
+        let new = RepoPath::from_str("new").unwrap();
+        vfs.write(new, b"abc", UpdateFlag::Regular).unwrap();
+        assert_eq!(file_mode(&vfs, new), util::file::apply_umask(0o666));
+
+        // The exec bits follow the read bits, and the umask.
+        let file = RepoPath::from_str("file").unwrap();
+        let executable = 0o640 | util::file::apply_umask(0o110);
+        write_with_mode(&vfs, file, 0o640);
+        vfs.write(file, b"abc", UpdateFlag::Executable).unwrap();
+        assert_eq!(file_mode(&vfs, file), executable);
+        vfs.write(file, b"abc", UpdateFlag::Regular).unwrap();
+        assert_eq!(file_mode(&vfs, file), 0o640);
+
+        vfs.set_executable(file, true).unwrap();
+        assert_eq!(file_mode(&vfs, file), executable);
+        vfs.set_executable(file, false).unwrap();
+        assert_eq!(file_mode(&vfs, file), 0o640);
+
+        // Staged files get the mode of the file they replace.
+        let staged = tmp.path().join("staged");
+        fs::write(&staged, b"abc").unwrap();
+        vfs.rename_into(file, &staged, UpdateFlag::Executable)
+            .unwrap();
+        assert_eq!(file_mode(&vfs, file), executable);
+    }
 }
 
 fn supports_symlinks(path: &Path) -> Result<bool> {
@@ -494,6 +1110,24 @@ fn detect_case_sensitive(root: &Path) -> Result<bool> {
     }
 }
 
+/// Clear the read-only attribute of the file at `path`. Returns whether it
+/// was set.
+#[cfg(windows)]
+fn clear_readonly(path: &Path) -> Result<bool> {
+    let mut permissions = match fs::metadata(path) {
+        Ok(metadata) => metadata.permissions(),
+        Err(_) => return Ok(false),
+    };
+    if !permissions.readonly() {
+        return Ok(false);
+    }
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    fs::set_permissions(path, permissions)
+        .with_context(|| format!("Can't make {:?} writable", path))?;
+    Ok(true)
+}
+
 /// Roughly compares metadata, only for internal vfs usage
 /// Do not make this fn public
 fn metadata_eq(m1: &Metadata, m2: &Metadata) -> Result<bool> {
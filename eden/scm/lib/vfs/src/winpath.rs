@@ -0,0 +1,168 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Windows path limitations.
+//!
+//! The classic Win32 file APIs can't handle paths of `MAX_PATH` (260) UTF-16
+//! units or more, and silently rewrite names that are DOS devices (`con`,
+//! `aux.c`) or end with a dot or a space. Paths in the extended-length form
+//! (`\\?\C:\...`) are passed to the filesystem as-is, which lifts both
+//! limitations where the filesystem permits.
+
+use std::path::PathBuf;
+
+use types::RepoPath;
+
+/// Length, in UTF-16 units, of the longest path the classic Win32 APIs
+/// accept, including the terminating NUL.
+pub const WINDOWS_MAX_PATH: usize = 260;
+
+/// Paths at least this long are converted to the extended-length form.
+/// Directories need room for an 8.3 file name below the classic limit.
+const EXTENDED_LENGTH_THRESHOLD: usize = WINDOWS_MAX_PATH - 12;
+
+const RESERVED_NAMES: [&str; 22] = [
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// A file name the classic Win32 APIs don't write as-is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowsNameIssue {
+    /// A DOS device name, optionally with an extension, like `nul` or
+    /// `aux.c`. Writing it writes to the device.
+    ReservedName,
+    /// The name ends with a dot or a space, which is stripped.
+    TrailingDotOrSpace,
+}
+
+/// Returns how Windows mishandles the path component `name`, if it does.
+pub fn windows_name_issue(name: &str) -> Option<WindowsNameIssue> {
+    if name.ends_with('.') || name.ends_with(' ') {
+        // "." and ".." are never repo path components.
+        return Some(WindowsNameIssue::TrailingDotOrSpace);
+    }
+    let stem = name.split('.').next().unwrap_or_default();
+    let stem = stem.trim_end_matches(' ');
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        return Some(WindowsNameIssue::ReservedName);
+    }
+    None
+}
+
+/// Whether any component of `path` is mishandled by Windows.
+pub fn has_windows_name_issue(path: &RepoPath) -> bool {
+    path.components()
+        .any(|c| windows_name_issue(c.as_str()).is_some())
+}
+
+/// On Windows, converts `path` to the extended-length form if it is too
+/// long for the classic Win32 APIs, or if `force` is set. Returns `path`
+/// unchanged on other platforms, or if it is relative or already in that
+/// form.
+pub fn extended_length_path(path: PathBuf, force: bool) -> PathBuf {
+    if cfg!(not(windows)) {
+        return path;
+    }
+    let converted = path.to_str().and_then(|s| {
+        if force || s.encode_utf16().count() >= EXTENDED_LENGTH_THRESHOLD {
+            to_extended_length(s)
+        } else {
+            None
+        }
+    });
+    converted.map_or(path, PathBuf::from)
+}
+
+/// Converts an absolute Windows path to the extended-length form, or returns
+/// `None` if it can't be converted. No normalization happens past the
+/// prefix, so forward slashes are converted here.
+fn to_extended_length(path: &str) -> Option<String> {
+    if path.starts_with(r"\\?\") || path.starts_with(r"\\.\") {
+        return None;
+    }
+    let path = path.replace('/', r"\");
+    if let Some(unc) = path.strip_prefix(r"\\") {
+        return Some(format!(r"\\?\UNC\{}", unc));
+    }
+    let is_drive_path = match path.as_bytes() {
+        [drive, b':', b'\\', ..] => drive.is_ascii_alphabetic(),
+        _ => false,
+    };
+    if is_drive_path {
+        return Some(format!(r"\\?\{}", path));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_windows_name_issue() {
+        for name in [
+            "con",
+            "CON",
+            "aux.c",
+            "con.txt",
+            "nul",
+            "Com1.tar.gz",
+            "lpt9 .x",
+        ] {
+            assert_eq!(
+                windows_name_issue(name),
+                Some(WindowsNameIssue::ReservedName),
+                "{}",
+                name
+            );
+        }
+        for name in ["a.", "a ", "aux.", "b.c.."] {
+            assert_eq!(
+                windows_name_issue(name),
+                Some(WindowsNameIssue::TrailingDotOrSpace),
+                "{}",
+                name
+            );
+        }
+        for name in [
+            "console",
+            "auxiliary.c",
+            "com10",
+            "lpt",
+            "a.con",
+            ".nul",
+            "file.txt",
+        ] {
+            assert_eq!(windows_name_issue(name), None, "{}", name);
+        }
+
+        assert!(has_windows_name_issue(
+            RepoPath::from_str("src/aux/mod.rs").unwrap()
+        ));
+        assert!(!has_windows_name_issue(
+            RepoPath::from_str("src/auxv/mod.rs").unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_to_extended_length() {
+        assert_eq!(
+            to_extended_length(r"C:\repo/a/b").as_deref(),
+            Some(r"\\?\C:\repo\a\b")
+        );
+        assert_eq!(
+            to_extended_length(r"\\server\share\repo/a").as_deref(),
+            Some(r"\\?\UNC\server\share\repo\a")
+        );
+        assert_eq!(to_extended_length(r"\\?\C:\repo\a"), None);
+        assert_eq!(to_extended_length(r"repo\a"), None);
+    }
+}
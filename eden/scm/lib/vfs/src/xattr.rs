@@ -0,0 +1,157 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Extended attributes that do not survive a file being rewritten, saved
+//! and restored by `VFS::save_xattrs` and `VFS::restore_xattrs`.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+/// Extended attributes holding the POSIX access ACL and the file
+/// capabilities set by `setcap`.
+pub const PRESERVED_XATTRS: &[&str] = &["system.posix_acl_access", "security.capability"];
+
+/// Whether extended attributes can be saved and restored on this platform.
+pub const XATTRS_SUPPORTED: bool = cfg!(target_os = "linux");
+
+/// The `PRESERVED_XATTRS` a file had.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SavedXattrs {
+    attrs: Vec<(&'static str, Vec<u8>)>,
+}
+
+impl SavedXattrs {
+    /// Whether the file had none of the attributes, or did not exist.
+    pub fn is_empty(&self) -> bool {
+        self.attrs.is_empty()
+    }
+
+    /// Set attribute `name` to `value`, to be restored.
+    pub fn insert(&mut self, name: &'static str, value: Vec<u8>) {
+        self.attrs.retain(|(n, _)| *n != name);
+        self.attrs.push((name, value));
+    }
+
+    /// The value of attribute `name`, if the file had it.
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.attrs
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| value.as_slice())
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn save(path: &Path) -> Result<SavedXattrs> {
+    use anyhow::Context;
+
+    let c_path = linux::c_path(path)?;
+    let mut attrs = Vec::new();
+    for name in PRESERVED_XATTRS {
+        let value = linux::get(&c_path, name)
+            .with_context(|| format!("Can't read {} of {:?}", name, path))?;
+        if let Some(value) = value {
+            attrs.push((*name, value));
+        }
+    }
+    Ok(SavedXattrs { attrs })
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn restore(path: &Path, saved: &SavedXattrs) -> Result<()> {
+    use anyhow::Context;
+
+    let c_path = linux::c_path(path)?;
+    for (name, value) in &saved.attrs {
+        linux::set(&c_path, name, value)
+            .with_context(|| format!("Can't set {} of {:?}", name, path))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn save(_path: &Path) -> Result<SavedXattrs> {
+    anyhow::bail!("saving extended attributes is only supported on Linux")
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn restore(_path: &Path, _saved: &SavedXattrs) -> Result<()> {
+    anyhow::bail!("restoring extended attributes is only supported on Linux")
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::ffi::CStr;
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    use anyhow::Result;
+
+    pub(super) fn c_path(path: &Path) -> Result<CString> {
+        Ok(CString::new(path.as_os_str().as_bytes())?)
+    }
+
+    /// The value of attribute `name` of `path`, not following symlinks.
+    /// `None` if the file, or the attribute, does not exist, or if the
+    /// filesystem does not support it.
+    pub(super) fn get(path: &CStr, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let name = CString::new(name)?;
+        loop {
+            let size =
+                unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+            if size < 0 {
+                return absent_or_err(io::Error::last_os_error());
+            }
+            let mut value = vec![0u8; size as usize];
+            let read = unsafe {
+                libc::lgetxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    value.as_mut_ptr() as *mut libc::c_void,
+                    value.len(),
+                )
+            };
+            if read < 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() == Some(libc::ERANGE) {
+                    // The attribute grew since its size was read.
+                    continue;
+                }
+                return absent_or_err(err);
+            }
+            value.truncate(read as usize);
+            return Ok(Some(value));
+        }
+    }
+
+    pub(super) fn set(path: &CStr, name: &str, value: &[u8]) -> io::Result<()> {
+        let name = CString::new(name)?;
+        let ret = unsafe {
+            libc::lsetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn absent_or_err(err: io::Error) -> io::Result<Option<Vec<u8>>> {
+        match err.raw_os_error() {
+            Some(libc::ENODATA) | Some(libc::ENOTSUP) | Some(libc::ENOENT) => Ok(None),
+            _ => Err(err),
+        }
+    }
+}